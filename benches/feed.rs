@@ -0,0 +1,88 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Feed snapshots and ZAP authentication against caches of 1k, 10k and
+//! 100k certs.
+//!
+//!     cargo bench --features bench-support
+
+#[macro_use]
+extern crate criterion;
+extern crate czmq;
+extern crate inauth_client;
+
+use criterion::Criterion;
+use czmq::{ZMsg, ZSock, ZSys};
+use inauth_client::CertType;
+use inauth_client::bench_support::{self, Feed};
+
+const SIZES: [usize; 3] = [1_000, 10_000, 100_000];
+
+fn snapshot(c: &mut Criterion) {
+    ZSys::init();
+
+    for &n in SIZES.iter() {
+        let certs = bench_support::certs(CertType::Host, n).unwrap();
+
+        // A fresh cache for each sample, so the frames are built anew
+        c.bench_function(&format!("snapshot build {}", n), |b| {
+            b.iter_with_setup(|| Feed::new(bench_support::copy_certs(&certs).unwrap()),
+                              |feed| feed.snapshot().unwrap().unwrap())
+        });
+
+        let feed = Feed::new(bench_support::copy_certs(&certs).unwrap());
+        feed.snapshot().unwrap();
+        c.bench_function(&format!("snapshot reuse {}", n), |b| {
+            b.iter(|| feed.snapshot().unwrap().unwrap())
+        });
+        c.bench_function(&format!("snapshot batched {}", n), |b| {
+            b.iter(|| assert_eq!(feed.batched_snapshot(500).unwrap(), n))
+        });
+
+        let endpoint = format!("inproc://bench_snapshot_recv_{}", n);
+        let mut pull = ZSock::new_pull(&endpoint).unwrap();
+        let mut push = ZSock::new_push(&endpoint).unwrap();
+        c.bench_function(&format!("snapshot recv {}", n), |b| {
+            b.iter_with_setup(|| feed.snapshot().unwrap().unwrap().send(&mut push).unwrap(),
+                              |_| assert_eq!(bench_support::recv_snapshot(&mut pull).unwrap(), n))
+        });
+    }
+}
+
+fn authenticate(c: &mut Criterion) {
+    ZSys::init();
+
+    for &n in SIZES.iter() {
+        let certs = bench_support::certs(CertType::User, n).unwrap();
+        let requests: Vec<ZMsg> = certs.iter().map(|cert| bench_support::zap_request(cert).unwrap()).collect();
+
+        let endpoint = format!("inproc://bench_authenticate_{}", n);
+        let mut zap = ZSock::new_req(&endpoint).unwrap();
+        let zap_server = ZSock::new_rep(&endpoint).unwrap();
+        let _handler = bench_support::zap_handler(zap_server, certs).unwrap();
+
+        let mut i = 0;
+        c.bench_function(&format!("zap authenticate {}", n), |b| {
+            b.iter(|| {
+                requests[i % n].dup().unwrap().send(&mut zap).unwrap();
+                i += 1;
+                let reply = ZMsg::recv(&mut zap).unwrap();
+                reply.popstr().unwrap().unwrap();
+                reply.popstr().unwrap().unwrap();
+                assert_eq!(reply.popstr().unwrap().unwrap(), "200");
+            })
+        });
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = snapshot, authenticate
+}
+criterion_main!(benches);
@@ -0,0 +1,45 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+#[cfg(feature = "grpc")]
+extern crate protoc_rust_grpc;
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        use std::env;
+        use std::fs::File;
+        use std::io::{Read, Write};
+        use std::path::Path;
+
+        println!("cargo:rerun-if-changed=proto/cert.proto");
+
+        // Requires `protoc` on the PATH
+        let out_dir = env::var("OUT_DIR").unwrap();
+        protoc_rust_grpc::run(protoc_rust_grpc::Args {
+            out_dir: &out_dir,
+            includes: &["proto"],
+            input: &["proto/cert.proto"],
+            rust_protobuf: true,
+        }).expect("Could not generate gRPC code from proto/cert.proto");
+
+        // The generated files are include!()ed by src/grpc_service.rs,
+        // which can't take their inner attributes. Their allows are
+        // set on the including module instead.
+        for name in &["cert.rs", "cert_grpc.rs"] {
+            let path = Path::new(&out_dir).join(name);
+            let mut code = String::new();
+            File::open(&path).and_then(|mut fh| fh.read_to_string(&mut code)).expect("Could not read generated gRPC code");
+            let code: String = code.lines()
+                .filter(|l| !l.starts_with("#!["))
+                .map(|l| format!("{}\n", l))
+                .collect();
+            File::create(&path).and_then(|mut fh| fh.write_all(code.as_bytes())).expect("Could not write generated gRPC code");
+        }
+    }
+}
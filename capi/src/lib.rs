@@ -0,0 +1,15 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! libinauth_capi, the shared library behind include/inauth_client.h.
+//! The bindings live in inauth_client's `capi` module; this crate only
+//! exports them.
+
+extern crate inauth_client;
+
+pub use inauth_client::capi::*;
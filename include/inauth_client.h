@@ -0,0 +1,96 @@
+/*
+ * Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+ * top-level directory of this distribution and at
+ * https://intecture.io/COPYRIGHT.
+ *
+ * Licensed under the Mozilla Public License 2.0 <LICENSE or
+ * https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+ * modified, or distributed except according to those terms.
+ */
+
+/*
+ * C bindings for inauth_client, built as libinauth_capi with
+ * `cargo build -p inauth-capi`.
+ * Keep in step with src/capi.rs.
+ *
+ * Functions that can fail return NULL or -1 and leave a message for
+ * inauth_last_error() on the calling thread. Strings returned by the
+ * library are freed with inauth_string_free().
+ */
+
+#ifndef INAUTH_CLIENT_H
+#define INAUTH_CLIENT_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct inauth_zap inauth_zap;
+typedef struct inauth_cert_client inauth_cert_client;
+
+/* The last error on this thread, or NULL. Valid until the next call that
+ * fails. */
+const char *inauth_last_error(void);
+
+void inauth_string_free(char *s);
+
+/* Free a list returned by inauth_cert_list(). */
+void inauth_list_free(char **list, size_t len);
+
+/* Subscribe to the Auth server and handle ZAP requests for this process.
+ * cert_type is "host", "user" or NULL for both. cert_path is this
+ * process's secret cert and auth_cert_path the Auth server's public
+ * cert. */
+inauth_zap *inauth_zap_new(const char *cert_type,
+                           const char *cert_path,
+                           const char *auth_cert_path,
+                           const char *auth_server,
+                           uint32_t auth_port,
+                           int allow_self);
+
+/* Block until the handler has its first snapshot. Returns 0, or -1 if it
+ * isn't ready within timeout_ms. */
+int inauth_zap_wait_ready(inauth_zap *zap, uint32_t timeout_ms);
+
+void inauth_zap_free(inauth_zap *zap);
+
+/* A client for the cert API at endpoint, e.g.
+ * "tcp://auth.example.com:7101", authenticating with the cert at
+ * cert_path. */
+inauth_cert_client *inauth_cert_client_new(const char *endpoint,
+                                           const char *server_public_path,
+                                           const char *cert_path);
+
+/* How long to wait for each reply. Defaults to 5 seconds. */
+void inauth_cert_client_set_timeout(inauth_cert_client *client, uint32_t timeout_ms);
+
+void inauth_cert_client_free(inauth_cert_client *client);
+
+/* Create a cert, setting public_key and secret_key to its Z85 keys. */
+int inauth_cert_create(inauth_cert_client *client,
+                       const char *cert_type,
+                       const char *name,
+                       char **public_key,
+                       char **secret_key);
+
+int inauth_cert_delete(inauth_cert_client *client, const char *name);
+
+/* The names of certs of cert_type, setting len to how many. */
+char **inauth_cert_list(inauth_cert_client *client, const char *cert_type, size_t *len);
+
+/* The Z85 public key of the cert called name. */
+char *inauth_cert_lookup(inauth_cert_client *client, const char *name);
+
+/* Check a Z85 public key for known-bad patterns, such as published test
+ * keys. Sets problem to a description, or NULL if the key looks safe.
+ * Returns -1 if the key isn't valid Z85. */
+int inauth_key_check(const char *public_key, char **problem);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,181 @@
+# Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+# top-level directory of this distribution and at
+# https://intecture.io/COPYRIGHT.
+#
+# Licensed under the Mozilla Public License 2.0 <LICENSE or
+# https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+# modified, or distributed except according to those terms.
+
+"""Python bindings for inauth_client.
+
+Wraps the C API (include/inauth_client.h), built as libinauth_capi by
+the capi crate:
+
+    cargo build --release -p inauth-capi
+
+The library is found at $INAUTH_CLIENT_LIB, or by the usual linker
+search for libinauth_capi.
+
+    from inauth import CertClient
+    client = CertClient("tcp://auth.example.com:7101",
+                        "/usr/local/etc/intecture/auth.crt",
+                        "/usr/local/etc/intecture/user.crt")
+    public, secret = client.create("host", "web1.example.com")
+    print(client.list("host"))
+"""
+
+import ctypes
+import ctypes.util
+import os
+
+__all__ = ["InauthError", "CertClient", "ZapHandler", "check_key"]
+
+
+class InauthError(Exception):
+    pass
+
+
+def _load():
+    path = os.environ.get("INAUTH_CLIENT_LIB") or ctypes.util.find_library("inauth_capi")
+    if path is None:
+        raise ImportError("libinauth_capi not found; set INAUTH_CLIENT_LIB")
+    lib = ctypes.CDLL(path)
+
+    c_str = ctypes.c_char_p
+    # Strings we must free are kept as raw pointers
+    c_owned = ctypes.POINTER(ctypes.c_char)
+    c_handle = ctypes.c_void_p
+
+    def sig(name, restype, *argtypes):
+        fn = getattr(lib, name)
+        fn.restype = restype
+        fn.argtypes = argtypes
+
+    sig("inauth_last_error", c_str)
+    sig("inauth_string_free", None, c_owned)
+    sig("inauth_list_free", None, ctypes.POINTER(c_owned), ctypes.c_size_t)
+    sig("inauth_zap_new", c_handle, c_str, c_str, c_str, c_str, ctypes.c_uint32, ctypes.c_int)
+    sig("inauth_zap_wait_ready", ctypes.c_int, c_handle, ctypes.c_uint32)
+    sig("inauth_zap_free", None, c_handle)
+    sig("inauth_cert_client_new", c_handle, c_str, c_str, c_str)
+    sig("inauth_cert_client_set_timeout", None, c_handle, ctypes.c_uint32)
+    sig("inauth_cert_client_free", None, c_handle)
+    sig("inauth_cert_create", ctypes.c_int, c_handle, c_str, c_str,
+        ctypes.POINTER(c_owned), ctypes.POINTER(c_owned))
+    sig("inauth_cert_delete", ctypes.c_int, c_handle, c_str)
+    sig("inauth_cert_list", ctypes.POINTER(c_owned), c_handle, c_str, ctypes.POINTER(ctypes.c_size_t))
+    sig("inauth_cert_lookup", c_owned, c_handle, c_str)
+    sig("inauth_key_check", ctypes.c_int, c_str, ctypes.POINTER(c_owned))
+    return lib
+
+
+_lib = _load()
+
+
+def _enc(s):
+    return None if s is None else s.encode("utf-8")
+
+
+def _error():
+    msg = _lib.inauth_last_error()
+    return InauthError(msg.decode("utf-8") if msg else "unknown error")
+
+
+def _check(result):
+    if result != 0:
+        raise _error()
+
+
+def _take(ptr):
+    """Copy a string returned by the library, then free it."""
+    if not ptr:
+        return None
+    try:
+        return ctypes.string_at(ptr).decode("utf-8")
+    finally:
+        _lib.inauth_string_free(ptr)
+
+
+class CertClient(object):
+    """A client for the Auth server's cert API, authenticating with the
+    user cert at `cert_path`."""
+
+    def __init__(self, endpoint, server_public_path, cert_path, timeout_ms=None):
+        self._ptr = _lib.inauth_cert_client_new(_enc(endpoint), _enc(server_public_path), _enc(cert_path))
+        if not self._ptr:
+            raise _error()
+        if timeout_ms is not None:
+            _lib.inauth_cert_client_set_timeout(self._ptr, timeout_ms)
+
+    def __del__(self):
+        self.close()
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *exc):
+        self.close()
+
+    def close(self):
+        if getattr(self, "_ptr", None):
+            _lib.inauth_cert_client_free(self._ptr)
+            self._ptr = None
+
+    def create(self, cert_type, name):
+        """Create a cert, returning its (public, secret) Z85 keys."""
+        public = ctypes.POINTER(ctypes.c_char)()
+        secret = ctypes.POINTER(ctypes.c_char)()
+        _check(_lib.inauth_cert_create(self._ptr, _enc(cert_type), _enc(name),
+                                       ctypes.byref(public), ctypes.byref(secret)))
+        return _take(public), _take(secret)
+
+    def delete(self, name):
+        _check(_lib.inauth_cert_delete(self._ptr, _enc(name)))
+
+    def list(self, cert_type):
+        length = ctypes.c_size_t()
+        names = _lib.inauth_cert_list(self._ptr, _enc(cert_type), ctypes.byref(length))
+        if not names:
+            raise _error()
+        try:
+            return [ctypes.string_at(names[i]).decode("utf-8") for i in range(length.value)]
+        finally:
+            _lib.inauth_list_free(names, length)
+
+    def lookup(self, name):
+        """The Z85 public key of the cert called `name`."""
+        key = _take(_lib.inauth_cert_lookup(self._ptr, _enc(name)))
+        if key is None:
+            raise _error()
+        return key
+
+
+class ZapHandler(object):
+    """Handle ZAP requests for this process, using certs from the Auth
+    server. `cert_type` is "host", "user" or None for both."""
+
+    def __init__(self, cert_type, cert_path, auth_cert_path, auth_server, auth_port, allow_self=False):
+        self._ptr = _lib.inauth_zap_new(_enc(cert_type), _enc(cert_path), _enc(auth_cert_path),
+                                        _enc(auth_server), auth_port, 1 if allow_self else 0)
+        if not self._ptr:
+            raise _error()
+
+    def __del__(self):
+        self.close()
+
+    def close(self):
+        if getattr(self, "_ptr", None):
+            _lib.inauth_zap_free(self._ptr)
+            self._ptr = None
+
+    def wait_ready(self, timeout_ms=5000):
+        """Block until the handler has its first snapshot of certs."""
+        _check(_lib.inauth_zap_wait_ready(self._ptr, timeout_ms))
+
+
+def check_key(public_key):
+    """Return why a Z85 public key shouldn't be trusted, or None if it
+    looks safe."""
+    problem = ctypes.POINTER(ctypes.c_char)()
+    _check(_lib.inauth_key_check(_enc(public_key), ctypes.byref(problem)))
+    return _take(problem)
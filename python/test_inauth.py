@@ -0,0 +1,58 @@
+# Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+# top-level directory of this distribution and at
+# https://intecture.io/COPYRIGHT.
+#
+# Licensed under the Mozilla Public License 2.0 <LICENSE or
+# https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+# modified, or distributed except according to those terms.
+
+"""Smoke tests for the Python bindings, against a built libinauth_capi:
+
+    cargo build -p inauth-capi
+    INAUTH_CLIENT_LIB=target/debug/libinauth_capi.so python -m unittest discover -s python
+"""
+
+import os
+import unittest
+
+import inauth
+
+Z85_CHARS = ("0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ"
+             ".-:+=^!/*?&<>()[]{}@%$#")
+
+
+def z85_encode(data):
+    out = []
+    for i in range(0, len(data), 4):
+        value = 0
+        for b in bytearray(data[i:i + 4]):
+            value = value * 256 + b
+        chars = []
+        for _ in range(5):
+            chars.append(Z85_CHARS[value % 85])
+            value //= 85
+        out.extend(reversed(chars))
+    return "".join(out)
+
+
+class TestInauth(unittest.TestCase):
+    def test_check_key(self):
+        self.assertEqual(inauth.check_key("rq:rM>}U?@Lns47E1%kR.o@n%FcmmsL/@{H8]yf7"), "published test key")
+        self.assertEqual(inauth.check_key(z85_encode(bytes(bytearray(range(32))))), None)
+        self.assertEqual(inauth.check_key(z85_encode(os.urandom(32))), None)
+
+    def test_errors(self):
+        with self.assertRaises(inauth.InauthError) as e:
+            inauth.check_key("not z85")
+        self.assertTrue(str(e.exception))
+
+        with self.assertRaises(inauth.InauthError) as e:
+            inauth.CertClient(None, "/nonexistent/inauth.crt", "/nonexistent/inauth.crt")
+        self.assertEqual(str(e.exception), "Invalid argument provided")
+
+        with self.assertRaises(inauth.InauthError):
+            inauth.CertClient("tcp://127.0.0.1:7101", "/nonexistent/inauth.crt", "/nonexistent/inauth.crt")
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,188 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! CIDR allow and deny lists for ZAP requests.
+
+use cert::CertType;
+use error::{Error, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// Key for rules that apply to every cert type
+pub const WILDCARD: &'static str = "*";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    fn parse(rule: &str) -> Result<Cidr> {
+        let invalid = || Error::InvalidAddressRule(rule.to_string());
+
+        let (addr, prefix) = match rule.find('/') {
+            Some(i) => (&rule[..i], Some(&rule[i + 1..])),
+            None => (rule, None),
+        };
+        let network = try!(addr.parse::<IpAddr>().or(Err(invalid())));
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => try!(p.parse::<u8>().or(Err(invalid()))),
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+
+        Ok(Cidr {
+            network: network,
+            prefix: prefix,
+        })
+    }
+
+    fn contains(&self, addr: &IpAddr) -> bool {
+        let (net, addr) = match (self.network, *addr) {
+            (IpAddr::V4(n), IpAddr::V4(a)) => (n.octets().to_vec(), a.octets().to_vec()),
+            (IpAddr::V6(n), IpAddr::V6(a)) => (n.octets().to_vec(), a.octets().to_vec()),
+            _ => return false,
+        };
+
+        let bytes = (self.prefix / 8) as usize;
+        let bits = self.prefix % 8;
+        if net[..bytes] != addr[..bytes] {
+            return false;
+        }
+        if bits > 0 {
+            let mask = 0xffu8 << (8 - bits);
+            return net[bytes] & mask == addr[bytes] & mask;
+        }
+        true
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AddressRules {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AddressRules {
+    /// Rules are CIDR ranges ("10.8.0.0/16") or single addresses.
+    /// Deny rules take precedence. If there are any allow rules, the
+    /// address must match one of them.
+    pub fn new(allow: &[String], deny: &[String]) -> Result<AddressRules> {
+        Ok(AddressRules {
+            allow: try!(allow.iter().map(|r| Cidr::parse(r)).collect()),
+            deny: try!(deny.iter().map(|r| Cidr::parse(r)).collect()),
+        })
+    }
+
+    fn allows(&self, addr: Option<&IpAddr>) -> bool {
+        let matches = |list: &Vec<Cidr>| addr.map(|a| list.iter().any(|c| c.contains(a))).unwrap_or(false);
+        !matches(&self.deny) && (self.allow.is_empty() || matches(&self.allow))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AddressPolicy {
+    global: AddressRules,
+    cert_types: HashMap<String, AddressRules>,
+}
+
+impl AddressPolicy {
+    /// Rules are keyed by cert type ("host" or "user"), or "*" for
+    /// rules that apply to every client.
+    pub fn new(mut rules: HashMap<String, AddressRules>) -> Result<AddressPolicy> {
+        let global = rules.remove(WILDCARD).unwrap_or_default();
+        for cert_type in rules.keys() {
+            try!(CertType::from_str(cert_type).or(Err(Error::InvalidAddressRule(cert_type.clone()))));
+        }
+
+        Ok(AddressPolicy {
+            global: global,
+            cert_types: rules,
+        })
+    }
+
+    /// Check the global rules, before we know who the client is.
+    /// Clients without an IP address (e.g. over IPC) only pass if
+    /// there is no allow list.
+    pub fn allows_address(&self, address: &str) -> bool {
+        self.global.allows(parse_address(address).as_ref())
+    }
+
+    /// Check the rules for the client's cert type.
+    pub fn allows_cert_type(&self, address: &str, cert_type: CertType) -> bool {
+        match self.cert_types.get(cert_type.to_str()) {
+            Some(rules) => rules.allows(parse_address(address).as_ref()),
+            None => true,
+        }
+    }
+}
+
+fn parse_address(address: &str) -> Option<IpAddr> {
+    match address.parse() {
+        // Dual-stack sockets report IPv4 peers as "::ffff:a.b.c.d"
+        Ok(IpAddr::V6(v6)) => {
+            let s = v6.segments();
+            if s[..5] == [0, 0, 0, 0, 0] && s[5] == 0xffff {
+                v6.to_ipv4().map(IpAddr::V4)
+            } else {
+                Some(IpAddr::V6(v6))
+            }
+        },
+        Ok(addr) => Some(addr),
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::CertType;
+    use std::collections::HashMap;
+    use super::*;
+
+    #[test]
+    fn test_cidr() {
+        let cidr = Cidr::parse("10.8.0.0/13").unwrap();
+        assert!(cidr.contains(&"10.15.255.1".parse().unwrap()));
+        assert!(!cidr.contains(&"10.16.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"::1".parse().unwrap()));
+
+        assert!(Cidr::parse("192.168.1.1").unwrap().contains(&"192.168.1.1".parse().unwrap()));
+        assert!(Cidr::parse("fd00::/8").unwrap().contains(&"fd12::1".parse().unwrap()));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&"8.8.8.8".parse().unwrap()));
+
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("10.0.0/8").is_err());
+        assert!(Cidr::parse("office").is_err());
+    }
+
+    #[test]
+    fn test_policy() {
+        let mut rules = HashMap::new();
+        rules.insert("*".to_string(), AddressRules::new(&[], &["10.0.0.66".into()]).unwrap());
+        rules.insert("user".to_string(), AddressRules::new(&["10.8.0.0/16".into()], &[]).unwrap());
+        let policy = AddressPolicy::new(rules).unwrap();
+
+        assert!(policy.allows_address("10.8.0.1"));
+        assert!(policy.allows_address(""));
+        assert!(!policy.allows_address("10.0.0.66"));
+        assert!(!policy.allows_address("::ffff:10.0.0.66"));
+
+        assert!(policy.allows_cert_type("10.8.0.1", CertType::User));
+        assert!(!policy.allows_cert_type("192.168.0.1", CertType::User));
+        assert!(!policy.allows_cert_type("", CertType::User));
+        assert!(policy.allows_cert_type("192.168.0.1", CertType::Host));
+
+        let mut rules = HashMap::new();
+        rules.insert("admin".to_string(), AddressRules::default());
+        assert!(AddressPolicy::new(rules).is_err());
+    }
+}
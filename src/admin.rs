@@ -0,0 +1,186 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Local admin socket for introspecting a running server.
+//!
+//! The socket only binds to `ipc://` endpoints and the socket file is
+//! made readable by its owner only, so access is governed by
+//! filesystem permissions rather than CURVE certs. Every reply is a
+//! single JSON frame.
+
+use cert::CertType;
+use cert_cache::SharedCertCache;
+use czmq::{ZMsg, ZSock};
+use error::{Error, Result};
+use fleet::FleetHealth;
+use inauth_client::LogSampler;
+use log::{LogLevelFilter, MaxLogLevelFilter};
+use serde_json::{self, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Instant;
+use timeouts;
+use zap_proxy::{self, FeedStats};
+use zdaemon::ZMsgExtended;
+
+#[derive(Debug, Serialize)]
+struct CacheStats {
+    hosts: usize,
+    users: usize,
+    last_sequence: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct FeedSubscribers {
+    sequence: u64,
+    topics: HashMap<String, u32>,
+    subscribes: u64,
+    peak_subscribe_rate: u32,
+    draining: bool,
+}
+
+pub struct Admin {
+    cert_cache: SharedCertCache,
+    feed_stats: Rc<RefCell<FeedStats>>,
+    fleet: Rc<RefCell<FleetHealth>>,
+    config_dump: String,
+    log_level: MaxLogLevelFilter,
+    log_sampler: LogSampler,
+}
+
+impl Admin {
+    pub fn new(cert_cache: SharedCertCache,
+               feed_stats: Rc<RefCell<FeedStats>>,
+               fleet: Rc<RefCell<FleetHealth>>,
+               config_dump: String,
+               log_level: MaxLogLevelFilter,
+               log_sampler: LogSampler) -> Admin {
+        Admin {
+            cert_cache: cert_cache,
+            feed_stats: feed_stats,
+            fleet: fleet,
+            config_dump: config_dump,
+            log_level: log_level,
+            log_sampler: log_sampler,
+        }
+    }
+
+    pub fn cache_stats(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let cache = self.cert_cache.read();
+        let stats = CacheStats {
+            hosts: cache.dump(CertType::Host).len(),
+            users: cache.dump(CertType::User).len(),
+            last_sequence: cache.last_sequence(),
+        };
+        reply(sock, router_id, &serde_json::to_string(&stats)?)
+    }
+
+    pub fn feed_subscribers(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let feed = self.feed_stats.borrow();
+        let subscribers = FeedSubscribers {
+            sequence: feed.sequence,
+            topics: feed.subscribers.clone(),
+            subscribes: feed.subscribes,
+            peak_subscribe_rate: feed.peak_subscribe_rate,
+            draining: feed.draining,
+        };
+        reply(sock, router_id, &serde_json::to_string(&subscribers)?)
+    }
+
+    /// The cache health each reporting agent last sent, stale agents
+    /// first. See `fleet::FleetHealth::summary()`.
+    pub fn fleet_cache_health(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let sequence = self.feed_stats.borrow().sequence;
+        let agents = self.fleet.borrow().summary(sequence, Instant::now());
+        reply(sock, router_id, &serde_json::to_string(&agents)?)
+    }
+
+    /// The last attestation published on the feed, or null if there
+    /// hasn't been one yet.
+    pub fn attestation(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let value = match self.cert_cache.read().attestation() {
+            Some(a) => a.to_json(),
+            None => Value::Null,
+        };
+        reply(sock, router_id, &serde_json::to_string(&value)?)
+    }
+
+    /// Ask subscribers to move to other servers ahead of a restart.
+    /// Replies with whether the server was already draining.
+    pub fn drain(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let already = self.feed_stats.borrow().draining;
+        zap_proxy::request_drain()?;
+        reply(sock, router_id, &serde_json::to_string(&already)?)
+    }
+
+    pub fn config_dump(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        reply(sock, router_id, &self.config_dump)
+    }
+
+    /// Get the log level, or set it if a level frame is sent.
+    pub fn log_level(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let msg = timeouts::recv_rest(sock, 0, Some(1))?;
+        if let Some(level) = msg.popstr() {
+            let level = level.or(Err(Error::InvalidArg))?;
+            let filter = LogLevelFilter::from_str(&level).or(Err(Error::InvalidArg))?;
+            self.log_level.set(filter);
+            info!("Log level set to {} via admin socket", filter);
+        }
+
+        reply(sock, router_id, &serde_json::to_string(&self.log_level.get().to_string())?)
+    }
+
+    /// Get how many accepted ZAP requests and subscriptions there are
+    /// per one logged, or set it if a number frame is sent. Rejects
+    /// are always logged.
+    pub fn log_sampling(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let msg = timeouts::recv_rest(sock, 0, Some(1))?;
+        if let Some(every) = msg.popstr() {
+            let every = every.or(Err(Error::InvalidArg))?.parse().or(Err(Error::InvalidArg))?;
+            self.log_sampler.set_accept_every(every);
+            info!("Logging 1 in {} accepts via admin socket", self.log_sampler.accept_every());
+        }
+
+        reply(sock, router_id, &serde_json::to_string(&self.log_sampler.accept_every())?)
+    }
+}
+
+/// Bind the admin socket, refusing anything but a local `ipc://`
+/// endpoint.
+pub fn bind(endpoint: &str) -> Result<ZSock> {
+    if !endpoint.starts_with("ipc://") {
+        return Err(Error::InvalidEndpoint);
+    }
+
+    let sock = ZSock::new_router(&format!("@{}", endpoint))?;
+    fs::set_permissions(&endpoint[6..], fs::Permissions::from_mode(0o600))?;
+    Ok(sock)
+}
+
+fn reply(sock: &mut ZSock, router_id: &[u8], body: &str) -> Result<()> {
+    let msg = ZMsg::new_ok()?;
+    msg.pushstr("")?;
+    msg.pushbytes(router_id)?;
+    msg.addstr(body)?;
+    msg.send(sock)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind() {
+        assert!(bind("tcp://127.0.0.1:7104").is_err());
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Choosing between Auth server replicas, by what they advertise about
+//! themselves in feed heartbeats.
+
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
+
+/// Which of several Auth servers a `ZapHandler` should prefer. Once
+/// the servers have advertised themselves, the handler stops
+/// subscribing to the others, and goes back to them while none of the
+/// preferred ones is connected.
+///
+/// Servers advertise themselves if they have a `feed_endpoint`, which
+/// must match how the handler connects to them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AffinityPolicy {
+    /// Prefer servers with this affinity tag, e.g. a region
+    pub tag: Option<String>,
+    /// Of the servers otherwise preferred, use only the one whose
+    /// heartbeats arrive soonest after being sent. This compares
+    /// clocks, so they should be in sync.
+    pub lowest_latency: bool,
+}
+
+/// What a server said about itself in its last heartbeat.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerAdvert {
+    pub endpoint: String,
+    pub tags: Vec<String>,
+    /// Microseconds from the server sending the heartbeat to us
+    /// receiving it, if it was timestamped
+    pub latency_micros: Option<u64>,
+}
+
+impl AffinityPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.tag.is_some() || self.lowest_latency
+    }
+
+    /// Which of `candidates` to prefer, or `None` to keep them all,
+    /// e.g. because some haven't advertised themselves yet or none
+    /// has the tag.
+    pub fn preferred(&self, candidates: &[String], adverts: &HashMap<String, ServerAdvert>) -> Option<Vec<String>> {
+        if !self.is_enabled() || candidates.is_empty() {
+            return None;
+        }
+
+        let mut known = Vec::new();
+        for endpoint in candidates {
+            match adverts.get(endpoint) {
+                Some(advert) => known.push(advert),
+                None => return None,
+            }
+        }
+
+        if let Some(ref tag) = self.tag {
+            known.retain(|a| a.tags.contains(tag));
+        }
+        if self.lowest_latency {
+            if let Some(fastest) = known.iter().filter(|a| a.latency_micros.is_some()).min_by_key(|a| a.latency_micros) {
+                return Some(vec![fastest.endpoint.clone()]);
+            }
+        }
+
+        if known.is_empty() {
+            None
+        } else {
+            Some(known.iter().map(|a| a.endpoint.clone()).collect())
+        }
+    }
+}
+
+/// Map the ways ZeroMQ may report each of `endpoints`, e.g. by IP
+/// address rather than host name, back to the endpoint itself.
+pub fn endpoint_aliases(endpoints: &[String]) -> HashMap<String, String> {
+    let mut aliases = HashMap::new();
+    for endpoint in endpoints {
+        aliases.insert(endpoint.clone(), endpoint.clone());
+        if endpoint.starts_with("tcp://") {
+            if let Ok(addrs) = endpoint[6..].to_socket_addrs() {
+                for addr in addrs {
+                    aliases.insert(format!("tcp://{}", addr), endpoint.clone());
+                }
+            }
+        }
+    }
+    aliases
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    #[test]
+    fn test_preferred() {
+        let endpoints: Vec<String> = vec!["tcp://eu1:7102".into(), "tcp://eu2:7102".into(), "tcp://us1:7102".into()];
+        let mut adverts = HashMap::new();
+        for (endpoint, tag, latency) in vec![("tcp://eu1:7102", "eu", 900), ("tcp://eu2:7102", "eu", 400), ("tcp://us1:7102", "us", 100)] {
+            adverts.insert(endpoint.to_string(), ServerAdvert {
+                endpoint: endpoint.into(),
+                tags: vec![tag.into()],
+                latency_micros: Some(latency),
+            });
+        }
+
+        let eu = AffinityPolicy { tag: Some("eu".into()), lowest_latency: false };
+        assert_eq!(eu.preferred(&endpoints, &adverts), Some(vec!["tcp://eu1:7102".into(), "tcp://eu2:7102".into()]));
+        assert!(AffinityPolicy { tag: Some("ap".into()), lowest_latency: false }.preferred(&endpoints, &adverts).is_none());
+        assert!(AffinityPolicy::default().preferred(&endpoints, &adverts).is_none());
+
+        let fastest_eu = AffinityPolicy { tag: Some("eu".into()), lowest_latency: true };
+        assert_eq!(fastest_eu.preferred(&endpoints, &adverts), Some(vec!["tcp://eu2:7102".into()]));
+        let fastest = AffinityPolicy { tag: None, lowest_latency: true };
+        assert_eq!(fastest.preferred(&endpoints, &adverts), Some(vec!["tcp://us1:7102".into()]));
+
+        // Wait until every candidate has advertised itself
+        adverts.remove("tcp://us1:7102");
+        assert!(eu.preferred(&endpoints, &adverts).is_none());
+    }
+
+    #[test]
+    fn test_endpoint_aliases() {
+        let aliases = endpoint_aliases(&["tcp://127.0.0.1:7102".into(), "ipc:///tmp/auth".into()]);
+        assert_eq!(aliases.get("tcp://127.0.0.1:7102").map(|e| e.as_str()), Some("tcp://127.0.0.1:7102"));
+        assert_eq!(aliases.get("ipc:///tmp/auth").map(|e| e.as_str()), Some("ipc:///tmp/auth"));
+    }
+}
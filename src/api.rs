@@ -6,284 +6,1914 @@
 // https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
 // modified, or distributed except according to those terms.
 
-use cert::{Cert, CertType};
-use cert_cache::CertCache;
-use czmq::{ZFrame, ZMsg, ZSock};
+use cert::{Cert, CertType, ISSUED_META, REVOKED_META};
+use cert_cache::{self, SharedCertCache};
+use config::ListMask;
+use czmq::{ZCert, ZFrame, ZMsg, ZSock};
 use error::{Error, Result};
+use expiry;
+use filter::Filter;
+use fleet::{CacheReport, FleetHealth};
+use key_health;
+use messages::{self, CreateReply, CreateRequest, DeleteReply, DeleteRequest, Encoding, ListReply, ListRequest, LookupReply, LookupRequest};
+use protocol;
+use quota::CreateQuotas;
+use rotation::Rotations;
+use serde_json;
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::randombytes::randombytes;
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::process::Command;
 use std::rc::Rc;
-use storage::PersistenceAdaptor;
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use storage::{self, CertRequest, PersistDisk, PersistenceAdaptor, RotationKey};
+use storage::mirror::MirroredStorage;
+use request_id;
 use request_meta::RequestMeta;
+use timeouts;
+use wire_trace::{Direction, WireTracer};
+use zap_proxy::FeedStats;
 use zdaemon::ZMsgExtended;
+use zmq::z85_decode;
 
 pub struct CertApi<P> {
     persistence: P,
     publisher: ZSock,
-    cert_cache: Rc<RefCell<CertCache>>,
+    cert_cache: SharedCertCache,
+    tracer: WireTracer,
+    list_masking: HashMap<String, HashMap<String, ListMask>>,
+    request_notify: Option<String>,
+    quotas: CreateQuotas,
+    rotations: Rotations,
 }
 
+// Callers with this role bypass list masking
+const ADMIN_ROLE: &'static str = "admin";
+
+// Cert requests one caller can have awaiting approval at once
+const MAX_PENDING_REQUESTS: usize = 10;
+
 impl<P> CertApi<P> where P: PersistenceAdaptor {
-    pub fn new(persistence: P, cert_cache: Rc<RefCell<CertCache>>) -> Result<CertApi<P>> {
+    pub fn new(persistence: P,
+               cert_cache: SharedCertCache,
+               tracer: WireTracer,
+               list_masking: Option<HashMap<String, HashMap<String, ListMask>>>,
+               request_notify: Option<String>,
+               quotas: CreateQuotas,
+               rotations: Rotations) -> Result<CertApi<P>> {
         Ok(CertApi {
             persistence: persistence,
             publisher: ZSock::new_pub("inproc://auth_publisher")?,
             cert_cache: cert_cache,
+            tracer: tracer,
+            list_masking: list_masking.unwrap_or(HashMap::new()),
+            request_notify: request_notify,
+            quotas: quotas,
+            rotations: rotations,
         })
     }
 
-    pub fn list(&mut self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
-        let msg = ZMsg::expect_recv(sock, 1, Some(1), false)?;
-        let cert_type = match msg.popstr().unwrap() {
-            Ok(str) => str,
+    fn is_admin(meta: &RequestMeta) -> bool {
+        meta.role.as_ref().map(|r| r == ADMIN_ROLE).unwrap_or(false)
+    }
+
+    // Only users can change certificates, and a user that belongs to
+    // a domain only those within it
+    fn check_manage(meta: &RequestMeta, cert: Option<&Cert>) -> Result<()> {
+        if meta.cert_type != CertType::User {
+            return Err(Error::Forbidden);
+        }
+        if let (Some(domain), Some(cert)) = (meta.domain.as_ref(), cert) {
+            match cert.meta("domain") {
+                Some(Ok(ref d)) if d == domain => (),
+                _ if Self::is_admin(meta) => (),
+                _ => return Err(Error::Forbidden),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn list(&mut self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        let meta = RequestMeta::new(&endpoint_frame)?;
+        self.do_list(sock, router_id, &meta)
+    }
+
+    fn list_mask(&self, meta: &RequestMeta, listed: CertType) -> ListMask {
+        if Self::is_admin(meta) {
+            return ListMask::Full;
+        }
+
+        match self.list_masking.get(meta.cert_type.to_str()) {
+            Some(rules) => *rules.get(listed.to_str()).unwrap_or(&ListMask::Full),
+            None => ListMask::Full,
+        }
+    }
+
+    // Allow callers that authenticate out of band (e.g. tests and
+    // the HTTP gateway)
+    pub fn do_list(&mut self, sock: &mut ZSock, router_id: &[u8], meta: &RequestMeta) -> Result<()> {
+        let (request, encoding, msg) = messages::recv::<ListRequest>(sock)?;
+        self.tracer.record(Direction::In, "api", &msg, &[]);
+
+        let cert_type = CertType::from_str(&request.cert_type)?;
+        let mask = self.list_mask(meta, cert_type);
+
+        let mut names = Vec::new();
+        if mask != ListMask::Omit {
+            for cert in self.cert_cache.read().dump(cert_type) {
+                names.push(masked_name(cert, mask));
+            }
+        }
+        let reply = messages::reply(&ListReply { names: names }, encoding, router_id)?;
+        self.tracer.record(Direction::Out, "api", &reply, &[]);
+        reply.send(sock)?;
+        Ok(())
+    }
+
+    pub fn search(&mut self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        let meta = RequestMeta::new(&endpoint_frame)?;
+        self.do_search(sock, router_id, &meta)
+    }
+
+    // Allow callers that authenticate out of band (e.g. tests and
+    // the HTTP gateway)
+    pub fn do_search(&mut self, sock: &mut ZSock, router_id: &[u8], meta: &RequestMeta) -> Result<()> {
+        let msg = protocol::CERT_SEARCH.recv(sock)?;
+        self.tracer.record(Direction::In, "api", &msg, &[]);
+        let filter = match msg.popstr().unwrap() {
+            Ok(f) => Filter::parse(&f)?,
             Err(_) => return Err(Error::InvalidArg),
         };
 
         let reply = ZMsg::new_ok()?;
-        reply.pushstr("")?;
-        reply.pushbytes(router_id)?;
-        for cert in self.cert_cache.borrow().dump(CertType::from_str(&cert_type)?) {
-            reply.addstr(cert.name())?;
+        request_id::address(&reply, router_id)?;
+        for cert_type in &[CertType::Host, CertType::User] {
+            let mask = self.list_mask(meta, *cert_type);
+            if mask == ListMask::Omit {
+                continue;
+            }
+
+            for cert in self.cert_cache.read().dump(*cert_type) {
+                // Masked certs are matched on what the caller can see,
+                // so the filter can't reveal what the mask hides
+                let name = masked_name(cert, mask);
+                let matches = match mask {
+                    ListMask::Full => filter.matches_cert(cert),
+                    _ => filter.matches(&|key: &str| match key {
+                        "name" => Some(name.clone()),
+                        "type" => Some(cert_type.to_str().to_string()),
+                        _ => None,
+                    }),
+                };
+                if matches {
+                    reply.addstr(&name)?;
+                }
+            }
         }
+        self.tracer.record(Direction::Out, "api", &reply, &[]);
         reply.send(sock)?;
         Ok(())
     }
 
-    pub fn lookup(&mut self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
-        let msg = ZMsg::expect_recv(sock, 1, Some(1), false)?;
-        let name = match msg.popstr().unwrap() {
-            Ok(str) => str,
-            Err(_) => return Err(Error::InvalidArg),
-        };
+    pub fn lookup(&mut self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        let meta = RequestMeta::new(&endpoint_frame)?;
+        self.do_lookup(sock, router_id, &meta)
+    }
 
-        match self.cert_cache.borrow().get_name(&name) {
-            Some(cert) => {
-                let reply = ZMsg::new_ok()?;
-                reply.pushstr("")?;
-                reply.pushbytes(router_id)?;
-                reply.addstr(cert.public_txt())?;
+    // Allow callers that authenticate out of band (e.g. tests and
+    // the HTTP gateway)
+    pub fn do_lookup(&mut self, sock: &mut ZSock, router_id: &[u8], meta: &RequestMeta) -> Result<()> {
+        let (request, encoding, msg) = messages::recv::<LookupRequest>(sock)?;
+        self.tracer.record(Direction::In, "api", &msg, &[]);
+
+        // Certs the caller can't list by name can't be looked up by it
+        // either
+        match self.cert_cache.read().get_name(&request.name) {
+            Some(cert) if self.list_mask(meta, cert.cert_type()) == ListMask::Full => {
+                let reply = messages::reply(&LookupReply { public_key: cert.public_txt().to_string() }, encoding, router_id)?;
+                self.tracer.record(Direction::Out, "api", &reply, &[]);
                 reply.send(sock)?;
                 Ok(())
             },
-            None => Err(Error::InvalidCert),
+            _ => Err(Error::InvalidCert),
         }
     }
 
-    pub fn create(&mut self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
-        // Only users can create certificates
-        let meta = RequestMeta::new(&endpoint_frame)?;
-        if meta.cert_type != CertType::User {
-            return Err(Error::Forbidden);
+    /// Send every cert of the requested type, with the feed
+    /// `sequence` the snapshot was taken at, for
+    /// `CertCache::apply_snapshot()`.
+    pub fn resync(&mut self, sock: &mut ZSock, router_id: &[u8], sequence: u64) -> Result<()> {
+        let msg = protocol::FEED_RESYNC.recv(sock)?;
+        self.tracer.record(Direction::In, "api", &msg, &[]);
+        let cert_type = match msg.popstr() {
+            Some(Ok(ref t)) if !t.is_empty() => Some(CertType::from_str(t)?),
+            Some(Ok(_)) | None => None,
+            Some(Err(_)) => return Err(Error::InvalidArg),
+        };
+
+        let reply = ZMsg::new_ok()?;
+        request_id::address(&reply, router_id)?;
+        reply.addstr(&sequence.to_string())?;
+        let cert_types = match cert_type {
+            Some(t) => vec![t],
+            None => vec![CertType::Host, CertType::User],
+        };
+        for cert_type in cert_types {
+            for cert in self.cert_cache.read().dump(cert_type) {
+                reply.addstr(cert.public_txt())?;
+                reply.addbytes(&cert.encode_meta())?;
+            }
         }
+        self.tracer.record(Direction::Out, "api", &reply, &[]);
+        reply.send(sock)?;
+        Ok(())
+    }
 
+    pub fn create(&mut self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        let meta = RequestMeta::new(&endpoint_frame)?;
         self.do_create(sock, router_id, &meta)
     }
 
-    // Allow testing without auth
-    fn do_create(&mut self, sock: &mut ZSock, router_id: &[u8], meta: &RequestMeta) -> Result<()> {
-        let request = ZMsg::expect_recv(sock, 2, Some(2), false)?;
-
-        let cert_type = match request.popstr().unwrap() {
-            Ok(t) => CertType::from_str(&t)?,
-            Err(_) => return Err(Error::InvalidCertMeta),
-        };
-
-        let cert_name = match request.popstr().unwrap() {
-            Ok(n) => n,
-            Err(_) => return Err(Error::InvalidCertMeta),
-        };
+    // Allow callers that authenticate out of band (e.g. tests and
+    // the HTTP gateway)
+    pub fn do_create(&mut self, sock: &mut ZSock, router_id: &[u8], meta: &RequestMeta) -> Result<()> {
+        let (request, encoding, msg) = messages::recv::<CreateRequest>(sock)?;
+        self.tracer.record(Direction::In, "api", &msg, &[]);
+        Self::check_manage(meta, None)?;
 
-        let cert = Cert::new(&cert_name, cert_type)?;
+        let cert_type = CertType::from_str(&request.cert_type)?;
+        self.quotas.check(&meta.name, cert_type)?;
+        let cert = Cert::new(&request.name, cert_type)?;
         // If a user belongs to a domain, they can only create new
         // certificates within that domain.
         if let Some(ref domain) = meta.domain {
             cert.set_meta("domain", domain);
         }
+        cert.set_meta(ISSUED_META, &expiry::now().to_string());
         self.persistence.create(&cert)?;
+        self.quotas.record(&meta.name, cert_type);
+        self.publish_add(&cert)?;
+
+        // Reply cert
+        let msg = messages::reply(&CreateReply::new(&cert), encoding, router_id)?;
+        // Never write the secret key to the trace. JSON replies carry
+        // it in their only frame.
+        let secret_frame = request_id::status_index() + if encoding == Encoding::Json { 1 } else { 2 };
+        self.tracer.record(Direction::Out, "api", &msg, &[secret_frame]);
+        msg.send(sock)?;
+
+        Ok(())
+    }
+
+    pub fn delete(&mut self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        let meta = RequestMeta::new(&endpoint_frame)?;
+        self.do_delete(sock, router_id, &meta)
+    }
+
+    // Allow callers that authenticate out of band (e.g. tests and
+    // the HTTP gateway)
+    pub fn do_delete(&mut self, sock: &mut ZSock, router_id: &[u8], meta: &RequestMeta) -> Result<()> {
+        let (request, encoding, msg) = messages::recv::<DeleteRequest>(sock)?;
+        self.tracer.record(Direction::In, "api", &msg, &[]);
+        Self::check_manage(meta, None)?;
+
+        let cert = self.persistence.read(&request.name)?;
+        Self::check_manage(meta, Some(&cert))?;
+
+        self.persistence.delete(&request.name)?;
+        self.cancel_rotation(&request.name)?;
 
-        // Publish cert
         let msg = ZMsg::new();
-        msg.addstr(cert.cert_type().to_str())?;
-        msg.addstr("ADD")?;
-        msg.addstr(cert.public_txt())?;
-        msg.addbytes(&cert.encode_meta())?;
-        msg.send(&mut self.publisher)?;
+        msg.send_multi(&mut self.publisher, &[
+            cert.cert_type().to_str(),
+            "DEL",
+            &cert.public_txt(),
+        ])?;
+
+        let msg = messages::reply(&DeleteReply {}, encoding, router_id)?;
+        self.tracer.record(Direction::Out, "api", &msg, &[]);
+        msg.send(sock)?;
+
+        Ok(())
+    }
+
+    pub fn rotate(&mut self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        let meta = RequestMeta::new(&endpoint_frame)?;
+        self.do_rotate(sock, router_id, &meta)
+    }
+
+    /// Give a cert a new key pair. The old key stops authenticating as
+    /// soon as subscribers see its DEL.
+    // Allow callers that authenticate out of band (e.g. tests and
+    // the HTTP gateway)
+    pub fn do_rotate(&mut self, sock: &mut ZSock, router_id: &[u8], meta: &RequestMeta) -> Result<()> {
+        let request = protocol::CERT_ROTATE.recv(sock)?;
+        self.tracer.record(Direction::In, "api", &request, &[]);
+        let name = match request.popstr().unwrap() {
+            Ok(n) => n,
+            Err(_) => return Err(Error::InvalidCert),
+        };
+        Self::check_manage(meta, None)?;
+
+        let old = self.persistence.read(&name)?;
+        Self::check_manage(meta, Some(&old))?;
+        // A new key mustn't bring a revoked cert back
+        if old.is_revoked() {
+            return Err(Error::InvalidCert);
+        }
+        let cert = rekeyed(&old, expiry::now())?;
+        self.replace(&old, &cert)?;
+        self.cancel_rotation(&name)?;
+
+        let msg = ZMsg::new();
+        msg.send_multi(&mut self.publisher, &[old.cert_type().to_str(), "DEL", old.public_txt()])?;
+        self.publish_add(&cert)?;
+        info!("{}Rotated the key for {} from {} to {}", request_id::log_prefix(), name, old.public_txt(), cert.public_txt());
 
-        // Reply cert
         let msg = ZMsg::new_ok()?;
-        msg.pushstr("")?;
-        msg.pushbytes(router_id)?;
+        request_id::address(&msg, router_id)?;
         msg.addstr(cert.public_txt())?;
-        msg.addstr(cert.secret_txt())?;
+        msg.addstr(cert.secret_txt().expose())?;
         msg.addbytes(&cert.encode_meta())?;
+        // Never write the secret key to the trace
+        self.tracer.record(Direction::Out, "api", &msg, &[request_id::status_index() + 2]);
         msg.send(sock)?;
 
         Ok(())
     }
 
-    pub fn delete(&mut self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
-        // Only users can delete certificates
+    pub fn revoke(&mut self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        // Only users can revoke certificates
         let meta = RequestMeta::new(&endpoint_frame)?;
         if meta.cert_type != CertType::User {
             return Err(Error::Forbidden);
         }
 
-        self.do_delete(sock, router_id)
+        self.do_revoke(sock, router_id)
     }
 
-    // Allow testing without auth
-    fn do_delete(&mut self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
-        let request = ZMsg::expect_recv(sock, 1, Some(1), false)?;
-        let name: String = match request.popstr().unwrap() {
+    /// Mark a cert revoked and publish it, so that subscribers stop
+    /// accepting it straight away. Unlike a deleted cert, a revoked
+    /// one stays on record, so its name can't be reissued by accident.
+    // Allow callers that authenticate out of band (e.g. tests)
+    pub fn do_revoke(&mut self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let request = protocol::CERT_REVOKE.recv(sock)?;
+        self.tracer.record(Direction::In, "api", &request, &[]);
+        let name = match request.popstr().unwrap() {
             Ok(n) => n,
             Err(_) => return Err(Error::InvalidCert),
         };
 
+        let old = self.persistence.read(&name)?;
         let cert = self.persistence.read(&name)?;
-
-        self.persistence.delete(&name)?;
+        cert.set_meta(REVOKED_META, "true");
+        self.replace(&old, &cert)?;
+        self.cancel_rotation(&name)?;
 
         let msg = ZMsg::new();
-        msg.send_multi(&mut self.publisher, &[
-            cert.cert_type().to_str(),
-            "DEL",
-            &cert.public_txt(),
-        ])?;
+        msg.addstr(cert.cert_type().to_str())?;
+        msg.addstr("UPDATE")?;
+        msg.addstr(cert.public_txt())?;
+        msg.addbytes(&cert.encode_meta())?;
+        msg.send(&mut self.publisher)?;
+        warn!(target: "audit", "{}Revoked {} cert {} ({})", request_id::log_prefix(), cert.cert_type().to_str(), name, cert.public_txt());
 
         let msg = ZMsg::new_ok()?;
-        msg.pushstr("")?;
-        msg.pushbytes(router_id)?;
+        request_id::address(&msg, router_id)?;
+        self.tracer.record(Direction::Out, "api", &msg, &[]);
         msg.send(sock)?;
 
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use cert::{Cert, CertType};
-    use cert_cache::CertCache;
-    use czmq::{ZMsg, ZSock, ZSys};
-    use std::cell::RefCell;
-    use std::rc::Rc;
-    use storage::{PersistenceAdaptor, PersistDisk};
-    use super::*;
-    use tempdir::TempDir;
-    use zdaemon::ZMsgExtended;
-
-    #[test]
-    fn test_list() {
-        ZSys::init();
-
-        let host = Cert::new("luke.jedi.org", CertType::Host).unwrap();
-        let user = Cert::new("luke_vader", CertType::User).unwrap();
-        let (_dir, mut api) = create_api(">inproc://api_test_list_publisher", Some(vec![&host, &user]));
+    // Swap `old` for `cert` in storage, putting `old` back if that
+    // fails
+    fn replace(&mut self, old: &Cert, cert: &Cert) -> Result<()> {
+        self.persistence.delete(old.name())?;
+        if let Err(e) = self.persistence.create(cert) {
+            if let Err(restore) = self.persistence.create(old) {
+                error!("{}Could not restore {} after a failed update: {}", request_id::log_prefix(), old.name(), restore);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
 
-        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+    /// Send the caller the key pair replacing its own cert, while a
+    /// scheduled rotation is in its grace window. See `rotation`.
+    pub fn pending_rotation(&mut self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        let meta = RequestMeta::new(&endpoint_frame)?;
+        self.do_pending_rotation(sock, router_id, &meta)
+    }
 
-        client.send_str("user").unwrap();
-        api.list(&mut server, b"router_id").unwrap();
+    // Allow callers that authenticate out of band (e.g. tests)
+    pub fn do_pending_rotation(&mut self, sock: &mut ZSock, router_id: &[u8], meta: &RequestMeta) -> Result<()> {
+        let request = protocol::CERT_PENDING_ROTATION.recv(sock)?;
+        self.tracer.record(Direction::In, "api", &request, &[]);
 
-        let reply = ZMsg::recv(&mut client).unwrap();
-        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
-        assert_eq!(reply.popstr().unwrap().unwrap(), "");
-        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
-        assert_eq!(reply.popstr().unwrap().unwrap(), "luke_vader");
+        let rotation = match self.rotations.get(&meta.name) {
+            Some(r) if r.old.cert_type() == meta.cert_type => r,
+            _ => return Err(Error::NoPendingRotation),
+        };
+        warn!(target: "audit", "{}Sent {} cert {} its rotated key {}", request_id::log_prefix(), meta.cert_type.to_str(), meta.name, rotation.new.public_txt());
 
-        client.send_str("host").unwrap();
-        api.list(&mut server, b"router_id").unwrap();
+        let msg = ZMsg::new_ok()?;
+        request_id::address(&msg, router_id)?;
+        msg.addstr(rotation.new.public_txt())?;
+        msg.addstr(rotation.new.secret_txt().expose())?;
+        msg.addbytes(&rotation.new.encode_meta())?;
+        msg.addstr(&rotation.retire_at.to_string())?;
+        // Never write the secret key to the trace
+        self.tracer.record(Direction::Out, "api", &msg, &[request_id::status_index() + 2]);
+        msg.send(sock)?;
 
-        let reply = ZMsg::recv(&mut client).unwrap();
-        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
-        assert_eq!(reply.popstr().unwrap().unwrap(), "");
-        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
-        assert_eq!(reply.popstr().unwrap().unwrap(), "luke.jedi.org");
+        Ok(())
     }
 
-    #[test]
-    fn test_lookup() {
-        ZSys::init();
+    /// Retire the old keys of scheduled rotations whose grace window
+    /// has ended, then start rotating the certs that are due.
+    pub fn run_rotations(&mut self, now: u64) -> Result<()> {
+        self.withdraw_abandoned_rotations()?;
 
-        let cert = Cert::new("r2d2", CertType::Host).unwrap();
-        let (_dir, mut api) = create_api(">inproc://api_test_lookup_publisher", Some(vec![&cert]));
+        // Certs issued before rotation policies existed count from now,
+        // rather than all rotating at once
+        let legacy: Vec<Cert> = self.persistence.dump()?.into_iter().filter(|c| c.issued_at().is_none() && self.rotations.applies_to(c.cert_type())).collect();
+        for old in legacy {
+            let cert = self.persistence.read(old.name())?;
+            cert.set_meta(ISSUED_META, &now.to_string());
+            self.replace(&old, &cert)?;
+        }
 
-        let mut client = ZSock::new_req("inproc://api_test_lookup").unwrap();
-        let mut server = ZSock::new_rep("inproc://api_test_lookup").unwrap();
+        for rotation in self.rotations.take_finished(now) {
+            let old = self.persistence.read(rotation.old.name())?;
+            self.replace(&old, &rotation.new)?;
 
-        client.send_str("Han Solo").unwrap();
-        assert!(api.lookup(&mut server, b"router_id").is_err());
-        server.send_str("").unwrap();
-        client.recv_str().unwrap().unwrap();
+            let msg = ZMsg::new();
+            msg.send_multi(&mut self.publisher, &[old.cert_type().to_str(), "DEL", old.public_txt()])?;
+            warn!(target: "audit", "Retired key {} of {} cert {} after a scheduled rotation", old.public_txt(), old.cert_type().to_str(), old.name());
+        }
 
-        client.send_str("r2d2").unwrap();
-        assert!(api.lookup(&mut server, b"router_id").is_ok());
+        let due: Vec<Cert> = self.persistence.dump()?.into_iter().filter(|c| self.rotations.is_due(c, now)).collect();
+        for old in due {
+            let cert = rekeyed(&old, now)?;
+            self.publish_add(&cert)?;
+            info!("Rotating the key for {} from {} to {}", old.name(), old.public_txt(), cert.public_txt());
+            self.rotations.start(old, cert, now);
+        }
+        self.save_rotation_keys()
+    }
 
-        let reply = ZMsg::recv(&mut client).unwrap();
-        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
-        assert_eq!(reply.popstr().unwrap().unwrap(), "");
-        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
-        assert_eq!(reply.popstr().unwrap().unwrap(), cert.public_txt());
+    // Abandon any scheduled rotation of `name`, withdrawing its new key
+    fn cancel_rotation(&mut self, name: &str) -> Result<()> {
+        if let Some(rotation) = self.rotations.cancel(name) {
+            let msg = ZMsg::new();
+            msg.send_multi(&mut self.publisher, &[rotation.new.cert_type().to_str(), "DEL", rotation.new.public_txt()])?;
+            info!("{}Cancelled the scheduled rotation of {} to {}", request_id::log_prefix(), name, rotation.new.public_txt());
+            self.save_rotation_keys()?;
+        }
+        Ok(())
     }
 
-    #[test]
-    fn test_create() {
-        ZSys::init();
+    // Store the new public keys of pending rotations, so that those a
+    // restart abandons can be withdrawn
+    fn save_rotation_keys(&mut self) -> Result<()> {
+        let keys: Vec<RotationKey> = self.rotations.pending().map(|r| RotationKey {
+            name: r.new.name().to_string(),
+            cert_type: r.new.cert_type().to_str().to_string(),
+            public_key: r.new.public_txt().to_string(),
+        }).collect();
+        self.persistence.write_rotation_keys(&keys)
+    }
 
-        let (_dir, mut api) = create_api(">inproc://api_test_create_publisher", None);
+    // Publish a DEL for each stored rotation key that isn't pending.
+    // Keys that made it into storage, as their rotation finished just
+    // before a restart, are kept.
+    fn withdraw_abandoned_rotations(&mut self) -> Result<()> {
+        for key in self.persistence.read_rotation_keys()? {
+            if self.rotations.get(&key.name).map(|r| r.new.public_txt() == key.public_key).unwrap_or(false) {
+                continue;
+            }
+            if self.persistence.read_pubkey(&key.public_key).is_ok() {
+                continue;
+            }
+            let msg = ZMsg::new();
+            msg.send_multi(&mut self.publisher, &[key.cert_type.as_str(), "DEL", key.public_key.as_str()])?;
+            warn!(target: "audit", "Withdrew key {} of {} cert {}, whose scheduled rotation was abandoned by a restart", key.public_key, key.cert_type, key.name);
+        }
+        Ok(())
+    }
 
-        let mut subscriber = ZSock::new_sub("@inproc://api_test_create_publisher", Some("host")).unwrap();
-        let mut client = ZSock::new_req("inproc://api_test_create").unwrap();
-        let mut server = ZSock::new_rep("inproc://api_test_create").unwrap();
+    /// Republish a single cert's ADD, either to all subscribers of its
+    /// type or, if a subscriber name is given, to that subscriber only.
+    pub fn push(&mut self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        // Only users can republish certificates
+        let meta = RequestMeta::new(&endpoint_frame)?;
+        if meta.cert_type != CertType::User {
+            return Err(Error::Forbidden);
+        }
 
-        let msg = ZMsg::new();
-        msg.send_multi(&mut client, &["host", "usetheforks.com"]).unwrap();
-        let meta = RequestMeta {
-            name: "test".into(),
-            cert_type: CertType::User,
-            domain: None,
+        self.do_push(sock, router_id)
+    }
+
+    // Allow callers that authenticate out of band (e.g. tests and
+    // the admin socket)
+    pub fn do_push(&mut self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let request = protocol::FEED_PUSH.recv(sock)?;
+        self.tracer.record(Direction::In, "api", &request, &[]);
+        let name = match request.popstr().unwrap() {
+            Ok(n) => n,
+            Err(_) => return Err(Error::InvalidArg),
+        };
+        let subscriber = match request.popstr() {
+            Some(Ok(s)) => Some(s),
+            Some(Err(_)) => return Err(Error::InvalidArg),
+            None => None,
         };
-        api.do_create(&mut server, b"router_id", &meta).unwrap();
 
-        let reply = ZMsg::recv(&mut client).unwrap();
-        assert_eq!(reply.size(), 6);
-        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
-        assert_eq!(reply.popstr().unwrap().unwrap(), "");
-        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
-        let pubkey = reply.popstr().unwrap().unwrap();
+        {
+            let cache = self.cert_cache.read();
+            let cert = cache.get_name(&name).ok_or(Error::InvalidCert)?;
 
-        let sub_reply = ZMsg::recv(&mut subscriber).unwrap();
-        sub_reply.popstr().unwrap().unwrap(); // Remove topic frame
-        assert_eq!(sub_reply.popstr().unwrap().unwrap(), "ADD");
-        assert_eq!(sub_reply.popstr().unwrap().unwrap(), pubkey);
+            let msg = ZMsg::new();
+            match subscriber {
+                Some(ref s) => msg.addstr(&cert_cache::direct_topic(s))?,
+                None => msg.addstr(cert.cert_type().to_str())?,
+            }
+            msg.addstr("ADD")?;
+            msg.addstr(cert.public_txt())?;
+            msg.addbytes(&cert.encode_meta())?;
+            msg.send(&mut self.publisher)?;
+        }
+
+        let msg = ZMsg::new_ok()?;
+        request_id::address(&msg, router_id)?;
+        self.tracer.record(Direction::Out, "api", &msg, &[]);
+        msg.send(sock)?;
+
+        Ok(())
     }
 
-    #[test]
-    fn test_delete() {
-        ZSys::init();
+    /// Queue a cert for admin approval. The requester generates the
+    /// key pair and sends only the public key, so nothing secret waits
+    /// on the server.
+    pub fn request(&mut self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        let meta = RequestMeta::new(&endpoint_frame)?;
+        self.do_request(sock, router_id, &meta)
+    }
 
-        let cert = Cert::new("c3po", CertType::Host).unwrap();
-        let (_dir, mut api) = create_api(">inproc://api_test_delete_publisher", Some(vec![&cert]));
+    // Allow callers that authenticate out of band (e.g. tests)
+    pub fn do_request(&mut self, sock: &mut ZSock, router_id: &[u8], meta: &RequestMeta) -> Result<()> {
+        let msg = protocol::CERT_REQUEST.recv(sock)?;
+        self.tracer.record(Direction::In, "api", &msg, &[]);
+        let mut frames = Vec::new();
+        while let Some(frame) = msg.popstr() {
+            frames.push(frame.or(Err(Error::InvalidCertMeta))?);
+        }
 
-        let mut subscriber = ZSock::new_sub("@inproc://api_test_delete_publisher", Some("host")).unwrap();
-        let mut client = ZSock::new_req("inproc://api_test_delete").unwrap();
-        let mut server = ZSock::new_rep("inproc://api_test_delete").unwrap();
+        let cert_type = CertType::from_str(&frames[0])?;
+        let request = CertRequest {
+            id: random_id(),
+            name: frames[1].clone(),
+            cert_type: cert_type.to_str().to_string(),
+            public_key: frames[2].clone(),
+            requested_by: meta.name.clone(),
+            requested_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        };
 
-        client.send_str("Han Solo's Millenium Falcon Ignition Key").unwrap();
-        assert!(api.do_delete(&mut server, b"router_id").is_err());
-        server.send_str("").unwrap();
-        client.recv_str().unwrap().unwrap();
+        // Catch problems now rather than on approval
+        let cert = requested_cert(&request)?;
+        if let Some(problem) = key_health::check_cert(&cert) {
+            return Err(Error::WeakKey(problem.to_string()));
+        }
+        let mut requests = self.persistence.read_requests()?;
+        if self.cert_cache.read().get_name(&request.name).is_some() || requests.iter().any(|r| r.name == request.name) {
+            return Err(Error::CertNameCollision);
+        }
+        if requests.iter().filter(|r| r.requested_by == request.requested_by).count() >= MAX_PENDING_REQUESTS {
+            return Err(Error::QuotaExceeded(format!("{} already has {} certificate requests pending", request.requested_by, MAX_PENDING_REQUESTS)));
+        }
+        requests.push(request.clone());
+        self.persistence.write_requests(&requests)?;
+        self.notify_request(&request);
 
-        client.send_str("c3po").unwrap();
-        assert!(api.do_delete(&mut server, b"router_id").is_ok());
+        let reply = ZMsg::new_ok()?;
+        request_id::address(&reply, router_id)?;
+        reply.addstr(&request.id)?;
+        self.tracer.record(Direction::Out, "api", &reply, &[]);
+        reply.send(sock)?;
+        Ok(())
+    }
 
-        let reply = ZMsg::recv(&mut client).unwrap();
-        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
-        assert_eq!(reply.popstr().unwrap().unwrap(), "");
-        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+    pub fn pending_list(&mut self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        // Only admins can see other users' requests
+        let meta = RequestMeta::new(&endpoint_frame)?;
+        if !Self::is_admin(&meta) {
+            return Err(Error::Forbidden);
+        }
 
-        let sub_reply = ZMsg::recv(&mut subscriber).unwrap();
-        sub_reply.popstr().unwrap().unwrap(); // Remove topic frame
-        assert_eq!(sub_reply.popstr().unwrap().unwrap(), "DEL");
-        assert_eq!(sub_reply.popstr().unwrap().unwrap(), cert.public_txt());
+        self.do_pending_list(sock, router_id)
+    }
+
+    /// Reply with the pending requests as a JSON array, oldest first.
+    // Allow callers that authenticate out of band (e.g. tests and
+    // the admin socket)
+    pub fn do_pending_list(&mut self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let requests = self.persistence.read_requests()?;
+
+        let reply = ZMsg::new_ok()?;
+        request_id::address(&reply, router_id)?;
+        reply.addstr(&serde_json::to_string(&requests)?)?;
+        self.tracer.record(Direction::Out, "api", &reply, &[]);
+        reply.send(sock)?;
+        Ok(())
+    }
+
+    pub fn approve(&mut self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        let meta = RequestMeta::new(&endpoint_frame)?;
+        if !Self::is_admin(&meta) {
+            return Err(Error::Forbidden);
+        }
+
+        self.do_approve(sock, router_id)
+    }
+
+    /// Issue the cert for a pending request and publish it.
+    // Allow callers that authenticate out of band (e.g. tests and
+    // the admin socket)
+    pub fn do_approve(&mut self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let request = self.take_request(sock)?;
+        let cert = requested_cert(&request)?;
+        // The cert counts towards the requester's quota, as if they had
+        // created it, so requests can't be used to get around it
+        self.quotas.check(&request.requested_by, cert.cert_type())?;
+        self.persistence.create(&cert)?;
+        self.quotas.record(&request.requested_by, cert.cert_type());
+        self.remove_request(&request.id)?;
+        self.publish_add(&cert)?;
+        info!("{}Approved certificate request {} for {} ({})", request_id::log_prefix(), request.id, request.name, request.requested_by);
+
+        let reply = ZMsg::new_ok()?;
+        request_id::address(&reply, router_id)?;
+        reply.addstr(&request.name)?;
+        self.tracer.record(Direction::Out, "api", &reply, &[]);
+        reply.send(sock)?;
+        Ok(())
+    }
+
+    pub fn deny(&mut self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        let meta = RequestMeta::new(&endpoint_frame)?;
+        if !Self::is_admin(&meta) {
+            return Err(Error::Forbidden);
+        }
+
+        self.do_deny(sock, router_id)
+    }
+
+    // Allow callers that authenticate out of band (e.g. tests and
+    // the admin socket)
+    pub fn do_deny(&mut self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let request = self.take_request(sock)?;
+        self.remove_request(&request.id)?;
+        info!("{}Denied certificate request {} for {} ({})", request_id::log_prefix(), request.id, request.name, request.requested_by);
+
+        let reply = ZMsg::new_ok()?;
+        request_id::address(&reply, router_id)?;
+        self.tracer.record(Direction::Out, "api", &reply, &[]);
+        reply.send(sock)?;
+        Ok(())
+    }
+
+    // Read a request ID from `sock` and find the pending request. Both
+    // endpoints that use this take the same frames.
+    fn take_request(&mut self, sock: &mut ZSock) -> Result<CertRequest> {
+        let msg = protocol::CERT_APPROVE.recv(sock)?;
+        self.tracer.record(Direction::In, "api", &msg, &[]);
+        let id = match msg.popstr().unwrap() {
+            Ok(id) => id,
+            Err(_) => return Err(Error::InvalidArg),
+        };
+
+        self.persistence.read_requests()?
+            .into_iter()
+            .find(|r| r.id == id)
+            .ok_or(Error::UnknownCertRequest)
+    }
+
+    fn remove_request(&mut self, id: &str) -> Result<()> {
+        let mut requests = self.persistence.read_requests()?;
+        requests.retain(|r| r.id != id);
+        self.persistence.write_requests(&requests)
+    }
+
+    fn publish_add(&mut self, cert: &Cert) -> Result<()> {
+        let msg = ZMsg::new();
+        msg.addstr(cert.cert_type().to_str())?;
+        msg.addstr("ADD")?;
+        msg.addstr(cert.public_txt())?;
+        msg.addbytes(&cert.encode_meta())?;
+        msg.send(&mut self.publisher)?;
+        Ok(())
+    }
+
+    // The command runs in the background so a slow mail server
+    // doesn't hold up the API. Its exit status is only logged.
+    fn notify_request(&self, request: &CertRequest) {
+        info!("{}Certificate request {} from {} for {} {} awaits approval", request_id::log_prefix(), request.id, request.requested_by, request.cert_type, request.name);
+
+        if let Some(ref command) = self.request_notify {
+            let child = Command::new(command)
+                .env("INAUTH_REQUEST_ID", &request.id)
+                .env("INAUTH_REQUEST_NAME", &request.name)
+                .env("INAUTH_REQUEST_TYPE", &request.cert_type)
+                .env("INAUTH_REQUEST_BY", &request.requested_by)
+                .spawn();
+            match child {
+                Ok(mut child) => {
+                    let command = command.clone();
+                    thread::spawn(move || match child.wait() {
+                        Ok(status) if !status.success() => warn!("Request notify command {} failed: {}", command, status),
+                        Err(e) => warn!("Request notify command {} failed: {}", command, e),
+                        _ => (),
+                    });
+                },
+                Err(e) => warn!("Could not run request notify command {}: {}", command, e),
+            }
+        }
+    }
+}
+
+impl CertApi<MirroredStorage<PersistDisk>> {
+    /// Take a step towards switching to another storage directory,
+    /// while requests are still served from the current one. The step
+    /// is "attach" followed by the new directory, "copy", "verify",
+    /// "promote", "abort" or "status". See `storage::mirror`.
+    /// Replies with the switch status as JSON.
+    // Only available on the admin socket
+    pub fn do_storage_switch(&mut self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let msg = timeouts::recv_rest(sock, 1, Some(2))?;
+        self.tracer.record(Direction::In, "api", &msg, &[]);
+        let step = msg.popstr().unwrap().or(Err(Error::InvalidArg))?;
+
+        match step.as_ref() {
+            "attach" => {
+                let path = match msg.popstr() {
+                    Some(Ok(p)) => p,
+                    _ => return Err(Error::InvalidArg),
+                };
+                let mut mirror = PersistDisk::new(&path)?;
+                mirror.set_file_perms(self.persistence.primary().file_perms().clone());
+                self.persistence.attach(mirror)?;
+            },
+            "copy" => {
+                self.persistence.copy()?;
+            },
+            "verify" => self.persistence.verify()?,
+            "promote" => {
+                self.persistence.promote()?;
+                warn!("{}Switched storage backend. Update \"cert_path\" in auth.json before restarting.", request_id::log_prefix());
+            },
+            "abort" => {
+                self.persistence.abort();
+            },
+            "status" => (),
+            _ => return Err(Error::InvalidArg),
+        }
+
+        let reply = ZMsg::new_ok()?;
+        request_id::address(&reply, router_id)?;
+        reply.addstr(&serde_json::to_string(&self.persistence.status())?)?;
+        self.tracer.record(Direction::Out, "api", &reply, &[]);
+        reply.send(sock)?;
+        Ok(())
+    }
+}
+
+fn requested_cert(request: &CertRequest) -> Result<Cert> {
+    storage::check_name(&request.name)?;
+    // Z85 encodes a 32 byte key as 40 characters
+    if request.public_key.len() != 40 || z85_decode(&request.public_key).map(|k| k.len() != 32).unwrap_or(true) {
+        return Err(Error::InvalidCert);
+    }
+
+    // We never hold the secret key
+    let zcert = ZCert::from_txt(&request.public_key, "0000000000000000000000000000000000000000")?;
+    zcert.set_meta("name", &request.name);
+    zcert.set_meta("type", &request.cert_type);
+    zcert.set_meta(ISSUED_META, &expiry::now().to_string());
+    Cert::from_zcert(zcert)
+}
+
+// A new key pair for `old`, with its metadata, issued at `now`
+fn rekeyed(old: &Cert, now: u64) -> Result<Cert> {
+    let cert = Cert::new(old.name(), old.cert_type())?;
+    for key in old.meta_keys() {
+        if key == ISSUED_META {
+            continue;
+        }
+        if let Some(Ok(value)) = old.meta(key) {
+            cert.set_meta(key, &value);
+        }
+    }
+    cert.set_meta(ISSUED_META, &now.to_string());
+    Ok(cert)
+}
+
+fn random_id() -> String {
+    let hex: Vec<String> = randombytes(8).iter().map(|b| format!("{:02x}", b)).collect();
+    hex.join("")
+}
+
+fn masked_name(cert: &Cert, mask: ListMask) -> String {
+    match mask {
+        ListMask::Hash => {
+            let sha256::Digest(digest) = sha256::hash(cert.name().as_bytes());
+            let hex: Vec<String> = digest.iter().map(|b| format!("{:02x}", b)).collect();
+            hex.join("")
+        },
+        _ => cert.name().to_string(),
+    }
+}
+
+/// Collects cache health reports from client handlers. See `fleet`.
+pub struct FleetApi {
+    fleet: Rc<RefCell<FleetHealth>>,
+    tracer: WireTracer,
+}
+
+impl FleetApi {
+    pub fn new(fleet: Rc<RefCell<FleetHealth>>, tracer: WireTracer) -> FleetApi {
+        FleetApi {
+            fleet: fleet,
+            tracer: tracer,
+        }
+    }
+
+    pub fn report(&self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        let meta = RequestMeta::new(&endpoint_frame)?;
+        self.do_report(sock, router_id, &meta)
+    }
+
+    pub fn do_report(&self, sock: &mut ZSock, router_id: &[u8], meta: &RequestMeta) -> Result<()> {
+        let msg = protocol::FLEET_REPORT.recv(sock)?;
+        self.tracer.record(Direction::In, "api", &msg, &[]);
+        let json = msg.popstr().unwrap().or(Err(Error::InvalidArg))?;
+        let report = CacheReport::from_json(&json).or(Err(Error::InvalidArg))?;
+        self.fleet.borrow_mut().record(&meta.name, report, Instant::now());
+
+        let reply = ZMsg::new_ok()?;
+        request_id::address(&reply, router_id)?;
+        self.tracer.record(Direction::Out, "api", &reply, &[]);
+        reply.send(sock)?;
+        Ok(())
+    }
+}
+
+/// Answers `cert::expiring`. See `expiry`.
+pub struct ExpiryApi {
+    cert_cache: SharedCertCache,
+    warn_within: u64,
+    tracer: WireTracer,
+}
+
+impl ExpiryApi {
+    /// Requests that don't say how far ahead to look get certs
+    /// expiring within `warn_within` seconds.
+    pub fn new(cert_cache: SharedCertCache, warn_within: u64, tracer: WireTracer) -> ExpiryApi {
+        ExpiryApi {
+            cert_cache: cert_cache,
+            warn_within: warn_within,
+            tracer: tracer,
+        }
+    }
+
+    pub fn expiring(&self, sock: &mut ZSock, endpoint_frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        // Only users can list expiring certificates
+        let meta = RequestMeta::new(&endpoint_frame)?;
+        if meta.cert_type != CertType::User {
+            return Err(Error::Forbidden);
+        }
+
+        self.do_expiring(sock, router_id)
+    }
+
+    /// Reply with the expiring certs as a JSON array, soonest first.
+    // Allow callers that authenticate out of band (e.g. tests and
+    // the admin socket)
+    pub fn do_expiring(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let msg = protocol::CERT_EXPIRING.recv(sock)?;
+        self.tracer.record(Direction::In, "api", &msg, &[]);
+        let within = match msg.popstr() {
+            Some(Ok(s)) => s.parse().or(Err(Error::InvalidArg))?,
+            Some(Err(_)) => return Err(Error::InvalidArg),
+            None => self.warn_within,
+        };
+
+        let certs = expiry::expiring(&self.cert_cache.read(), within, expiry::now());
+        let reply = ZMsg::new_ok()?;
+        request_id::address(&reply, router_id)?;
+        reply.addstr(&serde_json::to_string(&certs)?)?;
+        self.tracer.record(Direction::Out, "api", &reply, &[]);
+        reply.send(sock)?;
+        Ok(())
+    }
+}
+
+/// Answers `server::info`, which describes this server so that
+/// clients can choose between replicas.
+pub struct InfoApi {
+    feed_endpoint: Option<String>,
+    affinity_tags: Vec<String>,
+    cert_cache: SharedCertCache,
+    feed_stats: Rc<RefCell<FeedStats>>,
+    started: Instant,
+    tracer: WireTracer,
+}
+
+#[derive(Serialize)]
+struct ServerInfo<'a> {
+    version: &'static str,
+    feed_endpoint: Option<&'a str>,
+    affinity_tags: &'a [String],
+    draining: bool,
+    sequence: u64,
+    uptime_secs: u64,
+    certs: CertCounts,
+}
+
+#[derive(Serialize)]
+struct CertCounts {
+    hosts: usize,
+    users: usize,
+}
+
+impl InfoApi {
+    pub fn new(feed_endpoint: Option<String>, affinity_tags: Vec<String>, cert_cache: SharedCertCache, feed_stats: Rc<RefCell<FeedStats>>, tracer: WireTracer) -> InfoApi {
+        InfoApi {
+            feed_endpoint: feed_endpoint,
+            affinity_tags: affinity_tags,
+            cert_cache: cert_cache,
+            feed_stats: feed_stats,
+            started: Instant::now(),
+            tracer: tracer,
+        }
+    }
+
+    /// Reply with a JSON object describing the server.
+    pub fn info(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let stats = self.feed_stats.borrow();
+        let cache = self.cert_cache.read();
+        let info = ServerInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            feed_endpoint: self.feed_endpoint.as_ref().map(|e| e.as_str()),
+            affinity_tags: &self.affinity_tags,
+            draining: stats.draining,
+            sequence: stats.sequence,
+            uptime_secs: self.started.elapsed().as_secs(),
+            certs: CertCounts {
+                hosts: cache.dump(CertType::Host).len(),
+                users: cache.dump(CertType::User).len(),
+            },
+        };
+
+        let reply = ZMsg::new_ok()?;
+        request_id::address(&reply, router_id)?;
+        reply.addstr(&serde_json::to_string(&info)?)?;
+        self.tracer.record(Direction::Out, "api", &reply, &[]);
+        reply.send(sock)?;
+        Ok(())
+    }
+
+    /// Reply with a JSON description of the API's endpoints and the
+    /// protocol versions this server speaks. See `protocol::describe()`.
+    pub fn describe(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let reply = ZMsg::new_ok()?;
+        request_id::address(&reply, router_id)?;
+        reply.addstr(&serde_json::to_string(&protocol::describe())?)?;
+        self.tracer.record(Direction::Out, "api", &reply, &[]);
+        reply.send(sock)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType, EXPIRES_META};
+    use cert_cache::CertCache;
+    use config::{CertQuota, ListMask, RotationPolicy};
+    use czmq::{ZCert, ZMsg, ZSock, ZSys};
+    use expiry;
+    use std::cell::RefCell;
+    use request_id::{self, RequestId};
+    use std::collections::HashMap;
+    use serde_json;
+    use std::fs::File;
+    use std::rc::Rc;
+    use storage::{CertRequest, PersistenceAdaptor, PersistDisk};
+    use super::*;
+    use tempdir::TempDir;
+    use wire_trace::{decode, TraceFrame, WireTracer};
+    use zdaemon::ZMsgExtended;
+
+    #[test]
+    fn test_list() {
+        ZSys::init();
+
+        let host = Cert::new("luke.jedi.org", CertType::Host).unwrap();
+        let user = Cert::new("luke_vader", CertType::User).unwrap();
+        let (_dir, mut api) = create_api(">inproc://api_test_list_publisher", Some(vec![&host, &user]));
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+
+        let meta = RequestMeta {
+            name: "test".into(),
+            cert_type: CertType::User,
+            domain: None,
+            role: None,
+        };
+
+        client.send_str("user").unwrap();
+        api.do_list(&mut server, b"router_id", &meta).unwrap();
+
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "luke_vader");
+
+        client.send_str("host").unwrap();
+        api.do_list(&mut server, b"router_id", &meta).unwrap();
+
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "luke.jedi.org");
+    }
+
+    #[test]
+    fn test_resync() {
+        ZSys::init();
+
+        let host = Cert::new("luke.jedi.org", CertType::Host).unwrap();
+        let user = Cert::new("luke_vader", CertType::User).unwrap();
+        let (_dir, mut api) = create_api(">inproc://api_test_resync_publisher", Some(vec![&host, &user]));
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+
+        client.send_str("host").unwrap();
+        api.resync(&mut server, b"router_id", 7).unwrap();
+
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        let mut cache = CertCache::new(None);
+        assert_eq!(cache.apply_snapshot(Some(CertType::Host), &reply).unwrap(), Some(1));
+        assert!(cache.get_name("luke.jedi.org").is_some());
+        assert_eq!(cache.last_sequence(), Some(7));
+
+        client.send_str("").unwrap();
+        api.resync(&mut server, b"router_id", 8).unwrap();
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.size(), 8);
+
+        client.send_str("keys:host").unwrap();
+        assert!(api.resync(&mut server, b"router_id", 8).is_err());
+    }
+
+    #[test]
+    fn test_list_masking() {
+        ZSys::init();
+
+        let host = Cert::new("luke.jedi.org", CertType::Host).unwrap();
+        let user = Cert::new("luke_vader", CertType::User).unwrap();
+        let (_dir, mut api) = create_api(">inproc://api_test_list_masking_publisher", Some(vec![&host, &user]));
+
+        let mut host_rules = HashMap::new();
+        host_rules.insert("user".to_string(), ListMask::Omit);
+        host_rules.insert("host".to_string(), ListMask::Hash);
+        api.list_masking.insert("host".to_string(), host_rules);
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+
+        let mut meta = RequestMeta {
+            name: "luke.jedi.org".into(),
+            cert_type: CertType::Host,
+            domain: None,
+            role: None,
+        };
+
+        client.send_str("user").unwrap();
+        api.do_list(&mut server, b"router_id", &meta).unwrap();
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.size(), 3);
+
+        client.send_str("host").unwrap();
+        api.do_list(&mut server, b"router_id", &meta).unwrap();
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.size(), 4);
+        let name = reply.popstr().unwrap().unwrap();
+        assert_eq!(name.len(), 64);
+        assert!(name != "luke.jedi.org");
+
+        meta.role = Some("admin".into());
+        client.send_str("user").unwrap();
+        api.do_list(&mut server, b"router_id", &meta).unwrap();
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "luke_vader");
+    }
+
+    #[test]
+    fn test_search() {
+        ZSys::init();
+
+        let web = Cert::new("web1.example.com", CertType::Host).unwrap();
+        web.set_meta("env", "prod");
+        let db = Cert::new("db1.example.com", CertType::Host).unwrap();
+        db.set_meta("env", "dev");
+        let user = Cert::new("han", CertType::User).unwrap();
+        let (_dir, mut api) = create_api(">inproc://api_test_search_publisher", Some(vec![&web, &db, &user]));
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+        let meta = RequestMeta {
+            name: "test".into(),
+            cert_type: CertType::User,
+            domain: None,
+            role: None,
+        };
+
+        client.send_str("type=host AND env=prod").unwrap();
+        api.do_search(&mut server, b"router_id", &meta).unwrap();
+
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.size(), 4);
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "web1.example.com");
+
+        client.send_str("type=").unwrap();
+        assert!(api.do_search(&mut server, b"router_id", &meta).is_err());
+
+        // Hashed names can't be matched on the names they hide
+        let mut user_rules = HashMap::new();
+        user_rules.insert("host".to_string(), ListMask::Hash);
+        api.list_masking.insert("user".to_string(), user_rules);
+        for filter in &["name~web*", "env=prod"] {
+            client.send_str(filter).unwrap();
+            api.do_search(&mut server, b"router_id", &meta).unwrap();
+            let reply = ZMsg::recv(&mut client).unwrap();
+            assert_eq!(reply.size(), 3);
+        }
+        client.send_str("type=host").unwrap();
+        api.do_search(&mut server, b"router_id", &meta).unwrap();
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.size(), 5);
+    }
+
+    #[test]
+    fn test_lookup() {
+        ZSys::init();
+
+        let cert = Cert::new("r2d2", CertType::Host).unwrap();
+        let (_dir, mut api) = create_api(">inproc://api_test_lookup_publisher", Some(vec![&cert]));
+
+        let mut client = ZSock::new_req("inproc://api_test_lookup").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_lookup").unwrap();
+        let meta = RequestMeta {
+            name: "luke".into(),
+            cert_type: CertType::User,
+            domain: None,
+            role: None,
+        };
+
+        client.send_str("Han Solo").unwrap();
+        assert!(api.do_lookup(&mut server, b"router_id", &meta).is_err());
+        server.send_str("").unwrap();
+        client.recv_str().unwrap().unwrap();
+
+        client.send_str("r2d2").unwrap();
+        assert!(api.do_lookup(&mut server, b"router_id", &meta).is_ok());
+
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        assert_eq!(reply.popstr().unwrap().unwrap(), cert.public_txt());
+    }
+
+    #[test]
+    fn test_info() {
+        ZSys::init();
+
+        let stats = Rc::new(RefCell::new(FeedStats::default()));
+        stats.borrow_mut().sequence = 12;
+        let host = Cert::new("luke.jedi.org", CertType::Host).unwrap();
+        let cache = SharedCertCache::new(CertCache::new(Some(vec![host])));
+        let api = InfoApi::new(Some("tcp://auth1.example.com:7102".into()), vec!["eu-west".into()], cache, stats, WireTracer::disabled());
+
+        let mut client = ZSock::new_req("inproc://api_test_info").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_info").unwrap();
+        client.send_str("server::info").unwrap();
+        server.recv_str().unwrap().unwrap();
+        api.info(&mut server, b"router_id").unwrap();
+
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        let info: serde_json::Value = serde_json::from_str(&reply.popstr().unwrap().unwrap()).unwrap();
+        assert_eq!(info["feed_endpoint"], "tcp://auth1.example.com:7102");
+        assert_eq!(info["affinity_tags"][0], "eu-west");
+        assert_eq!(info["draining"].as_bool(), Some(false));
+        assert_eq!(info["sequence"], 12);
+        assert_eq!(info["uptime_secs"], 0);
+        assert_eq!(info["certs"]["hosts"], 1);
+        assert_eq!(info["certs"]["users"], 0);
+    }
+
+    #[test]
+    fn test_expiring() {
+        ZSys::init();
+
+        let host = Cert::new("luke.jedi.org", CertType::Host).unwrap();
+        host.set_meta(EXPIRES_META, &(expiry::now() + 3600).to_string());
+        let cache = SharedCertCache::new(CertCache::new(Some(vec![host])));
+        let api = ExpiryApi::new(cache, 60, WireTracer::disabled());
+
+        let mut client = ZSock::new_req("inproc://api_test_expiring").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_expiring").unwrap();
+        let expiring = |within: &str, client: &mut ZSock, server: &mut ZSock| {
+            ZMsg::new().send_multi(client, &["cert::expiring", within]).unwrap();
+            server.recv_str().unwrap().unwrap();
+            api.do_expiring(server, b"router_id").unwrap();
+
+            let reply = ZMsg::recv(client).unwrap();
+            assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+            assert_eq!(reply.popstr().unwrap().unwrap(), "");
+            assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+            let certs: serde_json::Value = serde_json::from_str(&reply.popstr().unwrap().unwrap()).unwrap();
+            certs.as_array().unwrap().len()
+        };
+
+        assert_eq!(expiring("60", &mut client, &mut server), 0);
+        assert_eq!(expiring("7200", &mut client, &mut server), 1);
+    }
+
+    #[test]
+    fn test_fleet_report() {
+        ZSys::init();
+
+        let fleet = Rc::new(RefCell::new(FleetHealth::new()));
+        let api = FleetApi::new(fleet.clone(), WireTracer::disabled());
+        let meta = RequestMeta { name: "web1".into(), cert_type: CertType::Host, domain: None, role: None };
+
+        let mut client = ZSock::new_req("inproc://api_test_fleet_report").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_fleet_report").unwrap();
+        client.send_str("{\"sequence\": 4, \"cache_size\": 2, \"last_update_age_secs\": null}").unwrap();
+        api.do_report(&mut server, b"router_id", &meta).unwrap();
+
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+
+        let summary = fleet.borrow().summary(6, Instant::now());
+        assert_eq!(summary.len(), 1);
+        assert_eq!(summary[0].name, "web1");
+        assert_eq!(summary[0].sequence_lag, Some(2));
+
+        client.send_str("{\"cache_size\": \"lots\"}").unwrap();
+        assert!(api.do_report(&mut server, b"router_id", &meta).is_err());
+    }
+
+    #[test]
+    fn test_describe() {
+        ZSys::init();
+
+        let api = InfoApi::new(None, Vec::new(), SharedCertCache::new(CertCache::new(None)), Rc::new(RefCell::new(FeedStats::default())), WireTracer::disabled());
+
+        let mut client = ZSock::new_req("inproc://api_test_describe").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_describe").unwrap();
+        client.send_str("api::describe").unwrap();
+        server.recv_str().unwrap().unwrap();
+        api.describe(&mut server, b"router_id").unwrap();
+
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        let desc: serde_json::Value = serde_json::from_str(&reply.popstr().unwrap().unwrap()).unwrap();
+        assert_eq!(desc["api_version"], 1);
+        assert_eq!(desc["zap_version"], "1.0");
+        let list = desc["endpoints"].as_array().unwrap().iter().find(|e| e["name"] == "cert::list").unwrap();
+        assert_eq!(list["request"][0]["name"], "cert_type");
+    }
+
+    #[test]
+    fn test_create() {
+        ZSys::init();
+
+        let (_dir, mut api) = create_api(">inproc://api_test_create_publisher", None);
+
+        let mut subscriber = ZSock::new_sub("@inproc://api_test_create_publisher", Some("host")).unwrap();
+        let mut client = ZSock::new_req("inproc://api_test_create").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_create").unwrap();
+
+        let msg = ZMsg::new();
+        msg.send_multi(&mut client, &["host", "usetheforks.com"]).unwrap();
+        let meta = RequestMeta {
+            name: "test".into(),
+            cert_type: CertType::User,
+            domain: None,
+            role: None,
+        };
+        api.do_create(&mut server, b"router_id", &meta).unwrap();
+
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.size(), 6);
+        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        let pubkey = reply.popstr().unwrap().unwrap();
+
+        let sub_reply = ZMsg::recv(&mut subscriber).unwrap();
+        sub_reply.popstr().unwrap().unwrap(); // Remove topic frame
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), "ADD");
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), pubkey);
+    }
+
+    #[test]
+    fn test_create_typed() {
+        ZSys::init();
+
+        let (_dir, mut api) = create_api(">inproc://api_test_create_typed_publisher", None);
+
+        let mut client = ZSock::new_req("inproc://api_test_create_typed").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_create_typed").unwrap();
+
+        let msg = ZMsg::new();
+        msg.send_multi(&mut client, &[&protocol::typed_marker(), r#"{"type":"host","name":"usetheforks.com"}"#]).unwrap();
+        let meta = RequestMeta {
+            name: "test".into(),
+            cert_type: CertType::User,
+            domain: None,
+            role: None,
+        };
+        api.do_create(&mut server, b"router_id", &meta).unwrap();
+
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.size(), 4);
+        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        let json: serde_json::Value = serde_json::from_str(&reply.popstr().unwrap().unwrap()).unwrap();
+        assert_eq!(json["metadata"]["name"], serde_json::Value::String("usetheforks.com".into()));
+        assert!(json["secret_key"].is_string());
+    }
+
+    #[test]
+    fn test_create_trace_redacted() {
+        ZSys::init();
+
+        let (dir, mut api) = create_api(">inproc://api_test_create_trace_redacted_publisher", None);
+        let trace_path = dir.path().join("trace.bin");
+        api.tracer = WireTracer::new(&trace_path).unwrap();
+
+        let mut client = ZSock::new_req("inproc://api_test_create_trace_redacted").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_create_trace_redacted").unwrap();
+
+        let msg = ZMsg::new();
+        msg.send_multi(&mut client, &["host", "usetheforks.com"]).unwrap();
+        let meta = RequestMeta {
+            name: "test".into(),
+            cert_type: CertType::User,
+            domain: None,
+            role: None,
+        };
+        let id = RequestId::from_frame(b"rid:trace-1").unwrap().unwrap();
+        request_id::with_current(id, || api.do_create(&mut server, b"router_id", &meta)).unwrap();
+
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "rid:trace-1");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        let public = reply.popstr().unwrap().unwrap();
+        let secret = reply.popstr().unwrap().unwrap();
+
+        let records = decode(&mut File::open(&trace_path).unwrap()).unwrap();
+        let out = &records[1];
+        assert_eq!(out.frames[4], TraceFrame::Data(public.into_bytes()));
+        assert_eq!(out.frames[5], TraceFrame::Redacted(secret.len()));
+        assert!(!out.frames.contains(&TraceFrame::Data(secret.into_bytes())));
+    }
+
+    #[test]
+    fn test_delete() {
+        ZSys::init();
+
+        let cert = Cert::new("c3po", CertType::Host).unwrap();
+        let (_dir, mut api) = create_api(">inproc://api_test_delete_publisher", Some(vec![&cert]));
+
+        let mut subscriber = ZSock::new_sub("@inproc://api_test_delete_publisher", Some("host")).unwrap();
+        let mut client = ZSock::new_req("inproc://api_test_delete").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_delete").unwrap();
+        let meta = RequestMeta {
+            name: "luke".into(),
+            cert_type: CertType::User,
+            domain: None,
+            role: None,
+        };
+
+        client.send_str("Han Solo's Millenium Falcon Ignition Key").unwrap();
+        assert!(api.do_delete(&mut server, b"router_id", &meta).is_err());
+        server.send_str("").unwrap();
+        client.recv_str().unwrap().unwrap();
+
+        client.send_str("c3po").unwrap();
+        assert!(api.do_delete(&mut server, b"router_id", &meta).is_ok());
+
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+
+        let sub_reply = ZMsg::recv(&mut subscriber).unwrap();
+        sub_reply.popstr().unwrap().unwrap(); // Remove topic frame
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), "DEL");
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), cert.public_txt());
+    }
+
+    #[test]
+    fn test_rotate() {
+        ZSys::init();
+
+        let cert = Cert::new("bb8", CertType::Host).unwrap();
+        cert.set_meta("env", "prod");
+        let (_dir, mut api) = create_api(">inproc://api_test_rotate_publisher", Some(vec![&cert]));
+
+        let mut subscriber = ZSock::new_sub("@inproc://api_test_rotate_publisher", Some("host")).unwrap();
+        let mut client = ZSock::new_req("inproc://api_test_rotate").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_rotate").unwrap();
+        let meta = RequestMeta {
+            name: "luke".into(),
+            cert_type: CertType::User,
+            domain: None,
+            role: None,
+        };
+
+        client.send_str("bb8").unwrap();
+        api.do_rotate(&mut server, b"router_id", &meta).unwrap();
+
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        let pubkey = reply.popstr().unwrap().unwrap();
+        let secret = reply.popstr().unwrap().unwrap();
+        assert!(pubkey != cert.public_txt());
+        let rotated = ZCert::from_txt(&pubkey, &secret).unwrap();
+        rotated.decode_meta(&reply.popbytes().unwrap().unwrap()).unwrap();
+        assert_eq!(rotated.meta("env").unwrap().unwrap(), "prod");
+        assert_eq!(api.persistence.read("bb8").unwrap().public_txt(), pubkey);
+
+        // The old key goes before the new one arrives
+        let sub_reply = ZMsg::recv(&mut subscriber).unwrap();
+        sub_reply.popstr().unwrap().unwrap(); // Remove topic frame
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), "DEL");
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), cert.public_txt());
+        let sub_reply = ZMsg::recv(&mut subscriber).unwrap();
+        sub_reply.popstr().unwrap().unwrap(); // Remove topic frame
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), "ADD");
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), pubkey);
+    }
+
+    #[test]
+    fn test_scheduled_rotation() {
+        ZSys::init();
+
+        let cert = Cert::new("r2d2", CertType::Host).unwrap();
+        let (_dir, mut api) = create_api(">inproc://api_test_scheduled_rotation_publisher", Some(vec![&cert]));
+        let mut policies = HashMap::new();
+        policies.insert(CertType::Host, RotationPolicy { every_days: 90, grace_days: 7 });
+        api.rotations = Rotations::new(policies);
+
+        let mut subscriber = ZSock::new_sub("@inproc://api_test_scheduled_rotation_publisher", Some("host")).unwrap();
+        let mut client = ZSock::new_req("inproc://api_test_scheduled_rotation").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_scheduled_rotation").unwrap();
+
+        // Without an issue time, the cert counts as issued at the
+        // first check
+        api.run_rotations(1000).unwrap();
+        assert_eq!(api.persistence.read("r2d2").unwrap().issued_at(), Some(1000));
+
+        let due = 1000 + 90 * 24 * 60 * 60;
+        api.run_rotations(due).unwrap();
+        let sub_reply = ZMsg::recv(&mut subscriber).unwrap();
+        sub_reply.popstr().unwrap().unwrap(); // Remove topic frame
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), "ADD");
+        let pubkey = sub_reply.popstr().unwrap().unwrap();
+        assert!(pubkey != cert.public_txt());
+        assert_eq!(api.persistence.read("r2d2").unwrap().public_txt(), cert.public_txt());
+
+        let meta = RequestMeta {
+            name: "r2d2".into(),
+            cert_type: CertType::Host,
+            domain: None,
+            role: None,
+        };
+        client.send_str("").unwrap();
+        server.recv_str().unwrap().unwrap();
+        api.do_pending_rotation(&mut server, b"router_id", &meta).unwrap();
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        assert_eq!(reply.popstr().unwrap().unwrap(), pubkey);
+        reply.popstr().unwrap().unwrap(); // Secret key
+        reply.popbytes().unwrap().unwrap(); // Metadata
+        assert_eq!(reply.popstr().unwrap().unwrap(), (due + 7 * 24 * 60 * 60).to_string());
+
+        // The old key is retired once the grace window ends
+        api.run_rotations(due + 7 * 24 * 60 * 60).unwrap();
+        assert_eq!(api.persistence.read("r2d2").unwrap().public_txt(), pubkey);
+        let sub_reply = ZMsg::recv(&mut subscriber).unwrap();
+        sub_reply.popstr().unwrap().unwrap(); // Remove topic frame
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), "DEL");
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), cert.public_txt());
+        assert!(api.persistence.read_rotation_keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_abandoned_rotation() {
+        ZSys::init();
+
+        let cert = Cert::new("c3po", CertType::Host).unwrap();
+        cert.set_meta(ISSUED_META, "0");
+        let (_dir, mut api) = create_api(">inproc://api_test_abandoned_rotation_publisher", Some(vec![&cert]));
+        let mut policies = HashMap::new();
+        policies.insert(CertType::Host, RotationPolicy { every_days: 90, grace_days: 7 });
+        api.rotations = Rotations::new(policies.clone());
+
+        let mut subscriber = ZSock::new_sub("@inproc://api_test_abandoned_rotation_publisher", Some("host")).unwrap();
+        subscriber.set_rcvtimeo(Some(500));
+
+        let due = 90 * 24 * 60 * 60;
+        api.run_rotations(due).unwrap();
+        let sub_reply = ZMsg::recv(&mut subscriber).unwrap();
+        sub_reply.popstr().unwrap().unwrap(); // Remove topic frame
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), "ADD");
+        let abandoned = sub_reply.popstr().unwrap().unwrap();
+        assert_eq!(api.persistence.read_rotation_keys().unwrap()[0].public_key, abandoned);
+
+        // A restart loses the pending rotation, so its key is withdrawn
+        // before the rotation starts afresh
+        api.rotations = Rotations::new(policies);
+        api.run_rotations(due + 60).unwrap();
+        let sub_reply = ZMsg::recv(&mut subscriber).unwrap();
+        sub_reply.popstr().unwrap().unwrap(); // Remove topic frame
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), "DEL");
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), abandoned);
+        let sub_reply = ZMsg::recv(&mut subscriber).unwrap();
+        sub_reply.popstr().unwrap().unwrap(); // Remove topic frame
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), "ADD");
+        let pubkey = sub_reply.popstr().unwrap().unwrap();
+        assert!(pubkey != abandoned);
+        let keys = api.persistence.read_rotation_keys().unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].public_key, pubkey);
+    }
+
+    #[test]
+    fn test_revoke() {
+        ZSys::init();
+
+        let cert = Cert::new("k2so", CertType::Host).unwrap();
+        let (_dir, mut api) = create_api(">inproc://api_test_revoke_publisher", Some(vec![&cert]));
+
+        let mut subscriber = ZSock::new_sub("@inproc://api_test_revoke_publisher", Some("host")).unwrap();
+        let mut client = ZSock::new_req("inproc://api_test_revoke").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_revoke").unwrap();
+
+        client.send_str("k2so").unwrap();
+        api.do_revoke(&mut server, b"router_id").unwrap();
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.size(), 3);
+        assert!(api.persistence.read("k2so").unwrap().is_revoked());
+
+        let sub_reply = ZMsg::recv(&mut subscriber).unwrap();
+        sub_reply.popstr().unwrap().unwrap(); // Remove topic frame
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), "UPDATE");
+        assert_eq!(sub_reply.popstr().unwrap().unwrap(), cert.public_txt());
+
+        // A revoked cert can't be given a fresh key
+        client.send_str("k2so").unwrap();
+        let meta = RequestMeta {
+            name: "luke".into(),
+            cert_type: CertType::User,
+            domain: None,
+            role: None,
+        };
+        assert!(api.do_rotate(&mut server, b"router_id", &meta).is_err());
+    }
+
+    #[test]
+    fn test_manage_permissions() {
+        ZSys::init();
+
+        let web1 = Cert::new("web1", CertType::Host).unwrap();
+        web1.set_meta("domain", "rebels");
+        let web2 = Cert::new("web2", CertType::Host).unwrap();
+        web2.set_meta("domain", "empire");
+        let (_dir, mut api) = create_api(">inproc://api_test_manage_permissions_publisher", Some(vec![&web1, &web2]));
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+        let mut meta = RequestMeta {
+            name: "luke".into(),
+            cert_type: CertType::User,
+            domain: Some("rebels".into()),
+            role: None,
+        };
+
+        // Users in a domain can only touch certs in that domain
+        client.send_str("web2").unwrap();
+        match api.do_delete(&mut server, b"router_id", &meta) {
+            Err(Error::Forbidden) => (),
+            r => panic!("Expected Forbidden, got {:?}", r),
+        }
+        client.send_str("web2").unwrap();
+        match api.do_rotate(&mut server, b"router_id", &meta) {
+            Err(Error::Forbidden) => (),
+            r => panic!("Expected Forbidden, got {:?}", r),
+        }
+        client.send_str("web1").unwrap();
+        api.do_rotate(&mut server, b"router_id", &meta).unwrap();
+        ZMsg::recv(&mut client).unwrap();
+
+        // ...unless they're admins
+        meta.role = Some(ADMIN_ROLE.into());
+        client.send_str("web2").unwrap();
+        api.do_delete(&mut server, b"router_id", &meta).unwrap();
+        ZMsg::recv(&mut client).unwrap();
+
+        // Hosts can't change certs at all
+        let host = RequestMeta {
+            name: "web1".into(),
+            cert_type: CertType::Host,
+            domain: None,
+            role: None,
+        };
+        client.send_str("web1").unwrap();
+        match api.do_delete(&mut server, b"router_id", &host) {
+            Err(Error::Forbidden) => (),
+            r => panic!("Expected Forbidden, got {:?}", r),
+        }
+        assert!(api.persistence.read("web1").is_ok());
+
+        // Nor look up the names they can't list
+        let mut host_rules = HashMap::new();
+        host_rules.insert("host".to_string(), ListMask::Hash);
+        api.list_masking.insert("host".to_string(), host_rules);
+        client.send_str("web1").unwrap();
+        assert!(api.do_lookup(&mut server, b"router_id", &host).is_err());
+    }
+
+    #[test]
+    fn test_push() {
+        ZSys::init();
+
+        let cert = Cert::new("r2d2", CertType::Host).unwrap();
+        let (_dir, mut api) = create_api(">inproc://api_test_push_publisher", Some(vec![&cert]));
+
+        let mut subscriber = ZSock::new_sub("@inproc://api_test_push_publisher", Some("host")).unwrap();
+        subscriber.set_subscribe("@web10/");
+        subscriber.set_rcvtimeo(Some(500));
+        let mut client = ZSock::new_req("inproc://api_test_push").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_push").unwrap();
+
+        client.send_str("nonexistent").unwrap();
+        assert!(api.do_push(&mut server, b"router_id").is_err());
+        server.send_str("").unwrap();
+        client.recv_str().unwrap().unwrap();
+
+        client.send_str("r2d2").unwrap();
+        api.do_push(&mut server, b"router_id").unwrap();
+        ZMsg::recv(&mut client).unwrap();
+
+        let msg = ZMsg::recv(&mut subscriber).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "host");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "ADD");
+        assert_eq!(msg.popstr().unwrap().unwrap(), cert.public_txt());
+
+        // Pushes to web1 aren't for web10, so only the second arrives
+        for name in &["web1", "web10"] {
+            let msg = ZMsg::new();
+            msg.addstr("r2d2").unwrap();
+            msg.addstr(name).unwrap();
+            msg.send(&mut client).unwrap();
+            api.do_push(&mut server, b"router_id").unwrap();
+            ZMsg::recv(&mut client).unwrap();
+        }
+
+        let msg = ZMsg::recv(&mut subscriber).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "@web10/");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "ADD");
+    }
+
+    #[test]
+    fn test_request() {
+        ZSys::init();
+
+        let existing = Cert::new("r2d2", CertType::Host).unwrap();
+        let (_dir, mut api) = create_api(">inproc://api_test_request_publisher", Some(vec![&existing]));
+
+        let mut subscriber = ZSock::new_sub("@inproc://api_test_request_publisher", Some("host")).unwrap();
+        subscriber.set_rcvtimeo(Some(500));
+        let mut client = ZSock::new_req("inproc://api_test_request").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_request").unwrap();
+        let meta = RequestMeta {
+            name: "luke".into(),
+            cert_type: CertType::User,
+            domain: None,
+            role: None,
+        };
+
+        let requested = Cert::new("x-wing", CertType::Host).unwrap();
+        let msg = ZMsg::new();
+        msg.send_multi(&mut client, &["host", "x-wing", requested.public_txt()]).unwrap();
+        api.do_request(&mut server, b"router_id", &meta).unwrap();
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "router_id");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        let id = reply.popstr().unwrap().unwrap();
+
+        // Names already issued or requested are refused
+        for name in &["r2d2", "x-wing"] {
+            let other = Cert::new(name, CertType::Host).unwrap();
+            let msg = ZMsg::new();
+            msg.send_multi(&mut client, &["host", name, other.public_txt()]).unwrap();
+            assert!(api.do_request(&mut server, b"router_id", &meta).is_err());
+            server.send_str("").unwrap();
+            client.recv_str().unwrap().unwrap();
+        }
+
+        // As are bad keys, and names that aren't safe to store
+        let other = Cert::new("y-wing", CertType::Host).unwrap();
+        for &(name, key) in &[("y-wing", "not a key"), ("y-wing", "0000000000000000000000000000000000000000!"), ("../y-wing", other.public_txt())] {
+            let msg = ZMsg::new();
+            msg.send_multi(&mut client, &["host", name, key]).unwrap();
+            assert!(api.do_request(&mut server, b"router_id", &meta).is_err());
+            server.send_str("").unwrap();
+            client.recv_str().unwrap().unwrap();
+        }
+
+        api.do_pending_list(&mut server, b"router_id").unwrap();
+        let reply = ZMsg::recv(&mut client).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        let pending: Vec<CertRequest> = serde_json::from_str(&reply.popstr().unwrap().unwrap()).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, id);
+        assert_eq!(pending[0].requested_by, "luke");
+
+        client.send_str("nonexistent").unwrap();
+        assert!(api.do_approve(&mut server, b"router_id").is_err());
+        server.send_str("").unwrap();
+        client.recv_str().unwrap().unwrap();
+
+        client.send_str(&id).unwrap();
+        api.do_approve(&mut server, b"router_id").unwrap();
+        let reply = ZMsg::recv(&mut client).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "x-wing");
+
+        let msg = ZMsg::recv(&mut subscriber).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "host");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "ADD");
+        assert_eq!(msg.popstr().unwrap().unwrap(), requested.public_txt());
+        assert_eq!(api.persistence.read("x-wing").unwrap().public_txt(), requested.public_txt());
+        assert!(api.persistence.read_requests().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deny() {
+        ZSys::init();
+
+        let (_dir, mut api) = create_api(">inproc://api_test_deny_publisher", None);
+        let mut client = ZSock::new_req("inproc://api_test_deny").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_deny").unwrap();
+        let meta = RequestMeta {
+            name: "luke".into(),
+            cert_type: CertType::User,
+            domain: None,
+            role: None,
+        };
+
+        let requested = Cert::new("tie-fighter", CertType::Host).unwrap();
+        let msg = ZMsg::new();
+        msg.send_multi(&mut client, &["host", "tie-fighter", requested.public_txt()]).unwrap();
+        api.do_request(&mut server, b"router_id", &meta).unwrap();
+        let reply = ZMsg::recv(&mut client).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        let id = reply.popstr().unwrap().unwrap();
+
+        client.send_str(&id).unwrap();
+        api.do_deny(&mut server, b"router_id").unwrap();
+        ZMsg::recv(&mut client).unwrap();
+        assert!(api.persistence.read_requests().unwrap().is_empty());
+        assert!(api.persistence.read("tie-fighter").is_err());
+    }
+
+    #[test]
+    fn test_request_limit() {
+        ZSys::init();
+
+        let (_dir, mut api) = create_api(">inproc://api_test_request_limit_publisher", None);
+        let mut client = ZSock::new_req("inproc://api_test_request_limit").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_request_limit").unwrap();
+        let meta = RequestMeta {
+            name: "luke".into(),
+            cert_type: CertType::User,
+            domain: None,
+            role: None,
+        };
+
+        for i in 0..MAX_PENDING_REQUESTS + 1 {
+            let name = format!("x-wing{}", i);
+            let requested = Cert::new(&name, CertType::Host).unwrap();
+            let msg = ZMsg::new();
+            msg.send_multi(&mut client, &["host", &name, requested.public_txt()]).unwrap();
+            if i < MAX_PENDING_REQUESTS {
+                api.do_request(&mut server, b"router_id", &meta).unwrap();
+                ZMsg::recv(&mut client).unwrap();
+            } else {
+                match api.do_request(&mut server, b"router_id", &meta) {
+                    Err(Error::QuotaExceeded(_)) => (),
+                    _ => panic!("Expected quota error"),
+                }
+                server.send_str("").unwrap();
+                client.recv_str().unwrap().unwrap();
+            }
+        }
+        assert_eq!(api.persistence.read_requests().unwrap().len(), MAX_PENDING_REQUESTS);
+    }
+
+    #[test]
+    fn test_approve_quota() {
+        ZSys::init();
+
+        let (_dir, mut api) = create_api(">inproc://api_test_approve_quota_publisher", None);
+        let mut quotas = HashMap::new();
+        quotas.insert("host".to_string(), CertQuota { max: 1, window_secs: 3600 });
+        api.quotas = CreateQuotas::new(Some(quotas));
+        let mut client = ZSock::new_req("inproc://api_test_approve_quota").unwrap();
+        let mut server = ZSock::new_rep("inproc://api_test_approve_quota").unwrap();
+        let meta = RequestMeta {
+            name: "luke".into(),
+            cert_type: CertType::User,
+            domain: None,
+            role: None,
+        };
+
+        let mut ids = Vec::new();
+        for name in &["x-wing", "y-wing"] {
+            let requested = Cert::new(name, CertType::Host).unwrap();
+            let msg = ZMsg::new();
+            msg.send_multi(&mut client, &["host", name, requested.public_txt()]).unwrap();
+            api.do_request(&mut server, b"router_id", &meta).unwrap();
+            let reply = ZMsg::recv(&mut client).unwrap();
+            reply.popstr().unwrap().unwrap();
+            reply.popstr().unwrap().unwrap();
+            reply.popstr().unwrap().unwrap();
+            ids.push(reply.popstr().unwrap().unwrap());
+        }
+
+        client.send_str(&ids[0]).unwrap();
+        api.do_approve(&mut server, b"router_id").unwrap();
+        ZMsg::recv(&mut client).unwrap();
+
+        // Luke's quota is used up, so the second request stays pending
+        client.send_str(&ids[1]).unwrap();
+        match api.do_approve(&mut server, b"router_id") {
+            Err(Error::QuotaExceeded(_)) => (),
+            _ => panic!("Expected quota error"),
+        }
+        server.send_str("").unwrap();
+        client.recv_str().unwrap().unwrap();
+        assert!(api.persistence.read("y-wing").is_err());
+        assert_eq!(api.persistence.read_requests().unwrap().len(), 1);
     }
 
     fn create_api(endpoint: &str, certs: Option<Vec<&Cert>>) -> (TempDir, CertApi<PersistDisk>) {
@@ -296,11 +1926,16 @@ mod tests {
             }
         }
 
-        let cert_cache = Rc::new(RefCell::new(CertCache::new(Some(disk.dump().unwrap()))));
+        let cert_cache = SharedCertCache::new(CertCache::new(Some(disk.dump().unwrap())));
         let api = CertApi {
             persistence: disk,
             publisher: ZSock::new_pub(endpoint).unwrap(),
             cert_cache: cert_cache,
+            tracer: WireTracer::disabled(),
+            list_masking: HashMap::new(),
+            request_notify: None,
+            quotas: CreateQuotas::new(None),
+            rotations: Rotations::new(HashMap::new()),
         };
         (dir, api)
     }
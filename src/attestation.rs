@@ -0,0 +1,272 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Signed attestations of the Auth server's cert set.
+//!
+//! The server periodically publishes the merkle root of every cert's
+//! name and public key, with the feed sequence and a timestamp, on the
+//! `attest` topic. It is signed with an Ed25519 key kept for this
+//! purpose, so anyone holding the public key can check that a replica
+//! or a copy of the store holds exactly the attested set.
+//!
+//! Leaves are `sha256(0x00 || name || 0x00 || pubkey)`, sorted by name,
+//! and each parent is `sha256(0x01 || left || right)`. An odd node is
+//! carried up to the next level unchanged.
+
+use czmq::ZMsg;
+use error::{Error, Result};
+use serde_json::{Map, Value};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::sign::{self, PublicKey, SecretKey, Seed, Signature};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+pub const ATTESTATION_TOPIC: &'static str = "attest";
+const SIGNING_CONTEXT: &'static [u8] = b"inauth-attestation-v1\0";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attestation {
+    /// Feed sequence when the attestation was made
+    pub sequence: u64,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub root: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+impl Attestation {
+    pub fn sign(root: [u8; 32], sequence: u64, timestamp: u64, key: &SecretKey) -> Attestation {
+        let sign::Signature(signature) = sign::sign_detached(&signed_bytes(&root, sequence, timestamp), key);
+        Attestation {
+            sequence: sequence,
+            timestamp: timestamp,
+            root: root,
+            signature: signature.to_vec(),
+        }
+    }
+
+    pub fn verify(&self, key: &PublicKey) -> bool {
+        match Signature::from_slice(&self.signature) {
+            Some(sig) => sign::verify_detached(&sig, &signed_bytes(&self.root, self.sequence, self.timestamp), key),
+            None => false,
+        }
+    }
+
+    /// Check the signature and that `entries` is the attested set.
+    pub fn check<'a, I>(&self, key: &PublicKey, entries: I) -> Result<()>
+        where I: IntoIterator<Item = (&'a str, &'a str)>
+    {
+        if !self.verify(key) {
+            return Err(Error::InvalidAttestation("bad signature".into()));
+        }
+        if merkle_root(entries) != self.root {
+            return Err(Error::InvalidAttestation("cert set does not match the attested root".into()));
+        }
+        Ok(())
+    }
+
+    /// The feed message: topic, "ATTEST", sequence, timestamp, root and
+    /// signature, the last two hex encoded.
+    pub fn to_msg(&self) -> Result<ZMsg> {
+        let msg = ZMsg::new();
+        try!(msg.addstr(ATTESTATION_TOPIC));
+        try!(msg.addstr("ATTEST"));
+        try!(msg.addstr(&self.sequence.to_string()));
+        try!(msg.addstr(&self.timestamp.to_string()));
+        try!(msg.addstr(&to_hex(&self.root)));
+        try!(msg.addstr(&to_hex(&self.signature)));
+        Ok(msg)
+    }
+
+    /// Parse the frames following the topic and action.
+    pub fn from_frames(frames: &[String]) -> Result<Attestation> {
+        if frames.len() != 4 {
+            return Err(Error::InvalidAttestation("wrong number of frames".into()));
+        }
+        Self::from_parts(&frames[0], &frames[1], &frames[2], &frames[3])
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut map = Map::new();
+        map.insert("sequence".into(), Value::from(self.sequence));
+        map.insert("timestamp".into(), Value::from(self.timestamp));
+        map.insert("root".into(), Value::String(to_hex(&self.root)));
+        map.insert("signature".into(), Value::String(to_hex(&self.signature)));
+        Value::Object(map)
+    }
+
+    pub fn from_json(value: &Value) -> Result<Attestation> {
+        let field = |name: &str| -> Result<String> {
+            match value.get(name) {
+                Some(&Value::String(ref s)) => Ok(s.clone()),
+                Some(&Value::Number(ref n)) => Ok(n.to_string()),
+                _ => Err(Error::InvalidAttestation(format!("missing {}", name))),
+            }
+        };
+        Self::from_parts(&try!(field("sequence")), &try!(field("timestamp")), &try!(field("root")), &try!(field("signature")))
+    }
+
+    fn from_parts(sequence: &str, timestamp: &str, root: &str, signature: &str) -> Result<Attestation> {
+        let invalid = |what: &str| Error::InvalidAttestation(format!("invalid {}", what));
+
+        let root_bytes = try!(from_hex(root).ok_or(invalid("root")));
+        if root_bytes.len() != 32 {
+            return Err(invalid("root"));
+        }
+        let mut root = [0; 32];
+        root.copy_from_slice(&root_bytes);
+
+        Ok(Attestation {
+            sequence: try!(sequence.parse().or(Err(invalid("sequence")))),
+            timestamp: try!(timestamp.parse().or(Err(invalid("timestamp")))),
+            root: root,
+            signature: try!(from_hex(signature).ok_or(invalid("signature"))),
+        })
+    }
+}
+
+fn signed_bytes(root: &[u8; 32], sequence: u64, timestamp: u64) -> Vec<u8> {
+    let mut bytes = SIGNING_CONTEXT.to_vec();
+    for shift in (0..8).rev() {
+        bytes.push((sequence >> (shift * 8)) as u8);
+    }
+    for shift in (0..8).rev() {
+        bytes.push((timestamp >> (shift * 8)) as u8);
+    }
+    bytes.extend_from_slice(root);
+    bytes
+}
+
+/// Merkle root of `(name, pubkey)` pairs, in any order.
+pub fn merkle_root<'a, I>(entries: I) -> [u8; 32]
+    where I: IntoIterator<Item = (&'a str, &'a str)>
+{
+    let mut entries: Vec<_> = entries.into_iter().collect();
+    entries.sort();
+
+    let mut level: Vec<[u8; 32]> = entries.iter().map(|&(name, pubkey)| {
+        let mut leaf = vec![0u8];
+        leaf.extend_from_slice(name.as_bytes());
+        leaf.push(0);
+        leaf.extend_from_slice(pubkey.as_bytes());
+        hash(&leaf)
+    }).collect();
+
+    if level.is_empty() {
+        return hash(&[]);
+    }
+
+    while level.len() > 1 {
+        level = level.chunks(2).map(|pair| {
+            if pair.len() == 1 {
+                pair[0]
+            } else {
+                let mut node = vec![1u8];
+                node.extend_from_slice(&pair[0]);
+                node.extend_from_slice(&pair[1]);
+                hash(&node)
+            }
+        }).collect();
+    }
+    level[0]
+}
+
+fn hash(bytes: &[u8]) -> [u8; 32] {
+    let sha256::Digest(digest) = sha256::hash(bytes);
+    digest
+}
+
+/// Load the signing key from `path`, which holds a hex encoded seed,
+/// creating it (readable by its owner only) if it doesn't exist.
+pub fn load_or_create_key<P: AsRef<Path>>(path: P) -> Result<(PublicKey, SecretKey)> {
+    let path = path.as_ref();
+    if !path.exists() {
+        let (_, sk) = sign::gen_keypair();
+        let mut fh = try!(OpenOptions::new().write(true).create_new(true).open(path));
+        try!(fs::set_permissions(path, fs::Permissions::from_mode(0o600)));
+        // The first half of an Ed25519 secret key is its seed
+        try!(writeln!(fh, "{}", to_hex(&sk.0[..32])));
+    }
+
+    load_key(path)
+}
+
+/// Load the signing key from `path`, which holds a hex encoded seed.
+pub fn load_key<P: AsRef<Path>>(path: P) -> Result<(PublicKey, SecretKey)> {
+    let mut hex = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut hex));
+    let seed = try!(from_hex(hex.trim()).and_then(|s| Seed::from_slice(&s)).ok_or(Error::InvalidAttestation("invalid signing key file".into())));
+    Ok(sign::keypair_from_seed(&seed))
+}
+
+pub fn public_key_to_hex(key: &PublicKey) -> String {
+    to_hex(&key.0)
+}
+
+pub fn public_key_from_hex(hex: &str) -> Result<PublicKey> {
+    from_hex(hex).and_then(|k| PublicKey::from_slice(&k)).ok_or(Error::InvalidAttestation("invalid public key".into()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use sodiumoxide::crypto::sign;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_merkle_root() {
+        let a = merkle_root(vec![("web1", "pk1"), ("web2", "pk2"), ("db1", "pk3")]);
+        let b = merkle_root(vec![("db1", "pk3"), ("web2", "pk2"), ("web1", "pk1")]);
+        assert_eq!(a, b);
+        assert!(a != merkle_root(vec![("web1", "pk1"), ("web2", "pk2")]));
+        assert!(a != merkle_root(vec![("web1", "pk1"), ("web2", "pkX"), ("db1", "pk3")]));
+        assert_eq!(merkle_root(vec![]), hash(&[]));
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let (pk, sk) = sign::gen_keypair();
+        let (other, _) = sign::gen_keypair();
+        let entries = vec![("web1", "pk1")];
+
+        let attestation = Attestation::sign(merkle_root(entries.clone()), 7, 1500000000, &sk);
+        assert!(attestation.check(&pk, entries.clone()).is_ok());
+        assert!(attestation.check(&other, entries.clone()).is_err());
+        assert!(attestation.check(&pk, vec![("web1", "pk2")]).is_err());
+
+        let mut tampered = attestation.clone();
+        tampered.sequence = 8;
+        assert!(!tampered.verify(&pk));
+
+        assert_eq!(Attestation::from_json(&attestation.to_json()).unwrap(), attestation);
+    }
+
+    #[test]
+    fn test_load_or_create_key() {
+        let dir = TempDir::new("attestation_test_key").unwrap();
+        let path = dir.path().join("attest.key");
+
+        let (pk, _) = load_or_create_key(&path).unwrap();
+        let (pk2, _) = load_or_create_key(&path).unwrap();
+        assert_eq!(pk, pk2);
+        assert_eq!(public_key_from_hex(&public_key_to_hex(&pk)).unwrap(), pk);
+    }
+}
@@ -0,0 +1,44 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Application-defined authorization for `ZapHandler`.
+
+use cert::Cert;
+
+/// The parts of a ZAP request an `AuthPolicy` can inspect.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZapRequestInfo<'a> {
+    pub domain: &'a str,
+    pub address: &'a str,
+    pub identity: &'a str,
+    /// "CURVE", "PLAIN" or "NULL"
+    pub mechanism: &'a str,
+    /// Public key for CURVE, username for PLAIN, empty for NULL
+    pub client_id: &'a str,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+/// Custom checks run by `ZapHandler` after its own, e.g. time windows
+/// or calls to an external service.
+///
+/// `cert` is the client's cert if the handler authenticated it, in
+/// which case `Deny` rejects the client. Otherwise it is `None`, and
+/// `Allow` admits the client anyway, named after its client ID. Return
+/// `Deny` for `None` unless you mean to let in clients the Auth server
+/// doesn't know about.
+///
+/// The policy runs on the ZAP worker thread, so slow checks hold up
+/// other authentication requests.
+pub trait AuthPolicy: Send {
+    fn authorize(&self, req: &ZapRequestInfo, cert: Option<&Cert>) -> Decision;
+}
@@ -0,0 +1,91 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Hooks into the feed and ZAP internals for the Criterion benchmarks
+//! in benches/. Not a stable API.
+//!
+//! Requires the `bench-support` feature.
+
+use cert::{Cert, CertType};
+use cert_cache::{snapshot_msg, CertCache, SnapshotFormat};
+use czmq::{ZCert, ZMsg, ZSock};
+use error::Result;
+use zap_handler::ZapHandler;
+
+/// `n` new certs of `cert_type`, named e.g. host0, host1...
+pub fn certs(cert_type: CertType, n: usize) -> Result<Vec<Cert>> {
+    (0..n).map(|i| Cert::new(&format!("{}{}", cert_type.to_str(), i), cert_type)).collect()
+}
+
+/// Copies of `certs`, which is much quicker than generating new ones
+/// for each cache that needs to own them.
+pub fn copy_certs(certs: &[Cert]) -> Result<Vec<Cert>> {
+    certs.iter().map(|cert| {
+        let zcert = ZCert::from_keys(cert.public_key(), *cert.secret_key().expose());
+        for key in cert.meta_keys() {
+            if let Some(Ok(value)) = cert.meta(key) {
+                zcert.set_meta(key, &value);
+            }
+        }
+        Cert::from_zcert(zcert)
+    }).collect()
+}
+
+/// The Auth server's side of the feed: a cache of host certs to send
+/// snapshots of.
+pub struct Feed {
+    cache: CertCache,
+}
+
+impl Feed {
+    pub fn new(certs: Vec<Cert>) -> Feed {
+        Feed {
+            cache: CertCache::new(Some(certs)),
+        }
+    }
+
+    /// The single-message snapshot a new subscriber is sent. The
+    /// frames are built on the first call and reused until the cache
+    /// changes.
+    pub fn snapshot(&self) -> Result<Option<ZMsg>> {
+        snapshot_msg(Some(CertType::Host), &self.cache.snapshot_frames(Some(CertType::Host)))
+    }
+
+    /// Build a batched snapshot and drop each message, returning the
+    /// number of certs in it.
+    pub fn batched_snapshot(&self, batch_size: usize) -> Result<usize> {
+        self.cache.snapshot_batches(Some(CertType::Host), None, SnapshotFormat::Batched, batch_size, |_| Ok(()))
+    }
+}
+
+/// Receive a snapshot from `sock` into an empty cache, as a new
+/// subscriber does, returning the number of certs cached.
+pub fn recv_snapshot(sock: &mut ZSock) -> Result<usize> {
+    let mut cache = CertCache::new(None);
+    try!(cache.recv(sock));
+    Ok(cache.size())
+}
+
+/// A ZAP handler answering requests on `zap` from `certs`, without an
+/// Auth server.
+pub fn zap_handler(zap: ZSock, certs: Vec<Cert>) -> Result<ZapHandler> {
+    ZapHandler::bench_worker(zap, CertCache::new(Some(certs)))
+}
+
+/// A CURVE ZAP request from `cert`'s key.
+pub fn zap_request(cert: &Cert) -> Result<ZMsg> {
+    let msg = ZMsg::new();
+    try!(msg.addstr("1.0"));
+    try!(msg.addstr("1"));
+    try!(msg.addstr("bench"));
+    try!(msg.addstr("127.0.0.1"));
+    try!(msg.addstr(""));
+    try!(msg.addstr("CURVE"));
+    try!(msg.addbytes(cert.public_key()));
+    Ok(msg)
+}
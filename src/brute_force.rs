@@ -0,0 +1,212 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Temporary bans for clients that repeatedly fail ZAP authentication.
+//!
+//! Failures are counted separately per public key and per source
+//! address, so both a misconfigured client and an address cycling
+//! through guessed keys get banned. Each repeat offence doubles the
+//! ban, up to `max_ban`.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Forget idle entries once we are tracking this many
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BanPolicy {
+    /// Failures within `window` that trigger a ban
+    pub threshold: u32,
+    pub window: Duration,
+    /// Length of the first ban
+    pub ban: Duration,
+    pub max_ban: Duration,
+}
+
+impl Default for BanPolicy {
+    fn default() -> BanPolicy {
+        BanPolicy {
+            threshold: 5,
+            window: Duration::from_secs(60),
+            ban: Duration::from_secs(30),
+            max_ban: Duration::from_secs(3600),
+        }
+    }
+}
+
+struct Entry {
+    failures: u32,
+    window_start: Instant,
+    banned_until: Option<Instant>,
+    offences: u32,
+}
+
+pub struct BruteForceGuard {
+    policy: BanPolicy,
+    entries: HashMap<String, Entry>,
+}
+
+impl BruteForceGuard {
+    pub fn new(policy: BanPolicy) -> BruteForceGuard {
+        BruteForceGuard {
+            policy: policy,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn is_banned(&self, client_pk: &str, address: &str, now: Instant) -> bool {
+        keys(client_pk, address).iter().any(|k| {
+            match self.entries.get(k).and_then(|e| e.banned_until) {
+                Some(until) => until > now,
+                None => false,
+            }
+        })
+    }
+
+    /// Record a failed authentication. Returns the ban length for any
+    /// key that is newly banned as a result.
+    pub fn record_failure(&mut self, client_pk: &str, address: &str, now: Instant) -> Vec<(String, Duration)> {
+        if self.entries.len() >= MAX_ENTRIES {
+            self.prune(now);
+        }
+
+        let policy = self.policy;
+        let mut banned = Vec::new();
+
+        for key in keys(client_pk, address) {
+            let entry = self.entries.entry(key.clone()).or_insert(Entry {
+                failures: 0,
+                window_start: now,
+                banned_until: None,
+                offences: 0,
+            });
+
+            if now.duration_since(entry.window_start) > policy.window {
+                entry.failures = 0;
+                entry.window_start = now;
+            }
+            entry.failures += 1;
+
+            if entry.failures >= policy.threshold {
+                // A long enough first ban overflows when doubled
+                let ban = policy.ban.checked_mul(2u32.pow(cmp::min(entry.offences, 16)))
+                                    .map(|b| cmp::min(b, policy.max_ban))
+                                    .unwrap_or(policy.max_ban);
+                entry.banned_until = Some(now + ban);
+                entry.offences = entry.offences.saturating_add(1);
+                entry.failures = 0;
+                entry.window_start = now;
+                banned.push((key, ban));
+            }
+        }
+
+        banned
+    }
+
+    /// Clear the failure count for a client that authenticated. Bans
+    /// and offence history are kept.
+    pub fn record_success(&mut self, client_pk: &str, address: &str) {
+        for key in keys(client_pk, address) {
+            if let Some(entry) = self.entries.get_mut(&key) {
+                entry.failures = 0;
+            }
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        let policy = self.policy;
+        self.entries.retain(|_, e| {
+            e.banned_until.map(|u| u > now).unwrap_or(false) ||
+                now.duration_since(e.window_start) <= cmp::max(policy.window, policy.max_ban)
+        });
+    }
+}
+
+fn keys(client_pk: &str, address: &str) -> Vec<String> {
+    let mut keys = vec![format!("key {}", client_pk)];
+    // Inproc and IPC peers have no address
+    if !address.is_empty() {
+        keys.push(format!("address {}", address));
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::*;
+
+    fn policy() -> BanPolicy {
+        BanPolicy {
+            threshold: 3,
+            window: Duration::from_secs(10),
+            ban: Duration::from_secs(5),
+            max_ban: Duration::from_secs(12),
+        }
+    }
+
+    #[test]
+    fn test_ban_and_backoff() {
+        let mut guard = BruteForceGuard::new(policy());
+        let now = Instant::now();
+
+        assert!(guard.record_failure("pk", "10.0.0.1", now).is_empty());
+        assert!(guard.record_failure("pk", "10.0.0.1", now).is_empty());
+        assert!(!guard.is_banned("pk", "10.0.0.1", now));
+
+        let banned = guard.record_failure("pk", "10.0.0.1", now);
+        assert_eq!(banned.len(), 2);
+        assert_eq!(banned[0].1, Duration::from_secs(5));
+        assert!(guard.is_banned("pk", "10.0.0.2", now));
+        assert!(guard.is_banned("other", "10.0.0.1", now));
+        assert!(!guard.is_banned("pk", "10.0.0.1", now + Duration::from_secs(6)));
+
+        // Second offence doubles the ban, third hits the cap
+        let later = now + Duration::from_secs(6);
+        for _ in 0..3 {
+            guard.record_failure("pk", "", later);
+        }
+        assert!(guard.is_banned("pk", "", later + Duration::from_secs(9)));
+        let later = later + Duration::from_secs(11);
+        let banned = (0..3).flat_map(|_| guard.record_failure("pk", "", later)).collect::<Vec<_>>();
+        assert_eq!(banned[0].1, Duration::from_secs(12));
+    }
+
+    #[test]
+    fn test_ban_overflow() {
+        let mut guard = BruteForceGuard::new(BanPolicy {
+            ban: Duration::from_secs(u64::max_value() / 2 + 1),
+            max_ban: Duration::from_secs(3600),
+            ..policy()
+        });
+        let now = Instant::now();
+
+        for offence in 0..3 {
+            let later = now + Duration::from_secs(3601 * offence);
+            let banned = (0..3).flat_map(|_| guard.record_failure("pk", "", later)).collect::<Vec<_>>();
+            assert_eq!(banned, vec![("key pk".to_string(), Duration::from_secs(3600))]);
+        }
+    }
+
+    #[test]
+    fn test_window_and_success() {
+        let mut guard = BruteForceGuard::new(policy());
+        let now = Instant::now();
+
+        guard.record_failure("pk", "", now);
+        guard.record_failure("pk", "", now);
+        guard.record_success("pk", "");
+        assert!(guard.record_failure("pk", "", now).is_empty());
+
+        // Failures outside the window don't accumulate
+        guard.record_failure("pk", "", now);
+        assert!(guard.record_failure("pk", "", now + Duration::from_secs(11)).is_empty());
+    }
+}
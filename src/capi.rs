@@ -0,0 +1,377 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! C bindings for `ZapHandler` and `CertClient`, declared in
+//! include/inauth_client.h. Requires the `capi` feature.
+//!
+//! Functions that can fail return NULL or -1, and leave a message for
+//! `inauth_last_error()` on the calling thread. A panic is caught and
+//! reported the same way, as unwinding into C is undefined behaviour.
+//! Strings returned to the caller are freed with `inauth_string_free()`.
+
+use cert::CertType;
+use cert_client::CertClient;
+use czmq::ZCert;
+use error::{Error, Result};
+use key_health;
+use libc::{c_char, c_int, size_t};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::time::Duration;
+use zap_handler::ZapHandler;
+use zmq::z85_decode;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// The last error on this thread, or NULL. Valid until the next call
+/// that fails.
+#[no_mangle]
+pub extern "C" fn inauth_last_error() -> *const c_char {
+    guard(|| {
+        Ok(LAST_ERROR.with(|e| e.borrow().as_ref().map(|s| s.as_ptr()).unwrap_or(ptr::null())))
+    }).unwrap_or(ptr::null())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn inauth_string_free(s: *mut c_char) {
+    record(guard(|| {
+        if !s.is_null() {
+            drop(CString::from_raw(s));
+        }
+        Ok(())
+    }))
+}
+
+/// Free a list from `inauth_cert_list()`.
+#[no_mangle]
+pub unsafe extern "C" fn inauth_list_free(list: *mut *mut c_char, len: size_t) {
+    record(guard(|| {
+        if !list.is_null() {
+            let strings = Box::from_raw(slice::from_raw_parts_mut(list, len));
+            for &s in strings.iter() {
+                inauth_string_free(s);
+            }
+        }
+        Ok(())
+    }))
+}
+
+/// Subscribe to the Auth server at `auth_server`:`auth_port` and handle
+/// ZAP requests for this process. `cert_type` is "host", "user" or NULL
+/// for both. Certs are paths to the secret cert and the Auth server's
+/// public cert.
+#[no_mangle]
+pub unsafe extern "C" fn inauth_zap_new(cert_type: *const c_char,
+                                        cert_path: *const c_char,
+                                        auth_cert_path: *const c_char,
+                                        auth_server: *const c_char,
+                                        auth_port: u32,
+                                        allow_self: c_int) -> *mut ZapHandler {
+    let result = guard(|| -> Result<ZapHandler> {
+        let cert_type = match try!(opt_str(cert_type)) {
+            Some(t) => Some(try!(CertType::from_str(t))),
+            None => None,
+        };
+        let cert = try!(ZCert::load(try!(req_str(cert_path))));
+        let auth_cert = try!(ZCert::load(try!(req_str(auth_cert_path))));
+        ZapHandler::new(cert_type, &cert, &auth_cert, try!(req_str(auth_server)), auth_port, allow_self != 0)
+    });
+    boxed(result)
+}
+
+/// Block until the handler has its first snapshot. Returns 0, or -1 if
+/// it isn't ready within `timeout_ms`.
+#[no_mangle]
+pub unsafe extern "C" fn inauth_zap_wait_ready(zap: *mut ZapHandler, timeout_ms: u32) -> c_int {
+    status(guard(|| {
+        let zap = try!(zap.as_ref().ok_or(Error::InvalidArg));
+        zap.wait_ready(Duration::from_millis(timeout_ms as u64))
+    }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn inauth_zap_free(zap: *mut ZapHandler) {
+    record(guard(|| {
+        if !zap.is_null() {
+            drop(Box::from_raw(zap));
+        }
+        Ok(())
+    }))
+}
+
+/// A client for the cert API at `endpoint`, e.g.
+/// "tcp://auth.example.com:7101", authenticating with the cert at
+/// `cert_path`.
+#[no_mangle]
+pub unsafe extern "C" fn inauth_cert_client_new(endpoint: *const c_char,
+                                                server_public_path: *const c_char,
+                                                cert_path: *const c_char) -> *mut CertClient {
+    let result = guard(|| -> Result<CertClient> {
+        CertClient::load(try!(req_str(endpoint)), try!(req_str(server_public_path)), try!(req_str(cert_path)))
+    });
+    boxed(result)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn inauth_cert_client_set_timeout(client: *mut CertClient, timeout_ms: u32) {
+    record(guard(|| {
+        if let Some(c) = client.as_mut() {
+            c.set_timeout(Duration::from_millis(timeout_ms as u64));
+        }
+        Ok(())
+    }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn inauth_cert_client_free(client: *mut CertClient) {
+    record(guard(|| {
+        if !client.is_null() {
+            drop(Box::from_raw(client));
+        }
+        Ok(())
+    }))
+}
+
+/// Create a cert, setting `public_key` and `secret_key` to its Z85
+/// keys.
+#[no_mangle]
+pub unsafe extern "C" fn inauth_cert_create(client: *mut CertClient,
+                                            cert_type: *const c_char,
+                                            name: *const c_char,
+                                            public_key: *mut *mut c_char,
+                                            secret_key: *mut *mut c_char) -> c_int {
+    let result = guard(|| -> Result<(CString, CString)> {
+        let client = try!(client.as_mut().ok_or(Error::InvalidArg));
+        if public_key.is_null() || secret_key.is_null() {
+            return Err(Error::InvalidArg);
+        }
+        let cert = try!(client.create(try!(req_str(name)), try!(CertType::from_str(try!(req_str(cert_type))))));
+        Ok((try!(c_string(cert.public_txt())), try!(c_string(cert.secret_txt().expose()))))
+    });
+    match result {
+        Ok((public, secret)) => {
+            *public_key = public.into_raw();
+            *secret_key = secret.into_raw();
+            0
+        },
+        Err(e) => status::<()>(Err(e)),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn inauth_cert_delete(client: *mut CertClient, name: *const c_char) -> c_int {
+    status(guard(|| -> Result<()> {
+        let client = try!(client.as_mut().ok_or(Error::InvalidArg));
+        client.delete(try!(req_str(name)))
+    }))
+}
+
+/// The names of certs of `cert_type`, setting `len` to how many. Free
+/// with `inauth_list_free()`.
+#[no_mangle]
+pub unsafe extern "C" fn inauth_cert_list(client: *mut CertClient, cert_type: *const c_char, len: *mut size_t) -> *mut *mut c_char {
+    let result = guard(|| -> Result<Vec<CString>> {
+        let client = try!(client.as_mut().ok_or(Error::InvalidArg));
+        if len.is_null() {
+            return Err(Error::InvalidArg);
+        }
+        let names = try!(client.list(try!(CertType::from_str(try!(req_str(cert_type))))));
+        // Convert every name before handing any to C, so a failure
+        // partway drops the strings converted so far
+        names.iter().map(|n| c_string(n)).collect()
+    });
+    match result {
+        Ok(names) => {
+            let list: Vec<*mut c_char> = names.into_iter().map(|s| s.into_raw()).collect();
+            *len = list.len();
+            Box::into_raw(list.into_boxed_slice()) as *mut *mut c_char
+        },
+        Err(e) => {
+            set_error(e);
+            ptr::null_mut()
+        },
+    }
+}
+
+/// The Z85 public key of the cert called `name`.
+#[no_mangle]
+pub unsafe extern "C" fn inauth_cert_lookup(client: *mut CertClient, name: *const c_char) -> *mut c_char {
+    let result = guard(|| -> Result<CString> {
+        let client = try!(client.as_mut().ok_or(Error::InvalidArg));
+        c_string(&try!(client.lookup(try!(req_str(name)))))
+    });
+    match result {
+        Ok(s) => s.into_raw(),
+        Err(e) => {
+            set_error(e);
+            ptr::null_mut()
+        },
+    }
+}
+
+/// Check a Z85 public key for known-bad patterns, such as published
+/// test keys. Sets `problem` to a description, or NULL if the key looks
+/// safe. Returns -1 if the key isn't valid Z85.
+#[no_mangle]
+pub unsafe extern "C" fn inauth_key_check(public_key: *const c_char, problem: *mut *mut c_char) -> c_int {
+    let result = guard(|| -> Result<Option<CString>> {
+        if problem.is_null() {
+            return Err(Error::InvalidArg);
+        }
+        let public_txt = try!(req_str(public_key));
+        let decoded = try!(z85_decode(public_txt).or(Err(Error::InvalidCert)));
+        if decoded.len() != 32 {
+            return Err(Error::InvalidCert);
+        }
+        match key_health::check_key(&decoded, public_txt) {
+            Some(p) => Ok(Some(try!(c_string(&p.to_string())))),
+            None => Ok(None),
+        }
+    });
+    match result {
+        Ok(p) => {
+            *problem = p.map(|s| s.into_raw()).unwrap_or(ptr::null_mut());
+            0
+        },
+        Err(e) => status::<()>(Err(e)),
+    }
+}
+
+// Run `f`, turning a panic into an error rather than letting it unwind
+// into C.
+fn guard<T, F: FnOnce() -> Result<T>>(f: F) -> Result<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => {
+            let msg = match payload.downcast_ref::<&str>() {
+                Some(s) => s.to_string(),
+                None => payload.downcast_ref::<String>().cloned().unwrap_or("panic".to_string()),
+            };
+            Err(Error::Panic(msg))
+        },
+    }
+}
+
+// For functions that return nothing, so can only report an error
+// through `inauth_last_error()`.
+fn record(result: Result<()>) {
+    if let Err(e) = result {
+        set_error(e);
+    }
+}
+
+fn set_error(err: Error) {
+    let msg = CString::new(err.to_string().replace('\0', "")).unwrap();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+fn status<T>(result: Result<T>) -> c_int {
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            set_error(e);
+            -1
+        },
+    }
+}
+
+fn boxed<T>(result: Result<T>) -> *mut T {
+    match result {
+        Ok(v) => Box::into_raw(Box::new(v)),
+        Err(e) => {
+            set_error(e);
+            ptr::null_mut()
+        },
+    }
+}
+
+fn c_string(s: &str) -> Result<CString> {
+    CString::new(s).or(Err(Error::InvalidArg))
+}
+
+unsafe fn opt_str<'a>(s: *const c_char) -> Result<Option<&'a str>> {
+    if s.is_null() {
+        Ok(None)
+    } else {
+        CStr::from_ptr(s).to_str().map(Some).or(Err(Error::InvalidArg))
+    }
+}
+
+unsafe fn req_str<'a>(s: *const c_char) -> Result<&'a str> {
+    try!(opt_str(s)).ok_or(Error::InvalidArg)
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::size_t;
+    use std::ffi::{CStr, CString};
+    use std::ptr;
+    use super::*;
+
+    #[test]
+    fn test_errors() {
+        unsafe {
+            let missing = CString::new("/nonexistent/inauth.crt").unwrap();
+            let endpoint = CString::new("tcp://127.0.0.1:7101").unwrap();
+            let client = inauth_cert_client_new(endpoint.as_ptr(), missing.as_ptr(), missing.as_ptr());
+            assert!(client.is_null());
+            assert!(!inauth_last_error().is_null());
+
+            let client = inauth_cert_client_new(ptr::null(), missing.as_ptr(), missing.as_ptr());
+            assert!(client.is_null());
+            assert_eq!(CStr::from_ptr(inauth_last_error()).to_str().unwrap(), Error::InvalidArg.to_string());
+
+            let mut len: size_t = 0;
+            assert!(inauth_cert_list(ptr::null_mut(), ptr::null(), &mut len).is_null());
+            assert_eq!(inauth_cert_delete(ptr::null_mut(), ptr::null()), -1);
+
+            // Freeing NULL is a no-op
+            inauth_cert_client_free(ptr::null_mut());
+            inauth_zap_free(ptr::null_mut());
+            inauth_string_free(ptr::null_mut());
+            inauth_list_free(ptr::null_mut(), 0);
+        }
+    }
+
+    #[test]
+    fn test_guard() {
+        match guard(|| -> Result<()> { panic!("boom") }) {
+            Err(Error::Panic(ref msg)) => assert_eq!(msg, "boom"),
+            _ => panic!("expected the panic to be caught"),
+        }
+        match guard(|| -> Result<()> { panic!("boom {}", 2) }) {
+            Err(Error::Panic(ref msg)) => assert_eq!(msg, "boom 2"),
+            _ => panic!("expected the panic to be caught"),
+        }
+        assert_eq!(guard(|| Ok(1)).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_key_check() {
+        unsafe {
+            let mut problem = ptr::null_mut();
+            let test_key = CString::new("rq:rM>}U?@Lns47E1%kR.o@n%FcmmsL/@{H8]yf7").unwrap();
+            assert_eq!(inauth_key_check(test_key.as_ptr(), &mut problem), 0);
+            assert_eq!(CStr::from_ptr(problem).to_str().unwrap(), "published test key");
+            inauth_string_free(problem);
+
+            let cert = ::czmq::ZCert::new().unwrap();
+            let key = CString::new(cert.public_txt()).unwrap();
+            assert_eq!(inauth_key_check(key.as_ptr(), &mut problem), 0);
+            assert!(problem.is_null());
+
+            let bad = CString::new("not z85").unwrap();
+            assert_eq!(inauth_key_check(bad.as_ptr(), &mut problem), -1);
+        }
+    }
+}
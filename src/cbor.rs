@@ -0,0 +1,173 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! CBOR (RFC 7049) encoding of cert metadata for the feed.
+//!
+//! Metadata is a map of text keys to text values, which is all this
+//! module reads or writes. It is smaller than the ZCert metadata blob,
+//! and any CBOR library can parse it.
+
+use czmq::ZCert;
+use error::{Error, Result};
+
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_MAP: u8 = 5;
+
+/// Re-encode a ZCert metadata blob (see `ZCert::encode_meta()`) as a
+/// CBOR map. Values that aren't UTF-8 are converted lossily.
+pub fn from_zcert_meta(meta: &[u8]) -> Result<Vec<u8>> {
+    let zcert = ZCert::from_keys(&[0; 32], &[0; 32]);
+    try!(zcert.decode_meta(meta));
+    let mut pairs = Vec::new();
+    for key in zcert.meta_keys() {
+        let value = match zcert.meta(key) {
+            Some(Ok(v)) => v,
+            Some(Err(b)) => String::from_utf8_lossy(&b).into_owned(),
+            None => continue,
+        };
+        pairs.push((key.to_string(), value));
+    }
+    Ok(encode_map(&pairs))
+}
+
+/// The reverse of `from_zcert_meta()`.
+pub fn to_zcert_meta(cbor: &[u8]) -> Result<Vec<u8>> {
+    let zcert = ZCert::from_keys(&[0; 32], &[0; 32]);
+    for (key, value) in try!(decode_map(cbor)) {
+        zcert.set_meta(&key, &value);
+    }
+    Ok(zcert.encode_meta())
+}
+
+pub fn encode_map(pairs: &[(String, String)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_head(&mut out, MAJOR_MAP, pairs.len() as u64);
+    for &(ref key, ref value) in pairs {
+        write_head(&mut out, MAJOR_TEXT, key.len() as u64);
+        out.extend_from_slice(key.as_bytes());
+        write_head(&mut out, MAJOR_TEXT, value.len() as u64);
+        out.extend_from_slice(value.as_bytes());
+    }
+    out
+}
+
+/// Decode a map of text strings. Indefinite lengths and other types
+/// are rejected.
+pub fn decode_map(data: &[u8]) -> Result<Vec<(String, String)>> {
+    let mut pos = 0;
+    let len = try!(read_head(data, &mut pos, MAJOR_MAP));
+    let mut pairs = Vec::new();
+    for _ in 0..len {
+        let key = try!(read_text(data, &mut pos));
+        let value = try!(read_text(data, &mut pos));
+        pairs.push((key, value));
+    }
+    if pos != data.len() {
+        return Err(Error::InvalidCertMeta);
+    }
+    Ok(pairs)
+}
+
+fn write_head(out: &mut Vec<u8>, major: u8, len: u64) {
+    let major = major << 5;
+    if len < 24 {
+        out.push(major | len as u8);
+    } else if len <= 0xff {
+        out.push(major | 24);
+        out.push(len as u8);
+    } else if len <= 0xffff {
+        out.push(major | 25);
+        out.extend_from_slice(&[(len >> 8) as u8, len as u8]);
+    } else if len <= 0xffff_ffff {
+        out.push(major | 26);
+        for shift in &[24, 16, 8, 0] {
+            out.push((len >> *shift) as u8);
+        }
+    } else {
+        out.push(major | 27);
+        for shift in &[56, 48, 40, 32, 24, 16, 8, 0] {
+            out.push((len >> *shift) as u8);
+        }
+    }
+}
+
+fn read_head(data: &[u8], pos: &mut usize, major: u8) -> Result<u64> {
+    let initial = match data.get(*pos) {
+        Some(b) => *b,
+        None => return Err(Error::InvalidCertMeta),
+    };
+    *pos += 1;
+    if initial >> 5 != major {
+        return Err(Error::InvalidCertMeta);
+    }
+
+    let extra = match initial & 0x1f {
+        n @ 0...23 => return Ok(n as u64),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        _ => return Err(Error::InvalidCertMeta),
+    };
+    if *pos + extra > data.len() {
+        return Err(Error::InvalidCertMeta);
+    }
+    let len = data[*pos..*pos + extra].iter().fold(0, |acc, b| acc << 8 | *b as u64);
+    *pos += extra;
+    Ok(len)
+}
+
+fn read_text(data: &[u8], pos: &mut usize) -> Result<String> {
+    let len = try!(read_head(data, pos, MAJOR_TEXT));
+    if len > (data.len() - *pos) as u64 {
+        return Err(Error::InvalidCertMeta);
+    }
+    let end = *pos + len as usize;
+    let text = try!(String::from_utf8(data[*pos..end].to_vec()).or(Err(Error::InvalidCertMeta)));
+    *pos = end;
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType};
+    use czmq::ZSys;
+    use super::*;
+
+    #[test]
+    fn test_encode_map() {
+        // From RFC 7049 appendix A
+        let pairs = vec![("a".to_string(), "A".to_string()), ("b".to_string(), "B".to_string())];
+        assert_eq!(encode_map(&pairs), vec![0xa2, 0x61, 0x61, 0x61, 0x41, 0x61, 0x62, 0x61, 0x42]);
+        assert_eq!(decode_map(&encode_map(&pairs)).unwrap(), pairs);
+
+        let long = vec![("k".to_string(), "v".repeat(300))];
+        let encoded = encode_map(&long);
+        assert_eq!(&encoded[3..6], &[0x79, 0x01, 0x2c]);
+        assert_eq!(decode_map(&encoded).unwrap(), long);
+
+        assert!(decode_map(&[0xa1, 0x61, 0x61]).is_err());
+        assert!(decode_map(&[0xbf, 0xff]).is_err());
+        assert!(decode_map(&[0xa0, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_zcert_meta() {
+        ZSys::init();
+
+        let cert = Cert::new("web1.example.com", CertType::Host).unwrap();
+        cert.set_meta("role", "web");
+        let cbor = from_zcert_meta(&cert.encode_meta()).unwrap();
+        assert!(cbor.len() < cert.encode_meta().len());
+
+        let zcert = ZCert::from_keys(&[0; 32], &[0; 32]);
+        zcert.decode_meta(&to_zcert_meta(&cbor).unwrap()).unwrap();
+        assert_eq!(zcert.meta("name").unwrap().unwrap(), "web1.example.com");
+        assert_eq!(zcert.meta("role").unwrap().unwrap(), "web");
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Key ceremony: split the server's secret key among custodians and,
+//! for disaster recovery only, put it back together.
+//!
+//! Each custodian receives one share as a line of text:
+//!
+//! ```text
+//! inauth-share-1:<key id>:<index>:<threshold>:<hex share>:<checksum>
+//! ```
+//!
+//! The key id identifies the server key the share belongs to and the
+//! checksum catches transcription errors. Every step is appended to an
+//! audit log, which records share fingerprints but never share contents.
+
+use czmq::ZCert;
+use error::{Error, Result};
+use shamir::{self, Share};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::scalarmult::curve25519::{scalarmult_base, Scalar};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SHARE_PREFIX: &'static str = "inauth-share-1";
+
+/// One custodian's share of a server secret key.
+#[derive(Debug, PartialEq)]
+pub struct KeyShare {
+    pub key_id: String,
+    pub threshold: u8,
+    pub share: Share,
+}
+
+impl KeyShare {
+    pub fn to_text(&self) -> String {
+        let body = format!("{}:{}:{}:{}:{}", SHARE_PREFIX, self.key_id, self.share.x, self.threshold, to_hex(&self.share.y));
+        let checksum = checksum(&body);
+        format!("{}:{}", body, checksum)
+    }
+
+    pub fn parse(text: &str) -> Result<KeyShare> {
+        let text = text.trim();
+        let (body, checksum_txt) = match text.rfind(':') {
+            Some(i) => (&text[..i], &text[i + 1..]),
+            None => return Err(Error::InvalidShare("not a share".into())),
+        };
+        if checksum(body) != checksum_txt {
+            return Err(Error::InvalidShare("checksum mismatch, check for typos".into()));
+        }
+
+        let parts: Vec<&str> = body.split(':').collect();
+        if parts.len() != 5 || parts[0] != SHARE_PREFIX {
+            return Err(Error::InvalidShare("not a share".into()));
+        }
+
+        Ok(KeyShare {
+            key_id: parts[1].into(),
+            threshold: parts[3].parse().or(Err(Error::InvalidShare("bad threshold".into())))?,
+            share: Share {
+                x: parts[2].parse().or(Err(Error::InvalidShare("bad index".into())))?,
+                y: from_hex(parts[4]).ok_or(Error::InvalidShare("bad share data".into()))?,
+            },
+        })
+    }
+
+    /// Identifies a share in the audit log without revealing it.
+    pub fn fingerprint(&self) -> String {
+        checksum(&self.to_text())
+    }
+}
+
+/// Short identifier for a server key, derived from its public half.
+pub fn key_id(public_key: &[u8]) -> String {
+    to_hex(&hash(public_key)[..8])
+}
+
+/// Split `cert`'s secret key into `shares` shares, any `threshold` of
+/// which recover it.
+pub fn split(cert: &ZCert, shares: u8, threshold: u8) -> Result<Vec<KeyShare>> {
+    let key_id = key_id(cert.public_key());
+    let split = shamir::split(cert.secret_key(), shares, threshold)?;
+
+    // Prove the shares work before anyone walks away with them
+    let mut check = shamir::combine(&split[..threshold as usize])?;
+    let matches = check == cert.secret_key();
+    zero(&mut check);
+    if !matches {
+        return Err(Error::InvalidShare("shares do not reconstruct the key".into()));
+    }
+
+    Ok(split.into_iter().map(|share| KeyShare {
+        key_id: key_id.clone(),
+        threshold: threshold,
+        share: share,
+    }).collect())
+}
+
+/// Write each share to its own file in `dir`, readable by its owner
+/// only, and return the paths.
+pub fn write_shares<P: AsRef<Path>>(shares: &[KeyShare], dir: P) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for share in shares {
+        let path = dir.as_ref().join(format!("share-{}-{}.txt", share.key_id, share.share.x));
+        let mut fh = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        writeln!(fh, "{}", share.to_text())?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Rebuild the server cert from custodians' shares, checking the result
+/// against the server's public cert.
+pub fn reconstruct(public: &ZCert, shares: &[KeyShare]) -> Result<ZCert> {
+    let key_id = key_id(public.public_key());
+    let threshold = shares.first().map(|s| s.threshold).unwrap_or(0);
+    for share in shares {
+        if share.key_id != key_id {
+            return Err(Error::InvalidShare(format!("share {} is for key {}, not {}", share.share.x, share.key_id, key_id)));
+        }
+        if share.threshold != threshold {
+            return Err(Error::InvalidShare(format!("share {} disagrees on the threshold", share.share.x)));
+        }
+    }
+    if shares.len() < threshold as usize {
+        return Err(Error::InvalidShare(format!("{} share(s) needed, {} given", threshold, shares.len())));
+    }
+
+    let raw: Vec<Share> = shares.iter().map(|s| s.share.clone()).collect();
+    let mut secret = shamir::combine(&raw)?;
+    let derived = Scalar::from_slice(&secret).map(|s| scalarmult_base(&s).0);
+    if derived.as_ref().map(|pk| &pk[..]) != Some(public.public_key()) {
+        zero(&mut secret);
+        return Err(Error::InvalidShare("shares do not match the server's public key".into()));
+    }
+
+    let cert = ZCert::from_keys(public.public_key(), &secret);
+    zero(&mut secret);
+    for key in public.meta_keys() {
+        if let Some(Ok(value)) = public.meta(key) {
+            cert.set_meta(key, &value);
+        }
+    }
+    Ok(cert)
+}
+
+/// Append-only record of ceremony events.
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new<P: AsRef<Path>>(path: P) -> AuditLog {
+        AuditLog { path: path.as_ref().to_owned() }
+    }
+
+    /// Append an event with its details. The operator is taken from
+    /// `$USER`.
+    pub fn record(&self, event: &str, details: &[(&str, String)]) -> Result<()> {
+        let exists = self.path.exists();
+        let mut fh = OpenOptions::new().append(true).create(true).open(&self.path)?;
+        if !exists {
+            fs::set_permissions(&self.path, fs::Permissions::from_mode(0o600))?;
+        }
+
+        let mut line = format!("{} {} operator={}",
+                               SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+                               event,
+                               env::var("USER").unwrap_or("unknown".into()));
+        for &(key, ref value) in details {
+            line.push_str(&format!(" {}={:?}", key, value));
+        }
+        writeln!(fh, "{}", line)?;
+        fh.sync_all()?;
+        Ok(())
+    }
+}
+
+fn checksum(text: &str) -> String {
+    to_hex(&hash(text.as_bytes())[..4])
+}
+
+fn hash(bytes: &[u8]) -> [u8; 32] {
+    let sha256::Digest(digest) = sha256::hash(bytes);
+    digest
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+// Best effort scrubbing of plaintext key material
+fn zero(buf: &mut Vec<u8>) {
+    for b in buf.iter_mut() {
+        *b = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use czmq::ZCert;
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_share_text() {
+        let share = KeyShare {
+            key_id: "0123456789abcdef".into(),
+            threshold: 2,
+            share: Share { x: 3, y: vec![0, 1, 254, 255] },
+        };
+        let text = share.to_text();
+        assert!(text.starts_with("inauth-share-1:0123456789abcdef:3:2:0001feff:"));
+        assert_eq!(KeyShare::parse(&format!("  {}\n", text)).unwrap(), share);
+
+        assert!(KeyShare::parse(&text.replace("0001feff", "0001fefe")).is_err());
+        assert!(KeyShare::parse("inauth-share-1").is_err());
+    }
+
+    #[test]
+    fn test_split_reconstruct() {
+        let dir = TempDir::new("ceremony_test_split_reconstruct").unwrap();
+
+        let cert = ZCert::new().unwrap();
+        cert.set_meta("name", "auth");
+        let shares = split(&cert, 3, 2).unwrap();
+        assert_eq!(shares.len(), 3);
+
+        let paths = write_shares(&shares, dir.path()).unwrap();
+        let mut text = String::new();
+        fs::File::open(&paths[2]).unwrap().read_to_string(&mut text).unwrap();
+        assert_eq!(fs::metadata(&paths[2]).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(write_shares(&shares, dir.path()).is_err());
+
+        let public = ZCert::from_keys(cert.public_key(), &[0; 32]);
+        public.set_meta("name", "auth");
+        let loaded = vec![KeyShare::parse(&text).unwrap(), KeyShare::parse(&shares[0].to_text()).unwrap()];
+        let rebuilt = reconstruct(&public, &loaded).unwrap();
+        assert_eq!(rebuilt.secret_txt(), cert.secret_txt());
+        assert_eq!(rebuilt.meta("name").unwrap().unwrap(), "auth");
+
+        assert!(reconstruct(&public, &loaded[..1]).is_err());
+        let other = ZCert::new().unwrap();
+        assert!(reconstruct(&other, &loaded).is_err());
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let dir = TempDir::new("ceremony_test_audit_log").unwrap();
+        let path = dir.path().join("ceremony.log");
+        let log = AuditLog::new(&path);
+        log.record("init", &[("key", "abc".into())]).unwrap();
+        log.record("reconstruct", &[("shares", "1,2".into())]).unwrap();
+
+        let mut contents = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(" init operator="));
+        assert!(lines[0].ends_with(" key=\"abc\""));
+        assert!(lines[1].contains(" reconstruct "));
+    }
+}
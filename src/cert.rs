@@ -8,9 +8,21 @@
 
 use czmq::ZCert;
 use error::{Error, Result};
+use secret::Secret;
 use std::ops::{Deref, DerefMut};
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+/// Metadata holding when a cert stops being valid, in seconds since the
+/// Unix epoch.
+pub const EXPIRES_META: &'static str = "expires";
+/// Metadata marking a cert the Auth server has revoked, set to "true".
+pub const REVOKED_META: &'static str = "revoked";
+/// Metadata suspending a cert until it is re-enabled, set to "true".
+pub const DISABLED_META: &'static str = "disabled";
+/// Metadata holding when the Auth server issued the cert's key pair, in
+/// seconds since the Unix epoch.
+pub const ISSUED_META: &'static str = "issued";
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum CertType {
     Host,
     User,
@@ -84,6 +96,64 @@ impl Cert {
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    /// When the cert stops being valid, in seconds since the Unix
+    /// epoch, or `None` if it doesn't expire. A malformed expiry counts
+    /// as having expired already.
+    #[allow(dead_code)]
+    pub fn expires_at(&self) -> Option<u64> {
+        match self.zcert.meta(EXPIRES_META) {
+            Some(Ok(s)) => Some(s.parse().unwrap_or(0)),
+            Some(Err(_)) => Some(0),
+            None => None,
+        }
+    }
+
+    /// When the cert's key pair was issued, in seconds since the Unix
+    /// epoch, or `None` if it predates issue times or is malformed.
+    #[allow(dead_code)]
+    pub fn issued_at(&self) -> Option<u64> {
+        match self.zcert.meta(ISSUED_META) {
+            Some(Ok(s)) => s.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Whether the cert has expired as of `now`, in seconds since the
+    /// Unix epoch.
+    #[allow(dead_code)]
+    pub fn is_expired_at(&self, now: u64) -> bool {
+        self.expires_at().map(|e| e <= now).unwrap_or(false)
+    }
+
+    #[allow(dead_code)]
+    pub fn is_revoked(&self) -> bool {
+        match self.zcert.meta(REVOKED_META) {
+            Some(Ok(s)) => s == "true",
+            _ => false,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn is_disabled(&self) -> bool {
+        match self.zcert.meta(DISABLED_META) {
+            Some(Ok(s)) => s == "true",
+            _ => false,
+        }
+    }
+
+    #[allow(dead_code)]
+    /// Shadows `ZCert::secret_key()` so the key can't be formatted by
+    /// accident.
+    pub fn secret_key(&self) -> Secret<&[u8]> {
+        Secret::new(self.zcert.secret_key())
+    }
+
+    /// Shadows `ZCert::secret_txt()` so the key can't be formatted by
+    /// accident.
+    pub fn secret_txt(&self) -> Secret<&str> {
+        Secret::new(self.zcert.secret_txt())
+    }
 }
 
 impl Deref for Cert {
@@ -123,6 +193,31 @@ mod tests {
         assert!(Cert::new("test_user", CertType::User).is_ok());
     }
 
+    #[test]
+    fn test_expiry_revocation() {
+        let cert = Cert::new("test_user", CertType::User).unwrap();
+        assert_eq!(cert.expires_at(), None);
+        assert!(!cert.is_expired_at(u64::max_value()));
+        assert!(!cert.is_revoked());
+
+        cert.set_meta(EXPIRES_META, "1500000000");
+        assert!(!cert.is_expired_at(1499999999));
+        assert!(cert.is_expired_at(1500000000));
+        cert.set_meta(REVOKED_META, "true");
+        assert!(cert.is_revoked());
+        assert!(!cert.is_disabled());
+        cert.set_meta(DISABLED_META, "true");
+        assert!(cert.is_disabled());
+
+        let malformed = Cert::new("test_user", CertType::User).unwrap();
+        malformed.set_meta(EXPIRES_META, "tomorrow");
+        assert!(malformed.is_expired_at(0));
+
+        assert_eq!(cert.issued_at(), None);
+        cert.set_meta(ISSUED_META, "1400000000");
+        assert_eq!(cert.issued_at(), Some(1400000000));
+    }
+
     #[test]
     fn test_from_zcert() {
         let zcert = ZCert::new().unwrap();
@@ -133,4 +228,14 @@ mod tests {
         zcert.set_meta("type", "host");
         assert!(Cert::from_zcert(zcert).is_ok());
     }
+
+    #[test]
+    fn test_secret_redacted() {
+        let cert = Cert::new("test_user", CertType::User).unwrap();
+        let secret = cert.secret_txt().expose().to_string();
+        assert_eq!(secret, cert.zcert.secret_txt());
+        assert!(!format!("{:?}", cert).contains(&secret));
+        assert!(!format!("{:?}", cert.secret_txt()).contains(&secret));
+        assert!(!format!("{}", cert.secret_txt()).contains(&secret));
+    }
 }
@@ -5,224 +5,2852 @@
 // Licensed under the Mozilla Public License 2.0 <LICENSE or
 // https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
 // modified, or distributed except according to those terms.
+use affinity::ServerAdvert;
+use attestation::{self, Attestation};
+use cbor;
 use cert::{Cert, CertType};
+use compression;
 use czmq::{ZCert, ZMsg, ZSock};
 use error::{Error, Result};
-use std::collections::HashMap;
+use feed_signature::{self, FeedVerifier, SIGNATURE_PREFIX};
+use filter::Filter;
+use latency::{self, LatencyHistogram};
+use serde_json::{self, Map, Value};
+use sodiumoxide::crypto::sign::PublicKey;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+/// Feed topics starting with this prefix address a single subscriber,
+/// identified by its cert name and ended by `DIRECT_TOPIC_SUFFIX`, e.g.
+/// "@web1.example.com/". See `direct_topic()`.
+pub const DIRECT_TOPIC_PREFIX: &'static str = "@";
+
+/// Ends the cert name in a direct topic, so that subscribing to "@web1/"
+/// doesn't also match "@web10/". Cert names can't contain it.
+pub const DIRECT_TOPIC_SUFFIX: char = '/';
+
+/// Feed topics starting with this prefix, followed by a cert type (e.g.
+/// "keys:host"), carry public keys without metadata. Subscribing to the
+/// bare prefix gets keys for every cert type.
+pub const KEYS_ONLY_TOPIC_PREFIX: &'static str = "keys:";
+
+/// Feed topics starting with this prefix, followed by a topic for full
+/// certs (e.g. "batch:host", or "batch:" for every type), carry the
+/// same changes, but snapshots are sent as ADDs of a batch of certs at
+/// a time, ended by an END. See `SnapshotFormat`.
+pub const BATCH_TOPIC_PREFIX: &'static str = "batch:";
+
+/// Like `BATCH_TOPIC_PREFIX`, but metadata frames are compressed with
+/// zstd.
+pub const ZSTD_BATCH_TOPIC_PREFIX: &'static str = "zbatch:";
+
+/// Like `BATCH_TOPIC_PREFIX`, but metadata frames are CBOR maps. See
+/// `cbor`.
+pub const CBOR_BATCH_TOPIC_PREFIX: &'static str = "cbatch:";
+
+/// Feed messages republished from another Auth server by a bridge
+/// carry a frame of this prefix, before the timestamp, followed by the
+/// comma separated names of the servers they have passed through, e.g.
+/// "origin=dc2,dc3". See `ZapBridge`.
+pub const ORIGIN_PREFIX: &'static str = "origin=";
+
+/// Separates a cert type topic from a scope (e.g. "host/webfarm"),
+/// whose subscribers only receive the certs in that scope. Scopes can
+/// follow a batch prefix, but not a keys-only one.
+pub const SCOPE_SEPARATOR: char = '/';
+
+/// Scopes starting with this prefix name a tenant namespace (e.g.
+/// "host/ns:acme"), matched against the cert's "namespace" metadata.
+/// Other scopes are matched against its "group".
+pub const NAMESPACE_SCOPE_PREFIX: &'static str = "ns:";
+
+/// Feed messages end with a frame of this prefix followed by the
+/// publish time, in microseconds since the Unix epoch. Attestations go
+/// without. Keys-only clients older than feed signing would misread it
+/// as a key, so need upgrading before the publisher.
+pub const TIMESTAMP_PREFIX: &'static str = "ts=";
+
+/// A subscription may be followed by a frame of this prefix and the
+/// position the subscriber has applied the feed up to, as
+/// "<epoch>:<sequence>" from the publisher's last heartbeat. The
+/// publisher then sends the certs changed since, rather than a whole
+/// snapshot, if it still can. See `resume_frame()`.
+pub const RESUME_PREFIX: &'static str = "since=";
+
+const CACHE_FILE_VERSION: u64 = 1;
+
+// Quarantined certs kept for inspection, beyond which the oldest are
+// forgotten
+const QUARANTINE_LIMIT: usize = 100;
+
+// How long (µs) the publish time of a removed cert's last change is
+// kept, to ignore older changes still arriving from other publishers
+const TOMBSTONE_MICROS: u64 = 60 * 60 * 1_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    /// A cert was added to the feed, or an existing cert was re-sent.
+    Added,
+    Removed,
+    /// An UPDATE replaced a cert's metadata.
+    Updated,
+}
+
+/// How a subscriber asks for snapshots to be sent, by the prefix of the
+/// topic it subscribes to. Older subscribers only know `Single`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SnapshotFormat {
+    /// One ADD with every cert
+    Single,
+    /// ADDs of up to a batch of certs each, then an END with the
+    /// number of certs sent
+    Batched,
+    /// As `Batched`, with zstd-compressed metadata
+    Compressed,
+    /// As `Batched`, with metadata as CBOR
+    Cbor,
+}
+
+impl Default for SnapshotFormat {
+    fn default() -> SnapshotFormat {
+        SnapshotFormat::Single
+    }
+}
+
+impl SnapshotFormat {
+    pub fn topic_prefix(&self) -> &'static str {
+        match *self {
+            SnapshotFormat::Single => "",
+            SnapshotFormat::Batched => BATCH_TOPIC_PREFIX,
+            SnapshotFormat::Compressed => ZSTD_BATCH_TOPIC_PREFIX,
+            SnapshotFormat::Cbor => CBOR_BATCH_TOPIC_PREFIX,
+        }
+    }
+
+    /// The format a topic asks for, and the topic without its prefix.
+    pub fn from_topic(topic: &str) -> (SnapshotFormat, &str) {
+        if topic.starts_with(BATCH_TOPIC_PREFIX) {
+            (SnapshotFormat::Batched, &topic[BATCH_TOPIC_PREFIX.len()..])
+        } else if topic.starts_with(ZSTD_BATCH_TOPIC_PREFIX) {
+            (SnapshotFormat::Compressed, &topic[ZSTD_BATCH_TOPIC_PREFIX.len()..])
+        } else if topic.starts_with(CBOR_BATCH_TOPIC_PREFIX) {
+            (SnapshotFormat::Cbor, &topic[CBOR_BATCH_TOPIC_PREFIX.len()..])
+        } else {
+            (SnapshotFormat::Single, topic)
+        }
+    }
+
+    fn encode_meta(&self, meta: Vec<u8>) -> Result<Vec<u8>> {
+        match *self {
+            SnapshotFormat::Compressed => compression::compress(&meta),
+            SnapshotFormat::Cbor => cbor::from_zcert_meta(&meta),
+            _ => Ok(meta),
+        }
+    }
+
+    fn decode_meta(&self, meta: Vec<u8>) -> Result<Vec<u8>> {
+        match *self {
+            SnapshotFormat::Compressed => compression::decompress(&meta),
+            SnapshotFormat::Cbor => cbor::to_zcert_meta(&meta),
+            _ => Ok(meta),
+        }
+    }
+}
+
+/// Bounds on the certs a cache keeps from the feed, in case DEL
+/// messages are missed. Certs passed to `CertCache::new()` are exempt.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheLimits {
+    /// Drop certs this long after they were last received. A snapshot
+    /// re-sends every live cert, so this should comfortably exceed the
+    /// interval between resyncs.
+    pub ttl: Option<Duration>,
+    /// Evict the least recently used certs beyond this many.
+    pub max_entries: Option<usize>,
+}
+
+/// The latest heartbeat from the publisher. See
+/// `CertCache::heartbeat()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Heartbeat {
+    pub received: Instant,
+    pub sequence: u64,
+    /// Certs on the topic the heartbeat was sent on, if the publisher
+    /// said
+    pub certs: Option<usize>,
+    /// Identifies the publisher's run, which `sequence` counts from, if
+    /// the publisher said
+    pub epoch: Option<u64>,
+}
+
+/// A cert the feed published on the topic for another cert type,
+/// which the cache refused.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuarantinedCert {
+    pub name: String,
+    pub public_key: String,
+    /// The type in the cert's metadata
+    pub cert_type: CertType,
+    pub topic: String,
+}
+
+/// Certs refused for disagreeing with their feed topic. See
+/// `CertCache::quarantine()`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Quarantine {
+    /// Certs refused since the cache was created
+    pub total: u64,
+    /// The latest refused certs, oldest first
+    pub certs: Vec<QuarantinedCert>,
+}
+
+#[derive(Debug)]
+struct EntryTimes {
+    received: Instant,
+    used: Mutex<Instant>,
+}
+
+pub type Subscriptions = Arc<Mutex<Vec<Subscription>>>;
+
+pub struct Subscription {
+    filter: Option<Filter>,
+    callback: Box<FnMut(Change, &Cert) + Send>,
+}
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Subscription {{ filter: {:?} }}", self.filter)
+    }
+}
 
 #[derive(Debug)]
 pub struct CertCache {
     cache: HashMap<String, Cert>,
+    // Keys of the certs with each name and of each type, kept in step
+    // with `cache` by `store()` and `remove()`
+    names: HashMap<String, HashSet<String>>,
+    types: HashMap<CertType, HashSet<String>>,
+    // Unscoped snapshot frames for each topic, built on demand and
+    // dropped whenever a cert is stored or removed
+    snapshots: Mutex<HashMap<Option<CertType>, SnapshotFrames>>,
+    last_sequence: Option<u64>,
+    attestation: Option<Attestation>,
+    // Certs that didn't come from the feed, which a resync keeps
+    pinned: HashSet<String>,
+    // Topics waiting for a snapshot to replace their certs. For
+    // batched topics, it's their next complete END.
+    resync_topics: HashSet<String>,
+    // Resyncing topics whose publisher has sent a SNAPSHOT notice, so
+    // their next ADD is the snapshot
+    announced: HashSet<String>,
+    format: SnapshotFormat,
+    // The scope of the topic we subscribe to, if any
+    scope: Option<String>,
+    // Whether we subscribe to keys-only topics
+    keys_only: bool,
+    // Keys received on each resyncing batched topic, and how many
+    // certs were sent
+    batches: HashMap<String, (HashSet<String>, usize)>,
+    // Whether the feed has changed the cache since it was last saved
+    modified: bool,
+    // Endpoints of publishers that announced they are draining
+    drain_notices: Vec<Option<String>>,
+    // Publishers that advertised their endpoint
+    adverts: Vec<ServerAdvert>,
+    limits: CacheLimits,
+    // When each unpinned cert was received and last looked up
+    times: HashMap<String, EntryTimes>,
+    // Keys whose certs were revoked, or removed by `purge_expired()`,
+    // until the feed sends a valid cert for them
+    revoked: HashSet<String>,
+    expired: HashSet<String>,
+    latency: Arc<Mutex<LatencyHistogram>>,
+    quarantine: Arc<Mutex<Quarantine>>,
+    heartbeat: Arc<Mutex<Option<Heartbeat>>>,
+    // When the feed last added or removed certs
+    last_update: Option<Instant>,
+    // Publish time of the last change applied to each key, including
+    // removed ones for a while. See `is_stale()`.
+    changed_at: HashMap<String, u64>,
+    subscriptions: Subscriptions,
+    feed_verifier: Option<FeedVerifier>,
 }
 
 impl CertCache {
     pub fn new(certs: Option<Vec<Cert>>) -> CertCache {
-        let mut cache = HashMap::new();
+        let mut cache = CertCache {
+            cache: HashMap::new(),
+            names: HashMap::new(),
+            types: HashMap::new(),
+            snapshots: Mutex::new(HashMap::new()),
+            last_sequence: None,
+            attestation: None,
+            pinned: HashSet::new(),
+            resync_topics: HashSet::new(),
+            announced: HashSet::new(),
+            format: SnapshotFormat::Single,
+            scope: None,
+            keys_only: false,
+            batches: HashMap::new(),
+            modified: false,
+            drain_notices: Vec::new(),
+            adverts: Vec::new(),
+            limits: CacheLimits::default(),
+            times: HashMap::new(),
+            revoked: HashSet::new(),
+            expired: HashSet::new(),
+            latency: Arc::new(Mutex::new(LatencyHistogram::new())),
+            quarantine: Arc::new(Mutex::new(Quarantine::default())),
+            heartbeat: Arc::new(Mutex::new(None)),
+            last_update: None,
+            changed_at: HashMap::new(),
+            subscriptions: Arc::new(Mutex::new(Vec::new())),
+            feed_verifier: None,
+        };
 
-        // Warm up cache
+        // Warm up cache. Revoked certs stay on record, but must never
+        // authenticate again.
         if let Some(certs) = certs {
             for cert in certs {
-                cache.insert(cert.public_txt().to_string(), cert);
+                let pubkey = cert.public_txt().to_string();
+                if cert.is_revoked() {
+                    cache.revoked.insert(pubkey);
+                    continue;
+                }
+                cache.pinned.insert(pubkey.clone());
+                cache.store(pubkey, cert);
             }
         }
 
-        CertCache {
-            cache: cache,
-        }
+        cache
     }
 
-    // This is only used by the client
+    /// Call `callback` whenever a cert matching `filter` is added to or
+    /// removed from the cache by the feed. A `None` filter matches
+    /// every cert.
+    ///
+    /// Callbacks run on the thread applying feed messages, while the
+    /// subscription list is locked, so they must not subscribe again.
     #[allow(dead_code)]
-    pub fn get(&self, pubkey: &str) -> Option<&Cert> {
-        self.cache.get(pubkey)
+    pub fn on_change<F>(&self, filter: Option<Filter>, callback: F)
+        where F: FnMut(Change, &Cert) + Send + 'static
+    {
+        add_subscription(&self.subscriptions, filter, callback);
     }
 
-    // This is only used by the server
+    /// A handle to the subscription list, so that callers can keep
+    /// subscribing after the cache has been moved to another thread.
     #[allow(dead_code)]
-    pub fn get_name(&self, name: &str) -> Option<&Cert> {
-        for (_, cert) in &self.cache {
-            if cert.name() == name {
-                return Some(cert);
+    pub fn subscriptions(&self) -> Subscriptions {
+        self.subscriptions.clone()
+    }
+
+    fn notify(&self, change: Change, cert: &Cert) {
+        if let Ok(mut subscriptions) = self.subscriptions.lock() {
+            for sub in subscriptions.iter_mut() {
+                if sub.filter.as_ref().map(|f| f.matches_cert(cert)).unwrap_or(true) {
+                    (sub.callback)(change, cert);
+                }
             }
         }
+    }
 
-        None
+    /// A handle to the delay between the publisher timestamping feed
+    /// messages and `recv()` applying them.
+    #[allow(dead_code)]
+    pub fn latency(&self) -> Arc<Mutex<LatencyHistogram>> {
+        self.latency.clone()
     }
 
-    pub fn dump(&self, cert_type: CertType) -> Vec<&Cert> {
-        let mut dump = Vec::new();
+    /// A handle to the certs whose metadata type disagreed with the
+    /// topic they were published on, e.g. a user cert on the "host"
+    /// topic. Subscribers filtering by topic would take them for the
+    /// wrong type, so they are never cached.
+    #[allow(dead_code)]
+    pub fn quarantine(&self) -> Arc<Mutex<Quarantine>> {
+        self.quarantine.clone()
+    }
 
-        for (_, cert) in &self.cache {
-            if cert.cert_type() == cert_type {
-                dump.push(cert);
-            }
-        }
+    /// A handle to the latest heartbeat from the publisher, which sends
+    /// them periodically. A feed without them for several intervals
+    /// has most likely lost its server.
+    #[allow(dead_code)]
+    pub fn heartbeat(&self) -> Arc<Mutex<Option<Heartbeat>>> {
+        self.heartbeat.clone()
+    }
 
-        dump
+    /// When the feed last sent certs to add or remove, including
+    /// snapshots that changed nothing.
+    #[allow(dead_code)]
+    pub fn last_update(&self) -> Option<Instant> {
+        self.last_update
     }
 
-    pub fn send(&self, sock: &mut ZSock, topic: Option<CertType>) -> Result<()> {
-        let msg = ZMsg::new();
-        match topic {
-            Some(cert_type) => try!(msg.addstr(cert_type.to_str())),
-            None => try!(msg.addstr("")),
-        }
-        try!(msg.addstr("ADD"));
+    /// The number of certs cached, including pinned ones.
+    #[allow(dead_code)]
+    pub fn size(&self) -> usize {
+        self.cache.len()
+    }
 
-        for (_, cert) in &self.cache {
-            if topic.is_none() || cert.cert_type() == topic.unwrap() {
-                try!(msg.addstr(cert.public_txt()));
-                try!(msg.addbytes(&cert.encode_meta()));
+    /// The last feed sequence announced by the publisher's heartbeat.
+    #[allow(dead_code)]
+    pub fn last_sequence(&self) -> Option<u64> {
+        self.last_sequence
+    }
 
-                debug!("Sending {}", cert.public_txt());
-                for key in cert.meta_keys() {
-                    debug!("Meta {}: {}", key, cert.meta(key).unwrap().unwrap());
-                }
-            }
-        }
+    /// The most recent attestation received from the feed. It has not
+    /// been verified.
+    #[allow(dead_code)]
+    pub fn attestation(&self) -> Option<&Attestation> {
+        self.attestation.as_ref()
+    }
 
-        if msg.size() > 2 {
-            try!(msg.send(sock));
+    /// Merkle root of the cached certs, to compare with an
+    /// attestation.
+    #[allow(dead_code)]
+    pub fn merkle_root(&self) -> [u8; 32] {
+        attestation::merkle_root(self.cache.values().map(|c| (c.name(), c.public_txt())))
+    }
+
+    /// Replace the certs on each of `topics` with the next snapshot
+    /// of them, e.g. after reconnecting to the publisher. Certs it
+    /// covers that aren't in the snapshot were deleted while we were
+    /// away, so they are removed. See `snapshot_topics()`.
+    ///
+    /// A snapshot is the ADD following a SNAPSHOT notice on the topic
+    /// (see `snapshot_notice()`). Other ADDs are live changes, and are
+    /// applied as usual in the meantime. Batched topics are replaced
+    /// once the snapshot's END arrives.
+    ///
+    /// Certs passed to `new()` are kept, as they didn't come from the
+    /// feed.
+    #[allow(dead_code)]
+    pub fn resync(&mut self, topics: &[String]) {
+        for topic in topics {
+            self.batches.remove(topic);
         }
+        self.resync_topics.extend(topics.iter().cloned());
+    }
 
-        Ok(())
+    /// Publishers that have announced they are draining since the last
+    /// call, by the endpoint they advertise, if any.
+    #[allow(dead_code)]
+    pub fn take_drain_notices(&mut self) -> Vec<Option<String>> {
+        mem::replace(&mut self.drain_notices, Vec::new())
     }
 
-    pub fn recv(&mut self, sock: &mut ZSock) -> Result<ZMsg> {
-        let msg = try!(ZMsg::recv(sock));
+    /// Heartbeats received since the last call from publishers that
+    /// advertise their endpoint.
+    #[allow(dead_code)]
+    pub fn take_adverts(&mut self) -> Vec<ServerAdvert> {
+        mem::replace(&mut self.adverts, Vec::new())
+    }
 
-        // Remove topic frame
-        try!(msg.next().ok_or(Error::InvalidCertFeed));
+    /// Whether any topic passed to `resync()` is still waiting for its
+    /// snapshot.
+    #[allow(dead_code)]
+    pub fn is_resyncing(&self) -> bool {
+        !self.resync_topics.is_empty()
+    }
 
-        let action = match try!(try!(msg.next().ok_or(Error::InvalidCertFeed)).data()) {
-            Ok(s) => s,
-            Err(_) => return Err(Error::InvalidCertFeed),
+    #[allow(dead_code)]
+    pub fn limits(&self) -> CacheLimits {
+        self.limits
+    }
+
+    /// Bound the certs kept from the feed. Certs beyond `max_entries`
+    /// are evicted straight away, while expired certs are hidden from
+    /// `get()` until `expire()` removes them.
+    #[allow(dead_code)]
+    pub fn set_limits(&mut self, limits: CacheLimits) {
+        self.limits = limits;
+        self.evict();
+    }
+
+    /// Which batched topics' messages to apply, if any: those of
+    /// `format`, which the cache should be subscribed to. Others are
+    /// copies of the same changes for other subscribers. `Single` by
+    /// default.
+    #[allow(dead_code)]
+    pub fn set_format(&mut self, format: SnapshotFormat) {
+        self.format = format;
+    }
+
+    /// Only apply feed messages signed with `key`, published no more
+    /// than `max_age` ago and not already applied. Others are logged
+    /// and dropped. See `feed_signature`.
+    #[allow(dead_code)]
+    pub fn verify_feed(&mut self, key: PublicKey, max_age: Duration) {
+        self.feed_verifier = Some(FeedVerifier::new(key, max_age));
+    }
+
+    /// The scope subscribed to, if any. Messages for other scopes,
+    /// which arrive as ZMQ subscriptions match by prefix, are ignored.
+    #[allow(dead_code)]
+    pub fn set_scope(&mut self, scope: Option<String>) {
+        self.scope = scope;
+    }
+
+    /// Whether we subscribe to keys-only topics. Keys-only copies of
+    /// changes, which subscribers to every topic also receive, are
+    /// otherwise ignored.
+    #[allow(dead_code)]
+    pub fn set_keys_only(&mut self, keys_only: bool) {
+        self.keys_only = keys_only;
+    }
+
+    /// Remove certs that have outlived the TTL as of `now`.
+    #[allow(dead_code)]
+    pub fn expire(&mut self, now: Instant) {
+        let ttl = match self.limits.ttl {
+            Some(ttl) => ttl,
+            None => return,
         };
 
-        match action.as_ref() {
-            "ADD" => {
-                while let Some(frame) = msg.next() {
-                    let pubkey = match try!(frame.data()) {
-                        Ok(s) => s,
-                        Err(_) => return Err(Error::InvalidCertFeed),
-                    };
+        let expired: Vec<String> = self.times.iter()
+            .filter(|&(_, t)| now.duration_since(t.received) >= ttl)
+            .map(|(k, _)| k.clone())
+            .collect();
 
-                    if let Some(frame) = msg.next() {
-                        let meta = match try!(frame.data()) {
-                            Ok(s) => s.into_bytes(),
-                            Err(b) => b,
-                        };
+        for pubkey in expired {
+            if let Some(cert) = self.remove(&pubkey) {
+                debug!("Expiring {}", pubkey);
+                self.notify(Change::Removed, &cert);
+                self.modified = true;
+            }
+        }
+    }
 
-                        let zcert = try!(ZCert::from_txt(&pubkey, "0000000000000000000000000000000000000000"));
-                        try!(zcert.decode_meta(&meta));
+    // Remove the least recently used certs beyond `max_entries`
+    fn evict(&mut self) {
+        let max = match self.limits.max_entries {
+            Some(max) if self.times.len() > max => max,
+            _ => return,
+        };
 
-                        debug!("Receiving {}", pubkey);
-                        for key in zcert.meta_keys() {
-                            debug!("Meta {}: {}", key, zcert.meta(key).unwrap().unwrap());
-                        }
+        let mut lru: Vec<(Instant, String)> = self.times.iter().map(|(k, t)| (*t.used.lock().unwrap(), k.clone())).collect();
+        lru.sort();
+        let excess = lru.len() - max;
 
-                        self.cache.insert(zcert.public_txt().to_string(), try!(Cert::from_zcert(zcert)));
-                    } else {
-                        break;
-                    }
-                }
-            },
-            "DEL" => {
-                let pubkey = match try!(try!(msg.next().ok_or(Error::InvalidCertFeed)).data()) {
-                    Ok(s) => s,
-                    Err(_) => return Err(Error::InvalidCertFeed),
-                };
+        for (_, pubkey) in lru.into_iter().take(excess) {
+            if let Some(cert) = self.remove(&pubkey) {
+                debug!("Evicting {}", pubkey);
+                self.notify(Change::Removed, &cert);
+                self.modified = true;
+            }
+        }
+    }
+
+    fn insert(&mut self, pubkey: String, cert: Cert) {
+        self.revoked.remove(&pubkey);
+        self.expired.remove(&pubkey);
+        if !self.pinned.contains(&pubkey) {
+            let now = Instant::now();
+            self.times.insert(pubkey.clone(), EntryTimes { received: now, used: Mutex::new(now) });
+        }
+        self.store(pubkey, cert);
+    }
+
+    // Add to the cache and its indexes, replacing any cert with the
+    // same key
+    fn store(&mut self, pubkey: String, cert: Cert) {
+        self.snapshots.lock().unwrap().clear();
+        if let Some(old) = self.cache.remove(&pubkey) {
+            self.unindex(&pubkey, &old);
+        }
+        self.names.entry(cert.name().to_string()).or_insert_with(HashSet::new).insert(pubkey.clone());
+        self.types.entry(cert.cert_type()).or_insert_with(HashSet::new).insert(pubkey.clone());
+        self.cache.insert(pubkey, cert);
+    }
 
-                self.cache.remove(&pubkey);
+    fn unindex(&mut self, pubkey: &str, cert: &Cert) {
+        let empty = match self.names.get_mut(cert.name()) {
+            Some(keys) => {
+                keys.remove(pubkey);
+                keys.is_empty()
             },
-            _ => return Err(Error::InvalidCertFeed),
+            None => false,
+        };
+        if empty {
+            self.names.remove(cert.name());
+        }
+        if let Some(keys) = self.types.get_mut(&cert.cert_type()) {
+            keys.remove(pubkey);
         }
+    }
 
-        Ok(msg)
+    // Add a cert from the feed, returning false if it was revoked.
+    // Revocations take effect straight away rather than waiting for a
+    // DEL.
+    fn add_cert(&mut self, cert: Cert) -> bool {
+        let pubkey = cert.public_txt().to_string();
+        if cert.is_revoked() {
+            debug!("Revoking {}", pubkey);
+            if let Some(old) = self.remove(&pubkey) {
+                self.pinned.remove(&pubkey);
+                self.notify(Change::Removed, &old);
+            }
+            self.revoked.insert(pubkey);
+            self.modified = true;
+            return false;
+        }
+
+        self.notify(Change::Added, &cert);
+        self.insert(pubkey, cert);
+        self.modified = true;
+        true
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use cert::{Cert, CertType};
-    use czmq::{ZCert, ZMsg, ZSock, ZSys};
-    use super::*;
+    // Replace the metadata of a cert we already hold, ignoring unknown
+    // keys as only ADD introduces certs. A cert can't change type.
+    fn update_cert(&mut self, topic: &str, cert: Cert) {
+        let pubkey = cert.public_txt().to_string();
+        match self.cache.get(&pubkey).map(|c| c.cert_type()) {
+            Some(t) if t == cert.cert_type() => (),
+            Some(_) => {
+                self.quarantine_cert(topic, &cert);
+                return;
+            },
+            None => {
+                debug!("Ignoring update to unknown key {}", pubkey);
+                return;
+            },
+        }
 
-    #[test]
-    fn test_get() {
-        let (cache, pubkey) = create_cache();
+        if cert.is_revoked() {
+            self.add_cert(cert);
+            return;
+        }
 
-        assert!(cache.get("nonexistent").is_none());
-        assert_eq!(cache.get(&pubkey).unwrap().public_txt(), &pubkey);
+        debug!("Updating {}", pubkey);
+        self.notify(Change::Updated, &cert);
+        self.insert(pubkey, cert);
+        self.modified = true;
     }
 
-    #[test]
-    fn test_get_name() {
-        let (cache, _) = create_cache();
+    fn quarantine_cert(&mut self, topic: &str, cert: &Cert) {
+        warn!(target: "audit", "Quarantined {} cert {} ({}) published on the \"{}\" topic", cert.cert_type().to_str(), cert.name(), cert.public_txt(), topic);
 
-        assert!(cache.get_name("nonexistent").is_none());
-        assert_eq!(cache.get_name("peetar!").unwrap().name(), "peetar!");
+        let mut quarantine = self.quarantine.lock().unwrap();
+        quarantine.total += 1;
+        if quarantine.certs.len() == QUARANTINE_LIMIT {
+            quarantine.certs.remove(0);
+        }
+        quarantine.certs.push(QuarantinedCert {
+            name: cert.name().to_string(),
+            public_key: cert.public_txt().to_string(),
+            cert_type: cert.cert_type(),
+            topic: topic.to_string(),
+        });
     }
 
-    #[test]
-    fn test_send() {
-        ZSys::init();
-        let (cache, pubkey) = create_cache();
+    fn remove(&mut self, pubkey: &str) -> Option<Cert> {
+        self.times.remove(pubkey);
+        let cert = self.cache.remove(pubkey);
+        if let Some(ref c) = cert {
+            self.unindex(pubkey, c);
+            self.snapshots.lock().unwrap().clear();
+        }
+        cert
+    }
 
-        let mut client = ZSock::new_push("inproc://cert_cache_send").unwrap();
-        let mut server = ZSock::new_pull("inproc://cert_cache_send").unwrap();
-        server.set_rcvtimeo(Some(500));
+    // Whether a change to `pubkey` published at `published` is older
+    // than one already applied, e.g. because it comes from a publisher
+    // that hasn't caught up with another. If not, it becomes the
+    // latest. Unstamped changes are never stale.
+    fn is_stale(&mut self, pubkey: &str, published: Option<u64>) -> bool {
+        let published = match published {
+            Some(p) => p,
+            None => return false,
+        };
+        if self.changed_at.get(pubkey).map(|p| *p > published).unwrap_or(false) {
+            debug!("Ignoring a change to {} older than the last one applied", pubkey);
+            return true;
+        }
+        self.changed_at.insert(pubkey.to_string(), published);
+        false
+    }
 
-        cache.send(&mut client, Some(CertType::Host)).unwrap();
-        assert!(server.recv_str().is_err());
+    // Forget when removed certs last changed, once older changes can
+    // no longer be in flight
+    fn prune_tombstones(&mut self, now: u64) {
+        let cache = &self.cache;
+        self.changed_at.retain(|k, p| cache.contains_key(k) || *p + TOMBSTONE_MICROS > now);
+    }
 
-        cache.send(&mut client, Some(CertType::User)).unwrap();
-        let msg = ZMsg::recv(&mut server).unwrap();
-        msg.popstr().unwrap().unwrap(); // Discard topic
-        assert_eq!(msg.popstr().unwrap().unwrap(), "ADD");
-        assert_eq!(msg.popstr().unwrap().unwrap(), pubkey);
+    // Remove certs of `cert_type` (or any type) that aren't in `keep`
+    fn replace(&mut self, cert_type: Option<CertType>, keep: &HashSet<String>) {
+        let stale: Vec<String> = self.cache.iter()
+            .filter(|&(k, c)| cert_type.map(|t| c.cert_type() == t).unwrap_or(true) && !keep.contains(k) && !self.pinned.contains(k))
+            .map(|(k, _)| k.clone())
+            .collect();
 
-        let zcert = ZCert::new().unwrap();
-        zcert.decode_meta(&msg.popbytes().unwrap().unwrap()).unwrap();
-        assert_eq!(zcert.meta("name").unwrap().unwrap(), "peetar!");
-        assert_eq!(zcert.meta("type").unwrap().unwrap(), "user");
+        for pubkey in stale {
+            if let Some(cert) = self.remove(&pubkey) {
+                debug!("Dropping {} after resync", pubkey);
+                self.notify(Change::Removed, &cert);
+                self.modified = true;
+            }
+        }
     }
 
-    #[test]
-    fn test_recv() {
-        ZSys::init();
+    /// Whether the feed has added or removed certs since the cache
+    /// was last saved.
+    #[allow(dead_code)]
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
 
-        let mut cache = CertCache::new(None);
-        let c1 = Cert::new("dan", CertType::User).unwrap();
-        let c2 = Cert::new("web1.example.com", CertType::Host).unwrap();
+    /// Write the certs received from the feed to `path`, so that
+    /// `load()` can restore them after a restart. Certs passed to
+    /// `new()` aren't saved.
+    ///
+    /// The file is written alongside `path` and renamed over it, so a
+    /// crash never leaves a partial cache behind.
+    #[allow(dead_code)]
+    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let certs: Vec<Value> = self.cache.iter()
+            .filter(|&(k, _)| !self.pinned.contains(k))
+            .map(|(_, cert)| {
+                let mut meta = Map::new();
+                for key in cert.meta_keys() {
+                    if let Some(Ok(value)) = cert.meta(key) {
+                        meta.insert(key.to_string(), Value::String(value));
+                    }
+                }
 
-        let mut client = ZSock::new_push("inproc://cert_cache_recv").unwrap();
-        let mut server = ZSock::new_pull("inproc://cert_cache_recv").unwrap();
-        server.set_rcvtimeo(Some(500));
+                let mut entry = Map::new();
+                entry.insert("public_key".into(), Value::String(cert.public_txt().to_string()));
+                entry.insert("meta".into(), Value::Object(meta));
+                Value::Object(entry)
+            })
+            .collect();
 
-        assert!(cache.recv(&mut server).is_err());
+        let mut doc = Map::new();
+        doc.insert("version".into(), Value::from(CACHE_FILE_VERSION));
+        doc.insert("certs".into(), Value::Array(certs));
 
-        let msg = ZMsg::new();
-        msg.addstr("topic").unwrap();
-        msg.addstr("ADD").unwrap();
-        msg.addstr(c1.public_txt()).unwrap();
-        msg.addbytes(&c1.encode_meta()).unwrap();
-        msg.addstr(c2.public_txt()).unwrap();
-        msg.addbytes(&c2.encode_meta()).unwrap();
-        msg.send(&mut client).unwrap();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        {
+            let mut fh = try!(File::create(&tmp_path));
+            try!(fh.write_all(try!(serde_json::to_string(&Value::Object(doc))).as_bytes()));
+            try!(fh.sync_all());
+        }
+        try!(fs::rename(&tmp_path, path));
 
-        assert!(cache.recv(&mut server).is_ok());
-        assert!(cache.cache.contains_key(c1.public_txt()));
-        assert!(cache.cache.contains_key(c2.public_txt()));
+        self.modified = false;
+        Ok(())
+    }
 
-        let msg = ZMsg::new();
-        msg.addstr("topic").unwrap();
-        msg.addstr("DEL").unwrap();
-        msg.addstr(c1.public_txt()).unwrap();
-        msg.send(&mut client).unwrap();
+    /// Add certs saved by `save()`, optionally only those of
+    /// `cert_type`, and return how many were added. Certs already in
+    /// the cache are kept.
+    ///
+    /// Loaded certs are treated as if they came from the feed, so the
+    /// next resync drops any that have since been deleted.
+    #[allow(dead_code)]
+    pub fn load<P: AsRef<Path>>(&mut self, path: P, cert_type: Option<CertType>) -> Result<usize> {
+        let mut json = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut json));
+        let doc: Value = try!(serde_json::from_str(&json));
 
-        assert!(cache.recv(&mut server).is_ok());
-        assert!(!cache.cache.contains_key(c1.public_txt()));
+        if doc.get("version").and_then(|v| v.as_u64()) != Some(CACHE_FILE_VERSION) {
+            return Err(Error::InvalidCertCache);
+        }
+        let entries = try!(doc.get("certs").and_then(|c| c.as_array()).ok_or(Error::InvalidCertCache));
+
+        let mut loaded = 0;
+        for entry in entries {
+            let pubkey = try!(entry.get("public_key").and_then(|k| k.as_str()).ok_or(Error::InvalidCertCache));
+            let meta = try!(entry.get("meta").and_then(|m| m.as_object()).ok_or(Error::InvalidCertCache));
+
+            let zcert = try!(ZCert::from_txt(pubkey, "0000000000000000000000000000000000000000"));
+            for (key, value) in meta {
+                zcert.set_meta(key, try!(value.as_str().ok_or(Error::InvalidCertCache)));
+            }
+            let cert = try!(Cert::from_zcert(zcert));
+
+            if cert_type.map(|t| cert.cert_type() == t).unwrap_or(true) && !self.cache.contains_key(pubkey) {
+                self.insert(pubkey.to_string(), cert);
+                loaded += 1;
+            }
+        }
+
+        self.evict();
+        Ok(loaded)
+    }
+
+    // This is only used by the client
+    #[allow(dead_code)]
+    pub fn get(&self, pubkey: &str) -> Option<&Cert> {
+        if let Some(t) = self.times.get(pubkey) {
+            if self.limits.ttl.map(|ttl| t.received.elapsed() >= ttl).unwrap_or(false) {
+                return None;
+            }
+            *t.used.lock().unwrap() = Instant::now();
+        }
+        let now = latency::now_micros() / 1_000_000;
+        self.cache.get(pubkey).and_then(|c| if c.is_expired_at(now) || c.is_disabled() { None } else { Some(c) })
+    }
+
+    /// Whether `pubkey`'s cert is hidden from `get()` because it is
+    /// disabled.
+    #[allow(dead_code)]
+    pub fn is_disabled(&self, pubkey: &str) -> bool {
+        self.cache.get(pubkey).map(|c| c.is_disabled()).unwrap_or(false)
+    }
+
+    /// Whether `pubkey`'s cert is hidden from `get()` because it has
+    /// outlived the TTL or its own expiry, or was purged for the
+    /// latter.
+    #[allow(dead_code)]
+    pub fn is_expired(&self, pubkey: &str) -> bool {
+        let ttl_expired = match (self.times.get(pubkey), self.limits.ttl) {
+            (Some(t), Some(ttl)) => t.received.elapsed() >= ttl,
+            _ => false,
+        };
+        let now = latency::now_micros() / 1_000_000;
+        ttl_expired || self.expired.contains(pubkey) || self.cache.get(pubkey).map(|c| c.is_expired_at(now)).unwrap_or(false)
+    }
+
+    /// Whether the feed revoked `pubkey`'s cert.
+    #[allow(dead_code)]
+    pub fn is_revoked(&self, pubkey: &str) -> bool {
+        self.revoked.contains(pubkey)
+    }
+
+    /// Remove certs whose own expiry has passed as of `now`, in seconds
+    /// since the Unix epoch. Unlike `expire()`, this includes certs
+    /// passed to `new()`. Returns how many were removed.
+    #[allow(dead_code)]
+    pub fn purge_expired(&mut self, now: u64) -> usize {
+        let expired: Vec<String> = self.cache.iter()
+            .filter(|&(_, c)| c.is_expired_at(now))
+            .map(|(k, _)| k.clone())
+            .collect();
+
+        for pubkey in &expired {
+            if let Some(cert) = self.remove(pubkey) {
+                debug!("Purging expired cert {}", pubkey);
+                self.pinned.remove(pubkey);
+                self.expired.insert(pubkey.clone());
+                self.notify(Change::Removed, &cert);
+                self.modified = true;
+            }
+        }
+        expired.len()
+    }
+
+    // This is only used by the server
+    #[allow(dead_code)]
+    pub fn get_name(&self, name: &str) -> Option<&Cert> {
+        self.names.get(name)
+            .and_then(|keys| keys.iter().next())
+            .and_then(|pubkey| self.cache.get(pubkey))
+    }
+
+    pub fn dump(&self, cert_type: CertType) -> Vec<&Cert> {
+        match self.types.get(&cert_type) {
+            Some(keys) => keys.iter().filter_map(|pubkey| self.cache.get(pubkey)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Convenience wrapper for callers that don't need to inspect the
+    // snapshot before it is sent.
+    #[allow(dead_code)]
+    pub fn send(&self, sock: &mut ZSock, topic: Option<CertType>, scope: Option<&str>) -> Result<()> {
+        if let Some(msg) = try!(self.snapshot(topic, scope)) {
+            try!(msg.send(sock));
+        }
+
+        Ok(())
+    }
+
+    /// Build the ADD message sent to new subscribers, or `None` if
+    /// there are no certificates for `topic` and `scope`.
+    pub fn snapshot(&self, topic: Option<CertType>, scope: Option<&str>) -> Result<Option<ZMsg>> {
+        if scope.is_none() {
+            return snapshot_msg(topic, &self.snapshot_frames(topic));
+        }
+
+        let frames = self.cert_frames(topic, scope);
+        if frames.is_empty() {
+            return Ok(None);
+        }
+
+        let msg = ZMsg::new();
+        try!(msg.addstr(&scoped_topic(topic, scope)));
+        try!(msg.addstr("ADD"));
+        for frame in &frames {
+            try!(msg.addbytes(frame));
+        }
+        Ok(Some(msg))
+    }
+
+    /// The public key and metadata frames of an unscoped snapshot of
+    /// `topic`. Pass them to `snapshot_msg()` after releasing the cache,
+    /// so that building and sending the message doesn't hold it up.
+    pub fn snapshot_frames(&self, topic: Option<CertType>) -> SnapshotFrames {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        if let Some(frames) = snapshots.get(&topic) {
+            return frames.clone();
+        }
+
+        let frames = Arc::new(self.cert_frames(topic, None));
+        snapshots.insert(topic, frames.clone());
+        frames
+    }
+
+    // Public key and metadata frames of the certs of `topic` in `scope`
+    fn cert_frames(&self, topic: Option<CertType>, scope: Option<&str>) -> Vec<Vec<u8>> {
+        let keys: Vec<&String> = match topic {
+            Some(t) => self.types.get(&t).map(|k| k.iter().collect()).unwrap_or(Vec::new()),
+            None => self.cache.keys().collect(),
+        };
+
+        let mut frames = Vec::with_capacity(keys.len() * 2);
+        for key in keys {
+            if let Some(cert) = self.cache.get(key) {
+                if scope.map(|s| in_scope(cert, s)).unwrap_or(true) {
+                    frames.push(cert.public_txt().as_bytes().to_vec());
+                    frames.push(cert.encode_meta());
+                }
+            }
+        }
+        frames
+    }
+
+    /// The current state of `keys`, changed since a subscriber's resume
+    /// position: one ADD on `cert_type`'s topic with those still
+    /// cached, and a DEL for each of the rest.
+    #[allow(dead_code)]
+    pub fn changes(&self, cert_type: CertType, keys: &[String]) -> Result<Vec<ZMsg>> {
+        let mut changes = Vec::new();
+        let add = ZMsg::new();
+        try!(add.addstr(cert_type.to_str()));
+        try!(add.addstr("ADD"));
+
+        for key in keys {
+            match self.cache.get(key) {
+                Some(cert) if cert.cert_type() == cert_type => {
+                    try!(add.addstr(cert.public_txt()));
+                    try!(add.addbytes(&cert.encode_meta()));
+                },
+                _ => {
+                    let del = ZMsg::new();
+                    try!(del.addstr(cert_type.to_str()));
+                    try!(del.addstr("DEL"));
+                    try!(del.addstr(key));
+                    changes.push(del);
+                },
+            }
+        }
+
+        if add.size() > 2 {
+            changes.insert(0, add);
+        }
+        Ok(changes)
+    }
+
+    /// Like `snapshot()`, but for subscribers that asked for batches in
+    /// `format`: ADDs of up to `batch_size` certs, each passed to
+    /// `send` as soon as it is built, then an END with the number of
+    /// certs sent. Topics without certs just get the END. Returns the
+    /// number of certs sent.
+    pub fn snapshot_batches<F>(&self, topic: Option<CertType>, scope: Option<&str>, format: SnapshotFormat, batch_size: usize, mut send: F) -> Result<usize>
+        where F: FnMut(ZMsg) -> Result<()>
+    {
+        let topic_str = format!("{}{}", format.topic_prefix(), scoped_topic(topic, scope));
+        // Unscoped snapshots share the metadata encoded for single ADDs
+        let frames = match scope {
+            Some(_) => Arc::new(self.cert_frames(topic, scope)),
+            None => self.snapshot_frames(topic),
+        };
+
+        for batch in frames.chunks(cmp::max(batch_size, 1) * 2) {
+            let msg = ZMsg::new();
+            try!(msg.addstr(&topic_str));
+            try!(msg.addstr("ADD"));
+            for cert in batch.chunks(2) {
+                try!(msg.addbytes(&cert[0]));
+                match format {
+                    SnapshotFormat::Compressed | SnapshotFormat::Cbor => try!(msg.addbytes(&try!(format.encode_meta(cert[1].clone())))),
+                    _ => try!(msg.addbytes(&cert[1])),
+                }
+            }
+            try!(send(msg));
+        }
+
+        let certs = frames.len() / 2;
+        let end = ZMsg::new();
+        try!(end.addstr(&topic_str));
+        try!(end.addstr("END"));
+        try!(end.addstr(&certs.to_string()));
+        try!(send(end));
+        Ok(certs)
+    }
+
+    /// Like `snapshot()`, but for keys-only subscribers of `cert_type`.
+    pub fn snapshot_keys(&self, cert_type: CertType) -> Result<Option<ZMsg>> {
+        let msg = ZMsg::new();
+        try!(msg.addstr(&keys_only_topic(cert_type)));
+        try!(msg.addstr("ADD"));
+
+        if let Some(keys) = self.types.get(&cert_type) {
+            for key in keys {
+                try!(msg.addstr(key));
+            }
+        }
+
+        if msg.size() > 2 {
+            Ok(Some(msg))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Replace the feed's certs of `cert_type` (or every type) with a
+    /// `feed::resync` reply, read from `msg`'s next frame onwards: the
+    /// snapshot's feed sequence followed by alternating public keys
+    /// and metadata.
+    ///
+    /// The whole snapshot is decoded before the cache is touched, so a
+    /// bad reply leaves it as it was. Returns the number of certs
+    /// applied, or `None` if the feed has already moved past the
+    /// snapshot's sequence, as applying it could undo newer changes.
+    #[allow(dead_code)]
+    pub fn apply_snapshot(&mut self, cert_type: Option<CertType>, msg: &ZMsg) -> Result<Option<usize>> {
+        let sequence: u64 = match try!(try!(msg.next().ok_or(Error::InvalidCertFeed)).data()) {
+            Ok(s) => try!(s.parse().or(Err(Error::InvalidCertFeed))),
+            Err(_) => return Err(Error::InvalidCertFeed),
+        };
+
+        let mut certs = Vec::new();
+        while let Some(frame) = msg.next() {
+            let pubkey = match try!(frame.data()) {
+                Ok(s) => s,
+                Err(_) => return Err(Error::InvalidCertFeed),
+            };
+            let meta = match try!(try!(msg.next().ok_or(Error::InvalidCertFeed)).data()) {
+                Ok(s) => s.into_bytes(),
+                Err(b) => b,
+            };
+            certs.push(try!(decode_cert(&pubkey, &meta)));
+        }
+
+        if self.last_sequence.map(|s| s > sequence).unwrap_or(false) {
+            debug!("Ignoring snapshot at sequence {}, already at {}", sequence, self.last_sequence.unwrap());
+            return Ok(None);
+        }
+
+        let topic = cert_type.map(|t| t.to_str()).unwrap_or("");
+        let mut received = HashSet::new();
+        for cert in certs {
+            if cert_type.map(|t| t != cert.cert_type()).unwrap_or(false) {
+                self.quarantine_cert(topic, &cert);
+                continue;
+            }
+            let pubkey = cert.public_txt().to_string();
+            if self.add_cert(cert) {
+                received.insert(pubkey);
+            }
+        }
+        self.replace(cert_type, &received);
+        self.resync_topics.remove(topic);
+        self.announced.remove(topic);
+        self.last_sequence = Some(sequence);
+        self.last_update = Some(Instant::now());
+        self.evict();
+
+        Ok(Some(received.len()))
+    }
+
+    /// Receive and apply a feed message, returning it. Messages that
+    /// fail `verify_feed()` are returned without being applied.
+    pub fn recv(&mut self, sock: &mut ZSock) -> Result<ZMsg> {
+        let msg = try!(ZMsg::recv(sock));
+        self.apply(msg)
+    }
+
+    /// As `recv()`, for a message the caller has already received.
+    pub fn apply(&mut self, msg: ZMsg) -> Result<ZMsg> {
+        if let Some(ref mut verifier) = self.feed_verifier {
+            if let Err(e) = verifier.check(&msg, latency::now_micros()) {
+                warn!(target: "audit", "Dropped feed message: {}", e);
+                return Ok(msg);
+            }
+        }
+
+        // The timestamp frame is skipped below, as each action ignores
+        // trailing frames it doesn't expect.
+        let published = published_at(&msg);
+
+        let topic = match try!(try!(msg.first().ok_or(Error::InvalidCertFeed)).data()) {
+            Ok(s) => s,
+            Err(_) => return Err(Error::InvalidCertFeed),
+        };
+        let (format, base_topic) = SnapshotFormat::from_topic(&topic);
+        if format != SnapshotFormat::Single && format != self.format {
+            // Another format's copy of a change
+            return Ok(msg);
+        }
+        let (base_topic, scope) = split_scope(base_topic);
+        if scope.is_some() && scope != self.scope.as_ref().map(|s| s.as_str()) {
+            // Another scope's copy of a change
+            return Ok(msg);
+        }
+        let keys_only = if base_topic.starts_with(KEYS_ONLY_TOPIC_PREFIX) {
+            Some(try!(CertType::from_str(&base_topic[KEYS_ONLY_TOPIC_PREFIX.len()..]).or(Err(Error::InvalidCertFeed))))
+        } else {
+            None
+        };
+        if keys_only.is_some() && !self.keys_only {
+            // Keys-only copy of a change
+            return Ok(msg);
+        }
+
+        // Full certs on a cert type's topic must be of that type
+        let topic_type = match keys_only {
+            Some(_) => None,
+            None => CertType::from_str(base_topic).ok(),
+        };
+
+        let action = match try!(try!(msg.next().ok_or(Error::InvalidCertFeed)).data()) {
+            Ok(s) => s,
+            Err(_) => return Err(Error::InvalidCertFeed),
+        };
+
+        let mut resync = action == "ADD" && format == SnapshotFormat::Single && self.announced.remove(&topic);
+        if resync {
+            self.resync_topics.remove(&topic);
+        }
+        let mut received = HashSet::new();
+        let mut sent = 0;
+
+        match action.as_ref() {
+            "ADD" if keys_only.is_some() => {
+                while let Some(frame) = msg.next() {
+                    let pubkey = match try!(frame.data()) {
+                        Ok(s) => s,
+                        Err(_) => return Err(Error::InvalidCertFeed),
+                    };
+                    if is_trailer(&pubkey) {
+                        break;
+                    }
+                    if self.is_stale(&pubkey, published) {
+                        continue;
+                    }
+                    received.insert(pubkey.clone());
+
+                    // Keys-only messages mustn't replace full certs,
+                    // such as our own
+                    if !self.cache.contains_key(&pubkey) {
+                        debug!("Receiving key {}", pubkey);
+                        let cert = try!(minimal_cert(&pubkey, keys_only.unwrap()));
+                        self.notify(Change::Added, &cert);
+                        self.insert(pubkey, cert);
+                        self.modified = true;
+                    } else if let Some(t) = self.times.get_mut(&pubkey) {
+                        t.received = Instant::now();
+                    }
+                }
+            },
+            "ADD" => {
+                while let Some(frame) = msg.next() {
+                    let pubkey = match try!(frame.data()) {
+                        Ok(s) => s,
+                        Err(_) => return Err(Error::InvalidCertFeed),
+                    };
+                    if is_trailer(&pubkey) {
+                        break;
+                    }
+
+                    if let Some(frame) = msg.next() {
+                        let meta = match try!(frame.data()) {
+                            Ok(s) => s.into_bytes(),
+                            Err(b) => b,
+                        };
+                        sent += 1;
+
+                        let cert = try!(decode_cert(&pubkey, &try!(format.decode_meta(meta))));
+                        if topic_type.map(|t| t != cert.cert_type()).unwrap_or(false) {
+                            self.quarantine_cert(&topic, &cert);
+                            continue;
+                        }
+                        if self.is_stale(&pubkey, published) {
+                            continue;
+                        }
+
+                        if self.add_cert(cert) {
+                            received.insert(pubkey);
+                        }
+                    } else {
+                        break;
+                    }
+                }
+            },
+            "UPDATE" if keys_only.is_none() => {
+                while let Some(frame) = msg.next() {
+                    let pubkey = match try!(frame.data()) {
+                        Ok(s) => s,
+                        Err(_) => return Err(Error::InvalidCertFeed),
+                    };
+                    if is_trailer(&pubkey) {
+                        break;
+                    }
+
+                    if let Some(frame) = msg.next() {
+                        let meta = match try!(frame.data()) {
+                            Ok(s) => s.into_bytes(),
+                            Err(b) => b,
+                        };
+
+                        let cert = try!(decode_cert(&pubkey, &try!(format.decode_meta(meta))));
+                        if topic_type.map(|t| t != cert.cert_type()).unwrap_or(false) {
+                            self.quarantine_cert(&topic, &cert);
+                            continue;
+                        }
+                        if self.is_stale(&pubkey, published) {
+                            continue;
+                        }
+                        self.update_cert(&topic, cert);
+                    } else {
+                        break;
+                    }
+                }
+            },
+            // The next ADD on the topic is a snapshot. Only subscribers
+            // waiting for one need it.
+            "SNAPSHOT" => {
+                if format == SnapshotFormat::Single && self.resync_topics.contains(&topic) {
+                    self.announced.insert(topic.clone());
+                }
+            },
+            // A batched snapshot is complete once every cert it sent
+            // has arrived. Otherwise we joined part way through one
+            // sent to another subscriber, so wait for our own.
+            "END" => {
+                let total: usize = match try!(try!(msg.next().ok_or(Error::InvalidCertFeed)).data()) {
+                    Ok(s) => try!(s.parse().or(Err(Error::InvalidCertFeed))),
+                    Err(_) => return Err(Error::InvalidCertFeed),
+                };
+
+                if self.resync_topics.contains(&topic) {
+                    let (keys, count) = self.batches.remove(&topic).unwrap_or((HashSet::new(), 0));
+                    if count >= total {
+                        self.resync_topics.remove(&topic);
+                        received = keys;
+                        resync = true;
+                    } else {
+                        debug!("Ignoring partial snapshot of {} of {} certs on \"{}\"", count, total, topic);
+                    }
+                }
+            },
+            "DEL" => {
+                let pubkey = match try!(try!(msg.next().ok_or(Error::InvalidCertFeed)).data()) {
+                    Ok(s) => s,
+                    Err(_) => return Err(Error::InvalidCertFeed),
+                };
+                if self.is_stale(&pubkey, published) {
+                    return Ok(msg);
+                }
+
+                if let Some(cert) = self.remove(&pubkey) {
+                    self.notify(Change::Removed, &cert);
+                    self.modified = true;
+                }
+            },
+            "HEARTBEAT" => {
+                let sequence = match try!(try!(msg.next().ok_or(Error::InvalidCertFeed)).data()) {
+                    Ok(s) => s,
+                    Err(_) => return Err(Error::InvalidCertFeed),
+                };
+
+                let sequence = try!(sequence.parse().or(Err(Error::InvalidCertFeed)));
+                self.last_sequence = Some(sequence);
+                self.prune_tombstones(latency::now_micros());
+
+                // Metadata frames are "key=value". Unknown keys are
+                // ignored so publishers can add more.
+                let mut draining = false;
+                let mut endpoint = None;
+                let mut tags = Vec::new();
+                let mut certs = None;
+                let mut epoch = None;
+                while let Some(frame) = msg.next() {
+                    if let Ok(meta) = try!(frame.data()) {
+                        match meta.find('=').map(|i| meta.split_at(i)) {
+                            Some(("state", "=draining")) => draining = true,
+                            Some(("endpoint", value)) => endpoint = Some(value[1..].to_string()),
+                            Some(("tags", value)) => tags = value[1..].split(',').filter(|t| !t.is_empty()).map(|t| t.to_string()).collect(),
+                            Some(("certs", value)) => certs = value[1..].parse().ok(),
+                            Some(("epoch", value)) => epoch = value[1..].parse().ok(),
+                            _ => (),
+                        }
+                    }
+                }
+                *self.heartbeat.lock().unwrap() = Some(Heartbeat {
+                    received: Instant::now(),
+                    sequence: sequence,
+                    certs: certs,
+                    epoch: epoch,
+                });
+                if draining {
+                    self.drain_notices.push(endpoint.clone());
+                }
+                if let Some(endpoint) = endpoint {
+                    let now = latency::now_micros();
+                    self.adverts.push(ServerAdvert {
+                        endpoint: endpoint,
+                        tags: tags,
+                        latency_micros: published.and_then(|p| if p <= now { Some(now - p) } else { None }),
+                    });
+                }
+            },
+            "ATTEST" => {
+                let mut frames = Vec::new();
+                while let Some(frame) = msg.next() {
+                    match try!(frame.data()) {
+                        Ok(s) => frames.push(s),
+                        Err(_) => return Err(Error::InvalidCertFeed),
+                    }
+                }
+
+                self.attestation = Some(try!(Attestation::from_frames(&frames)));
+            },
+            _ => return Err(Error::InvalidCertFeed),
+        }
+
+        if action == "ADD" || action == "UPDATE" || action == "DEL" {
+            self.last_update = Some(Instant::now());
+        }
+
+        if action == "ADD" && format != SnapshotFormat::Single && self.resync_topics.contains(&topic) {
+            let batch = self.batches.entry(topic.clone()).or_insert((HashSet::new(), 0));
+            batch.0.extend(received.drain());
+            batch.1 += sent;
+        }
+
+        if resync {
+            let cert_type = match keys_only {
+                Some(t) => Some(t),
+                None if base_topic.is_empty() => None,
+                None => Some(try!(CertType::from_str(base_topic).or(Err(Error::InvalidCertFeed)))),
+            };
+            // Changes published after the snapshot stand
+            if let Some(published) = published {
+                received.extend(self.changed_at.iter().filter(|&(_, p)| *p > published).map(|(k, _)| k.clone()));
+            }
+            self.replace(cert_type, &received);
+        }
+        self.evict();
+
+        if let Some(published) = published {
+            self.latency.lock().unwrap().record(published, latency::now_micros());
+        }
+
+        Ok(msg)
+    }
+}
+
+/// A `CertCache` that threads can share. Clones refer to the same
+/// cache.
+///
+/// Lookups take a read lock, so ZAP workers and API handlers can run
+/// them at once. Feed messages are applied under the write lock.
+#[derive(Clone, Debug)]
+pub struct SharedCertCache(Arc<RwLock<CertCache>>);
+
+impl SharedCertCache {
+    pub fn new(cache: CertCache) -> SharedCertCache {
+        SharedCertCache(Arc::new(RwLock::new(cache)))
+    }
+
+    /// Panics if a thread panicked while holding the write lock, as
+    /// the cache may be half updated.
+    pub fn read(&self) -> RwLockReadGuard<CertCache> {
+        self.0.read().unwrap()
+    }
+
+    /// See `read()`.
+    pub fn write(&self) -> RwLockWriteGuard<CertCache> {
+        self.0.write().unwrap()
+    }
+}
+
+/// Frames from `CertCache::snapshot_frames()`. They are shared with
+/// the cache until it changes, so cloning is cheap.
+pub type SnapshotFrames = Arc<Vec<Vec<u8>>>;
+
+/// Build the ADD message for an unscoped snapshot of `topic`, or `None`
+/// if there are no certs.
+pub fn snapshot_msg(topic: Option<CertType>, frames: &[Vec<u8>]) -> Result<Option<ZMsg>> {
+    if frames.is_empty() {
+        return Ok(None);
+    }
+
+    let msg = ZMsg::new();
+    try!(msg.addstr(&scoped_topic(topic, None)));
+    try!(msg.addstr("ADD"));
+    for frame in frames {
+        try!(msg.addbytes(frame));
+    }
+    debug!("Sending snapshot of {} certificates", frames.len() / 2);
+    Ok(Some(msg))
+}
+
+/// The notice sent on `topic` straight before a single-message
+/// snapshot, so that subscribers resyncing it can tell the snapshot
+/// from a live ADD. See `CertCache::resync()`.
+pub fn snapshot_notice(topic: &str) -> Result<ZMsg> {
+    let msg = ZMsg::new();
+    try!(msg.addstr(topic));
+    try!(msg.addstr("SNAPSHOT"));
+    Ok(msg)
+}
+
+/// Append the publish time to a feed message. See `TIMESTAMP_PREFIX`.
+#[allow(dead_code)]
+pub fn stamp(msg: &ZMsg) -> Result<()> {
+    let topic = match msg.first().map(|f| f.data()) {
+        Some(Ok(Ok(s))) => s,
+        _ => return Ok(()),
+    };
+    let action = match msg.next().map(|f| f.data()) {
+        Some(Ok(Ok(s))) => s,
+        _ => return Ok(()),
+    };
+
+    if !feed_signature::is_stamped(&topic, &action) {
+        return Ok(());
+    }
+    try!(msg.addstr(&format!("{}{}", TIMESTAMP_PREFIX, latency::now_micros())));
+    Ok(())
+}
+
+/// The frame resuming a subscription from `heartbeat`'s position, if
+/// its publisher sent an epoch. See `RESUME_PREFIX`.
+#[allow(dead_code)]
+pub fn resume_frame(heartbeat: &Heartbeat) -> Option<String> {
+    heartbeat.epoch.map(|epoch| format!("{}{}:{}", RESUME_PREFIX, epoch, heartbeat.sequence))
+}
+
+/// The epoch and sequence in a resume frame.
+#[allow(dead_code)]
+pub fn parse_resume(frame: &str) -> Option<(u64, u64)> {
+    if !frame.starts_with(RESUME_PREFIX) {
+        return None;
+    }
+    let mut parts = frame[RESUME_PREFIX.len()..].splitn(2, ':');
+    match (parts.next().map(|p| p.parse()), parts.next().map(|p| p.parse())) {
+        (Some(Ok(epoch)), Some(Ok(sequence))) => Some((epoch, sequence)),
+        _ => None,
+    }
+}
+
+/// The servers a feed message has passed through, from its origin
+/// frame. Empty if it has none.
+#[allow(dead_code)]
+pub fn origins(msg: &ZMsg) -> Vec<String> {
+    let mut frame = msg.first();
+    while let Some(f) = frame {
+        if let Ok(Ok(s)) = f.data() {
+            if s.starts_with(ORIGIN_PREFIX) {
+                return s[ORIGIN_PREFIX.len()..].split(',').filter(|o| !o.is_empty()).map(|o| o.to_string()).collect();
+            }
+        }
+        frame = msg.next();
+    }
+    Vec::new()
+}
+
+// Frames after a message's certs: its origin, timestamp and signature.
+// Public keys are 40 characters, so they can't be mistaken for any.
+fn is_trailer(frame: &str) -> bool {
+    frame.starts_with(ORIGIN_PREFIX) || frame.starts_with(TIMESTAMP_PREFIX) || frame.starts_with(SIGNATURE_PREFIX)
+}
+
+// The publish time, if the last frame is a timestamp, or a signature
+// following one. Public keys are 40 characters, so they can't be
+// mistaken for either.
+fn published_at(msg: &ZMsg) -> Option<u64> {
+    let timestamp = match msg.ref_last().map(|f| f.data()) {
+        Some(Ok(Ok(ref s))) if s.starts_with(SIGNATURE_PREFIX) => {
+            let size = msg.size();
+            let mut frame = msg.first();
+            for _ in 1..size.saturating_sub(1) {
+                frame = msg.next();
+            }
+            frame.map(|f| f.data())
+        },
+        last => last,
+    };
+    match timestamp {
+        Some(Ok(Ok(ref s))) if s.starts_with(TIMESTAMP_PREFIX) => s[TIMESTAMP_PREFIX.len()..].parse().ok(),
+        _ => None,
+    }
+}
+
+/// The topics snapshots for `subscription` are sent on. A bare
+/// keys-only subscription gets one per cert type.
+#[allow(dead_code)]
+pub fn snapshot_topics(subscription: &str) -> Vec<String> {
+    if subscription == KEYS_ONLY_TOPIC_PREFIX {
+        vec![keys_only_topic(CertType::Host), keys_only_topic(CertType::User)]
+    } else {
+        vec![subscription.to_string()]
+    }
+}
+
+/// Split a topic for full certs into its cert type and scope, if it
+/// has one.
+pub fn split_scope(topic: &str) -> (&str, Option<&str>) {
+    if let Some(i) = topic.find(SCOPE_SEPARATOR) {
+        if CertType::from_str(&topic[..i]).is_ok() {
+            return (&topic[..i], Some(&topic[i + 1..]));
+        }
+    }
+    (topic, None)
+}
+
+/// The topic for certs pushed to the subscriber whose cert is called
+/// `name`.
+pub fn direct_topic(name: &str) -> String {
+    format!("{}{}{}", DIRECT_TOPIC_PREFIX, name, DIRECT_TOPIC_SUFFIX)
+}
+
+/// The topic for certs of `cert_type` (or every type) in `scope`. A
+/// scope needs a cert type, so is dropped without one.
+pub fn scoped_topic(cert_type: Option<CertType>, scope: Option<&str>) -> String {
+    match (cert_type, scope) {
+        (Some(t), Some(s)) => format!("{}{}{}", t.to_str(), SCOPE_SEPARATOR, s),
+        (Some(t), None) => t.to_str().to_string(),
+        (None, _) => String::new(),
+    }
+}
+
+/// Whether `cert` belongs in `scope`. See `NAMESPACE_SCOPE_PREFIX`.
+pub fn in_scope(cert: &Cert, scope: &str) -> bool {
+    let (key, value) = if scope.starts_with(NAMESPACE_SCOPE_PREFIX) {
+        ("namespace", &scope[NAMESPACE_SCOPE_PREFIX.len()..])
+    } else {
+        ("group", scope)
+    };
+    match cert.meta(key) {
+        Some(Ok(ref v)) => v == value,
+        _ => false,
+    }
+}
+
+pub fn keys_only_topic(cert_type: CertType) -> String {
+    format!("{}{}", KEYS_ONLY_TOPIC_PREFIX, cert_type.to_str())
+}
+
+/// Copy a feed message for keys-only subscribers, dropping metadata.
+/// Returns `None` for messages that aren't published on a cert type
+/// topic, such as direct pushes, and for UPDATEs, which only change
+/// metadata.
+pub fn keys_only(msg: &ZMsg) -> Result<Option<ZMsg>> {
+    let topic = match msg.first().map(|f| f.data()) {
+        Some(Ok(Ok(s))) => s,
+        _ => return Ok(None),
+    };
+    let cert_type = match CertType::from_str(&topic) {
+        Ok(t) => t,
+        Err(_) => return Ok(None),
+    };
+    let action = match msg.next().map(|f| f.data()) {
+        Some(Ok(Ok(ref s))) if s == "UPDATE" => return Ok(None),
+        Some(Ok(Ok(s))) => s,
+        _ => return Ok(None),
+    };
+
+    let keys = ZMsg::new();
+    try!(keys.addstr(&keys_only_topic(cert_type)));
+    try!(keys.addstr(&action));
+
+    // ADD frames alternate between pubkey and metadata. Keys-only
+    // subscribers don't need the origin.
+    let mut is_key = true;
+    while let Some(frame) = msg.next() {
+        if let Ok(Ok(ref s)) = frame.data() {
+            if is_key && is_trailer(s) {
+                break;
+            }
+        }
+        if is_key || action != "ADD" {
+            try!(keys.append(try!(frame.dup())));
+        }
+        is_key = !is_key;
+    }
+
+    Ok(Some(keys))
+}
+
+fn decode_cert(pubkey: &str, meta: &[u8]) -> Result<Cert> {
+    let zcert = try!(ZCert::from_txt(pubkey, "0000000000000000000000000000000000000000"));
+    try!(zcert.decode_meta(meta));
+
+    debug!("Receiving {}", pubkey);
+    for key in zcert.meta_keys() {
+        debug!("Meta {}: {}", key, zcert.meta(key).unwrap().unwrap());
+    }
+
+    Cert::from_zcert(zcert)
+}
+
+/// Copy a feed message for subscribers of `format`, re-encoding
+/// metadata if need be. Returns `None` for messages that aren't
+/// published on a cert type topic, as with `keys_only()`.
+pub fn batched_copy(msg: &ZMsg, format: SnapshotFormat) -> Result<Option<ZMsg>> {
+    let topic = match msg.first().map(|f| f.data()) {
+        Some(Ok(Ok(s))) => s,
+        _ => return Ok(None),
+    };
+    if CertType::from_str(&topic).is_err() {
+        return Ok(None);
+    }
+    let action = match msg.next().map(|f| f.data()) {
+        Some(Ok(Ok(s))) => s,
+        _ => return Ok(None),
+    };
+
+    let copy = ZMsg::new();
+    try!(copy.addstr(&format!("{}{}", format.topic_prefix(), topic)));
+    try!(copy.addstr(&action));
+
+    // ADD and UPDATE frames alternate between pubkey and metadata
+    let mut is_key = true;
+    while let Some(frame) = msg.next() {
+        if is_key || (action != "ADD" && action != "UPDATE") {
+            try!(copy.append(try!(frame.dup())));
+        } else {
+            let meta = match try!(frame.data()) {
+                Ok(s) => s.into_bytes(),
+                Err(b) => b,
+            };
+            try!(copy.addbytes(&try!(format.encode_meta(meta))));
+        }
+        is_key = !is_key;
+    }
+
+    Ok(Some(copy))
+}
+
+/// Copy a feed message for subscribers of the scoped `topic` (e.g.
+/// "host/webfarm" or "batch:host/webfarm"), keeping only the certs in
+/// its scope. UPDATEs that move a cert out of the scope become DELs.
+/// Returns no copies for messages on other topics, or with nothing for
+/// the scope.
+pub fn scoped_copies(msg: &ZMsg, topic: &str) -> Result<Vec<ZMsg>> {
+    let (format, base_topic) = SnapshotFormat::from_topic(topic);
+    let (cert_type, scope) = match split_scope(base_topic) {
+        (t, Some(s)) => (t, s),
+        _ => return Ok(Vec::new()),
+    };
+    match msg.first().map(|f| f.data()) {
+        Some(Ok(Ok(ref s))) if s == cert_type => (),
+        _ => return Ok(Vec::new()),
+    }
+    let action = match msg.next().map(|f| f.data()) {
+        Some(Ok(Ok(s))) => s,
+        _ => return Ok(Vec::new()),
+    };
+
+    let copy = ZMsg::new();
+    try!(copy.addstr(topic));
+    try!(copy.addstr(&action));
+    let mut kept = 0;
+    let mut removed = Vec::new();
+
+    match action.as_ref() {
+        "ADD" | "UPDATE" => {
+            while let Some(frame) = msg.next() {
+                let pubkey = match try!(frame.data()) {
+                    Ok(s) => s,
+                    Err(_) => return Err(Error::InvalidCertFeed),
+                };
+                if is_trailer(&pubkey) {
+                    break;
+                }
+                let meta = match msg.next() {
+                    Some(frame) => match try!(frame.data()) {
+                        Ok(s) => s.into_bytes(),
+                        Err(b) => b,
+                    },
+                    None => break,
+                };
+
+                let zcert = try!(ZCert::from_txt(&pubkey, "0000000000000000000000000000000000000000"));
+                try!(zcert.decode_meta(&meta));
+                if in_scope(&try!(Cert::from_zcert(zcert)), scope) {
+                    try!(copy.addstr(&pubkey));
+                    try!(copy.addbytes(&try!(format.encode_meta(meta))));
+                    kept += 1;
+                } else if action == "UPDATE" {
+                    removed.push(pubkey);
+                }
+            }
+        },
+        // We can't tell which scope a deleted cert was in, so every
+        // scope of its type gets the DEL
+        "DEL" => {
+            if let Some(frame) = msg.next() {
+                try!(copy.append(try!(frame.dup())));
+                kept += 1;
+            }
+        },
+        _ => (),
+    }
+
+    let mut copies = Vec::new();
+    if kept > 0 {
+        copies.push(copy);
+    }
+    for pubkey in removed {
+        let del = ZMsg::new();
+        try!(del.addstr(topic));
+        try!(del.addstr("DEL"));
+        try!(del.addstr(&pubkey));
+        copies.push(del);
+    }
+    Ok(copies)
+}
+
+// Keys-only feeds don't carry a name, so the pubkey stands in for it.
+fn minimal_cert(pubkey: &str, cert_type: CertType) -> Result<Cert> {
+    let zcert = try!(ZCert::from_txt(pubkey, "0000000000000000000000000000000000000000"));
+    zcert.set_meta("name", pubkey);
+    zcert.set_meta("type", cert_type.to_str());
+    Cert::from_zcert(zcert)
+}
+
+pub fn add_subscription<F>(subscriptions: &Subscriptions, filter: Option<Filter>, callback: F)
+    where F: FnMut(Change, &Cert) + Send + 'static
+{
+    subscriptions.lock().unwrap().push(Subscription {
+        filter: filter,
+        callback: Box::new(callback),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use attestation::Attestation;
+    use cert::{Cert, CertType, EXPIRES_META, REVOKED_META};
+    use czmq::{ZCert, ZMsg, ZSock, ZSys};
+    use filter::Filter;
+    use sodiumoxide::crypto::sign;
+    use std::sync::{Arc, Mutex};
+    use std::thread::spawn;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_get() {
+        let (cache, pubkey) = create_cache();
+
+        assert!(cache.get("nonexistent").is_none());
+        assert_eq!(cache.get(&pubkey).unwrap().public_txt(), &pubkey);
+    }
+
+    #[test]
+    fn test_get_name() {
+        let (cache, _) = create_cache();
+
+        assert!(cache.get_name("nonexistent").is_none());
+        assert_eq!(cache.get_name("peetar!").unwrap().name(), "peetar!");
+    }
+
+    #[test]
+    fn test_shared() {
+        let (cache, pubkey) = create_cache();
+        let shared = SharedCertCache::new(cache);
+        let other = shared.clone();
+
+        let handle = spawn(move || other.write().purge_expired(u64::max_value()));
+        // The pinned cert has no expiry
+        assert_eq!(handle.join().unwrap(), 0);
+        assert!(shared.read().get(&pubkey).is_some());
+
+        let host = Cert::new("web1", CertType::Host).unwrap();
+        let host_pubkey = host.public_txt().to_string();
+        let other = shared.clone();
+        spawn(move || other.write().insert(host_pubkey, host)).join().unwrap();
+        assert!(shared.read().get_name("web1").is_some());
+    }
+
+    #[test]
+    fn test_snapshot_frames() {
+        let (mut cache, pubkey) = create_cache();
+        let frames = cache.snapshot_frames(None);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], pubkey.as_bytes());
+        // Reused until the cache changes
+        assert!(Arc::ptr_eq(&frames, &cache.snapshot_frames(None)));
+        assert!(cache.snapshot_frames(Some(CertType::Host)).is_empty());
+
+        let host = Cert::new("web1", CertType::Host).unwrap();
+        cache.insert(host.public_txt().to_string(), host);
+        assert_eq!(cache.snapshot_frames(None).len(), 4);
+        assert_eq!(cache.snapshot_frames(Some(CertType::Host)).len(), 2);
+
+        let msg = snapshot_msg(Some(CertType::Host), &cache.snapshot_frames(Some(CertType::Host))).unwrap().unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "host");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "ADD");
+        assert_eq!(msg.size(), 2);
+        assert!(snapshot_msg(None, &[]).unwrap().is_none());
+
+        cache.remove(&pubkey);
+        assert_eq!(cache.snapshot_frames(None).len(), 2);
+    }
+
+    #[test]
+    fn test_indexes() {
+        let (mut cache, _) = create_cache();
+        let host = Cert::new("web1", CertType::Host).unwrap();
+        let pubkey = host.public_txt().to_string();
+        cache.insert(pubkey.clone(), host);
+        assert_eq!(cache.get_name("web1").unwrap().public_txt(), pubkey);
+        assert_eq!(cache.dump(CertType::Host).len(), 1);
+
+        // Same key under a new name
+        let zcert = ZCert::from_txt(&pubkey, "0000000000000000000000000000000000000000").unwrap();
+        zcert.set_meta("name", "web2");
+        zcert.set_meta("type", "host");
+        cache.insert(pubkey.clone(), Cert::from_zcert(zcert).unwrap());
+        assert!(cache.get_name("web1").is_none());
+        assert_eq!(cache.get_name("web2").unwrap().public_txt(), pubkey);
+        assert_eq!(cache.dump(CertType::Host).len(), 1);
+
+        cache.remove(&pubkey);
+        assert!(cache.get_name("web2").is_none());
+        assert!(cache.dump(CertType::Host).is_empty());
+        assert_eq!(cache.dump(CertType::User).len(), 1);
+    }
+
+    #[test]
+    fn test_send() {
+        ZSys::init();
+        let (cache, pubkey) = create_cache();
+
+        let mut client = ZSock::new_push("inproc://cert_cache_send").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_send").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        cache.send(&mut client, Some(CertType::Host), None).unwrap();
+        assert!(server.recv_str().is_err());
+
+        cache.send(&mut client, Some(CertType::User), None).unwrap();
+        let msg = ZMsg::recv(&mut server).unwrap();
+        msg.popstr().unwrap().unwrap(); // Discard topic
+        assert_eq!(msg.popstr().unwrap().unwrap(), "ADD");
+        assert_eq!(msg.popstr().unwrap().unwrap(), pubkey);
+
+        let zcert = ZCert::new().unwrap();
+        zcert.decode_meta(&msg.popbytes().unwrap().unwrap()).unwrap();
+        assert_eq!(zcert.meta("name").unwrap().unwrap(), "peetar!");
+        assert_eq!(zcert.meta("type").unwrap().unwrap(), "user");
+    }
+
+    #[test]
+    fn test_changes() {
+        ZSys::init();
+        let (cache, pubkey) = create_cache();
+        let gone = ZCert::new().unwrap().public_txt().to_string();
+
+        let changes = cache.changes(CertType::User, &[gone.clone(), pubkey.clone()]).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].popstr().unwrap().unwrap(), "user");
+        assert_eq!(changes[0].popstr().unwrap().unwrap(), "ADD");
+        assert_eq!(changes[0].popstr().unwrap().unwrap(), pubkey);
+        assert_eq!(changes[0].size(), 1);
+        assert_eq!(changes[1].popstr().unwrap().unwrap(), "user");
+        assert_eq!(changes[1].popstr().unwrap().unwrap(), "DEL");
+        assert_eq!(changes[1].popstr().unwrap().unwrap(), gone);
+
+        // Keys of another type are gone from this type's topic
+        let changes = cache.changes(CertType::Host, &[pubkey]).unwrap();
+        assert_eq!(changes.len(), 1);
+        changes[0].popstr().unwrap().unwrap();
+        assert_eq!(changes[0].popstr().unwrap().unwrap(), "DEL");
+    }
+
+    #[test]
+    fn test_recv() {
+        ZSys::init();
+
+        let mut cache = CertCache::new(None);
+        let c1 = Cert::new("dan", CertType::User).unwrap();
+        let c2 = Cert::new("web1.example.com", CertType::Host).unwrap();
+
+        let mut client = ZSock::new_push("inproc://cert_cache_recv").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_recv").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        assert!(cache.recv(&mut server).is_err());
+
+        let msg = ZMsg::new();
+        msg.addstr("topic").unwrap();
+        msg.addstr("ADD").unwrap();
+        msg.addstr(c1.public_txt()).unwrap();
+        msg.addbytes(&c1.encode_meta()).unwrap();
+        msg.addstr(c2.public_txt()).unwrap();
+        msg.addbytes(&c2.encode_meta()).unwrap();
+        msg.send(&mut client).unwrap();
+
+        assert!(cache.recv(&mut server).is_ok());
+        assert!(cache.cache.contains_key(c1.public_txt()));
+        assert!(cache.cache.contains_key(c2.public_txt()));
+
+        let msg = ZMsg::new();
+        msg.addstr("topic").unwrap();
+        msg.addstr("DEL").unwrap();
+        msg.addstr(c1.public_txt()).unwrap();
+        msg.send(&mut client).unwrap();
+
+        assert!(cache.recv(&mut server).is_ok());
+        assert!(!cache.cache.contains_key(c1.public_txt()));
+    }
+
+    #[test]
+    fn test_recv_heartbeat() {
+        ZSys::init();
+
+        let mut cache = CertCache::new(None);
+        let mut client = ZSock::new_push("inproc://cert_cache_recv_heartbeat").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_recv_heartbeat").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let msg = ZMsg::new();
+        msg.addstr("topic").unwrap();
+        msg.addstr("HEARTBEAT").unwrap();
+        msg.addstr("nope").unwrap();
+        msg.send(&mut client).unwrap();
+
+        assert!(cache.recv(&mut server).is_err());
+        assert!(cache.last_sequence().is_none());
+
+        let msg = ZMsg::new();
+        msg.addstr("topic").unwrap();
+        msg.addstr("HEARTBEAT").unwrap();
+        msg.addstr("42").unwrap();
+        msg.send(&mut client).unwrap();
+
+        assert!(cache.recv(&mut server).is_ok());
+        assert_eq!(cache.last_sequence(), Some(42));
+    }
+
+    #[test]
+    fn test_heartbeat_certs() {
+        ZSys::init();
+
+        let mut cache = CertCache::new(None);
+        let mut client = ZSock::new_push("inproc://cert_cache_heartbeat_certs").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_heartbeat_certs").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        assert!(cache.heartbeat().lock().unwrap().is_none());
+
+        let msg = ZMsg::new();
+        msg.addstr("topic").unwrap();
+        msg.addstr("HEARTBEAT").unwrap();
+        msg.addstr("43").unwrap();
+        msg.addstr("certs=12").unwrap();
+        msg.send(&mut client).unwrap();
+
+        assert!(cache.recv(&mut server).is_ok());
+        let heartbeat = cache.heartbeat().lock().unwrap().unwrap();
+        assert_eq!((heartbeat.sequence, heartbeat.certs), (43, Some(12)));
+    }
+
+    #[test]
+    fn test_resume_frame() {
+        ZSys::init();
+
+        let mut cache = CertCache::new(None);
+        let mut client = ZSock::new_push("inproc://cert_cache_resume_frame").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_resume_frame").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let msg = ZMsg::new();
+        msg.addstr("topic").unwrap();
+        msg.addstr("HEARTBEAT").unwrap();
+        msg.addstr("43").unwrap();
+        msg.addstr("epoch=1500000000000000").unwrap();
+        msg.send(&mut client).unwrap();
+
+        assert!(cache.recv(&mut server).is_ok());
+        let heartbeat = cache.heartbeat().lock().unwrap().unwrap();
+        assert_eq!((heartbeat.sequence, heartbeat.epoch), (43, Some(1500000000000000)));
+        let resume = resume_frame(&heartbeat).unwrap();
+        assert_eq!(resume, "since=1500000000000000:43");
+        assert_eq!(parse_resume(&resume), Some((1500000000000000, 43)));
+        assert_eq!(parse_resume("since=1500000000000000"), None);
+    }
+
+    #[test]
+    fn test_drain_notice() {
+        ZSys::init();
+
+        let mut cache = CertCache::new(None);
+        let mut client = ZSock::new_push("inproc://cert_cache_drain_notice").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_drain_notice").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let msg = ZMsg::new();
+        msg.addstr("topic").unwrap();
+        msg.addstr("HEARTBEAT").unwrap();
+        msg.addstr("42").unwrap();
+        msg.addstr("endpoint=tcp://auth1:7102").unwrap();
+        msg.send(&mut client).unwrap();
+
+        assert!(cache.recv(&mut server).is_ok());
+        assert!(cache.take_drain_notices().is_empty());
+
+        // Fields this client doesn't know are ignored
+        let msg = ZMsg::new();
+        msg.addstr("topic").unwrap();
+        msg.addstr("HEARTBEAT").unwrap();
+        msg.addstr("43").unwrap();
+        msg.addstr("state=draining").unwrap();
+        msg.addstr("endpoint=tcp://auth1:7102").unwrap();
+        msg.addstr("future=1").unwrap();
+        msg.send(&mut client).unwrap();
+
+        assert!(cache.recv(&mut server).is_ok());
+        assert_eq!(cache.take_drain_notices(), vec![Some("tcp://auth1:7102".to_string())]);
+        assert!(cache.take_drain_notices().is_empty());
+    }
+
+    #[test]
+    fn test_adverts() {
+        ZSys::init();
+
+        let mut cache = CertCache::new(None);
+        let mut client = ZSock::new_push("inproc://cert_cache_adverts").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_adverts").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let msg = ZMsg::new();
+        msg.addstr("topic").unwrap();
+        msg.addstr("HEARTBEAT").unwrap();
+        msg.addstr("43").unwrap();
+        msg.addstr("endpoint=tcp://auth1:7102").unwrap();
+        msg.send(&mut client).unwrap();
+
+        assert!(cache.recv(&mut server).is_ok());
+        let adverts = cache.take_adverts();
+        assert_eq!(adverts.len(), 1);
+        assert_eq!(adverts[0].endpoint, "tcp://auth1:7102");
+        assert!(adverts[0].tags.is_empty());
+        assert!(cache.take_adverts().is_empty());
+
+        let msg = ZMsg::new();
+        msg.addstr("topic").unwrap();
+        msg.addstr("HEARTBEAT").unwrap();
+        msg.addstr("44").unwrap();
+        msg.addstr("endpoint=tcp://auth2:7102").unwrap();
+        msg.addstr("tags=eu-west,rack1").unwrap();
+        stamp(&msg).unwrap();
+        msg.send(&mut client).unwrap();
+
+        assert!(cache.recv(&mut server).is_ok());
+        assert!(cache.take_drain_notices().is_empty());
+        let adverts = cache.take_adverts();
+        assert_eq!(adverts[0].endpoint, "tcp://auth2:7102");
+        assert_eq!(adverts[0].tags, vec!["eu-west".to_string(), "rack1".to_string()]);
+        assert!(adverts[0].latency_micros.is_some());
+    }
+
+    #[test]
+    fn test_resync() {
+        ZSys::init();
+
+        let seed = Cert::new("self.example.com", CertType::Host).unwrap();
+        let seed_pubkey = seed.public_txt().to_string();
+        let mut cache = CertCache::new(Some(vec![seed]));
+        let c1 = Cert::new("web1.example.com", CertType::Host).unwrap();
+        let c2 = Cert::new("web2.example.com", CertType::Host).unwrap();
+        let c3 = Cert::new("web3.example.com", CertType::Host).unwrap();
+        let user = Cert::new("dan", CertType::User).unwrap();
+
+        let removed = Arc::new(Mutex::new(Vec::new()));
+        let r = removed.clone();
+        cache.on_change(None, move |change, cert| if change == Change::Removed {
+            r.lock().unwrap().push(cert.name().to_string());
+        });
+
+        let mut client = ZSock::new_push("inproc://cert_cache_resync").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_resync").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let add = |topic: &str, certs: &[&Cert]| {
+            let msg = ZMsg::new();
+            msg.addstr(topic).unwrap();
+            msg.addstr("ADD").unwrap();
+            for cert in certs {
+                msg.addstr(cert.public_txt()).unwrap();
+                msg.addbytes(&cert.encode_meta()).unwrap();
+            }
+            msg
+        };
+
+        add("", &[&c1, &c2, &user]).send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+
+        // A live ADD while waiting for the snapshot is merged, rather
+        // than taken for it
+        cache.resync(&snapshot_topics("host"));
+        add("host", &[&c2]).send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(c1.public_txt()).is_some());
+        assert!(cache.is_resyncing());
+
+        // The announced snapshot after reconnecting no longer has c1
+        snapshot_notice("host").unwrap().send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        add("host", &[&c2]).send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(!cache.is_resyncing());
+        assert!(cache.get(c1.public_txt()).is_none());
+        assert!(cache.get(c2.public_txt()).is_some());
+        assert!(cache.get(user.public_txt()).is_some());
+        assert!(cache.get(&seed_pubkey).is_some());
+
+        // Later ADDs are merged as usual, as are snapshots sent to
+        // other subscribers
+        add("host", &[&c3]).send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        snapshot_notice("host").unwrap().send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        add("host", &[&c2]).send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(c2.public_txt()).is_some());
+        assert!(cache.get(c3.public_txt()).is_some());
+
+        assert_eq!(*removed.lock().unwrap(), vec!["web1.example.com".to_string()]);
+        assert_eq!(snapshot_topics("keys:"), vec!["keys:host".to_string(), "keys:user".to_string()]);
+    }
+
+    #[test]
+    fn test_batched_resync() {
+        ZSys::init();
+
+        let mut cache = CertCache::new(None);
+        cache.set_format(SnapshotFormat::Batched);
+        let c1 = Cert::new("web1.example.com", CertType::Host).unwrap();
+        let c2 = Cert::new("web2.example.com", CertType::Host).unwrap();
+        let c3 = Cert::new("web3.example.com", CertType::Host).unwrap();
+
+        let mut client = ZSock::new_push("inproc://cert_cache_batched_resync").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_batched_resync").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        // ADDs carry certs, ENDs the number sent
+        let mut send = |topic: &str, action: &str, certs: &[&Cert], total: Option<usize>| {
+            let msg = ZMsg::new();
+            msg.addstr(topic).unwrap();
+            msg.addstr(action).unwrap();
+            for cert in certs {
+                msg.addstr(cert.public_txt()).unwrap();
+                msg.addbytes(&cert.encode_meta()).unwrap();
+            }
+            if let Some(total) = total {
+                msg.addstr(&total.to_string()).unwrap();
+            }
+            msg.send(&mut client).unwrap();
+        };
+
+        // Other formats' copies are ignored
+        send("zbatch:host", "ADD", &[&c1], None);
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(c1.public_txt()).is_none());
+        send("batch:host", "ADD", &[&c1], None);
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(c1.public_txt()).is_some());
+
+        // Joining part way through a snapshot doesn't drop c1
+        cache.resync(&snapshot_topics("batch:host"));
+        send("batch:host", "ADD", &[&c3], None);
+        cache.recv(&mut server).unwrap();
+        send("batch:host", "END", &[], Some(3));
+        cache.recv(&mut server).unwrap();
+        assert!(cache.is_resyncing());
+        assert!(cache.get(c1.public_txt()).is_some());
+
+        // c1 has since been deleted
+        send("batch:host", "ADD", &[&c2], None);
+        cache.recv(&mut server).unwrap();
+        send("batch:host", "ADD", &[&c3], None);
+        cache.recv(&mut server).unwrap();
+        send("batch:host", "END", &[], Some(2));
+        cache.recv(&mut server).unwrap();
+        assert!(!cache.is_resyncing());
+        assert!(cache.get(c1.public_txt()).is_none());
+        assert!(cache.get(c2.public_txt()).is_some());
+        assert!(cache.get(c3.public_txt()).is_some());
+
+        let mut sent = Vec::new();
+        assert_eq!(cache.snapshot_batches(Some(CertType::User), None, SnapshotFormat::Batched, 10, |m| { sent.push(m); Ok(()) }).unwrap(), 0);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].popstr().unwrap().unwrap(), "batch:user");
+        assert_eq!(sent[0].popstr().unwrap().unwrap(), "END");
+        assert_eq!(sent[0].popstr().unwrap().unwrap(), "0");
+    }
+
+    #[test]
+    fn test_cbor_feed() {
+        ZSys::init();
+
+        let mut cache = CertCache::new(None);
+        cache.set_format(SnapshotFormat::Cbor);
+        let c1 = Cert::new("web1.example.com", CertType::Host).unwrap();
+        c1.set_meta("role", "web");
+
+        let mut client = ZSock::new_push("inproc://cert_cache_cbor_feed").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_cbor_feed").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let msg = ZMsg::new();
+        msg.addstr("host").unwrap();
+        msg.addstr("ADD").unwrap();
+        msg.addstr(c1.public_txt()).unwrap();
+        msg.addbytes(&c1.encode_meta()).unwrap();
+        let copy = batched_copy(&msg, SnapshotFormat::Cbor).unwrap().unwrap();
+        assert_eq!(copy.popstr().unwrap().unwrap(), "cbatch:host");
+        copy.pushstr("cbatch:host").unwrap();
+
+        copy.send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        let cert = cache.get(c1.public_txt()).unwrap();
+        assert_eq!(cert.name(), "web1.example.com");
+        assert_eq!(cert.meta("role").unwrap().unwrap(), "web");
+    }
+
+    #[test]
+    fn test_apply_snapshot() {
+        ZSys::init();
+
+        let (mut cache, seed_pubkey) = create_cache();
+        let c1 = Cert::new("web1.example.com", CertType::Host).unwrap();
+        let c2 = Cert::new("web2.example.com", CertType::Host).unwrap();
+        let user = Cert::new("dan", CertType::User).unwrap();
+
+        let reply = |sequence: &str, certs: &[&Cert]| {
+            let msg = ZMsg::new();
+            msg.addstr(sequence).unwrap();
+            for cert in certs {
+                msg.addstr(cert.public_txt()).unwrap();
+                msg.addbytes(&cert.encode_meta()).unwrap();
+            }
+            msg
+        };
+
+        assert_eq!(cache.apply_snapshot(None, &reply("5", &[&c1, &user])).unwrap(), Some(2));
+        cache.resync(&snapshot_topics("host"));
+
+        // A bad reply changes nothing
+        let bad = reply("10", &[&c2]);
+        bad.addstr(c1.public_txt()).unwrap();
+        assert!(cache.apply_snapshot(Some(CertType::Host), &bad).is_err());
+        assert!(cache.get(c1.public_txt()).is_some());
+        assert!(cache.get(c2.public_txt()).is_none());
+        assert!(cache.apply_snapshot(None, &reply("nope", &[])).is_err());
+
+        // Mismatched certs are quarantined rather than applied
+        assert_eq!(cache.apply_snapshot(Some(CertType::Host), &reply("10", &[&c2, &user])).unwrap(), Some(1));
+        assert!(cache.get(c1.public_txt()).is_none());
+        assert!(cache.get(c2.public_txt()).is_some());
+        assert!(cache.get(user.public_txt()).is_some());
+        assert!(cache.get(&seed_pubkey).is_some());
+        assert_eq!(cache.quarantine().lock().unwrap().total, 1);
+        assert_eq!(cache.last_sequence(), Some(10));
+        assert!(!cache.is_resyncing());
+        assert!(cache.is_modified());
+
+        // Snapshots older than the feed are ignored
+        assert_eq!(cache.apply_snapshot(None, &reply("9", &[])).unwrap(), None);
+        assert!(cache.get(c2.public_txt()).is_some());
+        assert_eq!(cache.apply_snapshot(None, &reply("11", &[])).unwrap(), Some(0));
+        assert!(cache.get(c2.public_txt()).is_none());
+        assert!(cache.get(user.public_txt()).is_none());
+        assert!(cache.get(&seed_pubkey).is_some());
+    }
+
+    #[test]
+    fn test_recv_attestation() {
+        ZSys::init();
+
+        let (mut cache, _) = create_cache();
+        let (pk, sk) = sign::gen_keypair();
+
+        let mut client = ZSock::new_push("inproc://cert_cache_recv_attestation").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_recv_attestation").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let attestation = Attestation::sign(cache.merkle_root(), 3, 1500000000, &sk);
+        attestation.to_msg().unwrap().send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+
+        let received = cache.attestation().unwrap();
+        assert_eq!(received, &attestation);
+        assert!(received.verify(&pk));
+        assert_eq!(received.root, cache.merkle_root());
+    }
+
+    #[test]
+    fn test_on_change() {
+        ZSys::init();
+
+        let mut cache = CertCache::new(None);
+        let web = Cert::new("web1.example.com", CertType::Host).unwrap();
+        web.set_meta("group", "web");
+        let db = Cert::new("db1.example.com", CertType::Host).unwrap();
+        db.set_meta("group", "db");
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let c = changes.clone();
+        cache.on_change(Some(Filter::parse("group=web").unwrap()), move |change, cert| {
+            c.lock().unwrap().push((change, cert.name().to_string()));
+        });
+        let all = Arc::new(Mutex::new(0));
+        let a = all.clone();
+        cache.on_change(None, move |_, _| *a.lock().unwrap() += 1);
+
+        let mut client = ZSock::new_push("inproc://cert_cache_on_change").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_on_change").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let msg = ZMsg::new();
+        msg.addstr("host").unwrap();
+        msg.addstr("ADD").unwrap();
+        msg.addstr(web.public_txt()).unwrap();
+        msg.addbytes(&web.encode_meta()).unwrap();
+        msg.addstr(db.public_txt()).unwrap();
+        msg.addbytes(&db.encode_meta()).unwrap();
+        msg.send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+
+        let msg = ZMsg::new();
+        msg.addstr("host").unwrap();
+        msg.addstr("DEL").unwrap();
+        msg.addstr(web.public_txt()).unwrap();
+        msg.send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+
+        assert_eq!(*changes.lock().unwrap(), vec![
+            (Change::Added, "web1.example.com".to_string()),
+            (Change::Removed, "web1.example.com".to_string()),
+        ]);
+        assert_eq!(*all.lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_keys_only() {
+        ZSys::init();
+
+        let (mut cache, pubkey) = create_cache();
+        let c1 = Cert::new("web1.example.com", CertType::Host).unwrap();
+
+        let snapshot = cache.snapshot_keys(CertType::User).unwrap().unwrap();
+        assert_eq!(snapshot.popstr().unwrap().unwrap(), "keys:user");
+        assert_eq!(snapshot.popstr().unwrap().unwrap(), "ADD");
+        assert_eq!(snapshot.popstr().unwrap().unwrap(), pubkey);
+        assert_eq!(snapshot.size(), 0);
+        assert!(cache.snapshot_keys(CertType::Host).unwrap().is_none());
+
+        let msg = ZMsg::new();
+        msg.addstr("host").unwrap();
+        msg.addstr("ADD").unwrap();
+        msg.addstr(c1.public_txt()).unwrap();
+        msg.addbytes(&c1.encode_meta()).unwrap();
+        msg.addstr(&pubkey).unwrap();
+        msg.addbytes(&c1.encode_meta()).unwrap();
+
+        let mut client = ZSock::new_push("inproc://cert_cache_keys_only").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_keys_only").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let keys = keys_only(&msg).unwrap().unwrap();
+        assert_eq!(keys.size(), 4);
+        // Caches for full certs ignore keys-only copies
+        keys.send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(c1.public_txt()).is_none());
+
+        cache.set_keys_only(true);
+        keys_only(&msg).unwrap().unwrap().send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+
+        // New keys get a minimal cert, existing certs are untouched
+        let minimal = cache.get(c1.public_txt()).unwrap();
+        assert_eq!(minimal.name(), c1.public_txt());
+        assert_eq!(minimal.cert_type(), CertType::Host);
+        assert_eq!(cache.get(&pubkey).unwrap().name(), "peetar!");
+
+        let direct = ZMsg::new();
+        direct.addstr("@web1.example.com").unwrap();
+        direct.addstr("ADD").unwrap();
+        assert!(keys_only(&direct).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = TempDir::new("cert_cache_test_save_load").unwrap();
+        let path = dir.path().join("cache.json");
+
+        let (mut cache, own_pubkey) = create_cache();
+        let host = Cert::new("web1", CertType::Host).unwrap();
+        host.set_meta("groups", "web");
+        let user = Cert::new("bob", CertType::User).unwrap();
+        let host_pubkey = host.public_txt().to_string();
+        let user_pubkey = user.public_txt().to_string();
+        cache.insert(host_pubkey.clone(), host);
+        cache.insert(user_pubkey.clone(), user);
+        cache.modified = true;
+
+        cache.save(&path).unwrap();
+        assert!(!cache.is_modified());
+
+        let mut restored = CertCache::new(None);
+        assert_eq!(restored.load(&path, None).unwrap(), 2);
+        // Pinned certs aren't saved
+        assert!(restored.get(&own_pubkey).is_none());
+        let host = restored.get(&host_pubkey).unwrap();
+        assert_eq!(host.name(), "web1");
+        assert_eq!(host.meta("groups").unwrap().unwrap(), "web");
+        assert_eq!(restored.get(&user_pubkey).unwrap().cert_type(), CertType::User);
+        assert!(!restored.is_modified());
+
+        let mut hosts_only = CertCache::new(None);
+        assert_eq!(hosts_only.load(&path, Some(CertType::Host)).unwrap(), 1);
+        assert!(hosts_only.get(&user_pubkey).is_none());
+
+        File::create(&path).unwrap().write_all(b"{\"version\": 99, \"certs\": []}").unwrap();
+        assert!(CertCache::new(None).load(&path, None).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let (mut cache, own_pubkey) = create_cache();
+        let removed = Arc::new(Mutex::new(Vec::new()));
+        let removed_clone = removed.clone();
+        cache.on_change(None, move |change, cert| {
+            if change == Change::Removed {
+                removed_clone.lock().unwrap().push(cert.name().to_string());
+            }
+        });
+
+        let mut pubkeys = Vec::new();
+        for name in &["web1", "web2", "web3"] {
+            let cert = Cert::new(name, CertType::Host).unwrap();
+            pubkeys.push(cert.public_txt().to_string());
+            cache.insert(cert.public_txt().to_string(), cert);
+        }
+
+        // Using web1 makes web2 the least recently used
+        cache.times.get_mut(&pubkeys[0]).unwrap().used = Mutex::new(Instant::now() + Duration::from_secs(1));
+        cache.set_limits(CacheLimits { ttl: None, max_entries: Some(2) });
+        assert!(cache.get(&pubkeys[1]).is_none());
+        assert!(cache.get(&pubkeys[0]).is_some());
+        assert!(cache.get(&pubkeys[2]).is_some());
+        // Pinned certs don't count towards the limit
+        assert!(cache.get(&own_pubkey).is_some());
+
+        let ttl = Duration::from_secs(60);
+        cache.set_limits(CacheLimits { ttl: Some(ttl), max_entries: None });
+        cache.times.get_mut(&pubkeys[2]).unwrap().received = Instant::now() - ttl;
+        assert!(cache.get(&pubkeys[2]).is_none());
+        assert!(cache.cache.contains_key(&pubkeys[2]));
+        assert!(cache.is_expired(&pubkeys[2]));
+        assert!(!cache.is_expired(&pubkeys[0]));
+
+        cache.expire(Instant::now());
+        assert!(!cache.cache.contains_key(&pubkeys[2]));
+        assert!(cache.get(&pubkeys[0]).is_some());
+        assert!(cache.get(&own_pubkey).is_some());
+        assert!(cache.is_modified());
+        assert_eq!(*removed.lock().unwrap(), vec!["web2", "web3"]);
+    }
+
+    #[test]
+    fn test_expiry_revocation() {
+        ZSys::init();
+
+        let mut cache = CertCache::new(None);
+        let mut client = ZSock::new_push("inproc://cert_cache_expiry_revocation").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_expiry_revocation").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let now = latency::now_micros() / 1_000_000;
+        let expiring = Cert::new("web1", CertType::Host).unwrap();
+        expiring.set_meta(EXPIRES_META, &(now + 3600).to_string());
+        let revoked = Cert::new("web2", CertType::Host).unwrap();
+        let revoked_key = revoked.public_txt().to_string();
+
+        let msg = ZMsg::new();
+        msg.addstr("host").unwrap();
+        msg.addstr("ADD").unwrap();
+        for cert in &[&expiring, &revoked] {
+            msg.addstr(cert.public_txt()).unwrap();
+            msg.addbytes(&cert.encode_meta()).unwrap();
+        }
+        msg.send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(expiring.public_txt()).is_some());
+        assert!(cache.get(revoked.public_txt()).is_some());
+
+        // Revoked certs are removed as soon as the revocation arrives
+        revoked.set_meta(REVOKED_META, "true");
+        let msg = ZMsg::new();
+        msg.addstr("host").unwrap();
+        msg.addstr("ADD").unwrap();
+        msg.addstr(revoked.public_txt()).unwrap();
+        msg.addbytes(&revoked.encode_meta()).unwrap();
+        msg.send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(revoked.public_txt()).is_none());
+        assert!(cache.is_revoked(revoked.public_txt()));
+        assert!(!cache.is_revoked(expiring.public_txt()));
+
+        assert_eq!(cache.purge_expired(now), 0);
+        assert!(!cache.is_expired(expiring.public_txt()));
+        assert_eq!(cache.purge_expired(now + 3600), 1);
+        assert!(cache.get(expiring.public_txt()).is_none());
+        assert!(cache.is_expired(expiring.public_txt()));
+
+        // Stored revocations survive a restart
+        let cache = CertCache::new(Some(vec![revoked]));
+        assert!(cache.get(&revoked_key).is_none());
+        assert!(cache.is_revoked(&revoked_key));
+    }
+
+    #[test]
+    fn test_quarantine() {
+        ZSys::init();
+
+        let mut cache = CertCache::new(None);
+        let mut client = ZSock::new_push("inproc://cert_cache_quarantine").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_quarantine").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let host = Cert::new("web1", CertType::Host).unwrap();
+        let user = Cert::new("mallory", CertType::User).unwrap();
+        let add = |topic: &str| {
+            let msg = ZMsg::new();
+            msg.addstr(topic).unwrap();
+            msg.addstr("ADD").unwrap();
+            for cert in &[&host, &user] {
+                msg.addstr(cert.public_txt()).unwrap();
+                msg.addbytes(&cert.encode_meta()).unwrap();
+            }
+            msg
+        };
+
+        // The user cert doesn't belong on the host topic
+        add("host").send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(host.public_txt()).is_some());
+        assert!(cache.get(user.public_txt()).is_none());
+
+        let quarantine = cache.quarantine();
+        assert_eq!(*quarantine.lock().unwrap(), Quarantine {
+            total: 1,
+            certs: vec![QuarantinedCert {
+                name: "mallory".into(),
+                public_key: user.public_txt().into(),
+                cert_type: CertType::User,
+                topic: "host".into(),
+            }],
+        });
+
+        // Untyped topics carry any type
+        add("").send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(user.public_txt()).is_some());
+        assert_eq!(quarantine.lock().unwrap().total, 1);
+    }
+
+    #[test]
+    fn test_scopes() {
+        ZSys::init();
+
+        let web = Cert::new("web1", CertType::Host).unwrap();
+        web.set_meta("group", "webfarm");
+        let acme = Cert::new("acme1", CertType::Host).unwrap();
+        acme.set_meta("namespace", "acme");
+        let db = Cert::new("db1", CertType::Host).unwrap();
+
+        assert_eq!(split_scope("host/webfarm"), ("host", Some("webfarm")));
+        assert_eq!(split_scope("host"), ("host", None));
+        assert_eq!(split_scope(&direct_topic("web1")), ("@web1/", None));
+        assert_eq!(scoped_topic(Some(CertType::Host), Some("ns:acme")), "host/ns:acme");
+        assert!(in_scope(&web, "webfarm"));
+        assert!(!in_scope(&web, "ns:webfarm"));
+        assert!(in_scope(&acme, "ns:acme"));
+        assert!(!in_scope(&db, "webfarm"));
+
+        let cache = CertCache::new(Some(vec![web, acme, db]));
+        let snapshot = cache.snapshot(Some(CertType::Host), Some("webfarm")).unwrap().unwrap();
+        assert_eq!(snapshot.popstr().unwrap().unwrap(), "host/webfarm");
+        assert_eq!(snapshot.popstr().unwrap().unwrap(), "ADD");
+        assert_eq!(snapshot.size(), 2);
+        assert!(cache.snapshot(Some(CertType::User), Some("webfarm")).unwrap().is_none());
+
+        let web2 = Cert::new("web2", CertType::Host).unwrap();
+        web2.set_meta("group", "webfarm");
+        let db2 = Cert::new("db2", CertType::Host).unwrap();
+        let change = |topic: &str, action: &str, certs: &[&Cert]| {
+            let msg = ZMsg::new();
+            msg.addstr(topic).unwrap();
+            msg.addstr(action).unwrap();
+            for cert in certs {
+                msg.addstr(cert.public_txt()).unwrap();
+                msg.addbytes(&cert.encode_meta()).unwrap();
+            }
+            msg
+        };
+
+        let copies = scoped_copies(&change("host", "ADD", &[&web2, &db2]), "batch:host/webfarm").unwrap();
+        assert_eq!(copies.len(), 1);
+        assert_eq!(copies[0].popstr().unwrap().unwrap(), "batch:host/webfarm");
+        assert_eq!(copies[0].popstr().unwrap().unwrap(), "ADD");
+        assert_eq!(copies[0].popstr().unwrap().unwrap(), web2.public_txt());
+        assert_eq!(copies[0].size(), 1);
+
+        // Leaving the scope
+        web2.set_meta("group", "dbfarm");
+        let copies = scoped_copies(&change("host", "UPDATE", &[&web2]), "host/webfarm").unwrap();
+        assert_eq!(copies.len(), 1);
+        assert_eq!(copies[0].popstr().unwrap().unwrap(), "host/webfarm");
+        assert_eq!(copies[0].popstr().unwrap().unwrap(), "DEL");
+        assert_eq!(copies[0].popstr().unwrap().unwrap(), web2.public_txt());
+
+        let del = ZMsg::new();
+        del.addstr("host").unwrap();
+        del.addstr("DEL").unwrap();
+        del.addstr(db2.public_txt()).unwrap();
+        assert_eq!(scoped_copies(&del, "host/webfarm").unwrap().len(), 1);
+        assert!(scoped_copies(&del, "user/webfarm").unwrap().is_empty());
+        assert!(scoped_copies(&change("host", "ADD", &[&db2]), "host/webfarm").unwrap().is_empty());
+
+        // Subscribers to "host/web" also receive "host/webfarm"
+        let mut scoped = CertCache::new(None);
+        scoped.set_scope(Some("web".into()));
+        let mut client = ZSock::new_push("inproc://cert_cache_scopes").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_scopes").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        change("host/webfarm", "ADD", &[&db2]).send(&mut client).unwrap();
+        scoped.recv(&mut server).unwrap();
+        assert!(scoped.get(db2.public_txt()).is_none());
+        change("host/web", "ADD", &[&db2]).send(&mut client).unwrap();
+        scoped.recv(&mut server).unwrap();
+        assert!(scoped.get(db2.public_txt()).is_some());
+    }
+
+    #[test]
+    fn test_update() {
+        ZSys::init();
+
+        let mut cache = CertCache::new(None);
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let c = changes.clone();
+        cache.on_change(None, move |change, cert| c.lock().unwrap().push((change, cert.meta("group").and_then(|g| g.ok()))));
+
+        let mut client = ZSock::new_push("inproc://cert_cache_update").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_update").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let web = Cert::new("web1", CertType::Host).unwrap();
+        let stranger = Cert::new("web2", CertType::Host).unwrap();
+        let send = |action: &str, certs: &[&Cert]| {
+            let msg = ZMsg::new();
+            msg.addstr("host").unwrap();
+            msg.addstr(action).unwrap();
+            for cert in certs {
+                msg.addstr(cert.public_txt()).unwrap();
+                msg.addbytes(&cert.encode_meta()).unwrap();
+            }
+            msg
+        };
+
+        send("ADD", &[&web]).send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+
+        // Only certs already held are updated
+        web.set_meta("group", "web");
+        stranger.set_meta("group", "web");
+        let update = send("UPDATE", &[&web, &stranger]);
+        assert!(keys_only(&update).unwrap().is_none());
+        update.send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert_eq!(cache.get(web.public_txt()).unwrap().meta("group").unwrap().unwrap(), "web");
+        assert!(cache.get(stranger.public_txt()).is_none());
+
+        web.set_meta("disabled", "true");
+        send("UPDATE", &[&web]).send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(web.public_txt()).is_none());
+        assert!(cache.is_disabled(web.public_txt()));
+
+        web.set_meta("disabled", "false");
+        web.set_meta("revoked", "true");
+        send("UPDATE", &[&web]).send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.is_revoked(web.public_txt()));
+
+        assert_eq!(*changes.lock().unwrap(), vec![
+            (Change::Added, None),
+            (Change::Updated, Some("web".to_string())),
+            (Change::Updated, Some("web".to_string())),
+            (Change::Removed, Some("web".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn test_stamp() {
+        ZSys::init();
+
+        let (mut cache, _) = create_cache();
+        let latency = cache.latency();
+        let mut client = ZSock::new_push("inproc://cert_cache_stamp").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_stamp").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let cert = Cert::new("web1", CertType::Host).unwrap();
+        let msg = ZMsg::new();
+        msg.addstr("host").unwrap();
+        msg.addstr("ADD").unwrap();
+        msg.addstr(cert.public_txt()).unwrap();
+        msg.addbytes(&cert.encode_meta()).unwrap();
+        let keys = keys_only(&msg).unwrap().unwrap();
+        stamp(&msg).unwrap();
+        stamp(&keys).unwrap();
+        assert_eq!(msg.size(), 5);
+        assert!(published_at(&msg).is_some());
+        assert_eq!(keys.size(), 4);
+        assert!(published_at(&keys).is_some());
+
+        msg.send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(cert.public_txt()).is_some());
+        assert_eq!(latency.lock().unwrap().count, 1);
+
+        cache.set_keys_only(true);
+        keys.send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert_eq!(latency.lock().unwrap().count, 2);
+    }
+
+    #[test]
+    fn test_publishers_disagree() {
+        ZSys::init();
+
+        let (mut cache, _) = create_cache();
+        let mut server = ZSock::new_pull("inproc://cert_cache_publishers_disagree").unwrap();
+        server.set_rcvtimeo(Some(500));
+        // One publisher is ahead of the other, which is still sending
+        // older changes
+        let mut ahead = ZSock::new_push("inproc://cert_cache_publishers_disagree").unwrap();
+        let mut behind = ZSock::new_push("inproc://cert_cache_publishers_disagree").unwrap();
+
+        let c1 = Cert::new("web1", CertType::Host).unwrap();
+        let c2 = Cert::new("web2", CertType::Host).unwrap();
+        let change = |action: &str, cert: &Cert, published: u64| {
+            let msg = ZMsg::new();
+            msg.addstr("host").unwrap();
+            msg.addstr(action).unwrap();
+            msg.addstr(cert.public_txt()).unwrap();
+            if action == "ADD" {
+                msg.addbytes(&cert.encode_meta()).unwrap();
+            }
+            msg.addstr(&format!("{}{}", TIMESTAMP_PREFIX, published)).unwrap();
+            msg
+        };
+
+        change("ADD", &c1, 2000).send(&mut ahead).unwrap();
+        cache.recv(&mut server).unwrap();
+        change("DEL", &c1, 1000).send(&mut behind).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(c1.public_txt()).is_some());
+
+        change("ADD", &c2, 1500).send(&mut behind).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(c2.public_txt()).is_some());
+        change("DEL", &c2, 2500).send(&mut ahead).unwrap();
+        cache.recv(&mut server).unwrap();
+        change("ADD", &c2, 1600).send(&mut behind).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(c2.public_txt()).is_none());
+
+        // Newer changes apply whoever sends them
+        change("ADD", &c2, 3000).send(&mut behind).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(c2.public_txt()).is_some());
+    }
+
+    #[test]
+    fn test_verify_feed() {
+        ZSys::init();
+
+        let (pk, sk) = sign::gen_keypair();
+        let mut cache = CertCache::new(None);
+        cache.verify_feed(pk, Duration::from_secs(60));
+        let latency = cache.latency();
+        let mut client = ZSock::new_push("inproc://cert_cache_verify_feed").unwrap();
+        let mut server = ZSock::new_pull("inproc://cert_cache_verify_feed").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        let cert = Cert::new("web1", CertType::Host).unwrap();
+        let add = || {
+            let msg = ZMsg::new();
+            msg.addstr("host").unwrap();
+            msg.addstr("ADD").unwrap();
+            msg.addstr(cert.public_txt()).unwrap();
+            msg.addbytes(&cert.encode_meta()).unwrap();
+            stamp(&msg).unwrap();
+            msg
+        };
+
+        // Unsigned
+        add().send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(cert.public_txt()).is_none());
+
+        let signed = add();
+        feed_signature::sign(&signed, &sk).unwrap();
+        let replay = signed.dup().unwrap();
+        signed.send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(cert.public_txt()).is_some());
+        // The signature frame doesn't hide the timestamp
+        assert_eq!(latency.lock().unwrap().count, 1);
+
+        let del = ZMsg::new();
+        del.addstr("host").unwrap();
+        del.addstr("DEL").unwrap();
+        del.addstr(cert.public_txt()).unwrap();
+        stamp(&del).unwrap();
+        feed_signature::sign(&del, &sk).unwrap();
+        del.send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(cert.public_txt()).is_none());
+
+        replay.send(&mut client).unwrap();
+        cache.recv(&mut server).unwrap();
+        assert!(cache.get(cert.public_txt()).is_none());
     }
 
     fn create_cache() -> (CertCache, String) {
@@ -0,0 +1,302 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Client for the Auth server's cert API.
+//!
+//! Requests are sent over a CURVE-encrypted REQ socket, authenticated
+//! with the caller's user cert. The socket is opened on first use, and
+//! replaced after a timeout, as a REQ socket can't send again until it
+//! has its reply.
+
+use cert::{Cert, CertType};
+use czmq::{ZCert, ZMsg, ZPoller, ZSock, SocketType};
+use error::{Error, ErrorCode, Result};
+use keychain::load_keychain_cert;
+use std::time::Duration;
+
+// Starts a request ID frame, as in the server's request_id module
+const REQUEST_ID_PREFIX: &'static str = "rid:";
+
+pub struct CertClient {
+    endpoint: String,
+    server_key: String,
+    cert: ZCert,
+    timeout: Duration,
+    sock: Option<ZSock>,
+}
+
+impl CertClient {
+    /// `endpoint` is the API's address, e.g. "tcp://auth.example.com:7101".
+    /// `server_key` is the Auth server's Z85 public key, and `cert`
+    /// the cert to authenticate with.
+    pub fn new(endpoint: &str, server_key: &str, cert: ZCert) -> CertClient {
+        CertClient {
+            endpoint: endpoint.to_string(),
+            server_key: server_key.to_string(),
+            cert: cert,
+            timeout: Duration::from_secs(5),
+            sock: None,
+        }
+    }
+
+    /// As `new`, loading the server's public cert and the user cert
+    /// from disk.
+    pub fn load(endpoint: &str, server_public_path: &str, cert_path: &str) -> Result<CertClient> {
+        let server_cert = try!(ZCert::load(server_public_path));
+        let cert = try!(ZCert::load(cert_path));
+        Ok(CertClient::new(endpoint, server_cert.public_txt(), cert))
+    }
+
+    /// As `new`, loading the server's public cert from disk and the
+    /// user cert called `name` from the OS keychain. Requires the
+    /// `keychain` feature.
+    pub fn load_keychain(endpoint: &str, server_public_path: &str, name: &str) -> Result<CertClient> {
+        let server_cert = try!(ZCert::load(server_public_path));
+        let cert = try!(load_keychain_cert(name));
+        Ok(CertClient::new(endpoint, server_cert.public_txt(), cert.dup()))
+    }
+
+    /// How long to wait for each reply. Defaults to 5 seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Create a cert, returning it with its secret key.
+    pub fn create(&mut self, name: &str, cert_type: CertType) -> Result<Cert> {
+        let reply = try!(self.request(&["cert::create", cert_type.to_str(), name]));
+        if reply.len() < 3 {
+            return Err(Error::InvalidArgsCount);
+        }
+        let zcert = try!(ZCert::from_txt(&String::from_utf8_lossy(&reply[0]), &String::from_utf8_lossy(&reply[1])));
+        try!(zcert.decode_meta(&reply[2]));
+        Cert::from_zcert(zcert)
+    }
+
+    pub fn delete(&mut self, name: &str) -> Result<()> {
+        try!(self.request(&["cert::delete", name]));
+        Ok(())
+    }
+
+    /// Names of the certs of `cert_type`. Some may be masked,
+    /// depending on the caller's domain.
+    pub fn list(&mut self, cert_type: CertType) -> Result<Vec<String>> {
+        let reply = try!(self.request(&["cert::list", cert_type.to_str()]));
+        Ok(reply.into_iter().map(|f| String::from_utf8_lossy(&f).into_owned()).collect())
+    }
+
+    /// The Z85 public key of the cert called `name`.
+    pub fn lookup(&mut self, name: &str) -> Result<String> {
+        let mut reply = try!(self.request(&["cert::lookup", name]));
+        match reply.pop() {
+            Some(key) => Ok(String::from_utf8_lossy(&key).into_owned()),
+            None => Err(Error::InvalidArgsCount),
+        }
+    }
+
+    /// The key pair replacing our own cert in a scheduled rotation,
+    /// with its secret key, and when the old key stops authenticating
+    /// (seconds since the Unix epoch). Switch to it before then.
+    pub fn pending_rotation(&mut self) -> Result<(Cert, u64)> {
+        let reply = try!(self.request(&["cert::pending_rotation"]));
+        if reply.len() < 4 {
+            return Err(Error::InvalidArgsCount);
+        }
+        let zcert = try!(ZCert::from_txt(&String::from_utf8_lossy(&reply[0]), &String::from_utf8_lossy(&reply[1])));
+        try!(zcert.decode_meta(&reply[2]));
+        let retire_at = try!(String::from_utf8_lossy(&reply[3]).parse().or(Err(Error::InvalidArg)));
+        Ok((try!(Cert::from_zcert(zcert)), retire_at))
+    }
+
+    /// Send any API request, starting with the endpoint name, and
+    /// return the frames following "Ok". An "Err" reply is returned as
+    /// `Error::Api`, with its `ErrorCode` and message.
+    pub fn request(&mut self, request: &[&str]) -> Result<Vec<Vec<u8>>> {
+        self.send(None, request)
+    }
+
+    /// As `request`, tagged with `request_id` for the server to log and
+    /// echo back, so a failed request can be found in the server's
+    /// logs. IDs are up to 64 letters, digits, '-', '_' or '.'. Servers
+    /// that predate request IDs don't reply, so this times out.
+    pub fn request_with_id(&mut self, request_id: &str, request: &[&str]) -> Result<Vec<Vec<u8>>> {
+        self.send(Some(request_id), request)
+    }
+
+    fn send(&mut self, request_id: Option<&str>, request: &[&str]) -> Result<Vec<Vec<u8>>> {
+        let id_frame = request_id.map(|id| format!("{}{}", REQUEST_ID_PREFIX, id));
+
+        if self.sock.is_none() {
+            self.sock = Some(try!(self.connect()));
+        }
+
+        let reply = {
+            let sock = self.sock.as_mut().unwrap();
+
+            let msg = ZMsg::new();
+            if let Some(ref id) = id_frame {
+                try!(msg.addstr(id));
+            }
+            for frame in request {
+                try!(msg.addstr(frame));
+            }
+            try!(msg.send(sock));
+
+            let mut poller = try!(ZPoller::new());
+            try!(poller.add(sock));
+            let timeout = self.timeout.as_secs() as u32 * 1000 + self.timeout.subsec_nanos() / 1_000_000;
+            let ready: Option<ZSock> = poller.wait(Some(timeout));
+            if ready.is_some() {
+                Some(try!(ZMsg::recv(sock)))
+            } else {
+                None
+            }
+        };
+
+        let reply = match reply {
+            Some(r) => r,
+            None => {
+                // Lockstep is broken, so start again with a new socket
+                self.sock = None;
+                return Err(Error::ApiTimeout);
+            }
+        };
+
+        let mut status = reply.popstr().unwrap_or(Ok(String::new())).unwrap_or(String::new());
+        // A refused ID isn't echoed
+        if id_frame.as_ref() == Some(&status) {
+            status = reply.popstr().unwrap_or(Ok(String::new())).unwrap_or(String::new());
+        }
+        let mut frames = Vec::new();
+        while let Some(frame) = try!(reply.popbytes()) {
+            frames.push(frame);
+        }
+        if status == "Ok" {
+            Ok(frames)
+        } else {
+            // Servers before error codes send only the message
+            let message = frames.pop().map(|f| String::from_utf8_lossy(&f).into_owned()).unwrap_or(String::new());
+            let code = match frames.pop() {
+                Some(c) => ErrorCode::from(&*String::from_utf8_lossy(&c)),
+                None => ErrorCode::Unknown,
+            };
+            Err(Error::Api(code, message))
+        }
+    }
+
+    fn connect(&self) -> Result<ZSock> {
+        let mut sock = ZSock::new(SocketType::REQ);
+        sock.set_curve_serverkey(&self.server_key);
+        self.cert.apply(&mut sock);
+        // Don't hang on to unanswered requests when dropped
+        sock.set_linger(0);
+        try!(sock.connect(&self.endpoint));
+        Ok(sock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType};
+    use czmq::{SocketType, ZCert, ZMsg, ZSock, ZSys};
+    use error::Error;
+    use std::thread::spawn;
+    use std::time::Duration;
+    use super::*;
+
+    fn server(endpoint: &str) -> (ZSock, ZCert) {
+        let server_cert = ZCert::new().unwrap();
+        let mut server = ZSock::new(SocketType::REP);
+        server.set_curve_server(true);
+        server_cert.apply(&mut server);
+        server.bind(endpoint).unwrap();
+        (server, server_cert)
+    }
+
+    #[test]
+    fn test_request() {
+        ZSys::init();
+
+        let (mut server, server_cert) = server("inproc://cert_client_test_request");
+        let mut client = CertClient::new("inproc://cert_client_test_request", server_cert.public_txt(), ZCert::new().unwrap());
+        let cert = Cert::new("web1", CertType::Host).unwrap();
+        let public = cert.public_txt().to_string();
+        let secret = cert.secret_txt().expose().to_string();
+        let meta = cert.encode_meta();
+
+        let handle = spawn(move || {
+            let msg = ZMsg::recv(&mut server).unwrap();
+            assert_eq!(msg.popstr().unwrap().unwrap(), "cert::create");
+            assert_eq!(msg.popstr().unwrap().unwrap(), "host");
+            assert_eq!(msg.popstr().unwrap().unwrap(), "web1");
+            let reply = ZMsg::new();
+            reply.addstr("Ok").unwrap();
+            reply.addstr(&public).unwrap();
+            reply.addstr(&secret).unwrap();
+            reply.addbytes(&meta).unwrap();
+            reply.send(&mut server).unwrap();
+
+            let msg = ZMsg::recv(&mut server).unwrap();
+            assert_eq!(msg.popstr().unwrap().unwrap(), "cert::lookup");
+            let reply = ZMsg::new();
+            reply.addstr("Err").unwrap();
+            reply.addstr("invalid_cert").unwrap();
+            reply.addstr("Unknown certificate").unwrap();
+            reply.send(&mut server).unwrap();
+
+            // Servers before error codes send only the message
+            let msg = ZMsg::recv(&mut server).unwrap();
+            assert_eq!(msg.popstr().unwrap().unwrap(), "cert::lookup");
+            let reply = ZMsg::new();
+            reply.addstr("Err").unwrap();
+            reply.addstr("Unknown certificate").unwrap();
+            reply.send(&mut server).unwrap();
+
+            let msg = ZMsg::recv(&mut server).unwrap();
+            assert_eq!(msg.popstr().unwrap().unwrap(), "rid:agent-7");
+            assert_eq!(msg.popstr().unwrap().unwrap(), "cert::list");
+            let reply = ZMsg::new();
+            reply.addstr("rid:agent-7").unwrap();
+            reply.addstr("Ok").unwrap();
+            reply.addstr("web1").unwrap();
+            reply.send(&mut server).unwrap();
+        });
+
+        assert_eq!(client.create("web1", CertType::Host).unwrap(), cert);
+        match client.lookup("db1") {
+            Err(Error::Api(code, e)) => {
+                assert_eq!(code, ErrorCode::InvalidCert);
+                assert_eq!(e, "Unknown certificate");
+            },
+            _ => panic!("Expected API error"),
+        }
+        match client.lookup("db1") {
+            Err(Error::Api(code, e)) => {
+                assert_eq!(code, ErrorCode::Unknown);
+                assert_eq!(e, "Unknown certificate");
+            },
+            _ => panic!("Expected API error"),
+        }
+        assert_eq!(client.request_with_id("agent-7", &["cert::list", "host"]).unwrap(), vec![b"web1".to_vec()]);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_timeout() {
+        ZSys::init();
+
+        let (_server, server_cert) = server("inproc://cert_client_test_timeout");
+        let mut client = CertClient::new("inproc://cert_client_test_timeout", server_cert.public_txt(), ZCert::new().unwrap());
+        client.set_timeout(Duration::from_millis(50));
+
+        match client.list(CertType::User) {
+            Err(Error::ApiTimeout) => (),
+            _ => panic!("Expected timeout"),
+        }
+        assert!(client.sock.is_none());
+    }
+}
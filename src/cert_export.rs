@@ -0,0 +1,227 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Dump and restore a whole cert store as JSON, for backups and for
+//! cloning an environment.
+//!
+//! The store only ever holds public keys, so an export is safe to share
+//! unless it also carries the Auth server's own key, which a clone needs
+//! to be trusted by the same agents.
+
+use cert::{Cert, CertType};
+use czmq::ZCert;
+use error::{Error, Result};
+use std::collections::BTreeMap;
+use storage::{CertRequest, PersistenceAdaptor};
+
+/// Bumped whenever an older binary would misread the export.
+pub const EXPORT_FORMAT: u32 = 1;
+
+// Z85 for an all-zero key. Stored certs have no secret key.
+const NO_SECRET: &'static str = "0000000000000000000000000000000000000000";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Export {
+    pub format: u32,
+    /// Sorted by name
+    pub certs: Vec<ExportedCert>,
+    /// Pending cert requests, oldest first
+    pub requests: Vec<CertRequest>,
+    /// The Auth server's keypair, only if asked for
+    pub server: Option<ExportedKey>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExportedCert {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub cert_type: String,
+    pub public_key: String,
+    /// All metadata, including the name and type
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedKey {
+    pub public_key: String,
+    pub secret_key: String,
+    pub metadata: BTreeMap<String, String>,
+}
+
+pub enum Outcome {
+    Created,
+    /// Already in the store with the same key
+    Unchanged,
+    Failed(Error),
+}
+
+impl Export {
+    /// Everything in `persistence`, plus `server`'s keypair if given.
+    pub fn new<P: PersistenceAdaptor>(persistence: &mut P, server: Option<&ZCert>) -> Result<Export> {
+        let mut certs: Vec<_> = try!(persistence.dump()).iter().map(ExportedCert::new).collect();
+        certs.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(Export {
+            format: EXPORT_FORMAT,
+            certs: certs,
+            requests: try!(persistence.read_requests()),
+            server: server.map(|s| ExportedKey {
+                public_key: s.public_txt().to_string(),
+                secret_key: s.secret_txt().to_string(),
+                metadata: metadata(s),
+            }),
+        })
+    }
+
+    /// Restore the certs and requests into `persistence`, calling
+    /// `report` with the outcome for each cert. Returns the number that
+    /// failed. Certs already in the store are left alone, so an import
+    /// can safely be re-run.
+    pub fn import<P, F>(&self, persistence: &mut P, mut report: F) -> Result<usize>
+        where P: PersistenceAdaptor,
+              F: FnMut(&ExportedCert, &Outcome)
+    {
+        if self.format > EXPORT_FORMAT {
+            return Err(Error::ExportTooNew(self.format, EXPORT_FORMAT));
+        }
+
+        let mut failed = 0;
+        for exported in &self.certs {
+            let outcome = match persistence.read(&exported.name) {
+                Ok(ref existing) if existing.public_txt() == exported.public_key => Outcome::Unchanged,
+                Ok(_) => Outcome::Failed(Error::CertNameCollision),
+                Err(_) => match exported.to_cert().and_then(|c| persistence.create(&c)) {
+                    Ok(_) => Outcome::Created,
+                    Err(e) => Outcome::Failed(e),
+                },
+            };
+            if let Outcome::Failed(_) = outcome {
+                failed += 1;
+            }
+            report(exported, &outcome);
+        }
+
+        let mut requests = try!(persistence.read_requests());
+        let before = requests.len();
+        for request in &self.requests {
+            if !requests.iter().any(|r| r.id == request.id) {
+                requests.push(request.clone());
+            }
+        }
+        if requests.len() > before {
+            try!(persistence.write_requests(&requests));
+        }
+
+        Ok(failed)
+    }
+}
+
+impl ExportedCert {
+    pub fn new(cert: &Cert) -> ExportedCert {
+        ExportedCert {
+            name: cert.name().to_string(),
+            cert_type: cert.cert_type().to_str().to_string(),
+            public_key: cert.public_txt().to_string(),
+            metadata: metadata(cert),
+        }
+    }
+
+    pub fn to_cert(&self) -> Result<Cert> {
+        let zcert = try!(ZCert::from_txt(&self.public_key, NO_SECRET));
+        for (key, value) in &self.metadata {
+            zcert.set_meta(key, value);
+        }
+        zcert.set_meta("name", &self.name);
+        zcert.set_meta("type", try!(CertType::from_str(&self.cert_type)).to_str());
+        Cert::from_zcert(zcert)
+    }
+}
+
+impl ExportedKey {
+    pub fn to_zcert(&self) -> Result<ZCert> {
+        let zcert = try!(ZCert::from_txt(&self.public_key, &self.secret_key));
+        for (key, value) in &self.metadata {
+            zcert.set_meta(key, value);
+        }
+        Ok(zcert)
+    }
+}
+
+fn metadata(zcert: &ZCert) -> BTreeMap<String, String> {
+    let mut metadata = BTreeMap::new();
+    for key in zcert.meta_keys() {
+        if let Some(Ok(value)) = zcert.meta(key) {
+            metadata.insert(key.to_string(), value);
+        }
+    }
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType};
+    use czmq::{ZCert, ZSys};
+    use serde_json;
+    use storage::{CertRequest, PersistDisk, PersistenceAdaptor};
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_export_import() {
+        ZSys::init();
+
+        let from_dir = TempDir::new("cert_export_test_from").unwrap();
+        let mut from = PersistDisk::new(from_dir.path().to_str().unwrap()).unwrap();
+        let web1 = Cert::new("web1", CertType::Host).unwrap();
+        web1.set_meta("env", "prod");
+        from.create(&web1).unwrap();
+        let alice = Cert::new("alice", CertType::User).unwrap();
+        from.create(&alice).unwrap();
+        let request = CertRequest {
+            id: "1".into(),
+            name: "web2".into(),
+            cert_type: "host".into(),
+            public_key: "abc".into(),
+            requested_by: "alice".into(),
+            requested_at: 0,
+        };
+        from.write_requests(&[request.clone()]).unwrap();
+
+        let server = ZCert::new().unwrap();
+        let export = Export::new(&mut from, Some(&server)).unwrap();
+        assert_eq!(export.certs.iter().map(|c| &c.name as &str).collect::<Vec<_>>(), vec!["alice", "web1"]);
+        assert_eq!(export.certs[1].metadata.get("env").unwrap(), "prod");
+        assert_eq!(export.server.as_ref().unwrap().to_zcert().unwrap().secret_txt(), server.secret_txt());
+
+        // Round trip through JSON into an empty store
+        let export: Export = serde_json::from_str(&serde_json::to_string(&export).unwrap()).unwrap();
+        let to_dir = TempDir::new("cert_export_test_to").unwrap();
+        let mut to = PersistDisk::new(to_dir.path().to_str().unwrap()).unwrap();
+        let mut created = 0;
+        assert_eq!(export.import(&mut to, |_, o| if let Outcome::Created = *o { created += 1 }).unwrap(), 0);
+        assert_eq!(created, 2);
+        let imported = to.read("web1").unwrap();
+        assert_eq!(imported.public_txt(), web1.public_txt());
+        assert_eq!(imported.meta("env").unwrap().unwrap(), "prod");
+        assert_eq!(to.read_requests().unwrap(), vec![request]);
+
+        // Re-running changes nothing, but a different key is a conflict
+        let mut unchanged = 0;
+        assert_eq!(export.import(&mut to, |_, o| if let Outcome::Unchanged = *o { unchanged += 1 }).unwrap(), 0);
+        assert_eq!(unchanged, 2);
+        assert_eq!(to.read_requests().unwrap().len(), 1);
+
+        to.delete("alice").unwrap();
+        to.create(&Cert::new("alice", CertType::User).unwrap()).unwrap();
+        assert_eq!(export.import(&mut to, |_, _| ()).unwrap(), 1);
+
+        let mut newer = Export::new(&mut from, None).unwrap();
+        newer.format = EXPORT_FORMAT + 1;
+        assert!(newer.import(&mut to, |_, _| ()).is_err());
+    }
+}
@@ -8,53 +8,286 @@
 
 extern crate czmq;
 extern crate docopt;
+extern crate inauth_client;
+#[cfg(feature = "keychain")]
+extern crate keyring;
+extern crate libc;
+#[macro_use]
 extern crate log;
+#[cfg(feature = "hsm")]
+extern crate pkcs11;
+#[cfg(feature = "policy")]
+extern crate rhai;
 extern crate rustc_serialize;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate sodiumoxide;
 #[cfg(test)]
 extern crate tempdir;
 extern crate zdaemon;
 extern crate zmq;
 
+#[allow(dead_code)]
+mod attestation;
 mod cert;
+mod cert_export;
+mod ceremony;
 mod config;
 mod error;
+mod filter;
+#[allow(dead_code)]
+mod hsm;
+mod key_health;
+#[allow(dead_code)]
+mod keychain;
+mod output;
+#[allow(dead_code)]
+mod policy;
+mod provision;
+mod qr;
+#[allow(dead_code)]
+mod secret;
+#[allow(dead_code)]
+mod server_key;
+mod shamir;
+#[allow(dead_code)]
+mod storage;
+mod user_import;
+mod watch;
+#[allow(dead_code)]
+mod wire_trace;
 
-use cert::{Cert, CertType};
+use attestation::Attestation;
+use ceremony::{AuditLog, KeyShare};
+use cert::{Cert, CertType, REVOKED_META};
+use cert_export::Export;
 use config::Config;
+use czmq::{ZCert, ZMsg, ZSock, SocketType};
 use docopt::Docopt;
-use error::Result;
+use error::{Error, Result};
+use filter::Filter;
+use inauth_client::direct_topic;
+use output::OutputFormat;
+use policy::{Hook, PolicyLimits, PolicyScript};
+use provision::Entry;
+use qr::QrCode;
+use secret::Secret;
+use serde_json::Value;
 use std::{env, fs};
-use std::io::Read;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use storage::{CertRequest, FilePerms, PersistDisk, PersistenceAdaptor};
+use user_import::{Delivery, Handover, Importer, Outcome};
+use wire_trace::decode;
 
 static USAGE: &'static str = "
 Intecture Auth CLI.
 
 Usage:
-  inauth_cli user add [(-s | --silent)] [(-c <path> | --config <path>)] <username>
+  inauth_cli user add [(-s | --silent)] [(-c <path> | --config <path>)] [--remote --cert <path>] [--host <host>] [--role <role>] [--encrypt [--passphrase <source>] | --qr | --keychain] [--output <format>] <username>
+  inauth_cli user list [(-c <path> | --config <path>)] [--remote --cert <path>] [--host <host>] [--output <format>]
+  inauth_cli user delete [(-c <path> | --config <path>)] [--remote --cert <path>] [--host <host>] [--output <format>] <username>
+  inauth_cli host add [(-s | --silent)] [(-c <path> | --config <path>)] [--remote --cert <path>] [--bundle <path>] [--host <host>] [--output <format>] <hostname>
+  inauth_cli host list [(-c <path> | --config <path>)] [--remote --cert <path>] [--host <host>] [--output <format>]
+  inauth_cli host delete [(-c <path> | --config <path>)] [--remote --cert <path>] [--host <host>] [--output <format>] <hostname>
+  inauth_cli user import-csv [(-c <path> | --config <path>)] [--deliver <method>] [--out <dir>] [--skip-existing] <file>
+  inauth_cli import-csv [(-c <path> | --config <path>)] [--remote --cert <path>] [--host <host>] [--out <dir>] <file>
+  inauth_cli cert lookup [(-c <path> | --config <path>)] [--remote --cert <path>] [--host <host>] [--output <format>] <name>
+  inauth_cli cert show [(-c <path> | --config <path>)] [--output <format>] <name>
+  inauth_cli cert verify [(-c <path> | --config <path>)] [--remote --cert <path>] [--host <host>] [--output <format>] <file>
+  inauth_cli cert rotate [(-s | --silent)] [(-c <path> | --config <path>)] [--remote --cert <path>] [--host <host>] [--output <format>] <name>
+  inauth_cli cert revoke [(-c <path> | --config <path>)] [--remote --cert <path>] [--host <host>] [--output <format>] <name>
+  inauth_cli cert decrypt [--passphrase <source>] <file> <dest>
+  inauth_cli cert search [(-c <path> | --config <path>)] [--filter <expr>] [--output <format>]
+  inauth_cli cert request [(-c <path> | --config <path>)] --cert <path> [--host <host>] <type> <name>
+  inauth_cli cert pending [(-c <path> | --config <path>)] [--output <format>]
+  inauth_cli cert (approve | deny) [(-c <path> | --config <path>)] <id>
+  inauth_cli export [(-c <path> | --config <path>)] --all [--format <format>] [--secrets]
+  inauth_cli import [(-c <path> | --config <path>)] <file>
+  inauth_cli server init [(-c <path> | --config <path>)] [--cert-path <dir>] [--api-port <port>] [--update-port <port>] [--admin <name>] [--out <dir>] [--non-interactive]
+  inauth_cli server encrypt-key [(-c <path> | --config <path>)]
+  inauth_cli server hsm-import [(-c <path> | --config <path>)]
+  inauth_cli ceremony init [(-c <path> | --config <path>)] --shares <n> --threshold <k> [--out <dir>] [--witness <name>]...
+  inauth_cli ceremony reconstruct [(-c <path> | --config <path>)] [--witness <name>]... <share-file>...
+  inauth_cli storage audit-keys [(-c <path> | --config <path>)]
+  inauth_cli storage switch [(-c <path> | --config <path>)] <dir>
+  inauth_cli storage fix-perms [(-c <path> | --config <path>)] [--mode <mode>] [--owner <owner>]
+  inauth_cli trace decode <file>
+  inauth_cli config render [(-c <path> | --config <path>)]
+  inauth_cli watch [(-c <path> | --config <path>)] --cert <path> [--host <host>] [--type <type>] [--output <format>]
+  inauth_cli status [(-c <path> | --config <path>)] --cert <path> [--host <host>] [--output <format>]
+  inauth_cli admin [(-c <path> | --config <path>)] (cache-stats | feed-subscribers | config-dump | drain | fleet-health)
+  inauth_cli admin [(-c <path> | --config <path>)] log-level [<level>]
+  inauth_cli admin [(-c <path> | --config <path>)] log-sampling [<every>]
+  inauth_cli attest verify [(-c <path> | --config <path>)] [--key <pubkey>]
+  inauth_cli feed push [(-c <path> | --config <path>)] --name <cert> [--subscriber <id>]
+  inauth_cli policy test <script> <file>
   inauth_cli --version
 
   Options:
     -c --config <path>  Path to auth.json, e.g. \"/usr/local/etc\"
+    --admin <name>      Also create a first admin user with this name.
+    --api-port <port>   Port for the cert API [default: 7101].
+    --bundle <path>     Also write a bootstrap bundle for provisioning
+                        tools: the host cert, the Auth server's public
+                        key and where to reach it, as JSON.
+    --cert <path>       Your user certificate, to authenticate the request.
+    --cert-path <dir>   Directory for the cert store. Defaults to \"certs\"
+                        beside auth.json.
+    --deliver <method>  How to deliver imported certs: email, print or
+                        encrypt [default: print].
+    --encrypt           Encrypt the secret key with a passphrase, for
+                        sending by mail or ticket. Recipients unlock it
+                        with \"cert decrypt\".
+    --non-interactive   Don't prompt for settings, use the flags and
+                        defaults as they are.
+    --out <dir>         Directory for secret keys, encrypted certs or key
+                        shares [default: .].
+    --output <format>   text, json or yaml [default: text].
+    --owner <owner>     Owner for cert store files, as user or
+                        user:group, e.g. \"intecture\". Overrides
+                        \"cert_files\" in auth.json.
+    --passphrase <source>  Where to read the passphrase from: env:<VAR>,
+                        file:<path> or exec:<command>. Prompts if not
+                        given.
+    --remote            Go through the running server's API instead of
+                        the cert store, so changes take effect without a
+                        restart. Needs --cert, usually an admin's.
+    --qr                Show the certificate as a QR code, with the
+                        Auth server's details, for scanning onto a
+                        laptop or phone.
+    --role <role>       Role to embed in the certificate, e.g. \"admin\".
+    --format <format>   Export format. Only json is supported [default: json].
+    --filter <expr>     Filter expression, e.g. \"type=host AND env=prod\".
+    --host <host>       Auth server to send the request to, or for
+                        bootstrap bundles to point at [default: 127.0.0.1].
+    --key <pubkey>      Hex attestation public key. Defaults to the key
+                        in auth.json's \"attestation\" section.
+    --keychain          Store the secret key in this machine's OS keychain
+                        instead of printing it, for inauth_client's
+                        load_keychain_cert() to load.
+    --mode <mode>       Octal mode for cert store files, e.g. \"0600\".
+                        Overrides \"cert_files\" in auth.json.
+    --name <cert>       Name of the certificate to republish.
+    --shares <n>        Number of custodians to split the server key
+                        between.
     -s --silent         Save private key instead of printing it.
+    --secrets           Also export the Auth server's secret key, so that a
+                        clone is trusted by the same agents. Keep the
+                        export safe!
+    --skip-existing     Skip users that already have a certificate.
+    --subscriber <id>   Only push to the subscriber with this cert name.
+    --update-port <port>  Port for the cert feed [default: 7102].
+    --threshold <k>     Number of shares needed to reconstruct the key.
+    --type <type>       Only watch certs of this type, host or user.
     --version           Print this script's version.
+    --witness <name>    Name of a witness, recorded in the audit log.
 ";
 
 #[derive(Debug, RustcDecodable)]
 struct Args {
     cmd_add: bool,
+    cmd_admin: bool,
+    cmd_approve: bool,
+    cmd_attest: bool,
+    cmd_audit_keys: bool,
+    cmd_cache_stats: bool,
+    cmd_ceremony: bool,
+    cmd_cert: bool,
+    cmd_config: bool,
+    cmd_config_dump: bool,
+    cmd_decode: bool,
+    cmd_decrypt: bool,
+    cmd_delete: bool,
+    cmd_deny: bool,
+    cmd_drain: bool,
+    cmd_encrypt_key: bool,
+    cmd_export: bool,
+    cmd_feed: bool,
+    cmd_feed_subscribers: bool,
+    cmd_fix_perms: bool,
+    cmd_fleet_health: bool,
+    cmd_host: bool,
+    cmd_hsm_import: bool,
+    cmd_import: bool,
+    cmd_import_csv: bool,
+    cmd_init: bool,
+    cmd_list: bool,
+    cmd_lookup: bool,
+    cmd_log_level: bool,
+    cmd_log_sampling: bool,
+    cmd_pending: bool,
+    cmd_policy: bool,
+    cmd_push: bool,
+    cmd_reconstruct: bool,
+    cmd_render: bool,
+    cmd_request: bool,
+    cmd_revoke: bool,
+    cmd_rotate: bool,
+    cmd_search: bool,
+    cmd_server: bool,
+    cmd_show: bool,
+    cmd_status: bool,
+    cmd_storage: bool,
+    cmd_switch: bool,
+    cmd_test: bool,
+    cmd_trace: bool,
     cmd_user: bool,
+    cmd_verify: bool,
+    cmd_watch: bool,
+    arg_dest: String,
+    arg_dir: String,
+    arg_every: Option<String>,
+    arg_file: String,
+    arg_hostname: String,
+    arg_id: String,
+    arg_level: Option<String>,
+    arg_name: String,
+    arg_script: String,
+    arg_share_file: Vec<String>,
+    arg_type: String,
     arg_username: String,
+    flag_admin: Option<String>,
+    flag_api_port: u32,
+    flag_bundle: Option<String>,
     flag_c: Option<String>,
+    flag_cert: String,
+    flag_cert_path: Option<String>,
     flag_config: Option<String>,
+    flag_deliver: String,
+    flag_encrypt: bool,
+    flag_filter: Option<String>,
+    flag_format: String,
+    flag_host: String,
+    flag_key: Option<String>,
+    flag_keychain: bool,
+    flag_mode: Option<String>,
+    flag_name: String,
+    flag_non_interactive: bool,
+    flag_out: String,
+    flag_passphrase: Option<String>,
+    flag_qr: bool,
+    flag_output: String,
+    flag_owner: Option<String>,
+    flag_remote: bool,
+    flag_secrets: bool,
+    flag_role: Option<String>,
     flag_s: bool,
+    flag_shares: Option<u8>,
     flag_silent: bool,
+    flag_skip_existing: bool,
+    flag_subscriber: Option<String>,
+    flag_threshold: Option<u8>,
+    flag_type: Option<String>,
+    flag_update_port: u32,
     flag_version: bool,
+    flag_witness: Vec<String>,
 }
 
 fn main() {
@@ -73,35 +306,1401 @@ fn run(args: Args) -> Result<()> {
         println!(env!("CARGO_PKG_VERSION"));
         exit(0);
     }
-    else if args.cmd_user && args.cmd_add {
+
+    let output = OutputFormat::from_str(&args.flag_output)?;
+
+    if args.cmd_user && args.cmd_add {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        let cert = if is_remote(&args) {
+            // cert::create has no way to set a role
+            if args.flag_role.is_some() {
+                println!("--role can't be used with --remote");
+                exit(1);
+            }
+            remote_create(&config, &args, CertType::User, &args.arg_username)?
+        } else {
+            let cert = Cert::new(&args.arg_username, CertType::User)?;
+            if let Some(ref role) = args.flag_role {
+                cert.set_meta("role", role);
+            }
+            open_store(&config)?.create(&cert)?;
+            cert
+        };
+
+        let passphrase = if args.flag_encrypt {
+            Some(new_passphrase(args.flag_passphrase.as_ref())?)
+        } else {
+            None
+        };
+        let mut display = SecretDisplay::Plain;
+        let secret_path = if args.flag_keychain {
+            keychain::store_keychain_cert(&cert)?;
+            display = SecretDisplay::Keychain;
+            None
+        } else if args.flag_s || args.flag_silent {
+            let path = match passphrase {
+                Some(ref p) => {
+                    let path = format!("{}.crt.enc", &args.arg_username);
+                    server_key::save_encrypted(&cert, &path, p.expose())?;
+                    path
+                },
+                None => {
+                    let path = format!("{}.crt", &args.arg_username);
+                    cert.save_secret(&path)?;
+                    path
+                },
+            };
+            Some(path)
+        } else {
+            if let Some(ref p) = passphrase {
+                display = SecretDisplay::Encrypted(server_key::encrypt_armored(&cert, p.expose())?);
+            } else if args.flag_qr {
+                display = SecretDisplay::Qr(QrCode::encode(bundle_json(&config, &args.flag_host, &cert, false)?.as_bytes())?.render());
+            }
+            None
+        };
+        print_cert(&cert, args.flag_remote, secret_path, None, display, output)?;
+    }
+    else if args.cmd_user && args.cmd_list {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+
+        let names = list_certs(&config, &args, CertType::User)?;
+        if output != OutputFormat::Text {
+            println!("{}", output.render(&names)?);
+        } else {
+            for name in names {
+                println!("{}", name);
+            }
+        }
+    }
+    else if args.cmd_user && args.cmd_delete {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        delete_cert(&config, &args, CertType::User, &args.arg_username, output)?;
+    }
+    else if args.cmd_host && args.cmd_add {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        let cert = if is_remote(&args) {
+            remote_create(&config, &args, CertType::Host, &args.arg_hostname)?
+        } else {
+            let cert = Cert::new(&args.arg_hostname, CertType::Host)?;
+            open_store(&config)?.create(&cert)?;
+            cert
+        };
+
+        if let Some(ref path) = args.flag_bundle {
+            write_bundle(&config, &args.flag_host, &cert, path)?;
+        }
+        let secret_path = if args.flag_s || args.flag_silent {
+            let path = format!("{}.crt", &args.arg_hostname);
+            cert.save_secret(&path)?;
+            Some(path)
+        } else {
+            None
+        };
+        print_cert(&cert, args.flag_remote, secret_path, args.flag_bundle.clone(), SecretDisplay::Plain, output)?;
+    }
+    else if args.cmd_host && args.cmd_list {
         let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
         let config = read_conf(config_path)?;
-        let cert = Cert::new(&args.arg_username, CertType::User)?;
-        cert.save_public(&format!("{}/{}.crt", &config.cert_path, &args.arg_username))?;
 
-        if args.flag_s || args.flag_silent {
-            cert.save_secret(&format!("{}.crt", &args.arg_username))?;
+        let names = list_certs(&config, &args, CertType::Host)?;
+        if output != OutputFormat::Text {
+            println!("{}", output.render(&names)?);
         } else {
+            for name in names {
+                println!("{}", name);
+            }
+        }
+    }
+    else if args.cmd_host && args.cmd_delete {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        delete_cert(&config, &args, CertType::Host, &args.arg_hostname, output)?;
+    }
+    else if args.cmd_user && args.cmd_import_csv {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+
+        let mut importer = Importer::new(open_store(&config)?,
+                                         &config.cert_path,
+                                         Delivery::from_str(&args.flag_deliver)?,
+                                         PathBuf::from(&args.flag_out),
+                                         args.flag_skip_existing);
+        // Passphrases are listed apart from the files they unlock, so
+        // they're easier to pass on separately
+        let mut passphrases = Vec::new();
+        let failed = importer.run(Path::new(&args.arg_file), |row, outcome| {
+            match *outcome {
+                Outcome::Created(Handover::Sent) => println!("line {}: {}: created and emailed", row.line, row.name),
+                Outcome::Created(Handover::Cert(ref block)) => println!("line {}: {}: created\n{}", row.line, row.name, block.expose()),
+                Outcome::Created(Handover::Encrypted(ref path, ref passphrase)) => {
+                    println!("line {}: {}: created, encrypted to {}", row.line, row.name, path.display());
+                    passphrases.push((row.name.clone(), passphrase.clone()));
+                },
+                Outcome::Skipped(why) => println!("line {}: {}: skipped ({})", row.line, row.name, why),
+                Outcome::Failed(ref e) => println!("line {}: {}: failed: {}", row.line, row.name, e),
+            }
+        })?;
+
+        if !passphrases.is_empty() {
+            println!("\nOne-time passphrases, to give each user apart from their file:");
+            for (name, passphrase) in passphrases {
+                println!("{}: {}", name, passphrase.expose());
+            }
+        }
+
+        if failed > 0 {
+            println!("{} row(s) failed. Fix them and re-run to resume.", failed);
+            exit(1);
+        }
+
+        println!("**********
+* PLEASE NOTE: You must restart the Auth server before these certificates will become valid!
+**********");
+    }
+    else if args.cmd_import_csv {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        let entries = provision::parse_csv(&mut fs::File::open(&args.arg_file)?)?;
+        let remote = is_remote(&args);
+
+        // cert::create has no way to set metadata
+        if let Some(e) = entries.iter().find(|e| remote && e.has_metadata()) {
+            println!("line {}: groups and expiry can't be set with --remote", e.line);
+            exit(1);
+        }
+
+        let mut persistence = if remote { None } else { Some(open_store(&config)?) };
+        let create = |e: &Entry| match persistence {
+            Some(ref mut p) => {
+                let cert = e.new_cert()?;
+                p.create(&cert)?;
+                Ok(cert)
+            },
+            None => remote_create(&config, &args, e.checked_type()?, &e.name),
+        };
+        let failed = provision::run(&entries, Path::new(&args.flag_out), create, |e, result| {
+            match *result {
+                Ok(Some(ref path)) => println!("line {}: {} {}: created, secret key in {}", e.line, e.cert_type, e.name, path.display()),
+                Ok(None) => println!("line {}: {} {}: skipped (secret key file exists)", e.line, e.cert_type, e.name),
+                Err(ref err) => println!("line {}: {} {}: failed: {}", e.line, e.cert_type, e.name, err),
+            }
+        });
+
+        if !remote {
             println!("**********
+* PLEASE NOTE: You must restart the Auth server before these certificates will become valid!
+**********");
+        }
+        if failed > 0 {
+            println!("{} row(s) failed. Fix them and re-run to resume.", failed);
+            exit(1);
+        }
+    }
+    else if args.cmd_cert && args.cmd_lookup {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+
+        let public_key = if is_remote(&args) {
+            let reply = api_request(&config, &args.flag_host, &args.flag_cert, &["cert::lookup", &args.arg_name])?;
+            reply.into_iter().next().unwrap_or(String::new())
+        } else {
+            open_store(&config)?.read(&args.arg_name)?.public_txt().to_string()
+        };
+
+        if output != OutputFormat::Text {
+            println!("{}", output.render(&Lookup { name: &args.arg_name, public_key: &public_key })?);
+        } else {
+            println!("{}", public_key);
+        }
+    }
+    else if args.cmd_cert && args.cmd_show {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        let cert = open_store(&config)?.read(&args.arg_name)?;
+        let shown = ShownCert::new(&cert, now_secs());
+
+        if output != OutputFormat::Text {
+            println!("{}", output.render(&shown)?);
+        } else {
+            for (key, value) in &shown.metadata {
+                println!("{} = \"{}\"", key, value);
+            }
+            println!("public-key = \"{}\"", shown.public_key);
+            println!("fingerprint = {}", shown.fingerprint);
+            println!("status = {}", shown.status);
+        }
+    }
+    else if args.cmd_cert && args.cmd_verify {
+        let (cert, mut problems) = verify_file(&args.arg_file, now_secs());
+
+        // Only the running server knows whether it still has the cert
+        if let Some(ref cert) = cert {
+            if is_remote(&args) {
+                let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+                let config = read_conf(config_path)?;
+                match api_request(&config, &args.flag_host, &args.flag_cert, &["cert::lookup", cert.name()]) {
+                    Ok(ref reply) if reply.first().map(|k| k == cert.public_txt()).unwrap_or(false) => (),
+                    Ok(_) => problems.push(format!("the Auth server has a different key for {}", cert.name())),
+                    Err(e) => problems.push(format!("the Auth server can't find {}: {}", cert.name(), e)),
+                }
+            }
+        }
+
+        if output != OutputFormat::Text {
+            let verified = Verified {
+                file: &args.arg_file,
+                cert: cert.as_ref().map(|c| ShownCert::new(c, now_secs())),
+                problems: &problems,
+                valid: problems.is_empty(),
+            };
+            println!("{}", output.render(&verified)?);
+        } else if let (true, Some(cert)) = (problems.is_empty(), cert.as_ref()) {
+            println!("{} is a valid {} certificate for {}", args.arg_file, cert.cert_type().to_str(), cert.name());
+        } else {
+            for problem in &problems {
+                println!("{}: {}", args.arg_file, problem);
+            }
+        }
+
+        if !problems.is_empty() {
+            exit(1);
+        }
+    }
+    else if args.cmd_cert && args.cmd_rotate {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        let cert = if is_remote(&args) {
+            replied_cert(&api_request_frames(&config, &args.flag_host, &args.flag_cert, &["cert::rotate", &args.arg_name])?)?
+        } else {
+            let mut persistence = open_store(&config)?;
+            let old = persistence.read(&args.arg_name)?;
+            if old.is_revoked() {
+                println!("{} is revoked. Issue a new certificate instead.", args.arg_name);
+                exit(1);
+            }
+            let cert = Cert::new(&args.arg_name, old.cert_type())?;
+            for key in old.meta_keys() {
+                if let Some(Ok(value)) = old.meta(key) {
+                    cert.set_meta(key, &value);
+                }
+            }
+            replace_cert(&mut persistence, &old, &cert)?;
+            cert
+        };
+
+        let secret_path = if args.flag_s || args.flag_silent {
+            let path = format!("{}.crt", &args.arg_name);
+            cert.save_secret(&path)?;
+            Some(path)
+        } else {
+            None
+        };
+        print_cert(&cert, args.flag_remote, secret_path, None, SecretDisplay::Plain, output)?;
+    }
+    else if args.cmd_cert && args.cmd_revoke {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        let remote = is_remote(&args);
+        let cert_type = if remote {
+            api_request(&config, &args.flag_host, &args.flag_cert, &["cert::revoke", &args.arg_name])?;
+            None
+        } else {
+            let mut persistence = open_store(&config)?;
+            let old = persistence.read(&args.arg_name)?;
+            let cert = persistence.read(&args.arg_name)?;
+            cert.set_meta(REVOKED_META, "true");
+            replace_cert(&mut persistence, &old, &cert)?;
+            Some(cert.cert_type().to_str())
+        };
+
+        if output != OutputFormat::Text {
+            println!("{}", output.render(&Revoked { revoked: &args.arg_name, cert_type: cert_type, restart_required: !remote })?);
+        } else {
+            println!("Revoked {}", args.arg_name);
+            if !remote {
+                println!("**********
+* PLEASE NOTE: Running Auth servers will accept this certificate until restarted! Use --remote to revoke it through a running server instead.
+**********");
+            }
+        }
+    }
+    else if args.cmd_cert && args.cmd_decrypt {
+        let mut data = Vec::new();
+        fs::File::open(&args.arg_file)?.read_to_end(&mut data)?;
+        let passphrase = match args.flag_passphrase {
+            Some(ref source) => server_key::read_passphrase(source)?,
+            None => server_key::prompt("Passphrase: ")?,
+        };
+        let cert = server_key::decrypt_cert(&data, passphrase.expose())?;
+
+        if Path::new(&args.arg_dest).exists() {
+            println!("{} already exists", args.arg_dest);
+            exit(1);
+        }
+        cert.save_secret(&args.arg_dest)?;
+        fs::set_permissions(&args.arg_dest, fs::Permissions::from_mode(0o600))?;
+        println!("Wrote certificate to {}", args.arg_dest);
+    }
+    else if args.cmd_cert && args.cmd_search {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        let filter = match args.flag_filter {
+            Some(ref f) => Some(Filter::parse(f)?),
+            None => None,
+        };
+
+        let mut persistence = open_store(&config)?;
+        let certs: Vec<_> = persistence.dump()?.into_iter()
+            .filter(|c| filter.as_ref().map(|f| f.matches_cert(c)).unwrap_or(true))
+            .collect();
+
+        if output != OutputFormat::Text {
+            let found: Vec<_> = certs.iter().map(|c| Found { name: c.name(), cert_type: c.cert_type().to_str() }).collect();
+            println!("{}", output.render(&found)?);
+        } else {
+            for cert in certs {
+                println!("{}\t{}", cert.cert_type().to_str(), cert.name());
+            }
+        }
+    }
+    else if args.cmd_cert && args.cmd_request {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+
+        // The secret key never leaves this machine. It becomes valid
+        // once an admin approves the request.
+        let cert = Cert::new(&args.arg_name, CertType::from_str(&args.arg_type)?)?;
+        let reply = api_request(&config, &args.flag_host, &args.flag_cert, &["cert::request", &args.arg_type, &args.arg_name, cert.public_txt()])?;
+        let path = format!("{}.crt", &args.arg_name);
+        cert.save_secret(&path)?;
+        println!("Submitted request {}. Your certificate is saved in {} and will be valid once an admin approves it.", reply.first().map(|s| s.as_str()).unwrap_or(""), path);
+    }
+    else if args.cmd_cert && args.cmd_pending {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+
+        let requests: Vec<CertRequest> = serde_json::from_str(&admin_request(&config, &["cert::pending_list"])?)?;
+        if output != OutputFormat::Text {
+            println!("{}", output.render(&requests)?);
+        } else {
+            for r in requests {
+                println!("{}\t{}\t{}\t{}", r.id, r.cert_type, r.name, r.requested_by);
+            }
+        }
+    }
+    else if args.cmd_cert && args.cmd_approve {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        let name = admin_request(&config, &["cert::approve", &args.arg_id])?;
+        println!("Approved request {}, issued {}", args.arg_id, name);
+    }
+    else if args.cmd_cert && args.cmd_deny {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        admin_request(&config, &["cert::deny", &args.arg_id])?;
+        println!("Denied request {}", args.arg_id);
+    }
+    else if args.cmd_export {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        if args.flag_format != "json" {
+            return Err(Error::InvalidArg);
+        }
+
+        let server = if args.flag_secrets {
+            let _ = writeln!(io::stderr(), "This export includes the Auth server's secret key. Keep it safe!");
+            Some(server_key::load(&config.server_cert, config.server_cert_passphrase.as_ref())?)
+        } else {
+            None
+        };
+        let export = Export::new(&mut open_store(&config)?, server.as_ref())?;
+        println!("{}", serde_json::to_string_pretty(&export)?);
+    }
+    else if args.cmd_import {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        let export: Export = serde_json::from_reader(fs::File::open(&args.arg_file)?)?;
+
+        let failed = export.import(&mut open_store(&config)?, |cert, outcome| {
+            match *outcome {
+                cert_export::Outcome::Created => println!("{} {}: created", cert.cert_type, cert.name),
+                cert_export::Outcome::Unchanged => println!("{} {}: unchanged", cert.cert_type, cert.name),
+                cert_export::Outcome::Failed(ref e) => println!("{} {}: failed: {}", cert.cert_type, cert.name, e),
+            }
+        })?;
+
+        // Never replace an existing server key, which agents already trust
+        if let Some(ref key) = export.server {
+            if Path::new(&config.server_cert).exists() {
+                println!("Kept the existing server key in {}", config.server_cert);
+            } else {
+                let cert = key.to_zcert()?;
+                cert.save_public(&format!("{}_public", config.server_cert))?;
+                match config.server_cert_passphrase {
+                    Some(ref source) => server_key::save_encrypted(&cert, &config.server_cert, server_key::read_passphrase(source)?.expose())?,
+                    None => cert.save_secret(&config.server_cert)?,
+                }
+                println!("Restored the server key to {}", config.server_cert);
+            }
+        }
+
+        if failed > 0 {
+            println!("{} certificate(s) failed to import", failed);
+            exit(1);
+        }
+
+        println!("**********
+* PLEASE NOTE: You must restart the Auth server before these certificates will become valid!
+**********");
+    }
+    else if args.cmd_server && args.cmd_init {
+        let dir = match args.flag_c.as_ref().or(args.flag_config.as_ref()) {
+            Some(d) => PathBuf::from(d),
+            None => PathBuf::from(env::var("INAUTH_CONFIG_DIR").unwrap_or("/usr/local/etc/intecture".into())),
+        };
+        if dir.join("auth.json").exists() {
+            println!("{} already exists", dir.join("auth.json").display());
+            exit(1);
+        }
+
+        let mut settings = InitSettings {
+            server_cert: dir.join("auth.crt").to_string_lossy().into_owned(),
+            cert_path: args.flag_cert_path.clone().unwrap_or(dir.join("certs").to_string_lossy().into_owned()),
+            api_port: args.flag_api_port,
+            update_port: args.flag_update_port,
+        };
+        let mut admin = args.flag_admin.clone();
+        if !args.flag_non_interactive {
+            settings.server_cert = ask("Server certificate", &settings.server_cert)?;
+            settings.cert_path = ask("Certificate directory", &settings.cert_path)?;
+            settings.api_port = ask("API port", &settings.api_port.to_string())?.parse().or(Err(Error::InvalidArg))?;
+            settings.update_port = ask("Update port", &settings.update_port.to_string())?.parse().or(Err(Error::InvalidArg))?;
+            if admin.is_none() {
+                admin = Some(ask("First admin user (blank for none)", "")?).and_then(|a| if a.is_empty() { None } else { Some(a) });
+            }
+        }
+
+        let admin_path = server_init(&dir, &settings, admin.as_ref().map(|a| a as &str), Path::new(&args.flag_out))?;
+        println!("Wrote {}", dir.join("auth.json").display());
+        println!("Created the server certificate {} and certificate directory {}", settings.server_cert, settings.cert_path);
+        if let Some(path) = admin_path {
+            println!("Created admin user {}. Their certificate is saved in {}; distribute it securely.", admin.unwrap_or(String::new()), path.display());
+        }
+    }
+    else if args.cmd_server && args.cmd_encrypt_key {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        let cert = server_key::load(&config.server_cert, config.server_cert_passphrase.as_ref())?;
+
+        let passphrase = new_passphrase(None)?;
+        server_key::save_encrypted(&cert, &config.server_cert, passphrase.expose())?;
+        println!("Encrypted {}. Set \"server_cert_passphrase\" in auth.json so the Auth server can unlock it.", config.server_cert);
+    }
+    else if args.cmd_server && args.cmd_hsm_import {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        let hsm_config = match config.hsm {
+            Some(ref h) => h,
+            None => {
+                println!("Set \"hsm\" in auth.json first, with the token's module, slot and PIN");
+                exit(1);
+            }
+        };
+        let cert = server_key::load(&config.server_cert, config.server_cert_passphrase.as_ref())?;
+
+        hsm::store(hsm_config, &cert)?;
+        println!("Stored the server key on the token as \"{}\". Check the Auth server starts, then securely delete {} but keep {}_public.",
+                 hsm_config.label.as_ref().map(|l| l.as_str()).unwrap_or(hsm::DEFAULT_LABEL), config.server_cert, config.server_cert);
+    }
+    else if args.cmd_ceremony && args.cmd_init {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        if Path::new(&config.server_cert).exists() {
+            println!("{} already exists. The ceremony only creates new server keys.", config.server_cert);
+            exit(1);
+        }
+        if args.flag_witness.is_empty() {
+            println!("A key ceremony needs at least one --witness");
+            exit(1);
+        }
+
+        let log = AuditLog::new(format!("{}_ceremony.log", config.server_cert));
+        log.record("init-start", &[("witnesses", args.flag_witness.join(",")),
+                                   ("shares", args.flag_shares.unwrap_or(0).to_string()),
+                                   ("threshold", args.flag_threshold.unwrap_or(0).to_string())])?;
+        if let Err(e) = ceremony_init(&config, &args, &log) {
+            log.record("init-failed", &[("error", e.to_string())])?;
+            return Err(e);
+        }
+    }
+    else if args.cmd_ceremony && args.cmd_reconstruct {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        if Path::new(&config.server_cert).exists() {
+            println!("{} already exists. Reconstruction is for disaster recovery only and will not overwrite it.", config.server_cert);
+            exit(1);
+        }
+
+        let log = AuditLog::new(format!("{}_ceremony.log", config.server_cert));
+        log.record("reconstruct-start", &[("witnesses", args.flag_witness.join(",")),
+                                          ("files", args.arg_share_file.join(","))])?;
+        if let Err(e) = ceremony_reconstruct(&config, &args, &log) {
+            log.record("reconstruct-failed", &[("error", e.to_string())])?;
+            return Err(e);
+        }
+    }
+    else if args.cmd_storage && args.cmd_audit_keys {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+
+        let mut persistence = open_store(&config)?;
+        let certs = persistence.dump()?;
+        let problems = key_health::audit(&certs);
+        for &(ref name, ref problem) in &problems {
+            println!("{}\t{}", name, problem);
+        }
+
+        if !problems.is_empty() {
+            println!("{} of {} certificate(s) have unsafe keys. Delete and reissue them.", problems.len(), certs.len());
+            exit(1);
+        }
+        println!("Checked {} certificate(s), no problems found", certs.len());
+    }
+    else if args.cmd_storage && args.cmd_switch {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+
+        // The running server mirrors writes to the new directory while
+        // it copies, so it keeps serving throughout
+        let dir = fs::canonicalize(&args.arg_dir)?;
+        let dir = dir.to_str().ok_or(Error::InvalidCertPath)?;
+        admin_request(&config, &["storage::switch", "attach", dir])?;
+        for step in &["copy", "verify", "promote"] {
+            if let Err(e) = admin_request(&config, &["storage::switch", step]) {
+                let _ = admin_request(&config, &["storage::switch", "abort"]);
+                return Err(e);
+            }
+            println!("{}: done", step);
+        }
+
+        println!("The Auth server now stores certificates in {}. Set \"cert_path\" to it in auth.json before restarting the server.", dir);
+    }
+    else if args.cmd_storage && args.cmd_fix_perms {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+
+        let defaults = config.cert_files.clone().unwrap_or_default();
+        let mode = args.flag_mode.as_ref().or(defaults.mode.as_ref());
+        let owner = args.flag_owner.as_ref().or(defaults.owner.as_ref());
+        let perms = FilePerms::new(mode.map(|m| m as &str), owner.map(|o| o as &str))?;
+        if perms.is_empty() {
+            println!("No mode or owner given. Pass --mode or --owner, or set \"cert_files\" in auth.json.");
+            exit(1);
+        }
+
+        let mut persistence = PersistDisk::new(&config.cert_path)?;
+        persistence.set_file_perms(perms);
+        let fixed = persistence.fix_perms()?;
+        for name in &fixed {
+            println!("{}", name);
+        }
+        if fixed.is_empty() {
+            println!("All files in {} already have the right permissions", config.cert_path);
+        } else {
+            println!("Fixed {} file(s)", fixed.len());
+        }
+    }
+    else if args.cmd_watch {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+
+        let server_cert = ZCert::load(&format!("{}_public", config.server_cert))?;
+        let cert = ZCert::load(&args.flag_cert)?;
+        let topics = match args.flag_type {
+            Some(ref t) => vec![CertType::from_str(t)?],
+            None => vec![CertType::Host, CertType::User],
+        };
+
+        let mut sock = ZSock::new(SocketType::SUB);
+        sock.set_curve_serverkey(server_cert.public_txt());
+        cert.apply(&mut sock);
+        for topic in &topics {
+            sock.set_subscribe(topic.to_str());
+        }
+        // Certs pushed to this user alone, as an agent would see them
+        if let Some(Ok(name)) = cert.meta("name") {
+            sock.set_subscribe(&direct_topic(&name));
+        }
+        let endpoint = format!("tcp://{}:{}", args.flag_host, config.update_port);
+        sock.connect(&endpoint)?;
+        if output == OutputFormat::Text {
+            println!("Watching {}. The first ADDs are the current certs.", endpoint);
+        }
+
+        // DELs only carry the key, so remember what it belonged to
+        let mut known = HashMap::new();
+        loop {
+            let msg = ZMsg::recv(&mut sock)?;
+            let events = match watch::decode(&msg) {
+                Ok(e) => e,
+                Err(e) => {
+                    let _ = writeln!(io::stderr(), "Skipping unreadable feed message: {}", e);
+                    continue;
+                }
+            };
+
+            for mut event in events {
+                if event.action == "DEL" {
+                    if let Some((name, cert_type)) = known.remove(&event.public_key) {
+                        event.name = name;
+                        event.cert_type = cert_type;
+                    }
+                } else {
+                    known.insert(event.public_key.clone(), (event.name.clone(), event.cert_type.clone()));
+                }
+
+                match output {
+                    OutputFormat::Text => println!("{:<6} {:<5} {:<30} {}",
+                                                   event.action,
+                                                   event.cert_type.as_ref().unwrap_or(&event.topic),
+                                                   event.name.as_ref().map(|n| n.as_str()).unwrap_or("-"),
+                                                   event.public_key),
+                    // One document per event, so the stream can be piped
+                    OutputFormat::Json => println!("{}", serde_json::to_string(&event)?),
+                    OutputFormat::Yaml => println!("---\n{}", output.render(&event)?),
+                }
+            }
+        }
+    }
+    else if args.cmd_status {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+
+        let started = Instant::now();
+        let reply = api_request(&config, &args.flag_host, &args.flag_cert, &["server::info"])?;
+        let elapsed = started.elapsed();
+        let round_trip_ms = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64;
+
+        let mut info: Value = serde_json::from_str(reply.get(0).ok_or(Error::InvalidArgsCount)?)?;
+        if output != OutputFormat::Text {
+            if let Value::Object(ref mut map) = info {
+                map.insert("host".into(), Value::String(args.flag_host.clone()));
+                map.insert("round_trip_ms".into(), serde_json::to_value(round_trip_ms)?);
+            }
+            println!("{}", output.render(&info)?);
+        } else {
+            println!("Auth server {}:{} is up ({} ms round trip)", args.flag_host, config.api_port, round_trip_ms);
+            println!("Version:   {}", info["version"].as_str().unwrap_or("unknown"));
+            println!("Uptime:    {}", info["uptime_secs"].as_u64().map(format_uptime).unwrap_or("unknown".into()));
+            println!("Hosts:     {}", info["certs"]["hosts"]);
+            println!("Users:     {}", info["certs"]["users"]);
+            println!("Sequence:  {}", info["sequence"]);
+            if info["draining"].as_bool() == Some(true) {
+                println!("Draining subscribers ahead of a restart");
+            }
+        }
+    }
+    else if args.cmd_admin {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+
+        let mut request = Vec::new();
+        if args.cmd_cache_stats {
+            request.push("cache::stats");
+        }
+        else if args.cmd_feed_subscribers {
+            request.push("feed::subscribers");
+        }
+        else if args.cmd_config_dump {
+            request.push("config::dump");
+        }
+        else if args.cmd_drain {
+            request.push("feed::drain");
+        }
+        else if args.cmd_fleet_health {
+            request.push("fleet::cache_health");
+        }
+        else if args.cmd_log_level {
+            request.push("log::level");
+            if let Some(ref level) = args.arg_level {
+                request.push(level);
+            }
+        }
+        else if args.cmd_log_sampling {
+            request.push("log::sampling");
+            if let Some(ref every) = args.arg_every {
+                request.push(every);
+            }
+        }
+
+        println!("{}", admin_request(&config, &request)?);
+    }
+    else if args.cmd_attest && args.cmd_verify {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+
+        let public_key = match (args.flag_key.as_ref(), config.attestation.as_ref()) {
+            (Some(key), _) => attestation::public_key_from_hex(key)?,
+            (None, Some(a)) => attestation::load_key(&a.key_path)?.0,
+            (None, None) => return Err(Error::MissingConf),
+        };
+
+        let latest: Value = serde_json::from_str(&admin_request(&config, &["attest::latest"])?)?;
+        if latest.is_null() {
+            println!("The Auth server has not published an attestation yet");
+            exit(1);
+        }
+        let attestation = Attestation::from_json(&latest)?;
+
+        let mut persistence = open_store(&config)?;
+        let certs = persistence.dump()?;
+        attestation.check(&public_key, certs.iter().map(|c| (c.name(), c.public_txt())))?;
+        println!("Attestation {} matches the {} stored certificate(s)", attestation.sequence, certs.len());
+    }
+    else if args.cmd_feed && args.cmd_push {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+
+        let mut request = vec!["feed::push", &args.flag_name as &str];
+        if let Some(ref subscriber) = args.flag_subscriber {
+            request.push(subscriber);
+        }
+        admin_request(&config, &request)?;
+        println!("Republished {}", args.flag_name);
+    }
+    else if args.cmd_policy && args.cmd_test {
+        let script = PolicyScript::load(&args.arg_script, PolicyLimits::default())?;
+        let mut fh = fs::File::open(&args.arg_file)?;
+        let samples: Vec<PolicySample> = serde_json::from_reader(&mut fh)?;
+
+        let mut unexpected = 0;
+        for (i, sample) in samples.iter().enumerate() {
+            let hook = Hook::from_str(&sample.hook)?;
+            let outcome = match script.evaluate(hook, &sample.request) {
+                Ok(true) => "allow".to_string(),
+                Ok(false) => "deny".to_string(),
+                Err(e) => format!("deny ({})", e),
+            };
+            let allowed = outcome == "allow";
+
+            match sample.expect {
+                Some(expect) if expect != allowed => {
+                    unexpected += 1;
+                    println!("sample {} ({}): {}, expected {}", i + 1, hook.to_str(), outcome, if expect { "allow" } else { "deny" });
+                },
+                _ => println!("sample {} ({}): {}", i + 1, hook.to_str(), outcome),
+            }
+        }
+
+        if unexpected > 0 {
+            println!("{} sample(s) did not match their expected outcome", unexpected);
+            exit(1);
+        }
+    }
+    else if args.cmd_config && args.cmd_render {
+        let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
+        let config = read_conf(config_path)?;
+        // Tokens are redacted as they are in the admin config-dump
+        println!("{}", config::dump(&config)?);
+    }
+    else if args.cmd_trace && args.cmd_decode {
+        let mut fh = fs::File::open(&args.arg_file)?;
+        for record in decode(&mut fh)? {
+            println!("{}", record);
+        }
+    }
+
+    Ok(())
+}
+
+// Generate the server cert and hand its secret key out in shares
+fn ceremony_init(config: &Config, args: &Args, log: &AuditLog) -> Result<()> {
+    let shares = args.flag_shares.ok_or(Error::InvalidArg)?;
+    let threshold = args.flag_threshold.ok_or(Error::InvalidArg)?;
+
+    let cert = ZCert::new()?;
+    cert.set_meta("name", "auth");
+    cert.set_meta("type", CertType::Host.to_str());
+    let key_shares = ceremony::split(&cert, shares, threshold)?;
+
+    // Shares go out before the cert is saved, so a failure never leaves
+    // a key behind that nobody can recover
+    let paths = ceremony::write_shares(&key_shares, &args.flag_out)?;
+    cert.save_public(&format!("{}_public", config.server_cert))?;
+    match config.server_cert_passphrase {
+        Some(ref source) => server_key::save_encrypted(&cert, &config.server_cert, server_key::read_passphrase(source)?.expose())?,
+        None => cert.save_secret(&config.server_cert)?,
+    }
+
+    let key_id = ceremony::key_id(cert.public_key());
+    log.record("init", &[("key", key_id.clone()),
+                         ("witnesses", args.flag_witness.join(",")),
+                         ("shares", shares.to_string()),
+                         ("threshold", threshold.to_string()),
+                         ("fingerprints", key_shares.iter().map(|s| format!("{}/{}", s.share.x, s.fingerprint())).collect::<Vec<_>>().join(","))])?;
+
+    println!("Created server key {}. Any {} of these {} shares can reconstruct it:", key_id, threshold, shares);
+    for path in &paths {
+        println!("  {}", path.display());
+    }
+    println!("Give each share to its custodian, then delete it from this machine.");
+    Ok(())
+}
+
+// Rebuild the server cert from custodians' shares
+fn ceremony_reconstruct(config: &Config, args: &Args, log: &AuditLog) -> Result<()> {
+    let public = ZCert::load(&format!("{}_public", config.server_cert))?;
+
+    let mut shares = Vec::new();
+    for path in &args.arg_share_file {
+        let mut text = String::new();
+        fs::File::open(path)?.read_to_string(&mut text)?;
+        let share = KeyShare::parse(&text)?;
+        log.record("share-presented", &[("file", path.clone()),
+                                        ("key", share.key_id.clone()),
+                                        ("index", share.share.x.to_string()),
+                                        ("fingerprint", share.fingerprint())])?;
+        shares.push(share);
+    }
+
+    let cert = ceremony::reconstruct(&public, &shares)?;
+    match config.server_cert_passphrase {
+        Some(ref source) => server_key::save_encrypted(&cert, &config.server_cert, server_key::read_passphrase(source)?.expose())?,
+        None => cert.save_secret(&config.server_cert)?,
+    }
+
+    let key_id = ceremony::key_id(public.public_key());
+    log.record("reconstruct", &[("key", key_id.clone()),
+                                ("witnesses", args.flag_witness.join(",")),
+                                ("indexes", shares.iter().map(|s| s.share.x.to_string()).collect::<Vec<_>>().join(","))])?;
+    println!("Reconstructed server key {} to {}", key_id, config.server_cert);
+    Ok(())
+}
+
+// Bootstrap a new Auth server's config, cert store and server cert in
+// `dir`, plus an optional first admin whose secret key is saved to
+// `out`. auth.json is written last, so a failed init can be re-run.
+fn server_init(dir: &Path, settings: &InitSettings, admin: Option<&str>, out: &Path) -> Result<Option<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    // Only the Auth server's user may read or change the cert store
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(&settings.cert_path)?;
+    fs::set_permissions(&settings.cert_path, fs::Permissions::from_mode(0o700))?;
+
+    if !Path::new(&settings.server_cert).exists() {
+        let cert = ZCert::new()?;
+        cert.set_meta("name", "auth");
+        cert.set_meta("type", CertType::Host.to_str());
+        cert.save_public(&format!("{}_public", settings.server_cert))?;
+        cert.save_secret(&settings.server_cert)?;
+    }
+
+    let admin_path = match admin {
+        Some(name) => {
+            let path = out.join(format!("{}.crt", name));
+            let cert = Cert::new(name, CertType::User)?;
+            cert.set_meta("role", "admin");
+            PersistDisk::new(&settings.cert_path)?.create(&cert)?;
+            cert.save_secret(&path)?;
+            Some(path)
+        },
+        None => None,
+    };
+
+    let mut fh = fs::OpenOptions::new().write(true).create_new(true).mode(0o644).open(dir.join("auth.json"))?;
+    serde_json::to_writer_pretty(&mut fh, settings)?;
+    writeln!(fh)?;
+    Ok(admin_path)
+}
+
+// Prompt for a setting, falling back to `default` on a blank answer
+fn ask(question: &str, default: &str) -> Result<String> {
+    if default.is_empty() {
+        print!("{}: ", question);
+    } else {
+        print!("{} [{}]: ", question, default);
+    }
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(if answer.is_empty() { default.to_string() } else { answer.to_string() })
+}
+
+// Whether to go through the running server's API rather than the cert
+// store
+fn is_remote(args: &Args) -> bool {
+    if args.flag_remote && args.flag_cert.is_empty() {
+        println!("--remote needs --cert, a user certificate to authenticate with");
+        exit(1);
+    }
+    args.flag_remote
+}
+
+// Have the running server create and publish a cert
+fn remote_create(config: &Config, args: &Args, cert_type: CertType, name: &str) -> Result<Cert> {
+    replied_cert(&api_request_frames(config, &args.flag_host, &args.flag_cert, &["cert::create", cert_type.to_str(), name])?)
+}
+
+// The new cert in a cert::create or cert::rotate reply
+fn replied_cert(reply: &[Vec<u8>]) -> Result<Cert> {
+    if reply.len() < 3 {
+        return Err(Error::InvalidArgsCount);
+    }
+    let zcert = ZCert::from_txt(&String::from_utf8_lossy(&reply[0]), &String::from_utf8_lossy(&reply[1]))?;
+    zcert.decode_meta(&reply[2])?;
+    Cert::from_zcert(zcert)
+}
+
+// Swap `old` for `cert` in the cert store, putting `old` back if that
+// fails
+/// The cert store, writing files with the mode and owner from
+/// `cert_files`.
+fn open_store(config: &Config) -> Result<PersistDisk> {
+    let mut persistence = PersistDisk::new(&config.cert_path)?;
+    persistence.set_file_perms(FilePerms::from_config(config.cert_files.as_ref())?);
+    Ok(persistence)
+}
+
+fn replace_cert(persistence: &mut PersistDisk, old: &Cert, cert: &Cert) -> Result<()> {
+    persistence.delete(old.name())?;
+    if let Err(e) = persistence.create(cert) {
+        persistence.create(old)?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+// `secret_path` and `bundle_path` are where the secret key was saved
+// instead, if anywhere
+// How `print_cert` shows a secret key that isn't saved to a file
+enum SecretDisplay {
+    Plain,
+    /// Passphrase encrypted and armored
+    Encrypted(String),
+    /// A rendered QR code of the bootstrap bundle. JSON and YAML output
+    /// show the key as usual.
+    Qr(String),
+    /// Stored in the OS keychain
+    Keychain,
+}
+
+fn print_cert(cert: &Cert, remote: bool, secret_path: Option<String>, bundle_path: Option<String>, display: SecretDisplay, output: OutputFormat) -> Result<()> {
+    if output != OutputFormat::Text {
+        let keychain = match display {
+            SecretDisplay::Keychain => true,
+            _ => false,
+        };
+        let encrypted = match display {
+            SecretDisplay::Encrypted(armored) => Some(armored),
+            _ => None,
+        };
+        let secret = cert.secret_txt();
+        let role = match cert.meta("role") {
+            Some(Ok(role)) => Some(role),
+            _ => None,
+        };
+        let printed = PrintedCert {
+            name: cert.name(),
+            cert_type: cert.cert_type().to_str(),
+            role: role,
+            public_key: cert.public_txt(),
+            secret_key: if secret_path.is_none() && bundle_path.is_none() && encrypted.is_none() && !keychain { Some(secret.expose()) } else { None },
+            encrypted_secret_key: encrypted,
+            secret_path: secret_path,
+            bundle_path: bundle_path,
+            keychain: keychain,
+            restart_required: !remote,
+        };
+        println!("{}", output.render(&printed)?);
+        return Ok(());
+    }
+
+    if let Some(ref path) = bundle_path {
+        println!("Wrote bootstrap bundle to {}", path);
+    }
+    if secret_path.is_some() || bundle_path.is_some() {
+        return Ok(());
+    }
+
+    if !remote {
+        println!("**********
 * PLEASE NOTE: You must restart the Auth server before this certificate will become valid!
 **********
+");
+    }
+
+    match display {
+        SecretDisplay::Encrypted(armored) => {
+            println!("Please send this certificate and its passphrase separately. Save it to a file and unlock it with:
+
+    inauth_cli cert decrypt <file> {}.crt
 
-Please distribute this certificate securely.
+{}", cert.name(), armored);
+            return Ok(());
+        },
+        SecretDisplay::Qr(qr) => {
+            println!("Scan this code on the device {} will use. It holds the secret key, so keep it off screen shares.\n\n{}", cert.name(), qr);
+            return Ok(());
+        },
+        SecretDisplay::Keychain => {
+            println!("Stored the secret key for {} in the OS keychain. Programs running as this OS user can load it with inauth_client::load_keychain_cert(\"{}\").", cert.name(), cert.name());
+            return Ok(());
+        },
+        SecretDisplay::Plain => (),
+    }
+
+    let mut meta = String::new();
+    for key in &["name", "type", "role"] {
+        if let Some(Ok(value)) = cert.meta(key) {
+            meta.push_str(&format!("\n    {} = \"{}\"", key, value));
+        }
+    }
+    println!("Please distribute this certificate securely.
 
 ------------------------COPY BELOW THIS LINE-------------------------
-metadata
-    name = \"{}\"
-    type = \"user\"
+metadata{}
 curve
     public-key = \"{}\"
     secret-key = \"{}\"
-------------------------COPY ABOVE THIS LINE-------------------------", args.arg_username, cert.public_txt(), cert.secret_txt());
+------------------------COPY ABOVE THIS LINE-------------------------", meta, cert.public_txt(), cert.secret_txt().expose());
+    Ok(())
+}
+
+// Whatever would stop the cert in `path` authenticating an agent as of
+// `now`. The cert is `None` if it can't be read at all.
+fn verify_file(path: &str, now: u64) -> (Option<Cert>, Vec<String>) {
+    let zcert = match ZCert::load(path) {
+        Ok(zcert) => zcert,
+        Err(e) => return (None, vec![format!("can't read certificate: {}", e)]),
+    };
+    let cert = match Cert::from_zcert(zcert) {
+        Ok(cert) => cert,
+        Err(_) => return (None, vec!["missing or invalid \"name\" or \"type\" metadata".into()]),
+    };
+
+    let mut problems = Vec::new();
+    if cert.secret_key().expose().iter().all(|b| *b == 0) {
+        problems.push("no secret key, so this is only the public half".into());
+    }
+    if let Some(problem) = key_health::check_cert(&cert) {
+        problems.push(format!("unsafe key: {}", problem));
+    }
+    if cert.is_revoked() {
+        problems.push("revoked".into());
+    }
+    if cert.is_disabled() {
+        problems.push("disabled".into());
+    }
+    if cert.is_expired_at(now) {
+        problems.push(format!("expired at {}", cert.expires_at().unwrap_or(0)));
+    }
+    (Some(cert), problems)
+}
+
+// Seconds as e.g. "3d 4h 5m 6s", leaving out leading zero units
+fn format_uptime(secs: u64) -> String {
+    let units = [(secs / 86400, "d"), (secs / 3600 % 24, "h"), (secs / 60 % 60, "m")];
+    let mut parts: Vec<String> = units.iter()
+        .skip_while(|&&(n, _)| n == 0)
+        .map(|&(n, unit)| format!("{}{}", n, unit))
+        .collect();
+    parts.push(format!("{}s", secs % 60));
+    parts.join(" ")
+}
+
+// Ask for a passphrase to encrypt with, or read it from `source`
+fn new_passphrase(source: Option<&String>) -> Result<Secret<String>> {
+    if let Some(source) = source {
+        return server_key::read_passphrase(source);
+    }
+
+    let passphrase = server_key::prompt("New passphrase: ")?;
+    if passphrase.expose().is_empty() {
+        return Err(Error::MissingPassphrase);
+    }
+    if passphrase != server_key::prompt("Confirm passphrase: ")? {
+        return Err(Error::InvalidPassphrase);
+    }
+    Ok(passphrase)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Cert names from the running server or the cert store
+fn list_certs(config: &Config, args: &Args, cert_type: CertType) -> Result<Vec<String>> {
+    let mut names = if !is_remote(args) {
+        let mut persistence = open_store(config)?;
+        persistence.dump()?.into_iter().filter(|c| c.cert_type() == cert_type).map(|c| c.name().to_string()).collect()
+    } else {
+        api_request(config, &args.flag_host, &args.flag_cert, &["cert::list", cert_type.to_str()])?
+    };
+    names.sort();
+    Ok(names)
+}
+
+// Delete through the running server, which publishes the DEL so that
+// agents learn of it straight away, or from the cert store
+fn delete_cert(config: &Config, args: &Args, cert_type: CertType, name: &str, output: OutputFormat) -> Result<()> {
+    // Cert types share a namespace, so make sure we only ever delete
+    // the type asked for
+    if !list_certs(config, args, cert_type)?.iter().any(|n| n == name) {
+        println!("No {} named {}", cert_type.to_str(), name);
+        exit(1);
+    }
+
+    let remote = is_remote(args);
+    if !remote {
+        open_store(config)?.delete(name)?;
+    } else {
+        api_request(config, &args.flag_host, &args.flag_cert, &["cert::delete", name])?;
+    }
+
+    if output != OutputFormat::Text {
+        println!("{}", output.render(&Deleted { deleted: name, cert_type: cert_type.to_str(), restart_required: !remote })?);
+    } else {
+        println!("Deleted {}", name);
+        if !remote {
+            println!("**********
+* PLEASE NOTE: Running Auth servers will accept this certificate until restarted! Use --remote to delete it through a running server instead.
+**********");
+        }
+    }
+    Ok(())
+}
+
+/// The minimal auth.json written by `server init`.
+#[derive(Debug, Serialize)]
+struct InitSettings {
+    server_cert: String,
+    cert_path: String,
+    api_port: u32,
+    update_port: u32,
+}
+
+/// A new cert, as printed by `--output json|yaml`.
+#[derive(Debug, Serialize)]
+struct PrintedCert<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    cert_type: &'a str,
+    role: Option<String>,
+    public_key: &'a str,
+    /// Left out when saved to a file or encrypted instead
+    secret_key: Option<&'a str>,
+    encrypted_secret_key: Option<String>,
+    secret_path: Option<String>,
+    bundle_path: Option<String>,
+    /// Whether the secret key was stored in the OS keychain
+    keychain: bool,
+    /// Whether the Auth server must restart before the cert is valid
+    restart_required: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Deleted<'a> {
+    deleted: &'a str,
+    #[serde(rename = "type")]
+    cert_type: &'a str,
+    /// Whether running Auth servers accept the cert until restarted
+    restart_required: bool,
+}
+
+/// A stored cert, as printed by `cert show`.
+#[derive(Debug, Serialize)]
+struct ShownCert<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    cert_type: &'a str,
+    metadata: BTreeMap<String, String>,
+    public_key: &'a str,
+    /// Short hash of the public key, as in key ceremony logs
+    fingerprint: String,
+    /// "valid", "expired", "revoked" or "disabled"
+    status: &'static str,
+}
+
+impl<'a> ShownCert<'a> {
+    fn new(cert: &'a Cert, now: u64) -> ShownCert<'a> {
+        let mut metadata = BTreeMap::new();
+        for key in cert.meta_keys() {
+            let value = match cert.meta(key) {
+                Some(Ok(value)) => value,
+                Some(Err(bytes)) => String::from_utf8_lossy(&bytes).into_owned(),
+                None => continue,
+            };
+            metadata.insert(key.to_string(), value);
+        }
+
+        ShownCert {
+            name: cert.name(),
+            cert_type: cert.cert_type().to_str(),
+            metadata: metadata,
+            public_key: cert.public_txt(),
+            fingerprint: ceremony::key_id(cert.public_key()),
+            status: if cert.is_revoked() {
+                "revoked"
+            } else if cert.is_disabled() {
+                "disabled"
+            } else if cert.is_expired_at(now) {
+                "expired"
+            } else {
+                "valid"
+            },
         }
     }
+}
+
+#[derive(Debug, Serialize)]
+struct Verified<'a> {
+    file: &'a str,
+    cert: Option<ShownCert<'a>>,
+    problems: &'a [String],
+    valid: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Revoked<'a> {
+    revoked: &'a str,
+    /// Left out when revoked through the server
+    #[serde(rename = "type")]
+    cert_type: Option<&'static str>,
+    /// Whether running Auth servers accept the cert until restarted
+    restart_required: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Lookup<'a> {
+    name: &'a str,
+    public_key: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct Found<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    cert_type: &'a str,
+}
+
+/// Everything a new host or user needs to reach the Auth server.
+/// Written by `host add --bundle`, and shown by `user add --qr`.
+#[derive(Debug, Serialize)]
+struct Bundle<'a> {
+    name: &'a str,
+    public_key: &'a str,
+    secret_key: &'a str,
+    auth_public_key: &'a str,
+    auth_host: &'a str,
+    api_port: u32,
+    update_port: u32,
+    /// The server's advertised feed endpoint, if it has one
+    feed_endpoint: Option<&'a str>,
+}
 
+// The bundle holds the host's secret key, so only its owner may read it
+fn write_bundle(config: &Config, host: &str, cert: &Cert, path: &str) -> Result<()> {
+    let bundle = bundle_json(config, host, cert, true)?;
+    let mut fh = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    writeln!(fh, "{}", bundle)?;
     Ok(())
 }
 
+fn bundle_json(config: &Config, host: &str, cert: &Cert, pretty: bool) -> Result<String> {
+    let server_cert = ZCert::load(&format!("{}_public", config.server_cert))?;
+    let secret = cert.secret_txt();
+    let bundle = Bundle {
+        name: cert.name(),
+        public_key: cert.public_txt(),
+        secret_key: secret.expose(),
+        auth_public_key: server_cert.public_txt(),
+        auth_host: host,
+        api_port: config.api_port,
+        update_port: config.update_port,
+        feed_endpoint: config.feed_endpoint.as_ref().map(|e| e as &str),
+    };
+
+    if pretty {
+        Ok(serde_json::to_string_pretty(&bundle)?)
+    } else {
+        Ok(serde_json::to_string(&bundle)?)
+    }
+}
+
+// Send a request to the server's admin socket and return the reply body
+fn admin_request(config: &Config, request: &[&str]) -> Result<String> {
+    let endpoint = config.admin_socket.as_ref().ok_or(Error::MissingConf)?;
+    let mut sock = ZSock::new_req(endpoint)?;
+    sock.set_rcvtimeo(Some(5000));
+
+    let msg = ZMsg::new();
+    for frame in request {
+        msg.addstr(frame)?;
+    }
+    msg.send(&mut sock)?;
+
+    let reply = ZMsg::recv(&mut sock)?;
+    let status = reply.popstr().unwrap_or(Ok(String::new())).unwrap_or(String::new());
+    let body = reply.popstr().unwrap_or(Ok(String::new())).unwrap_or(String::new());
+    if status == "Ok" {
+        Ok(body)
+    } else {
+        Err(Error::Gateway(body))
+    }
+}
+
+// Send a request to the cert API as the user in `cert_path` and return
+// the reply frames after the status
+fn api_request(config: &Config, host: &str, cert_path: &str, request: &[&str]) -> Result<Vec<String>> {
+    let frames = api_request_frames(config, host, cert_path, request)?;
+    Ok(frames.into_iter().map(|f| String::from_utf8_lossy(&f).into_owned()).collect())
+}
+
+// As `api_request`, for replies with binary frames
+fn api_request_frames(config: &Config, host: &str, cert_path: &str, request: &[&str]) -> Result<Vec<Vec<u8>>> {
+    let server_cert = ZCert::load(&format!("{}_public", config.server_cert))?;
+    let cert = ZCert::load(cert_path)?;
+
+    let mut sock = ZSock::new(SocketType::REQ);
+    sock.set_curve_serverkey(server_cert.public_txt());
+    cert.apply(&mut sock);
+    sock.set_rcvtimeo(Some(5000));
+    sock.connect(&format!("tcp://{}:{}", host, config.api_port))?;
+
+    let msg = ZMsg::new();
+    for frame in request {
+        msg.addstr(frame)?;
+    }
+    msg.send(&mut sock)?;
+
+    let reply = ZMsg::recv(&mut sock)?;
+    let status = reply.popstr().unwrap_or(Ok(String::new())).unwrap_or(String::new());
+    let mut frames = Vec::new();
+    while let Some(frame) = reply.popbytes()? {
+        frames.push(frame);
+    }
+    if status == "Ok" {
+        Ok(frames)
+    } else {
+        Err(Error::Gateway(frames.pop().map(|f| String::from_utf8_lossy(&f).into_owned()).unwrap_or(String::new())))
+    }
+}
+
+/// A test input for `policy test`.
+#[derive(Debug, Deserialize)]
+struct PolicySample {
+    /// "zap" or "api"
+    hook: String,
+    request: Value,
+    expect: Option<bool>,
+}
+
 fn read_conf<P: AsRef<Path>>(path: Option<P>) -> Result<Config> {
     if let Some(p) = path {
         do_read_conf(p)
@@ -117,22 +1716,117 @@ fn read_conf<P: AsRef<Path>>(path: Option<P>) -> Result<Config> {
 }
 
 fn do_read_conf<P: AsRef<Path>>(path: P) -> Result<Config> {
-    let mut path = path.as_ref().to_owned();
-    path.push("auth.json");
+    let config = config::load(path)?;
 
-    let mut fh = fs::File::open(&path)?;
-    let mut json = String::new();
-    fh.read_to_string(&mut json)?;
-    Ok(serde_json::from_str(&json)?)
+    // Stderr keeps the banner out of output that may be piped
+    if let Some(banner) = config.messages.as_ref().and_then(|m| m.cli_banner.as_ref()) {
+        let _ = writeln!(io::stderr(), "{}", banner);
+    }
+
+    Ok(config)
 }
 
 #[cfg(test)]
 mod tests {
+    use cert::{Cert, CertType};
+    use config::Config;
+    use czmq::ZCert;
+    use serde_json::{self, Value};
     use std::{env, fs};
     use std::io::Write;
-    use super::read_conf;
+    use std::os::unix::fs::PermissionsExt;
+    use super::{format_uptime, read_conf, server_init, verify_file, write_bundle, InitSettings};
     use tempdir::TempDir;
 
+    #[test]
+    fn test_write_bundle() {
+        let tmpdir = TempDir::new("cli_test_write_bundle").unwrap();
+        let server_cert = tmpdir.path().join("auth.crt");
+        let server = ZCert::new().unwrap();
+        server.save_public(&format!("{}_public", server_cert.display())).unwrap();
+        let config: Config = serde_json::from_str(&format!("{{\"server_cert\": \"{}\", \"cert_path\": \"/path\", \"api_port\": 7101, \"update_port\": 7102}}", server_cert.display())).unwrap();
+
+        let cert = Cert::new("web1", CertType::Host).unwrap();
+        let path = tmpdir.path().join("web1.json");
+        let path = path.to_str().unwrap();
+        write_bundle(&config, "auth.example.com", &cert, path).unwrap();
+        assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let bundle: Value = serde_json::from_reader(fs::File::open(path).unwrap()).unwrap();
+        assert_eq!(bundle["name"], "web1");
+        assert_eq!(bundle["secret_key"], *cert.secret_txt().expose());
+        assert_eq!(bundle["auth_public_key"], server.public_txt());
+        assert_eq!(bundle["auth_host"], "auth.example.com");
+        assert_eq!(bundle["update_port"], 7102);
+        assert!(bundle["feed_endpoint"].is_null());
+
+        // Never overwrite another host's secret
+        assert!(write_bundle(&config, "auth.example.com", &cert, path).is_err());
+    }
+
+    #[test]
+    fn test_verify_file() {
+        let tmpdir = TempDir::new("cli_test_verify_file").unwrap();
+        let path = tmpdir.path().join("web1.crt");
+        let path = path.to_str().unwrap();
+
+        let (cert, problems) = verify_file(path, 100);
+        assert!(cert.is_none());
+        assert_eq!(problems.len(), 1);
+
+        let cert = Cert::new("web1", CertType::Host).unwrap();
+        cert.save_secret(path).unwrap();
+        let (verified, problems) = verify_file(path, 100);
+        assert_eq!(verified.unwrap().public_txt(), cert.public_txt());
+        assert!(problems.is_empty());
+
+        cert.set_meta("expires", "50");
+        cert.set_meta("revoked", "true");
+        cert.save_public(path).unwrap();
+        let (_, problems) = verify_file(path, 100);
+        assert_eq!(problems, vec!["no secret key, so this is only the public half", "revoked", "expired at 50"]);
+
+        let zcert = ZCert::new().unwrap();
+        zcert.set_meta("name", "web1");
+        zcert.save_secret(path).unwrap();
+        let (cert, _) = verify_file(path, 100);
+        assert!(cert.is_none());
+    }
+
+    #[test]
+    fn test_server_init() {
+        let tmpdir = TempDir::new("cli_test_server_init").unwrap();
+        let dir = tmpdir.path().join("etc");
+        let settings = InitSettings {
+            server_cert: dir.join("auth.crt").to_str().unwrap().into(),
+            cert_path: dir.join("certs").to_str().unwrap().into(),
+            api_port: 7101,
+            update_port: 7102,
+        };
+
+        let admin = server_init(&dir, &settings, Some("alice"), tmpdir.path()).unwrap().unwrap();
+        assert_eq!(fs::metadata(dir.join("certs")).unwrap().permissions().mode() & 0o777, 0o700);
+        assert!(ZCert::load(dir.join("auth.crt")).is_ok());
+        assert!(ZCert::load(dir.join("auth.crt_public")).is_ok());
+        assert!(dir.join("certs/alice.crt").exists());
+        let alice = Cert::from_zcert(ZCert::load(admin).unwrap()).unwrap();
+        assert_eq!(alice.meta("role").unwrap().unwrap(), "admin");
+
+        let config = read_conf(Some(&dir)).unwrap();
+        assert_eq!(config.cert_path, settings.cert_path);
+        assert_eq!(config.update_port, 7102);
+
+        // Never clobber an existing config
+        assert!(server_init(&dir, &settings, None, tmpdir.path()).is_err());
+    }
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(5), "5s");
+        assert_eq!(format_uptime(3 * 3600 + 5), "3h 0m 5s");
+        assert_eq!(format_uptime(2 * 86400 + 61), "2d 0h 1m 1s");
+    }
+
     #[test]
     fn test_read_conf() {
         let tmpdir = TempDir::new("cli_test_read_conf").unwrap();
@@ -7,19 +7,93 @@
 // modified, or distributed except according to those terms.
 
 extern crate czmq;
+#[cfg(feature = "keychain")]
+extern crate keyring;
+extern crate libc;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "policy")]
+extern crate rhai;
 extern crate serde_json;
+extern crate sodiumoxide;
+#[cfg(test)]
+extern crate tempdir;
 extern crate zdaemon;
 extern crate zmq;
+#[cfg(feature = "zstd")]
+extern crate zstd;
 
+mod address_policy;
+mod affinity;
+#[allow(dead_code)]
+mod attestation;
+mod auth_policy;
+#[cfg(feature = "bench-support")]
+#[doc(hidden)]
+pub mod bench_support;
+mod brute_force;
+#[cfg(feature = "capi")]
+pub mod capi;
+mod cbor;
 #[allow(dead_code)]
 mod cert;
 #[allow(dead_code)]
 mod cert_cache;
+mod cert_client;
+mod compression;
+mod domain_policy;
 #[allow(dead_code)]
 mod error;
+mod feed_monitor;
+#[allow(dead_code)]
+mod feed_signature;
+#[allow(dead_code)]
+mod key_health;
+mod keychain;
+#[allow(dead_code)]
+mod filter;
+mod frame_policy;
+mod latency;
+mod log_sampling;
+#[cfg(feature = "test-support")]
+mod mock_auth;
+#[cfg(feature = "test-support")]
+mod mock_zap;
+mod negative_cache;
+mod plain_auth;
+#[allow(dead_code)]
+mod policy;
+mod reconnect;
+mod secret;
+#[allow(dead_code)]
+mod timeouts;
 mod zap_handler;
+mod zap_stats;
 
-pub use cert::CertType;
-pub use zap_handler::ZapHandler;
+pub use address_policy::{AddressPolicy, AddressRules};
+pub use affinity::{AffinityPolicy, ServerAdvert};
+pub use attestation::{merkle_root, public_key_from_hex, Attestation, ATTESTATION_TOPIC};
+pub use auth_policy::{AuthPolicy, Decision, ZapRequestInfo};
+pub use brute_force::BanPolicy;
+pub use cert::{Cert, CertType};
+pub use cert_client::CertClient;
+pub use cert_cache::{direct_topic, CacheLimits, Change, Heartbeat, Quarantine, QuarantinedCert, DIRECT_TOPIC_PREFIX, DIRECT_TOPIC_SUFFIX, ORIGIN_PREFIX, SCOPE_SEPARATOR, TIMESTAMP_PREFIX};
+pub use domain_policy::DomainPolicy;
+pub use error::{Error, ErrorCode};
+pub use filter::Filter;
+pub use frame_policy::{FrameEnforcement, FramePolicy};
+pub use keychain::{load_keychain_cert, store_keychain_cert, KEYCHAIN_SERVICE};
+pub use latency::{LatencyHistogram, BUCKET_BOUNDS_MICROS};
+pub use log_sampling::LogSampler;
+#[cfg(feature = "test-support")]
+pub use mock_auth::MockAuthServer;
+#[cfg(feature = "test-support")]
+pub use mock_zap::{MockRequest, MockZapHandler};
+pub use negative_cache::NegativeCacheStats;
+pub use plain_auth::{HtpasswdVerifier, PamVerifier, PlainVerifier};
+pub use policy::{Hook, PolicyLimits, PolicyScript};
+pub use reconnect::ReconnectPolicy;
+pub use secret::Secret;
+pub use timeouts::SocketTimeouts;
+pub use zap_handler::{DenyReason, WorkerStatus, ZapHandler, ZapHandlerBuilder, ZapStatus};
+pub use zap_stats::{LatencyPercentiles, MechanismStats, ZapStats};
@@ -0,0 +1,67 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! zstd compression for cert metadata on the feed.
+//!
+//! Requires the `zstd` feature. Without it, `is_supported()` is false
+//! and everything else fails.
+
+use error::{Error, Result};
+#[cfg(feature = "zstd")]
+use zstd;
+
+// Metadata frames are small, so favour speed
+#[cfg(feature = "zstd")]
+const LEVEL: i32 = 3;
+
+pub fn is_supported() -> bool {
+    cfg!(feature = "zstd")
+}
+
+#[cfg(feature = "zstd")]
+pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    zstd::encode_all(data, LEVEL).map_err(|e| Error::Compression(e.to_string()))
+}
+
+#[cfg(feature = "zstd")]
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    zstd::decode_all(data).map_err(|e| Error::Compression(e.to_string()))
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn compress(_: &[u8]) -> Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn decompress(_: &[u8]) -> Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn unsupported() -> Error {
+    Error::Compression("inauth was built without the \"zstd\" feature".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let data: Vec<u8> = (0..10).flat_map(|_| b"name=web1.example.com;type=host".iter().cloned()).collect();
+        if is_supported() {
+            let compressed = compress(&data).unwrap();
+            assert!(compressed.len() < data.len());
+            assert_eq!(decompress(&compressed).unwrap(), data);
+        } else {
+            assert!(compress(&data).is_err());
+            assert!(decompress(&data).is_err());
+        }
+    }
+}
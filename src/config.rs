@@ -6,10 +6,508 @@
 // https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
 // modified, or distributed except according to those terms.
 
+//! Configuration, read from `auth.json` in layers:
+//!
+//! 1. Files listed in its `include` array, in order. Paths are relative
+//!    to the including file, and included files may include others.
+//! 2. `auth.json` itself.
+//! 3. `auth.<site>.json`, if `INAUTH_SITE` is set, for per-site
+//!    overrides.
+//! 4. `INAUTH_CONFIG__<KEY>` environment variables, with `__` between
+//!    nested keys, e.g. `INAUTH_CONFIG__HTTP_GATEWAY__BIND`. Values are
+//!    parsed as JSON, or taken as a string if that fails.
+//!
+//! Each layer is merged over the ones before it: objects key by key,
+//! and anything else (including arrays) replaced outright.
+
+use error::{Error, Result};
+use serde_json::{self, Map, Value};
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::result::Result as StdResult;
+
+pub const CONFIG_FILE: &'static str = "auth.json";
+const ENV_PREFIX: &'static str = "INAUTH_CONFIG__";
+const SITE_VAR: &'static str = "INAUTH_SITE";
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Read the layered config from `dir`. See the module docs.
+pub fn load<P: AsRef<Path>>(dir: P) -> Result<Config> {
+    let site = env::var(SITE_VAR).ok();
+    let value = try!(layered(dir.as_ref(), site.as_ref().map(|s| s.as_str()), env::vars()));
+    Ok(try!(serde_json::from_value(value)))
+}
+
+/// The merged JSON behind `load()`, for an explicit site and set of
+/// environment variables.
+pub fn layered<I>(dir: &Path, site: Option<&str>, vars: I) -> Result<Value>
+    where I: IntoIterator<Item = (String, String)>
+{
+    let mut value = try!(read_layer(&dir.join(CONFIG_FILE), 0));
+
+    if let Some(site) = site {
+        let overlay = try!(read_layer(&dir.join(format!("auth.{}.json", site)), 0));
+        merge(&mut value, overlay);
+    }
+
+    let mut vars: Vec<_> = vars.into_iter().filter(|&(ref name, _)| name.starts_with(ENV_PREFIX)).collect();
+    // Deterministic when one variable overrides part of another
+    vars.sort();
+    for (name, raw) in vars {
+        let path: Vec<String> = name[ENV_PREFIX.len()..].split("__").map(|k| k.to_lowercase()).collect();
+        let setting = serde_json::from_str(&raw).unwrap_or(Value::String(raw));
+        try!(set_path(&mut value, &path, setting).or(Err(Error::InvalidConfig(format!("cannot apply {}", name)))));
+    }
+
+    Ok(value)
+}
+
+/// Render `config` as JSON with bearer tokens removed.
+pub fn dump(config: &Config) -> Result<String> {
+    let mut value = try!(serde_json::to_value(config));
+    for key in &["http_gateway", "grpc", "websocket"] {
+        if let Some(tokens) = value.get_mut(*key).and_then(|v| v.get_mut("tokens")) {
+            let identities: Vec<Value> = match tokens.as_object() {
+                Some(map) => map.values().cloned().collect(),
+                None => Vec::new(),
+            };
+            *tokens = Value::Array(identities);
+        }
+    }
+    Ok(try!(serde_json::to_string_pretty(&value)))
+}
+
+// Read `path`, with its includes merged beneath it
+fn read_layer(path: &Path, depth: usize) -> Result<Value> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(Error::InvalidConfig(format!("includes nested too deeply at {}", path.display())));
+    }
+
+    let mut json = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut json));
+    let mut value: Value = try!(serde_json::from_str(&json));
+    let includes = match value.as_object_mut() {
+        Some(map) => map.remove("include"),
+        None => return Err(Error::InvalidConfig(format!("{} is not a JSON object", path.display()))),
+    };
+
+    let mut merged = Value::Object(Map::new());
+    if let Some(includes) = includes {
+        let includes = try!(includes.as_array().cloned().ok_or(Error::InvalidConfig("include must be a list of paths".into())));
+        let dir = path.parent().unwrap_or(Path::new("."));
+        for include in includes {
+            let include = try!(include.as_str().ok_or(Error::InvalidConfig("include must be a list of paths".into())));
+            merge(&mut merged, try!(read_layer(&dir.join(include), depth + 1)));
+        }
+    }
+    merge(&mut merged, value);
+
+    Ok(merged)
+}
+
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (&mut Value::Object(ref mut base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                if let Some(existing) = base.get_mut(&key) {
+                    merge(existing, value);
+                    continue;
+                }
+                base.insert(key, value);
+            }
+        },
+        (base, overlay) => *base = overlay,
+    }
+}
+
+// Set the value at a path of keys, creating objects along the way.
+// Fails if the path runs through something other than an object.
+fn set_path(value: &mut Value, path: &[String], setting: Value) -> StdResult<(), ()> {
+    let map = try!(value.as_object_mut().ok_or(()));
+    match path.split_first() {
+        Some((key, rest)) if rest.is_empty() => {
+            map.insert(key.clone(), setting);
+            Ok(())
+        },
+        Some((key, rest)) => set_path(map.entry(key.clone()).or_insert(Value::Object(Map::new())), rest, setting),
+        None => Err(()),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub server_cert: String,
+    /// Where to read the passphrase for an encrypted `server_cert`:
+    /// `prompt`, `env:<VAR>`, `file:<path>` or `exec:<command>`.
+    pub server_cert_passphrase: Option<String>,
+    /// Keep the server's secret key on a PKCS#11 token instead of in
+    /// `server_cert`, of which only the `_public` half is then read.
+    /// Requires the `hsm` feature.
+    pub hsm: Option<HsmConfig>,
     pub cert_path: String,
+    /// Mode and owner for files written to `cert_path`. Unset parts
+    /// are left to the umask and the writing user.
+    pub cert_files: Option<CertFilesConfig>,
     pub api_port: u32,
     pub update_port: u32,
+    /// Where clients subscribe to this server's feed, exactly as they
+    /// connect to it, e.g. "tcp://auth1.example.com:7102". Clients of
+    /// several servers use it to tell which one is draining, and to
+    /// pick one by affinity.
+    pub feed_endpoint: Option<String>,
+    /// Seconds between heartbeats on the update feed, so subscribers
+    /// can tell a quiet feed from a dead one [default: 5]
+    pub heartbeat_interval_secs: Option<u64>,
+    /// Certs per message in snapshots for subscribers that ask for
+    /// them in batches [default: 500]
+    pub snapshot_batch_size: Option<usize>,
+    /// Cert changes remembered for subscribers resuming from a feed
+    /// sequence, which are sent just the certs changed since. Those
+    /// resuming from before the oldest get a snapshot [default: 100000]
+    pub last_value_capacity: Option<usize>,
+    /// Labels such as a region or zone, advertised in feed heartbeats
+    /// and `server::info` so clients can prefer nearby replicas. Tags
+    /// must not contain commas. Needs `feed_endpoint`.
+    pub affinity_tags: Option<Vec<String>>,
+    /// Redaction rules for `cert::list`, keyed by caller cert type,
+    /// then by the cert type being listed. Unlisted pairs get full
+    /// detail, as do callers with the "admin" role.
+    pub list_masking: Option<HashMap<String, HashMap<String, ListMask>>>,
+    /// Optional HTTP JSON gateway to the cert API.
+    pub http_gateway: Option<HttpGatewayConfig>,
+    /// Optional gRPC service for the cert API. Requires the `grpc`
+    /// feature.
+    pub grpc: Option<GrpcConfig>,
+    /// Optional WebSocket mirror of the cert feed as JSON.
+    pub websocket: Option<WebSocketConfig>,
+    /// Local admin socket, e.g. "ipc:///var/run/inauth/admin.sock".
+    /// Only ipc:// endpoints are accepted.
+    pub admin_socket: Option<String>,
+    /// Per-client request limits, keyed by API endpoint (e.g.
+    /// "cert::create"), then by caller cert type ("host" or "user").
+    /// Either key may be "*" to match anything not listed.
+    pub rate_limits: Option<HashMap<String, HashMap<String, RateLimit>>>,
+    /// Limits on certs each user may create through `cert::create`,
+    /// keyed by the type of cert created ("host" or "user"), or "*"
+    /// for any type not listed. Users over quota get a
+    /// "quota_exceeded" error.
+    pub cert_quotas: Option<HashMap<String, CertQuota>>,
+    /// Temporarily ban clients that repeatedly fail ZAP
+    /// authentication. Defaults apply if omitted.
+    pub zap_ban: Option<ZapBanConfig>,
+    /// Site-specific text, e.g. legal notices. Reloaded on SIGHUP.
+    pub messages: Option<Messages>,
+    /// Source address rules for ZAP authentication, keyed by cert type
+    /// ("host" or "user"), or "*" for every client. Reloaded on SIGHUP.
+    pub zap_addresses: Option<HashMap<String, AddressRulesConfig>>,
+    /// Rhai authorization script for ZAP and API requests. Requires
+    /// the `policy` feature.
+    pub policy: Option<PolicyConfig>,
+    /// Periodically publish a signed digest of the cert set on the
+    /// feed's "attest" topic.
+    pub attestation: Option<AttestationConfig>,
+    /// Settings for `cert::request`, which queues certs for admin
+    /// approval.
+    pub cert_requests: Option<CertRequestsConfig>,
+    /// Republish other Auth servers' cert changes into this server's
+    /// feed, e.g. to run one server per datacenter.
+    pub federation: Option<FederationConfig>,
+    /// Bound blocking sends and receives on the API, admin and feed
+    /// sockets. A request whose frames don't all arrive in time gets
+    /// a "timeout" error.
+    pub socket_timeouts: Option<SocketTimeoutsConfig>,
+    /// Warn about certs nearing expiry in the audit log and through
+    /// webhooks. `cert::expiring` uses `warn_days` even if this is
+    /// left out.
+    pub expiry_notices: Option<ExpiryNoticesConfig>,
+    /// Replace key pairs on a schedule. See `rotation`.
+    pub rotation: Option<RotationConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HsmConfig {
+    /// The token's PKCS#11 library, e.g.
+    /// "/usr/lib/x86_64-linux-gnu/pkcs11/yubihsm_pkcs11.so"
+    pub module: String,
+    pub slot: u64,
+    /// Where to read the user PIN: `prompt`, `env:<VAR>`,
+    /// `file:<path>` or `exec:<command>`. For a YubiHSM 2, the PIN is
+    /// the auth key ID followed by its password, e.g. "0001password".
+    pub pin: String,
+    /// Label of the data object holding the key
+    /// [default: "inauth-server-key"]
+    pub label: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RotationConfig {
+    /// Keyed by cert type ("host" or "user"). Types not listed are
+    /// never rotated on a schedule. Certs issued before rotation
+    /// policies existed count as issued at the first check.
+    pub policies: HashMap<String, RotationPolicy>,
+    /// Seconds between checks for due rotations [default: 3600]
+    pub check_interval_secs: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RotationPolicy {
+    /// Replace key pairs this many days after they were issued
+    pub every_days: u64,
+    /// Days both the old and new keys authenticate, for the cert's
+    /// owner to fetch the new one from `cert::pending_rotation`
+    pub grace_days: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ExpiryNoticesConfig {
+    /// Warn this many days before a cert expires [default: 14]
+    pub warn_days: Option<u64>,
+    /// Seconds between scans for expiring certs [default: 3600]
+    pub scan_interval_secs: Option<u64>,
+    /// http:// URLs to POST each batch of warnings to, as JSON
+    pub webhooks: Option<Vec<String>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SocketTimeoutsConfig {
+    /// Unset leaves sends blocking
+    pub send_ms: Option<u64>,
+    /// Unset leaves receives blocking
+    pub recv_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CertFilesConfig {
+    /// Octal, e.g. "0640"
+    pub mode: Option<String>,
+    /// A user, or "user:group", by name or ID, e.g. "intecture".
+    /// Changing owner usually needs root.
+    pub owner: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FederationConfig {
+    /// Names this server in origin tags. Must be unique among the
+    /// federated servers.
+    pub origin: String,
+    pub bridges: Vec<BridgeConfig>,
+    /// Only accept changes from bridged servers whose server cert
+    /// matches. Unset accepts every bridge.
+    pub allowed_publishers: Option<PublisherAllowlistConfig>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PublisherAllowlistConfig {
+    /// Server cert names, e.g. "auth.dc2.example.com"
+    pub names: Option<Vec<String>>,
+    /// Server cert types, e.g. "host"
+    pub cert_types: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BridgeConfig {
+    /// The remote server's `federation.origin`
+    pub origin: String,
+    /// The remote server's update feed, e.g.
+    /// "tcp://auth.dc2.example.com:7102"
+    pub endpoint: String,
+    /// The remote server's public cert. The remote server must know
+    /// this server's cert as a host cert.
+    pub server_cert: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CertRequestsConfig {
+    /// Run for each new request, e.g. to email the admins. The request
+    /// is passed in `INAUTH_REQUEST_ID`, `INAUTH_REQUEST_NAME`,
+    /// `INAUTH_REQUEST_TYPE` and `INAUTH_REQUEST_BY`.
+    pub notify_command: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AttestationConfig {
+    /// Ed25519 signing key, created on first start if missing
+    pub key_path: String,
+    /// Seconds between attestations [default: 60]
+    pub interval_secs: Option<u64>,
+    /// Also sign every timestamped feed message with the key, so
+    /// clients that verify the feed reject forged and replayed
+    /// changes [default: false]
+    pub sign_feed: Option<bool>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PolicyConfig {
+    /// Path to the script, which is reloaded when it changes
+    pub script: String,
+    /// Time budget per hook call [default: 50]
+    pub timeout_ms: Option<u64>,
+    /// Operation budget per hook call [default: 100000]
+    pub max_operations: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AddressRulesConfig {
+    /// CIDR ranges or single addresses, e.g. "10.8.0.0/16"
+    pub allow: Option<Vec<String>>,
+    /// Takes precedence over `allow`
+    pub deny: Option<Vec<String>>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Messages {
+    /// Status text sent to clients that fail authentication. At most
+    /// 255 bytes, per the ZAP spec.
+    pub zap_denied: Option<String>,
+    /// Replies by why the client was denied: "unknown_key", "expired",
+    /// "revoked", "rate_limited" or "forbidden"
+    pub zap_replies: Option<HashMap<String, ZapReplyConfig>>,
+    /// Printed to stderr whenever the CLI loads this config
+    pub cli_banner: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ZapReplyConfig {
+    /// 300 (try again later), 400 or 500 [default: 400]
+    pub status: Option<u16>,
+    /// Defaults to `zap_denied`
+    pub text: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ZapBanConfig {
+    /// Failures within `window_secs` that trigger a ban
+    pub threshold: u32,
+    pub window_secs: u64,
+    /// Length of the first ban. Each repeat offence doubles it, up to
+    /// `max_ban_secs`.
+    pub ban_secs: u64,
+    pub max_ban_secs: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Sustained requests per second
+    pub rate: f64,
+    /// Requests allowed in a burst
+    pub burst: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CertQuota {
+    /// Certs each user may create per window
+    pub max: usize,
+    pub window_secs: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HttpGatewayConfig {
+    /// Address to listen on, e.g. "127.0.0.1:7103"
+    pub bind: String,
+    /// Bearer tokens and the user identity each one authenticates as
+    pub tokens: HashMap<String, GatewayIdentity>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebSocketConfig {
+    /// Address to listen on, e.g. "127.0.0.1:7104"
+    pub bind: String,
+    /// Bearer tokens and the user identity each one authenticates as
+    pub tokens: HashMap<String, GatewayIdentity>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GrpcConfig {
+    pub port: u16,
+    /// Bearer tokens and the user identity each one authenticates as
+    pub tokens: HashMap<String, GatewayIdentity>,
+}
+
+/// Who a bearer token authenticates as, in place of the meta a CURVE
+/// cert would carry. Requests are authorized as if this cert made
+/// them.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GatewayIdentity {
+    pub name: String,
+    /// "user" or "host" [default: user]
+    #[serde(rename = "type")]
+    pub cert_type: Option<String>,
+    pub domain: Option<String>,
+    pub role: Option<String>,
+}
+
+impl GatewayIdentity {
+    #[allow(dead_code)]
+    pub fn cert_type(&self) -> &str {
+        self.cert_type.as_ref().map(|t| t.as_str()).unwrap_or("user")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ListMask {
+    #[serde(rename = "full")]
+    Full,
+    #[serde(rename = "hash")]
+    Hash,
+    #[serde(rename = "omit")]
+    Omit,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use super::*;
+    use tempdir::TempDir;
+
+    fn write(dir: &Path, name: &str, json: &str) {
+        File::create(dir.join(name)).unwrap().write_all(json.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_layered() {
+        let tmpdir = TempDir::new("config_test_layered").unwrap();
+        let dir = tmpdir.path();
+        write(dir, "base.json", "{\"server_cert\": \"/base\", \"cert_path\": \"/certs\", \"api_port\": 7101, \"update_port\": 7102, \"http_gateway\": {\"bind\": \"127.0.0.1:7103\", \"tokens\": {}}}");
+        write(dir, "auth.json", "{\"include\": [\"base.json\"], \"server_cert\": \"/site\"}");
+        write(dir, "auth.syd.json", "{\"api_port\": 8101}");
+
+        let value = layered(dir, None, vec![]).unwrap();
+        assert_eq!(value["server_cert"], Value::String("/site".into()));
+        assert_eq!(value["api_port"], Value::from(7101));
+        assert!(value.get("include").is_none());
+
+        let vars = vec![
+            ("INAUTH_CONFIG__UPDATE_PORT".to_string(), "9102".to_string()),
+            ("INAUTH_CONFIG__HTTP_GATEWAY__BIND".to_string(), "0.0.0.0:7103".to_string()),
+            ("UNRELATED".to_string(), "1".to_string()),
+        ];
+        let config: Config = serde_json::from_value(layered(dir, Some("syd"), vars).unwrap()).unwrap();
+        assert_eq!(config.server_cert, "/site");
+        assert_eq!(config.api_port, 8101);
+        assert_eq!(config.update_port, 9102);
+        assert_eq!(config.http_gateway.unwrap().bind, "0.0.0.0:7103");
+
+        assert!(layered(dir, Some("missing"), vec![]).is_err());
+        let vars = vec![("INAUTH_CONFIG__API_PORT__NESTED".to_string(), "1".to_string())];
+        assert!(layered(dir, None, vars).is_err());
+
+        write(dir, "auth.json", "{\"include\": [\"auth.json\"]}");
+        assert!(layered(dir, None, vec![]).is_err());
+    }
+
+    #[test]
+    fn test_dump() {
+        let config: Config = serde_json::from_str("{\"server_cert\": \"/path\", \"cert_path\": \"/path\", \
+            \"api_port\": 123, \"update_port\": 123, \"http_gateway\": {\"bind\": \"127.0.0.1:7103\", \
+            \"tokens\": {\"s3cr3t\": {\"name\": \"ci\", \"role\": null}}}}").unwrap();
+        let dump = dump(&config).unwrap();
+
+        assert!(!dump.contains("s3cr3t"));
+        assert!(dump.contains("\"ci\""));
+        assert!(dump.contains("7103"));
+    }
 }
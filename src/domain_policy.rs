@@ -0,0 +1,144 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Per-domain rules for ZAP requests.
+//!
+//! Each ZeroMQ socket can set a ZAP domain (`ZMQ_ZAP_DOMAIN`), which is
+//! passed along with its authentication requests. Routing on it lets
+//! one `ZapHandler` protect several sockets with different rules.
+
+use cert::CertType;
+use czmq::ZCert;
+use std::collections::HashMap;
+
+/// Key for the policy used by domains without their own
+pub const WILDCARD: &'static str = "*";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DomainPolicy {
+    /// Cert types allowed on the domain. Empty allows every type.
+    pub cert_types: Vec<CertType>,
+    /// Values of the cert's "group" metadata allowed on the domain.
+    /// Empty allows every cert, including those without a group.
+    pub groups: Vec<String>,
+    /// Whether the handler's own cert may authenticate regardless of
+    /// `cert_types` and `groups`. Without it, the cert is checked like
+    /// any other.
+    pub allow_self: bool,
+    /// Metadata keys sent to the application in the ZAP reply. `None`
+    /// sends all of the cert's metadata. The cert name is always sent
+    /// as the User-Id.
+    pub disclose: Option<Vec<String>>,
+}
+
+impl DomainPolicy {
+    fn allows(&self, cert_type: CertType, meta: &ZCert, is_self: bool) -> bool {
+        if is_self && self.allow_self {
+            return true;
+        }
+
+        if !self.cert_types.is_empty() && !self.cert_types.contains(&cert_type) {
+            return false;
+        }
+
+        self.groups.is_empty() || match meta.meta("group") {
+            Some(Ok(group)) => self.groups.contains(&group),
+            _ => false,
+        }
+    }
+}
+
+pub struct DomainRouter {
+    policies: HashMap<String, DomainPolicy>,
+    self_key: String,
+}
+
+impl DomainRouter {
+    /// `policies` are keyed by ZAP domain, or "*" for any domain not
+    /// listed. Requests for other domains are denied.
+    pub fn new(policies: HashMap<String, DomainPolicy>, self_key: &str) -> DomainRouter {
+        DomainRouter {
+            policies: policies,
+            self_key: self_key.to_string(),
+        }
+    }
+
+    /// A router that applies the same policy to every domain.
+    pub fn any_domain(policy: DomainPolicy, self_key: &str) -> DomainRouter {
+        let mut policies = HashMap::new();
+        policies.insert(WILDCARD.to_string(), policy);
+        Self::new(policies, self_key)
+    }
+
+    /// Whether any domain accepts the handler's own cert, i.e. whether
+    /// it needs to be in the cache.
+    pub fn allows_self(&self) -> bool {
+        self.policies.values().any(|p| p.allow_self)
+    }
+
+    pub fn allows(&self, domain: &str, client_id: &str, cert_type: CertType, meta: &ZCert) -> bool {
+        match self.get(domain) {
+            Some(policy) => policy.allows(cert_type, meta, client_id == self.self_key),
+            None => false,
+        }
+    }
+
+    /// The metadata keys to disclose on `domain`, or `None` for all.
+    pub fn disclosed_keys(&self, domain: &str) -> Option<&[String]> {
+        self.get(domain).and_then(|p| p.disclose.as_ref()).map(|d| &d[..])
+    }
+
+    fn get(&self, domain: &str) -> Option<&DomainPolicy> {
+        self.policies.get(domain).or_else(|| self.policies.get(WILDCARD))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::CertType;
+    use czmq::ZCert;
+    use std::collections::HashMap;
+    use super::*;
+
+    #[test]
+    fn test_router() {
+        let mut policies = HashMap::new();
+        policies.insert("admin".to_string(), DomainPolicy {
+            cert_types: vec![CertType::User],
+            groups: vec!["ops".into()],
+            disclose: Some(vec!["type".into()]),
+            ..DomainPolicy::default()
+        });
+        policies.insert("*".to_string(), DomainPolicy {
+            allow_self: true,
+            ..DomainPolicy::default()
+        });
+        let router = DomainRouter::new(policies, "self_pk");
+        assert!(router.allows_self());
+        assert_eq!(router.disclosed_keys("admin"), Some(&["type".to_string()][..]));
+        assert_eq!(router.disclosed_keys("metrics"), None);
+
+        let cert = ZCert::new().unwrap();
+        cert.set_meta("group", "ops");
+        assert!(router.allows("admin", "pk", CertType::User, &cert));
+        assert!(!router.allows("admin", "pk", CertType::Host, &cert));
+        assert!(router.allows("admin", "self_pk", CertType::User, &cert));
+        assert!(!router.allows("admin", "self_pk", CertType::Host, &cert));
+        assert!(router.allows("metrics", "self_pk", CertType::Host, &cert));
+
+        let cert = ZCert::new().unwrap();
+        cert.set_meta("group", "dev");
+        assert!(!router.allows("admin", "pk", CertType::User, &cert));
+        assert!(!router.allows("admin", "pk", CertType::User, &ZCert::new().unwrap()));
+        assert!(router.allows("", "pk", CertType::User, &cert));
+
+        let router = DomainRouter::new(HashMap::new(), "self_pk");
+        assert!(!router.allows_self());
+        assert!(!router.allows("", "pk", CertType::User, &cert));
+    }
+}
@@ -17,46 +17,219 @@ pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
+    Api(ErrorCode, String),
+    ApiTimeout,
     CertNameCollision,
+    Compression(String),
     Czmq(czmq::Error),
+    DuplicateKey(String),
+    ExportTooNew(u32, u32),
+    FeedMonitor,
     Forbidden,
+    Gateway(String),
+    Hsm(String),
+    InvalidAddressRule(String),
     InvalidArg,
     InvalidArgsCount,
+    InvalidAttestation(String),
     InvalidCert,
+    InvalidCertCache,
     InvalidCertFeed,
     InvalidCertMeta,
     InvalidCertPath,
+    InvalidConfig(String),
     InvalidEndpoint,
+    InvalidFeedSignature(String),
+    InvalidFilter(String),
+    InvalidPassphrase,
+    InvalidPasswordFile,
+    InvalidRequestId,
+    InvalidShare(String),
+    InvalidStatusText(String),
+    InvalidWireTrace,
+    InvalidZapFrame(&'static str),
+    InvalidZapReply(String),
     InvalidZapRequest,
     Io(io::Error),
+    Keychain(String),
     LogInit(log::SetLoggerError),
     MissingConf,
+    MissingPassphrase,
+    NoPendingRotation,
+    Panic(String),
+    Policy(String),
     PollerTimeout,
+    QrTooLong(usize),
+    QuotaExceeded(String),
+    RateLimited,
     SerdeJson(serde_json::Error),
+    SnapshotTimeout,
+    Sodium,
+    StorageMismatch,
+    StorageSwitchStep,
+    StorageTooNew(u32, String, u32),
+    Timeout(String),
+    UnknownCertRequest,
+    WeakKey(String),
     ZapVersion,
     ZDaemon(zdaemon::Error),
     ZmqEncode(String),
 }
 
+/// Stable codes for errors in API replies, so clients can tell errors
+/// apart without matching on the message. The text form is sent on the
+/// wire; the numbers are fixed too, for clients that would rather
+/// switch on an integer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// A code this binary doesn't know, or a reply from a server that
+    /// predates error codes
+    Unknown = 0,
+    Internal = 1,
+    BadRequest = 2,
+    Forbidden = 3,
+    RateLimited = 4,
+    NotFound = 5,
+    InvalidCert = 6,
+    NameCollision = 7,
+    DuplicateKey = 8,
+    WeakKey = 9,
+    Policy = 10,
+    Timeout = 11,
+    QuotaExceeded = 12,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ErrorCode::Unknown => "unknown",
+            ErrorCode::Internal => "internal",
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::InvalidCert => "invalid_cert",
+            ErrorCode::NameCollision => "name_collision",
+            ErrorCode::DuplicateKey => "duplicate_key",
+            ErrorCode::WeakKey => "weak_key",
+            ErrorCode::Policy => "policy",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::QuotaExceeded => "quota_exceeded",
+        }
+    }
+}
+
+impl<'a> convert::From<&'a str> for ErrorCode {
+    fn from(code: &'a str) -> ErrorCode {
+        match code {
+            "internal" => ErrorCode::Internal,
+            "bad_request" => ErrorCode::BadRequest,
+            "forbidden" => ErrorCode::Forbidden,
+            "rate_limited" => ErrorCode::RateLimited,
+            "not_found" => ErrorCode::NotFound,
+            "invalid_cert" => ErrorCode::InvalidCert,
+            "name_collision" => ErrorCode::NameCollision,
+            "duplicate_key" => ErrorCode::DuplicateKey,
+            "weak_key" => ErrorCode::WeakKey,
+            "policy" => ErrorCode::Policy,
+            "timeout" => ErrorCode::Timeout,
+            "quota_exceeded" => ErrorCode::QuotaExceeded,
+            _ => ErrorCode::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Error {
+    /// The code sent with this error in an API reply.
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            Error::Api(code, _) => code,
+            Error::CertNameCollision => ErrorCode::NameCollision,
+            Error::DuplicateKey(_) => ErrorCode::DuplicateKey,
+            Error::Forbidden => ErrorCode::Forbidden,
+            Error::InvalidAddressRule(_) |
+            Error::InvalidArg |
+            Error::InvalidArgsCount |
+            Error::InvalidCertMeta |
+            Error::InvalidEndpoint |
+            Error::InvalidFilter(_) |
+            Error::InvalidRequestId => ErrorCode::BadRequest,
+            Error::InvalidCert => ErrorCode::InvalidCert,
+            Error::NoPendingRotation => ErrorCode::NotFound,
+            Error::Policy(_) => ErrorCode::Policy,
+            Error::QuotaExceeded(_) => ErrorCode::QuotaExceeded,
+            Error::RateLimited => ErrorCode::RateLimited,
+            Error::Timeout(_) => ErrorCode::Timeout,
+            Error::UnknownCertRequest => ErrorCode::NotFound,
+            Error::WeakKey(_) => ErrorCode::WeakKey,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            Error::Api(_, ref e) => write!(f, "API request failed: {}", e),
+            Error::ApiTimeout => write!(f, "Timed out waiting for the API to reply"),
             Error::CertNameCollision => write!(f, "Certificate name already exists"),
+            Error::Compression(ref e) => write!(f, "Compression error: {}", e),
             Error::Czmq(ref e) => write!(f, "CZMQ error: {}", e),
+            Error::DuplicateKey(ref name) => write!(f, "Public key is already in use by {}", name),
+            Error::ExportTooNew(found, supported) => write!(f, "Export format {} is newer than this binary supports ({}). Upgrade inauth to import it", found, supported),
+            Error::FeedMonitor => write!(f, "Could not monitor the certificate feed"),
             Error::Forbidden => write!(f, "Access to this endpoint is forbidden"),
+            Error::Gateway(ref e) => write!(f, "Gateway request failed: {}", e),
+            Error::Hsm(ref e) => write!(f, "HSM error: {}", e),
+            Error::InvalidAddressRule(ref e) => write!(f, "Invalid address rule: {}", e),
             Error::InvalidArg => write!(f, "Invalid argument provided"),
             Error::InvalidArgsCount => write!(f, "Invalid number of args provided"),
+            Error::InvalidAttestation(ref e) => write!(f, "Invalid attestation: {}", e),
             Error::InvalidCert => write!(f, "Invalid certificate"),
+            Error::InvalidCertCache => write!(f, "Invalid certificate cache file"),
             Error::InvalidCertFeed => write!(f, "Invalid message from certificate feed"),
             Error::InvalidCertMeta => write!(f, "Invalid certificate metadata"),
             Error::InvalidCertPath => write!(f, "Invalid certificate path"),
+            Error::InvalidConfig(ref e) => write!(f, "Invalid config: {}", e),
             Error::InvalidEndpoint => write!(f, "Invalid endpoint"),
+            Error::InvalidFeedSignature(ref e) => write!(f, "Invalid feed signature: {}", e),
+            Error::InvalidFilter(ref e) => write!(f, "Invalid filter expression: {}", e),
+            Error::InvalidPassphrase => write!(f, "Incorrect passphrase for encrypted certificate"),
+            Error::InvalidPasswordFile => write!(f, "Invalid password file"),
+            Error::InvalidRequestId => write!(f, "Invalid request ID"),
+            Error::InvalidShare(ref e) => write!(f, "Invalid key share: {}", e),
+            Error::InvalidStatusText(ref e) => write!(f, "Invalid ZAP status text: {}", e),
+            Error::InvalidWireTrace => write!(f, "Invalid or truncated wire trace"),
+            Error::InvalidZapReply(ref e) => write!(f, "Invalid ZAP reply: {}", e),
+            Error::InvalidZapFrame(name) => write!(f, "ZAP request has a missing or invalid {} frame", name),
             Error::InvalidZapRequest => write!(f, "Invalid ZAP request"),
             Error::Io(ref e) => write!(f, "IO error: {}", e),
+            Error::Keychain(ref e) => write!(f, "Keychain error: {}", e),
             Error::LogInit(ref e) => write!(f, "Log init error: {}", e),
             Error::MissingConf => write!(f, "Cannot open Auth config"),
+            Error::MissingPassphrase => write!(f, "A passphrase is required to unlock the server certificate"),
+            Error::NoPendingRotation => write!(f, "No scheduled key rotation is pending for this certificate"),
+            Error::Panic(ref e) => write!(f, "Internal error: {}", e),
+            Error::Policy(ref e) => write!(f, "Policy script error: {}", e),
             Error::PollerTimeout => write!(f, "Timeout while polling sockets"),
+            Error::QrTooLong(len) => write!(f, "{} bytes is too much to fit in a QR code", len),
+            Error::QuotaExceeded(ref quota) => write!(f, "Certificate quota exceeded: {}", quota),
+            Error::RateLimited => write!(f, "Too many requests; try again later"),
             Error::SerdeJson(ref e) => write!(f, "Serde JSON error: {}", e),
+            Error::SnapshotTimeout => write!(f, "Timed out waiting for the certificate snapshot"),
+            Error::Sodium => write!(f, "Libsodium operation failed"),
+            Error::StorageMismatch => write!(f, "Storage backends hold different data"),
+            Error::StorageSwitchStep => write!(f, "Storage switch step out of order"),
+            Error::StorageTooNew(found, ref by, supported) => write!(f, "Storage format {} (written by inauth {}) is newer than this binary supports ({}). Upgrade inauth, or run with --force to start anyway", found, by, supported),
+            Error::Timeout(ref doing) => write!(f, "Timed out {}", doing),
+            Error::UnknownCertRequest => write!(f, "No pending certificate request has this ID"),
+            Error::WeakKey(ref why) => write!(f, "Public key is unsafe to use: {}", why),
             Error::ZapVersion => write!(f, "ZAP version is invalid"),
             Error::ZDaemon(ref e) => write!(f, "ZDaemon error: {}", e),
             Error::ZmqEncode(ref e) => write!(f, "Could not encode Z85 string: {}", e),
@@ -67,22 +240,60 @@ impl fmt::Display for Error {
 impl error::Error for Error {
     fn description(&self) -> &str {
         match *self {
+            Error::Api(..) => "API request failed",
+            Error::ApiTimeout => "Timed out waiting for the API to reply",
             Error::CertNameCollision => "Certificate name already exists",
+            Error::Compression(_) => "Compression error",
             Error::Czmq(ref e) => e.description(),
+            Error::DuplicateKey(_) => "Public key is already in use",
+            Error::ExportTooNew(..) => "Export format is newer than this binary supports",
+            Error::FeedMonitor => "Could not monitor the certificate feed",
             Error::Forbidden => "Access to this endpoint is forbidden",
+            Error::Gateway(_) => "Gateway request failed",
+            Error::Hsm(_) => "HSM operation failed",
+            Error::InvalidAddressRule(_) => "Invalid address rule",
             Error::InvalidArg => "Invalid argument provided",
             Error::InvalidArgsCount => "Invalid number of args provided",
+            Error::InvalidAttestation(_) => "Invalid attestation",
             Error::InvalidCert => "Invalid certificate",
+            Error::InvalidCertCache => "Invalid certificate cache file",
             Error::InvalidCertFeed => "Invalid message from certificate feed",
             Error::InvalidCertMeta => "Invalid certificate metadata",
             Error::InvalidCertPath => "Invalid certificate path",
+            Error::InvalidConfig(_) => "Invalid config",
             Error::InvalidEndpoint => "Invalid endpoint",
+            Error::InvalidFeedSignature(_) => "Invalid feed signature",
+            Error::InvalidFilter(_) => "Invalid filter expression",
+            Error::InvalidPassphrase => "Incorrect passphrase for encrypted certificate",
+            Error::InvalidPasswordFile => "Invalid password file",
+            Error::InvalidRequestId => "Invalid request ID",
+            Error::InvalidShare(_) => "Invalid key share",
+            Error::InvalidStatusText(_) => "Invalid ZAP status text",
+            Error::InvalidWireTrace => "Invalid or truncated wire trace",
+            Error::InvalidZapReply(_) => "Invalid ZAP reply",
+            Error::InvalidZapFrame(_) => "ZAP request has a missing or invalid frame",
             Error::InvalidZapRequest => "Invalid ZAP request",
             Error::Io(ref e) => e.description(),
+            Error::Keychain(_) => "Keychain operation failed",
             Error::LogInit(ref e) => e.description(),
             Error::MissingConf => "Cannot open config",
+            Error::MissingPassphrase => "A passphrase is required to unlock the server certificate",
+            Error::NoPendingRotation => "No scheduled key rotation is pending",
+            Error::Panic(_) => "Internal error",
+            Error::Policy(_) => "Policy script error",
             Error::PollerTimeout => "Timeout while polling sockets",
+            Error::QrTooLong(_) => "Too much data to fit in a QR code",
+            Error::QuotaExceeded(_) => "Certificate quota exceeded",
+            Error::RateLimited => "Too many requests; try again later",
             Error::SerdeJson(ref e) => e.description(),
+            Error::SnapshotTimeout => "Timed out waiting for the certificate snapshot",
+            Error::Sodium => "Libsodium operation failed",
+            Error::StorageMismatch => "Storage backends hold different data",
+            Error::StorageSwitchStep => "Storage switch step out of order",
+            Error::StorageTooNew(..) => "Storage format is newer than this binary supports",
+            Error::Timeout(_) => "Timed out",
+            Error::UnknownCertRequest => "Unknown certificate request",
+            Error::WeakKey(_) => "Public key is unsafe to use",
             Error::ZapVersion => "ZAP version is invalid",
             Error::ZDaemon(ref e) => e.description(),
             Error::ZmqEncode(_) => "Could not encode Z85 string",
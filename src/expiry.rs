@@ -0,0 +1,271 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Warnings about certs nearing expiry, so operators can rotate keys
+//! before clients start failing ZAP checks.
+//!
+//! An `ExpiryNotifier` scans the cert cache, which mirrors storage,
+//! every interval. Each cert expiring within the warning window is
+//! logged to the "audit" target and POSTed to any webhooks, once per
+//! expiry time. `cert::expiring` lists the same certs on demand.
+
+use cert::CertType;
+use cert_cache::{CertCache, SharedCertCache};
+use czmq::ZSock;
+use error::{Error, Result};
+use serde_json;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::result::Result as StdResult;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zap_proxy::ticker;
+use zdaemon::{Endpoint, Error as DError};
+
+/// Warn about certs expiring within this many seconds, unless
+/// configured otherwise
+pub const DEFAULT_WARN_SECS: u64 = 14 * 24 * 60 * 60;
+
+const WEBHOOK_TIMEOUT: u64 = 10;
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ExpiringCert {
+    pub name: String,
+    pub cert_type: &'static str,
+    pub public_key: String,
+    /// Seconds since the Unix epoch
+    pub expires_at: u64,
+}
+
+#[derive(Serialize)]
+struct WebhookBody<'a> {
+    event: &'static str,
+    certs: &'a [ExpiringCert],
+}
+
+/// Certs that expire within `within` seconds of `now`, or have
+/// already, soonest first. Revoked and disabled certs are left out, as
+/// they no longer authenticate anyway.
+pub fn expiring(cache: &CertCache, within: u64, now: u64) -> Vec<ExpiringCert> {
+    let mut certs = Vec::new();
+    for cert_type in &[CertType::Host, CertType::User] {
+        for cert in cache.dump(*cert_type) {
+            if cert.is_revoked() || cert.is_disabled() {
+                continue;
+            }
+            match cert.expires_at() {
+                Some(expires) if expires <= now + within => certs.push(ExpiringCert {
+                    name: cert.name().to_string(),
+                    cert_type: cert_type.to_str(),
+                    public_key: cert.public_txt().to_string(),
+                    expires_at: expires,
+                }),
+                _ => (),
+            }
+        }
+    }
+    certs.sort_by_key(|c| c.expires_at);
+    certs
+}
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Check that `url` is one `ExpiryNotifier` can POST to. Only plain
+/// http:// URLs are supported.
+pub fn check_webhook(url: &str) -> Result<()> {
+    if url.starts_with("http://") && url.len() > "http://".len() {
+        Ok(())
+    } else {
+        Err(Error::InvalidConfig(format!("webhook {} must be an http:// URL", url)))
+    }
+}
+
+pub struct ExpiryNotifier {
+    ticker: ZSock,
+    cache: SharedCertCache,
+    warn_within: u64,
+    webhooks: Vec<String>,
+    // The expiry each cert was last warned about, by public key
+    notified: HashMap<String, u64>,
+}
+
+impl ExpiryNotifier {
+    /// Scan every `interval` for certs expiring within `warn_within`
+    /// seconds. See `check_webhook()` for the `webhooks` accepted.
+    pub fn new(interval: Duration, warn_within: u64, webhooks: Vec<String>, cache: SharedCertCache) -> Result<ExpiryNotifier> {
+        Ok(ExpiryNotifier {
+            ticker: try!(ticker(interval)),
+            cache: cache,
+            warn_within: warn_within,
+            webhooks: webhooks,
+            notified: HashMap::new(),
+        })
+    }
+
+    // Certs that are newly expiring, or whose expiry has changed
+    // since we last warned about them
+    fn scan(&mut self, now: u64) -> Vec<ExpiringCert> {
+        let certs = expiring(&self.cache.read(), self.warn_within, now);
+
+        // Forget certs that were renewed or removed, so they are
+        // warned about again if they come back
+        self.notified.retain(|k, _| certs.iter().any(|c| c.public_key == *k));
+
+        let mut fresh = Vec::new();
+        for cert in certs {
+            if self.notified.get(&cert.public_key) == Some(&cert.expires_at) {
+                continue;
+            }
+            self.notified.insert(cert.public_key.clone(), cert.expires_at);
+
+            if cert.expires_at <= now {
+                warn!(target: "audit", "{} cert {} has expired", cert.cert_type, cert.name);
+            } else {
+                warn!(target: "audit", "{} cert {} expires in {}h", cert.cert_type, cert.name, (cert.expires_at - now) / 3600);
+            }
+            fresh.push(cert);
+        }
+        fresh
+    }
+
+    // Webhooks are called in the background so a slow receiver
+    // doesn't hold up the service. Failures are only logged.
+    fn notify(&self, certs: Vec<ExpiringCert>) -> Result<()> {
+        if certs.is_empty() || self.webhooks.is_empty() {
+            return Ok(());
+        }
+
+        let body = try!(serde_json::to_string(&WebhookBody { event: "certs_expiring", certs: &certs }));
+        let webhooks = self.webhooks.clone();
+        thread::spawn(move || {
+            for url in webhooks {
+                if let Err(e) = post_json(&url, &body) {
+                    warn!("Could not call expiry webhook {}: {}", url, e);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Endpoint for ExpiryNotifier {
+    fn get_sockets(&mut self) -> Vec<&mut ZSock> {
+        vec![&mut self.ticker]
+    }
+
+    fn recv(&mut self, sock: &mut ZSock) -> StdResult<(), DError> {
+        let _ = try!(sock.recv_str());
+        let certs = self.scan(now());
+        try!(self.notify(certs));
+        Ok(())
+    }
+}
+
+// POST `body` as JSON and expect a 2xx status
+fn post_json(url: &str, body: &str) -> Result<()> {
+    try!(check_webhook(url));
+    let rest = &url["http://".len()..];
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    // Mind IPv6 literals, e.g. "[::1]"
+    let addr = if authority.rfind(':') > authority.rfind(']') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+
+    let mut stream = try!(TcpStream::connect(&*addr));
+    try!(stream.set_read_timeout(Some(Duration::from_secs(WEBHOOK_TIMEOUT))));
+    try!(stream.set_write_timeout(Some(Duration::from_secs(WEBHOOK_TIMEOUT))));
+    try!(write!(stream, "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", path, authority, body.len(), body));
+
+    let mut status = String::new();
+    try!(BufReader::new(stream).read_line(&mut status));
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(Error::Gateway(format!("webhook replied \"{}\"", status.trim()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType, EXPIRES_META, REVOKED_META};
+    use cert_cache::{CertCache, SharedCertCache};
+    use czmq::ZSys;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+
+    fn expiring_cert(name: &str, cert_type: CertType, expires: u64) -> Cert {
+        let cert = Cert::new(name, cert_type).unwrap();
+        cert.set_meta(EXPIRES_META, &expires.to_string());
+        cert
+    }
+
+    #[test]
+    fn test_expiring() {
+        ZSys::init();
+
+        let revoked = expiring_cert("old.example.com", CertType::Host, 1000);
+        revoked.set_meta(REVOKED_META, "true");
+        let cache = CertCache::new(Some(vec![
+            expiring_cert("web2.example.com", CertType::Host, 1500),
+            expiring_cert("web1.example.com", CertType::Host, 900),
+            expiring_cert("john.smith", CertType::User, 5000),
+            Cert::new("forever.example.com", CertType::Host).unwrap(),
+            revoked,
+        ]));
+
+        let certs = expiring(&cache, 1000, 1000);
+        let names: Vec<&str> = certs.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["web1.example.com", "web2.example.com"]);
+        assert_eq!(certs[1].cert_type, "host");
+        assert_eq!(certs[1].expires_at, 1500);
+    }
+
+    #[test]
+    fn test_scan() {
+        ZSys::init();
+
+        let cache = SharedCertCache::new(CertCache::new(Some(vec![expiring_cert("web1.example.com", CertType::Host, 1500)])));
+        let mut notifier = ExpiryNotifier::new(Duration::from_secs(3600), 1000, Vec::new(), cache.clone()).unwrap();
+
+        assert!(notifier.scan(100).is_empty());
+        assert_eq!(notifier.scan(1000).len(), 1);
+        // Only warned about once
+        assert!(notifier.scan(1100).is_empty());
+    }
+
+    #[test]
+    fn test_post_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/expiry", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            reader.get_mut().write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+            request_line
+        });
+
+        post_json(&url, "{}").unwrap();
+        assert_eq!(server.join().unwrap(), "POST /hooks/expiry HTTP/1.1\r\n");
+
+        assert!(check_webhook("https://example.com/hook").is_err());
+        assert!(check_webhook("http://").is_err());
+    }
+}
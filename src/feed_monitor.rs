@@ -0,0 +1,98 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Connection events for a feed subscriber.
+//!
+//! czmq's `ZMonitor` runs as an actor that can't be polled alongside
+//! other sockets, so this attaches a libzmq socket monitor directly and
+//! hands back the socket that receives its events.
+
+use czmq::{RawInterface, ZMsg, ZSock};
+use error::{Error, Result};
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+const ZMQ_EVENT_CONNECTED: u16 = 0x0001;
+const ZMQ_EVENT_DISCONNECTED: u16 = 0x0200;
+
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+extern "C" {
+    fn zsock_resolve(sock: *mut c_void) -> *mut c_void;
+    fn zmq_socket_monitor(sock: *mut c_void, addr: *const c_char, events: c_int) -> c_int;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FeedEvent {
+    /// Connected, or reconnected, to the endpoint
+    Connected(String),
+    Disconnected(String),
+}
+
+/// Monitor `sock` for connects and disconnects. Call this before
+/// connecting `sock`, or the first connection won't be reported.
+pub fn monitor(sock: &mut ZSock) -> Result<ZSock> {
+    let endpoint = format!("inproc://feed_monitor_{}", NEXT_ID.fetch_add(1, Ordering::SeqCst));
+    let addr = try!(CString::new(endpoint.clone()).or(Err(Error::InvalidEndpoint)));
+    let events = (ZMQ_EVENT_CONNECTED | ZMQ_EVENT_DISCONNECTED) as c_int;
+
+    if unsafe { zmq_socket_monitor(zsock_resolve(sock.as_mut_ptr()), addr.as_ptr(), events) } == -1 {
+        return Err(Error::FeedMonitor);
+    }
+
+    let monitor = try!(ZSock::new_pair(&format!(">{}", endpoint)));
+    monitor.set_linger(0);
+    Ok(monitor)
+}
+
+/// Read an event from a socket returned by `monitor()`.
+pub fn recv_event(monitor: &mut ZSock) -> Result<Option<FeedEvent>> {
+    let msg = try!(ZMsg::recv(monitor));
+
+    // The first frame is the event ID (u16) and its value (u32) in
+    // native byte order, the second the peer's endpoint.
+    let header = try!(try!(msg.popbytes()).ok_or(Error::FeedMonitor));
+    if header.len() != 6 {
+        return Err(Error::FeedMonitor);
+    }
+    let endpoint = match msg.popstr() {
+        Some(Ok(s)) => s,
+        _ => return Err(Error::FeedMonitor),
+    };
+
+    match u16::from_ne_bytes([header[0], header[1]]) {
+        ZMQ_EVENT_CONNECTED => Ok(Some(FeedEvent::Connected(endpoint))),
+        ZMQ_EVENT_DISCONNECTED => Ok(Some(FeedEvent::Disconnected(endpoint))),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use czmq::{ZSock, SocketType, ZSys};
+    use super::*;
+
+    #[test]
+    fn test_monitor() {
+        ZSys::init();
+
+        let server = ZSock::new(SocketType::PUB);
+        let port = server.bind("tcp://127.0.0.1:*[60000-]").unwrap();
+
+        let mut client = ZSock::new(SocketType::SUB);
+        let mut monitor = monitor(&mut client).unwrap();
+        monitor.set_rcvtimeo(Some(1000));
+        let endpoint = format!("tcp://127.0.0.1:{}", port);
+        client.connect(&endpoint).unwrap();
+
+        assert_eq!(recv_event(&mut monitor).unwrap(), Some(FeedEvent::Connected(endpoint.clone())));
+        drop(server);
+        assert_eq!(recv_event(&mut monitor).unwrap(), Some(FeedEvent::Disconnected(endpoint)));
+    }
+}
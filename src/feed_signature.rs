@@ -0,0 +1,229 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Signed feed messages, so that subscribers can tell a change the
+//! Auth server just published from a replay of an old one, e.g. an ADD
+//! for a key that has since been rotated.
+//!
+//! The publisher signs each timestamped message with its attestation
+//! key, over every frame up to and including the timestamp. A
+//! subscriber that knows the key drops messages that are unsigned,
+//! badly signed, older than its `max_age`, or that it has already
+//! seen.
+
+use attestation::{from_hex, to_hex};
+use cert_cache::TIMESTAMP_PREFIX;
+use czmq::ZMsg;
+use error::{Error, Result};
+use sodiumoxide::crypto::sign::{self, PublicKey, SecretKey, Signature};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Timestamped feed messages may end with a frame of this prefix
+/// followed by a hex Ed25519 signature of the frames before it.
+pub const SIGNATURE_PREFIX: &'static str = "sig=";
+
+const SIGNING_CONTEXT: &'static [u8] = b"inauth-feed-v1\0";
+
+/// Whether a feed message with this topic and action is timestamped,
+/// and so signed. Only attestations go without, as they carry their
+/// own signature.
+pub fn is_stamped(_topic: &str, action: &str) -> bool {
+    action != "ATTEST"
+}
+
+/// Sign a stamped feed message. Messages without a timestamp are left
+/// alone.
+pub fn sign(msg: &ZMsg, key: &SecretKey) -> Result<()> {
+    let frames = try!(frames(msg));
+    match frames.last() {
+        Some(f) if f.starts_with(TIMESTAMP_PREFIX.as_bytes()) => (),
+        _ => return Ok(()),
+    }
+    let sign::Signature(signature) = sign::sign_detached(&signed_bytes(&frames), key);
+    try!(msg.addstr(&format!("{}{}", SIGNATURE_PREFIX, to_hex(&signature))));
+    Ok(())
+}
+
+/// Checks signed feed messages against the publisher's key, and
+/// remembers the signatures it has accepted for long enough to reject
+/// replays of them.
+pub struct FeedVerifier {
+    key: PublicKey,
+    max_age: u64,
+    // Accepted signatures, by publish time
+    seen: HashMap<Vec<u8>, u64>,
+}
+
+impl FeedVerifier {
+    pub fn new(key: PublicKey, max_age: Duration) -> FeedVerifier {
+        FeedVerifier {
+            key: key,
+            max_age: max_age.as_secs() * 1_000_000 + (max_age.subsec_nanos() / 1_000) as u64,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Check `msg`, which arrived at `now` (see `latency::now_micros()`).
+    /// Attestations pass, as they carry their own signature.
+    pub fn check(&mut self, msg: &ZMsg, now: u64) -> Result<()> {
+        let mut frames = try!(frames(msg));
+        let stamped = match (frames.get(0), frames.get(1)) {
+            (Some(topic), Some(action)) => is_stamped(&String::from_utf8_lossy(topic), &String::from_utf8_lossy(action)),
+            _ => true,
+        };
+        if !stamped {
+            return Ok(());
+        }
+
+        let signature = match frames.pop() {
+            Some(ref f) if f.starts_with(SIGNATURE_PREFIX.as_bytes()) => {
+                let hex = String::from_utf8_lossy(&f[SIGNATURE_PREFIX.len()..]).into_owned();
+                try!(from_hex(&hex).and_then(|s| Signature::from_slice(&s)).ok_or(Error::InvalidFeedSignature("malformed signature".into())))
+            },
+            _ => return Err(Error::InvalidFeedSignature("unsigned message".into())),
+        };
+        if !sign::verify_detached(&signature, &signed_bytes(&frames), &self.key) {
+            return Err(Error::InvalidFeedSignature("bad signature".into()));
+        }
+
+        let published: u64 = match frames.last() {
+            Some(f) if f.starts_with(TIMESTAMP_PREFIX.as_bytes()) => {
+                try!(String::from_utf8_lossy(&f[TIMESTAMP_PREFIX.len()..]).parse().or(Err(Error::InvalidFeedSignature("malformed timestamp".into()))))
+            },
+            _ => return Err(Error::InvalidFeedSignature("missing timestamp".into())),
+        };
+        // Allow for as much clock skew forwards as backwards
+        if published + self.max_age < now || published > now + self.max_age {
+            return Err(Error::InvalidFeedSignature(format!("published {}s from now", (published as i64 - now as i64) / 1_000_000)));
+        }
+
+        let max_age = self.max_age;
+        self.seen.retain(|_, p| *p + max_age >= now);
+        let sign::Signature(signature) = signature;
+        if self.seen.insert(signature.to_vec(), published).is_some() {
+            return Err(Error::InvalidFeedSignature("replayed message".into()));
+        }
+        Ok(())
+    }
+}
+
+// Every frame, without moving the message's cursor for the caller
+fn frames(msg: &ZMsg) -> Result<Vec<Vec<u8>>> {
+    let mut frames = Vec::new();
+    let mut frame = msg.first();
+    while let Some(f) = frame {
+        frames.push(match try!(f.data()) {
+            Ok(s) => s.into_bytes(),
+            Err(b) => b,
+        });
+        frame = msg.next();
+    }
+    Ok(frames)
+}
+
+// Frames are length prefixed, so they can't be run together
+fn signed_bytes(frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut bytes = SIGNING_CONTEXT.to_vec();
+    for frame in frames {
+        let len = frame.len() as u64;
+        for i in (0..8).rev() {
+            bytes.push((len >> (i * 8)) as u8);
+        }
+        bytes.extend_from_slice(frame);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use cert_cache;
+    use czmq::ZMsg;
+    use latency;
+    use sodiumoxide::crypto::sign;
+    use std::time::Duration;
+    use super::*;
+
+    fn change() -> ZMsg {
+        let msg = ZMsg::new();
+        msg.addstr("host").unwrap();
+        msg.addstr("DEL").unwrap();
+        msg.addstr("0000000000000000000000000000000000000000").unwrap();
+        cert_cache::stamp(&msg).unwrap();
+        msg
+    }
+
+    #[test]
+    fn test_verify() {
+        let (pk, sk) = sign::gen_keypair();
+        let (other_pk, _) = sign::gen_keypair();
+        let now = latency::now_micros();
+
+        let msg = change();
+        sign(&msg, &sk).unwrap();
+        let mut verifier = FeedVerifier::new(pk, Duration::from_secs(60));
+        assert!(verifier.check(&msg, now).is_ok());
+        // Replayed
+        assert!(verifier.check(&msg, now).is_err());
+        // Too old by the time it arrives
+        let mut verifier = FeedVerifier::new(pk, Duration::from_secs(60));
+        assert!(verifier.check(&msg, now + 61_000_000).is_err());
+        // Someone else's
+        assert!(FeedVerifier::new(other_pk, Duration::from_secs(60)).check(&msg, now).is_err());
+        // Unsigned
+        assert!(FeedVerifier::new(pk, Duration::from_secs(60)).check(&change(), now).is_err());
+
+        // Tampered with
+        let frames: Vec<String> = {
+            let mut frames = vec![msg.first().unwrap().data().unwrap().unwrap()];
+            while let Some(f) = msg.next() {
+                frames.push(f.data().unwrap().unwrap());
+            }
+            frames
+        };
+        let tampered = ZMsg::new();
+        tampered.addstr("host").unwrap();
+        tampered.addstr("DEL").unwrap();
+        tampered.addstr("1111111111111111111111111111111111111111").unwrap();
+        tampered.addstr(&frames[3]).unwrap();
+        tampered.addstr(&frames[4]).unwrap();
+        assert!(FeedVerifier::new(pk, Duration::from_secs(60)).check(&tampered, now).is_err());
+
+    }
+
+    #[test]
+    fn test_verify_keys_only() {
+        let (pk, sk) = sign::gen_keypair();
+        let (_, other_sk) = sign::gen_keypair();
+        let now = latency::now_micros();
+
+        let keys = || {
+            let msg = ZMsg::new();
+            msg.addstr("keys:host").unwrap();
+            msg.addstr("ADD").unwrap();
+            msg.addstr("0000000000000000000000000000000000000000").unwrap();
+            cert_cache::stamp(&msg).unwrap();
+            msg
+        };
+
+        let msg = keys();
+        sign(&msg, &sk).unwrap();
+        assert_eq!(msg.size(), 5);
+        assert!(FeedVerifier::new(pk, Duration::from_secs(60)).check(&msg, now).is_ok());
+
+        // Forged, unsigned or by someone else
+        let forged = ZMsg::new();
+        forged.addstr("keys:host").unwrap();
+        forged.addstr("ADD").unwrap();
+        forged.addstr("1111111111111111111111111111111111111111").unwrap();
+        assert!(FeedVerifier::new(pk, Duration::from_secs(60)).check(&forged, now).is_err());
+        let forged = keys();
+        sign(&forged, &other_sk).unwrap();
+        assert!(FeedVerifier::new(pk, Duration::from_secs(60)).check(&forged, now).is_err());
+    }
+}
@@ -0,0 +1,355 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Filter expressions for searching certificate metadata, e.g.
+//! `type=host AND env=prod AND created_at>2024-01-01`.
+//!
+//! ```text
+//! expr       := and_expr ("OR" and_expr)*
+//! and_expr   := unary ("AND" unary)*
+//! unary      := "NOT" unary | "(" expr ")" | comparison
+//! comparison := key op value
+//! op         := "=" | "!=" | ">" | ">=" | "<" | "<=" | "~"
+//! ```
+//!
+//! `~` matches a glob pattern (`*` and `?`) of up to `MAX_GLOB_LEN`
+//! bytes. Ordering operators
+//! compare numerically when both sides are numbers, otherwise
+//! lexically, which suits ISO 8601 dates. Values may be quoted.
+
+use cert::Cert;
+use error::{Error, Result};
+use std::cmp::Ordering;
+
+/// Longest glob pattern a filter may contain, in bytes
+pub const MAX_GLOB_LEN: usize = 256;
+
+#[derive(Debug)]
+pub struct Filter {
+    expr: Expr,
+}
+
+#[derive(Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Cmp(String, Op, String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Glob,
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Op(Op),
+    LParen,
+    RParen,
+}
+
+impl Filter {
+    pub fn parse(input: &str) -> Result<Filter> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser { tokens: tokens, pos: 0 };
+        let expr = parser.expr()?;
+
+        if parser.pos < parser.tokens.len() {
+            return Err(Error::InvalidFilter(format!("Unexpected token {:?}", parser.tokens[parser.pos])));
+        }
+
+        Ok(Filter {
+            expr: expr,
+        })
+    }
+
+    /// Evaluate the filter using `lookup` to resolve keys. Comparisons
+    /// against missing keys are false.
+    pub fn matches<F>(&self, lookup: &F) -> bool
+        where F: Fn(&str) -> Option<String>
+    {
+        eval(&self.expr, lookup)
+    }
+
+    /// Evaluate against a certificate's name, type and metadata.
+    pub fn matches_cert(&self, cert: &Cert) -> bool {
+        self.matches(&|key: &str| {
+            match key {
+                "name" => Some(cert.name().to_string()),
+                "type" => Some(cert.cert_type().to_str().to_string()),
+                _ => match cert.meta(key) {
+                    Some(Ok(v)) => Some(v),
+                    _ => None,
+                },
+            }
+        })
+    }
+}
+
+fn eval<F>(expr: &Expr, lookup: &F) -> bool
+    where F: Fn(&str) -> Option<String>
+{
+    match *expr {
+        Expr::And(ref a, ref b) => eval(a, lookup) && eval(b, lookup),
+        Expr::Or(ref a, ref b) => eval(a, lookup) || eval(b, lookup),
+        Expr::Not(ref e) => !eval(e, lookup),
+        Expr::Cmp(ref key, op, ref value) => {
+            let actual = match lookup(key) {
+                Some(a) => a,
+                None => return false,
+            };
+
+            match op {
+                Op::Eq => actual == *value,
+                Op::Ne => actual != *value,
+                Op::Glob => glob_match(value.as_bytes(), actual.as_bytes()),
+                _ => {
+                    let ord = compare(&actual, value);
+                    match op {
+                        Op::Gt => ord == Ordering::Greater,
+                        Op::Ge => ord != Ordering::Less,
+                        Op::Lt => ord == Ordering::Less,
+                        Op::Le => ord != Ordering::Greater,
+                        _ => unreachable!(),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn compare(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(x), Ok(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+        _ => a.cmp(b),
+    }
+}
+
+// On a mismatch, retry from the last `*` with it swallowing one more
+// byte of text. Earlier `*`s never need revisiting, so the worst case
+// is proportional to the pattern's length times the text's.
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(&b'*') => {
+                star = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match star {
+                Some((sp, st)) => {
+                    star = Some((sp, st + 1));
+                    p = sp + 1;
+                    t = st + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ' ' | '\t' | '\n' => i += 1,
+            '(' => { tokens.push(Token::LParen); i += 1; },
+            ')' => { tokens.push(Token::RParen); i += 1; },
+            '=' => { tokens.push(Token::Op(Op::Eq)); i += 1; },
+            '~' => { tokens.push(Token::Op(Op::Glob)); i += 1; },
+            '!' | '>' | '<' => {
+                let eq = i + 1 < chars.len() && chars[i + 1] == '=';
+                let op = match (c, eq) {
+                    ('!', true) => Op::Ne,
+                    ('>', false) => Op::Gt,
+                    ('>', true) => Op::Ge,
+                    ('<', false) => Op::Lt,
+                    ('<', true) => Op::Le,
+                    _ => return Err(Error::InvalidFilter("Expected '=' after '!'".into())),
+                };
+                tokens.push(Token::Op(op));
+                i += if eq { 2 } else { 1 };
+            },
+            '"' | '\'' => {
+                let start = i + 1;
+                let end = match chars[start..].iter().position(|ch| *ch == c) {
+                    Some(p) => start + p,
+                    None => return Err(Error::InvalidFilter("Unterminated quote".into())),
+                };
+                tokens.push(Token::Word(chars[start..end].iter().cloned().collect()));
+                i = end + 1;
+            },
+            _ => {
+                let start = i;
+                while i < chars.len() && !" \t\n()=~!<>\"'".contains(chars[i]) {
+                    i += 1;
+                }
+                tokens.push(Token::Word(chars[start..i].iter().cloned().collect()));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(&Token::Word(ref w)) => w.eq_ignore_ascii_case(keyword),
+            _ => false,
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        let mut left = self.and_expr()?;
+        while self.peek_keyword("OR") {
+            self.pos += 1;
+            let right = self.and_expr()?;
+            left = Expr::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn and_expr(&mut self) -> Result<Expr> {
+        let mut left = self.unary()?;
+        while self.peek_keyword("AND") {
+            self.pos += 1;
+            let right = self.unary()?;
+            left = Expr::And(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.peek_keyword("NOT") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+
+        if self.tokens.get(self.pos) == Some(&Token::LParen) {
+            self.pos += 1;
+            let expr = self.expr()?;
+            if self.tokens.get(self.pos) != Some(&Token::RParen) {
+                return Err(Error::InvalidFilter("Expected ')'".into()));
+            }
+            self.pos += 1;
+            return Ok(expr);
+        }
+
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr> {
+        let key = match self.tokens.get(self.pos) {
+            Some(&Token::Word(ref w)) => w.clone(),
+            t => return Err(Error::InvalidFilter(format!("Expected key, found {:?}", t))),
+        };
+        let op = match self.tokens.get(self.pos + 1) {
+            Some(&Token::Op(op)) => op,
+            t => return Err(Error::InvalidFilter(format!("Expected operator after '{}', found {:?}", key, t))),
+        };
+        let value = match self.tokens.get(self.pos + 2) {
+            Some(&Token::Word(ref w)) => w.clone(),
+            t => return Err(Error::InvalidFilter(format!("Expected value after '{}', found {:?}", key, t))),
+        };
+        if op == Op::Glob && value.len() > MAX_GLOB_LEN {
+            return Err(Error::InvalidFilter(format!("Glob pattern longer than {} bytes", MAX_GLOB_LEN)));
+        }
+        self.pos += 3;
+
+        Ok(Expr::Cmp(key, op, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType};
+    use std::collections::HashMap;
+    use super::*;
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Filter::parse("type=").is_err());
+        assert!(Filter::parse("type host").is_err());
+        assert!(Filter::parse("(type=host").is_err());
+        assert!(Filter::parse("type=host AND").is_err());
+        assert!(Filter::parse("name=\"unterminated").is_err());
+        assert!(Filter::parse("a ! b").is_err());
+        assert!(Filter::parse(&format!("name~{}", "*".repeat(MAX_GLOB_LEN + 1))).is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let mut map = HashMap::new();
+        map.insert("type", "host");
+        map.insert("env", "prod");
+        map.insert("created_at", "2024-03-01");
+        map.insert("cpus", "16");
+        let f = |k: &str| map.get(k).map(|v| v.to_string());
+
+        assert!(Filter::parse("type=host AND env=prod AND created_at>2024-01-01").unwrap().matches(&f));
+        assert!(!Filter::parse("type=host AND env=dev").unwrap().matches(&f));
+        assert!(Filter::parse("env=dev OR env=prod").unwrap().matches(&f));
+        assert!(Filter::parse("NOT (env=dev OR env=staging)").unwrap().matches(&f));
+        assert!(Filter::parse("cpus >= 9").unwrap().matches(&f));
+        assert!(!Filter::parse("cpus < 9").unwrap().matches(&f));
+        assert!(Filter::parse("env != 'dev'").unwrap().matches(&f));
+        assert!(!Filter::parse("missing=x").unwrap().matches(&f));
+        assert!(Filter::parse("env~p*d").unwrap().matches(&f));
+    }
+
+    #[test]
+    fn test_matches_cert() {
+        let cert = Cert::new("web1.example.com", CertType::Host).unwrap();
+        cert.set_meta("env", "prod");
+
+        assert!(Filter::parse("name~web?.example.com AND type=host").unwrap().matches_cert(&cert));
+        assert!(!Filter::parse("type=user").unwrap().matches_cert(&cert));
+        assert!(Filter::parse("env=prod").unwrap().matches_cert(&cert));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"web*.com", b"web12.example.com"));
+        assert!(!glob_match(b"web?", b"web12"));
+        assert!(glob_match(b"", b""));
+        assert!(!glob_match(b"", b"a"));
+        assert!(glob_match(b"a*b*c", b"aXbYbZc"));
+        assert!(!glob_match(b"a*b*c", b"aXbYbZ"));
+        assert!(glob_match(b"*?", b"a"));
+        assert!(!glob_match(b"*?", b""));
+
+        // Would take exponential time with backtracking at every '*'
+        let text = "a".repeat(10000);
+        assert!(!glob_match(format!("{}b", "*a".repeat(50)).as_bytes(), text.as_bytes()));
+    }
+}
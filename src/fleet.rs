@@ -0,0 +1,114 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Cache health reported by client handlers across the fleet, so
+//! operators can spot agents serving stale caches. Clients opt in with
+//! `ZapHandlerBuilder::report_cache_health()`.
+
+use serde_json;
+use std::collections::HashMap;
+use std::time::Instant;
+
+// Agents whose cache lags the feed for longer than this are stale
+const STALE_AFTER_SECS: u64 = 60;
+
+/// What a client reports about its cache.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CacheReport {
+    /// The last feed sequence it received a heartbeat for
+    pub sequence: Option<u64>,
+    pub cache_size: usize,
+    /// Seconds since the feed last added or removed certs
+    pub last_update_age_secs: Option<u64>,
+}
+
+impl CacheReport {
+    pub fn from_json(json: &str) -> serde_json::Result<CacheReport> {
+        serde_json::from_str(json)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AgentHealth {
+    /// The agent's cert name
+    pub name: String,
+    pub report: CacheReport,
+    /// Seconds since the report arrived
+    pub report_age_secs: u64,
+    /// How many feed messages the agent is behind, if it said
+    pub sequence_lag: Option<u64>,
+    /// Whether the agent has been behind for too long, or has stopped
+    /// reporting
+    pub stale: bool,
+}
+
+/// The latest report from each agent.
+#[derive(Debug, Default)]
+pub struct FleetHealth {
+    reports: HashMap<String, (CacheReport, Instant)>,
+}
+
+impl FleetHealth {
+    pub fn new() -> FleetHealth {
+        FleetHealth::default()
+    }
+
+    pub fn record(&mut self, name: &str, report: CacheReport, now: Instant) {
+        self.reports.insert(name.to_string(), (report, now));
+    }
+
+    /// Every agent's health as of `now`, when the feed is at
+    /// `sequence`, stale agents first.
+    pub fn summary(&self, sequence: u64, now: Instant) -> Vec<AgentHealth> {
+        let mut agents: Vec<AgentHealth> = self.reports.iter().map(|(name, &(ref report, received))| {
+            let report_age = if now > received { now.duration_since(received).as_secs() } else { 0 };
+            let lag = report.sequence.map(|s| sequence.saturating_sub(s));
+            let update_age = report.last_update_age_secs.unwrap_or(0) + report_age;
+            AgentHealth {
+                name: name.clone(),
+                report: report.clone(),
+                report_age_secs: report_age,
+                sequence_lag: lag,
+                stale: report_age > STALE_AFTER_SECS || (lag.unwrap_or(0) > 0 && update_age > STALE_AFTER_SECS),
+            }
+        }).collect();
+
+        agents.sort_by(|a, b| b.stale.cmp(&a.stale).then_with(|| a.name.cmp(&b.name)));
+        agents
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let start = Instant::now();
+        let now = start + Duration::from_secs(600);
+        let mut fleet = FleetHealth::new();
+
+        let report = CacheReport::from_json(r#"{"sequence": 10, "cache_size": 3, "last_update_age_secs": 5}"#).unwrap();
+        fleet.record("web1", report.clone(), now);
+        fleet.record("web2", CacheReport { sequence: Some(8), last_update_age_secs: Some(120), ..report.clone() }, now);
+        fleet.record("web3", report, start);
+
+        let summary = fleet.summary(10, now);
+        let names: Vec<&str> = summary.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, vec!["web2", "web3", "web1"]);
+        assert_eq!(summary[0].sequence_lag, Some(2));
+        assert!(summary[0].stale);
+        assert_eq!(summary[1].report_age_secs, 600);
+        assert!(summary[1].stale);
+        assert_eq!(summary[2].sequence_lag, Some(0));
+        assert!(!summary[2].stale);
+
+        assert!(CacheReport::from_json("{}").is_err());
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Rules for the identity and mechanism frames of ZAP requests.
+//!
+//! The identity frame carries the client socket's routing ID
+//! (`ZMQ_ROUTING_ID`), which the client chooses. Requiring it to match
+//! the authenticated cert's name stops one client from posing as
+//! another to applications that route on it.
+
+use std::collections::HashMap;
+
+/// Key for the mechanisms allowed on domains without their own
+pub const WILDCARD: &'static str = "*";
+
+/// What to do with a request that breaks a `FramePolicy` rule. Both
+/// log a warning to the "audit" target.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameEnforcement {
+    /// Log the mismatch and carry on authenticating, e.g. while
+    /// finding out which clients would be denied
+    Log,
+    /// Deny the request as forbidden
+    Deny,
+}
+
+impl Default for FrameEnforcement {
+    fn default() -> FrameEnforcement {
+        FrameEnforcement::Deny
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FramePolicy {
+    /// Whether the identity frame must equal the authenticated cert's
+    /// name (or PLAIN username). Clients that don't set a routing ID
+    /// send an empty identity, which never matches.
+    pub require_identity_match: bool,
+    /// Mechanisms ("NULL", "PLAIN" or "CURVE") allowed, keyed by ZAP
+    /// domain, or "*" for any domain not listed. Domains matching
+    /// neither allow every mechanism.
+    pub mechanisms: HashMap<String, Vec<String>>,
+    pub enforcement: FrameEnforcement,
+}
+
+impl FramePolicy {
+    /// Why `mechanism` may not be used on `domain`, if it may not.
+    pub fn check_mechanism(&self, domain: &str, mechanism: &str) -> Option<String> {
+        match self.mechanisms.get(domain).or_else(|| self.mechanisms.get(WILDCARD)) {
+            Some(allowed) if !allowed.iter().any(|m| m == mechanism) => Some(format!("used mechanism {} on domain \"{}\"", mechanism, domain)),
+            _ => None,
+        }
+    }
+
+    /// Why `identity` doesn't match the client's `name`, if it must
+    /// and doesn't.
+    pub fn check_identity(&self, identity: &str, name: &str) -> Option<String> {
+        if self.require_identity_match && identity != name {
+            Some(format!("sent identity \"{}\" for {}", identity, name))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    #[test]
+    fn test_frame_policy() {
+        let policy = FramePolicy::default();
+        assert!(policy.check_mechanism("admin", "NULL").is_none());
+        assert!(policy.check_identity("", "jimbob").is_none());
+        assert_eq!(policy.enforcement, FrameEnforcement::Deny);
+
+        let mut mechanisms = HashMap::new();
+        mechanisms.insert("admin".to_string(), vec!["CURVE".to_string()]);
+        mechanisms.insert("*".to_string(), vec!["CURVE".to_string(), "PLAIN".to_string()]);
+        let policy = FramePolicy {
+            require_identity_match: true,
+            mechanisms: mechanisms,
+            enforcement: FrameEnforcement::Log,
+        };
+        assert!(policy.check_mechanism("admin", "CURVE").is_none());
+        assert!(policy.check_mechanism("admin", "PLAIN").is_some());
+        assert!(policy.check_mechanism("web", "PLAIN").is_none());
+        assert!(policy.check_mechanism("web", "NULL").is_some());
+        assert!(policy.check_identity("jimbob", "jimbob").is_none());
+        assert!(policy.check_identity("", "jimbob").is_some());
+        assert!(policy.check_identity("mallory", "jimbob").is_some());
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! gRPC service for the cert API, defined in `proto/cert.proto`.
+//!
+//! Like the HTTP gateway, calls are authenticated by bearer token and
+//! forwarded to the service thread over the gateway's inproc endpoint,
+//! so both share the same authorisation and trace handling.
+
+// Generated by build.rs
+#[allow(dead_code, non_camel_case_types, non_snake_case, non_upper_case_globals, unused_imports)]
+mod cert {
+    include!(concat!(env!("OUT_DIR"), "/cert.rs"));
+}
+#[allow(dead_code, non_camel_case_types, non_snake_case, non_upper_case_globals, unused_imports)]
+mod cert_grpc {
+    include!(concat!(env!("OUT_DIR"), "/cert_grpc.rs"));
+}
+
+use config::{GatewayIdentity, GrpcConfig};
+use czmq::ZSock;
+use error::{Error, ErrorCode, Result};
+use grpc;
+use http_gateway::{self, GATEWAY_ENDPOINT};
+use protobuf::RepeatedField;
+use self::cert::*;
+use self::cert_grpc::{CertService, CertServiceServer};
+use std::collections::HashMap;
+
+struct CertServiceImpl {
+    tokens: HashMap<String, GatewayIdentity>,
+}
+
+pub fn start(config: GrpcConfig) -> Result<grpc::Server> {
+    let mut server = grpc::ServerBuilder::new_plain();
+    server.http.set_port(config.port);
+    server.add_service(CertServiceServer::new_service_def(CertServiceImpl {
+        tokens: config.tokens,
+    }));
+    info!("gRPC service listening on port {}", config.port);
+    server.build().map_err(|e| Error::Gateway(format!("Could not start gRPC server: {}", e)))
+}
+
+impl CertServiceImpl {
+    fn call(&self, o: &grpc::RequestOptions, endpoint: &str, args: &[&str]) -> ::std::result::Result<Vec<String>, grpc::Error> {
+        let header = o.metadata.get("authorization").and_then(|h| ::std::str::from_utf8(h).ok());
+        let identity = match http_gateway::bearer_identity(header, &self.tokens) {
+            Some(i) => i,
+            None => return Err(status(grpc::GrpcStatus::Unauthenticated, "Missing or invalid bearer token")),
+        };
+
+        // ZSocks can't be shared between the server's worker threads,
+        // and inproc connections are cheap.
+        let mut backend = ZSock::new_dealer(&format!(">{}", GATEWAY_ENDPOINT))
+            .map_err(|e| status(grpc::GrpcStatus::Internal, &format!("{}", e)))?;
+        backend.set_rcvtimeo(Some(5000));
+
+        http_gateway::call(&mut backend, identity, endpoint, args).map_err(|e| {
+            let grpc_status = match e.code() {
+                ErrorCode::Forbidden | ErrorCode::Policy => grpc::GrpcStatus::PermissionDenied,
+                ErrorCode::NotFound => grpc::GrpcStatus::NotFound,
+                ErrorCode::NameCollision | ErrorCode::DuplicateKey => grpc::GrpcStatus::AlreadyExists,
+                ErrorCode::RateLimited | ErrorCode::QuotaExceeded => grpc::GrpcStatus::ResourceExhausted,
+                ErrorCode::Timeout => grpc::GrpcStatus::DeadlineExceeded,
+                ErrorCode::Internal | ErrorCode::Unknown => grpc::GrpcStatus::Internal,
+                ErrorCode::BadRequest | ErrorCode::InvalidCert | ErrorCode::WeakKey => grpc::GrpcStatus::InvalidArgument,
+            };
+            status(grpc_status, &format!("{}", e))
+        })
+    }
+}
+
+impl CertService for CertServiceImpl {
+    fn create(&self, o: grpc::RequestOptions, p: CreateRequest) -> grpc::SingleResponse<NewCert> {
+        match self.call(&o, "cert::create", &[cert_type(p.get_field_type()), p.get_name()]) {
+            Ok(r) => {
+                let mut r = r.into_iter();
+                let mut reply = NewCert::new();
+                reply.set_name(p.get_name().to_string());
+                reply.set_public_key(r.next().unwrap_or(String::new()));
+                reply.set_secret_key(r.next().unwrap_or(String::new()));
+                grpc::SingleResponse::completed(reply)
+            },
+            Err(e) => grpc::SingleResponse::err(e),
+        }
+    }
+
+    fn delete(&self, o: grpc::RequestOptions, p: NameRequest) -> grpc::SingleResponse<Empty> {
+        match self.call(&o, "cert::delete", &[p.get_name()]) {
+            Ok(_) => grpc::SingleResponse::completed(Empty::new()),
+            Err(e) => grpc::SingleResponse::err(e),
+        }
+    }
+
+    fn list(&self, o: grpc::RequestOptions, p: ListRequest) -> grpc::SingleResponse<CertList> {
+        match self.call(&o, "cert::list", &[cert_type(p.get_field_type())]) {
+            Ok(r) => grpc::SingleResponse::completed(cert_list(r)),
+            Err(e) => grpc::SingleResponse::err(e),
+        }
+    }
+
+    fn lookup(&self, o: grpc::RequestOptions, p: NameRequest) -> grpc::SingleResponse<CertKey> {
+        match self.call(&o, "cert::lookup", &[p.get_name()]) {
+            Ok(r) => {
+                let mut reply = CertKey::new();
+                reply.set_name(p.get_name().to_string());
+                reply.set_public_key(r.into_iter().next().unwrap_or(String::new()));
+                grpc::SingleResponse::completed(reply)
+            },
+            Err(e) => grpc::SingleResponse::err(e),
+        }
+    }
+
+    fn search(&self, o: grpc::RequestOptions, p: SearchRequest) -> grpc::SingleResponse<CertList> {
+        match self.call(&o, "cert::search", &[p.get_filter()]) {
+            Ok(r) => grpc::SingleResponse::completed(cert_list(r)),
+            Err(e) => grpc::SingleResponse::err(e),
+        }
+    }
+
+    fn rotate(&self, o: grpc::RequestOptions, p: NameRequest) -> grpc::SingleResponse<NewCert> {
+        match self.call(&o, "cert::rotate", &[p.get_name()]) {
+            Ok(r) => {
+                let mut r = r.into_iter();
+                let mut reply = NewCert::new();
+                reply.set_name(p.get_name().to_string());
+                reply.set_public_key(r.next().unwrap_or(String::new()));
+                reply.set_secret_key(r.next().unwrap_or(String::new()));
+                grpc::SingleResponse::completed(reply)
+            },
+            Err(e) => grpc::SingleResponse::err(e),
+        }
+    }
+}
+
+fn cert_type(t: CertType) -> &'static str {
+    match t {
+        CertType::HOST => "host",
+        CertType::USER => "user",
+    }
+}
+
+fn cert_list(names: Vec<String>) -> CertList {
+    let mut list = CertList::new();
+    list.set_certs(RepeatedField::from_vec(names));
+    list
+}
+
+fn status(code: grpc::GrpcStatus, message: &str) -> grpc::Error {
+    grpc::Error::GrpcMessage(grpc::GrpcMessageError {
+        grpc_status: code as i32,
+        grpc_message: message.to_string(),
+    })
+}
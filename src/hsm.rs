@@ -0,0 +1,199 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Keep the server's secret key on a PKCS#11 token, such as a YubiHSM 2
+//! (through yubihsm_pkcs11.so) or a smartcard, instead of on disk.
+//!
+//! The key is a private data object holding the raw 32-byte CURVE
+//! secret key, written by `inauth_cli server hsm-import`. At startup
+//! the server logs in to the token with its PIN, reads the key and
+//! checks it against `<server_cert>_public`.
+//!
+//! libzmq runs the CURVE handshake itself and needs the secret key in
+//! memory, so the token guards the key at rest and gates its use on the
+//! PIN, but can't perform the handshake for us.
+
+use config::HsmConfig;
+use czmq::ZCert;
+use error::{Error, Result};
+#[cfg(feature = "hsm")]
+use pkcs11::Ctx;
+#[cfg(feature = "hsm")]
+use pkcs11::types::{CK_ATTRIBUTE, CK_OBJECT_HANDLE, CK_SESSION_HANDLE, CK_TRUE, CKA_CLASS, CKA_LABEL,
+                    CKA_PRIVATE, CKA_TOKEN, CKA_VALUE, CKF_RW_SESSION, CKF_SERIAL_SESSION, CKO_DATA, CKU_USER};
+#[cfg(feature = "hsm")]
+use server_key;
+#[cfg(feature = "hsm")]
+use sodiumoxide::crypto::scalarmult::curve25519::{scalarmult_base, Scalar};
+#[cfg(feature = "hsm")]
+use std::fmt::Display;
+
+/// Label of the key's data object, unless configured otherwise
+pub const DEFAULT_LABEL: &'static str = "inauth-server-key";
+
+fn label(config: &HsmConfig) -> &str {
+    config.label.as_ref().map(|l| l.as_str()).unwrap_or(DEFAULT_LABEL)
+}
+
+/// Read the server's secret key from the token, returning the server
+/// cert with `public`'s key and meta.
+#[cfg(feature = "hsm")]
+pub fn load(config: &HsmConfig, public: &ZCert) -> Result<ZCert> {
+    let token = Token::open(config)?;
+    let object = match token.find(label(config))? {
+        Some(o) => o,
+        None => return Err(Error::Hsm(format!("no key labelled \"{}\" in slot {}", label(config), config.slot))),
+    };
+    let mut secret = token.value(object)?;
+    let cert = from_secret(public, &secret);
+    zero(&mut secret);
+    cert
+}
+
+/// Write `cert`'s secret key to the token. An existing key with the
+/// same label is left alone.
+#[cfg(feature = "hsm")]
+pub fn store(config: &HsmConfig, cert: &ZCert) -> Result<()> {
+    let token = Token::open(config)?;
+    if token.find(label(config))?.is_some() {
+        return Err(Error::Hsm(format!("a key labelled \"{}\" already exists in slot {}", label(config), config.slot)));
+    }
+
+    let label = label(config).to_string();
+    let template = vec![
+        CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_DATA),
+        CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&CK_TRUE),
+        CK_ATTRIBUTE::new(CKA_PRIVATE).with_bool(&CK_TRUE),
+        CK_ATTRIBUTE::new(CKA_LABEL).with_string(&label),
+        CK_ATTRIBUTE::new(CKA_VALUE).with_bytes(cert.secret_key()),
+    ];
+    token.ctx.create_object(token.session, &template).map_err(hsm_error)?;
+    Ok(())
+}
+
+/// Stand-in for builds without the `hsm` feature.
+#[cfg(not(feature = "hsm"))]
+pub fn load(config: &HsmConfig, _: &ZCert) -> Result<ZCert> {
+    Err(Error::Hsm(format!("inauth was built without the \"hsm\" feature, so can't read \"{}\"", label(config))))
+}
+
+/// Stand-in for builds without the `hsm` feature.
+#[cfg(not(feature = "hsm"))]
+pub fn store(config: &HsmConfig, _: &ZCert) -> Result<()> {
+    Err(Error::Hsm(format!("inauth was built without the \"hsm\" feature, so can't write \"{}\"", label(config))))
+}
+
+// A logged in session, closed on drop
+#[cfg(feature = "hsm")]
+struct Token {
+    ctx: Ctx,
+    session: CK_SESSION_HANDLE,
+}
+
+#[cfg(feature = "hsm")]
+impl Token {
+    fn open(config: &HsmConfig) -> Result<Token> {
+        let ctx = Ctx::new_and_initialize(&config.module).map_err(hsm_error)?;
+        let session = ctx.open_session(config.slot, CKF_SERIAL_SESSION | CKF_RW_SESSION, None, None).map_err(hsm_error)?;
+        let token = Token {
+            ctx: ctx,
+            session: session,
+        };
+        let pin = server_key::read_passphrase(&config.pin)?;
+        token.ctx.login(token.session, CKU_USER, Some(pin.expose())).map_err(hsm_error)?;
+        Ok(token)
+    }
+
+    fn find(&self, label: &str) -> Result<Option<CK_OBJECT_HANDLE>> {
+        let label = label.to_string();
+        let template = vec![
+            CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_DATA),
+            CK_ATTRIBUTE::new(CKA_LABEL).with_string(&label),
+        ];
+        self.ctx.find_objects_init(self.session, &template).map_err(hsm_error)?;
+        let found = self.ctx.find_objects(self.session, 2);
+        self.ctx.find_objects_final(self.session).map_err(hsm_error)?;
+        let found = found.map_err(hsm_error)?;
+        if found.len() > 1 {
+            return Err(Error::Hsm(format!("more than one key labelled \"{}\"", label)));
+        }
+        Ok(found.first().cloned())
+    }
+
+    // The first call gets the value's length, the second the value
+    fn value(&self, object: CK_OBJECT_HANDLE) -> Result<Vec<u8>> {
+        let mut template = vec![CK_ATTRIBUTE::new(CKA_VALUE)];
+        let len = self.ctx.get_attribute_value(self.session, object, &mut template).map_err(hsm_error)?.1[0].ulValueLen;
+        let value = vec![0; len as usize];
+        let mut template = vec![CK_ATTRIBUTE::new(CKA_VALUE).with_bytes(&value)];
+        self.ctx.get_attribute_value(self.session, object, &mut template).map_err(hsm_error)?;
+        Ok(value)
+    }
+}
+
+#[cfg(feature = "hsm")]
+impl Drop for Token {
+    fn drop(&mut self) {
+        let _ = self.ctx.logout(self.session);
+        let _ = self.ctx.close_session(self.session);
+    }
+}
+
+// Check `secret` belongs to `public` before trusting it, as a wrong
+// label or slot would otherwise start the server with a stranger's key
+#[cfg(feature = "hsm")]
+fn from_secret(public: &ZCert, secret: &[u8]) -> Result<ZCert> {
+    let derived = Scalar::from_slice(secret).map(|s| scalarmult_base(&s).0);
+    if derived.as_ref().map(|pk| &pk[..]) != Some(public.public_key()) {
+        return Err(Error::Hsm("the token's key does not match the server's public cert".into()));
+    }
+
+    let cert = ZCert::from_keys(public.public_key(), secret);
+    for key in public.meta_keys() {
+        if let Some(Ok(value)) = public.meta(key) {
+            cert.set_meta(key, &value);
+        }
+    }
+    Ok(cert)
+}
+
+#[cfg(feature = "hsm")]
+fn hsm_error<E: Display>(err: E) -> Error {
+    Error::Hsm(err.to_string())
+}
+
+// Best effort scrubbing of key material
+#[cfg(feature = "hsm")]
+fn zero(buf: &mut Vec<u8>) {
+    for b in buf.iter_mut() {
+        *b = 0;
+    }
+}
+
+#[cfg(all(test, feature = "hsm"))]
+mod tests {
+    use czmq::{ZCert, ZSys};
+    use super::*;
+
+    #[test]
+    fn test_from_secret() {
+        ZSys::init();
+
+        let server = ZCert::new().unwrap();
+        server.set_meta("name", "auth");
+        let public = ZCert::from_keys(server.public_key(), &[0; 32]);
+        public.set_meta("name", "auth");
+
+        let cert = from_secret(&public, server.secret_key()).unwrap();
+        assert_eq!(cert.secret_txt(), server.secret_txt());
+        assert_eq!(cert.meta("name").unwrap().unwrap(), "auth");
+
+        let stranger = ZCert::new().unwrap();
+        assert!(from_secret(&public, stranger.secret_key()).is_err());
+    }
+}
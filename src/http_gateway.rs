@@ -0,0 +1,541 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Optional HTTP JSON gateway for consumers that can't speak CURVE-ZMQ.
+//!
+//! | Method | Path              | API endpoint   |
+//! |--------|-------------------|----------------|
+//! | GET    | /certs?type=host  | cert::list     |
+//! | GET    | /certs?filter=... | cert::search   |
+//! | GET    | /certs/{name}     | cert::lookup   |
+//! | POST   | /certs            | cert::create   |
+//! | DELETE | /certs/{name}     | cert::delete   |
+//!
+//! Requests are authenticated with `Authorization: Bearer <token>`,
+//! where each token maps to an identity (name, cert type, domain and
+//! role) in the config. The API authorizes, rate limits and runs the
+//! policy script on gateway requests as if that identity's cert had
+//! made them over CURVE. The gateway speaks plain HTTP, so bind it to
+//! localhost or put TLS in front of it.
+//!
+//! The listener runs on its own thread and handles each connection on
+//! another, up to `MAX_CONNECTIONS` at once. Requests are forwarded to
+//! the service thread over `GATEWAY_ENDPOINT`, prefixed with the
+//! caller's identity frames. Each request carries a fresh request ID,
+//! so a reply that arrives after its caller gave up isn't mistaken for
+//! the next caller's.
+
+use cert::CertType;
+use config::{HttpGatewayConfig, GatewayIdentity};
+use czmq::{ZFrame, ZMsg, ZSock};
+use error::{Error, ErrorCode, Result};
+use request_id::{RequestId, REQUEST_ID_PREFIX};
+use request_meta::RequestMeta;
+use secret::Secret;
+use serde_json;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{JoinHandle, spawn};
+use std::time::Duration;
+
+pub const GATEWAY_ENDPOINT: &'static str = "inproc://auth_http_gateway";
+
+const MAX_BODY: usize = 64 * 1024;
+// Connections handled at once. Any more are turned away with a 503.
+const MAX_CONNECTIONS: usize = 32;
+// For reading the request from, and writing the reply to, the client
+const IO_TIMEOUT_SECS: u64 = 5;
+const BACKEND_TIMEOUT_MS: i32 = 5000;
+
+#[derive(Debug, Serialize)]
+struct CertList {
+    certs: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CertKey {
+    name: String,
+    public_key: String,
+}
+
+#[derive(Debug, Serialize)]
+struct NewCert {
+    name: String,
+    public_key: String,
+    #[serde(serialize_with = "serialize_secret")]
+    secret_key: Secret<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateRequest {
+    #[serde(rename = "type")]
+    cert_type: String,
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+/// Read the identity frames the gateway prepends to every request:
+/// name, cert type, domain and role, the last two empty if unset.
+pub fn recv_identity(sock: &mut ZSock) -> Result<RequestMeta> {
+    let mut frames = Vec::new();
+    for _ in 0..4 {
+        match ZFrame::recv(sock)?.data()? {
+            Ok(f) => frames.push(f),
+            Err(_) => return Err(Error::InvalidCert),
+        }
+    }
+    let role = frames.pop().and_then(non_empty);
+    let domain = frames.pop().and_then(non_empty);
+    let cert_type = CertType::from_str(&frames.pop().unwrap_or(String::new()))?;
+
+    Ok(RequestMeta {
+        name: frames.pop().unwrap_or(String::new()),
+        cert_type: cert_type,
+        domain: domain,
+        role: role,
+    })
+}
+
+fn non_empty(s: String) -> Option<String> {
+    if s.is_empty() { None } else { Some(s) }
+}
+
+pub fn spawn_gateway(config: HttpGatewayConfig) -> Result<JoinHandle<()>> {
+    for identity in config.tokens.values() {
+        if CertType::from_str(identity.cert_type()).is_err() {
+            return Err(Error::InvalidConfig(format!("gateway identity {} has unknown type \"{}\"", identity.name, identity.cert_type())));
+        }
+    }
+
+    let listener = TcpListener::bind(&config.bind as &str)?;
+    info!("HTTP gateway listening on {}", config.bind);
+
+    let tokens = Arc::new(config.tokens);
+    let active = Arc::new(AtomicUsize::new(0));
+    Ok(spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(s) => {
+                    if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        if let Err(e) = respond(&s, 503, &error_body("Too many connections")) {
+                            debug!("HTTP gateway connection error: {}", e);
+                        }
+                        continue;
+                    }
+
+                    let slot = Slot(active.clone());
+                    let tokens = tokens.clone();
+                    spawn(move || {
+                        let _slot = slot;
+                        if let Err(e) = handle(s, &tokens) {
+                            debug!("HTTP gateway connection error: {}", e);
+                        }
+                    });
+                },
+                Err(e) => error!("HTTP gateway accept error: {}", e),
+            }
+        }
+    }))
+}
+
+// Frees a connection slot when its thread ends, even by panicking
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// An HTTP request, with lowercase header names.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+fn handle(stream: TcpStream, tokens: &HashMap<String, GatewayIdentity>) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS)))?;
+    let writer = stream.try_clone()?;
+
+    let (status, body) = match read_request(BufReader::new(stream)) {
+        Ok(request) => route(&request, tokens),
+        Err(_) => (400, error_body("Malformed request")),
+    };
+
+    respond(&writer, status, &body)
+}
+
+fn respond(mut stream: &TcpStream, status: u16, body: &str) -> Result<()> {
+    stream.set_write_timeout(Some(Duration::from_secs(IO_TIMEOUT_SECS)))?;
+    write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, reason(status), body.len(), body)?;
+    Ok(())
+}
+
+fn route(request: &Request, tokens: &HashMap<String, GatewayIdentity>) -> (u16, String) {
+    let identity = match bearer_identity(request.headers.get("authorization").map(|h| h as &str), tokens) {
+        Some(i) => i,
+        None => return (401, error_body("Missing or invalid bearer token")),
+    };
+
+    // ZSocks can't be shared between connection threads, and inproc
+    // connections are cheap
+    let mut backend = match ZSock::new_dealer(&format!(">{}", GATEWAY_ENDPOINT)) {
+        Ok(b) => b,
+        Err(e) => return (500, error_body(&format!("{}", e))),
+    };
+    backend.set_rcvtimeo(Some(BACKEND_TIMEOUT_MS));
+    let backend = &mut backend;
+
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let result = match (request.method.as_ref(), &segments[..]) {
+        ("GET", &["certs"]) => {
+            if let Some(filter) = request.query.get("filter") {
+                call(backend, identity, "cert::search", &[filter]).map(|r| (200, names(r)))
+            } else {
+                match request.query.get("type") {
+                    Some(t) => call(backend, identity, "cert::list", &[t]).map(|r| (200, names(r))),
+                    None => return (400, error_body("Query parameter 'type' or 'filter' is required")),
+                }
+            }
+        },
+        ("GET", &["certs", name]) => {
+            call(backend, identity, "cert::lookup", &[name]).map(|r| {
+                (200, to_json(&CertKey {
+                    name: name.to_string(),
+                    public_key: r.into_iter().next().unwrap_or(String::new()),
+                }))
+            })
+        },
+        ("POST", &["certs"]) => {
+            let req: CreateRequest = match serde_json::from_slice(&request.body) {
+                Ok(r) => r,
+                Err(e) => return (400, error_body(&format!("Invalid request body: {}", e))),
+            };
+            call(backend, identity, "cert::create", &[&req.cert_type, &req.name]).map(|r| {
+                let mut r = r.into_iter();
+                (201, to_json(&NewCert {
+                    name: req.name.clone(),
+                    public_key: r.next().unwrap_or(String::new()),
+                    secret_key: Secret::new(r.next().unwrap_or(String::new())),
+                }))
+            })
+        },
+        ("DELETE", &["certs", name]) => {
+            call(backend, identity, "cert::delete", &[name]).map(|_| (204, String::new()))
+        },
+        (_, &["certs"]) | (_, &["certs", _]) => return (405, error_body("Method not allowed")),
+        _ => return (404, error_body("Not found")),
+    };
+
+    match result {
+        Ok(r) => r,
+        Err(Error::Api(code, ref message)) => (status_for(code), error_body(message)),
+        Err(e) => (status_for(e.code()), error_body(&format!("{}", e))),
+    }
+}
+
+/// The HTTP status for an API error.
+pub fn status_for(code: ErrorCode) -> u16 {
+    match code {
+        ErrorCode::BadRequest | ErrorCode::InvalidCert | ErrorCode::WeakKey => 400,
+        ErrorCode::Forbidden | ErrorCode::Policy => 403,
+        ErrorCode::NotFound => 404,
+        ErrorCode::NameCollision | ErrorCode::DuplicateKey => 409,
+        ErrorCode::RateLimited | ErrorCode::QuotaExceeded => 429,
+        ErrorCode::Timeout => 504,
+        ErrorCode::Internal | ErrorCode::Unknown => 500,
+    }
+}
+
+/// Resolve an `Authorization` header value to the identity its bearer
+/// token authenticates as.
+pub fn bearer_identity<'a>(header: Option<&str>, tokens: &'a HashMap<String, GatewayIdentity>) -> Option<&'a GatewayIdentity> {
+    match header {
+        Some(h) if h.starts_with("Bearer ") => tokens.get(h[7..].trim()),
+        _ => None,
+    }
+}
+
+/// Forward a request to the API and return the reply's data frames.
+pub fn call(backend: &mut ZSock, identity: &GatewayIdentity, endpoint: &str, args: &[&str]) -> Result<Vec<String>> {
+    let id_frame = format!("{}{}", REQUEST_ID_PREFIX, RequestId::generate());
+    let msg = ZMsg::new();
+    msg.addstr(&id_frame)?;
+    msg.addstr(endpoint)?;
+    msg.addstr(&identity.name)?;
+    msg.addstr(identity.cert_type())?;
+    msg.addstr(identity.domain.as_ref().map(|d| d as &str).unwrap_or(""))?;
+    msg.addstr(identity.role.as_ref().map(|r| r as &str).unwrap_or(""))?;
+    for arg in args {
+        msg.addstr(arg)?;
+    }
+    msg.send(backend)?;
+
+    // Replies to requests that timed out may still be queued, and can
+    // hold another caller's secret key
+    let reply = loop {
+        let reply = ZMsg::recv(backend).or(Err(Error::Timeout(format!("waiting for the API to reply to {}", endpoint))))?;
+        reply.popstr(); // Discard delimiter
+        match reply.popstr() {
+            Some(Ok(ref id)) if *id == id_frame => break reply,
+            _ => debug!("Dropped a stale reply on the HTTP gateway backend"),
+        }
+    };
+
+    let status = reply.popstr().ok_or(Error::InvalidArgsCount)?.unwrap_or(String::new());
+    let mut frames = Vec::new();
+    while let Some(frame) = reply.popstr() {
+        match frame {
+            Ok(s) => frames.push(s),
+            Err(_) => (), // Binary frames (e.g. encoded metadata) aren't exposed
+        }
+    }
+
+    if status == "Ok" {
+        Ok(frames)
+    } else {
+        let message = frames.pop().unwrap_or(status);
+        let code = frames.pop().map(|c| ErrorCode::from(&*c)).unwrap_or(ErrorCode::Unknown);
+        Err(Error::Api(code, message))
+    }
+}
+
+pub fn read_request<R: BufRead>(mut reader: R) -> Result<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().ok_or(Error::InvalidArg)?.to_string();
+    let target = parts.next().ok_or(Error::InvalidArg)?.to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let header = header.trim_right();
+        if header.is_empty() {
+            break;
+        }
+        let mut kv = header.splitn(2, ':');
+        let key = kv.next().unwrap_or("").trim().to_lowercase();
+        let value = kv.next().ok_or(Error::InvalidArg)?.trim().to_string();
+        headers.insert(key, value);
+    }
+
+    let len = match headers.get("content-length") {
+        Some(l) => l.parse().or(Err(Error::InvalidArg))?,
+        None => 0,
+    };
+    if len > MAX_BODY {
+        return Err(Error::InvalidArg);
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+
+    let (path, query) = match target.find('?') {
+        Some(i) => (target[..i].to_string(), parse_query(&target[i + 1..])),
+        None => (target.clone(), HashMap::new()),
+    };
+
+    Ok(Request {
+        method: method,
+        path: url_decode(&path),
+        query: query,
+        headers: headers,
+        body: body,
+    })
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let mut kv = pair.splitn(2, '=');
+        let key = url_decode(kv.next().unwrap_or(""));
+        let value = url_decode(kv.next().unwrap_or(""));
+        params.insert(key, value);
+    }
+    params
+}
+
+fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match ::std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => {
+                        out.push(b);
+                        i += 2;
+                    },
+                    None => out.push(b'%'),
+                }
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn names(frames: Vec<String>) -> String {
+    to_json(&CertList { certs: frames })
+}
+
+fn to_json<T: ::serde::Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or(String::new())
+}
+
+/// The only place a secret is written out: the reply to its owner.
+fn serialize_secret<S: ::serde::Serializer>(secret: &Secret<String>, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(secret.expose())
+}
+
+fn error_body(message: &str) -> String {
+    to_json(&ErrorBody { error: message.to_string() })
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        429 => "Too Many Requests",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::CertType;
+    use config::GatewayIdentity;
+    use czmq::{ZMsg, ZSock, ZSys};
+    use secret::Secret;
+    use std::thread::spawn;
+    use error::ErrorCode;
+    use super::{call, parse_query, read_request, recv_identity, status_for, to_json, url_decode, NewCert};
+
+    #[test]
+    fn test_call_drops_stale_reply() {
+        ZSys::init();
+
+        let mut router = ZSock::new_router("@inproc://http_gateway_test_call_drops_stale_reply").unwrap();
+        let mut backend = ZSock::new_dealer(">inproc://http_gateway_test_call_drops_stale_reply").unwrap();
+        backend.set_rcvtimeo(Some(100));
+        let identity = GatewayIdentity { name: "alice".into(), cert_type: None, domain: None, role: None };
+
+        let handle = spawn(move || {
+            // Hold the first reply until the second request arrives
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let msg = ZMsg::recv(&mut router).unwrap();
+                let router_id = msg.popbytes().unwrap().unwrap();
+                let id = msg.popstr().unwrap().unwrap();
+                requests.push((router_id, id));
+            }
+            for (i, (router_id, id)) in requests.into_iter().enumerate() {
+                let reply = ZMsg::new();
+                reply.addbytes(&router_id).unwrap();
+                reply.addstr("").unwrap();
+                reply.addstr(&id).unwrap();
+                reply.addstr("Ok").unwrap();
+                reply.addstr(if i == 0 { "secret-for-first" } else { "second" }).unwrap();
+                reply.send(&mut router).unwrap();
+            }
+        });
+
+        assert!(call(&mut backend, &identity, "cert::create", &["user", "first"]).is_err());
+        assert_eq!(call(&mut backend, &identity, "cert::lookup", &["second"]).unwrap(), vec!["second".to_string()]);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_recv_identity() {
+        ZSys::init();
+
+        let (mut client, mut server) = ZSys::create_pipe().unwrap();
+        let msg = ZMsg::new();
+        for frame in &["alice", "user", "rebels", ""] {
+            msg.addstr(frame).unwrap();
+        }
+        msg.send(&mut client).unwrap();
+
+        let meta = recv_identity(&mut server).unwrap();
+        assert_eq!(meta.name, "alice");
+        assert_eq!(meta.cert_type, CertType::User);
+        assert_eq!(meta.domain.unwrap(), "rebels");
+        assert!(meta.role.is_none());
+    }
+
+    #[test]
+    fn test_status_for() {
+        assert_eq!(status_for(ErrorCode::Forbidden), 403);
+        assert_eq!(status_for(ErrorCode::RateLimited), 429);
+        assert_eq!(status_for(ErrorCode::Timeout), 504);
+        assert_eq!(status_for(ErrorCode::Internal), 500);
+        assert_eq!(status_for(ErrorCode::Unknown), 500);
+    }
+
+    #[test]
+    fn test_read_request() {
+        let raw: &[u8] = b"POST /certs?x=1 HTTP/1.1\r\n\
+                           Authorization: Bearer abc\r\n\
+                           Content-Length: 2\r\n\
+                           \r\n\
+                           {}";
+        let request = read_request(raw).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/certs");
+        assert_eq!(request.query.get("x").unwrap(), "1");
+        assert_eq!(request.headers.get("authorization").unwrap(), "Bearer abc");
+        assert_eq!(request.body, b"{}");
+
+        let truncated: &[u8] = b"POST /certs HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}";
+        assert!(read_request(truncated).is_err());
+    }
+
+    #[test]
+    fn test_url_decode() {
+        assert_eq!(url_decode("type%3Dhost+AND+env%3Dprod"), "type=host AND env=prod");
+        assert_eq!(url_decode("100%"), "100%");
+        assert_eq!(url_decode("%zz"), "%zz");
+        assert_eq!(parse_query("filter=name~web*&type=host").get("filter").unwrap(), "name~web*");
+    }
+
+    #[test]
+    fn test_new_cert_redacted() {
+        let cert = NewCert {
+            name: "web1".into(),
+            public_key: "pubkey".into(),
+            secret_key: Secret::new("s3cr3t".into()),
+        };
+        assert!(!format!("{:?}", cert).contains("s3cr3t"));
+        assert!(to_json(&cert).contains("\"secret_key\":\"s3cr3t\""));
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Detection of public keys that shouldn't be trusted: published test
+//! keys, keys without enough entropy to be random, and keypairs
+//! reused by more than one cert.
+
+use cert::Cert;
+use std::collections::HashMap;
+use std::fmt;
+
+// Random keys have ~31 distinct bytes. Anything this repetitive was
+// typed in or generated by something broken.
+const MIN_DISTINCT_BYTES: usize = 8;
+
+// Example keys from the ZeroMQ docs, which end up in copy-pasted configs
+const KNOWN_TEST_KEYS: [&'static str; 2] = [
+    "rq:rM>}U?@Lns47E1%kR.o@n%FcmmsL/@{H8]yf7",
+    "Yne@$w-vo<fVvi]a<NY6T1ed:M$fCG*[IaLV{hID",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeyProblem {
+    AllZero,
+    Duplicate(String),
+    KnownTestKey,
+    LowEntropy,
+}
+
+impl fmt::Display for KeyProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeyProblem::AllZero => write!(f, "all-zero key"),
+            KeyProblem::Duplicate(ref name) => write!(f, "same key as {}", name),
+            KeyProblem::KnownTestKey => write!(f, "published test key"),
+            KeyProblem::LowEntropy => write!(f, "low entropy key"),
+        }
+    }
+}
+
+/// Check a single public key for known-bad patterns.
+pub fn check_key(public_key: &[u8], public_txt: &str) -> Option<KeyProblem> {
+    if public_key.iter().all(|b| *b == 0) {
+        return Some(KeyProblem::AllZero);
+    }
+
+    if KNOWN_TEST_KEYS.contains(&public_txt) {
+        return Some(KeyProblem::KnownTestKey);
+    }
+
+    let mut seen = [false; 256];
+    for b in public_key {
+        seen[*b as usize] = true;
+    }
+    if seen.iter().filter(|s| **s).count() < MIN_DISTINCT_BYTES {
+        return Some(KeyProblem::LowEntropy);
+    }
+
+    None
+}
+
+pub fn check_cert(cert: &Cert) -> Option<KeyProblem> {
+    check_key(cert.public_key(), cert.public_txt())
+}
+
+/// Scan `certs` for bad and duplicate keys. Returns the offending cert
+/// names, sorted. For duplicates, every cert after the first to use the
+/// key is reported against the first.
+pub fn audit(certs: &[Cert]) -> Vec<(String, KeyProblem)> {
+    let mut sorted: Vec<&Cert> = certs.iter().collect();
+    sorted.sort_by(|a, b| a.name().cmp(b.name()));
+
+    let mut owners: HashMap<&str, &str> = HashMap::new();
+    let mut problems = Vec::new();
+
+    for cert in sorted {
+        if let Some(problem) = check_cert(cert) {
+            problems.push((cert.name().to_string(), problem));
+        }
+
+        match owners.get(cert.public_txt()) {
+            Some(owner) => problems.push((cert.name().to_string(), KeyProblem::Duplicate(owner.to_string()))),
+            None => {
+                owners.insert(cert.public_txt(), cert.name());
+            },
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType};
+    use czmq::ZCert;
+    use super::*;
+
+    fn cert_from_keys(name: &str, public_key: &[u8]) -> Cert {
+        let zcert = ZCert::from_keys(public_key, &[1; 32]);
+        zcert.set_meta("name", name);
+        zcert.set_meta("type", "host");
+        Cert::from_zcert(zcert).unwrap()
+    }
+
+    #[test]
+    fn test_check_key() {
+        let cert = Cert::new("luke", CertType::User).unwrap();
+        assert_eq!(check_cert(&cert), None);
+
+        assert_eq!(check_key(&[0; 32], ""), Some(KeyProblem::AllZero));
+        assert_eq!(check_key(&[0xab; 32], ""), Some(KeyProblem::LowEntropy));
+        let pattern: Vec<u8> = (0..32).map(|i| (i % 4) as u8).collect();
+        assert_eq!(check_key(&pattern, ""), Some(KeyProblem::LowEntropy));
+        let ascending: Vec<u8> = (0..32).collect();
+        assert_eq!(check_key(&ascending, KNOWN_TEST_KEYS[0]), Some(KeyProblem::KnownTestKey));
+        assert_eq!(check_key(&ascending, ""), None);
+    }
+
+    #[test]
+    fn test_audit() {
+        let good = Cert::new("good", CertType::Host).unwrap();
+        let original = Cert::new("a", CertType::Host).unwrap();
+        let copy = cert_from_keys("b", original.public_key());
+        let zero = cert_from_keys("zero", &[0; 32]);
+
+        let problems = audit(&[zero, copy, good, original]);
+        assert_eq!(problems, vec![
+            ("b".to_string(), KeyProblem::Duplicate("a".into())),
+            ("zero".to_string(), KeyProblem::AllZero),
+        ]);
+    }
+}
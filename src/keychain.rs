@@ -0,0 +1,104 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! User secret keys in the OS keychain: the macOS Keychain, the Secret
+//! Service (e.g. GNOME Keyring) on Linux, or the Windows Credential
+//! Manager.
+//!
+//! Keys are stored under the service "inauth", with the cert name as
+//! the account and the Z85 secret key as the password. The public key
+//! is derived from the secret key on load.
+
+use cert::Cert;
+#[cfg(feature = "keychain")]
+use cert::CertType;
+#[cfg(feature = "keychain")]
+use czmq::ZCert;
+use error::{Error, Result};
+#[cfg(feature = "keychain")]
+use keyring::{Entry, Error as KeyringError};
+#[cfg(feature = "keychain")]
+use sodiumoxide::crypto::scalarmult::curve25519::{scalarmult_base, Scalar};
+#[cfg(feature = "keychain")]
+use zmq::z85_decode;
+
+/// The keychain service keys are stored under
+pub const KEYCHAIN_SERVICE: &'static str = "inauth";
+
+/// Store `cert`'s secret key for the current OS user. A key already
+/// stored under the cert's name is left alone, as it may belong to
+/// another Auth server's cert.
+#[cfg(feature = "keychain")]
+pub fn store_keychain_cert(cert: &Cert) -> Result<()> {
+    let entry = Entry::new(KEYCHAIN_SERVICE, cert.name());
+    match entry.get_password() {
+        Ok(_) => return Err(Error::Keychain(format!("a key for {} is already in the keychain", cert.name()))),
+        Err(KeyringError::NoEntry) => (),
+        Err(e) => return Err(Error::Keychain(e.to_string())),
+    }
+    entry.set_password(cert.secret_txt().expose()).map_err(|e| Error::Keychain(e.to_string()))
+}
+
+/// Load the user cert called `name`, as stored by `inauth_cli user add
+/// --keychain`.
+#[cfg(feature = "keychain")]
+pub fn load_keychain_cert(name: &str) -> Result<Cert> {
+    let secret = match Entry::new(KEYCHAIN_SERVICE, name).get_password() {
+        Ok(s) => s,
+        Err(KeyringError::NoEntry) => return Err(Error::Keychain(format!("no key for {} in the keychain", name))),
+        Err(e) => return Err(Error::Keychain(e.to_string())),
+    };
+    from_secret_txt(name, &secret)
+}
+
+/// Stand-in for builds without the `keychain` feature.
+#[cfg(not(feature = "keychain"))]
+pub fn store_keychain_cert(_: &Cert) -> Result<()> {
+    Err(Error::Keychain("inauth was built without the \"keychain\" feature".into()))
+}
+
+/// Stand-in for builds without the `keychain` feature.
+#[cfg(not(feature = "keychain"))]
+pub fn load_keychain_cert(_: &str) -> Result<Cert> {
+    Err(Error::Keychain("inauth was built without the \"keychain\" feature".into()))
+}
+
+#[cfg(feature = "keychain")]
+fn from_secret_txt(name: &str, secret_txt: &str) -> Result<Cert> {
+    let mut secret = try!(z85_decode(secret_txt).or(Err(Error::InvalidCert)));
+    let public = Scalar::from_slice(&secret).map(|s| scalarmult_base(&s).0);
+    let zcert = public.map(|pk| ZCert::from_keys(&pk, &secret));
+    // Best effort scrubbing of key material
+    for b in secret.iter_mut() {
+        *b = 0;
+    }
+
+    let zcert = try!(zcert.ok_or(Error::InvalidCert));
+    zcert.set_meta("name", name);
+    zcert.set_meta("type", CertType::User.to_str());
+    Cert::from_zcert(zcert)
+}
+
+#[cfg(all(test, feature = "keychain"))]
+mod tests {
+    use cert::{Cert, CertType};
+    use czmq::ZSys;
+    use super::*;
+
+    #[test]
+    fn test_from_secret_txt() {
+        ZSys::init();
+
+        let cert = Cert::new("john.smith", CertType::User).unwrap();
+        let loaded = from_secret_txt("john.smith", cert.secret_txt().expose()).unwrap();
+        assert_eq!(loaded, cert);
+        assert_eq!(loaded.secret_txt().expose(), cert.secret_txt().expose());
+
+        assert!(from_secret_txt("john.smith", "not z85").is_err());
+    }
+}
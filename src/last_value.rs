@@ -0,0 +1,195 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Which certs changed at which feed sequence, so that subscribers
+//! resuming from a known position (see `RESUME_PREFIX`) can be sent
+//! just the certs changed since, rather than a whole snapshot.
+//!
+//! Only the last change to each cert is kept. The certs themselves are
+//! taken from the `CertCache` when a subscriber resumes, so a resumed
+//! subscriber gets each cert's latest value, or a DEL.
+
+use cert::CertType;
+use cert_cache::{ORIGIN_PREFIX, TIMESTAMP_PREFIX};
+use czmq::ZMsg;
+use latency;
+use std::collections::{BTreeSet, HashMap};
+
+/// Changes remembered, unless set otherwise.
+pub const DEFAULT_CAPACITY: usize = 100_000;
+
+#[derive(Debug, Default)]
+struct TopicChanges {
+    // The sequence of each cert's last change
+    by_key: HashMap<String, u64>,
+    by_sequence: BTreeSet<(u64, String)>,
+}
+
+#[derive(Debug)]
+pub struct LastValueCache {
+    // Sequences restart from 0 with the publisher, so resume positions
+    // from another run mean nothing
+    epoch: u64,
+    capacity: usize,
+    // Changes up to this sequence may have been forgotten
+    horizon: u64,
+    latest: u64,
+    topics: HashMap<String, TopicChanges>,
+}
+
+impl LastValueCache {
+    pub fn new(capacity: usize) -> LastValueCache {
+        LastValueCache {
+            epoch: latency::now_micros(),
+            capacity: capacity,
+            horizon: 0,
+            latest: 0,
+            topics: HashMap::new(),
+        }
+    }
+
+    /// Identifies this run of the publisher in heartbeats.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    /// Note the certs `msg` changes, published at `sequence`. Only
+    /// changes on cert type topics are kept, as the rest are copies.
+    pub fn record(&mut self, msg: &ZMsg, sequence: u64) {
+        self.latest = sequence;
+
+        let topic = match msg.first().map(|f| f.data()) {
+            Some(Ok(Ok(s))) => s,
+            _ => return,
+        };
+        if CertType::from_str(&topic).is_err() {
+            return;
+        }
+        let paired = match msg.next().map(|f| f.data()) {
+            Some(Ok(Ok(ref s))) if s == "ADD" || s == "UPDATE" => true,
+            Some(Ok(Ok(ref s))) if s == "DEL" => false,
+            _ => return,
+        };
+
+        let changes = self.topics.entry(topic).or_insert_with(TopicChanges::default);
+        while let Some(frame) = msg.next() {
+            let pubkey = match frame.data() {
+                Ok(Ok(s)) => s,
+                _ => break,
+            };
+            if pubkey.starts_with(ORIGIN_PREFIX) || pubkey.starts_with(TIMESTAMP_PREFIX) {
+                break;
+            }
+
+            if let Some(previous) = changes.by_key.insert(pubkey.clone(), sequence) {
+                changes.by_sequence.remove(&(previous, pubkey.clone()));
+            }
+            changes.by_sequence.insert((sequence, pubkey));
+
+            if !paired || msg.next().is_none() {
+                break;
+            }
+        }
+        self.evict();
+    }
+
+    /// The keys on `cert_type`'s topic changed after `sequence` in run
+    /// `epoch`, or `None` if we can't tell, in which case the
+    /// subscriber needs a snapshot.
+    pub fn changed_since(&self, cert_type: CertType, epoch: u64, sequence: u64) -> Option<Vec<String>> {
+        if epoch != self.epoch || sequence < self.horizon || sequence > self.latest {
+            return None;
+        }
+
+        Some(match self.topics.get(cert_type.to_str()) {
+            Some(changes) => changes.by_sequence.range((sequence + 1, String::new())..).map(|&(_, ref k)| k.clone()).collect(),
+            None => Vec::new(),
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.topics.values().map(|t| t.by_key.len()).sum()
+    }
+
+    // Forget the oldest changes beyond our capacity
+    fn evict(&mut self) {
+        let mut len = self.len();
+        while len > self.capacity {
+            let oldest = self.topics.iter()
+                .filter_map(|(topic, changes)| changes.by_sequence.iter().next().map(|c| (c.clone(), topic.clone())))
+                .min();
+            let ((sequence, pubkey), topic) = match oldest {
+                Some(o) => o,
+                None => break,
+            };
+
+            let changes = self.topics.get_mut(&topic).unwrap();
+            changes.by_sequence.remove(&(sequence, pubkey.clone()));
+            changes.by_key.remove(&pubkey);
+            if sequence > self.horizon {
+                self.horizon = sequence;
+            }
+            len -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType};
+    use czmq::{ZMsg, ZSys};
+    use super::*;
+
+    fn change(topic: &str, action: &str, certs: &[&Cert]) -> ZMsg {
+        let msg = ZMsg::new();
+        msg.addstr(topic).unwrap();
+        msg.addstr(action).unwrap();
+        for cert in certs {
+            msg.addstr(cert.public_txt()).unwrap();
+            if action != "DEL" {
+                msg.addbytes(&cert.encode_meta()).unwrap();
+            }
+        }
+        msg.addstr("ts=1").unwrap();
+        msg
+    }
+
+    #[test]
+    fn test_changed_since() {
+        ZSys::init();
+
+        let web1 = Cert::new("web1", CertType::Host).unwrap();
+        let web2 = Cert::new("web2", CertType::Host).unwrap();
+        let web3 = Cert::new("web3", CertType::Host).unwrap();
+        let mut cache = LastValueCache::new(DEFAULT_CAPACITY);
+        let epoch = cache.epoch();
+
+        cache.record(&change("host", "ADD", &[&web1, &web2]), 1);
+        cache.record(&change("batch:host", "ADD", &[&web3]), 2);
+        cache.record(&change("host", "DEL", &[&web1]), 3);
+        cache.record(&change("host", "HEARTBEAT", &[]), 4);
+
+        assert_eq!(cache.changed_since(CertType::Host, epoch, 0).unwrap(), vec![web2.public_txt(), web1.public_txt()]);
+        assert_eq!(cache.changed_since(CertType::Host, epoch, 2).unwrap(), vec![web1.public_txt()]);
+        assert!(cache.changed_since(CertType::Host, epoch, 4).unwrap().is_empty());
+        assert!(cache.changed_since(CertType::User, epoch, 0).unwrap().is_empty());
+
+        assert!(cache.changed_since(CertType::Host, epoch + 1, 2).is_none());
+        assert!(cache.changed_since(CertType::Host, epoch, 5).is_none());
+
+        // Forgetting web2's change at 1 rules out resuming before it
+        cache.set_capacity(1);
+        assert!(cache.changed_since(CertType::Host, epoch, 0).is_none());
+        assert_eq!(cache.changed_since(CertType::Host, epoch, 1).unwrap(), vec![web1.public_txt()]);
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Feed propagation delay, from the publisher's timestamp to the
+//! subscriber applying the message.
+//!
+//! Both ends read the wall clock, so the figures are only as good as
+//! the clock sync between them. Messages that appear to arrive before
+//! they were sent are counted separately rather than as zero.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Upper bounds of the histogram buckets, in microseconds. A final
+/// bucket counts anything slower.
+pub const BUCKET_BOUNDS_MICROS: [u64; 6] = [1_000, 10_000, 100_000, 1_000_000, 10_000_000, 60_000_000];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LatencyHistogram {
+    /// One count per bucket in `BUCKET_BOUNDS_MICROS`, plus one for
+    /// anything slower
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum_micros: u64,
+    pub max_micros: u64,
+    /// Messages timestamped later than they arrived
+    pub skewed: u64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            buckets: vec![0; BUCKET_BOUNDS_MICROS.len() + 1],
+            ..LatencyHistogram::default()
+        }
+    }
+
+    /// Record a message published at `published` (see `now_micros()`)
+    /// and applied at `applied`.
+    pub fn record(&mut self, published: u64, applied: u64) {
+        if applied < published {
+            self.skewed += 1;
+            return;
+        }
+
+        let latency = applied - published;
+        let bucket = BUCKET_BOUNDS_MICROS.iter().position(|b| latency <= *b).unwrap_or(BUCKET_BOUNDS_MICROS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_micros = self.sum_micros.saturating_add(latency);
+        if latency > self.max_micros {
+            self.max_micros = latency;
+        }
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(micros_to_duration(self.sum_micros / self.count))
+        }
+    }
+}
+
+/// Microseconds since the Unix epoch.
+pub fn now_micros() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() * 1_000_000 + (d.subsec_nanos() / 1_000) as u64,
+        Err(_) => 0,
+    }
+}
+
+fn micros_to_duration(micros: u64) -> Duration {
+    Duration::new(micros / 1_000_000, ((micros % 1_000_000) * 1_000) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut histogram = LatencyHistogram::new();
+        assert!(histogram.mean().is_none());
+
+        histogram.record(1_000_000, 1_000_500);
+        histogram.record(1_000_000, 1_050_000);
+        histogram.record(1_000_000, 100_000_000);
+        histogram.record(1_000_000, 999_000);
+
+        assert_eq!(histogram.buckets, vec![1, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(histogram.count, 3);
+        assert_eq!(histogram.max_micros, 99_000_000);
+        assert_eq!(histogram.skewed, 1);
+        assert_eq!(histogram.mean(), Some(Duration::new(33, 16_833_000)));
+        assert!(now_micros() > 1_500_000_000_000_000);
+    }
+}
@@ -0,0 +1,66 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Sampling for debug logging that happens on every request, so that
+//! it can be left on at thousands of requests per second. Only accepts
+//! are sampled; rejects are always logged.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A handle to a sampling rate, shared by its clones.
+#[derive(Clone, Debug, Default)]
+pub struct LogSampler {
+    every: Arc<AtomicUsize>,
+    accepts: Arc<AtomicUsize>,
+}
+
+impl LogSampler {
+    /// Logs every accept until `set_accept_every()` is called.
+    pub fn new() -> LogSampler {
+        LogSampler::default()
+    }
+
+    /// Log 1 in `every` accepts. 0 and 1 log them all.
+    pub fn set_accept_every(&self, every: usize) {
+        self.every.store(every, Ordering::Relaxed);
+    }
+
+    pub fn accept_every(&self) -> usize {
+        match self.every.load(Ordering::Relaxed) {
+            0 => 1,
+            n => n,
+        }
+    }
+
+    /// Whether to log the next accept.
+    pub fn sample_accept(&self) -> bool {
+        let every = self.accept_every();
+        every == 1 || self.accepts.fetch_add(1, Ordering::Relaxed) % every == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_accept() {
+        let sampler = LogSampler::new();
+        assert!((0..5).all(|_| sampler.sample_accept()));
+
+        let clone = sampler.clone();
+        clone.set_accept_every(3);
+        assert_eq!(sampler.accept_every(), 3);
+        let sampled: Vec<bool> = (0..6).map(|_| sampler.sample_accept()).collect();
+        assert_eq!(sampled, vec![true, false, false, true, false, false]);
+
+        sampler.set_accept_every(0);
+        assert_eq!(clone.accept_every(), 1);
+    }
+}
@@ -0,0 +1,284 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Requests and replies for the API's typed endpoints.
+//!
+//! Each is read from either positional frames (API version 1) or one
+//! JSON frame (see `protocol::TYPED_VERSION`), and replied to in the
+//! same encoding, so handlers only deal with these structs.
+
+use cert::Cert;
+use czmq::{ZMsg, ZSock};
+use error::{Error, Result};
+use protocol::{self, Endpoint};
+use request_id;
+use serde::{Deserialize, Serialize};
+use serde_json;
+use std::collections::BTreeMap;
+use zdaemon::ZMsgExtended;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Frames,
+    Json,
+}
+
+pub trait Request: Deserialize + Sized {
+    fn endpoint() -> &'static Endpoint;
+    /// Read the request from positional frames.
+    fn from_frames(msg: &ZMsg) -> Result<Self>;
+}
+
+pub trait Reply: Serialize {
+    /// Append the reply as positional frames.
+    fn to_frames(&self, msg: &ZMsg) -> Result<()>;
+}
+
+/// Receive a request, with the frames it came in for tracing.
+pub fn recv<R: Request>(sock: &mut ZSock) -> Result<(R, Encoding, ZMsg)> {
+    let (msg, typed) = R::endpoint().recv_typed(sock)?;
+    if typed {
+        let json = match msg.first().map(|f| f.data()) {
+            Some(Ok(Ok(s))) => s,
+            _ => return Err(Error::InvalidArg),
+        };
+        Ok((serde_json::from_str(&json)?, Encoding::Json, msg))
+    } else {
+        Ok((R::from_frames(&msg)?, Encoding::Frames, msg))
+    }
+}
+
+/// An "Ok" reply to `router_id`, ready to send.
+pub fn reply<R: Reply>(reply: &R, encoding: Encoding, router_id: &[u8]) -> Result<ZMsg> {
+    let msg = ZMsg::new_ok()?;
+    request_id::address(&msg, router_id)?;
+    match encoding {
+        Encoding::Frames => reply.to_frames(&msg)?,
+        Encoding::Json => msg.addstr(&serde_json::to_string(reply)?)?,
+    }
+    Ok(msg)
+}
+
+// The frames as strings, or None where they aren't UTF-8
+fn frame_strs(msg: &ZMsg) -> Result<Vec<Option<String>>> {
+    let mut frames = Vec::new();
+    let mut next = msg.first();
+    while let Some(frame) = next {
+        frames.push(frame.data()?.ok());
+        next = msg.next();
+    }
+    Ok(frames)
+}
+
+fn frame_str(frames: &[Option<String>], index: usize, err: Error) -> Result<String> {
+    frames.get(index).and_then(|f| f.clone()).ok_or(err)
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct CreateRequest {
+    #[serde(rename = "type")]
+    pub cert_type: String,
+    pub name: String,
+}
+
+impl Request for CreateRequest {
+    fn endpoint() -> &'static Endpoint {
+        &protocol::CERT_CREATE
+    }
+
+    fn from_frames(msg: &ZMsg) -> Result<CreateRequest> {
+        let frames = frame_strs(msg)?;
+        Ok(CreateRequest {
+            cert_type: frame_str(&frames, 0, Error::InvalidCertMeta)?,
+            name: frame_str(&frames, 1, Error::InvalidCertMeta)?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreateReply {
+    pub public_key: String,
+    pub secret_key: String,
+    /// All metadata, including the name and type
+    pub metadata: BTreeMap<String, String>,
+    #[serde(skip_serializing)]
+    encoded_meta: Vec<u8>,
+}
+
+impl CreateReply {
+    pub fn new(cert: &Cert) -> CreateReply {
+        let mut metadata = BTreeMap::new();
+        for key in cert.meta_keys() {
+            if let Some(Ok(value)) = cert.meta(key) {
+                metadata.insert(key.to_string(), value);
+            }
+        }
+
+        CreateReply {
+            public_key: cert.public_txt().to_string(),
+            secret_key: cert.secret_txt().expose().to_string(),
+            metadata: metadata,
+            encoded_meta: cert.encode_meta(),
+        }
+    }
+}
+
+impl Reply for CreateReply {
+    fn to_frames(&self, msg: &ZMsg) -> Result<()> {
+        msg.addstr(&self.public_key)?;
+        msg.addstr(&self.secret_key)?;
+        msg.addbytes(&self.encoded_meta)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct DeleteRequest {
+    pub name: String,
+}
+
+impl Request for DeleteRequest {
+    fn endpoint() -> &'static Endpoint {
+        &protocol::CERT_DELETE
+    }
+
+    fn from_frames(msg: &ZMsg) -> Result<DeleteRequest> {
+        let frames = frame_strs(msg)?;
+        Ok(DeleteRequest {
+            name: frame_str(&frames, 0, Error::InvalidCert)?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeleteReply {}
+
+impl Reply for DeleteReply {
+    fn to_frames(&self, _: &ZMsg) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct ListRequest {
+    #[serde(rename = "type")]
+    pub cert_type: String,
+}
+
+impl Request for ListRequest {
+    fn endpoint() -> &'static Endpoint {
+        &protocol::CERT_LIST
+    }
+
+    fn from_frames(msg: &ZMsg) -> Result<ListRequest> {
+        let frames = frame_strs(msg)?;
+        Ok(ListRequest {
+            cert_type: frame_str(&frames, 0, Error::InvalidArg)?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListReply {
+    /// Possibly masked, depending on the caller
+    pub names: Vec<String>,
+}
+
+impl Reply for ListReply {
+    fn to_frames(&self, msg: &ZMsg) -> Result<()> {
+        for name in &self.names {
+            msg.addstr(name)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct LookupRequest {
+    pub name: String,
+}
+
+impl Request for LookupRequest {
+    fn endpoint() -> &'static Endpoint {
+        &protocol::CERT_LOOKUP
+    }
+
+    fn from_frames(msg: &ZMsg) -> Result<LookupRequest> {
+        let frames = frame_strs(msg)?;
+        Ok(LookupRequest {
+            name: frame_str(&frames, 0, Error::InvalidArg)?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LookupReply {
+    pub public_key: String,
+}
+
+impl Reply for LookupReply {
+    fn to_frames(&self, msg: &ZMsg) -> Result<()> {
+        msg.addstr(&self.public_key)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType};
+    use czmq::{ZMsg, ZSock, ZSys};
+    use protocol::typed_marker;
+    use serde_json::{self, Value};
+    use super::*;
+
+    #[test]
+    fn test_recv() {
+        ZSys::init();
+
+        let mut client = ZSock::new_push("inproc://messages_recv").unwrap();
+        let mut server = ZSock::new_pull("inproc://messages_recv").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        ZMsg::new().send_multi(&mut client, &["host", "web1"]).unwrap();
+        let (request, encoding, _) = recv::<CreateRequest>(&mut server).unwrap();
+        assert_eq!(request, CreateRequest { cert_type: "host".into(), name: "web1".into() });
+        assert_eq!(encoding, Encoding::Frames);
+
+        ZMsg::new().send_multi(&mut client, &[&typed_marker(), r#"{"type":"host","name":"web1"}"#]).unwrap();
+        let (request, encoding, _) = recv::<CreateRequest>(&mut server).unwrap();
+        assert_eq!(request.name, "web1");
+        assert_eq!(encoding, Encoding::Json);
+
+        // Too many frames, and JSON missing a field
+        ZMsg::new().send_multi(&mut client, &["web1", "web2"]).unwrap();
+        assert!(recv::<LookupRequest>(&mut server).is_err());
+        ZMsg::new().send_multi(&mut client, &[&typed_marker(), r#"{"type":"host"}"#]).unwrap();
+        assert!(recv::<CreateRequest>(&mut server).is_err());
+    }
+
+    #[test]
+    fn test_reply() {
+        ZSys::init();
+
+        let cert = Cert::new("web1", CertType::Host).unwrap();
+        let reply = CreateReply::new(&cert);
+
+        let msg = super::reply(&reply, Encoding::Frames, b"router_id").unwrap();
+        assert_eq!(msg.size(), 6);
+
+        let msg = super::reply(&reply, Encoding::Json, b"router_id").unwrap();
+        assert_eq!(msg.size(), 4);
+        msg.popstr();
+        msg.popstr();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "Ok");
+        let json: Value = serde_json::from_str(&msg.popstr().unwrap().unwrap()).unwrap();
+        assert_eq!(json["public_key"], Value::String(cert.public_txt().into()));
+        assert_eq!(json["metadata"]["name"], Value::String("web1".into()));
+        assert!(json.get("encoded_meta").is_none());
+    }
+}
@@ -0,0 +1,360 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! An Auth server for integration tests, which serves certs set by the
+//! test over the feed and answers API requests with scripted replies.
+//!
+//! Requires the `test-support` feature.
+
+use cert::{Cert, CertType};
+use cert_cache;
+use czmq::{SocketType, ZCert, ZMsg, ZPoller, ZSock, ZSys};
+use error::{Error, ErrorCode, Result};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread::{JoinHandle, spawn};
+use zap_handler::THREAD_TERM;
+
+struct State {
+    // Public key => type and encoded metadata
+    certs: BTreeMap<String, (CertType, Vec<u8>)>,
+    // Endpoint => replies, with their status
+    replies: HashMap<String, VecDeque<(bool, Vec<String>)>>,
+    requests: Vec<Vec<String>>,
+    // Changes to publish
+    outbox: Vec<Vec<Vec<u8>>>,
+}
+
+/// Stands in for the Auth server. The feed and API are CURVE servers
+/// using `cert()`, so a `ZapHandler` or `CertClient` can connect to it
+/// as they would the real server.
+///
+/// Only single-message snapshots of all certs or one cert type are
+/// served. Keys-only, batched and scoped subscribers, resume positions
+/// and heartbeats aren't supported.
+///
+/// Connections are authenticated by whatever ZAP handler the process
+/// runs. A `ZapHandler` subscribed to the mock needs `allow_self`, and
+/// `CertClient` certs must be added with `add_cert()` to be allowed.
+///
+/// ```text
+/// let server = MockAuthServer::new("tcp://127.0.0.1:7102", "tcp://127.0.0.1:7101")?;
+/// server.add_cert(&host_cert)?;
+/// server.reply_ok("cert::lookup", &[host_cert.public_txt()]);
+/// let handler = ZapHandler::new(None, &cert, server.cert(), "127.0.0.1", 7102, true)?;
+/// ```
+pub struct MockAuthServer {
+    worker: Option<JoinHandle<()>>,
+    thread_comm: ZSock,
+    state: Arc<Mutex<State>>,
+    cert: ZCert,
+}
+
+impl Drop for MockAuthServer {
+    fn drop(&mut self) {
+        // Ignore failure as it means the thread has already
+        // terminated.
+        let _ = self.thread_comm.send_str(THREAD_TERM);
+        if let Some(h) = self.worker.take() {
+            h.join().unwrap();
+        }
+    }
+}
+
+impl MockAuthServer {
+    /// Bind the feed and API to these endpoints, which may be inproc
+    /// or tcp. `ZapHandler` only connects over tcp.
+    pub fn new(feed_endpoint: &str, api_endpoint: &str) -> Result<MockAuthServer> {
+        let cert = try!(ZCert::new());
+
+        let mut feed = ZSock::new(SocketType::XPUB);
+        feed.set_xpub_verbose(true);
+        feed.set_zap_domain("auth.intecture");
+        feed.set_curve_server(true);
+        cert.apply(&mut feed);
+        feed.set_linger(0);
+        try!(feed.bind(feed_endpoint));
+
+        let mut api = ZSock::new(SocketType::ROUTER);
+        api.set_zap_domain("auth.intecture");
+        api.set_curve_server(true);
+        cert.apply(&mut api);
+        api.set_linger(0);
+        try!(api.bind(api_endpoint));
+
+        let (comm, comm_child) = try!(ZSys::create_pipe());
+        comm.set_linger(0);
+        comm_child.set_linger(0);
+
+        let state = Arc::new(Mutex::new(State {
+            certs: BTreeMap::new(),
+            replies: HashMap::new(),
+            requests: Vec::new(),
+            outbox: Vec::new(),
+        }));
+        let worker_state = state.clone();
+
+        Ok(MockAuthServer {
+            worker: Some(spawn(move || {
+                if let Err(_e) = run(feed, api, comm_child, worker_state) {
+                    error!("Mock Auth server Error: {:?}", _e);
+                }
+            })),
+            thread_comm: comm,
+            state: state,
+            cert: cert,
+        })
+    }
+
+    /// The server's cert, whose public key clients need.
+    pub fn cert(&self) -> &ZCert {
+        &self.cert
+    }
+
+    /// Serve `cert` to new subscribers, and publish it to existing
+    /// ones.
+    pub fn add_cert(&self, cert: &Cert) -> Result<()> {
+        let meta = cert.encode_meta();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.certs.insert(cert.public_txt().to_string(), (cert.cert_type(), meta.clone()));
+            state.outbox.push(vec![cert.cert_type().to_str().into(), b"ADD".to_vec(), cert.public_txt().into(), meta]);
+        }
+        self.wake()
+    }
+
+    /// Stop serving the cert with this public key, publishing a DEL if
+    /// it was added.
+    pub fn delete_cert(&self, public_key: &str) -> Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            match state.certs.remove(public_key) {
+                Some((cert_type, _)) => state.outbox.push(vec![cert_type.to_str().into(), b"DEL".to_vec(), public_key.into()]),
+                None => return Ok(()),
+            }
+        }
+        self.wake()
+    }
+
+    /// Answer the next request to `endpoint`, e.g. "cert::lookup", with
+    /// "Ok" and `frames`. Replies to an endpoint are used in the order
+    /// they were added.
+    pub fn reply_ok(&self, endpoint: &str, frames: &[&str]) {
+        self.script(endpoint, true, frames.iter().map(|f| f.to_string()).collect());
+    }
+
+    /// Answer the next request to `endpoint` with "Err", `code` and
+    /// `error`.
+    pub fn reply_err(&self, endpoint: &str, code: ErrorCode, error: &str) {
+        self.script(endpoint, false, vec![code.as_str().to_string(), error.to_string()]);
+    }
+
+    /// API requests received so far, oldest first, each starting with
+    /// its endpoint. Frames that aren't UTF-8 are converted lossily.
+    pub fn requests(&self) -> Vec<Vec<String>> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn clear_requests(&self) {
+        self.state.lock().unwrap().requests.clear();
+    }
+
+    fn script(&self, endpoint: &str, ok: bool, frames: Vec<String>) {
+        self.state.lock().unwrap().replies.entry(endpoint.to_string()).or_insert_with(VecDeque::new).push_back((ok, frames));
+    }
+
+    fn wake(&self) -> Result<()> {
+        try!(self.thread_comm.send_str("$WAKE"));
+        Ok(())
+    }
+}
+
+fn run(mut feed: ZSock, mut api: ZSock, mut comm: ZSock, state: Arc<Mutex<State>>) -> Result<()> {
+    let mut poller = try!(ZPoller::new());
+    try!(poller.add(&mut feed));
+    try!(poller.add(&mut api));
+    try!(poller.add(&mut comm));
+
+    loop {
+        let sock: Option<ZSock> = poller.wait(None);
+        if let Some(mut sock) = sock {
+            if sock == feed {
+                let msg = try!(ZMsg::recv(&mut sock));
+                let frame = match msg.popbytes() {
+                    Ok(Some(f)) => f,
+                    _ => continue,
+                };
+                // Only send certs on subscribe, as the real server does
+                if let Some((&1, topic)) = frame.split_first() {
+                    let cert_type = match topic {
+                        b"" => None,
+                        b"host" => Some(CertType::Host),
+                        b"user" => Some(CertType::User),
+                        _ => continue,
+                    };
+                    let topic = cert_type.map(|t| t.to_str()).unwrap_or("");
+                    try!(try!(cert_cache::snapshot_notice(topic)).send(&mut feed));
+                    let snapshot = try!(snapshot(&state.lock().unwrap(), cert_type));
+                    try!(snapshot.send(&mut feed));
+                }
+            }
+            else if sock == api {
+                let msg = try!(ZMsg::recv(&mut sock));
+                let router_id = try!(msg.popbytes()).unwrap_or(Vec::new());
+                // Empty delimiter from REQ sockets
+                let _ = try!(msg.popbytes());
+                let mut request_id = None;
+                let mut request = Vec::new();
+                while let Some(frame) = try!(msg.popbytes()) {
+                    let frame = String::from_utf8_lossy(&frame).into_owned();
+                    if request.is_empty() && request_id.is_none() && frame.starts_with("rid:") {
+                        request_id = Some(frame);
+                    } else {
+                        request.push(frame);
+                    }
+                }
+
+                let (ok, frames) = {
+                    let mut state = state.lock().unwrap();
+                    let endpoint = request.first().cloned().unwrap_or(String::new());
+                    state.requests.push(request);
+                    match state.replies.get_mut(&endpoint).and_then(|r| r.pop_front()) {
+                        Some(reply) => reply,
+                        None => (false, vec![ErrorCode::Internal.as_str().to_string(), format!("No scripted reply for {}", endpoint)]),
+                    }
+                };
+
+                let reply = ZMsg::new();
+                try!(reply.addbytes(&router_id));
+                try!(reply.addstr(""));
+                if let Some(ref id) = request_id {
+                    try!(reply.addstr(id));
+                }
+                try!(reply.addstr(if ok { "Ok" } else { "Err" }));
+                for frame in &frames {
+                    try!(reply.addstr(frame));
+                }
+                try!(reply.send(&mut api));
+            }
+            else if sock == comm {
+                if try!(comm.recv_str()).unwrap_or(String::new()) == THREAD_TERM {
+                    break;
+                }
+                let outbox: Vec<_> = state.lock().unwrap().outbox.drain(..).collect();
+                for frames in outbox {
+                    let msg = ZMsg::new();
+                    for frame in &frames {
+                        try!(msg.addbytes(frame));
+                    }
+                    try!(msg.send(&mut feed));
+                }
+            }
+        }
+
+        if poller.expired() {
+            return Err(Error::PollerTimeout);
+        }
+        else if poller.terminated() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Subscribers wait for a snapshot before reporting ready, so one is
+// sent even without any certs.
+fn snapshot(state: &State, cert_type: Option<CertType>) -> Result<ZMsg> {
+    let msg = ZMsg::new();
+    try!(msg.addstr(cert_type.map(|t| t.to_str()).unwrap_or("")));
+    try!(msg.addstr("ADD"));
+    for (public_key, &(t, ref meta)) in &state.certs {
+        if cert_type.map(|c| c == t).unwrap_or(true) {
+            try!(msg.addstr(public_key));
+            try!(msg.addbytes(meta));
+        }
+    }
+    Ok(msg)
+}
+
+#[cfg(all(test, feature = "test-support"))]
+mod tests {
+    use cert::{Cert, CertType};
+    use cert_client::CertClient;
+    use czmq::{SocketType, ZCert, ZMsg, ZSock, ZSys};
+    use error::Error;
+    use super::*;
+
+    #[test]
+    fn test_feed() {
+        ZSys::init();
+
+        let server = MockAuthServer::new("inproc://mock_auth_test_feed", "inproc://mock_auth_test_feed_api").unwrap();
+        let host = Cert::new("web1", CertType::Host).unwrap();
+        let user = Cert::new("alice", CertType::User).unwrap();
+        server.add_cert(&host).unwrap();
+        server.add_cert(&user).unwrap();
+
+        let mut subscriber = ZSock::new(SocketType::SUB);
+        subscriber.set_curve_serverkey(server.cert().public_txt());
+        ZCert::new().unwrap().apply(&mut subscriber);
+        subscriber.set_rcvtimeo(Some(500));
+        subscriber.connect("inproc://mock_auth_test_feed").unwrap();
+        subscriber.set_subscribe("host");
+
+        let notice = ZMsg::recv(&mut subscriber).unwrap();
+        assert_eq!(notice.popstr().unwrap().unwrap(), "host");
+        assert_eq!(notice.popstr().unwrap().unwrap(), "SNAPSHOT");
+        let snapshot = ZMsg::recv(&mut subscriber).unwrap();
+        assert_eq!(snapshot.popstr().unwrap().unwrap(), "host");
+        assert_eq!(snapshot.popstr().unwrap().unwrap(), "ADD");
+        assert_eq!(snapshot.popstr().unwrap().unwrap(), host.public_txt());
+        assert_eq!(snapshot.size(), 1);
+
+        server.delete_cert(host.public_txt()).unwrap();
+        let del = ZMsg::recv(&mut subscriber).unwrap();
+        assert_eq!(del.popstr().unwrap().unwrap(), "host");
+        assert_eq!(del.popstr().unwrap().unwrap(), "DEL");
+        assert_eq!(del.popstr().unwrap().unwrap(), host.public_txt());
+    }
+
+    #[test]
+    fn test_api() {
+        ZSys::init();
+
+        let server = MockAuthServer::new("inproc://mock_auth_test_api_feed", "inproc://mock_auth_test_api").unwrap();
+        let mut client = CertClient::new("inproc://mock_auth_test_api", server.cert().public_txt(), ZCert::new().unwrap());
+        client.set_timeout(::std::time::Duration::from_millis(500));
+
+        server.reply_ok("cert::list", &["web1", "web2"]);
+        server.reply_err("cert::delete", ErrorCode::Forbidden, "Permission denied");
+        assert_eq!(client.list(CertType::Host).unwrap(), vec!["web1", "web2"]);
+        match client.delete("web1") {
+            Err(Error::Api(code, e)) => {
+                assert_eq!(code, ErrorCode::Forbidden);
+                assert_eq!(e, "Permission denied");
+            },
+            _ => panic!("Expected API error"),
+        }
+        // Replies are used up
+        assert!(client.list(CertType::Host).is_err());
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0], vec!["cert::list", "host"]);
+        assert_eq!(requests[1], vec!["cert::delete", "web1"]);
+
+        server.clear_requests();
+        assert!(server.requests().is_empty());
+
+        // Request IDs are echoed, and left out of `requests()`
+        server.reply_ok("cert::lookup", &["key"]);
+        assert_eq!(client.request_with_id("agent-1", &["cert::lookup", "web1"]).unwrap(), vec![b"key".to_vec()]);
+        assert_eq!(server.requests()[0], vec!["cert::lookup", "web1"]);
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! A ZAP handler for unit tests, which answers from rules set by the
+//! test instead of the Auth server's feed.
+//!
+//! Requires the `test-support` feature.
+
+use czmq::{ZCert, ZMsg, ZPoller, ZSock, ZSys};
+use error::{Error, Result};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread::{JoinHandle, spawn};
+use timeouts;
+use zap_handler::{self, ZapStatus, DEFAULT_DENIED_TEXT, THREAD_TERM, ZAP_ENDPOINT};
+
+/// A ZAP request received by a `MockZapHandler`, and how it was
+/// answered.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockRequest {
+    pub domain: String,
+    pub address: String,
+    pub mechanism: String,
+    /// Public key for CURVE, username for PLAIN
+    pub client_id: String,
+    pub password: Option<String>,
+    pub allowed: bool,
+}
+
+struct Rules {
+    // Client IDs to allow, with the identity to reply with
+    allowed: HashMap<String, ZCert>,
+    denied: HashSet<String>,
+    allow_by_default: bool,
+    denied_text: String,
+    requests: Vec<MockRequest>,
+}
+
+/// Stands in for `ZapHandler`. Like the real handler, it serves the
+/// process-wide ZAP endpoint, so only one of them may exist at a time.
+///
+/// Clients are denied unless allowed by `allow()`, `allow_cert()` or
+/// `allow_all()`. `deny()` takes precedence over all of them. PLAIN
+/// passwords are recorded but not checked.
+pub struct MockZapHandler {
+    worker: Option<JoinHandle<()>>,
+    thread_comm: ZSock,
+    rules: Arc<Mutex<Rules>>,
+}
+
+impl Drop for MockZapHandler {
+    fn drop(&mut self) {
+        // Ignore failure as it means the thread has already
+        // terminated.
+        let _ = self.thread_comm.send_str(THREAD_TERM);
+        if let Some(h) = self.worker.take() {
+            h.join().unwrap();
+        }
+    }
+}
+
+impl MockZapHandler {
+    pub fn new() -> Result<MockZapHandler> {
+        Self::bind(ZAP_ENDPOINT)
+    }
+
+    fn bind(endpoint: &str) -> Result<MockZapHandler> {
+        let zap = try!(ZSock::new_rep(endpoint));
+        zap.set_linger(0);
+
+        let (comm, comm_child) = try!(ZSys::create_pipe());
+        comm.set_linger(0);
+        comm_child.set_linger(0);
+
+        let rules = Arc::new(Mutex::new(Rules {
+            allowed: HashMap::new(),
+            denied: HashSet::new(),
+            allow_by_default: false,
+            denied_text: DEFAULT_DENIED_TEXT.to_string(),
+            requests: Vec::new(),
+        }));
+        let worker_rules = rules.clone();
+
+        Ok(MockZapHandler {
+            worker: Some(spawn(move || {
+                if let Err(_e) = run(zap, comm_child, worker_rules) {
+                    error!("Mock ZAP Error: {:?}", _e);
+                }
+            })),
+            thread_comm: comm,
+            rules: rules,
+        })
+    }
+
+    /// Allow the client with this public key or PLAIN username. The
+    /// reply's User-Id is the client ID, with no other properties.
+    pub fn allow(&self, client_id: &str) -> Result<()> {
+        let identity = try!(zap_handler::named_identity(client_id));
+        self.rules.lock().unwrap().allowed.insert(client_id.to_string(), identity);
+        Ok(())
+    }
+
+    /// Allow `cert`'s public key, replying with its name and metadata
+    /// as the real handler would.
+    pub fn allow_cert(&self, cert: &ZCert) {
+        self.rules.lock().unwrap().allowed.insert(cert.public_txt().to_string(), cert.dup());
+    }
+
+    pub fn deny(&self, client_id: &str) {
+        self.rules.lock().unwrap().denied.insert(client_id.to_string());
+    }
+
+    /// Allow every client that isn't explicitly denied.
+    pub fn allow_all(&self, allow: bool) {
+        self.rules.lock().unwrap().allow_by_default = allow;
+    }
+
+    pub fn set_denied_text(&self, text: &str) -> Result<()> {
+        try!(zap_handler::validate_status_text(text));
+        self.rules.lock().unwrap().denied_text = text.to_string();
+        Ok(())
+    }
+
+    /// Requests received so far, oldest first.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.rules.lock().unwrap().requests.clone()
+    }
+
+    pub fn clear_requests(&self) {
+        self.rules.lock().unwrap().requests.clear();
+    }
+}
+
+fn run(mut zap: ZSock, mut comm: ZSock, rules: Arc<Mutex<Rules>>) -> Result<()> {
+    let mut poller = try!(ZPoller::new());
+    try!(poller.add(&mut zap));
+    try!(poller.add(&mut comm));
+
+    loop {
+        let sock: Option<ZSock> = poller.wait(None);
+        if let Some(mut sock) = sock {
+            if sock == zap {
+                let msg = try!(timeouts::recv(&mut sock));
+                let frames = match try!(zap_handler::parse_request(&mut zap, &msg)) {
+                    Some(frames) => frames,
+                    None => continue,
+                };
+
+                let mut rules = rules.lock().unwrap();
+                let identity = if rules.denied.contains(&frames.client_id) {
+                    None
+                } else if let Some(cert) = rules.allowed.get(&frames.client_id) {
+                    Some(cert.dup())
+                } else if rules.allow_by_default {
+                    Some(try!(zap_handler::named_identity(&frames.client_id)))
+                } else {
+                    None
+                };
+
+                rules.requests.push(MockRequest {
+                    domain: frames.domain.clone(),
+                    address: frames.address.clone(),
+                    mechanism: frames.mechanism.clone(),
+                    client_id: frames.client_id.clone(),
+                    password: frames.password.clone(),
+                    allowed: identity.is_some(),
+                });
+
+                try!(zap_handler::send_reply(&mut zap, &frames.sequence, identity.as_ref(), None, ZapStatus::Denied, &rules.denied_text));
+            }
+            else if sock == comm && try!(comm.recv_str()).unwrap_or(String::new()) == THREAD_TERM {
+                break;
+            }
+        }
+
+        if poller.expired() {
+            return Err(Error::PollerTimeout);
+        }
+        else if poller.terminated() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "test-support"))]
+mod tests {
+    use czmq::{ZCert, ZMsg, ZSock, ZSys};
+    use super::*;
+
+    #[test]
+    fn test_rules() {
+        ZSys::init();
+
+        let handler = MockZapHandler::bind("inproc://mock_zap_test_rules").unwrap();
+        let mut zap = ZSock::new_req("inproc://mock_zap_test_rules").unwrap();
+        zap.set_sndtimeo(Some(500));
+        zap.set_rcvtimeo(Some(500));
+
+        let cert = ZCert::new().unwrap();
+        cert.set_meta("name", "web1");
+
+        assert_eq!(request(&mut zap, &cert), "400");
+        handler.allow_cert(&cert);
+        assert_eq!(request(&mut zap, &cert), "200");
+        handler.deny(cert.public_txt());
+        handler.set_denied_text("Revoked").unwrap();
+        assert_eq!(request(&mut zap, &cert), "400");
+
+        let other = ZCert::new().unwrap();
+        handler.allow_all(true);
+        assert_eq!(request(&mut zap, &other), "200");
+
+        let requests = handler.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[0].client_id, cert.public_txt());
+        assert_eq!(requests[0].mechanism, "CURVE");
+        assert_eq!(requests.iter().map(|r| r.allowed).collect::<Vec<_>>(), vec![false, true, false, true]);
+
+        handler.clear_requests();
+        assert!(handler.requests().is_empty());
+    }
+
+    fn request(zap: &mut ZSock, cert: &ZCert) -> String {
+        let msg = ZMsg::new();
+        msg.addstr("1.0").unwrap();
+        msg.addstr("1").unwrap();
+        msg.addstr("test-domain").unwrap();
+        msg.addstr("127.0.0.1").unwrap();
+        msg.addstr("").unwrap();
+        msg.addstr("CURVE").unwrap();
+        msg.addbytes(cert.public_key()).unwrap();
+        msg.send(zap).unwrap();
+
+        let reply = ZMsg::recv(zap).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap()
+    }
+}
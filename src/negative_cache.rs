@@ -0,0 +1,135 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Short-lived memory of public keys that failed authentication.
+//!
+//! A misconfigured agent reconnects as fast as ZeroMQ lets it, and each
+//! attempt would otherwise go through the whole authentication path
+//! again. Keys found here are denied straight away, for the same
+//! reason as before, until their entry expires or the feed adds or
+//! updates a cert.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use zap_handler::DenyReason;
+
+// Forget expired entries once we are tracking this many
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NegativeCacheStats {
+    /// Requests denied from the negative cache
+    pub hits: u64,
+    /// Keys added after failing authentication
+    pub inserts: u64,
+    /// Times the cache was emptied because the feed added or updated
+    /// certs
+    pub flushes: u64,
+    /// Keys currently cached
+    pub entries: usize,
+}
+
+pub struct NegativeCache {
+    ttl: Duration,
+    // When each key was denied, and why
+    entries: HashMap<String, (Instant, DenyReason)>,
+    stats: Arc<Mutex<NegativeCacheStats>>,
+}
+
+impl NegativeCache {
+    /// A zero `ttl` disables the cache.
+    pub fn new(ttl: Duration) -> NegativeCache {
+        NegativeCache {
+            ttl: ttl,
+            entries: HashMap::new(),
+            stats: Arc::new(Mutex::new(NegativeCacheStats::default())),
+        }
+    }
+
+    /// A handle to the stats, which the cache keeps up to date.
+    pub fn stats(&self) -> Arc<Mutex<NegativeCacheStats>> {
+        self.stats.clone()
+    }
+
+    /// Why `client_pk` was denied, if it failed recently. Counts a hit
+    /// if so.
+    pub fn get(&mut self, client_pk: &str, now: Instant) -> Option<DenyReason> {
+        let ttl = self.ttl;
+        let hit = match self.entries.get(client_pk) {
+            Some(&(added, reason)) if now.duration_since(added) < ttl => Some(reason),
+            _ => None,
+        };
+
+        if hit.is_some() {
+            self.stats.lock().unwrap().hits += 1;
+        }
+        hit
+    }
+
+    pub fn insert(&mut self, client_pk: &str, reason: DenyReason, now: Instant) {
+        if self.ttl == Duration::from_secs(0) {
+            return;
+        }
+
+        if self.entries.len() >= MAX_ENTRIES {
+            let ttl = self.ttl;
+            self.entries.retain(|_, &mut (added, _)| now.duration_since(added) < ttl);
+        }
+        // Still full of live entries, so it's a storm of distinct keys
+        // that caching won't help with
+        if self.entries.len() < MAX_ENTRIES {
+            self.entries.insert(client_pk.to_string(), (now, reason));
+        }
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.inserts += 1;
+        stats.entries = self.entries.len();
+    }
+
+    /// Forget every key, e.g. because the feed may have added a cert
+    /// for one of them, or re-enabled one.
+    pub fn flush(&mut self) {
+        if !self.entries.is_empty() {
+            self.entries.clear();
+            let mut stats = self.stats.lock().unwrap();
+            stats.flushes += 1;
+            stats.entries = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::*;
+    use zap_handler::DenyReason;
+
+    #[test]
+    fn test_negative_cache() {
+        let mut cache = NegativeCache::new(Duration::from_secs(5));
+        let stats = cache.stats();
+        let now = Instant::now();
+
+        assert_eq!(cache.get("pk", now), None);
+        cache.insert("pk", DenyReason::Revoked, now);
+        cache.insert("other", DenyReason::UnknownKey, now);
+        assert_eq!(cache.get("pk", now + Duration::from_secs(4)), Some(DenyReason::Revoked));
+        assert_eq!(cache.get("other", now), Some(DenyReason::UnknownKey));
+        assert_eq!(cache.get("pk", now + Duration::from_secs(5)), None);
+        assert_eq!(cache.get("another", now), None);
+
+        cache.flush();
+        assert_eq!(cache.get("pk", now), None);
+        assert_eq!(*stats.lock().unwrap(), NegativeCacheStats { hits: 2, inserts: 2, flushes: 1, entries: 0 });
+
+        let mut disabled = NegativeCache::new(Duration::from_secs(0));
+        disabled.insert("pk", DenyReason::UnknownKey, now);
+        assert_eq!(disabled.get("pk", now), None);
+    }
+}
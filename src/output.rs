@@ -0,0 +1,115 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Machine readable CLI output, for scripts and configuration
+//! management.
+//!
+//! YAML is written in block style, with every string double quoted
+//! (and escaped as in JSON), so values never need YAML's type guessing.
+
+use error::{Error, Result};
+use serde::Serialize;
+use serde_json::{self, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// For people. Each command prints its own.
+    Text,
+    Json,
+    Yaml,
+}
+
+impl OutputFormat {
+    pub fn from_str(format: &str) -> Result<OutputFormat> {
+        match format {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "yaml" => Ok(OutputFormat::Yaml),
+            _ => Err(Error::InvalidArg),
+        }
+    }
+
+    /// Render `value` as JSON or YAML. Text output is up to the caller.
+    pub fn render<T: Serialize>(&self, value: &T) -> Result<String> {
+        let value = serde_json::to_value(value)?;
+        match *self {
+            OutputFormat::Yaml => {
+                let mut out = String::new();
+                write_yaml(&mut out, &value, 0);
+                Ok(out.trim_right().to_string())
+            },
+            _ => Ok(serde_json::to_string_pretty(&value)?),
+        }
+    }
+}
+
+// Scalars and empty collections go on their key's line
+fn is_inline(value: &Value) -> bool {
+    match *value {
+        Value::Array(ref a) => a.is_empty(),
+        Value::Object(ref o) => o.is_empty(),
+        _ => true,
+    }
+}
+
+fn inline(value: &Value) -> String {
+    match *value {
+        Value::Array(_) => "[]".into(),
+        Value::Object(_) => "{}".into(),
+        Value::Null => "null".into(),
+        ref v => serde_json::to_string(v).unwrap_or(String::new()),
+    }
+}
+
+fn write_yaml(out: &mut String, value: &Value, indent: usize) {
+    let pad = " ".repeat(indent);
+    match *value {
+        Value::Object(ref map) if !map.is_empty() => {
+            for (key, v) in map {
+                let key = serde_json::to_string(key).unwrap_or(String::new());
+                if is_inline(v) {
+                    out.push_str(&format!("{}{}: {}\n", pad, key, inline(v)));
+                } else {
+                    out.push_str(&format!("{}{}:\n", pad, key));
+                    write_yaml(out, v, indent + 2);
+                }
+            }
+        },
+        Value::Array(ref items) if !items.is_empty() => {
+            for item in items {
+                if is_inline(item) {
+                    out.push_str(&format!("{}- {}\n", pad, inline(item)));
+                } else {
+                    // Nested collections start on the dash's line
+                    let mut nested = String::new();
+                    write_yaml(&mut nested, item, indent + 2);
+                    out.push_str(&format!("{}- {}", pad, &nested[indent + 2..]));
+                }
+            }
+        },
+        ref v => out.push_str(&format!("{}{}\n", pad, inline(v))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let value: Value = ::serde_json::from_str(r#"{"certs": [{"name": "web1", "tags": []}, "dan"], "count": 2, "note": "a: \"b\"", "none": null}"#).unwrap();
+        assert_eq!(OutputFormat::Yaml.render(&value).unwrap(),
+                   "\"certs\":\n  - \"name\": \"web1\"\n    \"tags\": []\n  - \"dan\"\n\"count\": 2\n\"none\": null\n\"note\": \"a: \\\"b\\\"\"");
+        assert_eq!(OutputFormat::Yaml.render(&vec!["a", "b"]).unwrap(), "- \"a\"\n- \"b\"");
+        assert_eq!(OutputFormat::Json.render(&vec!["a"]).unwrap(), "[\n  \"a\"\n]");
+
+        assert_eq!(OutputFormat::from_str("yaml").unwrap(), OutputFormat::Yaml);
+        assert!(OutputFormat::from_str("xml").is_err());
+    }
+}
@@ -0,0 +1,343 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Username/password verification for the ZAP `PLAIN` mechanism.
+
+use error::{Error, Result};
+#[cfg(feature = "pam")]
+use libc::{self, c_int, c_void};
+#[cfg(any(feature = "htpasswd", feature = "pam"))]
+use libc::c_char;
+#[cfg(feature = "htpasswd")]
+use std::collections::HashMap;
+#[cfg(feature = "htpasswd")]
+use std::ffi::CStr;
+#[cfg(any(feature = "htpasswd", feature = "pam"))]
+use std::ffi::CString;
+#[cfg(feature = "htpasswd")]
+use std::fs::File;
+#[cfg(feature = "htpasswd")]
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+#[cfg(feature = "pam")]
+use std::ptr;
+#[cfg(any(feature = "htpasswd", feature = "pam"))]
+use std::sync::Mutex;
+
+/// Checks `PLAIN` credentials. Implement this to authenticate against
+/// an LDAP bind or anything else.
+pub trait PlainVerifier: Send {
+    fn verify(&self, username: &str, password: &str) -> bool;
+}
+
+/// Verifies against an htpasswd-style file of `username:hash` lines.
+///
+/// Hashes are checked with the system's crypt(3), so anything it
+/// supports works, e.g. SHA-512 (`htpasswd -2` or `mkpasswd -m
+/// sha-512`) and, with libxcrypt, bcrypt (`htpasswd -B`). Apache's
+/// `$apr1$` and `{SHA}` formats are not supported.
+#[cfg(feature = "htpasswd")]
+pub struct HtpasswdVerifier {
+    users: HashMap<String, String>,
+}
+
+#[cfg(feature = "htpasswd")]
+impl HtpasswdVerifier {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<HtpasswdVerifier> {
+        let fh = try!(File::open(path));
+        let mut users = HashMap::new();
+
+        for line in BufReader::new(fh).lines() {
+            let line = try!(line);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(user), Some(hash)) if !user.is_empty() && !hash.is_empty() => {
+                    if hash.starts_with("$apr1$") || hash.starts_with("{SHA}") {
+                        warn!("Ignoring unsupported password hash for {}", user);
+                        continue;
+                    }
+                    users.insert(user.to_string(), hash.to_string());
+                },
+                _ => return Err(Error::InvalidPasswordFile),
+            }
+        }
+
+        Ok(HtpasswdVerifier {
+            users: users,
+        })
+    }
+}
+
+#[cfg(feature = "htpasswd")]
+impl PlainVerifier for HtpasswdVerifier {
+    fn verify(&self, username: &str, password: &str) -> bool {
+        match self.users.get(username) {
+            Some(hash) => crypt_matches(password, hash),
+            None => false,
+        }
+    }
+}
+
+/// Stand-in for builds without the `htpasswd` feature, which can't be
+/// created.
+#[cfg(not(feature = "htpasswd"))]
+pub struct HtpasswdVerifier;
+
+#[cfg(not(feature = "htpasswd"))]
+impl HtpasswdVerifier {
+    pub fn new<P: AsRef<Path>>(_: P) -> Result<HtpasswdVerifier> {
+        Err(Error::InvalidConfig("inauth was built without the \"htpasswd\" feature".into()))
+    }
+}
+
+#[cfg(not(feature = "htpasswd"))]
+impl PlainVerifier for HtpasswdVerifier {
+    fn verify(&self, _: &str, _: &str) -> bool {
+        false
+    }
+}
+
+/// Verifies against the host's accounts through PAM, using the PAM
+/// service's stack (`/etc/pam.d/<service>`), including account checks
+/// such as expiry.
+///
+/// With `pam_unix`, the server can only check other users' passwords if
+/// it can read `/etc/shadow`, e.g. as a member of the "shadow" group.
+/// Modules may also delay failures by a few seconds, which holds up the
+/// ZAP worker; `nodelay` turns this off for `pam_unix`.
+#[cfg(feature = "pam")]
+pub struct PamVerifier {
+    service: CString,
+}
+
+#[cfg(feature = "pam")]
+impl PamVerifier {
+    /// `service` names the PAM config to use, e.g. "inauth".
+    pub fn new(service: &str) -> Result<PamVerifier> {
+        Ok(PamVerifier {
+            service: try!(CString::new(service).or(Err(Error::InvalidConfig(format!("invalid PAM service \"{}\"", service))))),
+        })
+    }
+}
+
+#[cfg(feature = "pam")]
+impl PlainVerifier for PamVerifier {
+    fn verify(&self, username: &str, password: &str) -> bool {
+        let (user, password) = match (CString::new(username), CString::new(password)) {
+            (Ok(u), Ok(p)) => (u, p),
+            _ => return false,
+        };
+
+        let conv = PamConv {
+            conv: converse,
+            appdata_ptr: password.as_ptr() as *mut c_void,
+        };
+        // Not every module is thread safe
+        let _lock = PAM_LOCK.lock().unwrap();
+        let mut handle = ptr::null_mut();
+        let mut status = unsafe { pam_start(self.service.as_ptr(), user.as_ptr(), &conv, &mut handle) };
+        if status != PAM_SUCCESS {
+            warn!("Could not start PAM: error {}", status);
+            return false;
+        }
+        status = unsafe { pam_authenticate(handle, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK) };
+        if status == PAM_SUCCESS {
+            status = unsafe { pam_acct_mgmt(handle, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK) };
+        }
+        unsafe { pam_end(handle, status) };
+        status == PAM_SUCCESS
+    }
+}
+
+/// Stand-in for builds without the `pam` feature, which can't be
+/// created.
+#[cfg(not(feature = "pam"))]
+pub struct PamVerifier;
+
+#[cfg(not(feature = "pam"))]
+impl PamVerifier {
+    pub fn new(_: &str) -> Result<PamVerifier> {
+        Err(Error::InvalidConfig("inauth was built without the \"pam\" feature".into()))
+    }
+}
+
+#[cfg(not(feature = "pam"))]
+impl PlainVerifier for PamVerifier {
+    fn verify(&self, _: &str, _: &str) -> bool {
+        false
+    }
+}
+
+#[cfg(feature = "pam")]
+const PAM_SUCCESS: c_int = 0;
+#[cfg(feature = "pam")]
+const PAM_BUF_ERR: c_int = 5;
+#[cfg(feature = "pam")]
+const PAM_CONV_ERR: c_int = 19;
+#[cfg(feature = "pam")]
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+#[cfg(feature = "pam")]
+const PAM_SILENT: c_int = 0x8000;
+#[cfg(feature = "pam")]
+const PAM_DISALLOW_NULL_AUTHTOK: c_int = 0x0001;
+
+#[cfg(feature = "pam")]
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[cfg(feature = "pam")]
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+#[cfg(feature = "pam")]
+#[repr(C)]
+struct PamConv {
+    conv: extern "C" fn(c_int, *mut *const PamMessage, *mut *mut PamResponse, *mut c_void) -> c_int,
+    appdata_ptr: *mut c_void,
+}
+
+#[cfg(feature = "pam")]
+enum PamHandle {}
+
+#[cfg(feature = "pam")]
+#[link(name = "pam")]
+extern "C" {
+    fn pam_start(service: *const c_char, user: *const c_char, conv: *const PamConv, handle: *mut *mut PamHandle) -> c_int;
+    fn pam_authenticate(handle: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_acct_mgmt(handle: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_end(handle: *mut PamHandle, status: c_int) -> c_int;
+}
+
+#[cfg(feature = "pam")]
+static PAM_LOCK: Mutex<()> = Mutex::new(());
+
+// Answers every hidden prompt with the password in `appdata`, which
+// is all a non-interactive login can do. Other prompts and messages
+// get empty replies. PAM frees the replies with free().
+#[cfg(feature = "pam")]
+extern "C" fn converse(num_msg: c_int, msg: *mut *const PamMessage, resp: *mut *mut PamResponse, appdata: *mut c_void) -> c_int {
+    if num_msg <= 0 || msg.is_null() || resp.is_null() {
+        return PAM_CONV_ERR;
+    }
+
+    let replies = unsafe { libc::calloc(num_msg as usize, ::std::mem::size_of::<PamResponse>()) } as *mut PamResponse;
+    if replies.is_null() {
+        return PAM_BUF_ERR;
+    }
+    for i in 0..num_msg as isize {
+        // Linux-PAM passes an array of pointers
+        let message = unsafe { *msg.offset(i) };
+        if !message.is_null() && unsafe { (*message).msg_style } == PAM_PROMPT_ECHO_OFF {
+            unsafe { (*replies.offset(i)).resp = libc::strdup(appdata as *const c_char) };
+        }
+    }
+    unsafe { *resp = replies };
+    PAM_SUCCESS
+}
+
+#[cfg(feature = "htpasswd")]
+#[link(name = "crypt")]
+extern "C" {
+    fn crypt(key: *const c_char, salt: *const c_char) -> *mut c_char;
+}
+
+// crypt() returns a pointer to a static buffer
+#[cfg(feature = "htpasswd")]
+static CRYPT_LOCK: Mutex<()> = Mutex::new(());
+
+#[cfg(feature = "htpasswd")]
+fn crypt_matches(password: &str, hash: &str) -> bool {
+    let (key, salt) = match (CString::new(password), CString::new(hash)) {
+        (Ok(k), Ok(s)) => (k, s),
+        _ => return false,
+    };
+
+    let _lock = CRYPT_LOCK.lock().unwrap();
+    let result = unsafe { crypt(key.as_ptr(), salt.as_ptr()) };
+    if result.is_null() {
+        return false;
+    }
+    let result = unsafe { CStr::from_ptr(result) }.to_bytes();
+
+    // Compare in constant time. Failed hashes start with "*", so
+    // they never match.
+    result.len() == hash.len() &&
+        result.iter().zip(hash.as_bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(all(test, any(feature = "htpasswd", feature = "pam")))]
+mod tests {
+    #[cfg(feature = "htpasswd")]
+    use std::fs::File;
+    #[cfg(feature = "htpasswd")]
+    use std::io::Write;
+    #[cfg(feature = "htpasswd")]
+    use super::*;
+    #[cfg(feature = "htpasswd")]
+    use tempdir::TempDir;
+
+    #[cfg(feature = "pam")]
+    #[test]
+    fn test_converse() {
+        use libc::{self, c_void};
+        use std::ffi::{CStr, CString};
+        use std::ptr;
+        use super::{converse, PamMessage, PamResponse, PAM_PROMPT_ECHO_OFF, PAM_SUCCESS};
+
+        let prompt = CString::new("Password: ").unwrap();
+        let password = CString::new("secret").unwrap();
+        let messages = [
+            PamMessage { msg_style: PAM_PROMPT_ECHO_OFF, msg: prompt.as_ptr() },
+            // PAM_TEXT_INFO
+            PamMessage { msg_style: 4, msg: prompt.as_ptr() },
+        ];
+        let mut ptrs = [&messages[0] as *const PamMessage, &messages[1] as *const PamMessage];
+        let mut replies: *mut PamResponse = ptr::null_mut();
+
+        assert_eq!(converse(2, ptrs.as_mut_ptr(), &mut replies, password.as_ptr() as *mut c_void), PAM_SUCCESS);
+        unsafe {
+            assert_eq!(CStr::from_ptr((*replies).resp).to_str().unwrap(), "secret");
+            assert!((*replies.offset(1)).resp.is_null());
+            libc::free((*replies).resp as *mut c_void);
+            libc::free(replies as *mut c_void);
+        }
+    }
+
+    #[cfg(feature = "htpasswd")]
+    #[test]
+    fn test_htpasswd() {
+        let dir = TempDir::new("plain_auth_test_htpasswd").unwrap();
+        let path = dir.path().join("htpasswd");
+        let mut fh = File::create(&path).unwrap();
+        // "secret", hashed with `openssl passwd -6 -salt saltsalt`
+        fh.write_all(b"# Legacy tooling\n\
+            deploy:$6$saltsalt$TVLlQcbpFVof5W3Yz4DTP6gRstiNuHwwTt6GLc1E5n0U0aDehy0S5knV8wiOQSpT0Y77vwPZN.Pq.H91p5hVO1\n\
+            old:$apr1$salt$hash\n").unwrap();
+
+        let verifier = HtpasswdVerifier::new(&path).unwrap();
+        assert!(verifier.verify("deploy", "secret"));
+        assert!(!verifier.verify("deploy", "Secret"));
+        assert!(!verifier.verify("old", "secret"));
+        assert!(!verifier.verify("nobody", "secret"));
+
+        let mut fh = File::create(&path).unwrap();
+        fh.write_all(b"no colon here\n").unwrap();
+        assert!(HtpasswdVerifier::new(&path).is_err());
+    }
+}
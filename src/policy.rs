@@ -0,0 +1,293 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Operator-provided authorization scripts, written in Rhai.
+//!
+//! A script may define `zap(request)`, called once a ZAP client has
+//! been identified, and `api(request)`, called before each API request.
+//! Each gets the request as a map and returns `true` to allow it:
+//!
+//! ```text
+//! fn api(request) {
+//!     request.endpoint != "cert::delete" || request.role == "admin"
+//! }
+//! ```
+//!
+//! A hook the script doesn't define allows everything. Errors, non-bool
+//! results and scripts that exceed their budget deny. Scripts are
+//! reloaded when their file changes.
+//!
+//! Requires the `policy` feature.
+
+use czmq::ZCert;
+use error::{Error, Result};
+use serde_json::{Map, Value};
+use std::time::Duration;
+#[cfg(feature = "policy")]
+use rhai::{Dynamic, Engine, Scope, AST};
+#[cfg(feature = "policy")]
+use std::fs::{self, File};
+#[cfg(feature = "policy")]
+use std::io::Read;
+use std::path::Path;
+#[cfg(feature = "policy")]
+use std::path::PathBuf;
+#[cfg(feature = "policy")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "policy")]
+use std::time::{Instant, SystemTime};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hook {
+    Api,
+    Zap,
+}
+
+impl Hook {
+    pub fn from_str(hook: &str) -> Result<Hook> {
+        match hook {
+            "api" => Ok(Hook::Api),
+            "zap" => Ok(Hook::Zap),
+            _ => Err(Error::Policy(format!("Unknown hook \"{}\"", hook))),
+        }
+    }
+
+    pub fn to_str(&self) -> &'static str {
+        match *self {
+            Hook::Api => "api",
+            Hook::Zap => "zap",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PolicyLimits {
+    /// Wall clock time per hook call
+    pub timeout: Duration,
+    /// Script operations per hook call
+    pub max_operations: u64,
+    /// Maximum length of any string, array or map
+    pub max_size: usize,
+}
+
+impl Default for PolicyLimits {
+    fn default() -> PolicyLimits {
+        PolicyLimits {
+            timeout: Duration::from_millis(50),
+            max_operations: 100_000,
+            max_size: 10_000,
+        }
+    }
+}
+
+#[cfg(feature = "policy")]
+pub struct PolicyScript {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    engine: Engine,
+    ast: AST,
+    limits: PolicyLimits,
+    // Checked by the engine's progress callback
+    deadline: Arc<Mutex<Instant>>,
+}
+
+#[cfg(feature = "policy")]
+impl PolicyScript {
+    pub fn load<P: AsRef<Path>>(path: P, limits: PolicyLimits) -> Result<PolicyScript> {
+        let deadline = Arc::new(Mutex::new(Instant::now()));
+
+        let mut engine = Engine::new();
+        engine.set_max_operations(limits.max_operations)
+              .set_max_string_size(limits.max_size)
+              .set_max_array_size(limits.max_size)
+              .set_max_map_size(limits.max_size)
+              .set_max_call_levels(32)
+              .set_max_expr_depths(64, 32);
+        let d = deadline.clone();
+        engine.on_progress(move |_| {
+            if Instant::now() > *d.lock().unwrap() {
+                Some(Dynamic::UNIT)
+            } else {
+                None
+            }
+        });
+
+        let path = path.as_ref().to_owned();
+        let (ast, modified) = try!(compile(&engine, &path));
+
+        Ok(PolicyScript {
+            path: path,
+            modified: modified,
+            engine: engine,
+            ast: ast,
+            limits: limits,
+            deadline: deadline,
+        })
+    }
+
+    /// Recompile the script if its file has changed. If the new version
+    /// doesn't compile, the old one stays in use.
+    pub fn reload_if_changed(&mut self) -> Result<bool> {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified == self.modified {
+            return Ok(false);
+        }
+
+        let (ast, modified) = try!(compile(&self.engine, &self.path));
+        self.ast = ast;
+        self.modified = modified;
+        info!("Reloaded policy script {}", self.path.display());
+        Ok(true)
+    }
+
+    /// Run `hook` against `request`, returning whether it is allowed.
+    pub fn evaluate(&self, hook: Hook, request: &Value) -> Result<bool> {
+        let name = hook.to_str();
+        if !self.ast.iter_functions().any(|f| f.name == name && f.params.len() == 1) {
+            return Ok(true);
+        }
+
+        *self.deadline.lock().unwrap() = Instant::now() + self.limits.timeout;
+        self.engine.call_fn::<bool>(&mut Scope::new(), &self.ast, name, (to_dynamic(request),))
+            .map_err(|e| Error::Policy(e.to_string()))
+    }
+
+    /// Like `evaluate()`, but picks up changes to the script first and
+    /// denies the request if the script fails.
+    pub fn allows(&mut self, hook: Hook, request: &Value) -> bool {
+        if let Err(e) = self.reload_if_changed() {
+            error!("Could not reload policy script: {}", e);
+        }
+
+        match self.evaluate(hook, request) {
+            Ok(allowed) => allowed,
+            Err(e) => {
+                warn!("Policy script failed, denying request: {}", e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(feature = "policy")]
+fn compile(engine: &Engine, path: &Path) -> Result<(AST, Option<SystemTime>)> {
+    let mut fh = try!(File::open(path));
+    let modified = try!(fh.metadata()).modified().ok();
+    let mut script = String::new();
+    try!(fh.read_to_string(&mut script));
+
+    let ast = try!(engine.compile(&script).map_err(|e| Error::Policy(e.to_string())));
+    Ok((ast, modified))
+}
+
+#[cfg(feature = "policy")]
+fn to_dynamic(value: &Value) -> Dynamic {
+    match *value {
+        Value::Null => Dynamic::UNIT,
+        Value::Bool(b) => Dynamic::from_bool(b),
+        Value::Number(ref n) => match n.as_i64() {
+            Some(i) => Dynamic::from_int(i),
+            None => Dynamic::from_float(n.as_f64().unwrap_or(0.0)),
+        },
+        Value::String(ref s) => Dynamic::from(s.clone()),
+        Value::Array(ref a) => Dynamic::from_array(a.iter().map(to_dynamic).collect()),
+        Value::Object(ref o) => Dynamic::from_map(o.iter().map(|(k, v)| (k.as_str().into(), to_dynamic(v))).collect()),
+    }
+}
+
+/// Stand-in for builds without the `policy` feature, which can't be
+/// loaded.
+#[cfg(not(feature = "policy"))]
+pub struct PolicyScript;
+
+#[cfg(not(feature = "policy"))]
+impl PolicyScript {
+    pub fn load<P: AsRef<Path>>(_: P, _: PolicyLimits) -> Result<PolicyScript> {
+        Err(Error::Policy("inauth was built without the \"policy\" feature".into()))
+    }
+
+    pub fn evaluate(&self, _: Hook, _: &Value) -> Result<bool> {
+        Ok(true)
+    }
+
+    pub fn allows(&mut self, _: Hook, _: &Value) -> bool {
+        true
+    }
+}
+
+/// The request passed to the `zap` hook.
+pub fn zap_request(domain: &str, address: &str, mechanism: &str, client_id: &str, cert: &ZCert) -> Value {
+    let mut meta = Map::new();
+    for key in cert.meta_keys() {
+        if let Some(Ok(v)) = cert.meta(key) {
+            meta.insert(key.to_string(), Value::String(v));
+        }
+    }
+
+    let mut request = Map::new();
+    request.insert("domain".into(), Value::String(domain.into()));
+    request.insert("address".into(), Value::String(address.into()));
+    request.insert("mechanism".into(), Value::String(mechanism.into()));
+    request.insert("client_id".into(), Value::String(client_id.into()));
+    request.insert("name".into(), meta.get("name").cloned().unwrap_or(Value::Null));
+    request.insert("type".into(), meta.get("type").cloned().unwrap_or(Value::Null));
+    request.insert("meta".into(), Value::Object(meta));
+    Value::Object(request)
+}
+
+/// The request passed to the `api` hook.
+pub fn api_request(endpoint: &str, name: &str, cert_type: &str, domain: Option<&String>, role: Option<&String>) -> Value {
+    let mut request = Map::new();
+    request.insert("endpoint".into(), Value::String(endpoint.into()));
+    request.insert("name".into(), Value::String(name.into()));
+    request.insert("type".into(), Value::String(cert_type.into()));
+    request.insert("domain".into(), domain.map(|d| Value::String(d.clone())).unwrap_or(Value::Null));
+    request.insert("role".into(), role.map(|r| Value::String(r.clone())).unwrap_or(Value::Null));
+    Value::Object(request)
+}
+
+#[cfg(all(test, feature = "policy"))]
+mod tests {
+    use czmq::ZCert;
+    use serde_json::Value;
+    use std::fs::File;
+    use std::io::Write;
+    use std::time::Duration;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_evaluate() {
+        let dir = TempDir::new("policy_test_evaluate").unwrap();
+        let path = dir.path().join("policy.rhai");
+        File::create(&path).unwrap().write_all(b"fn zap(request) { request.meta.group == \"web\" }").unwrap();
+
+        let mut script = PolicyScript::load(&path, PolicyLimits::default()).unwrap();
+        let cert = ZCert::new().unwrap();
+        cert.set_meta("name", "web1");
+        cert.set_meta("group", "web");
+        assert!(script.allows(Hook::Zap, &zap_request("d", "10.0.0.1", "CURVE", "pk", &cert)));
+        cert.set_meta("group", "db");
+        assert!(!script.allows(Hook::Zap, &zap_request("d", "10.0.0.1", "CURVE", "pk", &cert)));
+
+        // Undefined hooks allow everything
+        assert!(script.allows(Hook::Api, &Value::Null));
+    }
+
+    #[test]
+    fn test_budget() {
+        let dir = TempDir::new("policy_test_budget").unwrap();
+        let path = dir.path().join("policy.rhai");
+        File::create(&path).unwrap().write_all(b"fn api(request) { loop {} }").unwrap();
+
+        let limits = PolicyLimits { timeout: Duration::from_millis(10), ..PolicyLimits::default() };
+        let mut script = PolicyScript::load(&path, limits).unwrap();
+        assert!(script.evaluate(Hook::Api, &Value::Null).is_err());
+        assert!(!script.allows(Hook::Api, &Value::Null));
+    }
+}
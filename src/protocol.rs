@@ -0,0 +1,340 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! The API's wire protocol, as data. Handlers receive their request
+//! frames through these definitions, and `api::describe` sends them to
+//! tooling, so the two can't drift apart.
+//!
+//! Every request starts with the endpoint name, which is stripped
+//! before the handler sees the frames listed here. Every reply starts
+//! with "Ok", or "Err" followed by an error code (see `ErrorCode`) and
+//! a message. A request may be preceded by an ID frame, which is then
+//! echoed before the reply; see `request_id`.
+//!
+//! Endpoints marked `typed` also take a request as one JSON frame,
+//! after a "v2" frame, and reply with "Ok" and one JSON frame. See
+//! `messages` for their shape.
+
+use czmq::{ZMsg, ZSock};
+use error::{Error, Result};
+use timeouts;
+use zdaemon::ZMsgExtended;
+
+/// Bump whenever an endpoint's frames change incompatibly.
+pub const API_VERSION: u32 = 1;
+/// Version of the typed requests and replies in `messages`. A typed
+/// request starts with "v" and this number, which older servers reject
+/// as a bad frame count or argument.
+pub const TYPED_VERSION: u32 = 2;
+/// The only ZAP version libzmq speaks.
+pub const ZAP_VERSION: &'static str = "1.0";
+
+#[derive(Debug, Serialize)]
+pub struct Frame {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// May be left off the end of the message
+    pub optional: bool,
+    /// Repeats until the end of the message, possibly zero times
+    pub repeated: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Endpoint {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub request: &'static [Frame],
+    /// Frames following "Ok"
+    pub reply: &'static [Frame],
+    /// Also accepts typed requests
+    pub typed: bool,
+}
+
+impl Endpoint {
+    /// Receive the request frames that follow the endpoint name.
+    pub fn recv(&self, sock: &mut ZSock) -> Result<ZMsg> {
+        let (min, max) = self.frame_count();
+        timeouts::recv_rest(sock, min, max)
+    }
+
+    /// As `recv`, also accepting a typed request. Returns the frames
+    /// and whether they hold a typed request, in which case the JSON
+    /// frame is left in the message.
+    pub fn recv_typed(&self, sock: &mut ZSock) -> Result<(ZMsg, bool)> {
+        if !self.typed {
+            return Ok((self.recv(sock)?, false));
+        }
+
+        let msg = timeouts::recv_rest(sock, 0, None)?;
+        if msg.size() == 2 {
+            if let Some(first) = msg.popbytes()? {
+                if first == typed_marker().as_bytes() {
+                    return Ok((msg, true));
+                }
+                msg.pushbytes(&first)?;
+            }
+        }
+
+        let (min, max) = self.frame_count();
+        if msg.size() < min || max.map(|m| msg.size() > m).unwrap_or(false) {
+            return Err(Error::InvalidArgsCount);
+        }
+        Ok((msg, false))
+    }
+
+    // The fewest and most request frames, if limited
+    fn frame_count(&self) -> (usize, Option<usize>) {
+        let min = self.request.iter().filter(|f| !f.optional && !f.repeated).count();
+        let max = if self.request.iter().any(|f| f.repeated) {
+            None
+        } else {
+            Some(self.request.len())
+        };
+        (min, max)
+    }
+}
+
+/// The frame that starts a typed request, e.g. "v2".
+pub fn typed_marker() -> String {
+    format!("v{}", TYPED_VERSION)
+}
+
+const CERT_TYPE: Frame = Frame { name: "cert_type", description: "\"host\" or \"user\"", optional: false, repeated: false };
+const CERT_NAME: Frame = Frame { name: "name", description: "Certificate name", optional: false, repeated: false };
+const CERT_NAMES: Frame = Frame { name: "name", description: "Certificate name, possibly masked", optional: false, repeated: true };
+const PUBLIC_KEY: Frame = Frame { name: "public_key", description: "Z85-encoded public key", optional: false, repeated: false };
+const SECRET_KEY: Frame = Frame { name: "secret_key", description: "Z85-encoded secret key", optional: false, repeated: false };
+const METADATA: Frame = Frame { name: "metadata", description: "ZMTP-encoded cert metadata", optional: false, repeated: false };
+const REQUEST_ID: Frame = Frame { name: "id", description: "Pending request ID", optional: false, repeated: false };
+const JSON: Frame = Frame { name: "json", description: "JSON document", optional: false, repeated: false };
+
+pub const CERT_APPROVE: Endpoint = Endpoint {
+    name: "cert::approve",
+    description: "Issue the cert for a pending request. Admins only.",
+    request: &[REQUEST_ID],
+    reply: &[CERT_NAME],
+    typed: false,
+};
+
+pub const CERT_CREATE: Endpoint = Endpoint {
+    name: "cert::create",
+    description: "Create and publish a cert. Users only.",
+    request: &[CERT_TYPE, CERT_NAME],
+    reply: &[PUBLIC_KEY, SECRET_KEY, METADATA],
+    typed: true,
+};
+
+pub const CERT_DELETE: Endpoint = Endpoint {
+    name: "cert::delete",
+    description: "Delete a cert and publish its removal. Users only.",
+    request: &[CERT_NAME],
+    reply: &[],
+    typed: true,
+};
+
+pub const CERT_DENY: Endpoint = Endpoint {
+    name: "cert::deny",
+    description: "Discard a pending request. Admins only.",
+    request: &[REQUEST_ID],
+    reply: &[],
+    typed: false,
+};
+
+pub const CERT_EXPIRING: Endpoint = Endpoint {
+    name: "cert::expiring",
+    description: "List certs that expire soon, or already have, soonest first. Users only.",
+    request: &[Frame { name: "within_secs", description: "Seconds from now, or the server's warning window if left off", optional: true, repeated: false }],
+    reply: &[JSON],
+    typed: false,
+};
+
+pub const CERT_LIST: Endpoint = Endpoint {
+    name: "cert::list",
+    description: "List cert names of one type.",
+    request: &[CERT_TYPE],
+    reply: &[CERT_NAMES],
+    typed: true,
+};
+
+pub const CERT_LOOKUP: Endpoint = Endpoint {
+    name: "cert::lookup",
+    description: "Look up a cert's public key by name.",
+    request: &[CERT_NAME],
+    reply: &[PUBLIC_KEY],
+    typed: true,
+};
+
+pub const CERT_PENDING_LIST: Endpoint = Endpoint {
+    name: "cert::pending_list",
+    description: "List pending cert requests, oldest first. Admins only.",
+    request: &[],
+    reply: &[JSON],
+    typed: false,
+};
+
+pub const CERT_PENDING_ROTATION: Endpoint = Endpoint {
+    name: "cert::pending_rotation",
+    description: "Fetch the key pair replacing the caller's own cert in a scheduled rotation.",
+    request: &[],
+    reply: &[
+        PUBLIC_KEY,
+        SECRET_KEY,
+        METADATA,
+        Frame { name: "retire_at", description: "When the old key stops authenticating, in seconds since the Unix epoch", optional: false, repeated: false },
+    ],
+    typed: false,
+};
+
+pub const CERT_REQUEST: Endpoint = Endpoint {
+    name: "cert::request",
+    description: "Ask an admin to issue a cert for a key pair the caller generated.",
+    request: &[CERT_TYPE, CERT_NAME, PUBLIC_KEY],
+    reply: &[REQUEST_ID],
+    typed: false,
+};
+
+pub const CERT_REVOKE: Endpoint = Endpoint {
+    name: "cert::revoke",
+    description: "Revoke a cert, which stops authenticating straight away but stays on record. Users only.",
+    request: &[CERT_NAME],
+    reply: &[],
+    typed: false,
+};
+
+pub const CERT_ROTATE: Endpoint = Endpoint {
+    name: "cert::rotate",
+    description: "Replace a cert's key pair, keeping its name and metadata, and publish the change. Users only.",
+    request: &[CERT_NAME],
+    reply: &[PUBLIC_KEY, SECRET_KEY, METADATA],
+    typed: false,
+};
+
+pub const CERT_SEARCH: Endpoint = Endpoint {
+    name: "cert::search",
+    description: "List cert names matching a filter expression.",
+    request: &[Frame { name: "filter", description: "Filter expression, e.g. \"type=host AND env=prod\"", optional: false, repeated: false }],
+    reply: &[CERT_NAMES],
+    typed: false,
+};
+
+pub const FEED_PUSH: Endpoint = Endpoint {
+    name: "feed::push",
+    description: "Republish a cert to its subscribers, or to one subscriber. Users only.",
+    request: &[
+        CERT_NAME,
+        Frame { name: "subscriber", description: "Cert name of the only subscriber to send to", optional: true, repeated: false },
+    ],
+    reply: &[],
+    typed: false,
+};
+
+pub const FEED_RESYNC: Endpoint = Endpoint {
+    name: "feed::resync",
+    description: "Fetch every cert on the feed as one snapshot, to replace the caller's cache.",
+    request: &[Frame { name: "cert_type", description: "\"host\" or \"user\", or all certs if left off or empty", optional: true, repeated: false }],
+    reply: &[
+        Frame { name: "sequence", description: "Feed sequence the snapshot was taken at", optional: false, repeated: false },
+        Frame { name: "public_key", description: "Z85-encoded public key, each followed by its metadata", optional: false, repeated: true },
+        Frame { name: "metadata", description: "ZMTP-encoded cert metadata", optional: false, repeated: true },
+    ],
+    typed: false,
+};
+
+pub const FLEET_REPORT: Endpoint = Endpoint {
+    name: "fleet::report",
+    description: "Report how up to date the caller's cert cache is.",
+    request: &[Frame { name: "report", description: "JSON object of \"sequence\", \"cache_size\" and \"last_update_age_secs\"", optional: false, repeated: false }],
+    reply: &[],
+    typed: false,
+};
+
+pub const SERVER_INFO: Endpoint = Endpoint {
+    name: "server::info",
+    description: "Describe this server, so clients can choose between replicas and operators can check on it.",
+    request: &[],
+    reply: &[JSON],
+    typed: false,
+};
+
+pub const API_DESCRIBE: Endpoint = Endpoint {
+    name: "api::describe",
+    description: "Describe the endpoints and protocol versions this server supports.",
+    request: &[],
+    reply: &[JSON],
+    typed: false,
+};
+
+/// Every endpoint on the API socket.
+pub const ENDPOINTS: &'static [&'static Endpoint] = &[
+    &API_DESCRIBE,
+    &CERT_APPROVE,
+    &CERT_CREATE,
+    &CERT_DELETE,
+    &CERT_DENY,
+    &CERT_EXPIRING,
+    &CERT_LIST,
+    &CERT_LOOKUP,
+    &CERT_PENDING_LIST,
+    &CERT_PENDING_ROTATION,
+    &CERT_REQUEST,
+    &CERT_REVOKE,
+    &CERT_ROTATE,
+    &CERT_SEARCH,
+    &FEED_PUSH,
+    &FEED_RESYNC,
+    &FLEET_REPORT,
+    &SERVER_INFO,
+];
+
+#[derive(Debug, Serialize)]
+pub struct Description {
+    pub server_version: &'static str,
+    pub api_version: u32,
+    pub typed_version: u32,
+    pub zap_version: &'static str,
+    pub endpoints: &'static [&'static Endpoint],
+}
+
+pub fn describe() -> Description {
+    Description {
+        server_version: env!("CARGO_PKG_VERSION"),
+        api_version: API_VERSION,
+        typed_version: TYPED_VERSION,
+        zap_version: ZAP_VERSION,
+        endpoints: ENDPOINTS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use czmq::{ZMsg, ZSock, ZSys};
+    use super::*;
+
+    #[test]
+    fn test_recv() {
+        ZSys::init();
+
+        let mut client = ZSock::new_push("inproc://protocol_recv").unwrap();
+        let mut server = ZSock::new_pull("inproc://protocol_recv").unwrap();
+        server.set_rcvtimeo(Some(500));
+
+        // The optional subscriber may be left off
+        ZMsg::new().send_multi(&mut client, &["web1"]).unwrap();
+        assert_eq!(FEED_PUSH.recv(&mut server).unwrap().size(), 1);
+        ZMsg::new().send_multi(&mut client, &["web1", "web2", "web3"]).unwrap();
+        assert!(FEED_PUSH.recv(&mut server).is_err());
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let mut names: Vec<&str> = ENDPOINTS.iter().map(|e| e.name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), ENDPOINTS.len());
+    }
+}
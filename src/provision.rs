@@ -0,0 +1,203 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Bulk provisioning of users and hosts from a CSV of
+//! `type,name[,groups,expiry]` rows. Each new cert's secret key is
+//! written to `<name>.crt` in an output directory.
+
+use cert::{Cert, CertType, EXPIRES_META};
+use error::{Error, Result};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use user_import::split_records;
+
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub line: usize,
+    pub cert_type: String,
+    pub name: String,
+    /// Stored as the cert's "groups" metadata
+    pub groups: String,
+    /// Seconds since the Unix epoch, or a "YYYY-MM-DD" date (UTC)
+    pub expiry: String,
+}
+
+impl Entry {
+    /// Whether the row sets metadata beyond the name and type.
+    pub fn has_metadata(&self) -> bool {
+        !self.groups.is_empty() || !self.expiry.is_empty()
+    }
+
+    pub fn checked_type(&self) -> Result<CertType> {
+        if self.name.is_empty() || self.name.contains('/') {
+            return Err(Error::InvalidArg);
+        }
+        CertType::from_str(&self.cert_type)
+    }
+
+    /// A new cert for the row, with a fresh keypair.
+    pub fn new_cert(&self) -> Result<Cert> {
+        let cert = try!(Cert::new(&self.name, try!(self.checked_type())));
+        if !self.groups.is_empty() {
+            cert.set_meta("groups", &self.groups);
+        }
+        if !self.expiry.is_empty() {
+            cert.set_meta(EXPIRES_META, &try!(parse_expiry(&self.expiry)).to_string());
+        }
+        Ok(cert)
+    }
+}
+
+/// Create a cert for each entry with `create`, then save its secret key
+/// to `out_dir`. `report` is called with each entry and where its key
+/// went, or `None` if skipped. Returns the number that failed.
+///
+/// Entries whose key file already exists were provisioned by an earlier
+/// run and are skipped, so a partly failed run can be fixed up and
+/// re-run.
+pub fn run<C, F>(entries: &[Entry], out_dir: &Path, mut create: C, mut report: F) -> usize
+    where C: FnMut(&Entry) -> Result<Cert>,
+          F: FnMut(&Entry, &Result<Option<PathBuf>>)
+{
+    let mut failed = 0;
+    for entry in entries {
+        let result = provision(entry, out_dir, &mut create);
+        if result.is_err() {
+            failed += 1;
+        }
+        report(entry, &result);
+    }
+    failed
+}
+
+fn provision<C>(entry: &Entry, out_dir: &Path, create: &mut C) -> Result<Option<PathBuf>>
+    where C: FnMut(&Entry) -> Result<Cert>
+{
+    try!(entry.checked_type());
+    let path = out_dir.join(format!("{}.crt", entry.name));
+    if path.exists() {
+        return Ok(None);
+    }
+
+    let cert = try!(create(entry));
+    try!(cert.save_secret(&path));
+    Ok(Some(path))
+}
+
+/// Parse `type,name,groups,expiry` rows. A leading header row starting
+/// with "type" is skipped, as are blank lines. Fields may be quoted, so
+/// that groups can be comma separated.
+pub fn parse_csv<R: Read>(reader: &mut R) -> Result<Vec<Entry>> {
+    let mut content = String::new();
+    try!(reader.read_to_string(&mut content));
+
+    let mut entries = Vec::new();
+    for (i, (line, fields)) in try!(split_records(&content)).into_iter().enumerate() {
+        if fields.iter().all(|f| f.trim().is_empty()) {
+            continue;
+        }
+        if i == 0 && fields[0].trim().eq_ignore_ascii_case("type") {
+            continue;
+        }
+
+        let field = |n: usize| fields.get(n).map(|f| f.trim().to_string()).unwrap_or(String::new());
+        entries.push(Entry {
+            line: line,
+            cert_type: field(0),
+            name: field(1),
+            groups: field(2),
+            expiry: field(3),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Seconds since the Unix epoch for `expiry`, given either as such or
+/// as a "YYYY-MM-DD" date, meaning midnight UTC.
+pub fn parse_expiry(expiry: &str) -> Result<u64> {
+    if let Ok(secs) = expiry.parse() {
+        return Ok(secs);
+    }
+
+    let parts: Vec<i64> = match expiry.split('-').map(|p| p.parse()).collect() {
+        Ok(p) => p,
+        Err(_) => return Err(Error::InvalidArg),
+    };
+    if parts.len() != 3 {
+        return Err(Error::InvalidArg);
+    }
+    let (year, month, day) = (parts[0], parts[1], parts[2]);
+    if year < 1970 || month < 1 || month > 12 || day < 1 || day > 31 {
+        return Err(Error::InvalidArg);
+    }
+
+    // Days since 1970-01-01 in the proleptic Gregorian calendar
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Ok(days as u64 * 86400)
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType};
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_parse_csv() {
+        let mut csv: &[u8] = b"type,name,groups,expiry\n\
+                               host,web1,\"web,prod\",2030-01-01\n\
+                               \n\
+                               user,alice\n";
+        let entries = parse_csv(&mut csv).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], Entry { line: 2, cert_type: "host".into(), name: "web1".into(), groups: "web,prod".into(), expiry: "2030-01-01".into() });
+        assert_eq!(entries[1].line, 4);
+        assert!(!entries[1].has_metadata());
+    }
+
+    #[test]
+    fn test_parse_expiry() {
+        assert_eq!(parse_expiry("1700000000").unwrap(), 1700000000);
+        assert_eq!(parse_expiry("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_expiry("2000-03-01").unwrap(), 951868800);
+        assert_eq!(parse_expiry("2030-01-01").unwrap(), 1893456000);
+        assert!(parse_expiry("2030-13-01").is_err());
+        assert!(parse_expiry("tomorrow").is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = TempDir::new("provision_test_run").unwrap();
+        let mut csv: &[u8] = b"host,web1,web,2030-01-01\nuser,bad/name\nfoo,x\nuser,alice\n";
+        let entries = parse_csv(&mut csv).unwrap();
+
+        // Alice's key was written by an earlier run
+        Cert::new("alice", CertType::User).unwrap().save_secret(dir.path().join("alice.crt")).unwrap();
+
+        let mut created = Vec::new();
+        let mut skipped = 0;
+        let failed = run(&entries, dir.path(), |e| {
+            created.push(e.name.clone());
+            e.new_cert()
+        }, |_, r| if let Ok(None) = *r { skipped += 1 });
+        assert_eq!(failed, 2);
+        assert_eq!(skipped, 1);
+        assert_eq!(created, vec!["web1".to_string()]);
+
+        let cert = Cert::from_zcert(::czmq::ZCert::load(dir.path().join("web1.crt")).unwrap()).unwrap();
+        assert_eq!(cert.cert_type(), CertType::Host);
+        assert_eq!(cert.meta("groups").unwrap().unwrap(), "web");
+        assert_eq!(cert.expires_at(), Some(1893456000));
+    }
+}
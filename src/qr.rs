@@ -0,0 +1,485 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Minimal QR code encoder for showing certificates on a terminal.
+//!
+//! Only what a cert bundle needs is supported: byte mode, error
+//! correction level M and versions 1 to 20 (up to 666 bytes). The mask
+//! is chosen by the standard's penalty rules, as scanners expect.
+
+use error::{Error, Result};
+
+const MAX_VERSION: usize = 20;
+
+// Level M error correction codewords per block, and blocks, by version
+const ECC_CODEWORDS_PER_BLOCK: [usize; MAX_VERSION + 1] =
+    [0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26];
+const NUM_BLOCKS: [usize; MAX_VERSION + 1] =
+    [0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16];
+
+// Format information bits for level M
+const ECL_FORMAT_BITS: u32 = 0;
+
+// Modules of quiet zone around the code
+const QUIET_ZONE: usize = 4;
+
+pub struct QrCode {
+    size: usize,
+    /// Row major
+    modules: Vec<bool>,
+    is_function: Vec<bool>,
+}
+
+impl QrCode {
+    /// The smallest code that holds `data`.
+    pub fn encode(data: &[u8]) -> Result<QrCode> {
+        let version = match (1..MAX_VERSION + 1).find(|&v| data.len() <= capacity(v)) {
+            Some(v) => v,
+            None => return Err(Error::QrTooLong(data.len())),
+        };
+
+        let mut qr = QrCode {
+            size: version * 4 + 17,
+            modules: vec![false; (version * 4 + 17) * (version * 4 + 17)],
+            is_function: vec![false; (version * 4 + 17) * (version * 4 + 17)],
+        };
+        qr.draw_function_patterns(version);
+        let codewords = add_ecc_and_interleave(&encode_data(data, version), version);
+        qr.draw_codewords(&codewords);
+
+        let mut best = (0, u32::max_value());
+        for mask in 0..8 {
+            qr.apply_mask(mask);
+            qr.draw_format_bits(mask);
+            let penalty = qr.penalty();
+            if penalty < best.1 {
+                best = (mask, penalty);
+            }
+            // Masks are XORs, so applying one again undoes it
+            qr.apply_mask(mask);
+        }
+        qr.apply_mask(best.0);
+        qr.draw_format_bits(best.0);
+        Ok(qr)
+    }
+
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    /// Two rows of modules per line using half blocks, in black on
+    /// white whatever the terminal's colours.
+    pub fn render(&self) -> String {
+        let dark = |x: usize, y: usize| {
+            x >= QUIET_ZONE && y >= QUIET_ZONE && x < self.size + QUIET_ZONE && y < self.size + QUIET_ZONE
+                && self.is_dark(x - QUIET_ZONE, y - QUIET_ZONE)
+        };
+
+        let width = self.size + QUIET_ZONE * 2;
+        let mut out = String::new();
+        for row in 0..(width + 1) / 2 {
+            out.push_str("\x1b[30;47m");
+            for x in 0..width {
+                out.push(match (dark(x, row * 2), dark(x, row * 2 + 1)) {
+                    (true, true) => '\u{2588}',
+                    (true, false) => '\u{2580}',
+                    (false, true) => '\u{2584}',
+                    (false, false) => ' ',
+                });
+            }
+            out.push_str("\x1b[0m\n");
+        }
+        out.pop();
+        out
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        let i = y * self.size + x;
+        self.modules[i] = dark;
+        self.is_function[i] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+
+        // Timing patterns
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        // Finder patterns and their separators
+        for &(cx, cy) in &[(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4i32..5 {
+                for dx in -4i32..5 {
+                    let (x, y) = (cx as i32 + dx, cy as i32 + dy);
+                    if x >= 0 && y >= 0 && (x as usize) < size && (y as usize) < size {
+                        let dist = dx.abs().max(dy.abs());
+                        self.set_function(x as usize, y as usize, dist != 2 && dist != 4);
+                    }
+                }
+            }
+        }
+
+        // Alignment patterns, except where they'd overlap a finder
+        let positions = alignment_positions(version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &cy) in positions.iter().enumerate() {
+            for (j, &cx) in positions.iter().enumerate() {
+                if (i == 0 && j == 0) || (i == 0 && j == last) || (i == last && j == 0) {
+                    continue;
+                }
+                for dy in -2i32..3 {
+                    for dx in -2i32..3 {
+                        let dist = dx.abs().max(dy.abs());
+                        self.set_function((cx as i32 + dx) as usize, (cy as i32 + dy) as usize, dist != 1);
+                    }
+                }
+            }
+        }
+
+        // Reserve the format areas, drawn for real once masked
+        self.draw_format_bits(0);
+
+        if version >= 7 {
+            let mut rem = version as u32;
+            for _ in 0..12 {
+                rem = (rem << 1) ^ ((rem >> 11) * 0x1F25);
+            }
+            let bits = (version as u32) << 12 | rem;
+            for i in 0..18 {
+                let dark = (bits >> i) & 1 != 0;
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                self.set_function(a, b, dark);
+                self.set_function(b, a, dark);
+            }
+        }
+    }
+
+    fn draw_format_bits(&mut self, mask: u32) {
+        let size = self.size;
+        let data = ECL_FORMAT_BITS << 3 | mask;
+        let mut rem = data;
+        for _ in 0..10 {
+            rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+        }
+        let bits = (data << 10 | rem) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+
+        // Around the top left finder
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+
+        // Split between the other two
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        // Pairs of columns, right to left, zigzagging up and down
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            for vert in 0..size {
+                for j in 0..2 {
+                    let x = right - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward { size - 1 - vert } else { vert };
+                    if !self.is_function[y * size + x] && i < codewords.len() * 8 {
+                        self.modules[y * size + x] = (codewords[i >> 3] >> (7 - (i & 7))) & 1 != 0;
+                        i += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let i = y * self.size + x;
+                if invert && !self.is_function[i] {
+                    self.modules[i] = !self.modules[i];
+                }
+            }
+        }
+    }
+
+    fn penalty(&self) -> u32 {
+        let size = self.size;
+        let mut penalty = 0;
+
+        // Runs of five or more, and finder lookalikes, in rows then
+        // columns
+        for transpose in &[false, true] {
+            for a in 0..size {
+                let line: Vec<bool> = (0..size).map(|b| if *transpose { self.is_dark(a, b) } else { self.is_dark(b, a) }).collect();
+                penalty += run_penalty(&line) + finder_penalty(&line);
+            }
+        }
+
+        // Blocks of 2x2
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let c = self.is_dark(x, y);
+                if c == self.is_dark(x + 1, y) && c == self.is_dark(x, y + 1) && c == self.is_dark(x + 1, y + 1) {
+                    penalty += 3;
+                }
+            }
+        }
+
+        // Balance of dark and light, in steps of 5% from 50%
+        let total = (size * size) as i64;
+        let dark = self.modules.iter().filter(|&&m| m).count() as i64;
+        let k = ((dark * 20 - total * 10).abs() + total - 1) / total - 1;
+        penalty + k as u32 * 10
+    }
+}
+
+fn run_penalty(line: &[bool]) -> u32 {
+    let mut penalty = 0;
+    let mut run = 1;
+    for i in 1..line.len() + 1 {
+        if i < line.len() && line[i] == line[i - 1] {
+            run += 1;
+        } else {
+            if run >= 5 {
+                penalty += run - 2;
+            }
+            run = 1;
+        }
+    }
+    penalty
+}
+
+// 1:1:3:1:1 dark and light, with four light modules on either side
+fn finder_penalty(line: &[bool]) -> u32 {
+    const PATTERN: [bool; 7] = [true, false, true, true, true, false, true];
+    let light = |from: i32, to: i32| (from..to).all(|i| i < 0 || i as usize >= line.len() || !line[i as usize]);
+
+    let mut penalty = 0;
+    for i in 0..(line.len() + 1).saturating_sub(PATTERN.len()) {
+        if line[i..i + PATTERN.len()] == PATTERN[..] {
+            let start = i as i32;
+            let end = start + PATTERN.len() as i32;
+            if light(start - 4, start) || light(end, end + 4) {
+                penalty += 40;
+            }
+        }
+    }
+    penalty
+}
+
+fn alignment_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let count = version / 7 + 2;
+    let step = (version * 4 + count * 2 + 1) / (count * 2 - 2) * 2;
+    let mut positions = vec![6];
+    let mut pos = version * 4 + 10;
+    for _ in 0..count - 1 {
+        positions.insert(1, pos);
+        pos -= step;
+    }
+    positions
+}
+
+// Modules left for data and error correction once the function
+// patterns are drawn
+fn raw_data_modules(version: usize) -> usize {
+    let mut result = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let align = version / 7 + 2;
+        result -= (25 * align - 10) * align - 55;
+        if version >= 7 {
+            result -= 36;
+        }
+    }
+    result
+}
+
+fn data_codewords(version: usize) -> usize {
+    raw_data_modules(version) / 8 - ECC_CODEWORDS_PER_BLOCK[version] * NUM_BLOCKS[version]
+}
+
+// Bytes of payload, after the mode and length header
+fn capacity(version: usize) -> usize {
+    let header_bits = 4 + if version < 10 { 8 } else { 16 };
+    (data_codewords(version) * 8 - header_bits) / 8
+}
+
+// Byte mode segment, terminated and padded to the version's capacity
+fn encode_data(data: &[u8], version: usize) -> Vec<u8> {
+    let mut bits = Vec::new();
+    {
+        let mut push = |value: u32, len: usize| {
+            for i in (0..len).rev() {
+                bits.push((value >> i) & 1 != 0);
+            }
+        };
+        push(0b0100, 4);
+        push(data.len() as u32, if version < 10 { 8 } else { 16 });
+        for &b in data {
+            push(b as u32, 8);
+        }
+    }
+
+    let capacity_bits = data_codewords(version) * 8;
+    for _ in 0..4 {
+        if bits.len() < capacity_bits {
+            bits.push(false);
+        }
+    }
+    while bits.len() % 8 != 0 {
+        bits.push(false);
+    }
+
+    let mut codewords: Vec<u8> = bits.chunks(8).map(|c| c.iter().fold(0, |acc, &b| acc << 1 | b as u8)).collect();
+    let mut pad = 0xEC;
+    while codewords.len() < data_codewords(version) {
+        codewords.push(pad);
+        pad ^= 0xEC ^ 0x11;
+    }
+    codewords
+}
+
+fn add_ecc_and_interleave(data: &[u8], version: usize) -> Vec<u8> {
+    let num_blocks = NUM_BLOCKS[version];
+    let ecc_len = ECC_CODEWORDS_PER_BLOCK[version];
+    let raw_codewords = raw_data_modules(version) / 8;
+    let num_short_blocks = num_blocks - raw_codewords % num_blocks;
+    let short_block_len = raw_codewords / num_blocks;
+
+    // Short blocks get a dummy byte so all blocks are the same length
+    let divisor = rs_divisor(ecc_len);
+    let mut blocks = Vec::new();
+    let mut k = 0;
+    for i in 0..num_blocks {
+        let data_len = short_block_len - ecc_len + if i < num_short_blocks { 0 } else { 1 };
+        let mut block = data[k..k + data_len].to_vec();
+        k += data_len;
+        let ecc = rs_remainder(&block, &divisor);
+        if i < num_short_blocks {
+            block.push(0);
+        }
+        block.extend(ecc);
+        blocks.push(block);
+    }
+
+    let mut result = Vec::with_capacity(raw_codewords);
+    for i in 0..short_block_len + 1 {
+        for (j, block) in blocks.iter().enumerate() {
+            if i != short_block_len - ecc_len || j >= num_short_blocks {
+                result.push(block[i]);
+            }
+        }
+    }
+    result
+}
+
+// Multiply in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u32 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11D);
+        z ^= ((y as u32 >> i) & 1) * x as u32;
+    }
+    z as u8
+}
+
+fn rs_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0; degree];
+    result[degree - 1] = 1;
+    let mut root = 1;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_multiply(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    result
+}
+
+fn rs_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0; divisor.len()];
+    for &b in data {
+        let factor = b ^ result.remove(0);
+        result.push(0);
+        for (r, &d) in result.iter_mut().zip(divisor) {
+            *r ^= gf_multiply(d, factor);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{capacity, rs_divisor, rs_remainder, QrCode};
+
+    #[test]
+    fn test_rs_remainder() {
+        // 1-M "HELLO WORLD", from the worked example at thonky.com
+        let data = [32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17];
+        assert_eq!(rs_remainder(&data, &rs_divisor(10)), vec![196, 35, 39, 119, 235, 215, 231, 226, 93, 23]);
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(capacity(1), 14);
+        assert_eq!(capacity(10), 213);
+        assert_eq!(capacity(20), 666);
+
+        let qr = QrCode::encode(b"inauth").unwrap();
+        assert_eq!(qr.size, 21);
+        // Finder corners, timing pattern and the dark module
+        assert!(qr.is_dark(0, 0) && qr.is_dark(20, 0) && qr.is_dark(0, 20));
+        assert!(!qr.is_dark(7, 7));
+        assert!(qr.is_dark(8, 6) && !qr.is_dark(9, 6));
+        assert!(qr.is_dark(8, 13));
+
+        assert_eq!(QrCode::encode(&[b'x'; 300]).unwrap().size, 13 * 4 + 17);
+        assert!(QrCode::encode(&[b'x'; 667]).is_err());
+
+        let rendered = qr.render();
+        assert_eq!(rendered.lines().count(), (21 + 8 + 1) / 2);
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Quotas on how many certs each user may create in a time window, so
+//! that a stolen admin key can't mint host identities unnoticed.
+//!
+//! Creations are counted in memory, so a restart starts every user's
+//! window afresh.
+
+use cert::CertType;
+use config::CertQuota;
+use error::{Error, Result};
+use request_id;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+const WILDCARD: &'static str = "*";
+
+pub struct CreateQuotas {
+    quotas: HashMap<String, CertQuota>,
+    // Creation times within the window, keyed by the creator's cert
+    // name and the type of cert created
+    created: HashMap<(String, CertType), VecDeque<Instant>>,
+}
+
+impl CreateQuotas {
+    /// `quotas` is keyed by the type of cert created, or "*" for any
+    /// type not listed.
+    pub fn new(quotas: Option<HashMap<String, CertQuota>>) -> CreateQuotas {
+        CreateQuotas {
+            quotas: quotas.unwrap_or(HashMap::new()),
+            created: HashMap::new(),
+        }
+    }
+
+    /// Whether `creator` may create another cert of `cert_type`.
+    /// Failures are logged to the "audit" target.
+    pub fn check(&mut self, creator: &str, cert_type: CertType) -> Result<()> {
+        self.check_at(creator, cert_type, Instant::now())
+    }
+
+    /// Count a cert of `cert_type` that `creator` has just created.
+    pub fn record(&mut self, creator: &str, cert_type: CertType) {
+        self.record_at(creator, cert_type, Instant::now())
+    }
+
+    fn check_at(&mut self, creator: &str, cert_type: CertType, now: Instant) -> Result<()> {
+        let quota = match self.quota_for(cert_type) {
+            Some(q) => q,
+            None => return Ok(()),
+        };
+
+        let created = match self.created.get_mut(&(creator.to_string(), cert_type)) {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        expire(created, quota, now);
+
+        if created.len() < quota.max {
+            Ok(())
+        } else {
+            warn!(target: "audit", "{}{} reached their quota of {} {} certs per {}s", request_id::log_prefix(), creator, quota.max, cert_type.to_str(), quota.window_secs);
+            Err(Error::QuotaExceeded(format!("{} {} certs per {}s", quota.max, cert_type.to_str(), quota.window_secs)))
+        }
+    }
+
+    fn record_at(&mut self, creator: &str, cert_type: CertType, now: Instant) {
+        let quota = match self.quota_for(cert_type) {
+            Some(q) => q,
+            None => return,
+        };
+
+        let created = self.created.entry((creator.to_string(), cert_type)).or_insert(VecDeque::new());
+        expire(created, quota, now);
+        created.push_back(now);
+    }
+
+    fn quota_for(&self, cert_type: CertType) -> Option<CertQuota> {
+        self.quotas.get(cert_type.to_str()).or(self.quotas.get(WILDCARD)).cloned()
+    }
+}
+
+// Forget creations that have left the window
+fn expire(created: &mut VecDeque<Instant>, quota: CertQuota, now: Instant) {
+    let window = Duration::from_secs(quota.window_secs);
+    while created.front().map(|t| now.duration_since(*t) >= window).unwrap_or(false) {
+        created.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::CertType;
+    use config::CertQuota;
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let mut quotas = HashMap::new();
+        quotas.insert("host".to_string(), CertQuota { max: 2, window_secs: 60 });
+        let mut quotas = CreateQuotas::new(Some(quotas));
+        let now = Instant::now();
+
+        assert!(quotas.check_at("alice", CertType::Host, now).is_ok());
+        quotas.record_at("alice", CertType::Host, now);
+        quotas.record_at("alice", CertType::Host, now + Duration::from_secs(30));
+        assert!(quotas.check_at("alice", CertType::Host, now + Duration::from_secs(30)).is_err());
+
+        // Other users and cert types are counted separately
+        assert!(quotas.check_at("bob", CertType::Host, now).is_ok());
+        assert!(quotas.check_at("alice", CertType::User, now).is_ok());
+
+        // The first creation leaves the window
+        assert!(quotas.check_at("alice", CertType::Host, now + Duration::from_secs(60)).is_ok());
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let mut quotas = CreateQuotas::new(None);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(quotas.check_at("alice", CertType::Host, now).is_ok());
+            quotas.record_at("alice", CertType::Host, now);
+        }
+        assert!(quotas.created.is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Token bucket rate limiting of API requests per client cert.
+
+use cert::CertType;
+use config::RateLimit;
+use czmq::{ZFrame, ZSock};
+use error::{Error, Result};
+use request_id;
+use request_meta::RequestMeta;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Instant;
+
+const WILDCARD: &'static str = "*";
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    limits: HashMap<String, HashMap<String, RateLimit>>,
+    // Keyed by the endpoint the limit was configured for (which may
+    // be the wildcard) and the client's cert name.
+    buckets: HashMap<(String, String), Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Option<HashMap<String, HashMap<String, RateLimit>>>) -> RateLimiter {
+        RateLimiter {
+            limits: limits.unwrap_or(HashMap::new()),
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, endpoint: &str, meta: &RequestMeta) -> Result<()> {
+        self.check_at(endpoint, meta.cert_type, &meta.name, Instant::now())
+    }
+
+    fn check_at(&mut self, endpoint: &str, cert_type: CertType, name: &str, now: Instant) -> Result<()> {
+        let (key, limit) = match self.limit_for(endpoint, cert_type) {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+
+        let bucket = self.buckets.entry((key, name.to_string())).or_insert(Bucket {
+            tokens: limit.burst,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+        bucket.tokens = (bucket.tokens + elapsed * limit.rate).min(limit.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            debug!("{}Rate limited {} on {}", request_id::log_prefix(), name, endpoint);
+            Err(Error::RateLimited)
+        }
+    }
+
+    fn limit_for(&self, endpoint: &str, cert_type: CertType) -> Option<(String, RateLimit)> {
+        for key in &[endpoint, WILDCARD] {
+            if let Some(types) = self.limits.get(*key) {
+                if let Some(limit) = types.get(cert_type.to_str()).or(types.get(WILDCARD)) {
+                    return Some((key.to_string(), *limit));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Check the request against `limiter`. If it is over the limit, the
+/// rest of the request is discarded so that it isn't read as a new
+/// one.
+pub fn check_request(limiter: &RefCell<RateLimiter>, sock: &mut ZSock, endpoint: &str, endpoint_frame: &ZFrame) -> Result<()> {
+    check_identity(limiter, sock, endpoint, &RequestMeta::new(endpoint_frame)?)
+}
+
+/// Like `check_request()`, for callers authenticated out of band (e.g.
+/// through the HTTP gateway).
+pub fn check_identity(limiter: &RefCell<RateLimiter>, sock: &mut ZSock, endpoint: &str, meta: &RequestMeta) -> Result<()> {
+    if let Err(e) = limiter.borrow_mut().check(endpoint, meta) {
+        while sock.rcvmore() {
+            ZFrame::recv(sock)?;
+        }
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::CertType;
+    use config::RateLimit;
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let mut create = HashMap::new();
+        create.insert("user".to_string(), RateLimit { rate: 1.0, burst: 2.0 });
+        let mut any = HashMap::new();
+        any.insert("*".to_string(), RateLimit { rate: 10.0, burst: 1.0 });
+        let mut limits = HashMap::new();
+        limits.insert("cert::create".to_string(), create);
+        limits.insert("*".to_string(), any);
+
+        let mut limiter = RateLimiter::new(Some(limits));
+        let now = Instant::now();
+
+        // Burst of 2, then limited until a token is refilled
+        assert!(limiter.check_at("cert::create", CertType::User, "alice", now).is_ok());
+        assert!(limiter.check_at("cert::create", CertType::User, "alice", now).is_ok());
+        assert!(limiter.check_at("cert::create", CertType::User, "alice", now).is_err());
+        assert!(limiter.check_at("cert::create", CertType::User, "alice", now + Duration::from_millis(1100)).is_ok());
+
+        // Other clients have their own bucket
+        assert!(limiter.check_at("cert::create", CertType::User, "bob", now).is_ok());
+
+        // Hosts fall back to the wildcard endpoint, which is shared
+        // across endpoints
+        assert!(limiter.check_at("cert::create", CertType::Host, "web1", now).is_ok());
+        assert!(limiter.check_at("cert::list", CertType::Host, "web1", now).is_err());
+        assert!(limiter.check_at("cert::list", CertType::Host, "web1", now + Duration::from_millis(150)).is_ok());
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let mut limiter = RateLimiter::new(None);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(limiter.check_at("cert::list", CertType::Host, "web1", now).is_ok());
+        }
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Reconnect timing for the feed subscriber.
+//!
+//! When the Auth server restarts, every client retries on the same
+//! schedule and they all arrive at once. Backing off and adding a
+//! random delay per client spreads them out.
+
+use czmq::{RawInterface, ZSock};
+use sodiumoxide::randombytes::randombytes;
+use std::os::raw::{c_int, c_void};
+use std::time::Duration;
+
+// czmq 0.1 doesn't wrap these options
+extern "C" {
+    fn zsock_set_reconnect_ivl(sock: *mut c_void, reconnect_ivl: c_int);
+    fn zsock_set_reconnect_ivl_max(sock: *mut c_void, reconnect_ivl_max: c_int);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Delay before the first reconnect attempt
+    pub interval: Duration,
+    /// If set, the delay doubles after each failed attempt up to this
+    /// maximum. Otherwise every attempt waits `interval`.
+    pub max_interval: Option<Duration>,
+    /// Up to this much is added to `interval`, chosen at random once
+    /// per handler.
+    pub jitter: Duration,
+    /// How long to stay away from a server that announces it is
+    /// draining, provided others are configured. Its subscription is
+    /// dropped straight away and resumed afterwards.
+    pub drain_holdoff: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    /// ZeroMQ's defaults: retry every 100ms, without jitter. Draining
+    /// servers are avoided for a minute.
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            interval: Duration::from_millis(100),
+            max_interval: None,
+            jitter: Duration::from_millis(0),
+            drain_holdoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Suited to fleets of thousands of clients: back off from 1-6s up
+    /// to a minute, and avoid draining servers for five.
+    pub fn fleet() -> ReconnectPolicy {
+        ReconnectPolicy {
+            interval: Duration::from_secs(1),
+            max_interval: Some(Duration::from_secs(60)),
+            jitter: Duration::from_secs(5),
+            drain_holdoff: Duration::from_secs(300),
+        }
+    }
+
+    /// Set the options on `sock`. They only apply to connections made
+    /// afterwards.
+    pub fn apply(&self, sock: &mut ZSock) {
+        let interval = millis(self.interval) + random_below(millis(self.jitter));
+        unsafe {
+            zsock_set_reconnect_ivl(sock.as_mut_ptr(), interval as c_int);
+            // 0 disables backoff
+            zsock_set_reconnect_ivl_max(sock.as_mut_ptr(), self.max_interval.map(millis).unwrap_or(0) as c_int);
+        }
+    }
+}
+
+pub fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
+
+fn random_below(n: u64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+    randombytes(8).iter().fold(0u64, |acc, b| acc << 8 | *b as u64) % n
+}
+
+#[cfg(test)]
+mod tests {
+    use czmq::{RawInterface, ZSock, SocketType, ZSys};
+    use std::os::raw::{c_int, c_void};
+    use std::time::Duration;
+    use super::*;
+
+    extern "C" {
+        fn zsock_reconnect_ivl(sock: *mut c_void) -> c_int;
+        fn zsock_reconnect_ivl_max(sock: *mut c_void) -> c_int;
+    }
+
+    fn options(sock: &mut ZSock) -> (c_int, c_int) {
+        unsafe { (zsock_reconnect_ivl(sock.as_mut_ptr()), zsock_reconnect_ivl_max(sock.as_mut_ptr())) }
+    }
+
+    #[test]
+    fn test_apply() {
+        ZSys::init();
+
+        let mut sock = ZSock::new(SocketType::SUB);
+        ReconnectPolicy::fleet().apply(&mut sock);
+        let (interval, max) = options(&mut sock);
+        assert!(interval >= 1000 && interval < 6000);
+        assert_eq!(max, 60000);
+
+        ReconnectPolicy::default().apply(&mut sock);
+        assert_eq!(options(&mut sock), (100, 0));
+
+        assert_eq!(millis(Duration::from_millis(1500)), 1500);
+        assert_eq!(random_below(0), 0);
+        assert!(random_below(10) < 10);
+    }
+}
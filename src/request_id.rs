@@ -0,0 +1,314 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Correlation IDs for API requests, so a request can be followed
+//! through its reply, the wire trace and the logs.
+//!
+//! A client may choose the ID by sending `REQUEST_ID_PREFIX` and the ID
+//! as a frame before the endpoint name. Its reply, "Ok" or "Err", then
+//! starts with the same frame. Otherwise the server makes up an ID for
+//! its own logs and leaves the reply alone, so existing clients see no
+//! change.
+
+use czmq::{SocketType, ZFrame, ZMsg, ZSock};
+use error::{Error, Result};
+use sodiumoxide::randombytes::randombytes;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::result::Result as StdResult;
+use zdaemon::{Endpoint, Error as DError};
+
+pub const REQUEST_ID_PREFIX: &'static str = "rid:";
+/// Longest ID accepted from a client
+pub const MAX_LEN: usize = 64;
+
+thread_local! {
+    static CURRENT: RefCell<Option<RequestId>> = RefCell::new(None);
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId {
+    id: String,
+    // Chosen by the client, which expects it back in the reply
+    echo: bool,
+}
+
+impl RequestId {
+    pub fn generate() -> RequestId {
+        RequestId {
+            id: randombytes(8).iter().map(|b| format!("{:02x}", b)).collect(),
+            echo: false,
+        }
+    }
+
+    /// Parse an ID frame sent by a client, or None if `frame` isn't
+    /// one. IDs are up to `MAX_LEN` letters, digits, '-', '_' or '.'.
+    pub fn from_frame(frame: &[u8]) -> Option<Result<RequestId>> {
+        if !frame.starts_with(REQUEST_ID_PREFIX.as_bytes()) {
+            return None;
+        }
+
+        let id = &frame[REQUEST_ID_PREFIX.len()..];
+        if id.is_empty() || id.len() > MAX_LEN || !id.iter().all(|&c| (c as char).is_ascii_alphanumeric() || c == b'-' || c == b'_' || c == b'.') {
+            return Some(Err(Error::InvalidRequestId));
+        }
+
+        Some(Ok(RequestId {
+            id: String::from_utf8_lossy(id).into_owned(),
+            echo: true,
+        }))
+    }
+
+    /// Whether replies carry this ID
+    pub fn is_echoed(&self) -> bool {
+        self.echo
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.id)
+    }
+}
+
+/// The ID of the request being handled on this thread, if any.
+pub fn current() -> Option<RequestId> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+/// Run `f` with `id` as the current request's ID.
+pub fn with_current<T, F: FnOnce() -> T>(id: RequestId, f: F) -> T {
+    CURRENT.with(|c| *c.borrow_mut() = Some(id));
+    let result = f();
+    CURRENT.with(|c| *c.borrow_mut() = None);
+    result
+}
+
+/// "[<id>] " for the request being handled on this thread, to start
+/// log lines with.
+pub fn log_prefix() -> String {
+    match current() {
+        Some(id) => format!("[{}] ", id),
+        None => String::new(),
+    }
+}
+
+/// Address a reply to `router_id`, starting it with the current
+/// request's ID if the client sent one.
+pub fn address(msg: &ZMsg, router_id: &[u8]) -> Result<()> {
+    if let Some(id) = current() {
+        if id.is_echoed() {
+            msg.pushstr(&format!("{}{}", REQUEST_ID_PREFIX, id))?;
+        }
+    }
+    msg.pushstr("")?;
+    msg.pushbytes(router_id)?;
+    Ok(())
+}
+
+/// Where `address()` leaves a reply's status frame ("Ok" or "Err"),
+/// for finding the frames after it, e.g. to redact them from the wire
+/// trace.
+pub fn status_index() -> usize {
+    match current() {
+        Some(ref id) if id.is_echoed() => 3,
+        _ => 2,
+    }
+}
+
+/// An "Err" reply to `router_id`, with the error's code and message.
+pub fn error_reply(router_id: &[u8], err: &Error) -> Result<ZMsg> {
+    let msg = ZMsg::new();
+    msg.addstr("Err")?;
+    msg.addstr(err.code().as_str())?;
+    msg.addstr(err.description())?;
+    address(&msg, router_id)?;
+    Ok(msg)
+}
+
+type Shim = Box<Fn(&mut ZSock, ZFrame, Option<Vec<u8>>) -> StdResult<(), DError>>;
+
+/// As `zdaemon::Api`, also reading the request ID and making it
+/// `current()` while the endpoint runs.
+pub struct TracedApi {
+    sock: ZSock,
+    endpoints: HashMap<String, Shim>,
+}
+
+impl TracedApi {
+    pub fn new(sock: ZSock) -> TracedApi {
+        TracedApi {
+            sock: sock,
+            endpoints: HashMap::new(),
+        }
+    }
+
+    pub fn add<F>(&mut self, endpoint: &str, shim: F)
+        where F: Fn(&mut ZSock, ZFrame, Option<Vec<u8>>) -> StdResult<(), DError> + 'static {
+        self.endpoints.insert(endpoint.to_string(), Box::new(shim));
+    }
+
+    fn run(&mut self, endpoint_frame: ZFrame, router_id: Option<Vec<u8>>, request_id: RequestId) -> StdResult<(), DError> {
+        let endpoint = endpoint_frame.data()?.or(Err(DError::MessageUtf8))?;
+        debug!("[{}] Request to {}", request_id, endpoint);
+
+        match self.endpoints.get(&endpoint) {
+            Some(shim) => with_current(request_id, || shim(&mut self.sock, endpoint_frame, router_id)),
+            None => Err(DError::InvalidEndpoint),
+        }
+    }
+
+    // Drain a request with a bad ID and say why
+    fn reject(&mut self, router_id: Option<Vec<u8>>, err: Error) -> StdResult<(), DError> {
+        while self.sock.rcvmore() {
+            ZFrame::recv(&mut self.sock)?;
+        }
+        let msg = error_reply(&router_id.unwrap_or(Vec::new()), &err)?;
+        msg.send(&mut self.sock)?;
+        Err(err.into())
+    }
+}
+
+impl Endpoint for TracedApi {
+    fn get_sockets(&mut self) -> Vec<&mut ZSock> {
+        vec![&mut self.sock]
+    }
+
+    fn recv(&mut self, _: &mut ZSock) -> StdResult<(), DError> {
+        let router_id = match self.sock.zsock_type() {
+            SocketType::ROUTER => {
+                let frame = ZFrame::recv(&mut self.sock)?;
+                match frame.data()? {
+                    Ok(id) => Some(id.into_bytes()),
+                    Err(b) => Some(b),
+                }
+            },
+            _ => None
+        };
+
+        let mut endpoint = ZFrame::recv(&mut self.sock)?;
+
+        // REQ sockets send an empty delimiter first
+        if endpoint.data()?.or(Err(DError::MessageUtf8))? == "" {
+            let client_sock_type = endpoint.meta("Socket-Type")
+                                           .expect("missing Socket-Type meta")
+                                           .or(Err(DError::MessageUtf8))?;
+            if client_sock_type == "REQ" {
+                endpoint = ZFrame::recv(&mut self.sock)?;
+            }
+        }
+
+        let request_id = match endpoint.data()? {
+            Ok(s) => RequestId::from_frame(s.as_bytes()),
+            Err(b) => RequestId::from_frame(&b),
+        };
+        match request_id {
+            Some(Ok(id)) => {
+                endpoint = ZFrame::recv(&mut self.sock)?;
+                self.run(endpoint, router_id, id)
+            },
+            Some(Err(e)) => self.reject(router_id, e),
+            None => self.run(endpoint, router_id, RequestId::generate()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use czmq::{ZFrame, ZMsg, ZSock, ZSys};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use super::*;
+    use zdaemon::{Endpoint, ZMsgExtended};
+
+    #[test]
+    fn test_from_frame() {
+        assert!(RequestId::from_frame(b"cert::list").is_none());
+        let id = RequestId::from_frame(b"rid:agent-1.42").unwrap().unwrap();
+        assert_eq!(id.to_string(), "agent-1.42");
+        assert!(id.is_echoed());
+        assert!(RequestId::from_frame(b"rid:").unwrap().is_err());
+        assert!(RequestId::from_frame(b"rid:has space").unwrap().is_err());
+        assert!(RequestId::from_frame(&[b'r', b'i', b'd', b':', 0xff]).unwrap().is_err());
+
+        let generated = RequestId::generate();
+        assert_eq!(generated.to_string().len(), 16);
+        assert!(!generated.is_echoed());
+        assert!(current().is_none());
+    }
+
+    #[test]
+    fn test_traced_api() {
+        ZSys::init();
+
+        let mut client = ZSock::new_req(">inproc://request_id_test_traced_api").unwrap();
+        client.set_rcvtimeo(Some(500));
+        let server = ZSock::new_router("@inproc://request_id_test_traced_api").unwrap();
+        let mut api = TracedApi::new(server);
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let seen_ok = seen.clone();
+        api.add("test::ok", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| {
+            seen_ok.borrow_mut().push(current().unwrap());
+            let reply = ZMsg::new_ok().unwrap();
+            address(&reply, &id.unwrap()).unwrap();
+            reply.send(s).unwrap();
+            Ok(())
+        });
+        api.add("test::err", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| {
+            error_reply(&id.unwrap(), &Error::Forbidden).unwrap().send(s).unwrap();
+            Err(Error::Forbidden.into())
+        });
+
+        // Without an ID, the reply is unchanged
+        let msg = ZMsg::new();
+        msg.addstr("test::ok").unwrap();
+        msg.send(&mut client).unwrap();
+        let mut sock = ZSock::new_pull("inproc://request_id_test_unused").unwrap();
+        api.recv(&mut sock).unwrap();
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        assert!(reply.popstr().is_none());
+        assert!(!seen.borrow()[0].is_echoed());
+        assert!(current().is_none());
+
+        let msg = ZMsg::new();
+        msg.addstr("rid:abc").unwrap();
+        msg.addstr("test::ok").unwrap();
+        msg.send(&mut client).unwrap();
+        api.recv(&mut sock).unwrap();
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "rid:abc");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Ok");
+        assert_eq!(seen.borrow()[1].to_string(), "abc");
+
+        let msg = ZMsg::new();
+        msg.addstr("rid:abc").unwrap();
+        msg.addstr("test::err").unwrap();
+        msg.send(&mut client).unwrap();
+        assert!(api.recv(&mut sock).is_err());
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "rid:abc");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Err");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "forbidden");
+
+        // A bad ID is refused before the endpoint runs
+        let msg = ZMsg::new();
+        msg.addstr("rid:not ok").unwrap();
+        msg.addstr("test::ok").unwrap();
+        msg.addstr("arg").unwrap();
+        msg.send(&mut client).unwrap();
+        assert!(api.recv(&mut sock).is_err());
+        let reply = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Err");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "bad_request");
+        assert_eq!(seen.borrow().len(), 2);
+    }
+}
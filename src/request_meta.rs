@@ -14,6 +14,7 @@ pub struct RequestMeta {
     pub name: String,
     pub cert_type: CertType,
     pub domain: Option<String>,
+    pub role: Option<String>,
 }
 
 impl RequestMeta {
@@ -33,7 +34,12 @@ impl RequestMeta {
                     Some(domain)
                 } else {
                     None
-                }
+                },
+            role: if let Some(Ok(role)) = frame.meta("role") {
+                    Some(role)
+                } else {
+                    None
+                },
         })
     }
 }
@@ -61,6 +67,7 @@ mod tests {
         let client_cert = ZCert::new().unwrap();
         client_cert.set_meta("name", "ben.dover");
         client_cert.set_meta("type", "user");
+        client_cert.set_meta("role", "admin");
         client_cert.apply(&mut client);
         client.connect(&format!("tcp://127.0.0.1:{}", port)).unwrap();
 
@@ -78,6 +85,7 @@ mod tests {
 
         client.send_str("test").unwrap();
         let frame = ZFrame::recv(&mut server).unwrap();
-        assert!(RequestMeta::new(&frame).is_ok());
+        let meta = RequestMeta::new(&frame).unwrap();
+        assert_eq!(meta.role.unwrap(), "admin");
     }
 }
@@ -0,0 +1,185 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Scheduled key rotation.
+//!
+//! When a cert's key pair is older than its type's policy allows, the
+//! server issues a replacement and publishes an ADD for it alongside
+//! the old key, so both authenticate for a grace window. The cert's
+//! owner fetches the new secret key from `cert::pending_rotation`,
+//! authenticating with the old one. Once the window ends, the new key
+//! replaces the old in storage and the old key's DEL is published.
+//!
+//! Pending rotations are held in memory, so secret keys never touch
+//! the disk. Only their new public keys are stored, so that after a
+//! restart abandons them the first check withdraws those keys with a
+//! DEL, then starts the rotations afresh with new keys.
+//!
+//! Certs issued before rotation policies existed have no issue time.
+//! The first check stamps them with the time of the check, which their
+//! schedule then counts from.
+
+use api::CertApi;
+use cert::{Cert, CertType};
+use config::RotationPolicy;
+use czmq::ZSock;
+use error::Result;
+use expiry;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::hash_map::Values;
+use std::rc::Rc;
+use std::result::Result as StdResult;
+use std::time::Duration;
+use storage::PersistenceAdaptor;
+use zap_proxy::ticker;
+use zdaemon::{Endpoint, Error as DError};
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+pub struct PendingRotation {
+    pub old: Cert,
+    pub new: Cert,
+    /// When the old key is retired, in seconds since the Unix epoch
+    pub retire_at: u64,
+}
+
+pub struct Rotations {
+    policies: HashMap<CertType, RotationPolicy>,
+    // Keyed by cert name
+    pending: HashMap<String, PendingRotation>,
+}
+
+impl Rotations {
+    pub fn new(policies: HashMap<CertType, RotationPolicy>) -> Rotations {
+        Rotations {
+            policies: policies,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Whether certs of `cert_type` are rotated on a schedule.
+    pub fn applies_to(&self, cert_type: CertType) -> bool {
+        self.policies.contains_key(&cert_type)
+    }
+
+    /// Whether `cert`'s key pair is due for replacement as of `now`.
+    /// Certs without an issue time, or with a malformed one, count as
+    /// issued `now`, so aren't due.
+    pub fn is_due(&self, cert: &Cert, now: u64) -> bool {
+        let policy = match self.policies.get(&cert.cert_type()) {
+            Some(p) => p,
+            None => return false,
+        };
+        if cert.is_revoked() || cert.is_disabled() || self.pending.contains_key(cert.name()) {
+            return false;
+        }
+        cert.issued_at().unwrap_or(now) + policy.every_days * DAY_SECS <= now
+    }
+
+    /// Start replacing `old` with `new`, retiring `old` once its
+    /// type's grace window has passed.
+    pub fn start(&mut self, old: Cert, new: Cert, now: u64) {
+        let grace = self.policies.get(&old.cert_type()).map(|p| p.grace_days).unwrap_or(0);
+        self.pending.insert(old.name().to_string(), PendingRotation {
+            old: old,
+            new: new,
+            retire_at: now + grace * DAY_SECS,
+        });
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PendingRotation> {
+        self.pending.get(name)
+    }
+
+    pub fn pending(&self) -> Values<String, PendingRotation> {
+        self.pending.values()
+    }
+
+    /// Stop rotating `name`, e.g. because it was deleted or rotated by
+    /// hand.
+    pub fn cancel(&mut self, name: &str) -> Option<PendingRotation> {
+        self.pending.remove(name)
+    }
+
+    /// Remove and return the rotations whose grace window has ended.
+    pub fn take_finished(&mut self, now: u64) -> Vec<PendingRotation> {
+        let names: Vec<String> = self.pending.iter().filter(|&(_, r)| r.retire_at <= now).map(|(n, _)| n.clone()).collect();
+        names.iter().filter_map(|n| self.pending.remove(n)).collect()
+    }
+}
+
+/// Checks for due and finished rotations every interval.
+pub struct RotationScheduler<P> {
+    ticker: ZSock,
+    api: Rc<RefCell<CertApi<P>>>,
+}
+
+impl<P> RotationScheduler<P> where P: PersistenceAdaptor {
+    pub fn new(interval: Duration, api: Rc<RefCell<CertApi<P>>>) -> Result<RotationScheduler<P>> {
+        Ok(RotationScheduler {
+            ticker: try!(ticker(interval)),
+            api: api,
+        })
+    }
+}
+
+impl<P> Endpoint for RotationScheduler<P> where P: PersistenceAdaptor {
+    fn get_sockets(&mut self) -> Vec<&mut ZSock> {
+        vec![&mut self.ticker]
+    }
+
+    fn recv(&mut self, sock: &mut ZSock) -> StdResult<(), DError> {
+        let _ = try!(sock.recv_str());
+        try!(self.api.borrow_mut().run_rotations(expiry::now()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType, ISSUED_META, REVOKED_META};
+    use config::RotationPolicy;
+    use std::collections::HashMap;
+    use super::*;
+
+    #[test]
+    fn test_rotations() {
+        let mut policies = HashMap::new();
+        policies.insert(CertType::Host, RotationPolicy { every_days: 90, grace_days: 7 });
+        let mut rotations = Rotations::new(policies);
+
+        let host = Cert::new("web1.example.com", CertType::Host).unwrap();
+        host.set_meta(ISSUED_META, "0");
+        let user = Cert::new("john.smith", CertType::User).unwrap();
+        let legacy = Cert::new("web2.example.com", CertType::Host).unwrap();
+        let revoked = Cert::new("web3.example.com", CertType::Host).unwrap();
+        revoked.set_meta(REVOKED_META, "true");
+
+        assert!(!rotations.is_due(&host, 89 * DAY_SECS));
+        assert!(rotations.is_due(&host, 90 * DAY_SECS));
+        assert!(!rotations.is_due(&user, 90 * DAY_SECS));
+        assert!(!rotations.is_due(&legacy, 90 * DAY_SECS));
+        assert!(rotations.applies_to(CertType::Host));
+        assert!(!rotations.applies_to(CertType::User));
+        assert!(!rotations.is_due(&revoked, 90 * DAY_SECS));
+
+        let new = Cert::new("web1.example.com", CertType::Host).unwrap();
+        let new_key = new.public_txt().to_string();
+        rotations.start(Cert::from_zcert(host.dup()).unwrap(), new, 90 * DAY_SECS);
+        assert!(!rotations.is_due(&host, 90 * DAY_SECS));
+        assert_eq!(rotations.get("web1.example.com").unwrap().new.public_txt(), new_key);
+        assert_eq!(rotations.pending().count(), 1);
+
+        assert!(rotations.take_finished(96 * DAY_SECS).is_empty());
+        let finished = rotations.take_finished(97 * DAY_SECS);
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].old.public_txt(), host.public_txt());
+        assert!(rotations.get("web1.example.com").is_none());
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Wrapper for secret key material that keeps it out of formatted
+//! output.
+//!
+//! `Secret` prints as `[REDACTED]` for both `{}` and `{:?}`, so a secret
+//! can't end up in a log line, trace or error message by accident. Code
+//! that needs the value has to ask for it with `expose()`.
+
+use std::fmt;
+
+const REDACTED: &'static str = "[REDACTED]";
+
+#[derive(Clone, PartialEq)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Secret<T> {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret({})", REDACTED)
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted() {
+        let secret = Secret::new("hunter2".to_string());
+        assert!(!format!("{}", secret).contains("hunter2"));
+        assert!(!format!("{:?}", secret).contains("hunter2"));
+        assert!(!format!("{:#?}", Some(&secret)).contains("hunter2"));
+        assert_eq!(secret.expose(), "hunter2");
+        assert_eq!(secret.into_inner(), "hunter2");
+    }
+}
@@ -11,58 +11,129 @@ extern crate chan_signal;
 extern crate czmq;
 extern crate docopt;
 extern crate env_logger;
+#[cfg(feature = "grpc")]
+extern crate grpc;
 extern crate inauth_client;
+extern crate libc;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "hsm")]
+extern crate pkcs11;
+#[cfg(feature = "grpc")]
+extern crate protobuf;
+#[cfg(feature = "policy")]
+extern crate rhai;
 extern crate rustc_serialize;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate sodiumoxide;
 #[cfg(test)]
 extern crate tempdir;
 extern crate zdaemon;
 extern crate zmq;
+#[cfg(feature = "zstd")]
+extern crate zstd;
 
+mod admin;
+#[allow(dead_code)]
+mod affinity;
 mod api;
+#[allow(dead_code)]
+mod attestation;
+mod cbor;
 mod cert;
 mod cert_cache;
+mod compression;
 mod config;
 mod error;
+mod expiry;
+#[allow(dead_code)]
+mod feed_signature;
+mod filter;
+mod fleet;
+#[cfg(feature = "grpc")]
+mod grpc_service;
+#[allow(dead_code)]
+mod hsm;
+mod http_gateway;
+mod key_health;
+mod last_value;
+#[allow(dead_code)]
+mod latency;
+mod messages;
+#[allow(dead_code)]
+mod policy;
+mod protocol;
+mod quota;
+mod rate_limit;
+mod request_id;
 mod request_meta;
+mod rotation;
+#[allow(dead_code)]
+mod secret;
+mod server_key;
 mod storage;
+#[allow(dead_code)]
+mod timeouts;
+#[allow(dead_code)]
+mod wire_trace;
+mod ws_feed;
+mod zap_bridge;
 mod zap_proxy;
 
-use api::CertApi;
-use cert_cache::CertCache;
+use admin::Admin;
+use api::{CertApi, ExpiryApi, FleetApi, InfoApi};
+use cert_cache::{CertCache, SharedCertCache};
 use chan_signal::Signal;
 use config::Config;
-use czmq::{ZCert, ZFrame, ZMsg, ZSock, SocketType, ZSys};
+use czmq::{ZCert, ZFrame, ZSock, SocketType, ZSys};
 use docopt::Docopt;
-use error::Result;
-use inauth_client::{CertType, ZapHandler};
+use env_logger::LogBuilder;
+use error::{Error, Result};
+use expiry::ExpiryNotifier;
+use fleet::FleetHealth;
+use inauth_client::{AddressPolicy, AddressRules, BanPolicy, CertType, DenyReason, Error as ClientError, LogSampler, ZapHandler, ZapStatus};
+use log::{LogLevelFilter, MaxLogLevelFilter};
+use policy::{Hook, PolicyLimits, PolicyScript};
+use quota::CreateQuotas;
+use rate_limit::RateLimiter;
+use request_id::TracedApi;
+use request_meta::RequestMeta;
+use rotation::{RotationScheduler, Rotations};
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::{env, fs};
-use std::io::Read;
+use std::io;
 use std::rc::Rc;
 use std::result::Result as StdResult;
 use std::path::Path;
 use std::process::exit;
 use std::thread::spawn;
-use storage::{PersistDisk, PersistenceAdaptor};
-use zdaemon::{Api, Error as DError, Service, ZMsgExtended};
+use std::time::Duration;
+use storage::{FilePerms, PersistDisk, PersistenceAdaptor};
+use storage::mirror::MirroredStorage;
+use timeouts::SocketTimeouts;
+use wire_trace::{Direction, WireTracer};
+use zap_bridge::ZapBridge;
+use zap_proxy::{Attestor, PublisherAllowlist};
+use zdaemon::{Api, Error as DError, Service};
 
 static USAGE: &'static str = "
 Intecture Auth.
 
 Usage:
-  inauth [(-c <path> | --config <path>)]
+  inauth [(-c <path> | --config <path>)] [--trace-wire <file>] [--force]
   inauth (-h | --help)
   inauth --version
 
 Options:
   -c --config <path>    Path to auth.json, e.g. \"/usr/local/etc\"
+  --force               Start even if the cert store was written by a
+                        newer release. This risks corrupting it.
   -h --help             Show this screen.
+  --trace-wire <file>   Append protocol frame dumps to <file> for debugging.
   --version             Print this script's version.
 ";
 
@@ -71,8 +142,10 @@ Options:
 struct Args {
     flag_c: Option<String>,
     flag_config: Option<String>,
+    flag_force: bool,
     flag_h: bool,
     flag_help: bool,
+    flag_trace_wire: Option<String>,
     flag_version: bool,
 }
 
@@ -86,69 +159,375 @@ fn main() {
         exit(0);
     } else {
         let config_path = if args.flag_c.is_some() { args.flag_c.as_ref() } else { args.flag_config.as_ref() };
-        if let Err(e) = start(config_path) {
+        if let Err(e) = start(config_path, args.flag_trace_wire.as_ref(), args.flag_force) {
             println!("{}", e);
             exit(1);
         }
     }
 }
 
-fn start<P: AsRef<Path>>(path: Option<P>) -> Result<()> {
-    let signal = chan_signal::notify(&[Signal::INT, Signal::TERM]);
-    env_logger::init()?;
+fn start<P: AsRef<Path>>(path: Option<P>, trace_path: Option<P>, force: bool) -> Result<()> {
+    let signal = chan_signal::notify(&[Signal::INT, Signal::TERM, Signal::HUP]);
+    let log_level = init_logger()?;
     let (parent, child) = ZSys::create_pipe()?;
 
-    let config = read_conf(path)?;
+    let path = path.map(|p| p.as_ref().to_owned());
+    let config = read_conf(path.as_ref())?;
 
-    // Create new server cert if missing
-    let server_cert = match fs::metadata(&config.server_cert) {
-        Ok(_) => ZCert::load(&config.server_cert)?,
-        Err(_) => {
+    // Create new server cert if missing. Keys on an HSM are only ever
+    // put there by `inauth_cli server hsm-import`.
+    let server_cert = match (&config.hsm, fs::metadata(&config.server_cert)) {
+        (&Some(ref h), _) => hsm::load(h, &ZCert::load(&format!("{}_public", &config.server_cert))?)?,
+        (&None, Ok(_)) => server_key::load(&config.server_cert, config.server_cert_passphrase.as_ref())?,
+        (&None, Err(_)) => {
             let c = ZCert::new()?;
             c.set_meta("name", "auth");
             c.set_meta("type", CertType::Host.to_str());
             c.save_public(&format!("{}_public", &config.server_cert))?;
-            c.save_secret(&config.server_cert)?;
+            match config.server_cert_passphrase {
+                Some(ref source) => server_key::save_encrypted(&c, &config.server_cert, server_key::read_passphrase(source)?.expose())?,
+                None => c.save_secret(&config.server_cert)?,
+            }
             c
         }
     };
 
     let mut persistence = PersistDisk::new(&config.cert_path)?;
+    persistence.set_file_perms(FilePerms::from_config(config.cert_files.as_ref())?);
+    storage::check_version(&mut persistence, force)?;
+
+    // Certs stored before keys were checked at creation time
+    for (name, problem) in key_health::audit(&persistence.dump()?) {
+        warn!("Certificate {} has an unsafe key: {}", name, problem);
+    }
+
+    // The tracer is shared between endpoints, so it is opened in the
+    // service thread. Check the path up front so errors surface here.
+    if let Some(ref p) = trace_path {
+        fs::OpenOptions::new().create(true).append(true).open(p)?;
+    }
+    let trace_path = trace_path.map(|p| p.as_ref().to_owned());
+
+    let socket_timeouts = match config.socket_timeouts {
+        Some(t) => SocketTimeouts {
+            send: t.send_ms.map(Duration::from_millis),
+            recv: t.recv_ms.map(Duration::from_millis),
+        },
+        None => SocketTimeouts::default(),
+    };
 
     let mut api_sock = ZSock::new(SocketType::ROUTER);
+    socket_timeouts.apply(&api_sock);
     api_sock.set_zap_domain("auth.intecture");
     api_sock.set_curve_server(true);
     server_cert.apply(&mut api_sock);
     api_sock.bind(&format!("tcp://*:{}", config.api_port))?;
 
-    let _auth = ZapHandler::new(None, &server_cert, &server_cert, "127.0.0.1", config.update_port, true);
+    // The gateway thread blocks on accept() for the life of the
+    // process, so it is detached rather than joined on shutdown.
+    let gateway_enabled = config.http_gateway.is_some() || config.grpc.is_some();
+    if let Some(ref gateway_config) = config.http_gateway {
+        http_gateway::spawn_gateway(gateway_config.clone())?;
+    }
+    if let Some(ref ws_config) = config.websocket {
+        ws_feed::spawn_ws_feed(ws_config.clone())?;
+    }
+    let _grpc = start_grpc(&config)?;
+
+    let admin_sock = match config.admin_socket {
+        Some(ref endpoint) => {
+            let sock = admin::bind(endpoint)?;
+            socket_timeouts.apply(&sock);
+            Some(sock)
+        },
+        None => None,
+    };
+    let config_dump = config::dump(&config)?;
+    let api_policy = load_policy(&config)?;
+
+    let ban_policy = match config.zap_ban {
+        Some(b) => BanPolicy {
+            threshold: b.threshold,
+            window: Duration::from_secs(b.window_secs),
+            ban: Duration::from_secs(b.ban_secs),
+            max_ban: Duration::from_secs(b.max_ban_secs),
+        },
+        None => BanPolicy::default(),
+    };
+    let attestor = match config.attestation {
+        Some(ref a) => {
+            let (public_key, secret_key) = attestation::load_or_create_key(&a.key_path)?;
+            info!("Signing attestations with public key {}", attestation::public_key_to_hex(&public_key));
+            Some((secret_key, Duration::from_secs(a.interval_secs.unwrap_or(60))))
+        },
+        None => None,
+    };
+    let feed_key = match config.attestation {
+        Some(ref a) if a.sign_feed.unwrap_or(false) => attestor.as_ref().map(|&(ref key, _)| key.clone()),
+        _ => None,
+    };
+    let warn_within = config.expiry_notices.as_ref().and_then(|e| e.warn_days).map(|d| d * 24 * 60 * 60).unwrap_or(expiry::DEFAULT_WARN_SECS);
+    let expiry_notifier = match config.expiry_notices {
+        Some(ref e) => {
+            let webhooks = e.webhooks.clone().unwrap_or(Vec::new());
+            for url in &webhooks {
+                expiry::check_webhook(url)?;
+            }
+            Some((Duration::from_secs(e.scan_interval_secs.unwrap_or(3600)), webhooks))
+        },
+        None => None,
+    };
+    let mut rotation_policies = HashMap::new();
+    if let Some(ref r) = config.rotation {
+        for (cert_type, policy) in &r.policies {
+            rotation_policies.insert(cert::CertType::from_str(cert_type)?, *policy);
+        }
+    }
+    let rotation_enabled = !rotation_policies.is_empty();
+    let rotation_interval = Duration::from_secs(config.rotation.as_ref().and_then(|r| r.check_interval_secs).unwrap_or(3600));
+    let mut bridges = Vec::new();
+    let mut publisher_allowlist = None;
+    if let Some(ref fed) = config.federation {
+        for b in &fed.bridges {
+            bridges.push((ZCert::load(&b.server_cert)?, b.endpoint.clone(), fed.origin.clone(), b.origin.clone()));
+        }
+        if let Some(ref a) = fed.allowed_publishers {
+            let mut cert_types = Vec::new();
+            for t in a.cert_types.as_ref().unwrap_or(&Vec::new()) {
+                cert_types.push(cert::CertType::from_str(t)?);
+            }
+            publisher_allowlist = Some(PublisherAllowlist {
+                names: a.names.clone().unwrap_or(Vec::new()),
+                cert_types: cert_types,
+            });
+        }
+    }
+
+    let auth = ZapHandler::new_with_policy(None, &server_cert, &server_cert, "127.0.0.1", config.update_port, true, ban_policy);
+    if let Ok(ref a) = auth {
+        apply_zap_config(a, &config)?;
+    }
+    // Shared with the feed proxy, so one admin request samples both
+    let log_sampler = match auth {
+        Ok(ref a) => a.log_sampler(),
+        Err(_) => LogSampler::new(),
+    };
 
     let thread = spawn(move || {
         let mut service = Service::new(child).unwrap();
 
-        let cert_cache = Rc::new(RefCell::new(CertCache::new(Some(persistence.dump().unwrap()))));
-
-        let (zap_publisher, zap_subscriber) = zap_proxy::init(&server_cert, config.update_port, cert_cache.clone()).unwrap();
-        service.add_endpoint(zap_publisher).unwrap();
+        let tracer = match trace_path {
+            Some(p) => WireTracer::new(p).unwrap(),
+            None => WireTracer::disabled(),
+        };
+
+        let cert_cache = SharedCertCache::new(CertCache::new(Some(persistence.dump().unwrap())));
+
+        let (mut zap_publisher, mut zap_subscriber) = zap_proxy::init(&server_cert, config.update_port, config.feed_endpoint.clone(), config.affinity_tags.clone().unwrap_or(Vec::new()), cert_cache.clone(), tracer.clone(), log_sampler.clone()).unwrap();
+        // Endpoints are dropped in order on shutdown. The subscriber
+        // must drain into the publisher before the publisher flushes.
+        let feed_stats = zap_publisher.stats();
+        zap_publisher.set_heartbeat_interval(Duration::from_secs(config.heartbeat_interval_secs.unwrap_or(5))).unwrap();
+        zap_publisher.set_snapshot_batch_size(config.snapshot_batch_size.unwrap_or(zap_proxy::DEFAULT_SNAPSHOT_BATCH_SIZE));
+        zap_publisher.set_last_value_capacity(config.last_value_capacity.unwrap_or(last_value::DEFAULT_CAPACITY));
+        zap_publisher.set_socket_timeouts(socket_timeouts);
+        if let Some(key) = feed_key {
+            zap_publisher.set_signing_key(key);
+        }
+        if let Some(ref allowlist) = publisher_allowlist {
+            let publishers: Vec<_> = bridges.iter().map(|&(ref remote_cert, _, _, ref remote_origin)| (remote_origin.clone(), remote_cert)).collect();
+            zap_subscriber.set_publisher_allowlist(allowlist, &publishers);
+        }
         service.add_endpoint(zap_subscriber).unwrap();
+        service.add_endpoint(zap_publisher).unwrap();
+
+        for (remote_cert, endpoint, origin, remote_origin) in bridges {
+            service.add_endpoint(ZapBridge::new(&server_cert, &remote_cert, &endpoint, &origin, &remote_origin, tracer.clone()).unwrap()).unwrap();
+        }
+
+        if let Some((key, interval)) = attestor {
+            service.add_endpoint(Attestor::new(interval, cert_cache.clone(), feed_stats.clone(), key).unwrap()).unwrap();
+        }
 
-        let api_create = Rc::new(RefCell::new(CertApi::new(persistence, cert_cache.clone()).unwrap()));
+        if let Some((interval, webhooks)) = expiry_notifier {
+            service.add_endpoint(ExpiryNotifier::new(interval, warn_within, webhooks, cert_cache.clone()).unwrap()).unwrap();
+        }
+
+        let api_create = Rc::new(RefCell::new(CertApi::new(MirroredStorage::new(persistence), cert_cache.clone(), tracer.clone(), config.list_masking, config.cert_requests.and_then(|c| c.notify_command), CreateQuotas::new(config.cert_quotas), Rotations::new(rotation_policies)).unwrap()));
         let api_delete = api_create.clone();
         let api_list = api_create.clone();
         let api_lookup = api_create.clone();
+        let api_search = api_create.clone();
+        let api_push = api_create.clone();
+        let api_resync = api_create.clone();
+        let api_request = api_create.clone();
+        let api_pending = api_create.clone();
+        let api_approve = api_create.clone();
+        let api_deny = api_create.clone();
+        let api_rotate = api_create.clone();
+        let api_revoke = api_create.clone();
+        let api_gateway = api_create.clone();
+        let api_admin = api_create.clone();
+        let api_pending_rotation = api_create.clone();
+        if rotation_enabled {
+            service.add_endpoint(RotationScheduler::new(rotation_interval, api_create.clone()).unwrap()).unwrap();
+        }
 
-        let mut api = Api::new(api_sock);
-        api.add("cert::create", move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = api_create.borrow_mut().create(s, f, &i); error_handler(s, &i, r) });
-        api.add("cert::delete", move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = api_delete.borrow_mut().delete(s, f, &i); error_handler(s, &i, r) });
-        api.add("cert::list", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = api_list.borrow_mut().list(s, &i); error_handler(s, &i, r) });
-        api.add("cert::lookup", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = api_lookup.borrow_mut().lookup(s, &i); error_handler(s, &i, r) });
+        let t_create = tracer.clone();
+        let t_delete = tracer.clone();
+        let t_list = tracer.clone();
+        let t_lookup = tracer.clone();
+        let t_search = tracer.clone();
+        let t_push = tracer.clone();
+        let t_resync = tracer.clone();
+        let t_request = tracer.clone();
+        let t_pending = tracer.clone();
+        let t_approve = tracer.clone();
+        let t_deny = tracer.clone();
+        let t_rotate = tracer.clone();
+        let t_revoke = tracer.clone();
+        let t_info = tracer.clone();
+        let t_describe = tracer.clone();
+        let t_fleet = tracer.clone();
+        let t_expiring = tracer.clone();
+        let t_pending_rotation = tracer.clone();
+
+        let limiter = Rc::new(RefCell::new(RateLimiter::new(config.rate_limits)));
+        let rl_create = limiter.clone();
+        let rl_delete = limiter.clone();
+        let rl_list = limiter.clone();
+        let rl_lookup = limiter.clone();
+        let rl_search = limiter.clone();
+        let rl_push = limiter.clone();
+        let rl_resync = limiter.clone();
+        let rl_request = limiter.clone();
+        let rl_pending = limiter.clone();
+        let rl_approve = limiter.clone();
+        let rl_deny = limiter.clone();
+        let rl_rotate = limiter.clone();
+        let rl_revoke = limiter.clone();
+        let rl_info = limiter.clone();
+        let rl_describe = limiter.clone();
+        let rl_fleet = limiter.clone();
+        let rl_expiring = limiter.clone();
+        let rl_pending_rotation = limiter.clone();
+
+        let policy = Rc::new(RefCell::new(api_policy));
+        let pol_create = policy.clone();
+        let pol_delete = policy.clone();
+        let pol_list = policy.clone();
+        let pol_lookup = policy.clone();
+        let pol_search = policy.clone();
+        let pol_push = policy.clone();
+        let pol_resync = policy.clone();
+        let pol_request = policy.clone();
+        let pol_pending = policy.clone();
+        let pol_approve = policy.clone();
+        let pol_deny = policy.clone();
+        let pol_rotate = policy.clone();
+        let pol_revoke = policy.clone();
+        let pol_info = policy.clone();
+        let pol_describe = policy.clone();
+        let pol_fleet = policy.clone();
+        let pol_expiring = policy.clone();
+        let pol_pending_rotation = policy.clone();
+
+        let info_api = Rc::new(InfoApi::new(config.feed_endpoint.clone(), config.affinity_tags.clone().unwrap_or(Vec::new()), cert_cache.clone(), feed_stats.clone(), tracer.clone()));
+        let describe_api = info_api.clone();
+        let resync_stats = feed_stats.clone();
+        let fleet = Rc::new(RefCell::new(FleetHealth::new()));
+        let fleet_api = FleetApi::new(fleet.clone(), tracer.clone());
+        let expiry_api = Rc::new(ExpiryApi::new(cert_cache.clone(), warn_within, tracer.clone()));
+        let a_expiring = expiry_api.clone();
+
+        let mut api = TracedApi::new(api_sock);
+        api.add(protocol::CERT_CREATE.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_create, s, "cert::create", &f).and_then(|_| check_policy(&pol_create, s, "cert::create", &f)).and_then(|_| api_create.borrow_mut().create(s, f, &i)); error_handler(s, &i, &t_create, r) });
+        api.add(protocol::CERT_APPROVE.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_approve, s, "cert::approve", &f).and_then(|_| check_policy(&pol_approve, s, "cert::approve", &f)).and_then(|_| api_approve.borrow_mut().approve(s, f, &i)); error_handler(s, &i, &t_approve, r) });
+        api.add(protocol::CERT_DELETE.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_delete, s, "cert::delete", &f).and_then(|_| check_policy(&pol_delete, s, "cert::delete", &f)).and_then(|_| api_delete.borrow_mut().delete(s, f, &i)); error_handler(s, &i, &t_delete, r) });
+        api.add(protocol::CERT_DENY.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_deny, s, "cert::deny", &f).and_then(|_| check_policy(&pol_deny, s, "cert::deny", &f)).and_then(|_| api_deny.borrow_mut().deny(s, f, &i)); error_handler(s, &i, &t_deny, r) });
+        api.add(protocol::CERT_EXPIRING.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_expiring, s, "cert::expiring", &f).and_then(|_| check_policy(&pol_expiring, s, "cert::expiring", &f)).and_then(|_| expiry_api.expiring(s, f, &i)); error_handler(s, &i, &t_expiring, r) });
+        api.add(protocol::CERT_LIST.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_list, s, "cert::list", &f).and_then(|_| check_policy(&pol_list, s, "cert::list", &f)).and_then(|_| api_list.borrow_mut().list(s, f, &i)); error_handler(s, &i, &t_list, r) });
+        api.add(protocol::CERT_LOOKUP.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_lookup, s, "cert::lookup", &f).and_then(|_| check_policy(&pol_lookup, s, "cert::lookup", &f)).and_then(|_| api_lookup.borrow_mut().lookup(s, f, &i)); error_handler(s, &i, &t_lookup, r) });
+        api.add(protocol::CERT_PENDING_LIST.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_pending, s, "cert::pending_list", &f).and_then(|_| check_policy(&pol_pending, s, "cert::pending_list", &f)).and_then(|_| api_pending.borrow_mut().pending_list(s, f, &i)); error_handler(s, &i, &t_pending, r) });
+        api.add(protocol::CERT_PENDING_ROTATION.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_pending_rotation, s, "cert::pending_rotation", &f).and_then(|_| check_policy(&pol_pending_rotation, s, "cert::pending_rotation", &f)).and_then(|_| api_pending_rotation.borrow_mut().pending_rotation(s, f, &i)); error_handler(s, &i, &t_pending_rotation, r) });
+        api.add(protocol::CERT_REQUEST.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_request, s, "cert::request", &f).and_then(|_| check_policy(&pol_request, s, "cert::request", &f)).and_then(|_| api_request.borrow_mut().request(s, f, &i)); error_handler(s, &i, &t_request, r) });
+        api.add(protocol::CERT_REVOKE.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_revoke, s, "cert::revoke", &f).and_then(|_| check_policy(&pol_revoke, s, "cert::revoke", &f)).and_then(|_| api_revoke.borrow_mut().revoke(s, f, &i)); error_handler(s, &i, &t_revoke, r) });
+        api.add(protocol::CERT_ROTATE.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_rotate, s, "cert::rotate", &f).and_then(|_| check_policy(&pol_rotate, s, "cert::rotate", &f)).and_then(|_| api_rotate.borrow_mut().rotate(s, f, &i)); error_handler(s, &i, &t_rotate, r) });
+        api.add(protocol::CERT_SEARCH.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_search, s, "cert::search", &f).and_then(|_| check_policy(&pol_search, s, "cert::search", &f)).and_then(|_| api_search.borrow_mut().search(s, f, &i)); error_handler(s, &i, &t_search, r) });
+        api.add(protocol::FEED_PUSH.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_push, s, "feed::push", &f).and_then(|_| check_policy(&pol_push, s, "feed::push", &f)).and_then(|_| api_push.borrow_mut().push(s, f, &i)); error_handler(s, &i, &t_push, r) });
+        api.add(protocol::FEED_RESYNC.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_resync, s, "feed::resync", &f).and_then(|_| check_policy(&pol_resync, s, "feed::resync", &f)).and_then(|_| api_resync.borrow_mut().resync(s, &i, resync_stats.borrow().sequence)); error_handler(s, &i, &t_resync, r) });
+        api.add(protocol::SERVER_INFO.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_info, s, "server::info", &f).and_then(|_| check_policy(&pol_info, s, "server::info", &f)).and_then(|_| info_api.info(s, &i)); error_handler(s, &i, &t_info, r) });
+        api.add(protocol::FLEET_REPORT.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_fleet, s, "fleet::report", &f).and_then(|_| check_policy(&pol_fleet, s, "fleet::report", &f)).and_then(|_| fleet_api.report(s, f, &i)); error_handler(s, &i, &t_fleet, r) });
+        api.add(protocol::API_DESCRIBE.name, move |s: &mut ZSock, f: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = rate_limit::check_request(&rl_describe, s, "api::describe", &f).and_then(|_| check_policy(&pol_describe, s, "api::describe", &f)).and_then(|_| describe_api.describe(s, &i)); error_handler(s, &i, &t_describe, r) });
         service.add_endpoint(api).unwrap();
 
+        if let Some(sock) = admin_sock {
+            let admin = Rc::new(Admin::new(cert_cache.clone(), feed_stats, fleet, config_dump, log_level, log_sampler));
+            let a_attest = admin.clone();
+            let a_cache = admin.clone();
+            let a_feed = admin.clone();
+            let a_config = admin.clone();
+            let a_drain = admin.clone();
+            let a_log = admin.clone();
+            let a_sampling = admin.clone();
+            let a_fleet = admin;
+            let a_push = api_admin.clone();
+            let a_pending = api_admin.clone();
+            let a_approve = api_admin.clone();
+            let a_deny = api_admin.clone();
+            let a_storage = api_admin;
+
+            let mut admin_api = Api::new(sock);
+            admin_api.add("attest::latest", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = a_attest.attestation(s, &i); admin_error_handler(s, &i, r) });
+            admin_api.add("cache::stats", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = a_cache.cache_stats(s, &i); admin_error_handler(s, &i, r) });
+            admin_api.add("cert::approve", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = a_approve.borrow_mut().do_approve(s, &i); admin_error_handler(s, &i, r) });
+            admin_api.add("cert::deny", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = a_deny.borrow_mut().do_deny(s, &i); admin_error_handler(s, &i, r) });
+            admin_api.add("cert::expiring", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = a_expiring.do_expiring(s, &i); admin_error_handler(s, &i, r) });
+            admin_api.add("cert::pending_list", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = a_pending.borrow_mut().do_pending_list(s, &i); admin_error_handler(s, &i, r) });
+            admin_api.add("config::dump", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = a_config.config_dump(s, &i); admin_error_handler(s, &i, r) });
+            admin_api.add("feed::drain", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = a_drain.drain(s, &i); admin_error_handler(s, &i, r) });
+            admin_api.add("feed::subscribers", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = a_feed.feed_subscribers(s, &i); admin_error_handler(s, &i, r) });
+            admin_api.add("feed::push", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = a_push.borrow_mut().do_push(s, &i); admin_error_handler(s, &i, r) });
+            admin_api.add("fleet::cache_health", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = a_fleet.fleet_cache_health(s, &i); admin_error_handler(s, &i, r) });
+            admin_api.add("log::level", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = a_log.log_level(s, &i); admin_error_handler(s, &i, r) });
+            admin_api.add("log::sampling", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = a_sampling.log_sampling(s, &i); admin_error_handler(s, &i, r) });
+            admin_api.add("storage::switch", move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = a_storage.borrow_mut().do_storage_switch(s, &i); admin_error_handler(s, &i, r) });
+            service.add_endpoint(admin_api).unwrap();
+        }
+
+        // Requests from the HTTP gateway have already been
+        // authenticated by bearer token. The caller's identity arrives
+        // in the frames following the endpoint.
+        if gateway_enabled {
+            let routes: [(&'static str, GatewayHandler); 6] = [
+                ("cert::create", CertApi::do_create),
+                ("cert::delete", CertApi::do_delete),
+                ("cert::list", CertApi::do_list),
+                ("cert::lookup", CertApi::do_lookup),
+                ("cert::rotate", CertApi::do_rotate),
+                ("cert::search", CertApi::do_search),
+            ];
+            let mut gateway = TracedApi::new(ZSock::new_router(&format!("@{}", http_gateway::GATEWAY_ENDPOINT)).unwrap());
+            for &(endpoint, handler) in &routes {
+                add_gateway_route(&mut gateway, endpoint, handler, api_gateway.clone(), limiter.clone(), policy.clone(), tracer.clone());
+            }
+            service.add_endpoint(gateway).unwrap();
+        }
+
         service.start(None).unwrap();
     });
 
-    // Wait for interrupt from system
-    signal.recv().unwrap();
+    // Wait for interrupt from system, reloading ZAP settings on SIGHUP
+    while let Some(Signal::HUP) = signal.recv() {
+        let result = match auth {
+            Ok(ref a) => read_conf(path.as_ref()).and_then(|c| apply_zap_config(a, &c)),
+            Err(_) => Ok(()),
+        };
+        match result {
+            Ok(_) => info!("Reloaded ZAP settings from config"),
+            Err(e) => error!("Could not reload ZAP settings: {}", e),
+        }
+    }
 
     // Terminate loop
     parent.signal(1)?;
@@ -157,14 +536,175 @@ fn start<P: AsRef<Path>>(path: Option<P>) -> Result<()> {
     Ok(())
 }
 
-fn error_handler(sock: &mut ZSock, router_id: &[u8], result: Result<()>) -> StdResult<(), DError> {
+// Apply the reloadable parts of the config to the ZAP handler
+fn apply_zap_config(auth: &ZapHandler, config: &Config) -> Result<()> {
+    if let Some(text) = config.messages.as_ref().and_then(|m| m.zap_denied.as_ref()) {
+        auth.set_denied_text(text).map_err(client_error)?;
+    }
+    if let Some(replies) = config.messages.as_ref().and_then(|m| m.zap_replies.as_ref()) {
+        for (reason, reply) in replies {
+            let reason = DenyReason::from_str(reason).map_err(client_error)?;
+            let status = ZapStatus::from_code(reply.status.unwrap_or(400)).map_err(client_error)?;
+            auth.set_deny_reply(reason, status, reply.text.as_ref().map(|t| t.as_str())).map_err(client_error)?;
+        }
+    }
+
+    let mut rules = HashMap::new();
+    if let Some(ref addresses) = config.zap_addresses {
+        for (key, r) in addresses {
+            let allow = r.allow.clone().unwrap_or_default();
+            let deny = r.deny.clone().unwrap_or_default();
+            rules.insert(key.clone(), AddressRules::new(&allow, &deny).map_err(client_error)?);
+        }
+    }
+    auth.set_address_policy(AddressPolicy::new(rules).map_err(client_error)?);
+
+    let script = match config.policy {
+        Some(ref p) => {
+            let limits = inauth_client::PolicyLimits {
+                timeout: Duration::from_millis(p.timeout_ms.unwrap_or(50)),
+                max_operations: p.max_operations.unwrap_or(100_000),
+                ..inauth_client::PolicyLimits::default()
+            };
+            Some(inauth_client::PolicyScript::load(&p.script, limits).map_err(client_error)?)
+        },
+        None => None,
+    };
+    auth.set_policy(script);
+    Ok(())
+}
+
+// The ZAP handler comes from the client library, which has its own
+// copy of our error type.
+fn client_error(e: ClientError) -> Error {
+    match e {
+        ClientError::InvalidAddressRule(e) => Error::InvalidAddressRule(e),
+        ClientError::InvalidConfig(e) => Error::InvalidConfig(e),
+        ClientError::InvalidStatusText(e) => Error::InvalidStatusText(e),
+        ClientError::InvalidZapReply(e) => Error::InvalidZapReply(e),
+        ClientError::Io(e) => Error::Io(e),
+        ClientError::Policy(e) => Error::Policy(e),
+        e => Error::Io(io::Error::new(io::ErrorKind::Other, e.to_string())),
+    }
+}
+
+fn load_policy(config: &Config) -> Result<Option<PolicyScript>> {
+    match config.policy {
+        Some(ref p) => {
+            let limits = PolicyLimits {
+                timeout: Duration::from_millis(p.timeout_ms.unwrap_or(50)),
+                max_operations: p.max_operations.unwrap_or(100_000),
+                ..PolicyLimits::default()
+            };
+            Ok(Some(PolicyScript::load(&p.script, limits)?))
+        },
+        None => Ok(None),
+    }
+}
+
+// Run the policy script's `api` hook. Like rate_limit::check_request(),
+// a denied request is drained so it isn't read as a new one.
+fn check_policy(policy: &RefCell<Option<PolicyScript>>, sock: &mut ZSock, endpoint: &str, endpoint_frame: &ZFrame) -> Result<()> {
+    if policy.borrow().is_none() {
+        return Ok(());
+    }
+    check_identity_policy(policy, sock, endpoint, &RequestMeta::new(endpoint_frame)?)
+}
+
+fn check_identity_policy(policy: &RefCell<Option<PolicyScript>>, sock: &mut ZSock, endpoint: &str, meta: &RequestMeta) -> Result<()> {
+    if let Some(ref mut script) = *policy.borrow_mut() {
+        let request = policy::api_request(endpoint, &meta.name, meta.cert_type.to_str(), meta.domain.as_ref(), meta.role.as_ref());
+        if !script.allows(Hook::Api, &request) {
+            debug!("{}Policy script denied {} on {}", request_id::log_prefix(), meta.name, endpoint);
+            while sock.rcvmore() {
+                ZFrame::recv(sock)?;
+            }
+            return Err(Error::Forbidden);
+        }
+    }
+
+    Ok(())
+}
+
+type GatewayHandler = fn(&mut CertApi<MirroredStorage<PersistDisk>>, &mut ZSock, &[u8], &RequestMeta) -> Result<()>;
+
+// Serve `endpoint` to the HTTP and gRPC gateways, applying the same
+// rate limits and policy script as the CURVE API before `handler`
+// checks the identity's permissions
+fn add_gateway_route(gateway: &mut TracedApi,
+                     endpoint: &'static str,
+                     handler: GatewayHandler,
+                     api: Rc<RefCell<CertApi<MirroredStorage<PersistDisk>>>>,
+                     limiter: Rc<RefCell<RateLimiter>>,
+                     policy: Rc<RefCell<Option<PolicyScript>>>,
+                     tracer: WireTracer) {
+    gateway.add(endpoint, move |s: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| {
+        let i = id.unwrap();
+        let r = http_gateway::recv_identity(s).and_then(|m| {
+            rate_limit::check_identity(&limiter, s, endpoint, &m)?;
+            check_identity_policy(&policy, s, endpoint, &m)?;
+            handler(&mut *api.borrow_mut(), s, &i, &m)
+        });
+        error_handler(s, &i, &tracer, r)
+    });
+}
+
+#[cfg(feature = "grpc")]
+fn start_grpc(config: &Config) -> Result<Option<grpc::Server>> {
+    match config.grpc {
+        Some(ref c) => Ok(Some(grpc_service::start(c.clone())?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(not(feature = "grpc"))]
+fn start_grpc(config: &Config) -> Result<Option<()>> {
+    if config.grpc.is_some() {
+        warn!("Ignoring gRPC config as inauth was built without the \"grpc\" feature");
+    }
+    Ok(None)
+}
+
+// Like env_logger::init(), but keeps hold of the max level so it can be
+// changed at runtime from the admin socket. Without RUST_LOG, the
+// logger accepts everything and the max level starts at "error".
+fn init_logger() -> Result<MaxLogLevelFilter> {
+    let mut builder = LogBuilder::new();
+    let initial = match env::var("RUST_LOG") {
+        Ok(filters) => {
+            builder.parse(&filters);
+            None
+        },
+        Err(_) => {
+            builder.filter(None, LogLevelFilter::Trace);
+            Some(LogLevelFilter::Error)
+        },
+    };
+    let logger = builder.build();
+
+    let mut handle = None;
+    log::set_logger(|max_level| {
+        max_level.set(initial.unwrap_or(logger.filter()));
+        handle = Some(max_level);
+        Box::new(logger)
+    })?;
+    Ok(handle.unwrap())
+}
+
+fn admin_error_handler(sock: &mut ZSock, router_id: &[u8], result: Result<()>) -> StdResult<(), DError> {
+    error_handler(sock, router_id, &WireTracer::disabled(), result)
+}
+
+fn error_handler(sock: &mut ZSock, router_id: &[u8], tracer: &WireTracer, result: Result<()>) -> StdResult<(), DError> {
     match result {
         Ok(_) => Ok(()),
         Err(e) => {
+            info!("{}Request failed: {}", request_id::log_prefix(), e);
+            // The code goes before the message, so clients that only
+            // read the last frame still get the message
+            let msg = request_id::error_reply(router_id, &e)?;
             let derror: DError = e.into();
-            let msg = ZMsg::new_err(&derror)?;
-            msg.pushstr("")?;
-            msg.pushbytes(router_id)?;
+            tracer.record(Direction::Out, "api", &msg, &[]);
             msg.send(sock)?;
             Err(derror)
         }
@@ -186,13 +726,7 @@ fn read_conf<P: AsRef<Path>>(path: Option<P>) -> Result<Config> {
 }
 
 fn do_read_conf<P: AsRef<Path>>(path: P) -> Result<Config> {
-    let mut path = path.as_ref().to_owned();
-    path.push("auth.json");
-
-    let mut fh = fs::File::open(&path)?;
-    let mut json = String::new();
-    fh.read_to_string(&mut json)?;
-    Ok(serde_json::from_str(&json)?)
+    config::load(path)
 }
 
 #[cfg(test)]
@@ -203,6 +737,7 @@ mod tests {
     use std::io::Write;
     use super::{error_handler, read_conf};
     use tempdir::TempDir;
+    use wire_trace::WireTracer;
 
     #[test]
     fn test_error_handler() {
@@ -210,12 +745,13 @@ mod tests {
         let mut server = ZSock::new_pull("inproc://server_test_error_handler").unwrap();
         server.set_rcvtimeo(Some(500));
 
-        assert!(error_handler(&mut client, b"router_id", Err(Error::Forbidden)).is_err());
+        assert!(error_handler(&mut client, b"router_id", &WireTracer::disabled(), Err(Error::Forbidden)).is_err());
 
         let msg = ZMsg::recv(&mut server).unwrap();
         assert_eq!(msg.popstr().unwrap().unwrap(), "router_id");
         assert_eq!(msg.popstr().unwrap().unwrap(), "");
         assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "forbidden");
         assert_eq!(msg.popstr().unwrap().unwrap(), "Access to this endpoint is forbidden");
     }
 
@@ -0,0 +1,370 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Passphrase protection for the server's secret certificate, and for
+//! user certificates handed out by the CLI.
+//!
+//! An encrypted certificate is stored as `MAGIC || salt || nonce ||
+//! ciphertext`, where the key is derived from the passphrase with
+//! libsodium's pwhash and the certificate text is sealed with
+//! secretbox. The plaintext only ever exists in memory. For mail and
+//! tickets, the same bytes can be base64 armored.
+
+use czmq::ZCert;
+use error::{Error, Result};
+use libc;
+use rustc_serialize::base64::{self, FromBase64, ToBase64};
+use secret::Secret;
+use sodiumoxide;
+use sodiumoxide::crypto::{pwhash, secretbox};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::Path;
+use std::process::Command;
+use std::str;
+
+const MAGIC: &'static [u8] = b"INAUTH-ENC-1\n";
+const ARMOR_BEGIN: &'static str = "-----BEGIN INAUTH ENCRYPTED CERT-----";
+const ARMOR_END: &'static str = "-----END INAUTH ENCRYPTED CERT-----";
+
+/// Read a passphrase from `source`, which is one of:
+///
+/// - `prompt`: ask on the terminal (interactive use only)
+/// - `env:<VAR>`: read from an environment variable
+/// - `file:<path>`: read the first line of a file
+/// - `exec:<command>`: run a command (e.g. a KMS client) and use its
+///   stdout
+pub fn read_passphrase(source: &str) -> Result<Secret<String>> {
+    let passphrase = if source == "prompt" {
+        prompt("Server certificate passphrase: ")?.into_inner()
+    }
+    else if source.starts_with("env:") {
+        ::std::env::var(&source[4..]).or(Err(Error::MissingPassphrase))?
+    }
+    else if source.starts_with("file:") {
+        let mut fh = fs::File::open(&source[5..])?;
+        let mut contents = String::new();
+        fh.read_to_string(&mut contents)?;
+        contents.lines().next().unwrap_or("").to_string()
+    }
+    else if source.starts_with("exec:") {
+        let output = Command::new("sh").arg("-c").arg(&source[5..]).output()?;
+        if !output.status.success() {
+            return Err(Error::MissingPassphrase);
+        }
+        String::from_utf8_lossy(&output.stdout).trim_right_matches(|c| c == '\n' || c == '\r').to_string()
+    }
+    else {
+        return Err(Error::InvalidArg);
+    };
+
+    if passphrase.is_empty() {
+        Err(Error::MissingPassphrase)
+    } else {
+        Ok(Secret::new(passphrase))
+    }
+}
+
+/// Prompt on the terminal without echoing input.
+pub fn prompt(message: &str) -> Result<Secret<String>> {
+    print!("{}", message);
+    io::stdout().flush()?;
+
+    let mut term: libc::termios = unsafe { mem::zeroed() };
+    let is_tty = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut term) } == 0;
+    if is_tty {
+        let mut noecho = term;
+        noecho.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &noecho) };
+    }
+
+    let mut line = String::new();
+    let result = io::stdin().read_line(&mut line);
+
+    if is_tty {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &term) };
+        println!("");
+    }
+
+    result?;
+    Ok(Secret::new(line.trim_right_matches(|c| c == '\n' || c == '\r').to_string()))
+}
+
+pub fn is_encrypted<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let mut fh = fs::File::open(path)?;
+    let mut header = vec![0; MAGIC.len()];
+    match fh.read_exact(&mut header) {
+        Ok(_) => Ok(header == MAGIC),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Load the server certificate, decrypting it if necessary.
+pub fn load<P: AsRef<Path>>(path: P, passphrase_source: Option<&String>) -> Result<ZCert> {
+    if !is_encrypted(&path)? {
+        return Ok(ZCert::load(path)?);
+    }
+
+    let source = passphrase_source.ok_or(Error::MissingPassphrase)?;
+    let passphrase = read_passphrase(source)?;
+
+    let mut fh = fs::File::open(path)?;
+    let mut data = Vec::new();
+    fh.read_to_end(&mut data)?;
+    decrypt_cert(&data, passphrase.expose())
+}
+
+/// Decrypt a certificate written by `save_encrypted()` or
+/// `encrypt_armored()`.
+pub fn decrypt_cert(data: &[u8], passphrase: &str) -> Result<ZCert> {
+    let data = match dearmor(data) {
+        Some(d) => d,
+        None => data.to_vec(),
+    };
+
+    let mut plaintext = decrypt(&data, passphrase)?;
+    let cert = parse_cert(&plaintext);
+    zero(&mut plaintext);
+    cert
+}
+
+/// Encrypt the secret certificate to `path`, replacing any existing
+/// file.
+pub fn save_encrypted<P: AsRef<Path>>(cert: &ZCert, path: P, passphrase: &str) -> Result<()> {
+    let mut plaintext = format_cert(cert).into_bytes();
+    let data = encrypt(&plaintext, passphrase);
+    zero(&mut plaintext);
+    let data = data?;
+
+    let mut fh = fs::File::create(path)?;
+    fh.write_all(&data)?;
+    Ok(())
+}
+
+/// Encrypt the secret certificate as text, which can be pasted into a
+/// mail or ticket.
+#[allow(dead_code)]
+pub fn encrypt_armored(cert: &ZCert, passphrase: &str) -> Result<String> {
+    let mut plaintext = format_cert(cert).into_bytes();
+    let data = encrypt(&plaintext, passphrase);
+    zero(&mut plaintext);
+
+    let config = base64::Config {
+        char_set: base64::CharacterSet::Standard,
+        newline: base64::Newline::LF,
+        pad: true,
+        line_length: Some(64),
+    };
+    Ok(format!("{}\n{}\n{}", ARMOR_BEGIN, data?.to_base64(config), ARMOR_END))
+}
+
+// The encrypted bytes between the armor lines, ignoring anything
+// around them such as the rest of a mail
+fn dearmor(data: &[u8]) -> Option<Vec<u8>> {
+    let text = match str::from_utf8(data) {
+        Ok(t) => t,
+        Err(_) => return None,
+    };
+    let start = match text.find(ARMOR_BEGIN) {
+        Some(i) => i + ARMOR_BEGIN.len(),
+        None => return None,
+    };
+    match text[start..].find(ARMOR_END) {
+        Some(len) => text[start..start + len].from_base64().ok(),
+        None => None,
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &pwhash::Salt) -> Result<secretbox::Key> {
+    if !sodiumoxide::init() {
+        return Err(Error::Sodium);
+    }
+
+    let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
+    {
+        let secretbox::Key(ref mut kb) = key;
+        pwhash::derive_key(kb, passphrase.as_bytes(), salt,
+                           pwhash::OPSLIMIT_INTERACTIVE,
+                           pwhash::MEMLIMIT_INTERACTIVE).or(Err(Error::Sodium))?;
+    }
+    Ok(key)
+}
+
+fn encrypt(plaintext: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    if !sodiumoxide::init() {
+        return Err(Error::Sodium);
+    }
+
+    let salt = pwhash::gen_salt();
+    let nonce = secretbox::gen_nonce();
+    let key = derive_key(passphrase, &salt)?;
+
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&salt.0);
+    data.extend_from_slice(&nonce.0);
+    data.extend(secretbox::seal(plaintext, &nonce, &key));
+    Ok(data)
+}
+
+fn decrypt(data: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let header_len = MAGIC.len() + pwhash::SALTBYTES + secretbox::NONCEBYTES;
+    if data.len() < header_len || !data.starts_with(MAGIC) {
+        return Err(Error::InvalidCert);
+    }
+
+    let (salt, rest) = data[MAGIC.len()..].split_at(pwhash::SALTBYTES);
+    let (nonce, ciphertext) = rest.split_at(secretbox::NONCEBYTES);
+    let salt = pwhash::Salt::from_slice(salt).ok_or(Error::InvalidCert)?;
+    let nonce = secretbox::Nonce::from_slice(nonce).ok_or(Error::InvalidCert)?;
+
+    let key = derive_key(passphrase, &salt)?;
+    secretbox::open(ciphertext, &nonce, &key).or(Err(Error::InvalidPassphrase))
+}
+
+// Serialise a cert in the same ZPL layout that ZCert::save_secret()
+// produces, so it can be parsed back by parse_cert().
+fn format_cert(cert: &ZCert) -> String {
+    let mut zpl = String::from("metadata\n");
+    for key in cert.meta_keys() {
+        if let Some(Ok(value)) = cert.meta(key) {
+            zpl.push_str(&format!("    {} = \"{}\"\n", key, value));
+        }
+    }
+    zpl.push_str("curve\n");
+    zpl.push_str(&format!("    public-key = \"{}\"\n", cert.public_txt()));
+    zpl.push_str(&format!("    secret-key = \"{}\"\n", cert.secret_txt()));
+    zpl
+}
+
+fn parse_cert(zpl: &[u8]) -> Result<ZCert> {
+    let zpl = ::std::str::from_utf8(zpl).or(Err(Error::InvalidCert))?;
+
+    let mut section = "";
+    let mut meta = Vec::new();
+    let mut public = None;
+    let mut secret = None;
+
+    for line in zpl.lines() {
+        if line.trim().is_empty() || line.trim_left().starts_with('#') {
+            continue;
+        }
+
+        if !line.starts_with(' ') {
+            section = line.trim();
+            continue;
+        }
+
+        let mut parts = line.trim().splitn(2, '=');
+        let key = parts.next().unwrap_or("").trim();
+        let value = parts.next().ok_or(Error::InvalidCert)?.trim().trim_matches('"');
+
+        match (section, key) {
+            ("metadata", _) => meta.push((key.to_string(), value.to_string())),
+            ("curve", "public-key") => public = Some(value.to_string()),
+            ("curve", "secret-key") => secret = Some(value.to_string()),
+            _ => (),
+        }
+    }
+
+    let public = public.ok_or(Error::InvalidCert)?;
+    let mut secret = secret.ok_or(Error::InvalidCert)?;
+    let cert = ZCert::from_txt(&public, &secret);
+    unsafe { zero(secret.as_mut_vec()) };
+    let cert = cert?;
+
+    for (key, value) in meta {
+        cert.set_meta(&key, &value);
+    }
+
+    Ok(cert)
+}
+
+// Best effort scrubbing of plaintext key material
+fn zero(buf: &mut Vec<u8>) {
+    for b in buf.iter_mut() {
+        *b = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use czmq::ZCert;
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_read_passphrase() {
+        let dir = TempDir::new("server_key_test_read_passphrase").unwrap();
+        let path = dir.path().join("passphrase");
+        let mut fh = File::create(&path).unwrap();
+        fh.write_all(b"from file\nignored").unwrap();
+
+        env::set_var("SERVER_KEY_TEST_PASSPHRASE", "from env");
+        assert_eq!(read_passphrase("env:SERVER_KEY_TEST_PASSPHRASE").unwrap().expose(), "from env");
+        assert!(read_passphrase("env:SERVER_KEY_TEST_NONEXISTENT").is_err());
+        assert_eq!(read_passphrase(&format!("file:{}", path.to_str().unwrap())).unwrap().expose(), "from file");
+        assert_eq!(read_passphrase("exec:echo from exec").unwrap().expose(), "from exec");
+        assert!(read_passphrase("exec:false").is_err());
+        assert!(read_passphrase("carrier-pigeon").is_err());
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let dir = TempDir::new("server_key_test_encrypt_decrypt").unwrap();
+        let path = dir.path().join("auth.crt");
+
+        let cert = ZCert::new().unwrap();
+        cert.set_meta("name", "auth");
+        cert.set_meta("type", "host");
+
+        save_encrypted(&cert, &path, "open sesame").unwrap();
+        assert!(is_encrypted(&path).unwrap());
+
+        env::set_var("SERVER_KEY_TEST_WRONG", "open barley");
+        assert!(load(&path, Some(&"env:SERVER_KEY_TEST_WRONG".to_string())).is_err());
+        assert!(load(&path, None).is_err());
+
+        env::set_var("SERVER_KEY_TEST_RIGHT", "open sesame");
+        let loaded = load(&path, Some(&"env:SERVER_KEY_TEST_RIGHT".to_string())).unwrap();
+        assert_eq!(loaded.public_txt(), cert.public_txt());
+        assert_eq!(loaded.secret_txt(), cert.secret_txt());
+        assert_eq!(loaded.meta("name").unwrap().unwrap(), "auth");
+    }
+
+    #[test]
+    fn test_armored() {
+        let cert = ZCert::new().unwrap();
+        cert.set_meta("name", "dan");
+
+        let armored = encrypt_armored(&cert, "open sesame").unwrap();
+        assert!(armored.starts_with("-----BEGIN INAUTH ENCRYPTED CERT-----\n"));
+        assert!(armored.lines().all(|l| l.len() <= 64 || l.starts_with("-----")));
+
+        let mail = format!("Hi Dan,\n\n{}\n\nCheers", armored);
+        let decrypted = decrypt_cert(mail.as_bytes(), "open sesame").unwrap();
+        assert_eq!(decrypted.secret_txt(), cert.secret_txt());
+        assert_eq!(decrypted.meta("name").unwrap().unwrap(), "dan");
+        assert!(decrypt_cert(armored.as_bytes(), "open barley").is_err());
+    }
+
+    #[test]
+    fn test_load_plaintext() {
+        let dir = TempDir::new("server_key_test_load_plaintext").unwrap();
+        let path = dir.path().join("auth.crt");
+
+        let cert = ZCert::new().unwrap();
+        cert.save_secret(&path).unwrap();
+        assert!(!is_encrypted(&path).unwrap());
+        assert_eq!(load(&path, None).unwrap().public_txt(), cert.public_txt());
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Shamir's secret sharing over GF(2^8), byte by byte.
+//!
+//! Each byte of the secret is the constant term of a random polynomial
+//! of degree `threshold - 1`, and share `x` holds every polynomial's
+//! value at `x`. Any `threshold` shares recover the secret by Lagrange
+//! interpolation at 0; fewer reveal nothing about it.
+
+use error::{Error, Result};
+use sodiumoxide::randombytes::randombytes;
+
+/// One share: its x coordinate (1-255) and a byte per secret byte.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Share {
+    pub x: u8,
+    pub y: Vec<u8>,
+}
+
+/// Split `secret` into `shares` shares, any `threshold` of which
+/// recover it.
+pub fn split(secret: &[u8], shares: u8, threshold: u8) -> Result<Vec<Share>> {
+    if threshold < 2 || threshold > shares {
+        return Err(Error::InvalidShare(format!("Need 2 <= threshold ({}) <= shares ({})", threshold, shares)));
+    }
+
+    let mut out: Vec<Share> = (1..shares as u16 + 1).map(|x| Share { x: x as u8, y: Vec::with_capacity(secret.len()) }).collect();
+    for &byte in secret {
+        // Coefficients of x^1 to x^(threshold - 1)
+        let mut coefficients = randombytes(threshold as usize - 1);
+        for share in out.iter_mut() {
+            // Horner's method, highest degree first
+            let mut y = 0;
+            for &c in coefficients.iter().rev() {
+                y = mul(y, share.x) ^ c;
+            }
+            share.y.push(mul(y, share.x) ^ byte);
+        }
+        for c in coefficients.iter_mut() {
+            *c = 0;
+        }
+    }
+
+    Ok(out)
+}
+
+/// Recover the secret from at least the threshold number of shares.
+/// Too few shares give a wrong answer rather than an error, so check
+/// the result.
+pub fn combine(shares: &[Share]) -> Result<Vec<u8>> {
+    let len = match shares.first() {
+        Some(s) => s.y.len(),
+        None => return Err(Error::InvalidShare("No shares given".into())),
+    };
+    for (i, share) in shares.iter().enumerate() {
+        if share.x == 0 || share.y.len() != len {
+            return Err(Error::InvalidShare(format!("Share {} is malformed", share.x)));
+        }
+        if shares[..i].iter().any(|s| s.x == share.x) {
+            return Err(Error::InvalidShare(format!("Share {} was given twice", share.x)));
+        }
+    }
+
+    let mut secret = vec![0; len];
+    for share in shares {
+        // Lagrange basis polynomial for this share, at x = 0
+        let mut basis = 1;
+        for other in shares {
+            if other.x != share.x {
+                basis = mul(basis, div(other.x, other.x ^ share.x));
+            }
+        }
+        for (s, &y) in secret.iter_mut().zip(share.y.iter()) {
+            *s ^= mul(y, basis);
+        }
+    }
+
+    Ok(secret)
+}
+
+// Multiplication modulo the AES polynomial x^8 + x^4 + x^3 + x + 1
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+// a / b for non-zero b, as a * b^254
+fn div(a: u8, b: u8) -> u8 {
+    let mut inverse = 1;
+    for _ in 0..254 {
+        inverse = mul(inverse, b);
+    }
+    mul(a, inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field() {
+        assert_eq!(mul(0x57, 0x83), 0xc1);
+        for b in 1..256u16 {
+            assert_eq!(mul(div(1, b as u8), b as u8), 1);
+        }
+    }
+
+    #[test]
+    fn test_split_combine() {
+        let secret: Vec<u8> = (0..32).collect();
+        let shares = split(&secret, 5, 3).unwrap();
+        assert_eq!(shares.len(), 5);
+        assert!(shares.iter().all(|s| s.y != secret));
+
+        assert_eq!(combine(&shares[..3]).unwrap(), secret);
+        assert_eq!(combine(&[shares[4].clone(), shares[1].clone(), shares[2].clone()]).unwrap(), secret);
+        assert_eq!(combine(&shares).unwrap(), secret);
+        assert!(combine(&shares[..2]).unwrap() != secret);
+
+        assert!(combine(&[shares[0].clone(), shares[0].clone()]).is_err());
+        assert!(combine(&[]).is_err());
+        assert!(split(&secret, 3, 4).is_err());
+        assert!(split(&secret, 3, 1).is_err());
+    }
+}
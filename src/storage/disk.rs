@@ -9,13 +9,49 @@
 use cert::Cert;
 use czmq::ZCert;
 use error::{Error, Result};
+use key_health;
+use serde_json;
 use std::collections::HashMap;
-use std::fs::{metadata, read_dir, remove_file};
-use super::PersistenceAdaptor;
+use std::fs::{metadata, read_dir, remove_file, rename, File, Metadata};
+use std::io::{self, Read, Write};
+use std::time::UNIX_EPOCH;
+use super::{check_name, CertRequest, FilePerms, PersistenceAdaptor, RotationKey, StorageVersion};
+
+// Kept alongside the certs. Don't end in ".crt", so dump() skips them.
+const VERSION_FILE: &'static str = ".storage_version";
+const REQUESTS_FILE: &'static str = ".cert_requests";
+const ROTATION_KEYS_FILE: &'static str = ".rotation_keys";
+// Name to public key, so the store needn't read every cert at startup
+const INDEX_FILE: &'static str = ".cert_index";
+
+// A cert file's modification time, as seconds and nanoseconds since
+// the epoch
+type Mtime = (u64, u32);
+
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    public_key: String,
+    // When the cert file was last indexed. A file modified since, e.g.
+    // re-keyed behind our back, makes the whole index untrustworthy.
+    mtime: Mtime,
+}
 
+/// Certs stored as files in a directory.
+///
+/// The name to public key index is saved by `dump()` and on drop,
+/// rather than on every change. A store that exits without saving
+/// it leaves a stale index, which is rebuilt on the next start.
 pub struct PersistDisk {
     path: String,
+    // Name to public key, and back again. Each is the inverse of the
+    // other.
     name_cache: HashMap<String, String>,
+    pubkey_cache: HashMap<String, String>,
+    // Name to the mtime of the cert file when it was indexed
+    mtimes: HashMap<String, Mtime>,
+    // Whether the index has changed since it was saved
+    index_dirty: bool,
+    perms: FilePerms,
 }
 
 impl PersistDisk {
@@ -29,58 +65,238 @@ impl PersistDisk {
         let mut me = PersistDisk {
             path: path.to_string(),
             name_cache: HashMap::new(),
+            pubkey_cache: HashMap::new(),
+            mtimes: HashMap::new(),
+            index_dirty: false,
+            perms: FilePerms::default(),
         };
 
-        // Warm up name cache
-        try!(me.dump());
+        // Warm up name cache, from the saved index if it's current
+        if !try!(me.load_index()) {
+            me.name_cache.clear();
+            me.pubkey_cache.clear();
+            me.mtimes.clear();
+            try!(me.dump());
+        }
 
         Ok(me)
     }
 
+    /// Give files written from now on this mode and owner.
+    pub fn set_file_perms(&mut self, perms: FilePerms) {
+        self.perms = perms;
+    }
+
+    pub fn file_perms(&self) -> &FilePerms {
+        &self.perms
+    }
+
+    /// Apply the configured mode and owner to every file in the store
+    /// that differs, returning their names.
+    #[allow(dead_code)]
+    pub fn fix_perms(&self) -> Result<Vec<String>> {
+        let mut fixed = Vec::new();
+        if self.perms.is_empty() {
+            return Ok(fixed);
+        }
+
+        for entry in try!(read_dir(&self.path)) {
+            let entry = try!(entry);
+            if !try!(entry.file_type()).is_file() {
+                continue;
+            }
+            let path = entry.path();
+            if !try!(self.perms.matches(&path)) {
+                try!(self.perms.apply(&path));
+                fixed.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        fixed.sort();
+
+        Ok(fixed)
+    }
+
     fn pubkey_to_name(&self, pubkey: &str) -> Option<String> {
-        for (n, pk) in &self.name_cache {
-            if pubkey == pk {
-                return Some(n.to_string());
+        self.pubkey_cache.get(pubkey).cloned()
+    }
+
+    // Names of the certs on disk, with their files' mtimes
+    fn cert_files(&self) -> Result<HashMap<String, Mtime>> {
+        let mut files = HashMap::new();
+
+        for node in try!(read_dir(&self.path)) {
+            let node = try!(node);
+
+            if try!(node.file_type()).is_file() {
+                let file_name = match node.file_name().to_str() {
+                    Some(name) => name.to_string(),
+                    None => return Err(Error::InvalidCertPath),
+                };
+
+                if file_name.ends_with(".crt") {
+                    files.insert(file_name[..file_name.len() - 4].to_string(), try!(mtime(&try!(node.metadata()))));
+                }
             }
         }
 
-        None
+        Ok(files)
+    }
+
+    fn load(&mut self, name: &str) -> Result<Cert> {
+        let cert_path = format!("{}/{}.crt", &self.path, name);
+
+        // Taken before reading, so a write in between looks newer
+        let mtime = try!(mtime(&try!(metadata(&cert_path))));
+        // XXX Replace with own cert template
+        let cert = try!(Cert::from_zcert(try!(ZCert::load(&cert_path))));
+
+        self.index(cert.name(), cert.public_txt(), mtime);
+
+        Ok(cert)
+    }
+
+    // Read the saved index, returning false if there isn't one, it
+    // doesn't name exactly the certs on disk, or any of them has been
+    // modified since it was indexed.
+    fn load_index(&mut self) -> Result<bool> {
+        let mut fh = match File::open(&format!("{}/{}", &self.path, INDEX_FILE)) {
+            Ok(fh) => fh,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let mut json = String::new();
+        try!(fh.read_to_string(&mut json));
+        // A corrupt index, or one from before mtimes were recorded, is
+        // rebuilt like a missing one
+        let saved: HashMap<String, IndexEntry> = match serde_json::from_str(&json) {
+            Ok(i) => i,
+            Err(_) => return Ok(false),
+        };
+
+        let files = try!(self.cert_files());
+        if files.len() != saved.len() || files.iter().any(|(n, m)| saved.get(n).map(|e| e.mtime != *m).unwrap_or(true)) {
+            return Ok(false);
+        }
+        for (name, entry) in &saved {
+            self.index(name, &entry.public_key, entry.mtime);
+        }
+        self.index_dirty = false;
+
+        // Keys shared between names would have collapsed
+        Ok(self.name_cache.len() == saved.len())
+    }
+
+    fn save_index(&mut self) -> Result<()> {
+        if !self.index_dirty {
+            return Ok(());
+        }
+
+        // Replace the old index in one step, so it is never half written
+        let path = format!("{}/{}", &self.path, INDEX_FILE);
+        let tmp_path = format!("{}.tmp", path);
+        {
+            let index: HashMap<&String, IndexEntry> = self.name_cache.iter().map(|(name, pubkey)| {
+                (name, IndexEntry {
+                    public_key: pubkey.clone(),
+                    mtime: self.mtimes.get(name).cloned().unwrap_or((0, 0)),
+                })
+            }).collect();
+            let mut fh = try!(File::create(&tmp_path));
+            try!(fh.write_all(try!(serde_json::to_string(&index)).as_bytes()));
+        }
+        try!(self.perms.apply(&tmp_path));
+        try!(rename(&tmp_path, &path));
+        self.index_dirty = false;
+        Ok(())
+    }
+
+    // Record that `name` has `pubkey` in a file last modified at
+    // `mtime`, dropping whatever either was paired with before, e.g.
+    // because the cert was re-keyed or renamed on disk.
+    fn index(&mut self, name: &str, pubkey: &str, mtime: Mtime) {
+        if self.mtimes.get(name) != Some(&mtime) {
+            self.mtimes.insert(name.to_string(), mtime);
+            self.index_dirty = true;
+        }
+        if self.name_cache.get(name).map(|p| p == pubkey).unwrap_or(false) {
+            return;
+        }
+        self.index_dirty = true;
+        if let Some(old_pubkey) = self.name_cache.insert(name.to_string(), pubkey.to_string()) {
+            if old_pubkey != pubkey {
+                self.pubkey_cache.remove(&old_pubkey);
+            }
+        }
+        if let Some(old_name) = self.pubkey_cache.insert(pubkey.to_string(), name.to_string()) {
+            if old_name != name {
+                self.name_cache.remove(&old_name);
+                self.mtimes.remove(&old_name);
+            }
+        }
+    }
+
+    fn unindex(&mut self, name: &str) {
+        if let Some(pubkey) = self.name_cache.remove(name) {
+            self.pubkey_cache.remove(&pubkey);
+            self.mtimes.remove(name);
+            self.index_dirty = true;
+        }
+    }
+}
+
+impl Drop for PersistDisk {
+    fn drop(&mut self) {
+        if let Err(e) = self.save_index() {
+            error!("Could not save the cert index in {}: {}", self.path, e);
+        }
     }
 }
 
+fn mtime(meta: &Metadata) -> Result<Mtime> {
+    let since_epoch = try!(meta.modified()).duration_since(UNIX_EPOCH).unwrap_or_default();
+    Ok((since_epoch.as_secs(), since_epoch.subsec_nanos()))
+}
+
 impl PersistenceAdaptor for PersistDisk {
     type PK = String;
 
     fn create(&mut self, cert: &Cert) -> Result<String> {
+        try!(check_name(cert.name()));
         if self.name_cache.contains_key(cert.name()) {
             return Err(Error::CertNameCollision);
         }
+        if let Some(problem) = key_health::check_cert(cert) {
+            return Err(Error::WeakKey(problem.to_string()));
+        }
+        if let Some(owner) = self.pubkey_to_name(cert.public_txt()) {
+            return Err(Error::DuplicateKey(owner));
+        }
 
         let cert_path = format!("{}/{}.crt", &self.path, &cert.name());
 
         // Replace with own cert template
         try!(cert.save_public(&cert_path));
+        try!(self.perms.apply(&cert_path));
 
-        self.name_cache.insert(cert.name().to_string(), cert.public_txt().to_string());
+        let mtime = try!(mtime(&try!(metadata(&cert_path))));
+        self.index(cert.name(), cert.public_txt(), mtime);
 
         Ok(cert_path)
     }
 
     fn read(&mut self, name: &str) -> Result<Cert> {
-        let cert_path = format!("{}/{}.crt", &self.path, name);
-
-        // XXX Replace with own cert template
-        let cert = try!(Cert::from_zcert(try!(ZCert::load(&cert_path))));
-
-        self.name_cache.insert(cert.name().to_string(), cert.public_txt().to_string());
-
-        Ok(cert)
+        self.load(name)
     }
 
     fn read_pubkey(&mut self, pubkey: &str) -> Result<Cert> {
         match self.pubkey_to_name(pubkey) {
             Some(name) => {
-                self.read(&name)
+                let cert = try!(self.read(&name));
+                // Re-keyed on disk since it was indexed
+                if cert.public_txt() != pubkey {
+                    return Err(Error::InvalidCert);
+                }
+                Ok(cert)
             },
             None => Err(Error::InvalidCert),
         }
@@ -88,7 +304,7 @@ impl PersistenceAdaptor for PersistDisk {
 
     fn delete(&mut self, name: &str) -> Result<()> {
         try!(remove_file(&format!("{}/{}.crt", &self.path, name)));
-        self.name_cache.remove(name);
+        self.unindex(name);
         Ok(())
     }
 
@@ -105,31 +321,78 @@ impl PersistenceAdaptor for PersistDisk {
     fn dump(&mut self) -> Result<Vec<Cert>> {
         let mut certs = Vec::new();
 
-        for node in try!(read_dir(&self.path)) {
-            let node = try!(node);
-
-            if try!(node.file_type()).is_file() {
-                let file_name = match node.file_name().to_str() {
-                    Some(name) => name.to_string(),
-                    None => return Err(Error::InvalidCertPath),
-                };
-
-                if file_name.ends_with(".crt") {
-                    let (name, _) = file_name.split_at(file_name.len() - 4);
-                    certs.push(try!(self.read(name)));
-                }
-            }
+        for name in try!(self.cert_files()).keys() {
+            certs.push(try!(self.load(name)));
         }
+        // A full pass over the store, so a good time to save
+        try!(self.save_index());
 
         Ok(certs)
     }
+
+    fn read_version(&mut self) -> Result<Option<StorageVersion>> {
+        let mut fh = match File::open(&format!("{}/{}", &self.path, VERSION_FILE)) {
+            Ok(fh) => fh,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut json = String::new();
+        try!(fh.read_to_string(&mut json));
+        Ok(Some(try!(serde_json::from_str(&json))))
+    }
+
+    fn write_version(&mut self, version: &StorageVersion) -> Result<()> {
+        let path = format!("{}/{}", &self.path, VERSION_FILE);
+        let mut fh = try!(File::create(&path));
+        try!(fh.write_all(try!(serde_json::to_string(version)).as_bytes()));
+        self.perms.apply(&path)
+    }
+
+    fn read_requests(&mut self) -> Result<Vec<CertRequest>> {
+        let mut fh = match File::open(&format!("{}/{}", &self.path, REQUESTS_FILE)) {
+            Ok(fh) => fh,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut json = String::new();
+        try!(fh.read_to_string(&mut json));
+        Ok(try!(serde_json::from_str(&json)))
+    }
+
+    fn write_requests(&mut self, requests: &[CertRequest]) -> Result<()> {
+        let path = format!("{}/{}", &self.path, REQUESTS_FILE);
+        let mut fh = try!(File::create(&path));
+        try!(fh.write_all(try!(serde_json::to_string(requests)).as_bytes()));
+        self.perms.apply(&path)
+    }
+
+    fn read_rotation_keys(&mut self) -> Result<Vec<RotationKey>> {
+        let mut fh = match File::open(&format!("{}/{}", &self.path, ROTATION_KEYS_FILE)) {
+            Ok(fh) => fh,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut json = String::new();
+        try!(fh.read_to_string(&mut json));
+        Ok(try!(serde_json::from_str(&json)))
+    }
+
+    fn write_rotation_keys(&mut self, keys: &[RotationKey]) -> Result<()> {
+        let path = format!("{}/{}", &self.path, ROTATION_KEYS_FILE);
+        let mut fh = try!(File::create(&path));
+        try!(fh.write_all(try!(serde_json::to_string(keys)).as_bytes()));
+        self.perms.apply(&path)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use cert::{Cert, CertType};
-    use std::collections::HashMap;
-    use std::fs::metadata;
+    use std::fs::{metadata, remove_file, set_permissions, File, Permissions};
+    use std::io::Write;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::thread::sleep;
+    use std::time::Duration;
     use storage::PersistenceAdaptor;
     use super::*;
     use tempdir::TempDir;
@@ -147,16 +410,104 @@ mod tests {
 
     #[test]
     fn test_pubkey_to_name() {
-        let mut cache = HashMap::new();
-        cache.insert("name".to_string(), "pubkey".to_string());
-
-        let disk = PersistDisk {
+        let mut disk = PersistDisk {
             path: "/path/to/store".to_string(),
-            name_cache: cache,
+            name_cache: HashMap::new(),
+            pubkey_cache: HashMap::new(),
+            mtimes: HashMap::new(),
+            index_dirty: false,
+            perms: FilePerms::default(),
         };
+        disk.index("name", "pubkey", (0, 0));
 
         assert!(disk.pubkey_to_name("nonexistent").is_none());
         assert_eq!(disk.pubkey_to_name("pubkey").unwrap(), "name");
+        assert_indexes_match(&disk);
+    }
+
+    #[test]
+    fn test_index() {
+        let dir = TempDir::new("storage_disk_index").unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut disk = PersistDisk::new(path).unwrap();
+
+        let cert = Cert::new("web1", CertType::Host).unwrap();
+        disk.create(&cert).unwrap();
+        assert_eq!(disk.read_pubkey(cert.public_txt()).unwrap().name(), "web1");
+
+        // Re-keyed on disk
+        let rekeyed = Cert::new("web1", CertType::Host).unwrap();
+        rekeyed.save_public(&format!("{}/web1.crt", path)).unwrap();
+        disk.read("web1").unwrap();
+        assert!(disk.read_pubkey(cert.public_txt()).is_err());
+        assert_eq!(disk.read_pubkey(rekeyed.public_txt()).unwrap().name(), "web1");
+        assert_indexes_match(&disk);
+
+        // Renamed on disk, keeping the key
+        let zcert = ZCert::from_keys(rekeyed.public_key(), rekeyed.secret_key().expose());
+        zcert.set_meta("name", "web2");
+        zcert.set_meta("type", "host");
+        let renamed = Cert::from_zcert(zcert).unwrap();
+        renamed.save_public(&format!("{}/web2.crt", path)).unwrap();
+        remove_file(&format!("{}/web1.crt", path)).unwrap();
+        disk.read("web2").unwrap();
+        assert_eq!(disk.pubkey_to_name(rekeyed.public_txt()).unwrap(), "web2");
+        assert!(!disk.name_cache.contains_key("web1"));
+        assert_indexes_match(&disk);
+
+        disk.delete_pubkey(rekeyed.public_txt()).unwrap();
+        assert!(disk.read_pubkey(rekeyed.public_txt()).is_err());
+        assert!(disk.name_cache.is_empty());
+        assert_indexes_match(&disk);
+    }
+
+    #[test]
+    fn test_index_file() {
+        let dir = TempDir::new("storage_disk_index_file").unwrap();
+        let path = dir.path().to_str().unwrap();
+        let index_path = format!("{}/{}", path, INDEX_FILE);
+        let mut disk = PersistDisk::new(path).unwrap();
+        let cert = Cert::new("web1", CertType::Host).unwrap();
+        disk.create(&cert).unwrap();
+        // Not saved until dropped
+        assert!(metadata(&index_path).is_err());
+        drop(disk);
+        assert!(metadata(&index_path).is_ok());
+
+        // Trusted while it names the certs on disk, unmodified since...
+        let mtime = mtime(&metadata(&format!("{}/web1.crt", path)).unwrap()).unwrap();
+        let json = format!(r#"{{"web1":{{"public_key":"stale","mtime":[{},{}]}}}}"#, mtime.0, mtime.1);
+        File::create(&index_path).unwrap().write_all(json.as_bytes()).unwrap();
+        let mut disk = PersistDisk::new(path).unwrap();
+        assert_eq!(disk.pubkey_to_name("stale").unwrap(), "web1");
+        // ...though a lookup of the wrong key still fails
+        assert!(disk.read_pubkey("stale").is_err());
+        assert_eq!(disk.read_pubkey(cert.public_txt()).unwrap().name(), "web1");
+        drop(disk);
+
+        // Rebuilt once a cert is modified behind its back
+        sleep(Duration::from_millis(50));
+        let rekeyed = Cert::new("web1", CertType::Host).unwrap();
+        rekeyed.save_public(&format!("{}/web1.crt", path)).unwrap();
+        let disk = PersistDisk::new(path).unwrap();
+        assert_eq!(disk.pubkey_to_name(rekeyed.public_txt()).unwrap(), "web1");
+        assert!(disk.pubkey_to_name(cert.public_txt()).is_none());
+        drop(disk);
+
+        // ...or certs are added behind its back, or it's corrupt or in
+        // the old format
+        Cert::new("web2", CertType::Host).unwrap().save_public(&format!("{}/web2.crt", path)).unwrap();
+        let disk = PersistDisk::new(path).unwrap();
+        assert_eq!(disk.name_cache.len(), 2);
+        drop(disk);
+        File::create(&index_path).unwrap().write_all(b"{").unwrap();
+        let disk = PersistDisk::new(path).unwrap();
+        assert_eq!(disk.pubkey_to_name(rekeyed.public_txt()).unwrap(), "web1");
+        assert_indexes_match(&disk);
+        drop(disk);
+        File::create(&index_path).unwrap().write_all(br#"{"web1":"stale","web2":"stale"}"#).unwrap();
+        let disk = PersistDisk::new(path).unwrap();
+        assert_eq!(disk.pubkey_to_name(rekeyed.public_txt()).unwrap(), "web1");
     }
 
     #[test]
@@ -170,6 +521,59 @@ mod tests {
         assert!(metadata(&path).is_ok());
 
         assert!(disk.create(&cert).is_err());
+
+        let outside = Cert::new("../outside", CertType::User).unwrap();
+        assert!(disk.create(&outside).is_err());
+        assert!(metadata(dir.path().join("../outside.crt")).is_err());
+    }
+
+    #[test]
+    fn test_create_rejects_weak_keys() {
+        let dir = TempDir::new("storage_disk_create_rejects_weak_keys").unwrap();
+
+        let cert = Cert::new("test_user", CertType::User).unwrap();
+        let mut disk = PersistDisk::new(dir.path().to_str().unwrap()).unwrap();
+        disk.create(&cert).unwrap();
+
+        let zcert = ZCert::from_keys(&[0; 32], &[0; 32]);
+        zcert.set_meta("name", "zero");
+        zcert.set_meta("type", "user");
+        let zero = Cert::from_zcert(zcert).unwrap();
+        match disk.create(&zero) {
+            Err(Error::WeakKey(_)) => (),
+            _ => panic!("Expected weak key error"),
+        }
+        assert!(metadata(dir.path().join("zero.crt")).is_err());
+
+        // Same keypair under another name
+        let zcert = ZCert::from_keys(cert.public_key(), cert.secret_key().expose());
+        zcert.set_meta("name", "copy");
+        zcert.set_meta("type", "user");
+        let copy = Cert::from_zcert(zcert).unwrap();
+        match disk.create(&copy) {
+            Err(Error::DuplicateKey(owner)) => assert_eq!(owner, "test_user"),
+            _ => panic!("Expected duplicate key error"),
+        }
+    }
+
+    #[test]
+    fn test_fix_perms() {
+        let dir = TempDir::new("storage_disk_fix_perms").unwrap();
+        let mut disk = PersistDisk::new(dir.path().to_str().unwrap()).unwrap();
+
+        let cert = Cert::new("web1", CertType::Host).unwrap();
+        let path = disk.create(&cert).unwrap();
+        set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        assert!(disk.fix_perms().unwrap().is_empty());
+
+        disk.set_file_perms(FilePerms { mode: Some(0o600), uid: None, gid: None });
+        let cert = Cert::new("web2", CertType::Host).unwrap();
+        let path2 = disk.create(&cert).unwrap();
+        assert_eq!(metadata(&path2).unwrap().mode() & 0o777, 0o600);
+
+        assert_eq!(disk.fix_perms().unwrap(), vec!["web1.crt".to_string()]);
+        assert_eq!(metadata(&path).unwrap().mode() & 0o777, 0o600);
+        assert!(disk.fix_perms().unwrap().is_empty());
     }
 
     #[test]
@@ -205,4 +609,47 @@ mod tests {
         assert!((c1.public_txt() == dump_c1.public_txt() && c2.public_txt() == dump_c2.public_txt()) ||
                 (c1.public_txt() == dump_c2.public_txt() && c2.public_txt() == dump_c1.public_txt()));
     }
+
+    #[test]
+    fn test_requests() {
+        let dir = TempDir::new("storage_disk_requests").unwrap();
+        let mut disk = PersistDisk::new(dir.path().to_str().unwrap()).unwrap();
+        assert!(disk.read_requests().unwrap().is_empty());
+
+        let request = CertRequest {
+            id: "0123456789abcdef".into(),
+            name: "web1.example.com".into(),
+            cert_type: "host".into(),
+            public_key: Cert::new("web1.example.com", CertType::Host).unwrap().public_txt().into(),
+            requested_by: "bob".into(),
+            requested_at: 1500000000,
+        };
+        disk.write_requests(&[request.clone()]).unwrap();
+        assert_eq!(disk.read_requests().unwrap(), vec![request]);
+        // The requests file isn't mistaken for a cert
+        assert!(disk.dump().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rotation_keys() {
+        let dir = TempDir::new("storage_disk_rotation_keys").unwrap();
+        let mut disk = PersistDisk::new(dir.path().to_str().unwrap()).unwrap();
+        assert!(disk.read_rotation_keys().unwrap().is_empty());
+
+        let key = RotationKey {
+            name: "web1.example.com".into(),
+            cert_type: "host".into(),
+            public_key: Cert::new("web1.example.com", CertType::Host).unwrap().public_txt().into(),
+        };
+        disk.write_rotation_keys(&[key.clone()]).unwrap();
+        assert_eq!(disk.read_rotation_keys().unwrap(), vec![key]);
+        assert!(disk.dump().unwrap().is_empty());
+    }
+
+    fn assert_indexes_match(disk: &PersistDisk) {
+        assert_eq!(disk.name_cache.len(), disk.pubkey_cache.len());
+        for (name, pubkey) in &disk.name_cache {
+            assert_eq!(disk.pubkey_cache.get(pubkey), Some(name));
+        }
+    }
 }
@@ -0,0 +1,345 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Switching storage backends without restarting the server.
+//!
+//! A switch goes through these steps, each of which must succeed
+//! before the next:
+//!
+//! 1. `attach()` the new backend as a mirror. From then on every
+//!    write goes to both backends, while reads are still served from
+//!    the primary.
+//! 2. `copy()` whatever the mirror is missing from the primary.
+//! 3. `verify()` that both backends hold the same certs and requests.
+//! 4. `promote()` the mirror to primary, detaching the old one.
+//!
+//! `abort()` detaches the mirror at any point before promotion,
+//! leaving the primary as it was.
+
+use cert::Cert;
+use error::{Error, Result};
+use std::collections::HashMap;
+use super::{check_version, CertRequest, PersistenceAdaptor, RotationKey, StorageVersion};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum SwitchPhase {
+    /// No mirror attached
+    Idle,
+    /// Writes are mirrored, but the mirror may be missing older data
+    Mirroring,
+    Copied,
+    /// Both backends held the same data when last compared
+    Verified,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SwitchStatus {
+    pub phase: SwitchPhase,
+    /// Certs copied to the mirror by `copy()`
+    pub copied: usize,
+    /// Writes that succeeded on the primary but failed on the mirror
+    pub mirror_errors: u64,
+}
+
+pub struct MirroredStorage<P> {
+    primary: P,
+    mirror: Option<P>,
+    status: SwitchStatus,
+}
+
+impl<P> MirroredStorage<P> where P: PersistenceAdaptor {
+    pub fn new(primary: P) -> MirroredStorage<P> {
+        MirroredStorage {
+            primary: primary,
+            mirror: None,
+            status: SwitchStatus {
+                phase: SwitchPhase::Idle,
+                copied: 0,
+                mirror_errors: 0,
+            },
+        }
+    }
+
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    pub fn status(&self) -> SwitchStatus {
+        self.status.clone()
+    }
+
+    /// Start mirroring writes to `mirror`, which is stamped with the
+    /// current storage version.
+    pub fn attach(&mut self, mut mirror: P) -> Result<()> {
+        if self.mirror.is_some() {
+            return Err(Error::StorageSwitchStep);
+        }
+        try!(check_version(&mut mirror, false));
+
+        self.mirror = Some(mirror);
+        self.status = SwitchStatus {
+            phase: SwitchPhase::Mirroring,
+            copied: 0,
+            mirror_errors: 0,
+        };
+        info!("Attached storage mirror");
+        Ok(())
+    }
+
+    /// Make the mirror hold the same certs and requests as the
+    /// primary. Returns the number of certs copied.
+    pub fn copy(&mut self) -> Result<usize> {
+        let mirror = match self.mirror {
+            Some(ref mut m) => m,
+            None => return Err(Error::StorageSwitchStep),
+        };
+
+        let certs = try!(self.primary.dump());
+        let mut copied = 0;
+        for cert in &certs {
+            match mirror.read(cert.name()) {
+                Ok(ref c) if c.public_txt() == cert.public_txt() => continue,
+                Ok(_) => try!(mirror.delete(cert.name())),
+                Err(_) => (),
+            }
+            try!(mirror.create(cert));
+            copied += 1;
+        }
+
+        // Anything else was deleted from the primary before we
+        // attached
+        let names: Vec<&str> = certs.iter().map(|c| c.name()).collect();
+        for cert in try!(mirror.dump()) {
+            if !names.contains(&cert.name()) {
+                try!(mirror.delete(cert.name()));
+            }
+        }
+
+        try!(mirror.write_requests(&try!(self.primary.read_requests())));
+        try!(mirror.write_rotation_keys(&try!(self.primary.read_rotation_keys())));
+
+        info!("Copied {} certificate(s) to storage mirror", copied);
+        self.status.copied += copied;
+        self.status.phase = SwitchPhase::Copied;
+        Ok(copied)
+    }
+
+    /// Check that both backends hold the same certs, requests and
+    /// rotation keys.
+    pub fn verify(&mut self) -> Result<()> {
+        if self.status.phase == SwitchPhase::Idle || self.status.phase == SwitchPhase::Mirroring {
+            return Err(Error::StorageSwitchStep);
+        }
+        let mirror = match self.mirror {
+            Some(ref mut m) => m,
+            None => return Err(Error::StorageSwitchStep),
+        };
+
+        let primary_certs = fingerprint(&try!(self.primary.dump()));
+        let mirror_certs = fingerprint(&try!(mirror.dump()));
+        let mut differences = primary_certs.iter().filter(|&(name, key)| mirror_certs.get(name) != Some(key)).count();
+        differences += mirror_certs.keys().filter(|name| !primary_certs.contains_key(*name)).count();
+        if try!(self.primary.read_requests()) != try!(mirror.read_requests()) {
+            differences += 1;
+        }
+        if try!(self.primary.read_rotation_keys()) != try!(mirror.read_rotation_keys()) {
+            differences += 1;
+        }
+
+        if differences > 0 {
+            warn!("Storage mirror differs from the primary in {} place(s)", differences);
+            self.status.phase = SwitchPhase::Mirroring;
+            return Err(Error::StorageMismatch);
+        }
+
+        info!("Storage mirror matches the primary ({} certificate(s))", primary_certs.len());
+        self.status.phase = SwitchPhase::Verified;
+        Ok(())
+    }
+
+    /// Make the verified mirror the primary, returning the old primary,
+    /// which no longer receives writes.
+    pub fn promote(&mut self) -> Result<P> {
+        if self.status.phase != SwitchPhase::Verified {
+            return Err(Error::StorageSwitchStep);
+        }
+        let mirror = match self.mirror.take() {
+            Some(m) => m,
+            None => return Err(Error::StorageSwitchStep),
+        };
+
+        self.status.phase = SwitchPhase::Idle;
+        info!("Promoted storage mirror to primary");
+        Ok(::std::mem::replace(&mut self.primary, mirror))
+    }
+
+    /// Stop mirroring, returning the mirror if there was one.
+    pub fn abort(&mut self) -> Option<P> {
+        self.status.phase = SwitchPhase::Idle;
+        let mirror = self.mirror.take();
+        if mirror.is_some() {
+            info!("Detached storage mirror");
+        }
+        mirror
+    }
+
+    // Repeat a write on the mirror. The primary is the source of
+    // truth, so a failure here only means the mirror needs copying
+    // again.
+    fn mirror_write<F>(&mut self, write: F)
+        where F: FnOnce(&mut P) -> Result<()>
+    {
+        let failed = match self.mirror {
+            Some(ref mut m) => match write(m) {
+                Ok(()) => false,
+                Err(e) => {
+                    warn!("Could not mirror storage write: {}", e);
+                    true
+                },
+            },
+            None => false,
+        };
+
+        if failed {
+            self.status.mirror_errors += 1;
+            if self.status.phase != SwitchPhase::Idle {
+                self.status.phase = SwitchPhase::Mirroring;
+            }
+        }
+    }
+}
+
+// Public key by cert name
+fn fingerprint(certs: &[Cert]) -> HashMap<String, String> {
+    certs.iter().map(|c| (c.name().to_string(), c.public_txt().to_string())).collect()
+}
+
+impl<P> PersistenceAdaptor for MirroredStorage<P> where P: PersistenceAdaptor {
+    type PK = P::PK;
+
+    fn create(&mut self, cert: &Cert) -> Result<P::PK> {
+        let pk = try!(self.primary.create(cert));
+        self.mirror_write(|m| m.create(cert).map(|_| ()));
+        Ok(pk)
+    }
+
+    fn read(&mut self, name: &str) -> Result<Cert> {
+        self.primary.read(name)
+    }
+
+    fn read_pubkey(&mut self, pubkey: &str) -> Result<Cert> {
+        self.primary.read_pubkey(pubkey)
+    }
+
+    fn delete(&mut self, name: &str) -> Result<()> {
+        try!(self.primary.delete(name));
+        self.mirror_write(|m| m.delete(name));
+        Ok(())
+    }
+
+    fn delete_pubkey(&mut self, pubkey: &str) -> Result<()> {
+        try!(self.primary.delete_pubkey(pubkey));
+        self.mirror_write(|m| m.delete_pubkey(pubkey));
+        Ok(())
+    }
+
+    fn dump(&mut self) -> Result<Vec<Cert>> {
+        self.primary.dump()
+    }
+
+    fn read_version(&mut self) -> Result<Option<StorageVersion>> {
+        self.primary.read_version()
+    }
+
+    fn write_version(&mut self, version: &StorageVersion) -> Result<()> {
+        try!(self.primary.write_version(version));
+        self.mirror_write(|m| m.write_version(version));
+        Ok(())
+    }
+
+    fn read_requests(&mut self) -> Result<Vec<CertRequest>> {
+        self.primary.read_requests()
+    }
+
+    fn write_requests(&mut self, requests: &[CertRequest]) -> Result<()> {
+        try!(self.primary.write_requests(requests));
+        self.mirror_write(|m| m.write_requests(requests));
+        Ok(())
+    }
+
+    fn read_rotation_keys(&mut self) -> Result<Vec<RotationKey>> {
+        self.primary.read_rotation_keys()
+    }
+
+    fn write_rotation_keys(&mut self, keys: &[RotationKey]) -> Result<()> {
+        try!(self.primary.write_rotation_keys(keys));
+        self.mirror_write(|m| m.write_rotation_keys(keys));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType};
+    use storage::{PersistDisk, PersistenceAdaptor};
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_switch() {
+        let old_dir = TempDir::new("storage_mirror_old").unwrap();
+        let new_dir = TempDir::new("storage_mirror_new").unwrap();
+        let mut storage = MirroredStorage::new(PersistDisk::new(old_dir.path().to_str().unwrap()).unwrap());
+
+        let before = Cert::new("before", CertType::Host).unwrap();
+        storage.create(&before).unwrap();
+        assert!(storage.verify().is_err());
+
+        storage.attach(PersistDisk::new(new_dir.path().to_str().unwrap()).unwrap()).unwrap();
+        assert_eq!(storage.status().phase, SwitchPhase::Mirroring);
+
+        // Written to both while mirroring
+        let during = Cert::new("during", CertType::User).unwrap();
+        storage.create(&during).unwrap();
+        assert!(storage.promote().is_err());
+
+        assert_eq!(storage.copy().unwrap(), 1);
+        storage.verify().unwrap();
+
+        let old = storage.promote().unwrap();
+        assert_eq!(storage.status(), SwitchStatus { phase: SwitchPhase::Idle, copied: 1, mirror_errors: 0 });
+        drop(old);
+
+        // The new backend now serves reads and the old one is detached
+        let after = Cert::new("after", CertType::User).unwrap();
+        storage.create(&after).unwrap();
+        assert_eq!(storage.dump().unwrap().len(), 3);
+        let mut old = PersistDisk::new(old_dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(old.dump().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_verify_mismatch() {
+        let old_dir = TempDir::new("storage_mirror_mismatch_old").unwrap();
+        let new_dir = TempDir::new("storage_mirror_mismatch_new").unwrap();
+        let mut storage = MirroredStorage::new(PersistDisk::new(old_dir.path().to_str().unwrap()).unwrap());
+        storage.attach(PersistDisk::new(new_dir.path().to_str().unwrap()).unwrap()).unwrap();
+        storage.copy().unwrap();
+
+        // Changed behind the mirror's back
+        let mut old = PersistDisk::new(old_dir.path().to_str().unwrap()).unwrap();
+        old.create(&Cert::new("sneaky", CertType::Host).unwrap()).unwrap();
+        assert!(storage.verify().is_err());
+        assert_eq!(storage.status().phase, SwitchPhase::Mirroring);
+
+        storage.copy().unwrap();
+        storage.verify().unwrap();
+        assert!(storage.abort().is_some());
+        assert!(storage.promote().is_err());
+    }
+}
@@ -7,11 +7,58 @@
 // modified, or distributed except according to those terms.
 
 mod disk;
+pub mod mirror;
+mod perms;
 
 pub use self::disk::PersistDisk;
+pub use self::perms::FilePerms;
 
 use cert::Cert;
-use error::Result;
+use error::{Error, Result};
+
+/// On-disk format understood by this binary. Bump this whenever a
+/// storage change can't be read by older releases.
+pub const STORAGE_FORMAT: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StorageVersion {
+    pub format: u32,
+    /// Crate version of the binary that last wrote the record
+    pub written_by: String,
+}
+
+impl StorageVersion {
+    pub fn current() -> StorageVersion {
+        StorageVersion {
+            format: STORAGE_FORMAT,
+            written_by: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+/// A cert awaiting admin approval. The requester keeps the secret key,
+/// so only the public key is stored.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CertRequest {
+    pub id: String,
+    pub name: String,
+    pub cert_type: String,
+    pub public_key: String,
+    /// Cert name of the requester
+    pub requested_by: String,
+    /// Seconds since the Unix epoch
+    pub requested_at: u64,
+}
+
+/// The new key of a scheduled rotation in its grace window. Only the
+/// public key is stored, so a rotation abandoned by a restart can have
+/// its key withdrawn. See `rotation`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RotationKey {
+    pub name: String,
+    pub cert_type: String,
+    pub public_key: String,
+}
 
 pub trait PersistenceAdaptor {
     type PK;
@@ -22,4 +69,79 @@ pub trait PersistenceAdaptor {
     fn delete(&mut self, name: &str) -> Result<()>;
     fn delete_pubkey(&mut self, pubkey: &str) -> Result<()>;
     fn dump(&mut self) -> Result<Vec<Cert>>;
+    /// The version record, or `None` if the store predates it.
+    fn read_version(&mut self) -> Result<Option<StorageVersion>>;
+    fn write_version(&mut self, version: &StorageVersion) -> Result<()>;
+    /// Pending cert requests, oldest first.
+    fn read_requests(&mut self) -> Result<Vec<CertRequest>>;
+    fn write_requests(&mut self, requests: &[CertRequest]) -> Result<()>;
+    /// New keys of pending rotations.
+    fn read_rotation_keys(&mut self) -> Result<Vec<RotationKey>>;
+    fn write_rotation_keys(&mut self, keys: &[RotationKey]) -> Result<()>;
+}
+
+/// Refuse to use a store written in a newer format than this binary
+/// understands, unless `force` is set. Otherwise stamp the store with
+/// the current version.
+pub fn check_version<P: PersistenceAdaptor>(persistence: &mut P, force: bool) -> Result<()> {
+    if let Some(version) = persistence.read_version()? {
+        if version.format > STORAGE_FORMAT {
+            if !force {
+                return Err(Error::StorageTooNew(version.format, version.written_by, STORAGE_FORMAT));
+            }
+
+            // Leave the record alone so the newer release still
+            // recognises its own format.
+            warn!("Using storage format {} (written by {}), which is newer than this binary supports ({})",
+                  version.format, version.written_by, STORAGE_FORMAT);
+            return Ok(());
+        }
+    }
+
+    persistence.write_version(&StorageVersion::current())
+}
+
+/// Refuse cert names that can't safely be used as a file name: empty
+/// ones and those with a path separator, ".." or NUL.
+pub fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains('/') || name.contains("..") || name.contains('\0') {
+        return Err(Error::InvalidCertMeta);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_check_version() {
+        let dir = TempDir::new("storage_check_version").unwrap();
+        let mut disk = PersistDisk::new(dir.path().to_str().unwrap()).unwrap();
+
+        // Unversioned stores are stamped with the current version
+        assert!(disk.read_version().unwrap().is_none());
+        check_version(&mut disk, false).unwrap();
+        assert_eq!(disk.read_version().unwrap(), Some(StorageVersion::current()));
+
+        let newer = StorageVersion {
+            format: STORAGE_FORMAT + 1,
+            written_by: "99.0.0".into(),
+        };
+        disk.write_version(&newer).unwrap();
+        assert!(check_version(&mut disk, false).is_err());
+        assert!(check_version(&mut disk, true).is_ok());
+        assert_eq!(disk.read_version().unwrap(), Some(newer));
+    }
+
+    #[test]
+    fn test_check_name() {
+        assert!(check_name("web1.example.com").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name("../auth").is_err());
+        assert!(check_name("..").is_err());
+        assert!(check_name("certs/web1").is_err());
+        assert!(check_name("web1\0").is_err());
+    }
 }
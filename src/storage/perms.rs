@@ -0,0 +1,160 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use config::CertFilesConfig;
+use error::{Error, Result};
+use libc;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+/// Mode and ownership to give cert store files. See
+/// `config::CertFilesConfig`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FilePerms {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FilePerms {
+    pub fn new(mode: Option<&str>, owner: Option<&str>) -> Result<FilePerms> {
+        let mode = match mode {
+            Some(m) => match u32::from_str_radix(m.trim_left_matches("0o"), 8) {
+                Ok(m) if m <= 0o7777 => Some(m),
+                _ => return Err(Error::InvalidConfig(format!("invalid file mode {}", m))),
+            },
+            None => None,
+        };
+
+        let (uid, gid) = match owner {
+            Some(o) => {
+                let mut parts = o.splitn(2, ':');
+                let user = parts.next().unwrap_or("");
+                let uid = if user.is_empty() { None } else { Some(try!(lookup_user(user))) };
+                let gid = match parts.next() {
+                    Some(group) if !group.is_empty() => Some(try!(lookup_group(group))),
+                    _ => None,
+                };
+                (uid, gid)
+            },
+            None => (None, None),
+        };
+
+        Ok(FilePerms {
+            mode: mode,
+            uid: uid,
+            gid: gid,
+        })
+    }
+
+    pub fn from_config(config: Option<&CertFilesConfig>) -> Result<FilePerms> {
+        match config {
+            Some(c) => FilePerms::new(c.mode.as_ref().map(|m| m.as_str()), c.owner.as_ref().map(|o| o.as_str())),
+            None => Ok(FilePerms::default()),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        *self == FilePerms::default()
+    }
+
+    /// Whether `path` already has these permissions.
+    pub fn matches<P: AsRef<Path>>(&self, path: P) -> Result<bool> {
+        let meta = try!(fs::metadata(path));
+        Ok(self.mode.map(|m| meta.mode() & 0o7777 == m).unwrap_or(true)
+           && self.uid.map(|u| meta.uid() == u).unwrap_or(true)
+           && self.gid.map(|g| meta.gid() == g).unwrap_or(true))
+    }
+
+    pub fn apply<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if self.uid.is_some() || self.gid.is_some() {
+            let c_path = try!(CString::new(path.as_os_str().as_bytes()).or(Err(Error::InvalidCertPath)));
+            // -1 leaves the user or group as it is
+            let result = unsafe { libc::chown(c_path.as_ptr(), self.uid.unwrap_or(!0), self.gid.unwrap_or(!0)) };
+            if result != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        // After chown, which can clear setuid and setgid bits
+        if let Some(mode) = self.mode {
+            try!(fs::set_permissions(path, fs::Permissions::from_mode(mode)));
+        }
+        Ok(())
+    }
+}
+
+fn lookup_user(user: &str) -> Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = try!(CString::new(user).or(Err(Error::InvalidArg)));
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        Err(Error::InvalidConfig(format!("unknown user {}", user)))
+    } else {
+        Ok(unsafe { (*passwd).pw_uid })
+    }
+}
+
+fn lookup_group(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = try!(CString::new(group).or(Err(Error::InvalidArg)));
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        Err(Error::InvalidConfig(format!("unknown group {}", group)))
+    } else {
+        Ok(unsafe { (*entry).gr_gid })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_new() {
+        assert_eq!(FilePerms::new(Some("0640"), None).unwrap().mode, Some(0o640));
+        assert_eq!(FilePerms::new(Some("600"), None).unwrap().mode, Some(0o600));
+        assert!(FilePerms::new(Some("0980"), None).is_err());
+        assert!(FilePerms::new(Some("17777"), None).is_err());
+
+        let perms = FilePerms::new(None, Some("0:0")).unwrap();
+        assert_eq!((perms.uid, perms.gid), (Some(0), Some(0)));
+        assert_eq!(FilePerms::new(None, Some("root")).unwrap().uid, Some(0));
+        assert_eq!(FilePerms::new(None, Some(":0")).unwrap().uid, None);
+        assert!(FilePerms::new(None, Some("no-such-user-inauth")).is_err());
+        assert!(FilePerms::from_config(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_apply() {
+        let dir = TempDir::new("storage_perms_apply").unwrap();
+        let path = dir.path().join("web1.crt");
+        File::create(&path).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        // Our own user, which needs no privileges to set
+        let uid = fs::metadata(&path).unwrap().uid();
+        let perms = FilePerms { mode: Some(0o600), uid: Some(uid), gid: None };
+        assert!(!perms.matches(&path).unwrap());
+        perms.apply(&path).unwrap();
+        assert!(perms.matches(&path).unwrap());
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+    }
+}
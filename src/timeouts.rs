@@ -0,0 +1,141 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Send and receive timeouts for sockets.
+//!
+//! CZMQ reports a timed out send or receive as a NULL, like any other
+//! failure. These helpers check errno so a timeout is returned as
+//! `Error::Timeout`, which callers can retry or report as such.
+
+use czmq::{ZFrame, ZMsg, ZSock};
+use error::{Error, Result};
+use libc;
+use std::{cmp, io};
+use std::time::Duration;
+
+/// Send and receive timeouts for a socket. The default leaves both
+/// alone.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SocketTimeouts {
+    /// How long a send may block, e.g. on a full queue. `None` leaves
+    /// the socket's setting alone.
+    pub send: Option<Duration>,
+    /// How long a receive may wait for a message. `None` leaves the
+    /// socket's setting alone.
+    pub recv: Option<Duration>,
+}
+
+impl SocketTimeouts {
+    /// Set the timeouts on `sock`.
+    pub fn apply(&self, sock: &ZSock) {
+        if let Some(send) = self.send {
+            sock.set_sndtimeo(Some(to_ms(send)));
+        }
+        if let Some(recv) = self.recv {
+            sock.set_rcvtimeo(Some(to_ms(recv)));
+        }
+    }
+}
+
+/// Receive a message, waiting no longer than the socket's receive
+/// timeout.
+pub fn recv(sock: &mut ZSock) -> Result<ZMsg> {
+    ZMsg::recv(sock).map_err(|e| timeout_or(e.into(), "waiting for a message"))
+}
+
+/// As `recv`, expecting between `min` and `max` frames. `None` means
+/// no maximum.
+pub fn recv_expect(sock: &mut ZSock, min: usize, max: Option<usize>) -> Result<ZMsg> {
+    let msg = try!(recv(sock));
+    try!(check_size(&msg, min, max));
+    Ok(msg)
+}
+
+/// The frames left in the message being received, e.g. those after
+/// the endpoint name, expecting between `min` and `max`. Unlike
+/// `ZMsgExtended::expect_recv()`, this never reads on into the next
+/// message, and a frame that doesn't arrive is `Error::Timeout` rather
+/// than a short message.
+pub fn recv_rest(sock: &mut ZSock, min: usize, max: Option<usize>) -> Result<ZMsg> {
+    let msg = ZMsg::new();
+    while sock.rcvmore() {
+        let frame = try!(ZFrame::recv(sock).map_err(|e| timeout_or(e.into(), "waiting for the rest of a message")));
+        try!(msg.append(frame));
+    }
+    try!(check_size(&msg, min, max));
+    Ok(msg)
+}
+
+/// Send a message, waiting no longer than the socket's send timeout.
+pub fn send(msg: ZMsg, sock: &mut ZSock) -> Result<()> {
+    msg.send(sock).map_err(|e| timeout_or(e.into(), "sending a message"))
+}
+
+fn check_size(msg: &ZMsg, min: usize, max: Option<usize>) -> Result<()> {
+    if msg.size() < min || max.map(|m| msg.size() > m).unwrap_or(false) {
+        Err(Error::InvalidArgsCount)
+    } else {
+        Ok(())
+    }
+}
+
+// CZMQ leaves errno as libzmq set it
+fn timeout_or(err: Error, doing: &str) -> Error {
+    if io::Error::last_os_error().raw_os_error() == Some(libc::EAGAIN) {
+        Error::Timeout(doing.to_string())
+    } else {
+        err
+    }
+}
+
+fn to_ms(d: Duration) -> i32 {
+    let ms = d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64;
+    cmp::min(ms, i32::max_value() as u64) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use czmq::{ZMsg, ZSock, ZSys};
+    use error::Error;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn test_timeouts() {
+        ZSys::init();
+
+        let mut client = ZSock::new_push("inproc://timeouts_test").unwrap();
+        let mut server = ZSock::new_pull("inproc://timeouts_test").unwrap();
+        SocketTimeouts { send: None, recv: Some(Duration::from_millis(50)) }.apply(&server);
+        assert_eq!(server.rcvtimeo(), Some(50));
+
+        match recv(&mut server) {
+            Err(Error::Timeout(_)) => (),
+            _ => panic!("Expected timeout"),
+        }
+
+        let msg = ZMsg::new();
+        msg.addstr("cert::lookup").unwrap();
+        msg.addstr("web1").unwrap();
+        send(msg, &mut client).unwrap();
+        ZFrame::recv(&mut server).unwrap();
+        assert_eq!(recv_rest(&mut server, 1, Some(1)).unwrap().popstr().unwrap().unwrap(), "web1");
+
+        // The next message is left alone
+        let msg = ZMsg::new();
+        msg.addstr("server::info").unwrap();
+        msg.send(&mut client).unwrap();
+        let msg = ZMsg::new();
+        msg.addstr("cert::list").unwrap();
+        msg.addstr("host").unwrap();
+        msg.send(&mut client).unwrap();
+        ZFrame::recv(&mut server).unwrap();
+        assert_eq!(recv_rest(&mut server, 0, None).unwrap().size(), 0);
+        assert!(recv_expect(&mut server, 3, None).is_err());
+    }
+}
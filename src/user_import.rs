@@ -0,0 +1,466 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Bulk user onboarding from a CSV of `name,email,roles` rows.
+//!
+//! Each user is claimed by holding an exclusive lock on `.<name>.import`
+//! in the cert store, so concurrent imports never issue two certs for
+//! the same name. The lock goes with the process, so an import that
+//! was killed mid-row doesn't leave the name claimed. Completed rows are appended to `<csv>.progress`, which lets an
+//! interrupted import resume where it left off.
+
+use cert::{Cert, CertType};
+use error::{Error, Result};
+use libc;
+use secret::Secret;
+use server_key;
+use sodiumoxide::randombytes::randombytes;
+use std::collections::HashSet;
+use std::fs::{metadata, remove_file, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use storage::{self, PersistenceAdaptor};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Delivery {
+    Email,
+    Print,
+    Encrypt,
+}
+
+impl Delivery {
+    pub fn from_str(method: &str) -> Result<Delivery> {
+        match method {
+            "email" => Ok(Delivery::Email),
+            "print" => Ok(Delivery::Print),
+            "encrypt" => Ok(Delivery::Encrypt),
+            _ => Err(Error::InvalidArg),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Row {
+    pub line: usize,
+    pub name: String,
+    pub email: String,
+    pub roles: String,
+}
+
+/// What's left for the operator to pass on to a new user.
+pub enum Handover {
+    /// Nothing, as the cert was emailed to them
+    Sent,
+    /// The cert, with its secret key
+    Cert(Secret<String>),
+    /// The path of the encrypted cert, and its one-time passphrase to
+    /// pass on separately from it
+    Encrypted(PathBuf, Secret<String>),
+}
+
+pub enum Outcome {
+    Created(Handover),
+    Skipped(&'static str),
+    Failed(Error),
+}
+
+pub struct Importer<P: PersistenceAdaptor> {
+    persistence: P,
+    cert_path: String,
+    delivery: Delivery,
+    out_dir: PathBuf,
+    skip_existing: bool,
+}
+
+impl<P: PersistenceAdaptor> Importer<P> {
+    /// Import into `persistence`, the store at `cert_path`.
+    pub fn new(persistence: P, cert_path: &str, delivery: Delivery, out_dir: PathBuf, skip_existing: bool) -> Importer<P> {
+        Importer {
+            persistence: persistence,
+            cert_path: cert_path.to_string(),
+            delivery: delivery,
+            out_dir: out_dir,
+            skip_existing: skip_existing,
+        }
+    }
+
+    /// Import every row in `csv`, calling `report` with the outcome of
+    /// each. Returns the number of failed rows.
+    pub fn run<F>(&mut self, csv: &Path, mut report: F) -> Result<usize>
+        where F: FnMut(&Row, &Outcome)
+    {
+        let rows = parse_csv(&mut File::open(csv)?)?;
+
+        let progress_path = PathBuf::from(format!("{}.progress", csv.display()));
+        let done = read_progress(&progress_path)?;
+        let mut progress = OpenOptions::new().create(true).append(true).open(&progress_path)?;
+
+        let mut failed = 0;
+        for row in rows {
+            let outcome = if done.contains(&row.name) {
+                Outcome::Skipped("already imported")
+            } else {
+                match self.import_row(&row) {
+                    Ok(o) => o,
+                    Err(e) => Outcome::Failed(e),
+                }
+            };
+
+            match outcome {
+                Outcome::Created(_) | Outcome::Skipped(_) => writeln!(progress, "{}", row.name)?,
+                Outcome::Failed(_) => failed += 1,
+            }
+
+            report(&row, &outcome);
+        }
+
+        Ok(failed)
+    }
+
+    fn import_row(&mut self, row: &Row) -> Result<Outcome> {
+        storage::check_name(&row.name)?;
+        if !row.email.is_empty() {
+            check_email(&row.email)?;
+        }
+
+        // Claim the name. Whoever locks the claim first owns it, and
+        // releases it once the cert is in the store.
+        let claim = format!("{}/.{}.import", &self.cert_path, &row.name);
+        let lock = match lock_claim(&claim)? {
+            Some(lock) => lock,
+            None => return Err(Error::CertNameCollision),
+        };
+
+        let result = if Path::new(&format!("{}/{}.crt", &self.cert_path, &row.name)).exists() {
+            if self.skip_existing {
+                Ok(Outcome::Skipped("user exists"))
+            } else {
+                Err(Error::CertNameCollision)
+            }
+        } else {
+            self.create_cert(row).map(Outcome::Created)
+        };
+
+        // Remove the claim while it's still locked, so nobody can lock
+        // it between us unlocking and removing it
+        let _ = remove_file(&claim);
+        drop(lock);
+        result
+    }
+
+    fn create_cert(&mut self, row: &Row) -> Result<Handover> {
+        let cert = Cert::new(&row.name, CertType::User)?;
+        if !row.email.is_empty() {
+            cert.set_meta("email", &row.email);
+        }
+        if !row.roles.is_empty() {
+            cert.set_meta("role", &row.roles);
+        }
+        self.persistence.create(&cert)?;
+
+        // If delivery fails, withdraw the cert so the row can be
+        // retried. Nobody else has its secret key.
+        self.deliver(row, &cert).map_err(|e| {
+            let _ = self.persistence.delete(&row.name);
+            e
+        })
+    }
+
+    fn deliver(&self, row: &Row, cert: &Cert) -> Result<Handover> {
+        match self.delivery {
+            Delivery::Print => Ok(Handover::Cert(Secret::new(secret_block(row, cert)))),
+            Delivery::Email => {
+                if row.email.is_empty() {
+                    return Err(Error::InvalidArg);
+                }
+
+                // The recipient goes on the command line rather than
+                // being read from the headers, so the message can only
+                // reach the address we checked
+                let mut child = Command::new("sendmail").arg("-i").arg("--").arg(&row.email).stdin(Stdio::piped()).spawn()?;
+                {
+                    let stdin = child.stdin.as_mut().ok_or(Error::InvalidArg)?;
+                    write!(stdin, "To: {}\nSubject: Your Intecture certificate\n\n\
+                                   Please store this certificate securely.\n\n{}\n",
+                           row.email, secret_block(row, cert))?;
+                }
+                if !child.wait()?.success() {
+                    return Err(Error::Io(io::Error::new(io::ErrorKind::Other, "sendmail failed")));
+                }
+                Ok(Handover::Sent)
+            },
+            Delivery::Encrypt => {
+                let passphrase: String = randombytes(12).iter().map(|b| format!("{:02x}", b)).collect();
+                let path = self.out_dir.join(format!("{}.crt.enc", row.name));
+                server_key::save_encrypted(cert, &path, &passphrase)?;
+                Ok(Handover::Encrypted(path, Secret::new(passphrase)))
+            },
+        }
+    }
+}
+
+// Lock the claim at `path`, creating it if need be. Returns None if
+// another process holds it.
+fn lock_claim(path: &str) -> Result<Option<File>> {
+    loop {
+        let fh = OpenOptions::new().write(true).create(true).open(path)?;
+        if unsafe { libc::flock(fh.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                return Ok(None);
+            }
+            return Err(e.into());
+        }
+
+        // If the previous owner removed the claim between us opening
+        // and locking it, we've locked a file nobody else will see, so
+        // try again with a fresh one.
+        let locked = fh.metadata()?;
+        match metadata(path) {
+            Ok(ref meta) if meta.dev() == locked.dev() && meta.ino() == locked.ino() => return Ok(Some(fh)),
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn secret_block(row: &Row, cert: &Cert) -> String {
+    let mut meta = format!("    name = \"{}\"\n    type = \"user\"\n", row.name);
+    if !row.email.is_empty() {
+        meta.push_str(&format!("    email = \"{}\"\n", row.email));
+    }
+    if !row.roles.is_empty() {
+        meta.push_str(&format!("    role = \"{}\"\n", row.roles));
+    }
+
+    format!("------------------------COPY BELOW THIS LINE-------------------------
+metadata
+{}curve
+    public-key = \"{}\"
+    secret-key = \"{}\"
+------------------------COPY ABOVE THIS LINE-------------------------", meta, cert.public_txt(), cert.secret_txt().expose())
+}
+
+// A single bare address, which is all that's safe in a header or as
+// sendmail's recipient: no display name, lists, whitespace or control
+// characters, and not something sendmail could take for an option.
+fn check_email(email: &str) -> Result<()> {
+    let mut parts = email.split('@');
+    let valid = match (parts.next(), parts.next(), parts.next()) {
+        (Some(local), Some(domain), None) => !local.is_empty() && !domain.is_empty(),
+        _ => false,
+    };
+    if !valid || email.starts_with('-') || email.chars().any(|c| c.is_whitespace() || c.is_control() || ",;<>()\\\"".contains(c)) {
+        return Err(Error::InvalidArg);
+    }
+    Ok(())
+}
+
+fn read_progress(path: &Path) -> Result<HashSet<String>> {
+    let mut done = HashSet::new();
+    match File::open(path) {
+        Ok(fh) => {
+            for line in BufReader::new(fh).lines() {
+                done.insert(line?);
+            }
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(done)
+}
+
+/// Parse `name,email,roles` rows. A leading header row starting with
+/// "name" is skipped, as are blank lines. Fields may be quoted.
+pub fn parse_csv<R: Read>(reader: &mut R) -> Result<Vec<Row>> {
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+
+    let mut rows = Vec::new();
+    for (i, record) in split_records(&content)?.into_iter().enumerate() {
+        let (line, fields) = record;
+        if fields.iter().all(|f| f.trim().is_empty()) {
+            continue;
+        }
+        if i == 0 && fields[0].trim().eq_ignore_ascii_case("name") {
+            continue;
+        }
+
+        let field = |n: usize| fields.get(n).map(|f| f.trim().to_string()).unwrap_or(String::new());
+        rows.push(Row {
+            line: line,
+            name: field(0),
+            email: field(1),
+            roles: field(2),
+        });
+    }
+
+    Ok(rows)
+}
+
+// Split CSV content into records of fields, tracking the line number
+// each record starts on. Quoted fields may contain commas, newlines
+// and doubled quotes.
+pub fn split_records(content: &str) -> Result<Vec<(usize, Vec<String>)>> {
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut chars = content.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    quoted = false;
+                }
+            },
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(::std::mem::replace(&mut field, String::new())),
+            '\r' if !quoted => (),
+            '\n' if !quoted => {
+                fields.push(::std::mem::replace(&mut field, String::new()));
+                records.push((record_line, ::std::mem::replace(&mut fields, Vec::new())));
+                line += 1;
+                record_line = line;
+            },
+            '\n' => {
+                field.push(c);
+                line += 1;
+            },
+            _ => field.push(c),
+        }
+    }
+
+    if quoted {
+        return Err(Error::InvalidArg);
+    }
+
+    if !field.is_empty() || !fields.is_empty() {
+        fields.push(field);
+        records.push((record_line, fields));
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType};
+    use czmq::ZSys;
+    use error::Error;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use storage::{PersistDisk, PersistenceAdaptor};
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_parse_csv() {
+        let mut csv: &[u8] = b"name,email,roles\n\
+                               alice,alice@example.com,admin\n\
+                               \n\
+                               \"bob, jr\",\"bob@example.com\",\"dev\"\"ops\"\n\
+                               carol\n";
+        let rows = parse_csv(&mut csv).unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], Row { line: 2, name: "alice".into(), email: "alice@example.com".into(), roles: "admin".into() });
+        assert_eq!(rows[1].name, "bob, jr");
+        assert_eq!(rows[1].roles, "dev\"ops");
+        assert_eq!(rows[2].line, 5);
+        assert_eq!(rows[2].email, "");
+
+        let mut bad: &[u8] = b"\"unterminated,x\n";
+        assert!(parse_csv(&mut bad).is_err());
+    }
+
+    #[test]
+    fn test_check_email() {
+        assert!(check_email("alice@example.com").is_ok());
+        assert!(check_email("alice").is_err());
+        assert!(check_email("alice@example.com\nBcc: eve@example.com").is_err());
+        assert!(check_email("alice@example.com, eve@example.com").is_err());
+        assert!(check_email("Alice <alice@example.com>").is_err());
+        assert!(check_email("-oQ/tmp@example.com").is_err());
+        assert!(check_email("a@b@example.com").is_err());
+    }
+
+    #[test]
+    fn test_import_resume() {
+        ZSys::init();
+
+        let dir = TempDir::new("user_import_test_import_resume").unwrap();
+        let certs = dir.path().join("certs");
+        fs::create_dir(&certs).unwrap();
+        let csv = dir.path().join("hires.csv");
+        let mut fh = File::create(&csv).unwrap();
+        fh.write_all(b"alice,alice@example.com,admin\nbob/evil,,\ncarol,,\ndave,\"dave@example.com\r\nBcc: eve@example.com\",\n").unwrap();
+
+        // Carol already has a cert
+        let carol = Cert::new("carol", CertType::User).unwrap();
+        carol.save_public(certs.join("carol.crt").to_str().unwrap()).unwrap();
+
+        let cert_path = certs.to_str().unwrap();
+        let mut importer = Importer::new(PersistDisk::new(cert_path).unwrap(), cert_path, Delivery::Encrypt, dir.path().to_owned(), false);
+        let mut created = Vec::new();
+        let failed = importer.run(&csv, |row, outcome| {
+            if let Outcome::Created(Handover::Encrypted(ref path, ref passphrase)) = *outcome {
+                created.push((row.name.clone(), path.clone(), passphrase.expose().clone()));
+            }
+        }).unwrap();
+        assert_eq!(failed, 3);
+        assert_eq!(created.len(), 1);
+        let (ref name, ref path, ref passphrase) = created[0];
+        assert_eq!(name, "alice");
+        assert_eq!(path, &dir.path().join("alice.crt.enc"));
+        let mut data = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        assert!(server_key::decrypt_cert(&data, passphrase).is_ok());
+        assert!(!certs.join("dave.crt").exists());
+        assert!(!certs.join(".alice.import").exists());
+
+        // Imported through the store, so it's indexed and readable
+        let mut persistence = PersistDisk::new(cert_path).unwrap();
+        assert_eq!(persistence.read("alice").unwrap().meta("email").unwrap().unwrap(), "alice@example.com");
+
+        // Re-run skipping existing users: alice is in the progress file
+        // and carol is skipped rather than failing.
+        let mut importer = Importer::new(persistence, cert_path, Delivery::Encrypt, dir.path().to_owned(), true);
+        let mut skipped = 0;
+        let failed = importer.run(&csv, |_, outcome| {
+            if let Outcome::Skipped(_) = *outcome {
+                skipped += 1;
+            }
+        }).unwrap();
+        assert_eq!(failed, 2);
+        assert_eq!(skipped, 2);
+
+        // A name claimed by another import is left to it
+        let claim = lock_claim(certs.join(".erin.import").to_str().unwrap()).unwrap().unwrap();
+        let row = Row { line: 1, name: "erin".into(), email: String::new(), roles: String::new() };
+        match importer.import_row(&row) {
+            Err(Error::CertNameCollision) => (),
+            _ => panic!("expected a collision"),
+        }
+
+        // ...until that import exits, even if it never removed the claim
+        drop(claim);
+        assert!(certs.join(".erin.import").exists());
+        assert!(importer.import_row(&row).is_ok());
+        assert!(!certs.join(".erin.import").exists());
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Decoding of cert feed messages for `inauth_cli watch`.
+//!
+//! Only plain topics are decoded (e.g. "host", or "@name/" for certs
+//! pushed to one subscriber). Scoped copies of a change and heartbeats
+//! are skipped, so each change is seen once.
+
+use czmq::{ZCert, ZMsg};
+use error::{Error, Result};
+use inauth_client::{DIRECT_TOPIC_PREFIX, ORIGIN_PREFIX, SCOPE_SEPARATOR, TIMESTAMP_PREFIX};
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Event {
+    /// "ADD", "UPDATE" or "DEL"
+    pub action: String,
+    pub topic: String,
+    pub public_key: String,
+    /// Not sent with a DEL
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub cert_type: Option<String>,
+    /// Microseconds since the Unix epoch, if the publisher stamped it
+    pub published_at: Option<u64>,
+}
+
+/// The cert changes in a feed message, in the order sent.
+pub fn decode(msg: &ZMsg) -> Result<Vec<Event>> {
+    let mut frames = Vec::new();
+    let mut next = msg.first();
+    while let Some(frame) = next {
+        frames.push(match try!(frame.data()) {
+            Ok(s) => s.into_bytes(),
+            Err(b) => b,
+        });
+        next = msg.next();
+    }
+    if frames.len() < 2 {
+        return Err(Error::InvalidCertFeed);
+    }
+
+    let topic = String::from_utf8_lossy(&frames[0]).into_owned();
+    let action = String::from_utf8_lossy(&frames[1]).into_owned();
+    if !topic.starts_with(DIRECT_TOPIC_PREFIX) && topic.contains(SCOPE_SEPARATOR) {
+        return Ok(Vec::new());
+    }
+
+    // Trailing frames follow the certs
+    let mut end = frames.len();
+    while end > 2 && is_trailer(&frames[end - 1]) {
+        end -= 1;
+    }
+    let published_at = frames[end..].iter()
+        .map(|f| String::from_utf8_lossy(f))
+        .filter(|f| f.starts_with(TIMESTAMP_PREFIX))
+        .filter_map(|f| f[TIMESTAMP_PREFIX.len()..].parse().ok())
+        .next();
+
+    let mut events = Vec::new();
+    match action.as_ref() {
+        "ADD" | "UPDATE" => {
+            for pair in frames[2..end].chunks(2) {
+                if pair.len() < 2 {
+                    return Err(Error::InvalidCertFeed);
+                }
+                let meta = ZCert::from_keys(&[0; 32], &[0; 32]);
+                try!(meta.decode_meta(&pair[1]));
+                events.push(Event {
+                    action: action.clone(),
+                    topic: topic.clone(),
+                    public_key: String::from_utf8_lossy(&pair[0]).into_owned(),
+                    name: meta.meta("name").and_then(|m| m.ok()),
+                    cert_type: meta.meta("type").and_then(|m| m.ok()),
+                    published_at: published_at,
+                });
+            }
+        },
+        "DEL" if end > 2 => events.push(Event {
+            action: action,
+            topic: topic,
+            public_key: String::from_utf8_lossy(&frames[2]).into_owned(),
+            name: None,
+            cert_type: None,
+            published_at: published_at,
+        }),
+        "DEL" => return Err(Error::InvalidCertFeed),
+        _ => (),
+    }
+
+    Ok(events)
+}
+
+fn is_trailer(frame: &[u8]) -> bool {
+    frame.starts_with(ORIGIN_PREFIX.as_bytes()) || frame.starts_with(TIMESTAMP_PREFIX.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType};
+    use czmq::{ZMsg, ZSys};
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        ZSys::init();
+
+        let cert = Cert::new("web1", CertType::Host).unwrap();
+        let msg = ZMsg::new();
+        msg.addstr("host").unwrap();
+        msg.addstr("ADD").unwrap();
+        msg.addstr(cert.public_txt()).unwrap();
+        msg.addbytes(&cert.encode_meta()).unwrap();
+        msg.addstr("origin=dc2").unwrap();
+        msg.addstr("ts=1500000000000000").unwrap();
+        assert_eq!(decode(&msg).unwrap(), vec![Event {
+            action: "ADD".into(),
+            topic: "host".into(),
+            public_key: cert.public_txt().into(),
+            name: Some("web1".into()),
+            cert_type: Some("host".into()),
+            published_at: Some(1500000000000000),
+        }]);
+
+        let msg = ZMsg::new();
+        msg.addstr("host").unwrap();
+        msg.addstr("DEL").unwrap();
+        msg.addstr(cert.public_txt()).unwrap();
+        let events = decode(&msg).unwrap();
+        assert_eq!(events[0].public_key, cert.public_txt());
+        assert_eq!(events[0].published_at, None);
+
+        // Direct pushes end in a '/', but aren't scoped
+        let msg = ZMsg::new();
+        msg.addstr("@web1/").unwrap();
+        msg.addstr("DEL").unwrap();
+        msg.addstr(cert.public_txt()).unwrap();
+        assert_eq!(decode(&msg).unwrap()[0].topic, "@web1/");
+
+        // Scoped copies and heartbeats
+        let msg = ZMsg::new();
+        msg.addstr("host/webfarm").unwrap();
+        msg.addstr("DEL").unwrap();
+        msg.addstr(cert.public_txt()).unwrap();
+        assert!(decode(&msg).unwrap().is_empty());
+        let msg = ZMsg::new();
+        msg.addstr("").unwrap();
+        msg.addstr("HEARTBEAT").unwrap();
+        msg.addstr("7").unwrap();
+        assert!(decode(&msg).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,347 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Wire tracer for offline protocol debugging.
+//!
+//! Each traced message is appended to the capture file as a single
+//! length-prefixed record (all integers are big endian):
+//!
+//! ```text
+//! u32 record length (excluding this field)
+//! u64 timestamp (milliseconds since UNIX epoch)
+//! u8  direction (0 = in, 1 = out)
+//! u8  socket name length, followed by the socket name
+//! u16 frame count
+//! per frame:
+//!     u8  flags (1 = redacted)
+//!     u32 frame length, followed by the frame data (omitted if redacted)
+//! ```
+
+use czmq::ZMsg;
+use error::{Error, Result};
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const FLAG_REDACTED: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    fn to_byte(&self) -> u8 {
+        match *self {
+            Direction::In => 0,
+            Direction::Out => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Direction> {
+        match byte {
+            0 => Ok(Direction::In),
+            1 => Ok(Direction::Out),
+            _ => Err(Error::InvalidWireTrace),
+        }
+    }
+
+    pub fn to_str(&self) -> &'static str {
+        match *self {
+            Direction::In => "IN",
+            Direction::Out => "OUT",
+        }
+    }
+}
+
+/// Appends frame dumps to a capture file. A disabled tracer is a
+/// no-op, so callers can trace unconditionally.
+#[derive(Clone)]
+pub struct WireTracer {
+    file: Option<Rc<RefCell<File>>>,
+}
+
+impl WireTracer {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<WireTracer> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(WireTracer {
+            file: Some(Rc::new(RefCell::new(file))),
+        })
+    }
+
+    pub fn disabled() -> WireTracer {
+        WireTracer {
+            file: None,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Record `msg` without consuming it. Frames whose index is in
+    /// `redact` (e.g. secret keys) are recorded by length only.
+    ///
+    /// Tracing is a debugging aid, so failures are logged rather than
+    /// propagated to the caller.
+    pub fn record(&self, direction: Direction, socket: &str, msg: &ZMsg, redact: &[usize]) {
+        if let Some(ref file) = self.file {
+            let mut frames = Vec::new();
+            let mut frame = msg.first();
+            while let Some(f) = frame {
+                let data = match f.data() {
+                    Ok(Ok(s)) => s.into_bytes(),
+                    Ok(Err(b)) => b,
+                    Err(_) => Vec::new(),
+                };
+                frames.push(data);
+                frame = msg.next();
+            }
+
+            let record = encode_record(timestamp(), direction, socket, &frames, redact);
+            if let Err(e) = file.borrow_mut().write_all(&record) {
+                warn!("Could not write wire trace: {}", e);
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TraceFrame {
+    Data(Vec<u8>),
+    Redacted(usize),
+}
+
+#[derive(Debug)]
+pub struct TraceRecord {
+    pub timestamp: u64,
+    pub direction: Direction,
+    pub socket: String,
+    pub frames: Vec<TraceFrame>,
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}.{:03}] {} {} ({} frames)", self.timestamp / 1000, self.timestamp % 1000,
+            self.direction.to_str(), self.socket, self.frames.len())?;
+
+        for (i, frame) in self.frames.iter().enumerate() {
+            match *frame {
+                TraceFrame::Data(ref data) => {
+                    let printable = data.iter().all(|b| *b >= 0x20 && *b < 0x7f);
+                    if printable {
+                        write!(f, "\n  {:>3} [{:>4}] {}", i, data.len(), String::from_utf8_lossy(data))?;
+                    } else {
+                        let hex: Vec<String> = data.iter().map(|b| format!("{:02x}", b)).collect();
+                        write!(f, "\n  {:>3} [{:>4}] 0x{}", i, data.len(), hex.join(""))?;
+                    }
+                },
+                TraceFrame::Redacted(len) => write!(f, "\n  {:>3} [{:>4}] <redacted>", i, len)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Decode every record in a capture file.
+pub fn decode<R: Read>(reader: &mut R) -> Result<Vec<TraceRecord>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+
+    let mut records = Vec::new();
+    let mut cursor = Cursor { bytes: &bytes, pos: 0 };
+
+    while !cursor.is_empty() {
+        let len = cursor.u32()? as usize;
+        let end = cursor.pos + len;
+
+        let timestamp = cursor.u64()?;
+        let direction = Direction::from_byte(cursor.u8()?)?;
+        let socket_len = cursor.u8()? as usize;
+        let socket = String::from_utf8_lossy(cursor.take(socket_len)?).into_owned();
+
+        let count = cursor.u16()?;
+        let mut frames = Vec::new();
+        for _ in 0..count {
+            let flags = cursor.u8()?;
+            let frame_len = cursor.u32()? as usize;
+            if flags & FLAG_REDACTED == FLAG_REDACTED {
+                frames.push(TraceFrame::Redacted(frame_len));
+            } else {
+                frames.push(TraceFrame::Data(cursor.take(frame_len)?.to_vec()));
+            }
+        }
+
+        if cursor.pos != end {
+            return Err(Error::InvalidWireTrace);
+        }
+
+        records.push(TraceRecord {
+            timestamp: timestamp,
+            direction: direction,
+            socket: socket,
+            frames: frames,
+        });
+    }
+
+    Ok(records)
+}
+
+fn encode_record(timestamp: u64, direction: Direction, socket: &str, frames: &[Vec<u8>], redact: &[usize]) -> Vec<u8> {
+    let mut body = Vec::new();
+    push_u64(&mut body, timestamp);
+    body.push(direction.to_byte());
+
+    let socket = socket.as_bytes();
+    let socket = &socket[..socket.len().min(255)];
+    body.push(socket.len() as u8);
+    body.extend_from_slice(socket);
+
+    push_u16(&mut body, frames.len() as u16);
+    for (i, frame) in frames.iter().enumerate() {
+        if redact.contains(&i) {
+            body.push(FLAG_REDACTED);
+            push_u32(&mut body, frame.len() as u32);
+        } else {
+            body.push(0);
+            push_u32(&mut body, frame.len() as u32);
+            body.extend_from_slice(frame);
+        }
+    }
+
+    let mut record = Vec::with_capacity(body.len() + 4);
+    push_u32(&mut record, body.len() as u32);
+    record.extend(body);
+    record
+}
+
+fn timestamp() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64,
+        Err(_) => 0,
+    }
+}
+
+fn push_u16(buf: &mut Vec<u8>, n: u16) {
+    buf.push((n >> 8) as u8);
+    buf.push(n as u8);
+}
+
+fn push_u32(buf: &mut Vec<u8>, n: u32) {
+    push_u16(buf, (n >> 16) as u16);
+    push_u16(buf, n as u16);
+}
+
+fn push_u64(buf: &mut Vec<u8>, n: u64) {
+    push_u32(buf, (n >> 32) as u32);
+    push_u32(buf, n as u32);
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.pos + len > self.bytes.len() {
+            return Err(Error::InvalidWireTrace);
+        }
+
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+        Ok((b[0] as u16) << 8 | b[1] as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let hi = self.u16()? as u32;
+        let lo = self.u16()? as u32;
+        Ok(hi << 16 | lo)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let hi = self.u32()? as u64;
+        let lo = self.u32()? as u64;
+        Ok(hi << 32 | lo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use czmq::{ZMsg, ZSys};
+    use std::fs::File;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_record_decode() {
+        ZSys::init();
+
+        let dir = TempDir::new("wire_trace_test_record_decode").unwrap();
+        let path = dir.path().join("trace.bin");
+
+        let tracer = WireTracer::new(&path).unwrap();
+
+        let msg = ZMsg::new();
+        msg.addstr("Ok").unwrap();
+        msg.addstr("public").unwrap();
+        msg.addstr("secret").unwrap();
+        msg.addbytes(&[0, 1, 2]).unwrap();
+        tracer.record(Direction::Out, "api", &msg, &[2]);
+        tracer.record(Direction::In, "update", &msg, &[]);
+
+        // Message must remain intact after tracing
+        assert_eq!(msg.size(), 4);
+
+        let records = decode(&mut File::open(&path).unwrap()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Out);
+        assert_eq!(records[0].socket, "api");
+        assert_eq!(records[0].frames[1], TraceFrame::Data(b"public".to_vec()));
+        assert_eq!(records[0].frames[2], TraceFrame::Redacted(6));
+        assert_eq!(records[0].frames[3], TraceFrame::Data(vec![0, 1, 2]));
+        assert_eq!(records[1].direction, Direction::In);
+        assert_eq!(records[1].frames[2], TraceFrame::Data(b"secret".to_vec()));
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let record = encode_record(1, Direction::In, "api", &[b"frame".to_vec()], &[]);
+        let mut truncated = &record[..record.len() - 1];
+        assert!(decode(&mut truncated).is_err());
+
+        let mut whole = &record[..];
+        assert_eq!(decode(&mut whole).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let tracer = WireTracer::disabled();
+        assert!(!tracer.is_enabled());
+        tracer.record(Direction::In, "api", &ZMsg::new(), &[]);
+    }
+}
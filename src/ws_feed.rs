@@ -0,0 +1,318 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Optional WebSocket mirror of the cert feed, for browser dashboards
+//! and other consumers that can't speak CURVE-ZMQ.
+//!
+//! Clients connect to any path with a bearer token, either in an
+//! `Authorization` header or, as browsers can't set one, an
+//! `access_token` query parameter. They are sent a snapshot, then one
+//! text message per change:
+//!
+//! ```text
+//! {"event":"snapshot","certs":[{"name":"web1","type":"host","public_key":"...","meta":{...}}, ...]}
+//! {"event":"added","cert":{...}}
+//! {"event":"updated","cert":{...}}
+//! {"event":"removed","cert":{...}}
+//! ```
+//!
+//! Like the HTTP gateway, the listener speaks plain WS; bind it to
+//! localhost and terminate TLS (wss://) at a reverse proxy. It runs on
+//! its own thread, subscribed to the feed over `FEED_ENDPOINT`, and
+//! never reads from clients once they have connected.
+
+use cert::{Cert, CertType};
+use cert_cache::{CertCache, Change};
+use config::{GatewayIdentity, WebSocketConfig};
+use czmq::ZSock;
+use error::{Error, Result};
+use http_gateway;
+use serde_json;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{JoinHandle, spawn};
+use std::time::Duration;
+use zap_proxy::FEED_ENDPOINT;
+
+const HANDSHAKE_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC11B65";
+
+// How long the feed is waited on before checking for new connections
+const POLL_MS: i32 = 100;
+
+// Clients that can't take a message in this long are dropped
+const WRITE_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Debug, Serialize)]
+struct CertJson {
+    name: String,
+    #[serde(rename = "type")]
+    cert_type: String,
+    public_key: String,
+    meta: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChangeEvent {
+    event: &'static str,
+    cert: CertJson,
+}
+
+#[derive(Debug, Serialize)]
+struct SnapshotEvent {
+    event: &'static str,
+    certs: Vec<CertJson>,
+}
+
+pub fn spawn_ws_feed(config: WebSocketConfig) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(&config.bind as &str)?;
+    listener.set_nonblocking(true)?;
+    info!("WebSocket feed listening on {}", config.bind);
+
+    Ok(spawn(move || {
+        let mut subscriber = ZSock::new_sub(FEED_ENDPOINT, Some(CertType::Host.to_str())).unwrap();
+        subscriber.set_subscribe(CertType::User.to_str());
+        subscriber.set_rcvtimeo(Some(POLL_MS));
+
+        // Snapshots for other subscribers re-send every cert, so only
+        // certs whose metadata changed count as added
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut cache = CertCache::new(None);
+        cache.resync(&[CertType::Host.to_str().into(), CertType::User.to_str().into()]);
+        let events_cb = events.clone();
+        let mut known = HashMap::new();
+        cache.on_change(None, move |change, cert| {
+            let meta = cert.encode_meta();
+            let event = match change {
+                Change::Added | Change::Updated => {
+                    if known.insert(cert.public_txt().to_string(), meta.clone()) == Some(meta) {
+                        return;
+                    }
+                    if change == Change::Added { "added" } else { "updated" }
+                },
+                Change::Removed => {
+                    known.remove(cert.public_txt());
+                    "removed"
+                },
+            };
+            events_cb.lock().unwrap().push(to_json(&ChangeEvent { event: event, cert: cert_json(cert) }));
+        });
+
+        let mut clients: Vec<TcpStream> = Vec::new();
+        loop {
+            loop {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        match accept(stream, &config.tokens, &cache) {
+                            Ok(client) => clients.push(client),
+                            Err(e) => debug!("WebSocket connection error: {}", e),
+                        }
+                    },
+                    Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        error!("WebSocket accept error: {}", e);
+                        break;
+                    },
+                }
+            }
+
+            // Times out when the feed is quiet
+            if cache.recv(&mut subscriber).is_err() {
+                continue;
+            }
+            for event in events.lock().unwrap().drain(..) {
+                clients.retain(|c| {
+                    let mut c = c;
+                    write_text(&mut c, &event).is_ok()
+                });
+            }
+        }
+    }))
+}
+
+// Complete the handshake with a new client and send it a snapshot
+fn accept(stream: TcpStream, tokens: &HashMap<String, GatewayIdentity>, cache: &CertCache) -> Result<TcpStream> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS)))?;
+    let mut writer = stream.try_clone()?;
+
+    let request = http_gateway::read_request(BufReader::new(stream))?;
+    let key = match request.headers.get("sec-websocket-key") {
+        Some(k) if request.method == "GET" && request.headers.get("upgrade").map(|u| u.eq_ignore_ascii_case("websocket")).unwrap_or(false) => k.clone(),
+        _ => {
+            write!(writer, "HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")?;
+            return Err(Error::InvalidArg);
+        },
+    };
+    let header = request.headers.get("authorization").cloned()
+        .or(request.query.get("access_token").map(|t| format!("Bearer {}", t)));
+    let identity = match http_gateway::bearer_identity(header.as_ref().map(|h| h as &str), tokens) {
+        Some(i) => i,
+        None => {
+            write!(writer, "HTTP/1.1 401 Unauthorized\r\nConnection: close\r\n\r\n")?;
+            return Err(Error::Forbidden);
+        },
+    };
+
+    write!(writer, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept_key(&key))?;
+    debug!("WebSocket feed client connected as {}", identity.name);
+
+    let mut certs = Vec::new();
+    for cert_type in &[CertType::Host, CertType::User] {
+        certs.extend(cache.dump(*cert_type).into_iter().map(cert_json));
+    }
+    write_text(&mut writer, &to_json(&SnapshotEvent { event: "snapshot", certs: certs }))?;
+    Ok(writer)
+}
+
+fn cert_json(cert: &Cert) -> CertJson {
+    let mut meta = BTreeMap::new();
+    for key in cert.meta_keys() {
+        if let Some(Ok(value)) = cert.meta(key) {
+            meta.insert(key.to_string(), value);
+        }
+    }
+    CertJson {
+        name: cert.name().to_string(),
+        cert_type: cert.cert_type().to_str().to_string(),
+        public_key: cert.public_txt().to_string(),
+        meta: meta,
+    }
+}
+
+fn to_json<T: ::serde::Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or(String::new())
+}
+
+/// The `Sec-WebSocket-Accept` reply to a client's key, per RFC 6455.
+pub fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key.trim(), HANDSHAKE_GUID).as_bytes()))
+}
+
+// An unmasked, unfragmented text frame, as servers send
+fn write_text<W: Write>(writer: &mut W, text: &str) -> Result<()> {
+    let len = text.len();
+    let mut header = vec![0x81];
+    if len < 126 {
+        header.push(len as u8);
+    } else if len <= 0xffff {
+        header.push(126);
+        header.extend_from_slice(&[(len >> 8) as u8, len as u8]);
+    } else {
+        header.push(127);
+        header.extend((0..8).rev().map(|i| ((len as u64) >> (i * 8)) as u8));
+    }
+    writer.write_all(&header)?;
+    writer.write_all(text.as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Only for the handshake, which RFC 6455 specifies with SHA-1
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend((0..8).rev().map(|i| ((data.len() as u64 * 8) >> (i * 8)) as u8));
+
+    for chunk in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = (chunk[i * 4] as u32) << 24 | (chunk[i * 4 + 1] as u32) << 16 | (chunk[i * 4 + 2] as u32) << 8 | chunk[i * 4 + 3] as u32;
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for i in 0..80 {
+            let (f, k) = match i {
+                0...19 => ((b & c) | (!b & d), 0x5a827999),
+                20...39 => (b ^ c ^ d, 0x6ed9eba1),
+                40...59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(w[i]);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0; 20];
+    for (i, word) in h.iter().enumerate() {
+        for j in 0..4 {
+            digest[i * 4 + j] = (word >> (24 - j * 8)) as u8;
+        }
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - i * 6)) as usize & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType};
+    use super::{accept_key, base64, cert_json, write_text};
+
+    #[test]
+    fn test_accept_key() {
+        // From RFC 6455, section 1.3
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"a"), "YQ==");
+    }
+
+    #[test]
+    fn test_write_text() {
+        let mut frame = Vec::new();
+        write_text(&mut frame, "hi").unwrap();
+        assert_eq!(frame, vec![0x81, 2, b'h', b'i']);
+
+        let mut frame = Vec::new();
+        write_text(&mut frame, &"x".repeat(300)).unwrap();
+        assert_eq!(&frame[..4], &[0x81, 126, 1, 44]);
+        assert_eq!(frame.len(), 304);
+    }
+
+    #[test]
+    fn test_cert_json() {
+        let cert = Cert::new("web1", CertType::Host).unwrap();
+        let json = cert_json(&cert);
+        assert_eq!(json.name, "web1");
+        assert_eq!(json.cert_type, "host");
+        assert_eq!(json.meta.get("name").unwrap(), "web1");
+    }
+}
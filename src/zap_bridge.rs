@@ -0,0 +1,174 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Federation between Auth servers, e.g. one per datacenter.
+//!
+//! A `ZapBridge` subscribes to another server's feed and republishes
+//! its cert changes into ours, so clients can authenticate against a
+//! nearby server. Each change it republishes is tagged with the servers
+//! it has passed through (see `ORIGIN_PREFIX`), and changes that have
+//! already passed through this server are dropped. Servers can bridge
+//! each other both ways, or in a ring, without changes looping.
+
+use cert::CertType;
+use cert_cache::{self, ORIGIN_PREFIX, TIMESTAMP_PREFIX};
+use czmq::{ZCert, ZMsg, ZSock, SocketType};
+use error::Result;
+use std::result::Result as StdResult;
+use wire_trace::{Direction, WireTracer};
+use zap_proxy::BRIDGE_ENDPOINT;
+use zdaemon::{Endpoint, Error as DError};
+
+pub struct ZapBridge {
+    subscriber: ZSock,
+    publisher: ZSock,
+    origin: String,
+    remote_origin: String,
+    tracer: WireTracer,
+}
+
+impl ZapBridge {
+    /// Subscribe to the feed at `endpoint`, e.g.
+    /// "tcp://auth.dc2.example.com:7102", whose server cert is
+    /// `remote_cert`. We authenticate with `cert`, which the remote
+    /// server must know as a host cert. `origin` names this server in
+    /// origin tags, and `remote_origin` the remote one.
+    pub fn new(cert: &ZCert, remote_cert: &ZCert, endpoint: &str, origin: &str, remote_origin: &str, tracer: WireTracer) -> Result<ZapBridge> {
+        let mut subscriber = ZSock::new(SocketType::SUB);
+        subscriber.set_curve_serverkey(remote_cert.public_txt());
+        cert.apply(&mut subscriber);
+        subscriber.set_linger(0);
+        try!(subscriber.connect(endpoint));
+        // Other topics carry copies of the same changes, or things
+        // only meaningful to the remote server's own subscribers
+        for cert_type in &[CertType::Host, CertType::User] {
+            subscriber.set_subscribe(cert_type.to_str());
+        }
+
+        Ok(ZapBridge {
+            subscriber: subscriber,
+            publisher: try!(ZSock::new_pub(&format!(">{}", BRIDGE_ENDPOINT))),
+            origin: origin.into(),
+            remote_origin: remote_origin.into(),
+            tracer: tracer,
+        })
+    }
+}
+
+impl Endpoint for ZapBridge {
+    fn get_sockets(&mut self) -> Vec<&mut ZSock> {
+        vec![&mut self.subscriber]
+    }
+
+    fn recv(&mut self, sock: &mut ZSock) -> StdResult<(), DError> {
+        let msg = try!(ZMsg::recv(sock));
+        self.tracer.record(Direction::In, "bridge", &msg, &[]);
+
+        if let Some(copy) = try!(bridge_copy(&msg, &self.origin, &self.remote_origin)) {
+            self.tracer.record(Direction::Out, "bridge", &copy, &[]);
+            try!(copy.send(&mut self.publisher));
+        }
+        Ok(())
+    }
+}
+
+/// Copy a change from `remote_origin`'s feed for republishing on
+/// `origin`'s, adding `remote_origin` to its origin tag and dropping
+/// the remote timestamp, as our publisher adds its own. Returns `None`
+/// for messages that aren't cert changes on a cert type topic, or that
+/// have already passed through `origin`.
+pub fn bridge_copy(msg: &ZMsg, origin: &str, remote_origin: &str) -> Result<Option<ZMsg>> {
+    let mut origins = cert_cache::origins(msg);
+
+    let topic = match msg.first().map(|f| f.data()) {
+        Some(Ok(Ok(s))) => s,
+        _ => return Ok(None),
+    };
+    // Scoped topics carry copies of changes on their cert type's topic
+    if CertType::from_str(&topic).is_err() {
+        return Ok(None);
+    }
+    let action = match msg.next().map(|f| f.data()) {
+        Some(Ok(Ok(s))) => s,
+        _ => return Ok(None),
+    };
+    if action != "ADD" && action != "UPDATE" && action != "DEL" {
+        return Ok(None);
+    }
+
+    if origins.iter().any(|o| o == origin) {
+        debug!("Dropping {} from {} that has already passed through {}", action, remote_origin, origin);
+        return Ok(None);
+    }
+    if !origins.iter().any(|o| o == remote_origin) {
+        origins.push(remote_origin.to_string());
+    }
+
+    let copy = ZMsg::new();
+    try!(copy.addstr(&topic));
+    try!(copy.addstr(&action));
+    while let Some(frame) = msg.next() {
+        if let Ok(Ok(ref s)) = frame.data() {
+            if s.starts_with(ORIGIN_PREFIX) || s.starts_with(TIMESTAMP_PREFIX) {
+                break;
+            }
+        }
+        try!(copy.append(try!(frame.dup())));
+    }
+    try!(copy.addstr(&format!("{}{}", ORIGIN_PREFIX, origins.join(","))));
+
+    Ok(Some(copy))
+}
+
+#[cfg(test)]
+mod tests {
+    use cert::{Cert, CertType};
+    use czmq::{ZMsg, ZSys};
+    use super::*;
+
+    fn change(topic: &str, action: &str, cert: &Cert, trailers: &[&str]) -> ZMsg {
+        let msg = ZMsg::new();
+        msg.addstr(topic).unwrap();
+        msg.addstr(action).unwrap();
+        msg.addstr(cert.public_txt()).unwrap();
+        if action != "DEL" {
+            msg.addbytes(&cert.encode_meta()).unwrap();
+        }
+        for trailer in trailers {
+            msg.addstr(trailer).unwrap();
+        }
+        msg
+    }
+
+    #[test]
+    fn test_bridge_copy() {
+        ZSys::init();
+
+        let cert = Cert::new("web1", CertType::Host).unwrap();
+
+        // Changes made on the remote server
+        let copy = bridge_copy(&change("host", "ADD", &cert, &["ts=1500000000000000"]), "dc1", "dc2").unwrap().unwrap();
+        assert_eq!(copy.popstr().unwrap().unwrap(), "host");
+        assert_eq!(copy.popstr().unwrap().unwrap(), "ADD");
+        assert_eq!(copy.popstr().unwrap().unwrap(), cert.public_txt());
+        copy.popbytes().unwrap().unwrap();
+        assert_eq!(copy.popstr().unwrap().unwrap(), "origin=dc2");
+        assert!(copy.popstr().is_none());
+
+        // Changes the remote server bridged from elsewhere
+        let copy = bridge_copy(&change("host", "DEL", &cert, &["origin=dc3", "ts=1"]), "dc1", "dc2").unwrap().unwrap();
+        assert_eq!(cert_cache::origins(&copy), vec!["dc3", "dc2"]);
+        assert_eq!(copy.size(), 4);
+
+        // Our own changes, coming back round
+        assert!(bridge_copy(&change("host", "UPDATE", &cert, &["origin=dc1,dc3"]), "dc1", "dc2").unwrap().is_none());
+
+        assert!(bridge_copy(&change("host/webfarm", "ADD", &cert, &[]), "dc1", "dc2").unwrap().is_none());
+        assert!(bridge_copy(&change("host", "HEARTBEAT", &cert, &[]), "dc1", "dc2").unwrap().is_none());
+    }
+}
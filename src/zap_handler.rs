@@ -6,21 +6,233 @@
 // https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
 // modified, or distributed except according to those terms.
 
+use address_policy::AddressPolicy;
+use affinity::{self, AffinityPolicy, ServerAdvert};
+use attestation::ATTESTATION_TOPIC;
+use auth_policy::{AuthPolicy, Decision, ZapRequestInfo};
+use brute_force::{BanPolicy, BruteForceGuard};
 use cert::{Cert, CertType};
-use cert_cache::CertCache;
+use cert_cache::{self, CacheLimits, CertCache, Change, Heartbeat, KEYS_ONLY_TOPIC_PREFIX, Quarantine, SharedCertCache, SnapshotFormat, Subscriptions};
+use compression;
 use czmq::{ZCert, ZFrame, ZMsg, ZPoller, ZSock, SocketType, ZSys};
+use domain_policy::{DomainPolicy, DomainRouter};
 use error::{Error, Result};
+use feed_monitor::{self, FeedEvent};
+use filter::Filter;
+use frame_policy::{FrameEnforcement, FramePolicy};
+use latency::{self, LatencyHistogram};
+use log_sampling::LogSampler;
+use negative_cache::{NegativeCache, NegativeCacheStats};
+use plain_auth::PlainVerifier;
+use policy::{self, Hook, PolicyScript};
+use reconnect::{self, ReconnectPolicy};
+use serde_json::{Map, Value};
+use sodiumoxide::crypto::sign::PublicKey;
+use std::{cmp, i32, u32};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use std::thread::{JoinHandle, spawn};
-use zdaemon::ZMsgExtended;
+use std::time::{Duration, Instant};
+use timeouts::{self, SocketTimeouts};
+use zap_stats::{ZapStats, ZapStatsRecorder};
 use zmq::z85_encode;
 
-const ZAP_ENDPOINT: &'static str = "inproc://zeromq.zap.01";
-const THREAD_TERM: &'static str = "$TERM";
+pub const ZAP_ENDPOINT: &'static str = "inproc://zeromq.zap.01";
+pub const THREAD_TERM: &'static str = "$TERM";
+// Tells the worker to recalculate its poll timeout
+const THREAD_WAKE: &'static str = "$WAKE";
+// The Auth server API endpoint that receives cache health reports
+const CACHE_REPORT_ENDPOINT: &'static str = "fleet::report";
+// The Auth server API endpoint that sends full snapshots
+const RESYNC_ENDPOINT: &'static str = "feed::resync";
+pub const DEFAULT_DENIED_TEXT: &'static str = "No access";
+
+/// User-Ids of `PLAIN` clients start with this, so that a username
+/// can't pass for a cert of the same name.
+pub const PLAIN_IDENTITY_PREFIX: &'static str = "plain:";
+// How long an unknown key is denied without looking at it again
+const DEFAULT_NEGATIVE_TTL_SECS: u64 = 5;
+// Delay before restarting a failed worker, doubling with each failure
+// that follows closely on the last restart
+const RESTART_BACKOFF_MIN_MS: u64 = 100;
+const RESTART_BACKOFF_MAX_MS: u64 = 30_000;
+// A worker that ran this long before failing starts over at the
+// minimum backoff
+const RESTART_BACKOFF_RESET_SECS: u64 = 60;
+// How often certs past their own expiry are purged from the cache.
+// They are denied from the moment they expire regardless.
+const EXPIRY_PURGE_INTERVAL_SECS: u64 = 10;
+// Six of the Auth server's default heartbeat intervals
+const DEFAULT_HEARTBEAT_TIMEOUT_SECS: u64 = 30;
+// ZAP strings are length-prefixed with a single octet
+const MAX_STATUS_TEXT: usize = 255;
+// Connection properties libzmq sets itself
+const RESERVED_PROPERTIES: [&'static str; 6] = ["Identity", "Peer-Address", "Resource", "Routing-Id", "Socket-Type", "User-Id"];
+
+static NEXT_POOL_ID: AtomicUsize = ATOMIC_USIZE_INIT;
 
 pub struct ZapHandler {
     worker: Option<JoinHandle<()>>,
     thread_comm: ZSock,
+    subscriptions: Subscriptions,
+    settings: Arc<Mutex<Settings>>,
+    // Whether the cache mirrors the Auth server's whole cert set
+    full_feed: bool,
+    ready: Ready,
+    negative_stats: Arc<Mutex<NegativeCacheStats>>,
+    latency: Arc<Mutex<LatencyHistogram>>,
+    quarantine: Arc<Mutex<Quarantine>>,
+    heartbeat: Arc<Mutex<Option<Heartbeat>>>,
+    health: Arc<Mutex<Health>>,
+    stats: Arc<Mutex<ZapStatsRecorder>>,
+    stats_report: Arc<Mutex<Option<StatsReport>>>,
+    cache_report: Arc<Mutex<Option<CacheReport>>>,
+    log_sampler: LogSampler,
+}
+
+/// Liveness of the worker threads that answer ZAP requests. See
+/// `ZapHandler::status()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkerStatus {
+    /// Whether every worker is answering requests. With a single
+    /// worker, every connection attempt stalls while it isn't.
+    pub alive: bool,
+    /// Times a worker has been restarted after an error
+    pub restarts: u32,
+    /// The most recent error
+    pub last_error: Option<String>,
+}
+
+/// Why a ZAP request was denied. See `ZapHandler::set_deny_reply()`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DenyReason {
+    /// No cert for the key, or bad PLAIN credentials
+    UnknownKey,
+    /// The cert passed its own expiry, or outlived the cache's TTL
+    Expired,
+    /// The Auth server revoked the cert
+    Revoked,
+    /// The client is banned after repeated failures
+    RateLimited,
+    /// Turned away by an address, domain or policy rule, or the cert
+    /// is disabled
+    Forbidden,
+}
+
+impl DenyReason {
+    pub fn from_str(reason: &str) -> Result<DenyReason> {
+        match reason {
+            "unknown_key" => Ok(DenyReason::UnknownKey),
+            "expired" => Ok(DenyReason::Expired),
+            "revoked" => Ok(DenyReason::Revoked),
+            "rate_limited" => Ok(DenyReason::RateLimited),
+            "forbidden" => Ok(DenyReason::Forbidden),
+            _ => Err(Error::InvalidZapReply(format!("unknown reason \"{}\"", reason))),
+        }
+    }
+
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            &DenyReason::UnknownKey => "unknown_key",
+            &DenyReason::Expired => "expired",
+            &DenyReason::Revoked => "revoked",
+            &DenyReason::RateLimited => "rate_limited",
+            &DenyReason::Forbidden => "forbidden",
+        }
+    }
+}
+
+/// ZAP status codes for denying a request (RFC 27).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZapStatus {
+    /// 300: the client may try again later
+    TemporaryError,
+    /// 400: authentication failed
+    Denied,
+    /// 500: the handler could not decide
+    InternalError,
+}
+
+impl ZapStatus {
+    pub fn from_code(code: u16) -> Result<ZapStatus> {
+        match code {
+            300 => Ok(ZapStatus::TemporaryError),
+            400 => Ok(ZapStatus::Denied),
+            500 => Ok(ZapStatus::InternalError),
+            _ => Err(Error::InvalidZapReply(format!("status must be 300, 400 or 500, not {}", code))),
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            &ZapStatus::TemporaryError => "300",
+            &ZapStatus::Denied => "400",
+            &ZapStatus::InternalError => "500",
+        }
+    }
+}
+
+// Shared between the handler and the worker threads
+struct Health {
+    status: WorkerStatus,
+    // Workers waiting to restart
+    down: usize,
+    on_error: Option<Box<FnMut(&Error) + Send>>,
+}
+
+// Periodic callback with the handler's stats, run by the main worker
+struct StatsReport {
+    interval: Duration,
+    next: Instant,
+    callback: Box<FnMut(&ZapStats) + Send>,
+}
+
+// Periodic report of the cache's health to the Auth server, sent by
+// the main worker. See `ZapHandlerBuilder::report_cache_health()`.
+struct CacheReport {
+    interval: Duration,
+    next: Instant,
+    // A DEALER connected to the servers' APIs
+    sock: ZSock,
+}
+
+// Set by the worker once the initial snapshot has been applied
+type Ready = Arc<(Mutex<bool>, Condvar)>;
+
+// Runtime settings shared with the workers, which lock them for each
+// check of a ZAP request. Hooks such as policy scripts therefore run
+// one at a time, even with a worker pool.
+struct Settings {
+    denied_text: String,
+    // Replies that differ from "400" and `denied_text`, where the text
+    // defaults to `denied_text`
+    deny_replies: HashMap<DenyReason, (ZapStatus, Option<String>)>,
+    address_policy: AddressPolicy,
+    frame_policy: FramePolicy,
+    plain_verifier: Option<Box<PlainVerifier>>,
+    policy: Option<PolicyScript>,
+    auth_policy: Option<Box<AuthPolicy>>,
+    attestation: Option<AttestationCheck>,
+    heartbeat_timeout: Option<Duration>,
+}
+
+impl Settings {
+    fn deny_reply(&self, reason: DenyReason) -> (ZapStatus, String) {
+        match self.deny_replies.get(&reason) {
+            Some(&(status, Some(ref text))) => (status, text.clone()),
+            Some(&(status, None)) => (status, self.denied_text.clone()),
+            None => (ZapStatus::Denied, self.denied_text.clone()),
+        }
+    }
+}
+
+struct AttestationCheck {
+    key: PublicKey,
+    compare_root: bool,
 }
 
 impl Drop for ZapHandler {
@@ -37,20 +249,175 @@ impl Drop for ZapHandler {
 impl ZapHandler {
     // Seperate new() and run_worker() to allow for mocking sockets
     pub fn new(cert_type: Option<CertType>, cert: &ZCert, auth_cert: &ZCert, auth_server: &str, auth_port: u32, allow_self: bool) -> Result<ZapHandler> {
-        let zap = try!(ZSock::new_rep(ZAP_ENDPOINT));
+        Self::new_with_policy(cert_type, cert, auth_cert, auth_server, auth_port, allow_self, BanPolicy::default())
+    }
+
+    /// Like `new()`, with a custom policy for banning clients that
+    /// repeatedly fail authentication.
+    pub fn new_with_policy(cert_type: Option<CertType>,
+                           cert: &ZCert,
+                           auth_cert: &ZCert,
+                           auth_server: &str,
+                           auth_port: u32,
+                           allow_self: bool,
+                           ban_policy: BanPolicy) -> Result<ZapHandler> {
+        Self::builder(cert, auth_cert)
+            .cert_type(cert_type)
+            .server(auth_server, auth_port)
+            .allow_self(allow_self)
+            .ban_policy(ban_policy)
+            .build()
+    }
+
+    /// Like `new()`, with separate rules for each ZAP domain. Policies
+    /// are keyed by domain, or "*" for domains without their own.
+    /// Requests for any other domain are denied.
+    ///
+    /// ```text
+    /// let mut domains = HashMap::new();
+    /// domains.insert("admin".to_string(), DomainPolicy {
+    ///     cert_types: vec![CertType::User],
+    ///     groups: vec!["ops".into()],
+    ///     // Keep role metadata from the application
+    ///     disclose: Some(vec!["type".into(), "groups".into()]),
+    ///     ..DomainPolicy::default()
+    /// });
+    /// let handler = ZapHandler::new_with_domains(None, &cert, &auth_cert, "auth.example.com", 7101, domains)?;
+    /// ```
+    pub fn new_with_domains(cert_type: Option<CertType>,
+                            cert: &ZCert,
+                            auth_cert: &ZCert,
+                            auth_server: &str,
+                            auth_port: u32,
+                            domains: HashMap<String, DomainPolicy>) -> Result<ZapHandler> {
+        Self::builder(cert, auth_cert)
+            .cert_type(cert_type)
+            .server(auth_server, auth_port)
+            .domains(domains)
+            .build()
+    }
+
+    /// Like `new()`, but only public keys are fetched from the Auth
+    /// server. Use this for large fleets where the handler only needs
+    /// to know whether a key is allowed.
+    ///
+    /// Certs in the cache are named after their public key and carry
+    /// no other metadata, so the ZAP reply won't either.
+    pub fn new_keys_only(cert_type: Option<CertType>, cert: &ZCert, auth_cert: &ZCert, auth_server: &str, auth_port: u32, allow_self: bool) -> Result<ZapHandler> {
+        Self::builder(cert, auth_cert)
+            .cert_type(cert_type)
+            .server(auth_server, auth_port)
+            .allow_self(allow_self)
+            .keys_only(true)
+            .build()
+    }
+
+    /// Like `new()`, but subscribes to several Auth servers at once,
+    /// given as `(host, port)` pairs, so that clients can still be
+    /// authenticated while some of them are down. ZeroMQ keeps retrying
+    /// servers it can't reach.
+    ///
+    /// The servers must share `auth_cert` and should serve the same
+    /// certs, e.g. replicas of one store. Updates are applied from
+    /// whichever server sends them, unless a newer change to the same
+    /// cert has already been applied, e.g. from a replica that is
+    /// ahead of the sender. Changes are ordered by publish time, so the
+    /// servers' clocks should be kept in sync.
+    pub fn new_with_servers(cert_type: Option<CertType>, cert: &ZCert, auth_cert: &ZCert, servers: &[(&str, u32)], allow_self: bool) -> Result<ZapHandler> {
+        let mut builder = Self::builder(cert, auth_cert).cert_type(cert_type).allow_self(allow_self);
+        for &(host, port) in servers {
+            builder = builder.server(host, port);
+        }
+        builder.build()
+    }
+
+    /// Configure a handler option by option, for combinations the
+    /// constructors above don't cover.
+    ///
+    /// ```text
+    /// let handler = ZapHandler::builder(&cert, &auth_cert)
+    ///     .cert_type(Some(CertType::Host))
+    ///     .server("auth1.example.com", 7101)
+    ///     .server("auth2.example.com", 7101)
+    ///     .keys_only(true)
+    ///     .reconnect(ReconnectPolicy::fleet())
+    ///     .build()?;
+    /// ```
+    pub fn builder<'a>(cert: &'a ZCert, auth_cert: &'a ZCert) -> ZapHandlerBuilder<'a> {
+        ZapHandlerBuilder {
+            cert: cert,
+            auth_cert: auth_cert,
+            cert_type: None,
+            servers: Vec::new(),
+            allow_self: false,
+            domains: None,
+            ban_policy: BanPolicy::default(),
+            keys_only: false,
+            reconnect: ReconnectPolicy::default(),
+            cache_path: None,
+            cache_limits: CacheLimits::default(),
+            negative_ttl: Duration::from_secs(DEFAULT_NEGATIVE_TTL_SECS),
+            workers: 1,
+            affinity: AffinityPolicy::default(),
+            wait_ready: None,
+            cache_report: None,
+            resync_port: None,
+            snapshot_format: SnapshotFormat::Single,
+            scope: None,
+            socket_timeouts: SocketTimeouts::default(),
+            frame_policy: None,
+            feed_key: None,
+        }
+    }
+
+    fn connect(cert_type: Option<CertType>,
+               cert: &ZCert,
+               auth_cert: &ZCert,
+               servers: &[(&str, u32)],
+               domains: DomainRouter,
+               ban_policy: BanPolicy,
+               keys_only: bool,
+               reconnect: &ReconnectPolicy,
+               cache_path: Option<&str>,
+               cache_limits: CacheLimits,
+               negative_ttl: Duration,
+               workers: usize,
+               affinity: AffinityPolicy,
+               resync_port: Option<u32>,
+               snapshot_format: SnapshotFormat,
+               scope: Option<String>,
+               socket_timeouts: SocketTimeouts,
+               feed_key: Option<(PublicKey, Duration)>) -> Result<ZapHandler> {
+        let zap = if workers > 1 {
+            try!(ZSock::new_router(ZAP_ENDPOINT))
+        } else {
+            try!(ZSock::new_rep(ZAP_ENDPOINT))
+        };
         zap.set_linger(0);
+        socket_timeouts.apply(&zap);
 
         let mut subscriber = ZSock::new(SocketType::SUB);
         subscriber.set_curve_serverkey(auth_cert.public_txt());
         cert.apply(&mut subscriber);
         subscriber.set_linger(0);
-        try!(subscriber.connect(&format!("tcp://{}:{}", auth_server, auth_port)));
-        match cert_type {
-            Some(ct) => subscriber.set_subscribe(ct.to_str()),
-            None => subscriber.set_subscribe(""),
+        socket_timeouts.apply(&subscriber);
+        reconnect.apply(&mut subscriber);
+        let monitor = try!(feed_monitor::monitor(&mut subscriber));
+        let endpoints: Vec<String> = servers.iter().map(|&(host, port)| format!("tcp://{}:{}", host, port)).collect();
+        for endpoint in &endpoints {
+            try!(subscriber.connect(endpoint));
+        }
+        // Keys-only snapshots are small enough to send whole
+        let snapshot_format = if keys_only { SnapshotFormat::Single } else { snapshot_format };
+        let prefix = if keys_only { KEYS_ONLY_TOPIC_PREFIX } else { snapshot_format.topic_prefix() };
+        let subscription = format!("{}{}", prefix, cert_cache::scoped_topic(cert_type, scope.as_ref().map(|s| s.as_str())));
+        subscriber.set_subscribe(&subscription);
+        // Receive certs pushed directly to us
+        if let Some(Ok(name)) = cert.meta("name") {
+            subscriber.set_subscribe(&cert_cache::direct_topic(&name));
         }
 
-        let seed = if allow_self {
+        let seed = if domains.allows_self() {
             // Copy cert to new owned cert
             let c = ZCert::from_keys(cert.public_key(), cert.secret_key());
             c.set_meta("name", &cert.meta("name").unwrap().unwrap());
@@ -59,261 +426,2586 @@ impl ZapHandler {
         } else {
             None
         };
-        let cache = CertCache::new(seed);
+        let mut cache = CertCache::new(seed);
+        cache.set_limits(cache_limits);
+        cache.set_format(snapshot_format);
+        cache.set_scope(scope.clone());
+        cache.set_keys_only(keys_only);
+        if let Some((key, max_age)) = feed_key {
+            cache.verify_feed(key, max_age);
+        }
+        cache.resync(&cert_cache::snapshot_topics(&subscription));
+        if let Some(path) = cache_path {
+            if Path::new(path).exists() {
+                match cache.load(path, cert_type) {
+                    Ok(n) => info!("Loaded {} certificates from {}", n, path),
+                    // Start empty rather than not at all
+                    Err(e) => warn!("Could not load certificate cache {}: {}", path, e),
+                }
+            }
+        }
 
-        Self::run_worker(zap, subscriber, cache)
-    }
+        // Keys-only subscribers can't be sent full certs, and resync
+        // replies aren't scoped
+        let resync = match resync_port {
+            Some(port) if !keys_only && scope.is_none() => {
+                let mut sock = ZSock::new(SocketType::DEALER);
+                sock.set_curve_serverkey(auth_cert.public_txt());
+                cert.apply(&mut sock);
+                sock.set_linger(0);
+                sock.set_sndtimeo(Some(0));
+                for &(host, _) in servers {
+                    try!(sock.connect(&format!("tcp://{}:{}", host, port)));
+                }
+                Some(sock)
+            },
+            _ => None,
+        };
 
-    fn run_worker(zap: ZSock, subscriber: ZSock, cache: CertCache) -> Result<ZapHandler> {
-        let (comm, comm_child) = try!(ZSys::create_pipe());
-        comm.set_linger(0);
-        comm_child.set_linger(0);
+        let full_feed = cert_type.is_none() && !keys_only && !domains.allows_self();
+        let aliases = affinity::endpoint_aliases(&endpoints);
+        let feed = Feed {
+            monitor: monitor,
+            snapshot_topics: cert_cache::snapshot_topics(&subscription),
+            subscription: subscription,
+            endpoints: endpoints,
+            drain_holdoff: reconnect.drain_holdoff,
+            held: Vec::new(),
+            // Refresh certs well before they expire
+            sweep_interval: cache_limits.ttl.map(|ttl| ttl / 2),
+            next_sweep: cache_limits.ttl.map(|ttl| Instant::now() + ttl / 2),
+            affinity: affinity,
+            adverts: HashMap::new(),
+            aliases: aliases,
+            connected: HashSet::new(),
+            standby: Vec::new(),
+            resync: resync,
+            cert_type: cert_type,
+        };
+        let mut handler = try!(Self::run_worker_with_feed(zap, subscriber, Some(feed), cache, cache_path.map(|p| p.to_string()), domains, ban_policy, negative_ttl, workers));
+        handler.full_feed = full_feed;
+        Ok(handler)
+    }
 
-        Ok(ZapHandler {
-            worker: Some(spawn(move || {
-                let mut w = Worker::new(zap, subscriber, comm_child, cache);
-                if let Err(_e) = w.run() {
-                    error!("ZAP Error: {:?}", _e);
-                    // XXX impl error_handler()
-                }
-            })),
-            thread_comm: comm,
-        })
+    /// Call `callback` whenever a cert matching `filter` arrives on or
+    /// is removed from the Auth server's feed. A `None` filter matches
+    /// every cert.
+    ///
+    /// Callbacks run on the ZAP worker thread between requests, so
+    /// they should return quickly and must not call `on_change()`.
+    pub fn on_change<F>(&self, filter: Option<Filter>, callback: F)
+        where F: FnMut(Change, &Cert) + Send + 'static
+    {
+        cert_cache::add_subscription(&self.subscriptions, filter, callback);
     }
-}
 
-struct Worker {
-    zap: ZSock,
-    subscriber: ZSock,
-    comm: ZSock,
-    cache: CertCache,
-}
+    /// Set the status text sent to clients that fail authentication,
+    /// e.g. a legal notice. Takes effect from the next ZAP request.
+    pub fn set_denied_text(&self, text: &str) -> Result<()> {
+        try!(validate_status_text(text));
+        self.settings.lock().unwrap().denied_text = text.to_string();
+        Ok(())
+    }
 
-impl Worker {
-    fn new(zap: ZSock, subscriber: ZSock, comm: ZSock, cache: CertCache) -> Worker {
-        Worker {
-            zap: zap,
-            subscriber: subscriber,
-            comm: comm,
-            cache: cache,
+    /// Reply to requests denied for `reason` with `status`, and with
+    /// `text` rather than the denied text, e.g. to tell banned clients
+    /// to back off with a 300. Takes effect from the next ZAP request.
+    pub fn set_deny_reply(&self, reason: DenyReason, status: ZapStatus, text: Option<&str>) -> Result<()> {
+        if let Some(text) = text {
+            try!(validate_status_text(text));
         }
+        self.settings.lock().unwrap().deny_replies.insert(reason, (status, text.map(|t| t.to_string())));
+        Ok(())
     }
 
-    fn run(&mut self) -> Result<()> {
-        let mut poller = try!(ZPoller::new());
-        try!(poller.add(&mut self.zap));
-        try!(poller.add(&mut self.subscriber));
-        try!(poller.add(&mut self.comm));
+    /// Restrict which source addresses clients may connect from. The
+    /// global rules are checked before the client's key is looked up.
+    /// Takes effect from the next ZAP request.
+    pub fn set_address_policy(&self, policy: AddressPolicy) {
+        self.settings.lock().unwrap().address_policy = policy;
+    }
 
-        loop {
-            let sock: Option<ZSock> = poller.wait(None);
-            if let Some(mut sock) = sock {
-                if sock == self.zap {
-                    // These frames are system defined. We can safely
-                    // unwrap them.
-                    let msg = ZMsg::expect_recv(&mut sock, 7, Some(7), false).unwrap();
-                    let mut request = try!(ZapRequest::new(
-                        &self.cache,
-                        &mut self.zap,
-                        msg.popstr().unwrap().unwrap(),
-                        msg.popstr().unwrap().unwrap(),
-                        msg.popstr().unwrap().unwrap(),
-                        msg.popstr().unwrap().unwrap(),
-                        msg.popstr().unwrap().unwrap(),
-                        msg.popstr().unwrap().unwrap(),
-                        try!(z85_encode(&try!(msg.popbytes()).unwrap()))));
-
-                    try!(request.authenticate());
-                }
-                else if sock == self.subscriber {
-                    try!(self.cache.recv(&mut sock));
-                }
-                else if sock == self.comm && try!(self.comm.recv_str()).unwrap_or(String::new()) == THREAD_TERM {
-                    break;
-                }
-            }
+    /// Check requests' identity and mechanism frames against `policy`.
+    /// Takes effect from the next ZAP request.
+    pub fn set_frame_policy(&self, policy: FramePolicy) {
+        self.settings.lock().unwrap().frame_policy = policy;
+    }
 
-            if poller.expired() {
-                return Err(Error::PollerTimeout);
-            }
-            else if poller.terminated() {
-                break;
-            }
-        }
+    /// Authenticate `PLAIN` clients with `verifier`. Without one, every
+    /// `PLAIN` request is denied. Verified clients are treated as users
+    /// named "plain:<username>" (see `PLAIN_IDENTITY_PREFIX`), with the
+    /// "mechanism" property "PLAIN".
+    ///
+    /// The verifier runs on the ZAP worker thread, so slow backends
+    /// (e.g. an LDAP bind) hold up other authentication requests.
+    pub fn set_plain_verifier<V: PlainVerifier + 'static>(&self, verifier: V) {
+        self.settings.lock().unwrap().plain_verifier = Some(Box::new(verifier));
+    }
 
-        Ok(())
+    /// Go back to denying every `PLAIN` request, e.g. when a reloaded
+    /// config no longer names a verifier.
+    pub fn clear_plain_verifier(&self) {
+        self.settings.lock().unwrap().plain_verifier = None;
     }
-}
 
-struct ZapRequest<'a> {
-    cache: &'a CertCache,
-    zap: &'a mut ZSock,
-    _version: String,
-    sequence: String,
-    _domain: String,
-    _address: String,
-    _identity: String,
-    mechanism: String,
-    client_pk: String,
-}
+    /// Run `script`'s `zap` hook for every client that would otherwise
+    /// be authenticated. See the `policy` module for the script API.
+    pub fn set_policy(&self, script: Option<PolicyScript>) {
+        self.settings.lock().unwrap().policy = script;
+    }
 
-impl<'a> ZapRequest<'a> {
-    fn new(cache: &'a CertCache,
-           zap: &'a mut ZSock,
-           version: String,
-           sequence: String,
-           domain: String,
-           address: String,
-           identity: String,
-           mechanism: String,
-           client_pk: String) -> Result<ZapRequest<'a>> {
+    /// Run `policy` after the handler's own checks. See `AuthPolicy`.
+    pub fn set_auth_policy<P: AuthPolicy + 'static>(&self, policy: P) {
+        self.settings.lock().unwrap().auth_policy = Some(Box::new(policy));
+    }
 
-        // This is hardcoded in ZMQ, so must always be
-        // consistent, or we won't stick around.
-        if version != "1.0" {
-            return Err(Error::ZapVersion);
-        }
+    /// Check the Auth server's signed attestations against `key`. A bad
+    /// signature is logged as an error. If the handler subscribes to
+    /// every cert type and doesn't accept its own cert, the cached cert
+    /// set is also compared with the attested one, and a mismatch is
+    /// logged as a warning.
+    ///
+    /// Handlers subscribed to a single cert type or to keys only don't
+    /// receive attestations.
+    pub fn set_attestation_key(&self, key: PublicKey) {
+        self.settings.lock().unwrap().attestation = Some(AttestationCheck {
+            key: key,
+            compare_root: self.full_feed,
+        });
+    }
 
-        // Ensure that client key is valid
-        if client_pk.len() != 40 {
-            return Err(Error::InvalidZapRequest);
-        }
+    /// Counters for the cache of recently denied keys. See
+    /// `ZapHandlerBuilder::negative_cache_ttl()`.
+    pub fn negative_cache_stats(&self) -> NegativeCacheStats {
+        *self.negative_stats.lock().unwrap()
+    }
+
+    /// How long feed messages took from the Auth server publishing
+    /// them to this handler applying them. This relies on both clocks
+    /// being in sync.
+    pub fn feed_latency(&self) -> LatencyHistogram {
+        self.latency.lock().unwrap().clone()
+    }
 
-        debug!("New ZAP request from {} ({}) via {}", client_pk, address, mechanism);
+    /// The latest heartbeat from the Auth server, or `None` if it
+    /// hasn't sent one.
+    pub fn last_heartbeat(&self) -> Option<Heartbeat> {
+        *self.heartbeat.lock().unwrap()
+    }
 
-        Ok(ZapRequest {
-            cache: cache,
-            zap: zap,
-            _version: version,
-            sequence: sequence,
-            _domain: domain,
-            _address: address,
-            _identity: identity,
-            mechanism: mechanism,
-            client_pk: client_pk,
-        })
+    /// Consider the feed stale once it goes `timeout` without a
+    /// heartbeat, e.g. because the Auth server died or is partitioned
+    /// from us, and cached certs may be out of date. `None` never does.
+    /// Defaults to 30 seconds. Servers that don't send heartbeats are
+    /// never considered stale.
+    pub fn set_heartbeat_timeout(&self, timeout: Option<Duration>) {
+        self.settings.lock().unwrap().heartbeat_timeout = timeout;
     }
 
-    fn authenticate(&mut self) -> Result<()> {
-        match self.mechanism.as_ref() {
-            "CURVE" => {
-                let cert = self.cache.get(&self.client_pk);
-                if let Some(c) = cert {
-                    debug!("Authenticated {}", self.client_pk);
-                    try!(self.zap_reply(true, Some(c.encode_meta())));
-                    return Ok(());
-                }
-            },
-            _ => (),
+    /// Whether the feed has gone quiet for longer than the heartbeat
+    /// timeout. The worker also logs a warning when this happens.
+    pub fn is_feed_stale(&self) -> bool {
+        let timeout = self.settings.lock().unwrap().heartbeat_timeout;
+        is_stale(self.last_heartbeat(), timeout, Instant::now())
+    }
+
+    /// Counts of the ZAP requests answered so far, and how long they
+    /// took.
+    pub fn stats(&self) -> ZapStats {
+        let mut stats = self.stats.lock().unwrap().snapshot();
+        stats.quarantined = self.quarantine.lock().unwrap().total;
+        stats
+    }
+
+    /// Certs the feed published on the topic for another cert type,
+    /// which were refused rather than cached. Each is also logged to
+    /// the "audit" target.
+    pub fn quarantine(&self) -> Quarantine {
+        self.quarantine.lock().unwrap().clone()
+    }
+
+    /// A handle to the sampling of the handler's per-request debug
+    /// logging, e.g. to log 1 in 100 accepts during an incident. The
+    /// handle can be kept after the handler is dropped.
+    pub fn log_sampler(&self) -> LogSampler {
+        self.log_sampler.clone()
+    }
+
+    /// Call `callback` with the handler's stats every `interval`, e.g.
+    /// to feed them into the agent's telemetry. This replaces any
+    /// previous callback. The callback runs on the worker thread,
+    /// which answers no requests until it returns.
+    pub fn on_stats<F>(&self, interval: Duration, callback: F)
+        where F: FnMut(&ZapStats) + Send + 'static
+    {
+        *self.stats_report.lock().unwrap() = Some(StatsReport {
+            interval: interval,
+            next: Instant::now() + interval,
+            callback: Box::new(callback),
+        });
+        // Ignore failure as it means the thread has already
+        // terminated.
+        let _ = self.thread_comm.send_str(THREAD_WAKE);
+    }
+
+    // Start reporting the cache's health to the Auth servers'
+    // `endpoints`. See `ZapHandlerBuilder::report_cache_health()`.
+    fn report_cache_health(&self, cert: &ZCert, auth_cert: &ZCert, endpoints: &[String], interval: Duration) -> Result<()> {
+        let mut sock = ZSock::new(SocketType::DEALER);
+        sock.set_curve_serverkey(auth_cert.public_txt());
+        cert.apply(&mut sock);
+        sock.set_linger(0);
+        sock.set_sndtimeo(Some(0));
+        sock.set_rcvtimeo(Some(0));
+        for endpoint in endpoints {
+            try!(sock.connect(endpoint));
         }
 
-        debug!("Could not authenticate {}", self.client_pk);
-        try!(self.zap_reply(false, None));
+        *self.cache_report.lock().unwrap() = Some(CacheReport {
+            interval: interval,
+            next: Instant::now() + interval,
+            sock: sock,
+        });
+        let _ = self.thread_comm.send_str(THREAD_WAKE);
         Ok(())
     }
 
-    fn zap_reply(&mut self, ok: bool, metadata: Option<Vec<u8>>) -> Result<()> {
-        let msg = ZMsg::new();
-        try!(msg.addstr("1.0"));
-        try!(msg.addstr(&self.sequence));
+    /// Whether the worker threads are answering ZAP requests, and how
+    /// often they have failed.
+    pub fn status(&self) -> WorkerStatus {
+        self.health.lock().unwrap().status.clone()
+    }
 
-        if ok {
-            try!(msg.addstr("200"));
-            try!(msg.addstr("OK"));
-        } else {
-            try!(msg.addstr("400"));
-            try!(msg.addstr("No access"));
-        }
+    /// Call `callback` whenever a worker fails, before it is
+    /// restarted. The worker waits for the callback to return.
+    pub fn on_error<F>(&self, callback: F)
+        where F: FnMut(&Error) + Send + 'static
+    {
+        self.health.lock().unwrap().on_error = Some(Box::new(callback));
+    }
+
+    /// Whether the Auth server's initial snapshot has been applied.
+    pub fn is_ready(&self) -> bool {
+        *(self.ready.0).lock().unwrap()
+    }
+
+    /// Block until the Auth server's initial snapshot has been applied,
+    /// or `timeout` passes. Until then, clients whose certs haven't
+    /// arrived yet are denied, so wait before serving them. Certs
+    /// loaded from `ZapHandlerBuilder::cache_path()` are available
+    /// straight away.
+    pub fn wait_ready(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let &(ref lock, ref cvar) = &*self.ready;
+        let mut ready = lock.lock().unwrap();
 
-        try!(msg.addstr("")); // User ID
-        match metadata {
-            Some(data) => {
-                let frame = try!(ZFrame::new(&data));
-                try!(msg.append(frame));
+        while !*ready {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::SnapshotTimeout);
             }
-            None => try!(msg.addstr("")),
+            ready = cvar.wait_timeout(ready, deadline - now).unwrap().0;
         }
 
-        try!(msg.send(self.zap));
         Ok(())
     }
-}
 
-impl<'a> fmt::Debug for ZapRequest<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ZapRequest {{ version: {}, sequence: {}, domain: {}, address: {}, identity: {}, mechanism: {}, client_pk: {} }}",
-            self._version,
-            self.sequence,
-            self._domain,
-            self._address,
-            self._identity,
-            self.mechanism,
-            self.client_pk)
+    #[cfg(any(test, feature = "bench-support"))]
+    fn run_worker(zap: ZSock, subscriber: ZSock, cache: CertCache, domains: DomainRouter, ban_policy: BanPolicy) -> Result<ZapHandler> {
+        Self::run_worker_with_feed(zap, subscriber, None, cache, None, domains, ban_policy, Duration::from_secs(DEFAULT_NEGATIVE_TTL_SECS), 1)
+    }
+
+    /// A worker answering ZAP requests on `zap` from `cache` alone,
+    /// for the benchmarks in benches/.
+    #[cfg(feature = "bench-support")]
+    #[doc(hidden)]
+    pub fn bench_worker(zap: ZSock, cache: CertCache) -> Result<ZapHandler> {
+        Self::run_worker(zap, ZSock::new(SocketType::SUB), cache, DomainRouter::any_domain(DomainPolicy::default(), ""), BanPolicy::default())
+    }
+
+    fn run_worker_with_feed(zap: ZSock,
+                            subscriber: ZSock,
+                            feed: Option<Feed>,
+                            cache: CertCache,
+                            cache_path: Option<String>,
+                            domains: DomainRouter,
+                            ban_policy: BanPolicy,
+                            negative_ttl: Duration,
+                            workers: usize) -> Result<ZapHandler> {
+        let (comm, comm_child) = try!(ZSys::create_pipe());
+        comm.set_linger(0);
+        comm_child.set_linger(0);
+        let subscriptions = cache.subscriptions();
+        let latency = cache.latency();
+        let quarantine = cache.quarantine();
+        let heartbeat = cache.heartbeat();
+        let settings = Arc::new(Mutex::new(Settings {
+            denied_text: DEFAULT_DENIED_TEXT.to_string(),
+            deny_replies: HashMap::new(),
+            address_policy: AddressPolicy::default(),
+            frame_policy: FramePolicy::default(),
+            plain_verifier: None,
+            policy: None,
+            auth_policy: None,
+            attestation: None,
+            heartbeat_timeout: Some(Duration::from_secs(DEFAULT_HEARTBEAT_TIMEOUT_SECS)),
+        }));
+        let ready = Arc::new((Mutex::new(!cache.is_resyncing()), Condvar::new()));
+        let worker_ready = ready.clone();
+        let negative = NegativeCache::new(negative_ttl);
+        let negative_stats = negative.stats();
+        let shared = Shared {
+            cache: SharedCertCache::new(cache),
+            domains: Arc::new(domains),
+            guard: Arc::new(Mutex::new(BruteForceGuard::new(ban_policy))),
+            negative: Arc::new(Mutex::new(negative)),
+            settings: settings.clone(),
+            stats: Arc::new(Mutex::new(ZapStatsRecorder::new())),
+            stats_report: Arc::new(Mutex::new(None)),
+            cache_report: Arc::new(Mutex::new(None)),
+            log_sampler: LogSampler::new(),
+        };
+        let stats = shared.stats.clone();
+        let stats_report = shared.stats_report.clone();
+        let cache_report = shared.cache_report.clone();
+        let log_sampler = shared.log_sampler.clone();
+        let health = Arc::new(Mutex::new(Health {
+            status: WorkerStatus {
+                alive: true,
+                restarts: 0,
+                last_error: None,
+            },
+            down: 0,
+            on_error: None,
+        }));
+        let worker_health = health.clone();
+        // With a single worker, the main worker answers requests
+        // itself
+        let pool = if workers > 1 {
+            Some(try!(Pool::new(workers, &shared, &health)))
+        } else {
+            None
+        };
+
+        Ok(ZapHandler {
+            worker: Some(spawn(move || {
+                let mut w = Worker::new(zap, pool, subscriber, feed, comm_child, shared, cache_path, worker_ready);
+                supervise(&mut w, &worker_health);
+                worker_health.lock().unwrap().status.alive = false;
+            })),
+            thread_comm: comm,
+            subscriptions: subscriptions,
+            settings: settings,
+            full_feed: false,
+            ready: ready,
+            negative_stats: negative_stats,
+            latency: latency,
+            quarantine: quarantine,
+            heartbeat: heartbeat,
+            health: health,
+            stats: stats,
+            stats_report: stats_report,
+            cache_report: cache_report,
+            log_sampler: log_sampler,
+        })
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use cert::{Cert, CertType};
-    use cert_cache::CertCache;
-    use czmq::{ZCert, ZMsg, ZSock, SocketType, ZSys};
-    use std::thread::sleep;
-    use std::time::Duration;
-    use super::*;
+/// Options for a `ZapHandler`. See `ZapHandler::builder()`.
+pub struct ZapHandlerBuilder<'a> {
+    cert: &'a ZCert,
+    auth_cert: &'a ZCert,
+    cert_type: Option<CertType>,
+    servers: Vec<(String, u32)>,
+    allow_self: bool,
+    domains: Option<HashMap<String, DomainPolicy>>,
+    ban_policy: BanPolicy,
+    keys_only: bool,
+    reconnect: ReconnectPolicy,
+    cache_path: Option<String>,
+    cache_limits: CacheLimits,
+    negative_ttl: Duration,
+    workers: usize,
+    affinity: AffinityPolicy,
+    wait_ready: Option<Duration>,
+    cache_report: Option<(u32, Duration)>,
+    resync_port: Option<u32>,
+    snapshot_format: SnapshotFormat,
+    scope: Option<String>,
+    socket_timeouts: SocketTimeouts,
+    frame_policy: Option<FramePolicy>,
+    feed_key: Option<(PublicKey, Duration)>,
+}
 
-    #[test]
-    fn test_auth() {
-        ZSys::init();
+impl<'a> ZapHandlerBuilder<'a> {
+    /// Only fetch certs of this type. `None` (the default) fetches all.
+    pub fn cert_type(mut self, cert_type: Option<CertType>) -> Self {
+        self.cert_type = cert_type;
+        self
+    }
 
-        let cert = Cert::new("jimbob", CertType::User).unwrap();
+    /// Subscribe to the Auth server at `host`. Add at least one. See
+    /// `ZapHandler::new_with_servers()` for using several.
+    pub fn server(mut self, host: &str, port: u32) -> Self {
+        self.servers.push((host.to_string(), port));
+        self
+    }
 
-        let mut zap = ZSock::new_req("inproc://zap_handler_test_zap").unwrap();
-        zap.set_sndtimeo(Some(500));
-        zap.set_rcvtimeo(Some(500));
+    /// Whether the handler's own cert may authenticate. Ignored if
+    /// `domains()` is set.
+    pub fn allow_self(mut self, allow_self: bool) -> Self {
+        self.allow_self = allow_self;
+        self
+    }
 
-        let zap_server = ZSock::new_rep("inproc://zap_handler_test_zap").unwrap();
+    /// See `ZapHandler::new_with_domains()`.
+    pub fn domains(mut self, domains: HashMap<String, DomainPolicy>) -> Self {
+        self.domains = Some(domains);
+        self
+    }
 
-        let mut publisher = ZSock::new_pub("inproc://zap_handler_test_pub").unwrap();
-        publisher.set_sndtimeo(Some(500));
+    pub fn ban_policy(mut self, ban_policy: BanPolicy) -> Self {
+        self.ban_policy = ban_policy;
+        self
+    }
 
-        let subscriber = ZSock::new(SocketType::SUB);
-        subscriber.set_subscribe(CertType::User.to_str());
-        subscriber.connect("inproc://zap_handler_test_pub").unwrap();
+    /// See `ZapHandler::new_keys_only()`.
+    pub fn keys_only(mut self, keys_only: bool) -> Self {
+        self.keys_only = keys_only;
+        self
+    }
 
-        let _handler = ZapHandler::run_worker(zap_server, subscriber, CertCache::new(None)).unwrap();
+    /// How to retry the Auth server after losing the connection. Large
+    /// fleets should use `ReconnectPolicy::fleet()` or similar, so that
+    /// a server restart isn't met by every client at once.
+    pub fn reconnect(mut self, reconnect: ReconnectPolicy) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
 
-        let zap_msg = new_zap_msg(&cert);
-        zap_msg.send(&mut zap).unwrap();
+    /// Keep a copy of the cache at `path` and load it at startup, so
+    /// that peers can be authenticated after a reboot before the Auth
+    /// server is reachable. The file is rewritten whenever the feed
+    /// changes the cache, and loaded certs are replaced by the Auth
+    /// server's snapshot once connected.
+    ///
+    /// Anyone who can write to `path` can add certs, so it should only
+    /// be writable by this process.
+    pub fn cache_path(mut self, path: &str) -> Self {
+        self.cache_path = Some(path.to_string());
+        self
+    }
 
-        let reply = ZMsg::recv(&mut zap).unwrap();
-        reply.popstr().unwrap().unwrap();
-        reply.popstr().unwrap().unwrap();
-        assert_eq!(reply.popstr().unwrap().unwrap(), "400");
-        assert_eq!(reply.popstr().unwrap().unwrap(), "No access");
+    /// Bound the certs kept from the feed, so that a long-running
+    /// handler that misses DEL messages doesn't keep stale certs
+    /// forever. With a TTL, the handler asks the Auth server for a
+    /// fresh snapshot every half TTL, and certs missing from it are
+    /// dropped. Each request sends the snapshot to every subscriber
+    /// of the topic, so keep the TTL in hours rather than seconds.
+    pub fn cache_limits(mut self, limits: CacheLimits) -> Self {
+        self.cache_limits = limits;
+        self
+    }
 
-        let publish_msg = ZMsg::new();
-        publish_msg.addstr("user").unwrap();
-        publish_msg.addstr("ADD").unwrap();
-        publish_msg.addstr(cert.public_txt()).unwrap();
-        publish_msg.addbytes(&cert.encode_meta()).unwrap();
-        publish_msg.send(&mut publisher).unwrap();
+    /// How long to keep denying a failed CURVE key, with the same
+    /// reason, without looking it up again, to absorb reconnect storms
+    /// from misconfigured clients [default: 5s]. The keys are forgotten
+    /// whenever the feed adds or updates certs. Zero disables this.
+    /// Ignored while an `AuthPolicy` is set, as it may admit unknown
+    /// keys.
+    pub fn negative_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
 
-        sleep(Duration::from_millis(200));
+    /// Answer ZAP requests on `workers` threads (default 1), for
+    /// servers that accept many connections at once. The threads
+    /// share one cert cache, ban list and negative cache.
+    ///
+    /// Settings such as `set_policy()` and `set_auth_policy()` are
+    /// still only run by one thread at a time.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = cmp::max(workers, 1);
+        self
+    }
 
-        let zap_msg = new_zap_msg(&cert);
-        zap_msg.send(&mut zap).unwrap();
-        let reply = ZMsg::recv(&mut zap).unwrap();
-        reply.popstr().unwrap().unwrap();
-        reply.popstr().unwrap().unwrap();
-        assert_eq!(reply.popstr().unwrap().unwrap(), "200");
-        assert_eq!(reply.popstr().unwrap().unwrap(), "OK");
+    /// Which servers to prefer when subscribed to several. By default
+    /// every server is used. See `AffinityPolicy`.
+    pub fn affinity(mut self, affinity: AffinityPolicy) -> Self {
+        self.affinity = affinity;
+        self
     }
 
-    fn new_zap_msg(cert: &ZCert) -> ZMsg {
-        let zap_msg = ZMsg::new();
-        zap_msg.addstr("1.0").unwrap();
-        zap_msg.addstr("1").unwrap();
-        zap_msg.addstr("test-domain").unwrap();
+    /// Report the cache's applied sequence, size and last update to
+    /// the Auth server's API on `api_port` every `interval`, so
+    /// operators can spot agents with stale caches. Off by default.
+    /// With several servers, each report goes to one of them.
+    pub fn report_cache_health(mut self, api_port: u32, interval: Duration) -> Self {
+        self.cache_report = Some((api_port, interval));
+        self
+    }
+
+    /// Fetch snapshots from the Auth server's API on `api_port`
+    /// whenever the feed (re)connects, rather than relying on the
+    /// feed resending them. The snapshot is applied in one go, along
+    /// with the feed sequence it was taken at. Off by default, and
+    /// ignored for keys-only handlers.
+    pub fn resync_via_api(mut self, api_port: u32) -> Self {
+        self.resync_port = Some(api_port);
+        self
+    }
+
+    /// Ask for snapshots in batches, ended by an END, rather than one
+    /// message with every cert, which is slow and memory hungry for
+    /// large fleets. With `compress`, metadata is compressed with
+    /// zstd, which both ends must be built with the `zstd` feature
+    /// for. Needs an Auth server that understands batching. Ignored
+    /// for keys-only handlers.
+    pub fn batched_snapshots(mut self, compress: bool) -> Self {
+        self.snapshot_format = if compress { SnapshotFormat::Compressed } else { SnapshotFormat::Batched };
+        self
+    }
+
+    /// As `batched_snapshots(false)`, with metadata sent as CBOR maps,
+    /// which are smaller and easier to parse outside of CZMQ. Applies to
+    /// changes as well as snapshots. Needs an Auth server that
+    /// understands CBOR. Ignored for keys-only handlers.
+    pub fn cbor_snapshots(mut self) -> Self {
+        self.snapshot_format = SnapshotFormat::Cbor;
+        self
+    }
+
+    /// Only fetch certs in `scope` of the cert type set with
+    /// `cert_type()`: a group (e.g. "webfarm"), or a tenant namespace
+    /// prefixed with "ns:" (e.g. "ns:acme"). Needs an Auth server that
+    /// understands scopes. Can't be combined with `keys_only()`, and
+    /// disables `resync_via_api()`.
+    pub fn scope(mut self, scope: &str) -> Self {
+        self.scope = Some(scope.to_string());
+        self
+    }
+
+    /// Bound blocking sends and receives on the ZAP and feed sockets.
+    /// A ZAP request whose frames don't arrive in time is dropped
+    /// with `Error::Timeout` rather than blocking the worker. Unset
+    /// by default.
+    pub fn socket_timeouts(mut self, timeouts: SocketTimeouts) -> Self {
+        self.socket_timeouts = timeouts;
+        self
+    }
+
+    /// Only apply feed messages signed with the Auth server's
+    /// attestation `key` (its `attestation.sign_feed` setting), and
+    /// published no more than `max_age` ago, which must allow for clock
+    /// skew. Each message is applied at most once, so an old change,
+    /// such as the ADD of a since rotated key, can't be replayed.
+    /// Rejected messages are logged to the "audit" target. Off by
+    /// default.
+    pub fn verify_feed(mut self, key: PublicKey, max_age: Duration) -> Self {
+        self.feed_key = Some((key, max_age));
+        self
+    }
+
+    /// See `ZapHandler::set_frame_policy()`.
+    pub fn frame_policy(mut self, policy: FramePolicy) -> Self {
+        self.frame_policy = Some(policy);
+        self
+    }
+
+    /// Make `build()` block until the handler is ready, failing if it
+    /// isn't within `timeout`. See `ZapHandler::wait_ready()`.
+    pub fn wait_ready(mut self, timeout: Duration) -> Self {
+        self.wait_ready = Some(timeout);
+        self
+    }
+
+    pub fn build(self) -> Result<ZapHandler> {
+        if self.servers.is_empty() {
+            return Err(Error::InvalidArg);
+        }
+        if self.scope.is_some() && (self.cert_type.is_none() || self.keys_only) {
+            return Err(Error::InvalidArg);
+        }
+        if self.snapshot_format == SnapshotFormat::Compressed && !compression::is_supported() {
+            return Err(Error::Compression("inauth_client was built without the \"zstd\" feature".into()));
+        }
+
+        let domains = match self.domains {
+            Some(d) => DomainRouter::new(d, self.cert.public_txt()),
+            None => DomainRouter::any_domain(DomainPolicy { allow_self: self.allow_self, ..DomainPolicy::default() }, self.cert.public_txt()),
+        };
+        let servers: Vec<(&str, u32)> = self.servers.iter().map(|&(ref h, p)| (h.as_str(), p)).collect();
+        let handler = try!(ZapHandler::connect(self.cert_type, self.cert, self.auth_cert, &servers, domains, self.ban_policy, self.keys_only, &self.reconnect, self.cache_path.as_ref().map(|p| p.as_str()), self.cache_limits, self.negative_ttl, self.workers, self.affinity, self.resync_port, self.snapshot_format, self.scope, self.socket_timeouts, self.feed_key));
+
+        if let Some(policy) = self.frame_policy {
+            handler.set_frame_policy(policy);
+        }
+
+        if let Some((api_port, interval)) = self.cache_report {
+            let endpoints: Vec<String> = self.servers.iter().map(|&(ref host, _)| format!("tcp://{}:{}", host, api_port)).collect();
+            try!(handler.report_cache_health(self.cert, self.auth_cert, &endpoints, interval));
+        }
+
+        if let Some(timeout) = self.wait_ready {
+            try!(handler.wait_ready(timeout));
+        }
+        Ok(handler)
+    }
+}
+
+// Connection events for the subscriber, so the cache can be resynced
+// whenever it (re)connects to an Auth server. Missed DELs would
+// otherwise leave revoked certs in the cache.
+struct Feed {
+    monitor: ZSock,
+    snapshot_topics: Vec<String>,
+    subscription: String,
+    // Every server, as "tcp://host:port"
+    endpoints: Vec<String>,
+    drain_holdoff: Duration,
+    // Draining servers we've disconnected from, until when
+    held: Vec<(String, Instant)>,
+    // How often to expire certs and request a snapshot, if they have
+    // a TTL
+    sweep_interval: Option<Duration>,
+    next_sweep: Option<Instant>,
+    affinity: AffinityPolicy,
+    // The last heartbeat from each server that advertised itself
+    adverts: HashMap<String, ServerAdvert>,
+    // How the monitor may name each endpoint
+    aliases: HashMap<String, String>,
+    connected: HashSet<String>,
+    // Servers we've disconnected from in favour of preferred ones
+    standby: Vec<String>,
+    // A DEALER to the servers' APIs for `feed::resync`
+    resync: Option<ZSock>,
+    cert_type: Option<CertType>,
+}
+
+impl Feed {
+    // Ask one of the servers for a snapshot. If none is reachable the
+    // feed's own snapshot still arrives.
+    fn request_resync(&mut self) {
+        let topic = self.cert_type.map(|t| t.to_str()).unwrap_or("");
+        if let Some(ref mut sock) = self.resync {
+            let msg = ZMsg::new();
+            let sent = msg.addstr("")
+                .and_then(|_| msg.addstr(RESYNC_ENDPOINT))
+                .and_then(|_| msg.addstr(topic))
+                .and_then(|_| msg.send(sock));
+            if sent.is_err() {
+                debug!("Could not request a certificate snapshot");
+            }
+        }
+    }
+
+    // Poll timeout (ms) until the next held server is due back or the
+    // next sweep
+    fn poll_timeout(&self, now: Instant) -> Option<u32> {
+        self.held.iter()
+            .map(|&(_, until)| until)
+            .chain(self.next_sweep)
+            .map(|until| if until > now { reconnect::millis(until - now) } else { 0 })
+            .min()
+            .map(|ms| cmp::min(ms, u32::MAX as u64) as u32)
+    }
+
+    // Which servers to reconnect to and which to disconnect from to
+    // follow the affinity policy. Servers on standby are brought back
+    // once none of the others is connected, and put back on standby
+    // when a preferred one returns.
+    fn rebalance(&mut self) -> (Vec<String>, Vec<String>) {
+        let active: Vec<String> = self.endpoints.iter()
+            .filter(|e| !self.standby.contains(e) && !self.held.iter().any(|&(ref h, _)| h == *e))
+            .cloned()
+            .collect();
+
+        if !self.standby.is_empty() && !active.iter().any(|e| self.connected.contains(e)) {
+            return (mem::replace(&mut self.standby, Vec::new()), Vec::new());
+        }
+
+        let candidates: Vec<String> = active.into_iter().filter(|e| self.connected.contains(e)).collect();
+        let preferred = match self.affinity.preferred(&candidates, &self.adverts) {
+            Some(p) => p,
+            None => return (Vec::new(), Vec::new()),
+        };
+        let standby: Vec<String> = candidates.into_iter().filter(|e| !preferred.contains(e)).collect();
+        for endpoint in &standby {
+            self.connected.remove(endpoint);
+        }
+        self.standby.extend(standby.iter().cloned());
+        (Vec::new(), standby)
+    }
+
+    fn endpoint_name(&self, reported: &str) -> String {
+        self.aliases.get(reported).cloned().unwrap_or(reported.to_string())
+    }
+}
+
+// State the ZAP request handlers share, whether on the main worker or
+// in a pool
+#[derive(Clone)]
+struct Shared {
+    cache: SharedCertCache,
+    domains: Arc<DomainRouter>,
+    guard: Arc<Mutex<BruteForceGuard>>,
+    negative: Arc<Mutex<NegativeCache>>,
+    settings: Arc<Mutex<Settings>>,
+    stats: Arc<Mutex<ZapStatsRecorder>>,
+    stats_report: Arc<Mutex<Option<StatsReport>>>,
+    cache_report: Arc<Mutex<Option<CacheReport>>>,
+    log_sampler: LogSampler,
+}
+
+impl Shared {
+    fn authenticate(&self, zap: &mut ZSock, msg: &ZMsg) -> Result<()> {
+        let started = Instant::now();
+        let mut request = ZapRequest {
+            cache: &self.cache,
+            domains: &self.domains,
+            guard: &self.guard,
+            negative: &self.negative,
+            settings: &self.settings,
+            stats: &self.stats,
+            log_sampler: &self.log_sampler,
+            frames: match try!(parse_request(zap, msg)) {
+                Some(frames) => frames,
+                None => return Ok(()),
+            },
+            zap: zap,
+            allowed: false,
+        };
+        // Rejects name the client anyway, so this is left out while
+        // accepts are sampled
+        if request.log_sampler.accept_every() == 1 {
+            debug!("New ZAP request from {} ({}) via {}", request.frames.client_id, request.frames.address, request.frames.mechanism);
+        }
+        try!(request.authenticate());
+        self.stats.lock().unwrap().record(&request.frames.mechanism, request.allowed, started.elapsed());
+        Ok(())
+    }
+
+    // Poll timeout (ms) until the stats are next due
+    fn stats_timeout(&self, now: Instant) -> Option<u32> {
+        self.stats_report.lock().unwrap().as_ref().map(|r| {
+            let ms = if r.next > now { reconnect::millis(r.next - now) } else { 0 };
+            cmp::min(ms, u32::MAX as u64) as u32
+        })
+    }
+
+    // Poll timeout (ms) until the cache health is next reported
+    fn cache_report_timeout(&self, now: Instant) -> Option<u32> {
+        self.cache_report.lock().unwrap().as_ref().map(|r| {
+            let ms = if r.next > now { reconnect::millis(r.next - now) } else { 0 };
+            cmp::min(ms, u32::MAX as u64) as u32
+        })
+    }
+
+    // Tell the Auth server how up to date our cache is. Reports are
+    // best effort: one that can't be sent straight away is dropped,
+    // and the next will do.
+    fn report_cache(&self) {
+        let now = Instant::now();
+        let mut report = self.cache_report.lock().unwrap();
+        if let Some(ref mut report) = *report {
+            if report.next <= now {
+                // Discard replies to earlier reports
+                while let Ok(reply) = ZMsg::recv(&mut report.sock) {
+                    reply.popstr(); // Delimiter
+                    if let Some(Ok(status)) = reply.popstr() {
+                        if status != "Ok" {
+                            debug!("Auth server refused cache health report");
+                        }
+                    }
+                }
+
+                let body = cache_health(&self.cache.read(), now);
+                let msg = ZMsg::new();
+                let sent = msg.addstr("")
+                    .and_then(|_| msg.addstr(CACHE_REPORT_ENDPOINT))
+                    .and_then(|_| msg.addstr(&body))
+                    .and_then(|_| msg.send(&mut report.sock));
+                if sent.is_err() {
+                    debug!("Could not send cache health report");
+                }
+                report.next = now + report.interval;
+            }
+        }
+    }
+
+    fn report_stats(&self) {
+        let now = Instant::now();
+        let mut report = self.stats_report.lock().unwrap();
+        if let Some(ref mut report) = *report {
+            if report.next <= now {
+                let mut stats = self.stats.lock().unwrap().snapshot();
+                stats.quarantined = self.cache.read().quarantine().lock().unwrap().total;
+                (report.callback)(&stats);
+                report.next = now + report.interval;
+            }
+        }
+    }
+}
+
+// Worker threads that answer ZAP requests, which the main worker
+// forwards from a ROUTER on the ZAP endpoint to a DEALER they are
+// connected to. Dropping the pool stops them.
+struct Pool {
+    backend: ZSock,
+    workers: Vec<(ZSock, JoinHandle<()>)>,
+}
+
+impl Pool {
+    fn new(size: usize, shared: &Shared, health: &Arc<Mutex<Health>>) -> Result<Pool> {
+        let endpoint = format!("inproc://zap_workers_{}", NEXT_POOL_ID.fetch_add(1, Ordering::SeqCst));
+        let backend = try!(ZSock::new_dealer(&format!("@{}", endpoint)));
+        backend.set_linger(0);
+
+        let mut workers = Vec::new();
+        for _ in 0..size {
+            let (comm, comm_child) = try!(ZSys::create_pipe());
+            comm.set_linger(0);
+            comm_child.set_linger(0);
+            let mut worker = PoolWorker {
+                zap: try!(PoolWorker::connect(&endpoint)),
+                backend: endpoint.clone(),
+                comm: comm_child,
+                shared: shared.clone(),
+            };
+            let health = health.clone();
+            workers.push((comm, spawn(move || supervise(&mut worker, &health))));
+        }
+
+        Ok(Pool {
+            backend: backend,
+            workers: workers,
+        })
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        for &(ref comm, _) in &self.workers {
+            let _ = comm.send_str(THREAD_TERM);
+        }
+        for (_, h) in self.workers.drain(..) {
+            let _ = h.join();
+        }
+    }
+}
+
+// A worker that `supervise()` can restart
+trait Supervised {
+    fn run(&mut self) -> Result<()>;
+    // The pipe the handler sends THREAD_TERM down
+    fn comm(&self) -> &ZSock;
+    // Close the ZAP socket, which may be stuck midway through a
+    // request, returning its endpoint
+    fn close_zap(&mut self) -> Result<String>;
+    fn open_zap(&mut self, endpoint: &str) -> Result<()>;
+}
+
+// Run `worker` until told to terminate, restarting it after errors.
+// Each failure waits twice as long as the last before restarting,
+// unless the worker had been running for a while.
+fn supervise<W: Supervised>(worker: &mut W, health: &Mutex<Health>) {
+    let mut backoff = Duration::from_millis(RESTART_BACKOFF_MIN_MS);
+
+    loop {
+        let started = Instant::now();
+        let err = match worker.run() {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        error!("ZAP worker failed: {}", err);
+
+        if started.elapsed() >= Duration::from_secs(RESTART_BACKOFF_RESET_SECS) {
+            backoff = Duration::from_millis(RESTART_BACKOFF_MIN_MS);
+        }
+
+        // Call back without holding the lock, so the callback may use
+        // the handler
+        let callback = {
+            let mut health = health.lock().unwrap();
+            health.down += 1;
+            health.status.alive = false;
+            health.status.last_error = Some(err.to_string());
+            health.on_error.take()
+        };
+        if let Some(mut callback) = callback {
+            callback(&err);
+            let mut health = health.lock().unwrap();
+            if health.on_error.is_none() {
+                health.on_error = Some(callback);
+            }
+        }
+
+        // Close the socket now so a bound endpoint is free again by
+        // the time we reopen it
+        let endpoint = match worker.close_zap() {
+            Ok(e) => e,
+            Err(e) => {
+                error!("Cannot restart ZAP worker: {}", e);
+                return;
+            }
+        };
+
+        if wait_term(worker.comm(), backoff) {
+            return;
+        }
+
+        if let Err(e) = worker.open_zap(&endpoint) {
+            error!("Cannot restart ZAP worker: {}", e);
+            return;
+        }
+
+        info!("Restarting ZAP worker after {}ms", reconnect::millis(backoff));
+        {
+            let mut health = health.lock().unwrap();
+            health.down -= 1;
+            health.status.alive = health.down == 0;
+            health.status.restarts += 1;
+        }
+        backoff = cmp::min(backoff * 2, Duration::from_millis(RESTART_BACKOFF_MAX_MS));
+    }
+}
+
+// Sleep for `timeout`, returning early with true if told to terminate
+// meanwhile
+fn wait_term(comm: &ZSock, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        comm.set_rcvtimeo(Some(cmp::min(reconnect::millis(deadline - now), i32::MAX as u64) as i32));
+        let msg = comm.recv_str();
+        comm.set_rcvtimeo(None);
+        match msg {
+            Ok(Ok(ref s)) if s == THREAD_TERM => return true,
+            // Timed out
+            Err(_) => return false,
+            // Something else, which we drop
+            Ok(_) => (),
+        }
+    }
+}
+
+struct PoolWorker {
+    zap: ZSock,
+    // The pool's DEALER
+    backend: String,
+    comm: ZSock,
+    shared: Shared,
+}
+
+impl PoolWorker {
+    fn connect(backend: &str) -> Result<ZSock> {
+        let zap = ZSock::new(SocketType::REP);
+        zap.set_linger(0);
+        try!(zap.connect(backend));
+        Ok(zap)
+    }
+}
+
+impl Supervised for PoolWorker {
+    fn run(&mut self) -> Result<()> {
+        let mut poller = try!(ZPoller::new());
+        try!(poller.add(&mut self.zap));
+        try!(poller.add(&mut self.comm));
+
+        loop {
+            let sock: Option<ZSock> = poller.wait(None);
+            if let Some(mut sock) = sock {
+                if sock == self.zap {
+                    let msg = try!(timeouts::recv(&mut sock));
+                    try!(self.shared.authenticate(&mut self.zap, &msg));
+                }
+                else if sock == self.comm && try!(self.comm.recv_str()).unwrap_or(String::new()) == THREAD_TERM {
+                    break;
+                }
+            }
+
+            if poller.terminated() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn comm(&self) -> &ZSock {
+        &self.comm
+    }
+
+    fn close_zap(&mut self) -> Result<String> {
+        self.zap = ZSock::new(SocketType::REP);
+        Ok(self.backend.clone())
+    }
+
+    fn open_zap(&mut self, endpoint: &str) -> Result<()> {
+        self.zap = try!(PoolWorker::connect(endpoint));
+        Ok(())
+    }
+}
+
+struct Worker {
+    // A REP socket, or with a pool, a ROUTER whose requests are
+    // forwarded to the pool
+    zap: ZSock,
+    pool: Option<Pool>,
+    subscriber: ZSock,
+    feed: Option<Feed>,
+    comm: ZSock,
+    shared: Shared,
+    cache_path: Option<String>,
+    ready: Ready,
+    next_purge: Instant,
+    // Whether we've warned that the feed went quiet
+    feed_stale: bool,
+}
+
+impl Worker {
+    fn new(zap: ZSock, pool: Option<Pool>, subscriber: ZSock, feed: Option<Feed>, comm: ZSock, shared: Shared, cache_path: Option<String>, ready: Ready) -> Worker {
+        Worker {
+            zap: zap,
+            pool: pool,
+            subscriber: subscriber,
+            feed: feed,
+            comm: comm,
+            shared: shared,
+            cache_path: cache_path,
+            ready: ready,
+            next_purge: Instant::now() + Duration::from_secs(EXPIRY_PURGE_INTERVAL_SECS),
+            feed_stale: false,
+        }
+    }
+
+    // Warn once when the feed goes quiet, and again when it recovers
+    fn check_heartbeat(&mut self) {
+        let heartbeat = *self.shared.cache.read().heartbeat().lock().unwrap();
+        let timeout = self.shared.settings.lock().unwrap().heartbeat_timeout;
+        let stale = is_stale(heartbeat, timeout, Instant::now());
+        if stale && !self.feed_stale {
+            warn!("No heartbeat from the Auth server for {}s, cached certificates may be out of date", heartbeat.map(|h| h.received.elapsed().as_secs()).unwrap_or(0));
+        } else if !stale && self.feed_stale {
+            info!("Heartbeats from the Auth server have resumed");
+        }
+        self.feed_stale = stale;
+    }
+
+    // Poll timeout (ms) until the next timer is due
+    fn poll_timeout(&self, now: Instant) -> u32 {
+        let purge = if self.next_purge > now { reconnect::millis(self.next_purge - now) } else { 0 };
+        let purge = cmp::min(purge, u32::MAX as u64) as u32;
+        self.feed.as_ref().and_then(|f| f.poll_timeout(now))
+            .into_iter()
+            .chain(self.shared.stats_timeout(now))
+            .chain(self.shared.cache_report_timeout(now))
+            .fold(purge, cmp::min)
+    }
+
+    // Drop certs that have passed their own expiry
+    fn purge_expired(&mut self) {
+        let now = Instant::now();
+        if self.next_purge <= now {
+            let purged = self.shared.cache.write().purge_expired(latency::now_micros() / 1_000_000);
+            if purged > 0 {
+                debug!("Purged {} expired certificate(s)", purged);
+                self.save_cache();
+            }
+            self.next_purge = now + Duration::from_secs(EXPIRY_PURGE_INTERVAL_SECS);
+        }
+    }
+
+    // On (re)connecting, the SUB socket resends its subscriptions and
+    // the Auth server answers with a snapshot, which replaces whatever
+    // we had cached.
+    fn feed_event(&mut self, monitor: &mut ZSock) -> Result<()> {
+        match try!(feed_monitor::recv_event(monitor)) {
+            Some(FeedEvent::Connected(endpoint)) => {
+                info!("Connected to Auth server at {}, resyncing certificates", endpoint);
+                if let Some(ref mut feed) = self.feed {
+                    self.shared.cache.write().resync(&feed.snapshot_topics);
+                    feed.request_resync();
+                    let name = feed.endpoint_name(&endpoint);
+                    feed.connected.insert(name);
+                }
+            },
+            Some(FeedEvent::Disconnected(endpoint)) => {
+                warn!("Lost connection to Auth server at {}", endpoint);
+                if let Some(ref mut feed) = self.feed {
+                    let name = feed.endpoint_name(&endpoint);
+                    feed.connected.remove(&name);
+                }
+            },
+            None => (),
+        }
+
+        self.rebalance()
+    }
+
+    // A bad snapshot is left for the feed's own to replace, so it
+    // doesn't stop the worker.
+    fn apply_snapshot(&mut self, sock: &mut ZSock) -> Result<()> {
+        let msg = try!(ZMsg::recv(sock));
+        msg.popstr(); // Delimiter
+        let cert_type = self.feed.as_ref().and_then(|f| f.cert_type);
+        match msg.popstr() {
+            Some(Ok(ref status)) if status == "Ok" => {
+                match self.shared.cache.write().apply_snapshot(cert_type, &msg) {
+                    Ok(Some(n)) => info!("Applied snapshot of {} certificates", n),
+                    Ok(None) => debug!("Ignoring snapshot older than the feed"),
+                    Err(e) => warn!("Could not apply certificate snapshot: {}", e),
+                }
+            },
+            _ => warn!("Auth server refused snapshot request: {}", msg.popstr().and_then(|s| s.ok()).unwrap_or(String::new())),
+        }
+
+        // A denied key may have just been added
+        self.shared.negative.lock().unwrap().flush();
+        self.save_cache();
+        self.update_ready();
+        Ok(())
+    }
+
+    // Note what servers have advertised about themselves, then choose
+    // between them
+    fn adverts(&mut self) -> Result<()> {
+        let adverts = self.shared.cache.write().take_adverts();
+        if let Some(ref mut feed) = self.feed {
+            for advert in adverts {
+                feed.adverts.insert(advert.endpoint.clone(), advert);
+            }
+        }
+
+        self.rebalance()
+    }
+
+    fn rebalance(&mut self) -> Result<()> {
+        let (connect, disconnect) = match self.feed {
+            Some(ref mut feed) => feed.rebalance(),
+            None => return Ok(()),
+        };
+
+        for endpoint in &disconnect {
+            info!("Preferring other Auth servers, standing by on {}", endpoint);
+            try!(self.subscriber.disconnect(endpoint));
+        }
+        if !connect.is_empty() {
+            warn!("No preferred Auth server is connected, failing over to {}", connect.join(", "));
+        }
+        for endpoint in &connect {
+            try!(self.subscriber.connect(endpoint));
+        }
+
+        Ok(())
+    }
+
+    // Move away from servers that announce they are draining, as long
+    // as there's another to fall back on. Without an endpoint in the
+    // announcement we can't tell which server it was.
+    fn drain_notices(&mut self) -> Result<()> {
+        let notices = self.shared.cache.write().take_drain_notices();
+        for notice in notices {
+            let feed = match self.feed {
+                Some(ref mut f) => f,
+                None => continue,
+            };
+
+            match notice {
+                Some(endpoint) => {
+                    let held = feed.held.iter().any(|&(ref e, _)| *e == endpoint);
+                    if feed.endpoints.contains(&endpoint) && !held && feed.endpoints.len() - feed.held.len() > 1 {
+                        info!("Auth server at {} is draining, using the others for {}s", endpoint, feed.drain_holdoff.as_secs());
+                        try!(self.subscriber.disconnect(&endpoint));
+                        feed.connected.remove(&endpoint);
+                        feed.held.push((endpoint, Instant::now() + feed.drain_holdoff));
+                    } else if !held {
+                        info!("Auth server at {} is draining", endpoint);
+                    }
+                },
+                None => info!("An Auth server is draining"),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn resume_held(&mut self) -> Result<()> {
+        if let Some(ref mut feed) = self.feed {
+            let now = Instant::now();
+            let (due, held): (Vec<_>, Vec<_>) = feed.held.drain(..).partition(|&(_, until)| until <= now);
+            feed.held = held;
+
+            for (endpoint, _) in due {
+                info!("Resuming subscription to Auth server at {}", endpoint);
+                try!(self.subscriber.connect(&endpoint));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Drop expired certs, then ask for a snapshot to refresh the rest.
+    // Subscribing again to a topic we already have makes the SUB
+    // socket resend it, and unsubscribing once leaves just the one.
+    fn sweep(&mut self) {
+        let now = Instant::now();
+        if let Some(ref mut feed) = self.feed {
+            match (feed.next_sweep, feed.sweep_interval) {
+                (Some(due), Some(interval)) if due <= now => {
+                    debug!("Expiring certificates and requesting a snapshot");
+                    let mut cache = self.shared.cache.write();
+                    cache.expire(now);
+                    cache.resync(&feed.snapshot_topics);
+                    feed.request_resync();
+                    self.subscriber.set_subscribe(&feed.subscription);
+                    self.subscriber.set_unsubscribe(&feed.subscription);
+                    feed.next_sweep = Some(now + interval);
+                },
+                _ => return,
+            }
+        }
+        self.save_cache();
+    }
+
+    fn update_ready(&self) {
+        let &(ref lock, ref cvar) = &*self.ready;
+        let mut ready = lock.lock().unwrap();
+        if !*ready && !self.shared.cache.read().is_resyncing() {
+            debug!("Initial certificate snapshot applied");
+            *ready = true;
+            cvar.notify_all();
+        }
+    }
+
+    // Failing to save only costs us the cache after a restart, so it
+    // doesn't stop the worker.
+    fn save_cache(&mut self) {
+        if let Some(ref path) = self.cache_path {
+            let mut cache = self.shared.cache.write();
+            if cache.is_modified() {
+                if let Err(e) = cache.save(path) {
+                    warn!("Could not save certificate cache {}: {}", path, e);
+                }
+            }
+        }
+    }
+
+    // A bad attestation doesn't stop authentication, which carries on
+    // from the cache as before.
+    fn check_attestation(&self) {
+        // Lock in the same order as ZAP requests
+        let cache = self.shared.cache.read();
+        let settings = self.shared.settings.lock().unwrap();
+        if let (Some(check), Some(attestation)) = (settings.attestation.as_ref(), cache.attestation()) {
+            if !attestation.verify(&check.key) {
+                error!("Attestation {} has an invalid signature", attestation.sequence);
+            }
+            else if check.compare_root && attestation.root != cache.merkle_root() {
+                warn!("Cached certificates do not match attestation {}", attestation.sequence);
+            }
+            else {
+                debug!("Verified attestation {}", attestation.sequence);
+            }
+        }
+    }
+}
+
+impl Supervised for Worker {
+    fn run(&mut self) -> Result<()> {
+        let mut poller = try!(ZPoller::new());
+        try!(poller.add(&mut self.zap));
+        try!(poller.add(&mut self.subscriber));
+        try!(poller.add(&mut self.comm));
+        if let Some(ref mut feed) = self.feed {
+            try!(poller.add(&mut feed.monitor));
+            if let Some(ref mut resync) = feed.resync {
+                try!(poller.add(resync));
+            }
+        }
+        if let Some(ref mut pool) = self.pool {
+            try!(poller.add(&mut pool.backend));
+        }
+
+        loop {
+            // Waking up for timers, so an expired wait isn't an error
+            let timeout = self.poll_timeout(Instant::now());
+            let sock: Option<ZSock> = poller.wait(Some(timeout));
+            if let Some(mut sock) = sock {
+                if sock == self.zap {
+                    if let Some(ref mut pool) = self.pool {
+                        let msg = try!(ZMsg::recv(&mut sock));
+                        try!(msg.send(&mut pool.backend));
+                    } else {
+                        let msg = try!(timeouts::recv(&mut sock));
+                        try!(self.shared.authenticate(&mut self.zap, &msg));
+                    }
+                }
+                else if self.pool.as_ref().map(|p| p.backend == sock).unwrap_or(false) {
+                    let msg = try!(ZMsg::recv(&mut sock));
+                    try!(msg.send(&mut self.zap));
+                }
+                else if sock == self.subscriber {
+                    let msg = try!(self.shared.cache.write().recv(&mut sock));
+                    if let Some(Ok(Ok(topic))) = msg.first().map(|f| f.data()) {
+                        if topic == ATTESTATION_TOPIC {
+                            self.check_attestation();
+                        }
+                    }
+                    // A denied key may have just been added, or
+                    // re-enabled by an UPDATE
+                    if let Some(Ok(Ok(action))) = msg.next().map(|f| f.data()) {
+                        if action == "ADD" || action == "UPDATE" {
+                            self.shared.negative.lock().unwrap().flush();
+                        }
+                    }
+                    self.save_cache();
+                    self.update_ready();
+                    try!(self.drain_notices());
+                    try!(self.adverts());
+                }
+                else if self.feed.as_ref().map(|f| f.monitor == sock).unwrap_or(false) {
+                    try!(self.feed_event(&mut sock));
+                }
+                else if self.feed.as_ref().and_then(|f| f.resync.as_ref()).map(|r| *r == sock).unwrap_or(false) {
+                    try!(self.apply_snapshot(&mut sock));
+                }
+                else if sock == self.comm && try!(self.comm.recv_str()).unwrap_or(String::new()) == THREAD_TERM {
+                    break;
+                }
+            }
+
+            try!(self.resume_held());
+            self.sweep();
+            self.purge_expired();
+            self.check_heartbeat();
+            self.shared.report_stats();
+            self.shared.report_cache();
+
+            if poller.terminated() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn comm(&self) -> &ZSock {
+        &self.comm
+    }
+
+    fn close_zap(&mut self) -> Result<String> {
+        let endpoint = try!(self.zap.endpoint()).to_string();
+        self.zap = ZSock::new(SocketType::REP);
+        Ok(endpoint)
+    }
+
+    fn open_zap(&mut self, endpoint: &str) -> Result<()> {
+        let zap = if self.pool.is_some() {
+            try!(ZSock::new_router(endpoint))
+        } else {
+            try!(ZSock::new_rep(endpoint))
+        };
+        zap.set_linger(0);
+        self.zap = zap;
+        Ok(())
+    }
+}
+
+struct ZapRequest<'a> {
+    cache: &'a SharedCertCache,
+    domains: &'a DomainRouter,
+    guard: &'a Mutex<BruteForceGuard>,
+    negative: &'a Mutex<NegativeCache>,
+    settings: &'a Mutex<Settings>,
+    stats: &'a Mutex<ZapStatsRecorder>,
+    log_sampler: &'a LogSampler,
+    zap: &'a mut ZSock,
+    frames: RequestFrames,
+    // Whether the reply let the client in
+    allowed: bool,
+}
+
+/// A decoded ZAP request, shared with the mock handler.
+pub struct RequestFrames {
+    pub version: String,
+    pub sequence: String,
+    pub domain: String,
+    pub address: String,
+    pub identity: String,
+    pub mechanism: String,
+    /// Public key for CURVE, username for PLAIN
+    pub client_id: String,
+    pub password: Option<String>,
+}
+
+impl RequestFrames {
+    /// Parse a ZAP request. Anything in the process can send to the
+    /// ZAP endpoint, so a missing or garbled frame is an error rather
+    /// than a panic.
+    pub fn parse(msg: &ZMsg) -> Result<RequestFrames> {
+        let version = try!(pop_frame(msg, "version"));
+        let sequence = try!(pop_frame(msg, "sequence"));
+        let domain = try!(pop_frame(msg, "domain"));
+        let address = try!(pop_frame(msg, "address"));
+        // The client's routing ID, which may be binary
+        let identity = match try!(msg.popbytes()) {
+            Some(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            None => return Err(Error::InvalidZapFrame("identity")),
+        };
+        let mechanism = try!(pop_frame(msg, "mechanism"));
+
+        // This is hardcoded in ZMQ, so must always be
+        // consistent, or we won't stick around.
+        if version != "1.0" {
+            return Err(Error::ZapVersion);
+        }
+
+        // Credentials follow the first 6 frames: none for NULL, a key
+        // for CURVE, a username and password for PLAIN.
+        let mut credentials = Vec::new();
+        while let Some(frame) = try!(msg.popbytes()) {
+            credentials.push(frame);
+        }
+        if credentials.len() > 2 {
+            return Err(Error::InvalidZapRequest);
+        }
+        let mut credentials = credentials.into_iter();
+        let (client_id, password) = match (mechanism.as_ref(), credentials.next(), credentials.next()) {
+            ("CURVE", Some(key), None) => {
+                let client_pk = try!(z85_encode(&key));
+
+                // Ensure that client key is valid
+                if client_pk.len() != 40 {
+                    return Err(Error::InvalidZapRequest);
+                }
+                (client_pk, None)
+            },
+            ("PLAIN", Some(username), Some(password)) => {
+                (try!(String::from_utf8(username).or(Err(Error::InvalidZapRequest))),
+                 Some(try!(String::from_utf8(password).or(Err(Error::InvalidZapRequest)))))
+            },
+            ("NULL", None, None) => (String::new(), None),
+            _ => return Err(Error::InvalidZapRequest),
+        };
+
+        Ok(RequestFrames {
+            version: version,
+            sequence: sequence,
+            domain: domain,
+            address: address,
+            identity: identity,
+            mechanism: mechanism,
+            client_id: client_id,
+            password: password,
+        })
+    }
+}
+
+// Pop the next frame as a string, naming it in the error if it's
+// missing or not UTF-8
+fn pop_frame(msg: &ZMsg, name: &'static str) -> Result<String> {
+    match try!(msg.popbytes()) {
+        Some(bytes) => String::from_utf8(bytes).or(Err(Error::InvalidZapFrame(name))),
+        None => Err(Error::InvalidZapFrame(name)),
+    }
+}
+
+/// Parse a ZAP request, answering it with status 500 if it's
+/// malformed. ZAP sockets must reply to every request before they
+/// can take the next, so this returns `None` rather than an error
+/// once the reply is sent.
+pub fn parse_request(zap: &mut ZSock, msg: &ZMsg) -> Result<Option<RequestFrames>> {
+    // Read ahead of parsing, which consumes the frames
+    let sequence = match msg.first().and_then(|_| msg.next()).map(|f| f.data()) {
+        Some(Ok(Ok(s))) => s,
+        _ => String::new(),
+    };
+
+    match RequestFrames::parse(msg) {
+        Ok(frames) => Ok(Some(frames)),
+        Err(e) => {
+            warn!("Rejected malformed ZAP request: {}", e);
+            try!(send_reply(zap, &sequence, None, None, ZapStatus::InternalError, &e.to_string()));
+            Ok(None)
+        },
+    }
+}
+
+impl<'a> ZapRequest<'a> {
+    fn authenticate(&mut self) -> Result<()> {
+        let now = Instant::now();
+        if self.guard.lock().unwrap().is_banned(&self.frames.client_id, &self.frames.address, now) {
+            debug!("Rejected banned client {} ({})", self.frames.client_id, self.frames.address);
+            try!(self.deny(DenyReason::RateLimited));
+            return Ok(());
+        }
+
+        // Keys that just failed still count towards a ban
+        let cacheable = self.frames.mechanism == "CURVE" && self.settings.lock().unwrap().auth_policy.is_none();
+        let cached = if cacheable { self.negative.lock().unwrap().get(&self.frames.client_id, now) } else { None };
+        if let Some(reason) = cached {
+            debug!("Rejected recently denied client {}", self.frames.client_id);
+            self.record_failure(now);
+            try!(self.deny(reason));
+            return Ok(());
+        }
+
+        if !self.settings.lock().unwrap().address_policy.allows_address(&self.frames.address) {
+            debug!("Rejected {} from disallowed address {}", self.frames.client_id, self.frames.address);
+            try!(self.deny(DenyReason::Forbidden));
+            return Ok(());
+        }
+
+        let violation = self.settings.lock().unwrap().frame_policy.check_mechanism(&self.frames.domain, &self.frames.mechanism);
+        if try!(self.enforce_frame_policy(violation)) {
+            return Ok(());
+        }
+
+        let mut reason = DenyReason::UnknownKey;
+        match self.frames.mechanism.as_ref() {
+            "CURVE" => {
+                let cache = self.cache.read();
+                let cert = cache.get(&self.frames.client_id);
+                self.stats.lock().unwrap().record_lookup(cert.is_some());
+                if cache.is_revoked(&self.frames.client_id) {
+                    reason = DenyReason::Revoked;
+                } else if cache.is_expired(&self.frames.client_id) {
+                    reason = DenyReason::Expired;
+                } else if cache.is_disabled(&self.frames.client_id) {
+                    reason = DenyReason::Forbidden;
+                }
+                if let Some(c) = cert {
+                    if !self.settings.lock().unwrap().address_policy.allows_cert_type(&self.frames.address, c.cert_type()) {
+                        debug!("Rejected {} cert {} from disallowed address {}", c.cert_type().to_str(), self.frames.client_id, self.frames.address);
+                        try!(self.deny(DenyReason::Forbidden));
+                        return Ok(());
+                    }
+
+                    if !self.domains.allows(&self.frames.domain, &self.frames.client_id, c.cert_type(), c) {
+                        debug!("Rejected {} cert {} for domain \"{}\"", c.cert_type().to_str(), self.frames.client_id, self.frames.domain);
+                        try!(self.deny(DenyReason::Forbidden));
+                        return Ok(());
+                    }
+
+                    let name = match c.meta("name") {
+                        Some(Ok(name)) => name,
+                        _ => String::new(),
+                    };
+                    let violation = self.settings.lock().unwrap().frame_policy.check_identity(&self.frames.identity, &name);
+                    if try!(self.enforce_frame_policy(violation)) {
+                        return Ok(());
+                    }
+
+                    let request = policy::zap_request(&self.frames.domain, &self.frames.address, &self.frames.mechanism, &self.frames.client_id, c);
+                    if !self.settings.lock().unwrap().policy.as_mut().map(|p| p.allows(Hook::Zap, &request)).unwrap_or(true) {
+                        debug!("Rejected {} by policy script", self.frames.client_id);
+                        try!(self.deny(DenyReason::Forbidden));
+                        return Ok(());
+                    }
+
+                    if self.authorize(Some(c)) == Some(Decision::Deny) {
+                        debug!("Rejected {} by auth policy", self.frames.client_id);
+                        try!(self.deny(DenyReason::Forbidden));
+                        return Ok(());
+                    }
+
+                    if self.log_sampler.sample_accept() {
+                        debug!("Authenticated {}", self.frames.client_id);
+                    }
+                    self.guard.lock().unwrap().record_success(&self.frames.client_id, &self.frames.address);
+                    try!(self.zap_reply(c));
+                    return Ok(());
+                }
+            },
+            "PLAIN" => {
+                let allowed = {
+                    let settings = self.settings.lock().unwrap();
+                    let verified = match (settings.plain_verifier.as_ref(), self.frames.password.as_ref()) {
+                        (Some(v), Some(p)) => v.verify(&self.frames.client_id, p),
+                        _ => false,
+                    };
+                    verified && settings.address_policy.allows_cert_type(&self.frames.address, CertType::User)
+                };
+                if allowed {
+                    let name = format!("{}{}", PLAIN_IDENTITY_PREFIX, self.frames.client_id);
+                    let meta = try!(named_identity(&name));
+                    meta.set_meta("type", CertType::User.to_str());
+                    meta.set_meta("mechanism", "PLAIN");
+                    let meta = try!(Cert::from_zcert(meta));
+
+                    if !self.domains.allows(&self.frames.domain, &name, CertType::User, &meta) {
+                        debug!("Rejected PLAIN user {} for domain \"{}\"", self.frames.client_id, self.frames.domain);
+                        try!(self.deny(DenyReason::Forbidden));
+                        return Ok(());
+                    }
+
+                    let violation = self.settings.lock().unwrap().frame_policy.check_identity(&self.frames.identity, &self.frames.client_id);
+                    if try!(self.enforce_frame_policy(violation)) {
+                        return Ok(());
+                    }
+
+                    let request = policy::zap_request(&self.frames.domain, &self.frames.address, &self.frames.mechanism, &self.frames.client_id, &meta);
+                    if !self.settings.lock().unwrap().policy.as_mut().map(|p| p.allows(Hook::Zap, &request)).unwrap_or(true) {
+                        debug!("Rejected {} by policy script", self.frames.client_id);
+                        try!(self.deny(DenyReason::Forbidden));
+                        return Ok(());
+                    }
+
+                    if self.authorize(Some(&meta)) == Some(Decision::Deny) {
+                        debug!("Rejected {} by auth policy", self.frames.client_id);
+                        try!(self.deny(DenyReason::Forbidden));
+                        return Ok(());
+                    }
+
+                    if self.log_sampler.sample_accept() {
+                        debug!("Authenticated {} via PLAIN", self.frames.client_id);
+                    }
+                    self.guard.lock().unwrap().record_success(&self.frames.client_id, &self.frames.address);
+                    try!(self.zap_reply(&meta));
+                    return Ok(());
+                }
+            },
+            _ => (),
+        }
+
+        if self.authorize(None) == Some(Decision::Allow) {
+            if self.log_sampler.sample_accept() {
+                debug!("Admitted unknown client {} by auth policy", self.frames.client_id);
+            }
+            let identity = try!(named_identity(&self.frames.client_id));
+            try!(self.zap_reply(&identity));
+            return Ok(());
+        }
+
+        debug!("Could not authenticate {}", self.frames.client_id);
+        if cacheable {
+            self.negative.lock().unwrap().insert(&self.frames.client_id, reason, now);
+        }
+        self.record_failure(now);
+        try!(self.deny(reason));
+        Ok(())
+    }
+
+    fn record_failure(&mut self, now: Instant) {
+        let bans = self.guard.lock().unwrap().record_failure(&self.frames.client_id, &self.frames.address, now);
+        for (key, ban) in bans {
+            warn!(target: "audit", "Banned {} for {}s after repeated authentication failures", key, ban.as_secs());
+        }
+    }
+
+    // Log a frame policy violation, denying the request if the policy
+    // says to. Returns whether it was denied.
+    fn enforce_frame_policy(&mut self, violation: Option<String>) -> Result<bool> {
+        let why = match violation {
+            Some(why) => why,
+            None => return Ok(false),
+        };
+        warn!(target: "audit", "ZAP request from {} ({}) {}", self.frames.client_id, self.frames.address, why);
+        if self.settings.lock().unwrap().frame_policy.enforcement == FrameEnforcement::Deny {
+            try!(self.deny(DenyReason::Forbidden));
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn authorize(&self, cert: Option<&Cert>) -> Option<Decision> {
+        self.settings.lock().unwrap().auth_policy.as_ref().map(|p| p.authorize(&ZapRequestInfo {
+            domain: &self.frames.domain,
+            address: &self.frames.address,
+            identity: &self.frames.identity,
+            mechanism: &self.frames.mechanism,
+            client_id: &self.frames.client_id,
+        }, cert))
+    }
+
+    fn zap_reply(&mut self, identity: &ZCert) -> Result<()> {
+        let disclose = self.domains.disclosed_keys(&self.frames.domain);
+        self.allowed = true;
+        send_reply(self.zap, &self.frames.sequence, Some(identity), disclose, ZapStatus::Denied, "")
+    }
+
+    fn deny(&mut self, reason: DenyReason) -> Result<()> {
+        let (status, text) = self.settings.lock().unwrap().deny_reply(reason);
+        self.allowed = false;
+        send_reply(self.zap, &self.frames.sequence, None, None, status, &text)
+    }
+}
+
+/// Reply to the ZAP request numbered `sequence`. If the client was
+/// authenticated as `identity`, its name becomes the User-Id and its
+/// metadata, limited to `disclose` if given, is sent as connection
+/// properties. Otherwise the request is denied with `denied_status`
+/// and `denied_text`.
+pub fn send_reply(zap: &mut ZSock, sequence: &str, identity: Option<&ZCert>, disclose: Option<&[String]>, denied_status: ZapStatus, denied_text: &str) -> Result<()> {
+    let msg = ZMsg::new();
+    try!(msg.addstr("1.0"));
+    try!(msg.addstr(sequence));
+
+    match identity {
+        Some(cert) => {
+            try!(msg.addstr("200"));
+            try!(msg.addstr("OK"));
+            let user_id = match cert.meta("name") {
+                Some(Ok(name)) => name,
+                _ => String::new(),
+            };
+            try!(msg.addstr(&user_id));
+            let frame = try!(ZFrame::new(&zap_properties(cert, disclose)));
+            try!(msg.append(frame));
+        },
+        None => {
+            try!(msg.addstr(denied_status.code()));
+            try!(msg.addstr(denied_text));
+            try!(msg.addstr("")); // User ID
+            try!(msg.addstr("")); // Metadata
+        },
+    }
+
+    try!(msg.send(zap));
+    Ok(())
+}
+
+/// An identity for clients without a cert of their own, carrying only
+/// a name.
+pub fn named_identity(name: &str) -> Result<ZCert> {
+    let cert = try!(ZCert::from_txt("0000000000000000000000000000000000000000", "0000000000000000000000000000000000000000"));
+    cert.set_meta("name", name);
+    Ok(cert)
+}
+
+/// Encode `cert`'s metadata as ZMTP connection properties (RFC 27),
+/// which applications read with `zmq_msg_gets()`, e.g. "type" or
+/// "groups". "groups" is always set, from the "group" metadata if the
+/// cert has no "groups" of its own.
+///
+/// Only names in `disclose` are sent, if given. Names that libzmq sets
+/// itself, or that ZMTP can't carry, are dropped.
+pub fn zap_properties(cert: &ZCert, disclose: Option<&[String]>) -> Vec<u8> {
+    let mut props = Vec::new();
+    for key in cert.meta_keys() {
+        if let Some(Ok(value)) = cert.meta(key) {
+            props.push((key.to_string(), value));
+        }
+    }
+    if !props.iter().any(|&(ref k, _)| k == "groups") {
+        let groups = props.iter().find(|&&(ref k, _)| k == "group").map(|&(_, ref v)| v.clone());
+        props.push(("groups".to_string(), groups.unwrap_or(String::new())));
+    }
+
+    let mut encoded = Vec::new();
+    for (name, value) in props {
+        if !disclose.map(|keys| keys.contains(&name)).unwrap_or(true) {
+            continue;
+        }
+        if !valid_property_name(&name) {
+            debug!("Not sending metadata \"{}\" as a ZAP property", name);
+            continue;
+        }
+
+        encoded.push(name.len() as u8);
+        encoded.extend_from_slice(name.as_bytes());
+        let len = value.len() as u32;
+        encoded.extend_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+        encoded.extend_from_slice(value.as_bytes());
+    }
+    encoded
+}
+
+fn valid_property_name(name: &str) -> bool {
+    !name.is_empty() &&
+        name.len() <= 255 &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.+".contains(c)) &&
+        !RESERVED_PROPERTIES.iter().any(|r| r.eq_ignore_ascii_case(name))
+}
+
+/// Check that `text` fits in a ZAP status-text field.
+pub fn validate_status_text(text: &str) -> Result<()> {
+    if text.is_empty() {
+        Err(Error::InvalidStatusText("must not be empty".into()))
+    }
+    else if text.len() > MAX_STATUS_TEXT {
+        Err(Error::InvalidStatusText(format!("must be at most {} bytes", MAX_STATUS_TEXT)))
+    } else {
+        Ok(())
+    }
+}
+
+// The cache's health as a JSON object of the applied feed `sequence`,
+// `cache_size` and seconds since the feed last updated it
+// (`last_update_age_secs`)
+fn cache_health(cache: &CertCache, now: Instant) -> String {
+    let mut report = Map::new();
+    report.insert("sequence".into(), cache.last_sequence().map(Value::from).unwrap_or(Value::Null));
+    report.insert("cache_size".into(), Value::from(cache.size() as u64));
+    report.insert("last_update_age_secs".into(), cache.last_update()
+        .map(|t| Value::from(if now > t { now.duration_since(t).as_secs() } else { 0 }))
+        .unwrap_or(Value::Null));
+    Value::Object(report).to_string()
+}
+
+// Whether the feed has gone `timeout` without a heartbeat as of `now`
+fn is_stale(heartbeat: Option<Heartbeat>, timeout: Option<Duration>, now: Instant) -> bool {
+    match (heartbeat, timeout) {
+        (Some(h), Some(t)) => now > h.received && now.duration_since(h.received) > t,
+        _ => false,
+    }
+}
+
+impl<'a> fmt::Debug for ZapRequest<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ZapRequest {{ version: {}, sequence: {}, domain: {}, address: {}, identity: {}, mechanism: {}, client_id: {} }}",
+            self.frames.version,
+            self.frames.sequence,
+            self.frames.domain,
+            self.frames.address,
+            self.frames.identity,
+            self.frames.mechanism,
+            self.frames.client_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use address_policy::AddressRules;
+    use cert::{Cert, CertType};
+    use auth_policy::{AuthPolicy, Decision, ZapRequestInfo};
+    use cert_cache::CertCache;
+    use czmq::{ZCert, ZMsg, ZSock, SocketType, ZSys};
+    use domain_policy::{DomainPolicy, DomainRouter};
+    use plain_auth::PlainVerifier;
+    use reconnect::ReconnectPolicy;
+    use std::collections::HashMap;
+    use std::thread::sleep;
+    use std::time::Duration;
+    use super::*;
+    use zap_stats::MechanismStats;
+
+    #[test]
+    fn test_auth() {
+        ZSys::init();
+
+        let cert = Cert::new("jimbob", CertType::User).unwrap();
+
+        let mut zap = ZSock::new_req("inproc://zap_handler_test_zap").unwrap();
+        zap.set_sndtimeo(Some(500));
+        zap.set_rcvtimeo(Some(500));
+
+        let zap_server = ZSock::new_rep("inproc://zap_handler_test_zap").unwrap();
+
+        let mut publisher = ZSock::new_pub("inproc://zap_handler_test_pub").unwrap();
+        publisher.set_sndtimeo(Some(500));
+
+        let subscriber = ZSock::new(SocketType::SUB);
+        subscriber.set_subscribe(CertType::User.to_str());
+        subscriber.connect("inproc://zap_handler_test_pub").unwrap();
+
+        let _handler = ZapHandler::run_worker(zap_server, subscriber, CertCache::new(None), any_domain(), BanPolicy::default()).unwrap();
+
+        let zap_msg = new_zap_msg(&cert);
+        zap_msg.send(&mut zap).unwrap();
+
+        let reply = ZMsg::recv(&mut zap).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "400");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "No access");
+
+        let publish_msg = ZMsg::new();
+        publish_msg.addstr("user").unwrap();
+        publish_msg.addstr("ADD").unwrap();
+        publish_msg.addstr(cert.public_txt()).unwrap();
+        publish_msg.addbytes(&cert.encode_meta()).unwrap();
+        publish_msg.send(&mut publisher).unwrap();
+
+        sleep(Duration::from_millis(200));
+
+        let zap_msg = new_zap_msg(&cert);
+        zap_msg.send(&mut zap).unwrap();
+        let reply = ZMsg::recv(&mut zap).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "200");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "OK");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "jimbob");
+    }
+
+    #[test]
+    fn test_auth_stats() {
+        ZSys::init();
+
+        let cert = Cert::new("jimbob", CertType::User).unwrap();
+
+        let mut zap = ZSock::new_req("inproc://zap_handler_test_stats").unwrap();
+        zap.set_sndtimeo(Some(500));
+        zap.set_rcvtimeo(Some(500));
+
+        let zap_server = ZSock::new_rep("inproc://zap_handler_test_stats").unwrap();
+
+        let mut publisher = ZSock::new_pub("inproc://zap_handler_test_stats_pub").unwrap();
+        publisher.set_sndtimeo(Some(500));
+
+        let subscriber = ZSock::new(SocketType::SUB);
+        subscriber.set_subscribe(CertType::User.to_str());
+        subscriber.connect("inproc://zap_handler_test_stats_pub").unwrap();
+
+        let handler = ZapHandler::run_worker(zap_server, subscriber, CertCache::new(None), any_domain(), BanPolicy::default()).unwrap();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reports_cb = reports.clone();
+        handler.on_stats(Duration::from_millis(50), move |s| reports_cb.lock().unwrap().push(s.clone()));
+
+        for _ in 0..2 {
+            let zap_msg = new_zap_msg(&cert);
+            zap_msg.send(&mut zap).unwrap();
+            let reply = ZMsg::recv(&mut zap).unwrap();
+            reply.popstr().unwrap().unwrap();
+            reply.popstr().unwrap().unwrap();
+            assert_eq!(reply.popstr().unwrap().unwrap(), "400");
+        }
+
+        let publish_msg = ZMsg::new();
+        publish_msg.addstr("user").unwrap();
+        publish_msg.addstr("ADD").unwrap();
+        publish_msg.addstr(cert.public_txt()).unwrap();
+        publish_msg.addbytes(&cert.encode_meta()).unwrap();
+        publish_msg.send(&mut publisher).unwrap();
+
+        sleep(Duration::from_millis(200));
+
+        let zap_msg = new_zap_msg(&cert);
+        zap_msg.send(&mut zap).unwrap();
+        let reply = ZMsg::recv(&mut zap).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "200");
+
+        // The second denial comes from the negative cache, which skips
+        // the cert cache
+        let stats = handler.stats();
+        assert_eq!(stats.mechanisms["CURVE"], MechanismStats { allowed: 1, denied: 2 });
+        assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+        assert!(stats.latency.is_some());
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.last().map(|s| s.mechanisms["CURVE"].denied), Some(2));
+    }
+
+    #[test]
+    fn test_auth_negative_cache() {
+        ZSys::init();
+
+        let cert = Cert::new("jimbob", CertType::User).unwrap();
+
+        let mut zap = ZSock::new_req("inproc://zap_handler_test_negative_cache").unwrap();
+        zap.set_sndtimeo(Some(500));
+        zap.set_rcvtimeo(Some(500));
+
+        let zap_server = ZSock::new_rep("inproc://zap_handler_test_negative_cache").unwrap();
+
+        let mut publisher = ZSock::new_pub("inproc://zap_handler_test_negative_cache_pub").unwrap();
+        publisher.set_sndtimeo(Some(500));
+
+        let subscriber = ZSock::new(SocketType::SUB);
+        subscriber.set_subscribe(CertType::User.to_str());
+        subscriber.connect("inproc://zap_handler_test_negative_cache_pub").unwrap();
+
+        let handler = ZapHandler::run_worker(zap_server, subscriber, CertCache::new(None), any_domain(), BanPolicy::default()).unwrap();
+
+        // The second attempt is denied from the negative cache
+        for _ in 0..2 {
+            let zap_msg = new_zap_msg(&cert);
+            zap_msg.send(&mut zap).unwrap();
+
+            let reply = ZMsg::recv(&mut zap).unwrap();
+            reply.popstr().unwrap().unwrap();
+            reply.popstr().unwrap().unwrap();
+            assert_eq!(reply.popstr().unwrap().unwrap(), "400");
+            assert_eq!(reply.popstr().unwrap().unwrap(), "No access");
+        }
+        let stats = handler.negative_cache_stats();
+        assert_eq!((stats.hits, stats.inserts, stats.entries), (1, 1, 1));
+
+        // Adding the cert flushes the negative cache, so it's let in
+        let publish_msg = ZMsg::new();
+        publish_msg.addstr("user").unwrap();
+        publish_msg.addstr("ADD").unwrap();
+        publish_msg.addstr(cert.public_txt()).unwrap();
+        publish_msg.addbytes(&cert.encode_meta()).unwrap();
+        publish_msg.send(&mut publisher).unwrap();
+
+        sleep(Duration::from_millis(200));
+
+        let zap_msg = new_zap_msg(&cert);
+        zap_msg.send(&mut zap).unwrap();
+        let reply = ZMsg::recv(&mut zap).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "200");
+        assert_eq!(handler.negative_cache_stats().flushes, 1);
+    }
+
+    #[test]
+    fn test_cache_health() {
+        let cache = CertCache::new(Some(vec![Cert::new("web1", CertType::Host).unwrap()]));
+        let report: Value = ::serde_json::from_str(&cache_health(&cache, Instant::now())).unwrap();
+        assert_eq!(report["cache_size"], 1);
+        assert!(report["sequence"].is_null());
+        assert!(report["last_update_age_secs"].is_null());
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let now = Instant::now();
+        let heartbeat = Heartbeat { received: now, sequence: 1, certs: Some(2), epoch: None };
+        let timeout = Some(Duration::from_secs(30));
+
+        assert!(!is_stale(None, timeout, now + Duration::from_secs(60)));
+        assert!(!is_stale(Some(heartbeat), timeout, now + Duration::from_secs(30)));
+        assert!(is_stale(Some(heartbeat), timeout, now + Duration::from_secs(31)));
+        assert!(!is_stale(Some(heartbeat), None, now + Duration::from_secs(31)));
+    }
+
+    #[test]
+    fn test_deny_reply() {
+        ZSys::init();
+
+        let cert = Cert::new("jimbob", CertType::User).unwrap();
+        let mut zap = ZSock::new_req("inproc://zap_handler_test_deny_reply").unwrap();
+        zap.set_sndtimeo(Some(500));
+        zap.set_rcvtimeo(Some(500));
+        let zap_server = ZSock::new_rep("inproc://zap_handler_test_deny_reply").unwrap();
+        let handler = ZapHandler::run_worker(zap_server, ZSock::new(SocketType::SUB), CertCache::new(None), any_domain(), BanPolicy::default()).unwrap();
+
+        assert!(handler.set_deny_reply(DenyReason::UnknownKey, ZapStatus::TemporaryError, Some("")).is_err());
+        handler.set_deny_reply(DenyReason::UnknownKey, ZapStatus::TemporaryError, Some("Try later")).unwrap();
+        handler.set_deny_reply(DenyReason::Forbidden, ZapStatus::InternalError, None).unwrap();
+
+        let mut rules = HashMap::new();
+        rules.insert("*".to_string(), AddressRules::new(&[], &["127.0.0.1".to_string()]).unwrap());
+        handler.set_address_policy(AddressPolicy::new(rules).unwrap());
+
+        new_zap_msg(&cert).send(&mut zap).unwrap();
+        let reply = ZMsg::recv(&mut zap).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "500");
+        assert_eq!(reply.popstr().unwrap().unwrap(), DEFAULT_DENIED_TEXT);
+
+        handler.set_address_policy(AddressPolicy::default());
+        new_zap_msg(&cert).send(&mut zap).unwrap();
+        let reply = ZMsg::recv(&mut zap).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "300");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "Try later");
+
+        assert_eq!(DenyReason::from_str("rate_limited").unwrap(), DenyReason::RateLimited);
+        assert_eq!(DenyReason::Expired.to_str(), "expired");
+        assert!(DenyReason::from_str("bogus").is_err());
+        assert_eq!(ZapStatus::from_code(300).unwrap(), ZapStatus::TemporaryError);
+        assert!(ZapStatus::from_code(200).is_err());
+    }
+
+    #[test]
+    fn test_frame_policy() {
+        ZSys::init();
+
+        let cert = Cert::new("jimbob", CertType::User).unwrap();
+        let zap_server = ZSock::new_rep("inproc://zap_handler_test_frame_policy").unwrap();
+        let handler = ZapHandler::run_worker(zap_server, ZSock::new(SocketType::SUB), CertCache::new(Some(vec![Cert::from_zcert(cert.dup()).unwrap()])), any_domain(), BanPolicy::default()).unwrap();
+        // Tells policy denials apart from unknown clients
+        handler.set_deny_reply(DenyReason::Forbidden, ZapStatus::TemporaryError, None).unwrap();
+        let mut mechanisms = HashMap::new();
+        mechanisms.insert("test-domain".to_string(), vec!["CURVE".to_string()]);
+        handler.set_frame_policy(FramePolicy {
+            require_identity_match: true,
+            mechanisms: mechanisms,
+            enforcement: FrameEnforcement::Deny,
+        });
+
+        let mut zap = ZSock::new_req("inproc://zap_handler_test_frame_policy").unwrap();
+        zap.set_sndtimeo(Some(500));
+        zap.set_rcvtimeo(Some(500));
+
+        let plain = ZMsg::new();
+        for frame in &["1.0", "1", "test-domain", "127.0.0.1", "jimbob", "PLAIN", "jimbob", "s3cr3t"] {
+            plain.addstr(frame).unwrap();
+        }
+        let identified = ZMsg::new();
+        for frame in &["1.0", "1", "test-domain", "127.0.0.1", "jimbob", "CURVE"] {
+            identified.addstr(frame).unwrap();
+        }
+        identified.addbytes(cert.public_key()).unwrap();
+
+        // The empty identity of `new_zap_msg()` doesn't match
+        for (msg, status) in vec![(plain, "300"), (new_zap_msg(&cert), "300"), (identified, "200")] {
+            msg.send(&mut zap).unwrap();
+            let reply = ZMsg::recv(&mut zap).unwrap();
+            reply.popstr().unwrap().unwrap();
+            reply.popstr().unwrap().unwrap();
+            assert_eq!(reply.popstr().unwrap().unwrap(), status);
+        }
+
+        handler.set_frame_policy(FramePolicy {
+            require_identity_match: true,
+            enforcement: FrameEnforcement::Log,
+            ..FramePolicy::default()
+        });
+        new_zap_msg(&cert).send(&mut zap).unwrap();
+        let reply = ZMsg::recv(&mut zap).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "200");
+    }
+
+    #[test]
+    fn test_malformed() {
+        ZSys::init();
+
+        let cert = Cert::new("jimbob", CertType::User).unwrap();
+        let zap_server = ZSock::new_rep("inproc://zap_handler_test_malformed").unwrap();
+        let handler = ZapHandler::run_worker(zap_server, ZSock::new(SocketType::SUB), CertCache::new(None), any_domain(), BanPolicy::default()).unwrap();
+
+        let mut zap = ZSock::new_req("inproc://zap_handler_test_malformed").unwrap();
+        zap.set_sndtimeo(Some(500));
+        zap.set_rcvtimeo(Some(500));
+
+        let bad_version = ZMsg::new();
+        for frame in &["2.0", "1", "test-domain", "127.0.0.1", "", "CURVE"] {
+            bad_version.addstr(frame).unwrap();
+        }
+        bad_version.addbytes(cert.public_key()).unwrap();
+        let too_short = ZMsg::new();
+        too_short.addstr("1.0").unwrap();
+        too_short.addstr("2").unwrap();
+        let frame = ZFrame::new(&[0xff, 0xfe]).unwrap();
+        let bad_address = ZMsg::new();
+        for frame in &["1.0", "3", "test-domain"] {
+            bad_address.addstr(frame).unwrap();
+        }
+        bad_address.append(frame).unwrap();
+        for frame in &["", "NULL"] {
+            bad_address.addstr(frame).unwrap();
+        }
+
+        for (msg, sequence) in vec![(bad_version, "1"), (too_short, "2"), (bad_address, "3")] {
+            msg.send(&mut zap).unwrap();
+            let reply = ZMsg::recv(&mut zap).unwrap();
+            assert_eq!(reply.popstr().unwrap().unwrap(), "1.0");
+            assert_eq!(reply.popstr().unwrap().unwrap(), sequence);
+            assert_eq!(reply.popstr().unwrap().unwrap(), "500");
+        }
+        assert_eq!(handler.status(), WorkerStatus { alive: true, restarts: 0, last_error: None });
+
+        new_zap_msg(&cert).send(&mut zap).unwrap();
+        let reply = ZMsg::recv(&mut zap).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "400");
+    }
+
+    #[test]
+    fn test_restart() {
+        ZSys::init();
+
+        let cert = Cert::new("jimbob", CertType::User).unwrap();
+        let zap_server = ZSock::new_rep("inproc://zap_handler_test_restart").unwrap();
+        let mut publisher = ZSock::new_pub("inproc://zap_handler_test_restart_pub").unwrap();
+        publisher.set_sndtimeo(Some(500));
+        let subscriber = ZSock::new(SocketType::SUB);
+        subscriber.set_subscribe(CertType::User.to_str());
+        subscriber.connect("inproc://zap_handler_test_restart_pub").unwrap();
+        let handler = ZapHandler::run_worker(zap_server, subscriber, CertCache::new(None), any_domain(), BanPolicy::default()).unwrap();
+        assert_eq!(handler.status(), WorkerStatus { alive: true, restarts: 0, last_error: None });
+
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let errors_cb = errors.clone();
+        handler.on_error(move |e| errors_cb.lock().unwrap().push(e.to_string()));
+
+        // A feed message without an action fails the worker
+        let feed_msg = ZMsg::new();
+        feed_msg.addstr("user").unwrap();
+        feed_msg.send(&mut publisher).unwrap();
+
+        sleep(Duration::from_millis(50));
+        let status = handler.status();
+        assert!(!status.alive);
+        assert_eq!(status.restarts, 0);
+        assert_eq!(status.last_error, Some(Error::InvalidCertFeed.to_string()));
+        assert_eq!(*errors.lock().unwrap(), vec![Error::InvalidCertFeed.to_string()]);
+
+        sleep(Duration::from_millis(200));
+        let status = handler.status();
+        assert!(status.alive);
+        assert_eq!(status.restarts, 1);
+
+        let mut zap = ZSock::new_req("inproc://zap_handler_test_restart").unwrap();
+        zap.set_sndtimeo(Some(500));
+        zap.set_rcvtimeo(Some(500));
+        new_zap_msg(&cert).send(&mut zap).unwrap();
+        let reply = ZMsg::recv(&mut zap).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "400");
+    }
+
+    #[test]
+    fn test_pool() {
+        ZSys::init();
+
+        let known = Cert::new("jimbob", CertType::User).unwrap();
+        let unknown = Cert::new("mallory", CertType::User).unwrap();
+        let known_msgs: Vec<ZMsg> = (0..4).map(|_| new_zap_msg(&known)).collect();
+
+        let zap_server = ZSock::new_router("inproc://zap_handler_test_pool").unwrap();
+        let handler = ZapHandler::run_worker_with_feed(zap_server, ZSock::new(SocketType::SUB), None, CertCache::new(Some(vec![known])), None, any_domain(), BanPolicy::default(), Duration::from_secs(5), 3).unwrap();
+
+        // Send everything before reading replies, so the requests are
+        // spread over the pool
+        let mut clients = Vec::new();
+        for msg in known_msgs {
+            let mut zap = ZSock::new_req("inproc://zap_handler_test_pool").unwrap();
+            zap.set_sndtimeo(Some(500));
+            zap.set_rcvtimeo(Some(500));
+            msg.send(&mut zap).unwrap();
+            clients.push(zap);
+        }
+        for zap in &mut clients {
+            let reply = ZMsg::recv(zap).unwrap();
+            reply.popstr().unwrap().unwrap();
+            reply.popstr().unwrap().unwrap();
+            assert_eq!(reply.popstr().unwrap().unwrap(), "200");
+            assert_eq!(reply.popstr().unwrap().unwrap(), "OK");
+            assert_eq!(reply.popstr().unwrap().unwrap(), "jimbob");
+        }
+
+        // Whichever workers answer, the negative cache is shared
+        for _ in 0..2 {
+            let mut zap = ZSock::new_req("inproc://zap_handler_test_pool").unwrap();
+            zap.set_sndtimeo(Some(500));
+            zap.set_rcvtimeo(Some(500));
+            new_zap_msg(&unknown).send(&mut zap).unwrap();
+            let reply = ZMsg::recv(&mut zap).unwrap();
+            reply.popstr().unwrap().unwrap();
+            reply.popstr().unwrap().unwrap();
+            assert_eq!(reply.popstr().unwrap().unwrap(), "400");
+        }
+        let stats = handler.negative_cache_stats();
+        assert_eq!((stats.hits, stats.inserts), (1, 1));
+        assert!(handler.status().alive);
+    }
+
+    struct StaticVerifier;
+
+    impl PlainVerifier for StaticVerifier {
+        fn verify(&self, username: &str, password: &str) -> bool {
+            username == "deploy" && password == "secret"
+        }
+    }
+
+    #[test]
+    fn test_plain() {
+        ZSys::init();
+
+        let mut zap = ZSock::new_req("inproc://zap_handler_test_plain").unwrap();
+        zap.set_sndtimeo(Some(500));
+        zap.set_rcvtimeo(Some(500));
+
+        let zap_server = ZSock::new_rep("inproc://zap_handler_test_plain").unwrap();
+        let subscriber = ZSock::new(SocketType::SUB);
+
+        let handler = ZapHandler::run_worker(zap_server, subscriber, CertCache::new(None), any_domain(), BanPolicy::default()).unwrap();
+
+        // Denied until a verifier is set
+        new_plain_msg("deploy", "secret").send(&mut zap).unwrap();
+        let reply = ZMsg::recv(&mut zap).unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "1.0");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "1");
+        assert_eq!(reply.popstr().unwrap().unwrap(), "400");
+
+        handler.set_plain_verifier(StaticVerifier);
+
+        new_plain_msg("deploy", "nope").send(&mut zap).unwrap();
+        let reply = ZMsg::recv(&mut zap).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "400");
+
+        new_plain_msg("deploy", "secret").send(&mut zap).unwrap();
+        let reply = ZMsg::recv(&mut zap).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "200");
+        reply.popstr().unwrap().unwrap();
+        // Namespaced, so it can't pass for a cert called "deploy"
+        assert_eq!(reply.popstr().unwrap().unwrap(), "plain:deploy");
+        let meta = ZCert::new().unwrap();
+        meta.decode_meta(&reply.popbytes().unwrap().unwrap()).unwrap();
+        assert_eq!(meta.meta("name").unwrap().unwrap(), "plain:deploy");
+        assert_eq!(meta.meta("type").unwrap().unwrap(), "user");
+        assert_eq!(meta.meta("mechanism").unwrap().unwrap(), "PLAIN");
+
+        handler.clear_plain_verifier();
+        new_plain_msg("deploy", "secret").send(&mut zap).unwrap();
+        let reply = ZMsg::recv(&mut zap).unwrap();
+        reply.popstr().unwrap().unwrap();
+        reply.popstr().unwrap().unwrap();
+        assert_eq!(reply.popstr().unwrap().unwrap(), "400");
+    }
+
+    #[test]
+    fn test_domains() {
+        ZSys::init();
+
+        let mut zap = ZSock::new_req("inproc://zap_handler_test_domains").unwrap();
+        zap.set_sndtimeo(Some(500));
+        zap.set_rcvtimeo(Some(500));
+
+        let zap_server = ZSock::new_rep("inproc://zap_handler_test_domains").unwrap();
+        let subscriber = ZSock::new(SocketType::SUB);
+
+        let host = Cert::new("web1", CertType::Host).unwrap();
+        let mut domains = HashMap::new();
+        domains.insert("admin".to_string(), DomainPolicy {
+            cert_types: vec![CertType::User],
+            ..DomainPolicy::default()
+        });
+        domains.insert("metrics".to_string(), DomainPolicy::default());
+        let router = DomainRouter::new(domains, "");
+
+        let seed = Cert::from_zcert(host.dup()).unwrap();
+        let _handler = ZapHandler::run_worker(zap_server, subscriber, CertCache::new(Some(vec![seed])), router, BanPolicy::default()).unwrap();
+
+        for &(domain, status) in &[("admin", "400"), ("metrics", "200"), ("other", "400")] {
+            new_domain_zap_msg(domain, &host).send(&mut zap).unwrap();
+            let reply = ZMsg::recv(&mut zap).unwrap();
+            reply.popstr().unwrap().unwrap();
+            reply.popstr().unwrap().unwrap();
+            assert_eq!(reply.popstr().unwrap().unwrap(), status);
+        }
+    }
+
+    struct DomainGate;
+
+    impl AuthPolicy for DomainGate {
+        fn authorize(&self, req: &ZapRequestInfo, cert: Option<&Cert>) -> Decision {
+            match (req.domain, cert) {
+                ("closed", _) => Decision::Deny,
+                ("public", None) if req.mechanism == "NULL" => Decision::Allow,
+                (_, Some(_)) => Decision::Allow,
+                _ => Decision::Deny,
+            }
+        }
+    }
+
+    #[test]
+    fn test_auth_policy() {
+        ZSys::init();
+
+        let mut zap = ZSock::new_req("inproc://zap_handler_test_auth_policy").unwrap();
+        zap.set_sndtimeo(Some(500));
+        zap.set_rcvtimeo(Some(500));
+
+        let zap_server = ZSock::new_rep("inproc://zap_handler_test_auth_policy").unwrap();
+        let subscriber = ZSock::new(SocketType::SUB);
+
+        let host = Cert::new("web1", CertType::Host).unwrap();
+        let seed = Cert::from_zcert(host.dup()).unwrap();
+        let handler = ZapHandler::run_worker(zap_server, subscriber, CertCache::new(Some(vec![seed])), any_domain(), BanPolicy::default()).unwrap();
+        handler.set_auth_policy(DomainGate);
+
+        let null_msg = |domain: &str| {
+            let msg = ZMsg::new();
+            for frame in &["1.0", "1", domain, "127.0.0.1", "", "NULL"] {
+                msg.addstr(frame).unwrap();
+            }
+            msg
+        };
+        let requests = vec![
+            (new_domain_zap_msg("open", &host), "200"),
+            (new_domain_zap_msg("closed", &host), "400"),
+            (null_msg("public"), "200"),
+            (null_msg("open"), "400"),
+        ];
+        for (msg, status) in requests {
+            msg.send(&mut zap).unwrap();
+            let reply = ZMsg::recv(&mut zap).unwrap();
+            reply.popstr().unwrap().unwrap();
+            reply.popstr().unwrap().unwrap();
+            assert_eq!(reply.popstr().unwrap().unwrap(), status);
+        }
+    }
+
+    #[test]
+    fn test_wait_ready() {
+        ZSys::init();
+
+        let zap_server = ZSock::new_rep("inproc://zap_handler_test_wait_ready_zap").unwrap();
+
+        let mut publisher = ZSock::new_pub("inproc://zap_handler_test_wait_ready_pub").unwrap();
+        publisher.set_sndtimeo(Some(500));
+
+        let subscriber = ZSock::new(SocketType::SUB);
+        subscriber.set_subscribe(CertType::Host.to_str());
+        subscriber.connect("inproc://zap_handler_test_wait_ready_pub").unwrap();
+
+        let mut cache = CertCache::new(None);
+        cache.resync(&cert_cache::snapshot_topics(CertType::Host.to_str()));
+        let handler = ZapHandler::run_worker(zap_server, subscriber, cache, any_domain(), BanPolicy::default()).unwrap();
+        assert!(!handler.is_ready());
+        assert!(handler.wait_ready(Duration::from_millis(50)).is_err());
+
+        // An empty snapshot counts
+        cert_cache::snapshot_notice("host").unwrap().send(&mut publisher).unwrap();
+        let snapshot = ZMsg::new();
+        snapshot.addstr("host").unwrap();
+        snapshot.addstr("ADD").unwrap();
+        snapshot.send(&mut publisher).unwrap();
+
+        assert!(handler.wait_ready(Duration::from_secs(1)).is_ok());
+        assert!(handler.is_ready());
+    }
+
+    #[test]
+    fn test_feed_poll_timeout() {
+        let now = Instant::now();
+        let mut feed = Feed {
+            monitor: ZSock::new(SocketType::PAIR),
+            snapshot_topics: Vec::new(),
+            endpoints: vec!["tcp://auth1:7102".into(), "tcp://auth2:7102".into()],
+            drain_holdoff: Duration::from_secs(60),
+            held: Vec::new(),
+            subscription: String::new(),
+            sweep_interval: None,
+            next_sweep: None,
+            affinity: AffinityPolicy::default(),
+            adverts: HashMap::new(),
+            aliases: HashMap::new(),
+            connected: HashSet::new(),
+            standby: Vec::new(),
+            resync: None,
+            cert_type: None,
+        };
+        assert_eq!(feed.poll_timeout(now), None);
+
+        feed.held.push(("tcp://auth1:7102".into(), now + Duration::from_secs(60)));
+        feed.held.push(("tcp://auth2:7102".into(), now + Duration::from_millis(1500)));
+        assert_eq!(feed.poll_timeout(now), Some(1500));
+        assert_eq!(feed.poll_timeout(now + Duration::from_secs(2)), Some(0));
+
+        feed.next_sweep = Some(now + Duration::from_millis(500));
+        assert_eq!(feed.poll_timeout(now), Some(500));
+    }
+
+    #[test]
+    fn test_feed_rebalance() {
+        let endpoints: Vec<String> = vec!["tcp://eu1:7102".into(), "tcp://eu2:7102".into(), "tcp://us1:7102".into()];
+        let mut feed = Feed {
+            monitor: ZSock::new(SocketType::PAIR),
+            snapshot_topics: Vec::new(),
+            endpoints: endpoints.clone(),
+            drain_holdoff: Duration::from_secs(60),
+            held: Vec::new(),
+            subscription: String::new(),
+            sweep_interval: None,
+            next_sweep: None,
+            affinity: AffinityPolicy { tag: Some("eu".into()), lowest_latency: false },
+            adverts: HashMap::new(),
+            aliases: HashMap::new(),
+            connected: endpoints.iter().cloned().collect(),
+            standby: Vec::new(),
+            resync: None,
+            cert_type: None,
+        };
+        let none: (Vec<String>, Vec<String>) = (Vec::new(), Vec::new());
+
+        // Nothing changes until every connected server has advertised
+        for (endpoint, tag) in vec![("tcp://eu1:7102", "eu"), ("tcp://us1:7102", "us")] {
+            feed.adverts.insert(endpoint.to_string(), ServerAdvert { endpoint: endpoint.into(), tags: vec![tag.into()], latency_micros: None });
+        }
+        assert_eq!(feed.rebalance(), none);
+        feed.adverts.insert("tcp://eu2:7102".into(), ServerAdvert { endpoint: "tcp://eu2:7102".into(), tags: vec!["eu".into()], latency_micros: None });
+        assert_eq!(feed.rebalance(), (Vec::new(), vec!["tcp://us1:7102".into()]));
+        assert_eq!(feed.rebalance(), none);
+
+        // Fail over once neither preferred server is connected, then
+        // stand by again when one is back
+        feed.connected.remove("tcp://eu1:7102");
+        assert_eq!(feed.rebalance(), none);
+        feed.connected.remove("tcp://eu2:7102");
+        assert_eq!(feed.rebalance(), (vec!["tcp://us1:7102".into()], Vec::new()));
+        feed.connected.insert("tcp://us1:7102".into());
+        assert_eq!(feed.rebalance(), none);
+        feed.connected.insert("tcp://eu2:7102".into());
+        assert_eq!(feed.rebalance(), (Vec::new(), vec!["tcp://us1:7102".into()]));
+    }
+
+    #[test]
+    fn test_new_with_servers() {
+        let cert = ZCert::new().unwrap();
+        assert!(ZapHandler::new_with_servers(None, &cert, &cert, &[], false).is_err());
+        assert!(ZapHandler::builder(&cert, &cert).reconnect(ReconnectPolicy::fleet()).build().is_err());
+    }
+
+    #[test]
+    fn test_zap_properties() {
+        let cert = ZCert::new().unwrap();
+        cert.set_meta("name", "web1");
+        cert.set_meta("type", "host");
+        cert.set_meta("group", "web");
+        cert.set_meta("User-Id", "root");
+        cert.set_meta("bad name", "x");
+
+        let props = ZCert::new().unwrap();
+        props.decode_meta(&zap_properties(&cert, None)).unwrap();
+        assert_eq!(props.meta("type").unwrap().unwrap(), "host");
+        assert_eq!(props.meta("groups").unwrap().unwrap(), "web");
+        assert!(props.meta("User-Id").is_none());
+        assert!(props.meta("bad name").is_none());
+
+        let props = ZCert::new().unwrap();
+        props.decode_meta(&zap_properties(&cert, Some(&["groups".to_string()]))).unwrap();
+        assert_eq!(props.meta("groups").unwrap().unwrap(), "web");
+        assert!(props.meta("type").is_none());
+        assert!(props.meta("name").is_none());
+    }
+
+    #[test]
+    fn test_validate_status_text() {
+        assert!(validate_status_text("Access denied. Unauthorised use is prohibited.").is_ok());
+        assert!(validate_status_text("").is_err());
+        assert!(validate_status_text(&"x".repeat(255)).is_ok());
+        assert!(validate_status_text(&"x".repeat(256)).is_err());
+    }
+
+    fn new_plain_msg(username: &str, password: &str) -> ZMsg {
+        let zap_msg = ZMsg::new();
+        zap_msg.addstr("1.0").unwrap();
+        zap_msg.addstr("1").unwrap();
+        zap_msg.addstr("test-domain").unwrap();
+        zap_msg.addstr("127.0.0.1").unwrap();
+        zap_msg.addstr("").unwrap();
+        zap_msg.addstr("PLAIN").unwrap();
+        zap_msg.addstr(username).unwrap();
+        zap_msg.addstr(password).unwrap();
+        zap_msg
+    }
+
+    fn any_domain() -> DomainRouter {
+        DomainRouter::any_domain(DomainPolicy::default(), "")
+    }
+
+    fn new_zap_msg(cert: &ZCert) -> ZMsg {
+        new_domain_zap_msg("test-domain", cert)
+    }
+
+    fn new_domain_zap_msg(domain: &str, cert: &ZCert) -> ZMsg {
+        let zap_msg = ZMsg::new();
+        zap_msg.addstr("1.0").unwrap();
+        zap_msg.addstr("1").unwrap();
+        zap_msg.addstr(domain).unwrap();
         zap_msg.addstr("127.0.0.1").unwrap();
         zap_msg.addstr("").unwrap();
         zap_msg.addstr("CURVE").unwrap();
@@ -6,25 +6,116 @@
 // https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
 // modified, or distributed except according to those terms.
 
+use attestation::{Attestation, ATTESTATION_TOPIC};
 use cert::CertType;
-use cert_cache::CertCache;
+use cert_cache::{self, DIRECT_TOPIC_PREFIX, KEYS_ONLY_TOPIC_PREFIX, SharedCertCache, SnapshotFormat};
+use compression;
 use czmq::{ZCert, ZFrame, ZMsg, ZSock, SocketType, ZSys};
 use error::Result;
+use feed_signature;
+use inauth_client::LogSampler;
+use last_value::{self, LastValueCache};
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
+use sodiumoxide::crypto::sign::SecretKey;
 use std::result::Result as StdResult;
 use std::str;
-use zdaemon::{Endpoint, Error as DError, ZMsgExtended};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use timeouts::{self, SocketTimeouts};
+use wire_trace::{Direction, WireTracer};
+use zdaemon::{Endpoint, Error as DError};
 
-pub fn init(cert: &ZCert, update_port: u32, cert_cache: Rc<RefCell<CertCache>>) -> Result<(ZapPublisher, ZapSubscriber)> {
+// Maximum time (ms) to wait for queued feed messages to reach
+// subscribers during shutdown.
+const DRAIN_TIMEOUT: i32 = 1000;
+
+// Where the Attestor publishes into the feed, alongside the cert API
+const ATTESTOR_ENDPOINT: &'static str = "inproc://auth_attestor";
+
+/// The feed for subscribers in this process, such as the WebSocket
+/// feed. Inproc connections skip CURVE and ZAP.
+pub const FEED_ENDPOINT: &'static str = "inproc://auth_feed";
+
+/// Where bridges from other Auth servers publish into the feed. See
+/// `ZapBridge`.
+pub const BRIDGE_ENDPOINT: &'static str = "inproc://auth_bridge";
+
+// Subscriptions per second that get logged as a reconnect storm
+const STORM_THRESHOLD: u32 = 1000;
+
+// Where `request_drain()` tells the ZapPublisher to start draining
+const CONTROL_ENDPOINT: &'static str = "inproc://auth_feed_control";
+
+/// Certs per ADD in batched snapshots, unless set otherwise.
+pub const DEFAULT_SNAPSHOT_BATCH_SIZE: usize = 500;
+
+// Snapshot formats that need copies of each change
+const BATCHED_FORMATS: [SnapshotFormat; 3] = [SnapshotFormat::Batched, SnapshotFormat::Compressed, SnapshotFormat::Cbor];
+
+/// Feed activity, shared with the admin socket.
+#[derive(Debug, Default)]
+pub struct FeedStats {
+    /// Subscriptions per topic ("" is all topics). XPUB only reports
+    /// an unsubscribe once a topic has no subscribers left, and peers
+    /// resubscribe on reconnect, so counts are an upper bound.
+    pub subscribers: HashMap<String, u32>,
+    /// Number of messages published on the feed
+    pub sequence: u64,
+    /// Subscriptions received since startup
+    pub subscribes: u64,
+    /// The most subscriptions received in one second. Clients
+    /// resubscribe when they reconnect, so a spike means a reconnect
+    /// storm.
+    pub peak_subscribe_rate: u32,
+    /// Whether subscribers are being told to move to other servers
+    pub draining: bool,
+    // Start of the current second and subscriptions received in it
+    window: Option<(Instant, u32)>,
+}
+
+impl FeedStats {
+    fn record_subscribe(&mut self, now: Instant) {
+        self.subscribes += 1;
+
+        let count = match self.window {
+            Some((start, count)) if now.duration_since(start) < Duration::from_secs(1) => {
+                self.window = Some((start, count + 1));
+                count + 1
+            },
+            _ => {
+                self.window = Some((now, 1));
+                1
+            },
+        };
+
+        if count == STORM_THRESHOLD {
+            warn!("Reconnect storm: {} subscriptions in the last second", count);
+        }
+        if count > self.peak_subscribe_rate {
+            self.peak_subscribe_rate = count;
+        }
+    }
+}
+
+/// `endpoint` is where clients subscribe to this server, as they
+/// connect to it, e.g. "tcp://auth1.example.com:7102". It is advertised
+/// in heartbeats along with the affinity `tags`, so that clients of
+/// several servers know which one is draining and which are nearby.
+/// Subscription requests are logged as sampled by `log_sampler`.
+pub fn init(cert: &ZCert, update_port: u32, endpoint: Option<String>, tags: Vec<String>, cert_cache: SharedCertCache, tracer: WireTracer, log_sampler: LogSampler) -> Result<(ZapPublisher, ZapSubscriber)> {
     let mut xpub = ZSock::new(SocketType::XPUB);
     xpub.set_xpub_verbose(true);
     xpub.set_zap_domain("auth.intecture");
     xpub.set_curve_server(true);
     cert.apply(&mut xpub);
     try!(xpub.bind(&format!("tcp://*:{}", update_port)));
+    try!(xpub.bind(FEED_ENDPOINT));
 
     let xsub = try!(ZSock::new_xsub("inproc://auth_publisher"));
+    try!(xsub.connect(ATTESTOR_ENDPOINT));
+    try!(xsub.bind(BRIDGE_ENDPOINT));
 
     let (s_pipe, p_pipe) = try!(ZSys::create_pipe());
 
@@ -32,25 +123,317 @@ pub fn init(cert: &ZCert, update_port: u32, cert_cache: Rc<RefCell<CertCache>>)
         ZapPublisher {
             publisher: xpub,
             subscriber: s_pipe,
+            control: try!(ZSock::new_pull(&format!("@{}", CONTROL_ENDPOINT))),
             cache: cert_cache.clone(),
+            tracer: tracer.clone(),
+            sequence: 0,
+            stats: Rc::new(RefCell::new(FeedStats::default())),
+            endpoint: endpoint,
+            tags: tags,
+            log_sampler: log_sampler,
+            heartbeats: None,
+            batch_size: DEFAULT_SNAPSHOT_BATCH_SIZE,
+            last_values: LastValueCache::new(last_value::DEFAULT_CAPACITY),
+            signing_key: None,
         },
         ZapSubscriber {
             subscriber: xsub,
             publisher: p_pipe,
             cache: cert_cache,
+            tracer: tracer,
+            allowed_origins: None,
         }
     ))
 }
 
+/// Tell the running ZapPublisher to start draining: every subscriber,
+/// and every new one, gets a heartbeat asking it to prefer other
+/// servers. Updates are published as usual until shutdown.
+pub fn request_drain() -> Result<()> {
+    let control = try!(ZSock::new_push(&format!(">{}", CONTROL_ENDPOINT)));
+    control.set_sndtimeo(Some(1000));
+    try!(control.send_str("DRAIN"));
+    Ok(())
+}
+
+/// Publishes cert changes, snapshots, heartbeats and attestations.
+///
+/// ZMQ matches subscriptions by prefix, so subscribers to every topic
+/// ("") also receive keys-only copies ("keys:<type>"), HEARTBEATs and
+/// ATTESTs. `CertCache` ignores what it didn't subscribe to, but
+/// clients older than each of those fail on them with
+/// `InvalidCertFeed`, so clients must be upgraded before the server.
+/// The same goes for the SNAPSHOT notice sent before each snapshot.
 pub struct ZapPublisher {
     publisher: ZSock,
     subscriber: ZSock,
-    cache: Rc<RefCell<CertCache>>,
+    control: ZSock,
+    cache: SharedCertCache,
+    tracer: WireTracer,
+    sequence: u64,
+    stats: Rc<RefCell<FeedStats>>,
+    endpoint: Option<String>,
+    tags: Vec<String>,
+    log_sampler: LogSampler,
+    heartbeats: Option<ZSock>,
+    batch_size: usize,
+    last_values: LastValueCache,
+    signing_key: Option<SecretKey>,
+}
+
+impl ZapPublisher {
+    pub fn stats(&self) -> Rc<RefCell<FeedStats>> {
+        self.stats.clone()
+    }
+
+    /// Send a heartbeat on every topic each `interval`, so subscribers
+    /// can tell a quiet feed from a dead or partitioned server. Call
+    /// this before adding the publisher to a service.
+    pub fn set_heartbeat_interval(&mut self, interval: Duration) -> Result<()> {
+        self.heartbeats = Some(try!(ticker(interval)));
+        Ok(())
+    }
+
+    /// Certs per ADD for subscribers that asked for batched snapshots.
+    pub fn set_snapshot_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size;
+    }
+
+    /// Cert changes remembered for subscribers that resume from a
+    /// sequence. See `LastValueCache`.
+    pub fn set_last_value_capacity(&mut self, capacity: usize) {
+        self.last_values.set_capacity(capacity);
+    }
+
+    /// Sign every timestamped message with `key`, so subscribers can
+    /// reject forged and replayed ones. See `feed_signature`.
+    pub fn set_signing_key(&mut self, key: SecretKey) {
+        self.signing_key = Some(key);
+    }
+
+    /// Bound how long the publisher may block sending to, or waiting
+    /// on the rest of a request from, a subscriber.
+    pub fn set_socket_timeouts(&self, timeouts: SocketTimeouts) {
+        timeouts.apply(&self.publisher);
+    }
+
+    // Whether anyone is subscribed to topics of `format`
+    // Scoped subscribers get their own copies, so don't count here
+    fn has_subscribers(&self, format: SnapshotFormat) -> bool {
+        self.stats.borrow().subscribers.keys().any(|t| t.starts_with(format.topic_prefix()) && !is_scoped(t))
+    }
+
+    // Whether anyone is subscribed to keys-only topics
+    fn has_keys_only_subscribers(&self) -> bool {
+        self.stats.borrow().subscribers.keys().any(|t| t.starts_with(KEYS_ONLY_TOPIC_PREFIX))
+    }
+
+    fn scoped_topics(&self) -> Vec<String> {
+        self.stats.borrow().subscribers.keys().filter(|t| is_scoped(t)).cloned().collect()
+    }
+
+    fn publish(&mut self, msg: ZMsg) -> Result<()> {
+        // Subscribers to every topic would get keys-only copies too, so
+        // only send them to someone who asked
+        let keys = if self.has_keys_only_subscribers() {
+            try!(cert_cache::keys_only(&msg))
+        } else {
+            None
+        };
+        let mut copies = Vec::new();
+        for format in &BATCHED_FORMATS {
+            if self.has_subscribers(*format) {
+                if let Some(copy) = try!(cert_cache::batched_copy(&msg, *format)) {
+                    copies.push(copy);
+                }
+            }
+        }
+        for topic in self.scoped_topics() {
+            copies.extend(try!(cert_cache::scoped_copies(&msg, &topic)));
+        }
+        self.last_values.record(&msg, self.sequence + 1);
+        try!(stamp(&msg, self.signing_key.as_ref()));
+        self.tracer.record(Direction::Out, "update", &msg, &[]);
+        try!(msg.send(&mut self.publisher));
+
+        // Keys-only subscribers get the same change without metadata,
+        // and batched and scoped subscribers on their own topics. None
+        // count towards the sequence.
+        for copy in keys.into_iter().chain(copies) {
+            try!(stamp(&copy, self.signing_key.as_ref()));
+            self.tracer.record(Direction::Out, "update", &copy, &[]);
+            try!(copy.send(&mut self.publisher));
+        }
+        self.sequence += 1;
+        self.stats.borrow_mut().sequence = self.sequence;
+        Ok(())
+    }
+
+    // Batches are sent as they are built, rather than all at once
+    fn send_batches(&mut self, cert_type: Option<CertType>, scope: Option<&str>, format: SnapshotFormat) -> Result<()> {
+        let cache = self.cache.clone();
+        let publisher = &mut self.publisher;
+        let tracer = &self.tracer;
+        let key = self.signing_key.as_ref();
+        let sent = try!(cache.read().snapshot_batches(cert_type, scope, format, self.batch_size, |msg| {
+            try!(stamp(&msg, key));
+            tracer.record(Direction::Out, "update", &msg, &[]);
+            try!(msg.send(publisher));
+            Ok(())
+        }));
+        debug!("Sent batched snapshot of {} certificates", sent);
+        Ok(())
+    }
+
+    // Send a subscriber resuming from `position` the certs on `topic`
+    // changed since, then a heartbeat with the sequence they bring it
+    // up to. Returns false if the changes aren't all remembered.
+    fn send_changes(&mut self, topic: &str, cert_type: Option<CertType>, position: (u64, u64)) -> Result<bool> {
+        let cert_types = match cert_type {
+            Some(t) => vec![t],
+            None => vec![CertType::Host, CertType::User],
+        };
+        let mut changes = Vec::new();
+        for cert_type in cert_types {
+            match self.last_values.changed_since(cert_type, position.0, position.1) {
+                Some(keys) => changes.extend(try!(self.cache.read().changes(cert_type, &keys))),
+                None => return Ok(false),
+            }
+        }
+
+        debug!("Resuming subscription to \"{}\" from sequence {} with {} message(s)", topic, position.1, changes.len());
+        for msg in changes {
+            try!(stamp(&msg, self.signing_key.as_ref()));
+            self.tracer.record(Direction::Out, "update", &msg, &[]);
+            try!(msg.send(&mut self.publisher));
+        }
+        try!(self.heartbeat(topic));
+        Ok(true)
+    }
+
+    // Subscribers wait for a snapshot before reporting ready, so a
+    // topic without certs gets an ADD without any. It's announced by
+    // a SNAPSHOT notice, so resyncing subscribers can tell it from a
+    // live ADD. Nothing is published between the two.
+    fn send_snapshot(&mut self, topic: &str, snapshot: Option<ZMsg>) -> Result<()> {
+        let notice = try!(cert_cache::snapshot_notice(topic));
+        try!(stamp(&notice, self.signing_key.as_ref()));
+        self.tracer.record(Direction::Out, "update", &notice, &[]);
+        try!(notice.send(&mut self.publisher));
+
+        let snapshot = match snapshot {
+            Some(s) => s,
+            None => {
+                let msg = ZMsg::new();
+                try!(msg.addstr(topic));
+                try!(msg.addstr("ADD"));
+                msg
+            },
+        };
+        try!(stamp(&snapshot, self.signing_key.as_ref()));
+        self.tracer.record(Direction::Out, "update", &snapshot, &[]);
+        try!(snapshot.send(&mut self.publisher));
+        Ok(())
+    }
+
+    // The sequence, followed by "key=value" metadata frames:
+    // "state=draining" while draining, our endpoint, our affinity tags,
+    // comma separated, the number of certs on the topic and the epoch
+    // the sequence counts from.
+    fn heartbeat(&mut self, topic: &str) -> Result<()> {
+        let certs: usize = {
+            let cache = self.cache.read();
+            let (_, base_topic) = SnapshotFormat::from_topic(topic);
+            let (type_topic, scope) = cert_cache::split_scope(base_topic.trim_left_matches(KEYS_ONLY_TOPIC_PREFIX));
+            let cert_types = match CertType::from_str(type_topic) {
+                Ok(cert_type) => vec![cert_type],
+                Err(_) => vec![CertType::Host, CertType::User],
+            };
+            cert_types.into_iter()
+                .map(|t| cache.dump(t).into_iter().filter(|c| scope.map(|s| cert_cache::in_scope(c, s)).unwrap_or(true)).count())
+                .sum()
+        };
+
+        let msg = ZMsg::new();
+        try!(msg.addstr(topic));
+        try!(msg.addstr("HEARTBEAT"));
+        try!(msg.addstr(&self.sequence.to_string()));
+        if self.stats.borrow().draining {
+            try!(msg.addstr("state=draining"));
+        }
+        if let Some(ref endpoint) = self.endpoint {
+            try!(msg.addstr(&format!("endpoint={}", endpoint)));
+        }
+        if !self.tags.is_empty() {
+            try!(msg.addstr(&format!("tags={}", self.tags.join(","))));
+        }
+        try!(msg.addstr(&format!("certs={}", certs)));
+        try!(msg.addstr(&format!("epoch={}", self.last_values.epoch())));
+        try!(stamp(&msg, self.signing_key.as_ref()));
+        self.tracer.record(Direction::Out, "update", &msg, &[]);
+        try!(msg.send(&mut self.publisher));
+        Ok(())
+    }
+
+    fn heartbeat_all(&mut self) -> Result<()> {
+        for cert_type in &[CertType::Host, CertType::User] {
+            try!(self.heartbeat(cert_type.to_str()));
+            try!(self.heartbeat(&cert_cache::keys_only_topic(*cert_type)));
+            for format in &BATCHED_FORMATS {
+                if self.has_subscribers(*format) {
+                    try!(self.heartbeat(&format!("{}{}", format.topic_prefix(), cert_type.to_str())));
+                }
+            }
+        }
+        for topic in self.scoped_topics() {
+            try!(self.heartbeat(&topic));
+        }
+        Ok(())
+    }
+
+    fn start_draining(&mut self) -> Result<()> {
+        if !self.stats.borrow().draining {
+            info!("Draining: asking subscribers to move to other servers");
+            self.stats.borrow_mut().draining = true;
+            try!(self.heartbeat_all());
+        }
+        Ok(())
+    }
+
+    // Forward any feed messages still waiting on the pipe, then tell
+    // subscribers the last sequence so they can detect anything they
+    // missed once we come back up. We're going away, so this counts as
+    // draining too.
+    fn drain(&mut self) -> Result<()> {
+        self.subscriber.set_rcvtimeo(Some(0));
+        while let Ok(msg) = ZMsg::recv(&mut self.subscriber) {
+            try!(self.publish(msg));
+        }
+
+        self.stats.borrow_mut().draining = true;
+        try!(self.heartbeat_all());
+
+        // Let the XPUB flush its queue before the socket is closed
+        self.publisher.set_linger(DRAIN_TIMEOUT);
+        Ok(())
+    }
+}
+
+impl Drop for ZapPublisher {
+    fn drop(&mut self) {
+        if let Err(e) = self.drain() {
+            error!("Could not drain update feed: {}", e);
+        }
+    }
 }
 
 impl Endpoint for ZapPublisher {
     fn get_sockets(&mut self) -> Vec<&mut ZSock> {
-        vec![&mut self.publisher, &mut self.subscriber]
+        let mut sockets = vec![&mut self.publisher, &mut self.subscriber, &mut self.control];
+        if let Some(ref mut heartbeats) = self.heartbeats {
+            sockets.push(heartbeats);
+        }
+        sockets
     }
 
     fn recv(&mut self, sock: &mut ZSock) -> StdResult<(), DError> {
@@ -62,31 +445,146 @@ impl Endpoint for ZapPublisher {
                 Err(b) => b,
             };
 
+            // Receive any unreceived frames, such as a resume position
+            let rest = try!(timeouts::recv_rest(&mut self.publisher, 0, None));
+            let mut resume = None;
+            let mut next = rest.first();
+            while let Some(f) = next {
+                if let Ok(Ok(s)) = f.data() {
+                    resume = resume.or(cert_cache::parse_resume(&s));
+                }
+                next = rest.next();
+            }
+
             if let Some((event, topic_bytes)) = bytes.split_first() {
-                // Only send cache on subscribe ("1"), not unsubscribe ("0")
-                if event == &1 {
-                    let cert_type = if topic_bytes.len() == 0 {
-                        debug!("Request to subscribe to all certificates");
+                {
+                    let topic = String::from_utf8_lossy(topic_bytes).into_owned();
+                    let mut stats = self.stats.borrow_mut();
+                    if event == &1 {
+                        *stats.subscribers.entry(topic).or_insert(0) += 1;
+                        stats.record_subscribe(Instant::now());
+                    } else {
+                        stats.subscribers.remove(&topic);
+                    }
+                }
+
+                // Only send cache on subscribe ("1"), not unsubscribe ("0").
+                // Direct topics only carry pushed certs, so they don't
+                // get a snapshot. Keys-only topics get one without
+                // metadata.
+                if event == &1 && topic_bytes.starts_with(KEYS_ONLY_TOPIC_PREFIX.as_bytes()) {
+                    let topic = try!(str::from_utf8(&topic_bytes[KEYS_ONLY_TOPIC_PREFIX.len()..]));
+                    if self.log_sampler.sample_accept() {
+                        debug!("Request to subscribe to {} keys", if topic.is_empty() { "all" } else { topic });
+                    }
+                    let cert_types = if topic.is_empty() {
+                        vec![CertType::Host, CertType::User]
+                    } else {
+                        vec![try!(CertType::from_str(topic))]
+                    };
+                    for cert_type in cert_types {
+                        let topic = cert_cache::keys_only_topic(cert_type);
+                        let snapshot = try!(self.cache.read().snapshot_keys(cert_type));
+                        try!(self.send_snapshot(&topic, snapshot));
+                        // New subscribers need to know we're draining
+                        // as much as existing ones.
+                        if self.stats.borrow().draining {
+                            try!(self.heartbeat(&topic));
+                        }
+                    }
+                }
+                // Attestation subscribers get the latest one straight
+                // away rather than waiting for the next.
+                else if event == &1 && topic_bytes == ATTESTATION_TOPIC.as_bytes() {
+                    if self.log_sampler.sample_accept() {
+                        debug!("Request to subscribe to attestations");
+                    }
+                    if let Some(attestation) = self.cache.read().attestation() {
+                        let msg = try!(attestation.to_msg());
+                        self.tracer.record(Direction::Out, "update", &msg, &[]);
+                        try!(msg.send(&mut self.publisher));
+                    }
+                }
+                // Batched subscribers name the format they can take in
+                // their topic's prefix.
+                else if event == &1 && BATCHED_FORMATS.iter().any(|f| topic_bytes.starts_with(f.topic_prefix().as_bytes())) {
+                    let topic = try!(str::from_utf8(topic_bytes));
+                    let (format, base_topic) = SnapshotFormat::from_topic(topic);
+                    if self.log_sampler.sample_accept() {
+                        debug!("Request to subscribe to {} certificates in {:?} snapshots", if base_topic.is_empty() { "all" } else { base_topic }, format);
+                    }
+                    let (type_topic, scope) = cert_cache::split_scope(base_topic);
+                    let cert_type = if type_topic.is_empty() {
                         None
                     } else {
-                        let topic = try!(str::from_utf8(&topic_bytes));
-                        debug!("Request to subscribe to {} certificates", topic);
-                        Some(try!(CertType::from_str(topic)))
+                        Some(try!(CertType::from_str(type_topic)))
                     };
-                    try!(self.cache.borrow().send(&mut self.publisher, cert_type));
+                    if format == SnapshotFormat::Compressed && !compression::is_supported() {
+                        warn!("Cannot send compressed snapshots without the \"zstd\" feature");
+                    } else {
+                        try!(self.send_batches(cert_type, scope, format));
+                        if self.stats.borrow().draining {
+                            try!(self.heartbeat(topic));
+                        }
+                    }
+                }
+                // Subscribers that say how far they got only need the
+                // certs changed since, unless they want a scope.
+                else if event == &1 && !topic_bytes.starts_with(DIRECT_TOPIC_PREFIX.as_bytes()) {
+                    let sampled = self.log_sampler.sample_accept();
+                    let topic = try!(str::from_utf8(&topic_bytes));
+                    let (type_topic, scope) = cert_cache::split_scope(topic);
+                    let cert_type = if topic.is_empty() {
+                        if sampled {
+                            debug!("Request to subscribe to all certificates");
+                        }
+                        None
+                    } else {
+                        if sampled {
+                            debug!("Request to subscribe to {} certificates", topic);
+                        }
+                        Some(try!(CertType::from_str(type_topic)))
+                    };
+                    let resumed = match resume {
+                        Some(position) if scope.is_none() => try!(self.send_changes(topic, cert_type, position)),
+                        _ => false,
+                    };
+                    if !resumed {
+                        // Unscoped snapshots are built once the cache
+                        // is released
+                        let snapshot = match scope {
+                            Some(_) => try!(self.cache.read().snapshot(cert_type, scope)),
+                            None => {
+                                let frames = self.cache.read().snapshot_frames(cert_type);
+                                try!(cert_cache::snapshot_msg(cert_type, &frames))
+                            },
+                        };
+                        try!(self.send_snapshot(topic, snapshot));
+                        if self.stats.borrow().draining {
+                            try!(self.heartbeat(topic));
+                        }
+                    }
                 }
             }
 
-            // Receive any unreceived frames
-            let msg = try!(ZMsg::expect_recv(&mut self.publisher, 0, None, false));
+            let msg = rest;
             try!(msg.prepend(frame));
+            self.tracer.record(Direction::In, "update", &msg, &[]);
 
             // Pass subscription frame to publishers
             try!(msg.send(&mut self.subscriber));
         }
         else if *sock == self.subscriber {
             let msg = try!(ZMsg::recv(sock));
-            try!(msg.send(&mut self.publisher));
+            try!(self.publish(msg));
+        }
+        else if *sock == self.control {
+            let _ = try!(sock.recv_str());
+            try!(self.start_draining());
+        }
+        else if self.heartbeats.as_ref().map(|h| sock == h).unwrap_or(false) {
+            let _ = try!(sock.recv_str());
+            try!(self.heartbeat_all());
         }
         else {
             unreachable!();
@@ -95,10 +593,87 @@ impl Endpoint for ZapPublisher {
     }
 }
 
+/// Which federated servers may publish into this server's feed,
+/// matched against the meta of each bridge's remote server cert.
+/// A cert matching either list is allowed.
+#[derive(Clone, Debug, Default)]
+pub struct PublisherAllowlist {
+    pub names: Vec<String>,
+    pub cert_types: Vec<CertType>,
+}
+
+impl PublisherAllowlist {
+    pub fn allows(&self, cert: &ZCert) -> bool {
+        let name_allowed = match cert.meta("name") {
+            Some(Ok(name)) => self.names.iter().any(|n| *n == name),
+            _ => false,
+        };
+        let type_allowed = match cert.meta("type") {
+            Some(Ok(t)) => match CertType::from_str(&t) {
+                Ok(t) => self.cert_types.contains(&t),
+                Err(_) => false,
+            },
+            _ => false,
+        };
+        name_allowed || type_allowed
+    }
+}
+
 pub struct ZapSubscriber {
     subscriber: ZSock,
     publisher: ZSock,
-    cache: Rc<RefCell<CertCache>>,
+    cache: SharedCertCache,
+    tracer: WireTracer,
+    // Origins whose bridged messages we accept, if restricted
+    allowed_origins: Option<HashSet<String>>,
+}
+
+impl ZapSubscriber {
+    /// Only accept bridged messages whose every origin is a publisher
+    /// in `publishers` (origin and remote server cert, as passed to
+    /// `ZapBridge::new()`) that `allowlist` allows. Others are dropped
+    /// and logged to the "audit" target. Messages published by this
+    /// server carry no origin, so always pass.
+    pub fn set_publisher_allowlist(&mut self, allowlist: &PublisherAllowlist, publishers: &[(String, &ZCert)]) {
+        let mut allowed = HashSet::new();
+        for &(ref origin, cert) in publishers {
+            if allowlist.allows(cert) {
+                allowed.insert(origin.clone());
+            } else {
+                warn!(target: "audit", "Publisher {} is not allowed to publish into the feed", origin);
+            }
+        }
+        self.allowed_origins = Some(allowed);
+    }
+
+    // The first origin of `msg` that isn't allowed to publish, if any
+    fn unauthorized_origin(&self, msg: &ZMsg) -> Option<String> {
+        match self.allowed_origins {
+            Some(ref allowed) => cert_cache::origins(msg).into_iter().find(|o| !allowed.contains(o)),
+            None => None,
+        }
+    }
+}
+
+// The subscriber must be dropped before its ZapPublisher, which
+// flushes whatever we forward here.
+impl Drop for ZapSubscriber {
+    fn drop(&mut self) {
+        self.subscriber.set_rcvtimeo(Some(0));
+        while let Ok(msg) = ZMsg::recv(&mut self.subscriber) {
+            if self.unauthorized_origin(&msg).is_some() {
+                continue;
+            }
+            let msg = match self.cache.write().apply(msg) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
+            self.tracer.record(Direction::In, "publisher", &msg, &[]);
+            if msg.send(&mut self.publisher).is_err() {
+                break;
+            }
+        }
+    }
 }
 
 impl Endpoint for ZapSubscriber {
@@ -108,8 +683,15 @@ impl Endpoint for ZapSubscriber {
 
     fn recv(&mut self, sock: &mut ZSock) -> StdResult<(), DError> {
         if *sock == self.subscriber {
+            let msg = try!(ZMsg::recv(&mut self.subscriber));
+            if let Some(origin) = self.unauthorized_origin(&msg) {
+                warn!(target: "audit", "Dropped feed message from unauthorized publisher {}", origin);
+                return Ok(());
+            }
+
             // Cache certificate
-            let msg = try!(self.cache.borrow_mut().recv(&mut self.subscriber));
+            let msg = try!(self.cache.write().apply(msg));
+            self.tracer.record(Direction::In, "publisher", &msg, &[]);
 
             // Forward message to subscriber (XPUB)
             try!(msg.send(&mut self.publisher));
@@ -126,14 +708,95 @@ impl Endpoint for ZapSubscriber {
     }
 }
 
+/// Publishes a signed attestation of the cert set every interval.
+pub struct Attestor {
+    ticker: ZSock,
+    publisher: ZSock,
+    cache: SharedCertCache,
+    stats: Rc<RefCell<FeedStats>>,
+    key: SecretKey,
+}
+
+impl Attestor {
+    pub fn new(interval: Duration, cache: SharedCertCache, stats: Rc<RefCell<FeedStats>>, key: SecretKey) -> Result<Attestor> {
+        Ok(Attestor {
+            ticker: try!(ticker(interval)),
+            publisher: try!(ZSock::new_pub(ATTESTOR_ENDPOINT)),
+            cache: cache,
+            stats: stats,
+            key: key,
+        })
+    }
+
+    fn attest(&mut self) -> Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let attestation = Attestation::sign(self.cache.read().merkle_root(), self.stats.borrow().sequence, timestamp, &self.key);
+        debug!("Publishing attestation {}", attestation.sequence);
+        try!(try!(attestation.to_msg()).send(&mut self.publisher));
+        Ok(())
+    }
+}
+
+impl Endpoint for Attestor {
+    fn get_sockets(&mut self) -> Vec<&mut ZSock> {
+        vec![&mut self.ticker]
+    }
+
+    fn recv(&mut self, sock: &mut ZSock) -> StdResult<(), DError> {
+        let _ = try!(sock.recv_str());
+        try!(self.attest());
+        Ok(())
+    }
+}
+
+// Timestamp a feed message, then sign it if we have a key
+fn stamp(msg: &ZMsg, key: Option<&SecretKey>) -> Result<()> {
+    try!(cert_cache::stamp(msg));
+    if let Some(key) = key {
+        try!(feed_signature::sign(msg, key));
+    }
+    Ok(())
+}
+
+// Whether a subscription is for a scoped topic, in any format
+fn is_scoped(topic: &str) -> bool {
+    cert_cache::split_scope(SnapshotFormat::from_topic(topic).1).1.is_some()
+}
+
+/// A socket that receives a "TICK" every `interval`. The thread sending
+/// them stops once the socket is dropped and the pipe refuses its tick.
+pub fn ticker(interval: Duration) -> Result<ZSock> {
+    let (ticker, ticker_child) = try!(ZSys::create_pipe());
+    ticker.set_linger(0);
+    ticker_child.set_linger(0);
+    ticker_child.set_sndtimeo(Some(0));
+
+    spawn(move || {
+        let ticker = ticker_child;
+        loop {
+            sleep(interval);
+            if ticker.send_str("TICK").is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(ticker)
+}
+
 #[cfg(test)]
 mod tests {
+    use attestation::Attestation;
     use cert::{Cert, CertType};
     use cert_cache::CertCache;
-    use czmq::{RawInterface, ZMsg, ZSock, ZSys};
+    use czmq::{RawInterface, ZMsg, ZSock, SocketType, ZSys};
+    use sodiumoxide::crypto::sign;
     use std::cell::RefCell;
     use std::rc::Rc;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
     use super::*;
+    use wire_trace::WireTracer;
     use zdaemon::Endpoint;
 
     #[test]
@@ -148,7 +811,7 @@ mod tests {
         let host_pubkey = host_cert.public_txt().to_string();
         let host_meta = host_cert.encode_meta();
 
-        let cache = Rc::new(RefCell::new(CertCache::new(Some(vec![ user_cert ]))));
+        let cache = SharedCertCache::new(CertCache::new(Some(vec![ user_cert ])));
 
         let mut xpub = ZSock::new_xpub("inproc://zap_proxy_test_publisher").unwrap();
         xpub.set_sndtimeo(Some(500));
@@ -167,13 +830,26 @@ mod tests {
         let mut publisher = ZapPublisher {
             publisher: xpub,
             subscriber: s_pair,
+            control: ZSock::new(SocketType::PULL),
             cache: cache.clone(),
+            tracer: WireTracer::disabled(),
+            sequence: 0,
+            stats: Rc::new(RefCell::new(FeedStats::default())),
+            endpoint: None,
+            tags: Vec::new(),
+            log_sampler: LogSampler::new(),
+            heartbeats: None,
+            batch_size: DEFAULT_SNAPSHOT_BATCH_SIZE,
+            last_values: LastValueCache::new(last_value::DEFAULT_CAPACITY),
+            signing_key: None,
         };
 
         let mut subscriber = ZapSubscriber {
             subscriber: xsub,
             publisher: p_pair,
             cache: cache,
+            tracer: WireTracer::disabled(),
+            allowed_origins: None,
         };
 
         let mut server = ZSock::new_pub(">inproc://zap_proxy_test_subscriber").unwrap();
@@ -184,7 +860,7 @@ mod tests {
 
         publisher.recv(&mut xpub_clone).unwrap();
         subscriber.recv(&mut p_pair_clone).unwrap();
-        let msg = ZMsg::recv(&mut client).unwrap();
+        let msg = recv_snapshot(&mut client);
         msg.popstr().unwrap().unwrap(); // Discard topic
         assert_eq!(msg.popstr().unwrap().unwrap(), "ADD");
         assert_eq!(msg.popstr().unwrap().unwrap(), user_pubkey);
@@ -198,8 +874,7 @@ mod tests {
         client.set_subscribe("");
         publisher.recv(&mut xpub_clone).unwrap();
         subscriber.recv(&mut p_pair_clone).unwrap();
-        client.recv_str().unwrap().unwrap(); // Receive user cert again
-        client.flush();
+        recv_snapshot(&mut client); // Receive user cert again
 
         let msg = ZMsg::new();
         msg.addstr("host").unwrap();
@@ -210,12 +885,429 @@ mod tests {
 
         subscriber.recv(&mut xsub_clone).unwrap();
         publisher.recv(&mut s_pair_clone).unwrap();
-        assert!(subscriber.cache.borrow().get(&host_pubkey).is_some());
+        assert!(subscriber.cache.read().get(&host_pubkey).is_some());
 
         let msg = ZMsg::recv(&mut client).unwrap();
         msg.popstr().unwrap().unwrap(); // Discard topic
         assert_eq!(msg.popstr().unwrap().unwrap(), "ADD");
         assert_eq!(msg.popstr().unwrap().unwrap(), host_pubkey);
         assert_eq!(msg.popbytes().unwrap().unwrap(), host_meta);
+        assert_eq!(publisher.sequence, 1);
+
+        let stats = publisher.stats();
+        assert_eq!(stats.borrow().sequence, 1);
+        assert_eq!(stats.borrow().subscribers.get(""), Some(&1));
+        assert!(!stats.borrow().subscribers.contains_key("user"));
+    }
+
+    #[test]
+    fn test_drain() {
+        ZSys::init();
+
+        let cache = SharedCertCache::new(CertCache::new(None));
+
+        let mut xpub = ZSock::new_xpub("inproc://zap_proxy_test_drain_publisher").unwrap();
+        xpub.set_rcvtimeo(Some(500));
+        let mut xpub_clone = unsafe { ZSock::from_raw(xpub.as_mut_ptr(), false) };
+
+        let xsub = ZSock::new_xsub("@inproc://zap_proxy_test_drain_subscriber").unwrap();
+        let (s_pair, p_pair) = ZSys::create_pipe().unwrap();
+
+        let mut publisher = ZapPublisher {
+            publisher: xpub,
+            subscriber: s_pair,
+            control: ZSock::new(SocketType::PULL),
+            cache: cache.clone(),
+            tracer: WireTracer::disabled(),
+            sequence: 0,
+            stats: Rc::new(RefCell::new(FeedStats::default())),
+            endpoint: None,
+            tags: Vec::new(),
+            log_sampler: LogSampler::new(),
+            heartbeats: None,
+            batch_size: DEFAULT_SNAPSHOT_BATCH_SIZE,
+            last_values: LastValueCache::new(last_value::DEFAULT_CAPACITY),
+            signing_key: None,
+        };
+
+        let subscriber = ZapSubscriber {
+            subscriber: xsub,
+            publisher: p_pair,
+            cache: cache.clone(),
+            tracer: WireTracer::disabled(),
+            allowed_origins: None,
+        };
+
+        let mut server = ZSock::new_pub(">inproc://zap_proxy_test_drain_subscriber").unwrap();
+        server.set_sndtimeo(Some(500));
+
+        let mut client = ZSock::new_sub("inproc://zap_proxy_test_drain_publisher", Some("host")).unwrap();
+        client.set_rcvtimeo(Some(500));
+        publisher.recv(&mut xpub_clone).unwrap();
+
+        // The cache is empty, so the snapshot has no certs
+        let msg = recv_snapshot(&mut client);
+        assert_eq!(msg.popstr().unwrap().unwrap(), "host");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "ADD");
+        assert!(msg.popstr().unwrap().unwrap().starts_with(cert_cache::TIMESTAMP_PREFIX));
+        assert!(msg.popstr().is_none());
+
+        // Queue a message that nobody has processed yet
+        let host_cert = Cert::new("example.com", CertType::Host).unwrap();
+        let msg = ZMsg::new();
+        msg.addstr("host").unwrap();
+        msg.addstr("ADD").unwrap();
+        msg.addstr(host_cert.public_txt()).unwrap();
+        msg.addbytes(&host_cert.encode_meta()).unwrap();
+        msg.send(&mut server).unwrap();
+        sleep(Duration::from_millis(100));
+
+        drop(subscriber);
+        drop(publisher);
+
+        let msg = ZMsg::recv(&mut client).unwrap();
+        msg.popstr().unwrap().unwrap(); // Discard topic
+        assert_eq!(msg.popstr().unwrap().unwrap(), "ADD");
+        assert_eq!(msg.popstr().unwrap().unwrap(), host_cert.public_txt());
+        assert!(cache.read().get(host_cert.public_txt()).is_some());
+
+        let msg = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "host");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "HEARTBEAT");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "1");
+    }
+
+    #[test]
+    fn test_start_draining() {
+        ZSys::init();
+
+        let cache = SharedCertCache::new(CertCache::new(None));
+
+        let mut xpub = ZSock::new_xpub("inproc://zap_proxy_test_start_draining").unwrap();
+        xpub.set_rcvtimeo(Some(500));
+        let mut xpub_clone = unsafe { ZSock::from_raw(xpub.as_mut_ptr(), false) };
+        let (s_pair, _p_pair) = ZSys::create_pipe().unwrap();
+
+        let mut publisher = ZapPublisher {
+            publisher: xpub,
+            subscriber: s_pair,
+            control: ZSock::new(SocketType::PULL),
+            cache: cache,
+            tracer: WireTracer::disabled(),
+            sequence: 3,
+            stats: Rc::new(RefCell::new(FeedStats::default())),
+            endpoint: Some("tcp://auth1.example.com:7102".into()),
+            tags: vec!["eu-west".into(), "rack1".into()],
+            log_sampler: LogSampler::new(),
+            heartbeats: None,
+            batch_size: DEFAULT_SNAPSHOT_BATCH_SIZE,
+            last_values: LastValueCache::new(last_value::DEFAULT_CAPACITY),
+            signing_key: None,
+        };
+
+        let mut existing = ZSock::new_sub("inproc://zap_proxy_test_start_draining", Some("host")).unwrap();
+        existing.set_rcvtimeo(Some(500));
+        publisher.recv(&mut xpub_clone).unwrap();
+        recv_snapshot(&mut existing); // Empty snapshot
+
+        let expect_heartbeat = |sock: &mut ZSock| {
+            let msg = ZMsg::recv(sock).unwrap();
+            assert_eq!(msg.popstr().unwrap().unwrap(), "host");
+            assert_eq!(msg.popstr().unwrap().unwrap(), "HEARTBEAT");
+            assert_eq!(msg.popstr().unwrap().unwrap(), "3");
+            assert_eq!(msg.popstr().unwrap().unwrap(), "state=draining");
+            assert_eq!(msg.popstr().unwrap().unwrap(), "endpoint=tcp://auth1.example.com:7102");
+            assert_eq!(msg.popstr().unwrap().unwrap(), "tags=eu-west,rack1");
+            assert_eq!(msg.popstr().unwrap().unwrap(), "certs=0");
+        };
+
+        publisher.start_draining().unwrap();
+        assert!(publisher.stats().borrow().draining);
+        expect_heartbeat(&mut existing);
+
+        // New subscribers are told after their snapshot
+        let mut new = ZSock::new_sub("inproc://zap_proxy_test_start_draining", Some("host")).unwrap();
+        new.set_rcvtimeo(Some(500));
+        publisher.recv(&mut xpub_clone).unwrap();
+        let snapshot = recv_snapshot(&mut new);
+        snapshot.popstr().unwrap().unwrap();
+        assert_eq!(snapshot.popstr().unwrap().unwrap(), "ADD");
+        expect_heartbeat(&mut new);
+    }
+
+    #[test]
+    fn test_heartbeat_interval() {
+        ZSys::init();
+
+        let cache = SharedCertCache::new(CertCache::new(Some(vec![Cert::new("web1", CertType::Host).unwrap()])));
+
+        let xpub = ZSock::new_xpub("inproc://zap_proxy_test_heartbeat_interval").unwrap();
+        let (s_pair, _p_pair) = ZSys::create_pipe().unwrap();
+        let mut publisher = ZapPublisher {
+            publisher: xpub,
+            subscriber: s_pair,
+            control: ZSock::new(SocketType::PULL),
+            cache: cache,
+            tracer: WireTracer::disabled(),
+            sequence: 7,
+            stats: Rc::new(RefCell::new(FeedStats::default())),
+            endpoint: None,
+            tags: Vec::new(),
+            log_sampler: LogSampler::new(),
+            heartbeats: None,
+            batch_size: DEFAULT_SNAPSHOT_BATCH_SIZE,
+            last_values: LastValueCache::new(last_value::DEFAULT_CAPACITY),
+            signing_key: None,
+        };
+        publisher.set_heartbeat_interval(Duration::from_millis(10)).unwrap();
+        let mut ticker = unsafe { ZSock::from_raw(publisher.heartbeats.as_mut().unwrap().as_mut_ptr(), false) };
+        ticker.set_rcvtimeo(Some(500));
+
+        let mut client = ZSock::new_sub("inproc://zap_proxy_test_heartbeat_interval", Some("host")).unwrap();
+        client.set_rcvtimeo(Some(500));
+        sleep(Duration::from_millis(50));
+
+        publisher.recv(&mut ticker).unwrap();
+        let msg = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "host");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "HEARTBEAT");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "7");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "certs=1");
+    }
+
+    #[test]
+    fn test_batched_snapshot() {
+        ZSys::init();
+
+        let certs = vec![
+            Cert::new("web1", CertType::Host).unwrap(),
+            Cert::new("web2", CertType::Host).unwrap(),
+            Cert::new("web3", CertType::Host).unwrap(),
+            Cert::new("dan", CertType::User).unwrap(),
+        ];
+        let cache = SharedCertCache::new(CertCache::new(Some(certs)));
+
+        let mut xpub = ZSock::new_xpub("inproc://zap_proxy_test_batched_snapshot").unwrap();
+        xpub.set_rcvtimeo(Some(500));
+        let mut xpub_clone = unsafe { ZSock::from_raw(xpub.as_mut_ptr(), false) };
+        let (s_pair, _p_pair) = ZSys::create_pipe().unwrap();
+        let mut publisher = ZapPublisher {
+            publisher: xpub,
+            subscriber: s_pair,
+            control: ZSock::new(SocketType::PULL),
+            cache: cache,
+            tracer: WireTracer::disabled(),
+            sequence: 0,
+            stats: Rc::new(RefCell::new(FeedStats::default())),
+            endpoint: None,
+            tags: Vec::new(),
+            log_sampler: LogSampler::new(),
+            heartbeats: None,
+            batch_size: DEFAULT_SNAPSHOT_BATCH_SIZE,
+            last_values: LastValueCache::new(last_value::DEFAULT_CAPACITY),
+            signing_key: None,
+        };
+        publisher.set_snapshot_batch_size(2);
+
+        let mut client = ZSock::new_sub("inproc://zap_proxy_test_batched_snapshot", Some("batch:host")).unwrap();
+        client.set_rcvtimeo(Some(500));
+        let mut client_cache = CertCache::new(None);
+        client_cache.set_format(SnapshotFormat::Batched);
+        client_cache.resync(&cert_cache::snapshot_topics("batch:host"));
+
+        publisher.recv(&mut xpub_clone).unwrap();
+        let sizes: Vec<usize> = (0..3).map(|_| client_cache.recv(&mut client).unwrap().size()).collect();
+        // Topic, action, 2 certs and a timestamp; then 1 cert; then END
+        assert_eq!(sizes, vec![7, 5, 4]);
+        assert!(!client_cache.is_resyncing());
+        assert!(client_cache.get_name("web3").is_some());
+        assert!(client_cache.get_name("dan").is_none());
+
+        // Changes are copied to the batched topic
+        let web4 = Cert::new("web4", CertType::Host).unwrap();
+        let msg = ZMsg::new();
+        msg.addstr("host").unwrap();
+        msg.addstr("ADD").unwrap();
+        msg.addstr(web4.public_txt()).unwrap();
+        msg.addbytes(&web4.encode_meta()).unwrap();
+        publisher.publish(msg).unwrap();
+        let msg = client_cache.recv(&mut client).unwrap();
+        assert_eq!(msg.first().unwrap().data().unwrap().unwrap(), "batch:host");
+        assert!(client_cache.get_name("web4").is_some());
+        assert_eq!(publisher.sequence, 1);
+    }
+
+    #[test]
+    fn test_resume() {
+        ZSys::init();
+
+        let web1 = Cert::new("web1", CertType::Host).unwrap();
+        let web2 = Cert::new("web2", CertType::Host).unwrap();
+        let web3 = Cert::new("web3", CertType::Host).unwrap();
+        let (web2_key, web2_meta) = (web2.public_txt().to_string(), web2.encode_meta());
+        let cache = SharedCertCache::new(CertCache::new(Some(vec![web1, web2])));
+
+        let mut xpub = ZSock::new_xpub("inproc://zap_proxy_test_resume").unwrap();
+        xpub.set_xpub_verbose(true);
+        xpub.set_rcvtimeo(Some(500));
+        let mut xpub_clone = unsafe { ZSock::from_raw(xpub.as_mut_ptr(), false) };
+        let (s_pair, _p_pair) = ZSys::create_pipe().unwrap();
+        let mut publisher = ZapPublisher {
+            publisher: xpub,
+            subscriber: s_pair,
+            control: ZSock::new(SocketType::PULL),
+            cache: cache,
+            tracer: WireTracer::disabled(),
+            sequence: 0,
+            stats: Rc::new(RefCell::new(FeedStats::default())),
+            endpoint: None,
+            tags: Vec::new(),
+            log_sampler: LogSampler::new(),
+            heartbeats: None,
+            batch_size: DEFAULT_SNAPSHOT_BATCH_SIZE,
+            last_values: LastValueCache::new(last_value::DEFAULT_CAPACITY),
+            signing_key: None,
+        };
+        let epoch = publisher.last_values.epoch();
+
+        let msg = ZMsg::new();
+        msg.addstr("host").unwrap();
+        msg.addstr("ADD").unwrap();
+        msg.addstr(&web2_key).unwrap();
+        msg.addbytes(&web2_meta).unwrap();
+        publisher.publish(msg).unwrap();
+        let msg = ZMsg::new();
+        msg.addstr("host").unwrap();
+        msg.addstr("DEL").unwrap();
+        msg.addstr(web3.public_txt()).unwrap();
+        publisher.publish(msg).unwrap();
+
+        // An XSUB can send its resume position with the subscription
+        let mut client = ZSock::new(SocketType::XSUB);
+        client.set_rcvtimeo(Some(500));
+        client.connect("inproc://zap_proxy_test_resume").unwrap();
+        let subscribe = |client: &mut ZSock, position: &str| {
+            let msg = ZMsg::new();
+            msg.addbytes(b"\x01host").unwrap();
+            msg.addstr(position).unwrap();
+            msg.send(client).unwrap();
+        };
+
+        subscribe(&mut client, &format!("since={}:0", epoch));
+        publisher.recv(&mut xpub_clone).unwrap();
+        let msg = ZMsg::recv(&mut client).unwrap();
+        msg.popstr().unwrap().unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "ADD");
+        assert_eq!(msg.popstr().unwrap().unwrap(), web2_key);
+        let msg = ZMsg::recv(&mut client).unwrap();
+        msg.popstr().unwrap().unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "DEL");
+        assert_eq!(msg.popstr().unwrap().unwrap(), web3.public_txt());
+        let msg = ZMsg::recv(&mut client).unwrap();
+        msg.popstr().unwrap().unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "HEARTBEAT");
+        assert_eq!(msg.popstr().unwrap().unwrap(), "2");
+
+        // Positions from another run get a snapshot
+        subscribe(&mut client, &format!("since={}:1", epoch + 1));
+        publisher.recv(&mut xpub_clone).unwrap();
+        let msg = recv_snapshot(&mut client);
+        msg.popstr().unwrap().unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), "ADD");
+        // Topic and action popped; 2 certs and a timestamp
+        assert_eq!(msg.size(), 5);
+    }
+
+    #[test]
+    fn test_attestor() {
+        ZSys::init();
+
+        let cert = Cert::new("example.com", CertType::Host).unwrap();
+        let cache = SharedCertCache::new(CertCache::new(Some(vec![cert])));
+        let stats = Rc::new(RefCell::new(FeedStats::default()));
+        stats.borrow_mut().sequence = 5;
+        let (pk, sk) = sign::gen_keypair();
+
+        let mut attestor = Attestor::new(Duration::from_secs(3600), cache.clone(), stats, sk).unwrap();
+        let mut client = ZSock::new_sub(ATTESTOR_ENDPOINT, Some(ATTESTATION_TOPIC)).unwrap();
+        client.set_rcvtimeo(Some(500));
+        sleep(Duration::from_millis(100));
+
+        attestor.attest().unwrap();
+        let msg = ZMsg::recv(&mut client).unwrap();
+        assert_eq!(msg.popstr().unwrap().unwrap(), ATTESTATION_TOPIC);
+        assert_eq!(msg.popstr().unwrap().unwrap(), "ATTEST");
+        let mut frames = Vec::new();
+        while let Some(Ok(frame)) = msg.popstr() {
+            frames.push(frame);
+        }
+
+        let attestation = Attestation::from_frames(&frames).unwrap();
+        assert_eq!(attestation.sequence, 5);
+        assert_eq!(attestation.root, cache.read().merkle_root());
+        assert!(attestation.verify(&pk));
+    }
+
+    #[test]
+    fn test_publisher_allowlist() {
+        ZSys::init();
+
+        let dc2 = Cert::new("auth.dc2.example.com", CertType::Host).unwrap();
+        let dc3 = Cert::new("auth.dc3.example.com", CertType::Host).unwrap();
+        let allowlist = PublisherAllowlist {
+            names: vec!["auth.dc2.example.com".into()],
+            cert_types: Vec::new(),
+        };
+        assert!(allowlist.allows(&dc2));
+        assert!(!allowlist.allows(&dc3));
+        assert!(PublisherAllowlist { names: Vec::new(), cert_types: vec![CertType::Host] }.allows(&dc3));
+
+        let (_, p_pair) = ZSys::create_pipe().unwrap();
+        let mut subscriber = ZapSubscriber {
+            subscriber: ZSock::new(SocketType::XSUB),
+            publisher: p_pair,
+            cache: SharedCertCache::new(CertCache::new(None)),
+            tracer: WireTracer::disabled(),
+            allowed_origins: None,
+        };
+
+        let msg = ZMsg::new();
+        msg.addstr("host").unwrap();
+        msg.addstr("DEL").unwrap();
+        msg.addstr("0000000000000000000000000000000000000000").unwrap();
+        msg.addstr("origin=dc2,dc3").unwrap();
+        assert!(subscriber.unauthorized_origin(&msg).is_none());
+
+        subscriber.set_publisher_allowlist(&allowlist, &[("dc2".into(), &*dc2), ("dc3".into(), &*dc3)]);
+        assert_eq!(subscriber.unauthorized_origin(&msg), Some("dc3".to_string()));
+
+        let local = ZMsg::new();
+        local.addstr("host").unwrap();
+        local.addstr("DEL").unwrap();
+        local.addstr("0000000000000000000000000000000000000000").unwrap();
+        assert!(subscriber.unauthorized_origin(&local).is_none());
+    }
+
+    #[test]
+    fn test_record_subscribe() {
+        let mut stats = FeedStats::default();
+        let start = Instant::now();
+
+        for i in 0..5 {
+            stats.record_subscribe(start + Duration::from_millis(i * 100));
+        }
+        stats.record_subscribe(start + Duration::from_millis(1500));
+        stats.record_subscribe(start + Duration::from_millis(1600));
+
+        assert_eq!(stats.subscribes, 7);
+        assert_eq!(stats.peak_subscribe_rate, 5);
+    }
+
+    // The SNAPSHOT notice, then the snapshot it announces
+    fn recv_snapshot(sock: &mut ZSock) -> ZMsg {
+        let notice = ZMsg::recv(sock).unwrap();
+        notice.popstr().unwrap().unwrap();
+        assert_eq!(notice.popstr().unwrap().unwrap(), "SNAPSHOT");
+        ZMsg::recv(sock).unwrap()
     }
 }
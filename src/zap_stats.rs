@@ -0,0 +1,134 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Counters for the ZAP requests a `ZapHandler` answers, for agents to
+//! include in their own telemetry.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+// Percentiles are taken over this many of the latest requests
+const LATENCY_SAMPLES: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MechanismStats {
+    pub allowed: u64,
+    pub denied: u64,
+}
+
+/// Time taken to answer ZAP requests.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LatencyPercentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ZapStats {
+    /// Outcomes by mechanism, e.g. "CURVE"
+    pub mechanisms: HashMap<String, MechanismStats>,
+    /// CURVE keys found in the cert cache
+    pub cache_hits: u64,
+    /// CURVE keys looked up and not found
+    pub cache_misses: u64,
+    /// Certs refused for disagreeing with their feed topic
+    pub quarantined: u64,
+    /// Over the latest requests, or `None` before the first
+    pub latency: Option<LatencyPercentiles>,
+}
+
+/// Collects `ZapStats` as requests are answered.
+#[derive(Debug, Default)]
+pub struct ZapStatsRecorder {
+    stats: ZapStats,
+    // Latest request latencies, oldest first
+    samples: VecDeque<Duration>,
+}
+
+impl ZapStatsRecorder {
+    pub fn new() -> ZapStatsRecorder {
+        ZapStatsRecorder::default()
+    }
+
+    pub fn record(&mut self, mechanism: &str, allowed: bool, latency: Duration) {
+        let counts = self.stats.mechanisms.entry(mechanism.to_string()).or_insert(MechanismStats::default());
+        if allowed {
+            counts.allowed += 1;
+        } else {
+            counts.denied += 1;
+        }
+
+        if self.samples.len() == LATENCY_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+    }
+
+    pub fn record_lookup(&mut self, hit: bool) {
+        if hit {
+            self.stats.cache_hits += 1;
+        } else {
+            self.stats.cache_misses += 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> ZapStats {
+        let mut stats = self.stats.clone();
+        if !self.samples.is_empty() {
+            let mut sorted: Vec<Duration> = self.samples.iter().cloned().collect();
+            sorted.sort();
+            let percentile = |p: usize| sorted[rank_index(sorted.len(), p)];
+            stats.latency = Some(LatencyPercentiles {
+                p50: percentile(50),
+                p90: percentile(90),
+                p99: percentile(99),
+                max: sorted[sorted.len() - 1],
+            });
+        }
+        stats
+    }
+}
+
+// Index of the `p`th percentile in a sorted list of `len` samples, by
+// the nearest-rank method
+fn rank_index(len: usize, p: usize) -> usize {
+    let rank = (len * p + 99) / 100;
+    if rank == 0 { 0 } else { rank - 1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn test_recorder() {
+        let mut recorder = ZapStatsRecorder::new();
+        assert_eq!(recorder.snapshot(), ZapStats::default());
+
+        for ms in 1..101 {
+            recorder.record("CURVE", ms % 10 != 0, Duration::from_millis(ms));
+        }
+        recorder.record("PLAIN", false, Duration::from_millis(1));
+        recorder.record_lookup(true);
+        recorder.record_lookup(false);
+
+        let stats = recorder.snapshot();
+        assert_eq!(stats.mechanisms["CURVE"], MechanismStats { allowed: 90, denied: 10 });
+        assert_eq!(stats.mechanisms["PLAIN"], MechanismStats { allowed: 0, denied: 1 });
+        assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+
+        let latency = stats.latency.unwrap();
+        assert_eq!(latency.p50, Duration::from_millis(50));
+        assert_eq!(latency.p90, Duration::from_millis(90));
+        assert_eq!(latency.p99, Duration::from_millis(99));
+        assert_eq!(latency.max, Duration::from_millis(100));
+    }
+}